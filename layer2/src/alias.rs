@@ -0,0 +1,169 @@
+use derive_more::Display;
+
+use crate::types::{AliasRegistration, Hash, Hasher, H160, U256};
+
+pub const MIN_ALIAS_LEN: usize = 3;
+pub const MAX_ALIAS_LEN: usize = 32;
+// About two years of blocks, so a lost key can't hold a name forever
+pub const MAX_ALIAS_BLOCKS: u64 = 20_000_000;
+// Charged in the fee token for every block an alias is registered for
+pub const ALIAS_FEE_PER_BLOCK: u64 = 1;
+const ALIAS_KEY_PREFIX: &[u8] = b"alias";
+
+#[derive(Display, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AliasError {
+    #[display(
+        fmt = "Alias must be {} to {} characters of a-z, 0-9 and inner dashes",
+        MIN_ALIAS_LEN,
+        MAX_ALIAS_LEN
+    )]
+    InvalidName,
+    #[display(fmt = "Alias must be registered for 1 to {} blocks", MAX_ALIAS_BLOCKS)]
+    InvalidBlocks,
+    #[display(fmt = "Alias can't point to the zero address")]
+    ZeroAddress,
+}
+
+/// State trie key of an alias. Like multisig keys it's a 32 bytes hash,
+/// so it can't collide with an account.
+pub fn alias_key(name: &str) -> Hash {
+    Hasher::digest_([ALIAS_KEY_PREFIX, name.as_bytes()].concat())
+}
+
+/// Lowercase only, so an alias can't be spoofed with another case.
+pub fn validate_name(name: &str) -> Result<(), AliasError> {
+    let valid_chars = name
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+    if !(MIN_ALIAS_LEN..=MAX_ALIAS_LEN).contains(&name.len())
+        || !valid_chars
+        || name.starts_with('-')
+        || name.ends_with('-')
+    {
+        return Err(AliasError::InvalidName);
+    }
+
+    Ok(())
+}
+
+impl AliasRegistration {
+    pub fn validate(&self) -> Result<(), AliasError> {
+        validate_name(&self.name)?;
+        if self.blocks == 0 || self.blocks > MAX_ALIAS_BLOCKS {
+            return Err(AliasError::InvalidBlocks);
+        }
+        if self.address == H160::zero() {
+            return Err(AliasError::ZeroAddress);
+        }
+
+        Ok(())
+    }
+
+    /// Paid by the sender in the fee token on top of the cycles.
+    pub fn fee(&self) -> U256 {
+        U256::from(self.blocks) * U256::from(ALIAS_FEE_PER_BLOCK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use cita_trie::MemoryDB;
+    use rlp::{Decodable, Encodable, Rlp};
+
+    use super::*;
+    use crate::dev::DevWallet;
+    use crate::executor::{Execute, Executor, FeeConfig};
+    use crate::offline::UnsignedTransaction;
+    use crate::state::StateView;
+    use crate::types::{MultisigConfig, RawTransaction, SignedTransaction, U64};
+
+    fn register(wallet: &DevWallet, nonce: u64, name: &str, blocks: u64) -> SignedTransaction {
+        let sender = wallet.address;
+        let raw = RawTransaction {
+            chain_id: U64::one(),
+            cycles_price: U64::one(),
+            cycles_limit: 1000u64.into(),
+            nonce: RawTransaction::nonce_of(nonce),
+            requests: Vec::new(),
+            sender,
+            multisig: None,
+            alias: Some(AliasRegistration {
+                name: name.to_owned(),
+                address: sender,
+                blocks,
+            }),
+            timeout: None,
+        };
+        UnsignedTransaction::new(raw).sign(&wallet.key).unwrap()
+    }
+
+    #[test]
+    fn test_register_alias() {
+        let db = Arc::new(MemoryDB::new(true));
+        let (alice, bob) = (&DevWallet::derive(1).unwrap(), &DevWallet::derive(2).unwrap());
+        let exec = |number: u64, root: Hash, txs: &[SignedTransaction]| {
+            Executor::new(Arc::clone(&db))
+                .at_block(number)
+                .exec(root, txs)
+        };
+        let resolve = |root: Hash, number: u64| {
+            StateView::new(Arc::clone(&db), root)
+                .at_block(number)
+                .resolve_alias("alice")
+        };
+
+        let resp = exec(10, Hash::zero(), &[register(alice, 0, "alice", 100)]);
+        assert!(resp.inner[0].error.is_none());
+        let record = resolve(resp.state_root, 50).unwrap();
+        assert_eq!((record.address, record.expires_at), (alice.address, 110));
+
+        // Taken until it expires, renewals extend the expiry
+        let resp = exec(20, resp.state_root, &[
+            register(bob, 0, "alice", 100),
+            register(alice, 1, "alice", 50),
+        ]);
+        assert!(resp.inner[0].error.is_some());
+        assert!(resp.inner[1].error.is_none());
+        assert_eq!(resolve(resp.state_root, 159).unwrap().expires_at, 160);
+        assert!(resolve(resp.state_root, 160).is_none());
+        let resp = exec(170, resp.state_root, &[register(bob, 1, "alice", 10)]);
+        assert!(resp.inner[0].error.is_none());
+        assert_eq!(resolve(resp.state_root, 170).unwrap().owner, bob.address);
+
+        // Registered in the fee token
+        let fee = FeeConfig {
+            token:     Hash::repeat_byte(9),
+            recipient: bob.address,
+        };
+        let resp = Executor::new(Arc::clone(&db))
+            .with_fee(Some(fee))
+            .exec(Hash::zero(), &[register(alice, 0, "alice", 100)]);
+        assert!(resp.inner[0].error.is_some());
+    }
+
+    #[test]
+    fn test_alias_names_and_encoding() {
+        assert!(validate_name("alice-2").is_ok());
+        for name in ["al", "Alice", "-alice", "alice-", "ali ce", &"a".repeat(33)] {
+            assert_eq!(validate_name(name), Err(AliasError::InvalidName));
+        }
+        let alice = DevWallet::derive(1).unwrap();
+        let mut stx = register(&alice, 0, "alice", 0);
+        assert_eq!(
+            stx.raw.alias.as_ref().unwrap().validate(),
+            Err(AliasError::InvalidBlocks)
+        );
+
+        stx.raw.multisig = Some(MultisigConfig {
+            threshold: 1,
+            pub_keys:  vec![Bytes::from_static(&[2; 33])],
+        });
+        for raw in [register(&alice, 0, "alice", 1).raw, stx.raw] {
+            let decoded = RawTransaction::decode(&Rlp::new(&raw.rlp_bytes())).unwrap();
+            assert_eq!(decoded, raw);
+        }
+    }
+}
@@ -1,35 +1,401 @@
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use jsonrpsee::core::{Error, RpcResult};
 use jsonrpsee::proc_macros::rpc;
-use jsonrpsee::server::ServerBuilder;
+use jsonrpsee::server::middleware::proxy_get_request::ProxyGetRequestLayer;
+use jsonrpsee::server::{ServerBuilder, SubscriptionSink};
+use jsonrpsee::types::error::{CallError, ErrorObject};
+use jsonrpsee::types::SubscriptionResult;
+use rlp::Encodable;
+use serde::{Deserialize, Serialize};
+use share::error_code::RpcErrorCode;
+use share::front::RpcFront;
+use share::idempotency::IdempotencyKeys;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+use tower::ServiceBuilder;
 
+use crate::attestation::{AttestationError, AttestationPool, SignedAttestation};
+use crate::bridge::{EncodedCommitment, Layer3Bridge, WithdrawalBatch};
 use crate::chain::Chain;
-use crate::executor::Executor;
-use crate::mempool::MemPool;
-use crate::types::{Block, Hash, SignedTransaction, TokenBalance, H160, U64};
+use crate::config::{ConfigReloader, RpcLimits, RuntimeConfig};
+use crate::consensus::BlockPolicy;
+use crate::cors::cors_layer;
+use crate::executor::FeeConfig;
+use crate::health::HealthReport;
+use crate::mempool::{
+    BlockTemplate, ExpiredTransaction, MemPool, MemPoolContent, MemPoolEntry, MemPoolError,
+    MemPoolSize, TX_CYCLE_LIMIT,
+};
+use crate::metrics::{MethodMetrics, RpcMetrics, SlowQueryLayer};
+use crate::multisig::address_of;
+use crate::peer::{NodeIdentity, PeerBan, PeerManager};
+use crate::rpc_guard::RpcGuardLayer;
+use crate::state::{AccountState, BalanceProof, StateView};
+use crate::types::{
+    AliasRecord, Block, BlockUsage, BloomInput, Hash, Hasher, Log, LogEntry, RawTransaction,
+    SignedTransaction, TokenBalance, TransactionReceipt, H160, U256, U64,
+};
+use crate::upgrade::PROTOCOL_VERSION;
 
 #[rpc(server)]
 pub trait Rpc {
     #[method(name = "send_transaction")]
-    async fn send_transaction(&self, stx: SignedTransaction) -> RpcResult<()>;
+    async fn send_transaction(
+        &self,
+        stx: SignedTransaction,
+        idempotency_key: Option<String>,
+    ) -> RpcResult<SendTransactionResult>;
+
+    /// Validate and insert every transaction on its own, in order. One
+    /// rejected doesn't stop the rest, each counts against the rate limit.
+    #[method(name = "send_transactions")]
+    async fn send_transactions(
+        &self,
+        stxs: Vec<SignedTransaction>,
+    ) -> RpcResult<Vec<BatchTransactionResult>>;
 
     #[method(name = "get_block_by_number")]
     async fn get_block_by_number(&self, number: U64) -> RpcResult<Option<Block>>;
 
+    #[method(name = "get_block_by_hash")]
+    async fn get_block_by_hash(&self, hash: Hash) -> RpcResult<Option<Block>>;
+
+    /// Tip of the chain, none before the first block.
+    #[method(name = "get_latest_block")]
+    async fn get_latest_block(&self) -> RpcResult<Option<Block>>;
+
+    #[method(name = "get_block_usage")]
+    async fn get_block_usage(&self, number: U64) -> RpcResult<Option<BlockUsage>>;
+
     #[method(name = "get_transaction_by_hash")]
     async fn get_transaction_by_hash(&self, hash: Hash) -> RpcResult<Option<SignedTransaction>>;
 
+    #[method(name = "get_transaction_receipt")]
+    async fn get_transaction_receipt(&self, hash: Hash) -> RpcResult<Option<TransactionReceipt>>;
+
+    /// Logs of a range of at most 1000 blocks, of the given addresses only
+    /// when any are given.
+    #[method(name = "get_logs")]
+    async fn get_logs(&self, filter: LogFilter) -> RpcResult<Vec<LogEntry>>;
+
+    /// Whether `hash` waits in the mempool or where it's committed. Unknown
+    /// covers transactions never seen and those dropped unpackaged.
+    #[method(name = "get_transaction_status")]
+    async fn get_transaction_status(&self, hash: Hash) -> RpcResult<TransactionStatus>;
+
+    /// Balance at `block_number`, the latest block when omitted.
     #[method(name = "get_balance")]
-    async fn get_balance(&self, address: H160, token_id: Hash) -> RpcResult<TokenBalance>;
+    async fn get_balance(
+        &self,
+        address: H160,
+        token_id: Hash,
+        block_number: Option<U64>,
+    ) -> RpcResult<TokenBalance>;
+
+    /// Merkle proofs of the account and its balance of `token_id` at
+    /// `block_number`, the latest block when omitted.
+    #[method(name = "get_proof")]
+    async fn get_proof(
+        &self,
+        address: H160,
+        token_id: Hash,
+        block_number: Option<U64>,
+    ) -> RpcResult<BalanceProof>;
+
+    /// Execute a transaction on the latest state without committing it, to
+    /// check it before signing. Signatures aren't checked, and execution
+    /// isn't metered, so it uses the transaction's whole cycles limit.
+    #[method(name = "estimate_cycles")]
+    async fn estimate_cycles(&self, raw: RawTransaction) -> RpcResult<CyclesEstimate>;
+
+    /// The account at the latest block with every token it holds.
+    #[method(name = "get_account")]
+    async fn get_account(&self, address: H160) -> RpcResult<AccountState>;
+
+    /// Where the alias points at the latest block, null if it's not
+    /// registered or expired.
+    #[method(name = "resolve_alias")]
+    async fn resolve_alias(&self, name: String) -> RpcResult<Option<AliasRecord>>;
+
+    /// Nonce to sign the next transaction of `address` with, past the ones
+    /// it has waiting in the mempool.
+    #[method(name = "get_nonce")]
+    async fn get_nonce(&self, address: H160) -> RpcResult<U64>;
+
+    /// Why the transactions of `address` wait: its nonce at the latest
+    /// block, what it has queued, the first nonce it's missing and the
+    /// cycles price a replacement needs.
+    #[method(name = "get_account_diagnostics")]
+    async fn get_account_diagnostics(&self, address: H160) -> RpcResult<AccountDiagnostics>;
+
+    #[method(name = "build_block_template")]
+    async fn build_block_template(&self) -> RpcResult<BlockTemplate>;
+
+    /// Network the node accepts transactions for, to check before signing.
+    #[method(name = "chain_id")]
+    async fn chain_id(&self) -> RpcResult<U64>;
+
+    #[method(name = "node_info")]
+    async fn node_info(&self) -> RpcResult<NodeInfo>;
+
+    /// Progress towards the newest block known, null once the node has it.
+    /// Producing nodes are always synced.
+    #[method(name = "syncing")]
+    async fn syncing(&self) -> RpcResult<Option<SyncProgress>>;
+
+    #[method(name = "system_health")]
+    async fn health(&self) -> RpcResult<HealthReport>;
+
+    #[method(name = "system_ready")]
+    async fn ready(&self) -> RpcResult<HealthReport>;
+
+    /// Take another validator's attestation of the state after a block.
+    /// False when it's already kept.
+    #[method(name = "submit_attestation")]
+    async fn submit_attestation(&self, signed: SignedAttestation) -> RpcResult<bool>;
+
+    /// Validator attestations of the state after block `number`.
+    #[method(name = "get_attestations")]
+    async fn get_attestations(&self, number: U64) -> RpcResult<Vec<SignedAttestation>>;
+
+    /// Header of every new block, WebSocket only.
+    #[subscription(
+        name = "subscribe_new_heads",
+        unsubscribe = "unsubscribe_new_heads",
+        item = crate::types::Header
+    )]
+    fn subscribe_new_heads(&self);
+
+    /// Status of `tx_hash` now and on every change, ends once it's
+    /// committed. WebSocket only.
+    #[subscription(
+        name = "subscribe_tx_status",
+        unsubscribe = "unsubscribe_tx_status",
+        item = TxStatusUpdate
+    )]
+    fn subscribe_tx_status(&self, tx_hash: Hash);
+
+    /// Every transaction evicted from the mempool unpackaged, with what a
+    /// resubmission needs. WebSocket only.
+    #[subscription(
+        name = "subscribe_transaction_expired",
+        unsubscribe = "unsubscribe_transaction_expired",
+        item = ExpiredTransaction
+    )]
+    fn subscribe_transaction_expired(&self);
+}
+
+/// Operator transactions, mempool inspection, config reloads, peer bans
+/// and RPC metrics, served on the loopback only `admin_rpc_uri`.
+#[rpc(server)]
+pub trait OperatorRpc {
+    #[method(name = "admin_send_operator_transaction")]
+    async fn send_operator_transaction(&self, stx: SignedTransaction) -> RpcResult<Hash>;
+
+    /// Keep a layer3 checkpoint commitment, returns the hash it's kept
+    /// under. Each commitment starts at the block after the previous one.
+    #[method(name = "admin_submit_commitment")]
+    async fn submit_commitment(&self, commitment: EncodedCommitment) -> RpcResult<Hash>;
+
+    /// Pay a layer3 withdrawal batch in one transaction minted by the
+    /// payer, returns its hash. A batch paid before gets the same hash.
+    #[method(name = "admin_pay_withdrawals")]
+    async fn pay_withdrawals(&self, batch: WithdrawalBatch) -> RpcResult<Hash>;
+
+    #[method(name = "admin_mempool_content")]
+    async fn mempool_content(&self) -> RpcResult<MemPoolContent>;
+
+    #[method(name = "admin_mempool_size")]
+    async fn mempool_size(&self) -> RpcResult<MemPoolSize>;
+
+    /// Returns false if the transaction isn't queued. Its hash stays known,
+    /// so the same transaction can't be sent again.
+    #[method(name = "admin_drop_transaction")]
+    async fn drop_transaction(&self, tx_hash: Hash) -> RpcResult<bool>;
+
+    #[method(name = "admin_reload_config")]
+    async fn reload_config(&self) -> RpcResult<RuntimeConfig>;
+
+    #[method(name = "admin_node_id")]
+    async fn node_id(&self) -> RpcResult<Hash>;
+
+    #[method(name = "admin_ban_peer")]
+    async fn ban_peer(
+        &self,
+        peer_id: Hash,
+        reason: String,
+        duration_secs: Option<u64>,
+    ) -> RpcResult<()>;
+
+    #[method(name = "admin_unban_peer")]
+    async fn unban_peer(&self, peer_id: Hash) -> RpcResult<()>;
+
+    #[method(name = "admin_banned_peers")]
+    async fn banned_peers(&self) -> RpcResult<Vec<PeerBan>>;
+
+    /// Calls, errors and latency of every method of the node's RPC servers.
+    #[method(name = "admin_rpc_metrics")]
+    async fn rpc_metrics(&self) -> RpcResult<Vec<MethodMetrics>>;
+}
+
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const IDEMPOTENCY_KEY_CAPACITY: usize = 100_000;
+const MAX_BATCH_TRANSACTIONS: usize = 1000;
+const MAX_LOG_BLOCK_RANGE: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    Pending,
+    Committed,
+    // Neither pending nor in a block, e.g. evicted from a full mempool
+    Unknown,
+}
+
+/// Blocks and addresses `get_logs` returns the logs of.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    // The latest block when unset
+    pub from_block: Option<U64>,
+    pub to_block:   Option<U64>,
+    // Every address when empty
+    #[serde(default)]
+    pub addresses:  Vec<H160>,
+}
+
+/// Returned by `get_transaction_status`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Pending,
+    Committed {
+        block_number: U64,
+        index:        u64,
+    },
+    Unknown,
+}
+
+/// Returned by `estimate_cycles`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CyclesEstimate {
+    pub cycles:        U64,
+    // Charged to the fee payer, 0 when the node charges no fees
+    pub fee:           U256,
+    // 0 on success, the `ExecuteError` code otherwise
+    pub exit_code:     u32,
+    pub error_message: String,
+    pub logs:          Vec<Log>,
+}
+
+/// Returned by `get_account_diagnostics`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccountDiagnostics {
+    pub state_nonce:            U64,
+    // First nonce neither committed nor queued, the transactions queued
+    // above it wait for it
+    pub next_nonce:             U64,
+    // In nonce order
+    pub queued:                 Vec<QueuedTransaction>,
+    // Above every queued price of the account and the packaging floor
+    pub suggested_cycles_price: U64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct QueuedTransaction {
+    pub tx_hash:      Hash,
+    pub nonce:        Hash,
+    pub cycles_price: U64,
+    pub age_secs:     u64,
+    // Operator transactions are packaged ahead of the public pool
+    pub priority:     bool,
+    // Waits for `next_nonce` to be sent
+    pub blocked:      bool,
+}
+
+/// Returned by `node_info`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub version:          String,
+    pub chain_id:         U64,
+    // Address of the block proposer
+    pub proposer:         H160,
+    pub node_id:          Hash,
+    // Highest protocol version the node speaks
+    pub protocol_version: u32,
+    // Always 0 until nodes connect to each other
+    pub peer_count:       u64,
+    // Read replicas only serve the snapshots they follow
+    pub read_only:        bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SyncProgress {
+    pub current_block: U64,
+    pub highest_block: U64,
+}
+
+/// Where a node that doesn't produce blocks learns how far the chain is.
+pub trait SyncSource: Sync + Send {
+    fn highest_block(&self) -> Result<u64, anyhow::Error>;
+}
+
+/// Returned by `send_transaction`. A repeated idempotency key gets the
+/// transaction first sent with it, which isn't submitted again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SendTransactionResult {
+    pub tx_hash: Hash,
+    pub status:  TxStatus,
+}
+
+/// Outcome of one transaction of `send_transactions`, at the position it
+/// was sent at.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BatchTransactionResult {
+    pub tx_hash: Hash,
+    // None if the transaction was rejected
+    pub status:  Option<TxStatus>,
+    pub error:   Option<TransactionRejection>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransactionRejection {
+    // One of the `RpcErrorCode`s
+    pub code:    i32,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TxStatusUpdate {
+    pub tx_hash:      Hash,
+    pub status:       TxStatus,
+    // Block the transaction is committed in
+    pub block_number: Option<U64>,
 }
 
 pub struct RpcImpl<DB, C, M> {
-    trie_db: Arc<DB>,
-    chain:   Arc<C>,
-    mempool: Arc<M>,
+    trie_db:      Arc<DB>,
+    chain:        Arc<C>,
+    mempool:      Arc<M>,
+    reloader:     Arc<ConfigReloader>,
+    rate_limiter: RateLimiter,
+    identity:     Arc<NodeIdentity>,
+    idempotency:  IdempotencyKeys<Hash>,
+    // Subscriptions are refused without a feed, e.g. on read replicas
+    blocks:       Option<broadcast::Sender<Arc<Block>>>,
+    expired:      Option<broadcast::Sender<ExpiredTransaction>>,
+    metrics:      RpcMetrics,
+    chain_id:     U64,
+    proposer:     H160,
+    fee_token:    Option<Hash>,
+    block:        BlockPolicy,
+    // None on producing nodes
+    sync:         Option<Arc<dyn SyncSource>>,
+    // None unless the node keeps validator attestations
+    attestations: Option<Arc<AttestationPool>>,
 }
 
 #[async_trait]
@@ -39,43 +405,495 @@ where
     C: Chain + 'static,
     M: MemPool + 'static,
 {
-    async fn send_transaction(&self, stx: SignedTransaction) -> RpcResult<()> {
-        self.mempool
-            .insert(stx)
-            .await
-            .map_err(|e| Error::Custom(e.to_string()))
+    async fn send_transaction(
+        &self,
+        stx: SignedTransaction,
+        idempotency_key: Option<String>,
+    ) -> RpcResult<SendTransactionResult> {
+        if !self.rate_limiter.acquire() {
+            return Err(rpc_error(RpcErrorCode::RateLimited, "Rate limit exceeded"));
+        }
+
+        let tx_hash = stx.tx_hash;
+        if let Some(key) = &idempotency_key {
+            let bound = { self.idempotency.bind(key, tx_hash) }
+                .map_err(|e| rpc_error(RpcErrorCode::InvalidIdempotencyKey, e))?;
+            if let Some(original) = bound {
+                return self.tx_status(original).await;
+            }
+        }
+
+        if let Err(e) = self.mempool.insert(stx).await {
+            if let Some(key) = &idempotency_key {
+                self.idempotency.release(key, tx_hash);
+            }
+            return Err(to_rpc_error(e));
+        }
+        Ok(SendTransactionResult {
+            tx_hash,
+            status: TxStatus::Pending,
+        })
+    }
+
+    async fn send_transactions(
+        &self,
+        stxs: Vec<SignedTransaction>,
+    ) -> RpcResult<Vec<BatchTransactionResult>> {
+        if stxs.len() > MAX_BATCH_TRANSACTIONS {
+            return Err(rpc_error(
+                RpcErrorCode::BatchTooLarge,
+                format!("At most {} transactions per batch", MAX_BATCH_TRANSACTIONS),
+            ));
+        }
+
+        let mut results = Vec::with_capacity(stxs.len());
+        for stx in stxs {
+            let tx_hash = stx.tx_hash;
+            let inserted = if self.rate_limiter.acquire() {
+                { self.mempool.insert(stx).await }.map_err(|e| (error_code(&e), e.to_string()))
+            } else {
+                Err((RpcErrorCode::RateLimited, "Rate limit exceeded".to_owned()))
+            };
+
+            results.push(match inserted {
+                Ok(()) => BatchTransactionResult {
+                    tx_hash,
+                    status: Some(TxStatus::Pending),
+                    error: None,
+                },
+                Err((code, message)) => BatchTransactionResult {
+                    tx_hash,
+                    status: None,
+                    error: Some(TransactionRejection {
+                        code: code.code(),
+                        message,
+                    }),
+                },
+            });
+        }
+
+        Ok(results)
     }
 
     async fn get_block_by_number(&self, number: U64) -> RpcResult<Option<Block>> {
         self.chain
             .get_block_by_number(&number)
             .await
-            .map_err(|e| Error::Custom(e.to_string()))
+            .map_err(to_rpc_error)
     }
 
-    async fn get_transaction_by_hash(&self, hash: Hash) -> RpcResult<Option<SignedTransaction>> {
+    async fn get_block_by_hash(&self, hash: Hash) -> RpcResult<Option<Block>> {
+        self.chain
+            .get_block_by_hash(&hash)
+            .await
+            .map_err(to_rpc_error)
+    }
+
+    async fn get_latest_block(&self) -> RpcResult<Option<Block>> {
+        let header = match self.chain.get_latest_block().await {
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(to_rpc_error(e)),
+        };
+
         self.chain
-            .get_tx_by_hash(&hash)
+            .get_block_by_number(&header.number)
             .await
-            .map_err(|e| Error::Custom(e.to_string()))
+            .map_err(to_rpc_error)
+    }
+
+    async fn get_block_usage(&self, number: U64) -> RpcResult<Option<BlockUsage>> {
+        self.chain
+            .get_block_usage(&number)
+            .await
+            .map_err(to_rpc_error)
+    }
+
+    async fn get_transaction_by_hash(&self, hash: Hash) -> RpcResult<Option<SignedTransaction>> {
+        self.chain.get_tx_by_hash(&hash).await.map_err(to_rpc_error)
+    }
+
+    async fn get_transaction_receipt(&self, hash: Hash) -> RpcResult<Option<TransactionReceipt>> {
+        let receipt = self.chain.get_receipt(&hash).await.map_err(to_rpc_error)?;
+        if receipt.is_some() {
+            return Ok(receipt);
+        }
+
+        // Trimming keeps the transaction, a committed one without receipt
+        // had it trimmed
+        let trimmed_tip = { self.chain.receipts_trimmed_tip().await }.map_err(to_rpc_error)?;
+        if !trimmed_tip.is_zero()
+            && { self.chain.get_tx_by_hash(&hash).await }
+                .map_err(to_rpc_error)?
+                .is_some()
+        {
+            return Err(rpc_error(
+                RpcErrorCode::TrimmedHistory,
+                format!(
+                    "Receipt of {:?} is trimmed, receipts are kept after block {}",
+                    hash, trimmed_tip
+                ),
+            ));
+        }
+
+        Ok(None)
+    }
+
+    async fn get_logs(&self, filter: LogFilter) -> RpcResult<Vec<LogEntry>> {
+        let latest = match self.chain.get_latest_block().await.map_err(to_rpc_error)? {
+            Some(header) => header.number,
+            None => return Ok(Vec::new()),
+        };
+        let to = filter.to_block.unwrap_or(latest).min(latest);
+        let from = filter.from_block.unwrap_or(to);
+        if from > to || (to - from).as_u64() >= MAX_LOG_BLOCK_RANGE {
+            return Err(rpc_error(
+                RpcErrorCode::InvalidRange,
+                format!(
+                    "Block range {}..={} is reversed or longer than {} blocks",
+                    from, to, MAX_LOG_BLOCK_RANGE
+                ),
+            ));
+        }
+        let trimmed_tip = { self.chain.receipts_trimmed_tip().await }.map_err(to_rpc_error)?;
+        if !trimmed_tip.is_zero() && from <= trimmed_tip {
+            return Err(rpc_error(
+                RpcErrorCode::TrimmedHistory,
+                format!(
+                    "Logs of blocks up to {} are trimmed, start the range after it",
+                    trimmed_tip
+                ),
+            ));
+        }
+
+        let mut logs = Vec::new();
+        for number in from.as_u64()..=to.as_u64() {
+            let number = U64::from(number);
+            if !filter.addresses.is_empty() {
+                let header =
+                    { self.chain.get_header_by_number(&number).await }.map_err(to_rpc_error)?;
+                // Legacy headers have an empty bloom, their logs are checked
+                let bloom = header.map(|header| header.logs_bloom).unwrap_or_default();
+                let maybe = bloom.is_zero() || { filter.addresses.iter() }
+                    .any(|address| bloom.contains_input(BloomInput::Raw(address.as_bytes())));
+                if !maybe {
+                    continue;
+                }
+            }
+
+            let block_logs = self
+                .chain
+                .get_block_logs(&number)
+                .await
+                .map_err(to_rpc_error)?;
+            logs.extend(block_logs.into_iter().filter(|entry| {
+                filter.addresses.is_empty() || { entry.log.address() }
+                    .map(|address| filter.addresses.contains(&address))
+                    .unwrap_or(false)
+            }));
+        }
+
+        Ok(logs)
+    }
+
+    async fn get_transaction_status(&self, hash: Hash) -> RpcResult<TransactionStatus> {
+        if self.mempool.contains(&hash).await {
+            return Ok(TransactionStatus::Pending);
+        }
+
+        let receipt = self.chain.get_receipt(&hash).await.map_err(to_rpc_error)?;
+        Ok(match receipt {
+            Some(receipt) => TransactionStatus::Committed {
+                block_number: receipt.block_number,
+                index:        receipt.index,
+            },
+            None => TransactionStatus::Unknown,
+        })
+    }
+
+    async fn get_balance(
+        &self,
+        address: H160,
+        token_id: Hash,
+        block_number: Option<U64>,
+    ) -> RpcResult<TokenBalance> {
+        let state = self.state_at(block_number).await?;
+        Ok(state.balance(&address, &token_id))
     }
 
-    async fn get_balance(&self, address: H160, token_id: Hash) -> RpcResult<TokenBalance> {
-        let header = self
-            .chain
-            .get_latest_block()
+    async fn get_proof(
+        &self,
+        address: H160,
+        token_id: Hash,
+        block_number: Option<U64>,
+    ) -> RpcResult<BalanceProof> {
+        let state = self.state_at(block_number).await?;
+        state.proof(&address, &token_id).map_err(to_rpc_error)
+    }
+
+    async fn estimate_cycles(&self, raw: RawTransaction) -> RpcResult<CyclesEstimate> {
+        if raw.chain_id != self.chain_id {
+            return Err(rpc_error(
+                RpcErrorCode::InvalidChainId,
+                format!("Chain id {} is not {}", raw.chain_id, self.chain_id),
+            ));
+        }
+        if raw.cycles_limit > TX_CYCLE_LIMIT {
+            return Err(rpc_error(
+                RpcErrorCode::ExceedCycleLimit,
+                format!("Cycles limit is above {}", TX_CYCLE_LIMIT),
+            ));
+        }
+
+        let stx = SignedTransaction {
+            tx_hash: Hasher::digest_(raw.rlp_bytes()),
+            raw,
+            pub_key: Bytes::new(),
+            signature: Bytes::new(),
+            signatures: Vec::new(),
+            sponsor: None,
+        };
+        let fee = self.fee_token.map(|token| FeeConfig {
+            token,
+            recipient: self.proposer,
+        });
+        let resp = self.state_at(None).await?.dry_run(&stx, fee);
+        let (exit_code, error_message) = match resp.error {
+            Some(error) => (error.error_code, error.error_message),
+            None => (0, String::new()),
+        };
+
+        Ok(CyclesEstimate {
+            cycles: stx.cycle_limit(),
+            fee: if fee.is_some() {
+                stx.fee()
+            } else {
+                U256::zero()
+            },
+            exit_code,
+            error_message,
+            logs: resp.logs,
+        })
+    }
+
+    async fn get_account(&self, address: H160) -> RpcResult<AccountState> {
+        let state = self.state_at(None).await?;
+        Ok(state.account(&address))
+    }
+
+    async fn resolve_alias(&self, name: String) -> RpcResult<Option<AliasRecord>> {
+        Ok(self.state_at(None).await?.resolve_alias(&name))
+    }
+
+    async fn get_nonce(&self, address: H160) -> RpcResult<U64> {
+        let nonce = match self.mempool.next_nonce(&address).await {
+            Some(nonce) => nonce,
+            None => self.state_at(None).await?.nonce(&address),
+        };
+        Ok(nonce.into())
+    }
+
+    async fn get_account_diagnostics(&self, address: H160) -> RpcResult<AccountDiagnostics> {
+        let state_nonce = self.state_at(None).await?.nonce(&address);
+        let next_nonce = self.mempool.next_nonce(&address).await;
+        let next_nonce = next_nonce.unwrap_or(state_nonce);
+        let content = self.mempool.content().await.map_err(to_rpc_error)?;
+        let hint = self.mempool.resubmission_hint().await;
+
+        let tagged = |entries: Vec<MemPoolEntry>, priority: bool| {
+            entries.into_iter().map(move |entry| (entry, priority))
+        };
+        let mut queued = { tagged(content.priority, true) }
+            .chain(tagged(content.pending, false))
+            .filter(|(entry, _)| entry.stx.raw.sender == address)
+            .map(|(entry, priority)| QueuedTransaction {
+                tx_hash: entry.stx.tx_hash,
+                nonce: entry.stx.raw.nonce,
+                cycles_price: entry.stx.raw.cycles_price,
+                age_secs: entry.age_secs,
+                priority,
+                blocked: { entry.stx.raw.nonce_number() }.is_none_or(|nonce| nonce > next_nonce),
+            })
+            .collect::<Vec<_>>();
+        queued.sort_by_key(|tx| tx.nonce);
+
+        let replacement = { queued.iter() }
+            .map(|tx| tx.cycles_price.as_u64() + 1)
+            .max()
+            .unwrap_or_default();
+        Ok(AccountDiagnostics {
+            state_nonce: state_nonce.into(),
+            next_nonce: next_nonce.into(),
+            queued,
+            suggested_cycles_price: hint.min_cycles_price.max(replacement).into(),
+        })
+    }
+
+    async fn build_block_template(&self) -> RpcResult<BlockTemplate> {
+        self.mempool
+            .build_block_template(self.block.cycles_limit.into())
             .await
-            .map_err(|e| Error::Custom(e.to_string()))?;
-        let executor = Executor::new(Arc::clone(&self.trie_db));
+            .map_err(to_rpc_error)
+    }
+
+    async fn health(&self) -> RpcResult<HealthReport> {
+        let report = HealthReport::collect(self.chain.as_ref()).await;
+        report
+            .check_health()
+            .map_err(|e| rpc_error(RpcErrorCode::NotReady, e))?;
+        Ok(report)
+    }
+
+    async fn ready(&self) -> RpcResult<HealthReport> {
+        let report = HealthReport::collect(self.chain.as_ref()).await;
+        report
+            .check_ready(
+                self.reloader
+                    .current()
+                    .max_block_gap_secs(self.block.interval_secs),
+            )
+            .map_err(|e| rpc_error(RpcErrorCode::NotReady, e))?;
+        Ok(report)
+    }
+
+    async fn submit_attestation(&self, signed: SignedAttestation) -> RpcResult<bool> {
+        self.attestation_pool()?
+            .receive(signed)
+            .map_err(|e| rpc_error(e.code(), e))
+    }
+
+    async fn get_attestations(&self, number: U64) -> RpcResult<Vec<SignedAttestation>> {
+        self.attestation_pool()?
+            .attestations(number)
+            .map_err(|e| rpc_error(e.code(), e))
+    }
+
+    async fn chain_id(&self) -> RpcResult<U64> {
+        Ok(self.chain_id)
+    }
+
+    async fn node_info(&self) -> RpcResult<NodeInfo> {
+        Ok(NodeInfo {
+            version:          env!("CARGO_PKG_VERSION").to_owned(),
+            chain_id:         self.chain_id,
+            proposer:         self.proposer,
+            node_id:          self.identity.peer_id,
+            protocol_version: PROTOCOL_VERSION,
+            peer_count:       0,
+            read_only:        self.sync.is_some(),
+        })
+    }
+
+    async fn syncing(&self) -> RpcResult<Option<SyncProgress>> {
+        let sync = match &self.sync {
+            Some(sync) => sync,
+            None => return Ok(None),
+        };
+
+        let highest_block = sync.highest_block().map_err(to_rpc_error)?;
+        let current_block = { self.chain.get_latest_block().await }
+            .map_err(to_rpc_error)?
+            .map(|header| header.number.as_u64())
+            .unwrap_or_default();
+        if current_block >= highest_block {
+            return Ok(None);
+        }
+
+        Ok(Some(SyncProgress {
+            current_block: current_block.into(),
+            highest_block: highest_block.into(),
+        }))
+    }
 
-        Ok(executor.get_balance(
-            &executor.trie(
-                &executor
-                    .get_account(&executor.trie(&header.state_root), &address)
-                    .balance_root,
-            ),
-            &token_id,
-        ))
+    fn subscribe_new_heads(&self, mut sink: SubscriptionSink) -> SubscriptionResult {
+        let mut blocks = match self.block_feed(&mut sink) {
+            Some(blocks) => blocks,
+            None => return Ok(()),
+        };
+
+        tokio::spawn(async move {
+            while let Some(block) = next_block(&mut blocks).await {
+                if !matches!(sink.send(&block.header), Ok(true)) {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn subscribe_transaction_expired(&self, mut sink: SubscriptionSink) -> SubscriptionResult {
+        let mut expired = match &self.expired {
+            Some(expired) => expired.subscribe(),
+            None => {
+                let _ = sink.reject(rpc_error(
+                    RpcErrorCode::ReadOnly,
+                    "Node doesn't produce blocks",
+                ));
+                return Ok(());
+            }
+        };
+
+        tokio::spawn(async move {
+            loop {
+                let tx = match expired.recv().await {
+                    Ok(tx) => tx,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if !matches!(sink.send(&tx), Ok(true)) {
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn subscribe_tx_status(&self, mut sink: SubscriptionSink, tx_hash: Hash) -> SubscriptionResult {
+        // Subscribed before the first check, so no block is missed
+        let mut blocks = match self.block_feed(&mut sink) {
+            Some(blocks) => blocks,
+            None => return Ok(()),
+        };
+        let (chain, mempool) = (Arc::clone(&self.chain), Arc::clone(&self.mempool));
+
+        tokio::spawn(async move {
+            let mut update = match tx_status_update(chain.as_ref(), mempool.as_ref(), tx_hash).await
+            {
+                Ok(update) => update,
+                Err(e) => {
+                    let _ = sink.reject(to_rpc_error(e));
+                    return;
+                }
+            };
+            loop {
+                if !matches!(sink.send(&update), Ok(true)) || update.status == TxStatus::Committed {
+                    break;
+                }
+
+                let block = match next_block(&mut blocks).await {
+                    Some(block) => block,
+                    None => break,
+                };
+
+                let next = if block.txs.iter().any(|tx| tx.tx_hash == tx_hash) {
+                    TxStatusUpdate {
+                        tx_hash,
+                        status: TxStatus::Committed,
+                        block_number: Some(block.header.number),
+                    }
+                } else {
+                    match tx_status_update(chain.as_ref(), mempool.as_ref(), tx_hash).await {
+                        Ok(next) => next,
+                        Err(_) => break,
+                    }
+                };
+                if next == update {
+                    continue;
+                }
+                update = next;
+            }
+        });
+        Ok(())
     }
 }
 
@@ -85,16 +903,693 @@ where
     C: Chain + 'static,
     M: MemPool + 'static,
 {
-    pub fn new(trie_db: Arc<DB>, chain: Arc<C>, mempool: Arc<M>) -> Self {
+    pub fn new(
+        trie_db: Arc<DB>,
+        chain: Arc<C>,
+        mempool: Arc<M>,
+        reloader: Arc<ConfigReloader>,
+        identity: Arc<NodeIdentity>,
+    ) -> Self {
         RpcImpl {
             trie_db,
             chain,
             mempool,
+            rate_limiter: RateLimiter::new(reloader.subscribe()),
+            metrics: RpcMetrics::new(reloader.subscribe()),
+            reloader,
+            identity,
+            idempotency: IdempotencyKeys::new(IDEMPOTENCY_KEY_TTL, IDEMPOTENCY_KEY_CAPACITY),
+            blocks: None,
+            expired: None,
+            chain_id: U64::zero(),
+            proposer: H160::zero(),
+            fee_token: None,
+            block: BlockPolicy::default(),
+            sync: None,
+            attestations: None,
+        }
+    }
+
+    /// Network and proposer the node reports to clients, and the token the
+    /// proposer collects cycles in.
+    pub fn with_network(mut self, chain_id: U64, proposer: H160, fee_token: Option<Hash>) -> Self {
+        self.chain_id = chain_id;
+        self.proposer = proposer;
+        self.fee_token = fee_token;
+        self
+    }
+
+    /// Blocks consensus produces, for templates and readiness.
+    pub fn with_block_policy(mut self, block: BlockPolicy) -> Self {
+        self.block = block;
+        self
+    }
+
+    pub fn with_sync_source(mut self, sync: Arc<dyn SyncSource>) -> Self {
+        self.sync = Some(sync);
+        self
+    }
+
+    pub fn with_attestations(mut self, pool: Option<Arc<AttestationPool>>) -> Self {
+        self.attestations = pool;
+        self
+    }
+
+    /// Serve subscriptions from the blocks consensus publishes.
+    pub fn with_block_feed(mut self, blocks: broadcast::Sender<Arc<Block>>) -> Self {
+        self.blocks = Some(blocks);
+        self
+    }
+
+    pub fn with_expired_feed(mut self, expired: broadcast::Sender<ExpiredTransaction>) -> Self {
+        self.expired = Some(expired);
+        self
+    }
+
+    /// Counters to record the node's RPC servers in.
+    pub fn metrics(&self) -> RpcMetrics {
+        self.metrics.clone()
+    }
+
+    fn attestation_pool(&self) -> RpcResult<&AttestationPool> {
+        let disabled = AttestationError::Disabled;
+        { self.attestations.as_deref() }.ok_or_else(|| rpc_error(disabled.code(), disabled))
+    }
+
+    // State after block `number`, the latest one when none
+    async fn state_at(&self, number: Option<U64>) -> RpcResult<StateView<DB>> {
+        let header = match number {
+            Some(number) => self
+                .chain
+                .get_header_by_number(&number)
+                .await
+                .map_err(to_rpc_error)?
+                .ok_or_else(|| {
+                    rpc_error(RpcErrorCode::UnknownBlock, format!("No block {}", number))
+                })?,
+            None => self
+                .chain
+                .get_latest_block()
+                .await
+                .map_err(to_rpc_error)?
+                .ok_or_else(|| rpc_error(RpcErrorCode::UnknownBlock, "No block produced yet"))?,
+        };
+
+        let state_root = header.state_root();
+        let known = state_root.is_zero() || { self.trie_db.contains(state_root.as_bytes()) }
+            .map_err(|e| rpc_error(RpcErrorCode::Internal, e))?;
+        if !known {
+            return Err(rpc_error(
+                RpcErrorCode::PrunedState,
+                format!("State of block {} is pruned", header.number),
+            ));
         }
+
+        Ok(StateView::new(Arc::clone(&self.trie_db), state_root).at_block(header.number.as_u64()))
+    }
+
+    fn block_feed(&self, sink: &mut SubscriptionSink) -> Option<broadcast::Receiver<Arc<Block>>> {
+        match &self.blocks {
+            Some(blocks) => Some(blocks.subscribe()),
+            None => {
+                let _ = sink.reject(rpc_error(
+                    RpcErrorCode::ReadOnly,
+                    "Node doesn't produce blocks",
+                ));
+                None
+            }
+        }
+    }
+
+    async fn tx_status(&self, tx_hash: Hash) -> RpcResult<SendTransactionResult> {
+        let status = if self.mempool.contains(&tx_hash).await {
+            TxStatus::Pending
+        } else if { self.chain.get_tx_by_hash(&tx_hash).await }
+            .map_err(to_rpc_error)?
+            .is_some()
+        {
+            TxStatus::Committed
+        } else {
+            TxStatus::Unknown
+        };
+
+        Ok(SendTransactionResult { tx_hash, status })
     }
 }
 
-pub async fn run_jsonrpc_server<RPC: RpcServer>(rpc_impl: RPC, uri: SocketAddr) {
-    let server = ServerBuilder::default().build(uri).await.unwrap();
-    let _handle = server.start(rpc_impl.into_rpc()).unwrap();
+// Lagging subscribers skip the blocks they missed
+async fn next_block(blocks: &mut broadcast::Receiver<Arc<Block>>) -> Option<Arc<Block>> {
+    loop {
+        match blocks.recv().await {
+            Ok(block) => return Some(block),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+async fn tx_status_update<C: Chain, M: MemPool>(
+    chain: &C,
+    mempool: &M,
+    tx_hash: Hash,
+) -> anyhow::Result<TxStatusUpdate> {
+    if let Some(receipt) = chain.get_receipt(&tx_hash).await? {
+        return Ok(TxStatusUpdate {
+            tx_hash,
+            status: TxStatus::Committed,
+            block_number: Some(receipt.block_number),
+        });
+    }
+
+    let status = if mempool.contains(&tx_hash).await {
+        TxStatus::Pending
+    } else if chain.get_tx_by_hash(&tx_hash).await?.is_some() {
+        TxStatus::Committed
+    } else {
+        TxStatus::Unknown
+    };
+    Ok(TxStatusUpdate {
+        tx_hash,
+        status,
+        block_number: None,
+    })
+}
+
+/// Accepts transactions signed by an operator key only, and queues them
+/// for the next block without the public rate limit.
+pub struct OperatorRpcImpl<M> {
+    mempool:   Arc<M>,
+    operators: HashSet<H160>,
+    reloader:  Arc<ConfigReloader>,
+    identity:  Arc<NodeIdentity>,
+    peers:     Arc<PeerManager>,
+    metrics:   RpcMetrics,
+    // Replicas take no layer3 state
+    bridge:    Option<Arc<Layer3Bridge>>,
+}
+
+impl<M: MemPool> OperatorRpcImpl<M> {
+    pub fn new(
+        mempool: Arc<M>,
+        operators: HashSet<H160>,
+        reloader: Arc<ConfigReloader>,
+        identity: Arc<NodeIdentity>,
+        peers: Arc<PeerManager>,
+        metrics: RpcMetrics,
+    ) -> Self {
+        OperatorRpcImpl {
+            mempool,
+            operators,
+            reloader,
+            identity,
+            peers,
+            metrics,
+            bridge: None,
+        }
+    }
+
+    pub fn with_bridge(mut self, bridge: Arc<Layer3Bridge>) -> Self {
+        self.bridge = Some(bridge);
+        self
+    }
+
+    fn bridge(&self) -> RpcResult<&Layer3Bridge> {
+        { self.bridge.as_deref() }
+            .ok_or_else(|| rpc_error(RpcErrorCode::ReadOnly, "Node takes no layer3 state"))
+    }
+}
+
+#[async_trait]
+impl<M: MemPool + 'static> OperatorRpcServer for OperatorRpcImpl<M> {
+    async fn send_operator_transaction(&self, stx: SignedTransaction) -> RpcResult<Hash> {
+        // Multisig accounts sign with `signatures` and have no single
+        // signer to authenticate
+        let signer = address_of(&stx.pub_key);
+        if !stx.signatures.is_empty()
+            || signer != stx.raw.sender
+            || !self.operators.contains(&signer)
+        {
+            return Err(rpc_error(
+                RpcErrorCode::Unauthorized,
+                "Sender is not an operator",
+            ));
+        }
+
+        let tx_hash = stx.tx_hash;
+        self.mempool
+            .insert_priority(stx)
+            .await
+            .map_err(to_rpc_error)?;
+        Ok(tx_hash)
+    }
+
+    async fn submit_commitment(&self, commitment: EncodedCommitment) -> RpcResult<Hash> {
+        self.bridge()?
+            .submit_commitment(commitment.0)
+            .map_err(|e| rpc_error(e.code(), e))
+    }
+
+    async fn pay_withdrawals(&self, batch: WithdrawalBatch) -> RpcResult<Hash> {
+        let bridge = self.bridge()?;
+        let _paying = bridge.lock_payer().await;
+        if let Some(tx_hash) = bridge.paid(&batch.id).map_err(|e| rpc_error(e.code(), e))? {
+            return Ok(tx_hash);
+        }
+
+        let payer = bridge.payer().map_err(|e| rpc_error(e.code(), e))?;
+        let nonce = { self.mempool.next_nonce(&payer).await }
+            .ok_or_else(|| rpc_error(RpcErrorCode::Internal, "Payer has no nonce"))?;
+        let stx = bridge
+            .payout_transaction(&batch, nonce)
+            .map_err(|e| rpc_error(e.code(), e))?;
+        let tx_hash = stx.tx_hash;
+        self.mempool
+            .insert_priority(stx)
+            .await
+            .map_err(to_rpc_error)?;
+        bridge
+            .record_payout(&batch.id, &tx_hash)
+            .map_err(|e| rpc_error(e.code(), e))?;
+        Ok(tx_hash)
+    }
+
+    async fn mempool_content(&self) -> RpcResult<MemPoolContent> {
+        self.mempool.content().await.map_err(to_rpc_error)
+    }
+
+    async fn mempool_size(&self) -> RpcResult<MemPoolSize> {
+        Ok(self.mempool.size().await)
+    }
+
+    async fn drop_transaction(&self, tx_hash: Hash) -> RpcResult<bool> {
+        if !self.mempool.contains(&tx_hash).await {
+            return Ok(false);
+        }
+
+        self.mempool
+            .remove(vec![tx_hash])
+            .await
+            .map_err(to_rpc_error)?;
+        println!("[admin] dropped transaction {:?}", tx_hash);
+        Ok(true)
+    }
+
+    async fn reload_config(&self) -> RpcResult<RuntimeConfig> {
+        self.reloader
+            .reload()
+            .map_err(|e| rpc_error(RpcErrorCode::Internal, format!("{:#}", e)))
+    }
+
+    async fn node_id(&self) -> RpcResult<Hash> {
+        Ok(self.identity.peer_id)
+    }
+
+    async fn ban_peer(
+        &self,
+        peer_id: Hash,
+        reason: String,
+        duration_secs: Option<u64>,
+    ) -> RpcResult<()> {
+        self.peers
+            .ban(peer_id, reason, duration_secs)
+            .map_err(to_rpc_error)
+    }
+
+    async fn unban_peer(&self, peer_id: Hash) -> RpcResult<()> {
+        self.peers.unban(&peer_id).map_err(to_rpc_error)
+    }
+
+    async fn banned_peers(&self) -> RpcResult<Vec<PeerBan>> {
+        self.peers.banned().map_err(to_rpc_error)
+    }
+
+    async fn rpc_metrics(&self) -> RpcResult<Vec<MethodMetrics>> {
+        Ok(self.metrics.report())
+    }
+}
+
+/// Fixed one second window limiter, the limit is read from the latest
+/// runtime config on every call.
+struct RateLimiter {
+    runtime: watch::Receiver<RuntimeConfig>,
+    window:  Mutex<(Instant, u32)>,
+}
+
+impl RateLimiter {
+    fn new(runtime: watch::Receiver<RuntimeConfig>) -> Self {
+        RateLimiter {
+            runtime,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        let limit = self.runtime.borrow().rpc_rate_limit;
+        if limit == 0 {
+            return true;
+        }
+
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= Duration::from_secs(1) {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= limit {
+            return false;
+        }
+
+        window.1 += 1;
+        true
+    }
+}
+
+/// Error object carrying a code from the shared registry, so clients can
+/// match on the code instead of the message.
+pub(crate) fn rpc_error(code: RpcErrorCode, msg: impl ToString) -> Error {
+    Error::Call(CallError::Custom(ErrorObject::owned(
+        code.code(),
+        msg.to_string(),
+        None::<()>,
+    )))
+}
+
+fn to_rpc_error(e: anyhow::Error) -> Error {
+    rpc_error(error_code(&e), e)
+}
+
+fn error_code(e: &anyhow::Error) -> RpcErrorCode {
+    e.downcast_ref::<MemPoolError>()
+        .map(MemPoolError::code)
+        .unwrap_or(RpcErrorCode::Internal)
+}
+
+/// Serves HTTP and WebSocket on `uri`, subscriptions need WebSocket.
+pub async fn run_jsonrpc_server<RPC: RpcServer>(
+    rpc_impl: RPC,
+    uri: SocketAddr,
+    metrics: RpcMetrics,
+    limits: &RpcLimits,
+) {
+    let module = rpc_impl.into_rpc();
+    let names = module.method_names().collect::<HashSet<_>>();
+    for method in limits.allowed_methods.iter() {
+        if !names.contains(method.as_str()) {
+            println!("[rpc] allowed method {} doesn't exist", method);
+        }
+    }
+
+    // Plain `GET /health` and `GET /ready` for load balancers and probes,
+    // answered 500 when the matching RPC fails
+    let front = limits.front().unwrap();
+    let middleware = ServiceBuilder::new()
+        .option_layer(cors_layer(&limits.cors_allowed_origins).unwrap())
+        .layer(RpcGuardLayer::new(limits))
+        .layer(ProxyGetRequestLayer::new("/health", "system_health").unwrap())
+        .layer(ProxyGetRequestLayer::new("/ready", "system_ready").unwrap())
+        .layer(SlowQueryLayer::new("public", &metrics));
+    let server = ServerBuilder::default()
+        .max_connections(limits.max_connections)
+        .max_request_body_size(limits.max_request_body_bytes)
+        .max_response_body_size(limits.max_response_body_bytes)
+        .set_middleware(middleware)
+        .set_logger(metrics)
+        .build(front.as_ref().map_or(uri, |_| RpcFront::inner_addr()))
+        .await
+        .unwrap();
+    if let Some(front) = front {
+        front.spawn(uri, server.local_addr().unwrap()).await.unwrap();
+    }
+    // The server stops once its handle is dropped
+    let handle = server.start(module).unwrap();
+    tokio::spawn(handle.stopped());
+}
+
+/// Serve the operator RPC at `uri`, which config validation keeps on a
+/// loopback address. It goes without the TLS and client address front of
+/// the public RPC: only processes on the operator's own host reach it, so
+/// there's no network to encrypt and every client is the same address.
+pub async fn run_operator_server<RPC: OperatorRpcServer>(
+    rpc_impl: RPC,
+    uri: SocketAddr,
+    metrics: RpcMetrics,
+) {
+    let middleware = ServiceBuilder::new().layer(SlowQueryLayer::new("operator", &metrics));
+    let server = ServerBuilder::default()
+        .set_middleware(middleware)
+        .set_logger(metrics)
+        .build(uri)
+        .await
+        .unwrap();
+    let handle = server.start(rpc_impl.into_rpc()).unwrap();
+    tokio::spawn(handle.stopped());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use cita_trie::MemoryDB;
+    use jsonrpsee::core::Error;
+
+    use super::*;
+    use crate::bridge::BatchedTransfer;
+    use crate::chain::CovalentChain;
+    use crate::dev::DevWallet;
+    use crate::mempool::MemPoolImpl;
+    use crate::offline::UnsignedTransaction;
+    use crate::types::{BlockCommit, Header, TokenAction, TransactionRequest, U128};
+
+    // Dev wallet 0 is the only operator
+    fn operator_rpc(dir: &Path) -> OperatorRpcImpl<MemPoolImpl<MemoryDB>> {
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let db = Arc::new(MemoryDB::new(true));
+        let mempool = MemPoolImpl::new(runtime.clone(), U64::one(), db, state_root);
+        let peer_db = sled::open(dir.join("peer")).unwrap();
+        OperatorRpcImpl::new(
+            Arc::new(mempool),
+            [DevWallet::derive(0).unwrap().address].into(),
+            Arc::new(ConfigReloader::new(
+                dir.join("runtime.toml"),
+                RuntimeConfig::default(),
+            )),
+            Arc::new(NodeIdentity::load_or_generate(&dir.join("node.key")).unwrap()),
+            Arc::new(PeerManager::new(&peer_db).unwrap()),
+            RpcMetrics::new(runtime),
+        )
+    }
+
+    fn mint(wallet: &DevWallet, cycles_price: u64) -> SignedTransaction {
+        let raw = RawTransaction {
+            chain_id:     U64::one(),
+            cycles_price: cycles_price.into(),
+            cycles_limit: 1000u64.into(),
+            nonce:        Hash::zero(),
+            requests:     vec![TransactionRequest {
+                address:  wallet.address,
+                token_id: Hash::from_low_u64_be(1),
+                amount:   1u64.into(),
+                action:   TokenAction::Mint,
+                to:       None,
+            }],
+            sender:       wallet.address,
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        };
+        UnsignedTransaction::new(raw).sign(&wallet.key).unwrap()
+    }
+
+    fn error_code(err: Error) -> Option<RpcErrorCode> {
+        match err {
+            Error::Call(CallError::Custom(err)) => RpcErrorCode::from_code(err.code()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_operator_transactions_go_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = operator_rpc(dir.path());
+        let public = mint(&DevWallet::derive(1).unwrap(), 5);
+        rpc.mempool.insert(public.clone()).await.unwrap();
+
+        let err = rpc
+            .send_operator_transaction(mint(&DevWallet::derive(2).unwrap(), 10))
+            .await
+            .unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::Unauthorized));
+        // Signed by an operator key for another sender
+        let mut forged = mint(&DevWallet::derive(2).unwrap(), 10);
+        forged.pub_key = mint(&DevWallet::derive(0).unwrap(), 10).pub_key;
+        let err = rpc.send_operator_transaction(forged).await.unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::Unauthorized));
+        assert_eq!(rpc.mempool_size().await.unwrap().priority, 0);
+
+        // Packaged ahead of better paying public transactions
+        let operator = mint(&DevWallet::derive(0).unwrap(), 0);
+        let tx_hash = rpc
+            .send_operator_transaction(operator.clone())
+            .await
+            .unwrap();
+        assert_eq!(tx_hash, operator.tx_hash);
+        let txs = rpc.mempool.package(TX_CYCLE_LIMIT).await.unwrap();
+        assert_eq!(
+            txs.iter().map(|stx| stx.tx_hash).collect::<Vec<_>>(),
+            vec![operator.tx_hash, public.tx_hash]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inspect_and_drop_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let rpc = operator_rpc(dir.path());
+        let txs = [1, 2].map(|i| mint(&DevWallet::derive(i).unwrap(), i as u64));
+        for stx in txs.iter() {
+            rpc.mempool.insert(stx.clone()).await.unwrap();
+        }
+        let operator = mint(&DevWallet::derive(0).unwrap(), 0);
+        rpc.send_operator_transaction(operator.clone())
+            .await
+            .unwrap();
+
+        let size = rpc.mempool_size().await.unwrap();
+        assert_eq!((size.priority, size.pending), (1, 2));
+        let content = rpc.mempool_content().await.unwrap();
+        assert_eq!(content.priority[0].stx.tx_hash, operator.tx_hash);
+        assert_eq!(
+            content
+                .pending
+                .iter()
+                .map(|e| e.stx.tx_hash)
+                .collect::<Vec<_>>(),
+            vec![txs[1].tx_hash, txs[0].tx_hash]
+        );
+
+        assert!(rpc.drop_transaction(txs[1].tx_hash).await.unwrap());
+        assert!(rpc.drop_transaction(operator.tx_hash).await.unwrap());
+        // Already dropped
+        assert!(!rpc.drop_transaction(txs[1].tx_hash).await.unwrap());
+        let size = rpc.mempool_size().await.unwrap();
+        assert_eq!((size.priority, size.pending), (0, 1));
+        let content = rpc.mempool_content().await.unwrap();
+        assert_eq!(content.pending[0].stx.tx_hash, txs[0].tx_hash);
+        // The hash stays known, the same transaction can't be sent again
+        assert!(rpc.mempool.insert(txs[1].clone()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pay_withdrawal_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let batch = |id: u64| WithdrawalBatch {
+            id:           Hash::from_low_u64_be(id),
+            block_number: id,
+            transfers:    vec![BatchedTransfer {
+                recipient:   DevWallet::derive(1).unwrap().address,
+                token_id:    1u64.into(),
+                amount:      10u64.into(),
+                request_ids: vec![Hash::repeat_byte(id as u8)],
+            }],
+        };
+        let rpc = operator_rpc(dir.path());
+        let err = rpc.pay_withdrawals(batch(1)).await.unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::ReadOnly));
+
+        let bridge_db = sled::open(dir.path().join("bridge")).unwrap();
+        let payer = DevWallet::derive(3).unwrap();
+        let bridge = Layer3Bridge::new(&bridge_db).unwrap();
+        let rpc = rpc.with_bridge(Arc::new(bridge));
+        let err = rpc.pay_withdrawals(batch(1)).await.unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::ReadOnly));
+
+        let bridge = Layer3Bridge::new(&bridge_db).unwrap();
+        let bridge = bridge.with_payer(U64::one(), payer.key);
+        // Replaces the bridge without a payer
+        let rpc = rpc.with_bridge(Arc::new(bridge));
+        let tx_hash = rpc.pay_withdrawals(batch(1)).await.unwrap();
+        // Sent again after a lost answer
+        assert_eq!(rpc.pay_withdrawals(batch(1)).await.unwrap(), tx_hash);
+        rpc.pay_withdrawals(batch(2)).await.unwrap();
+        let content = rpc.mempool_content().await.unwrap();
+        assert_eq!(content.priority.len(), 2);
+        let paid = &content.priority[0].stx;
+        assert_eq!(paid.tx_hash, tx_hash);
+        assert_eq!(paid.raw.sender, payer.address);
+        assert_eq!(paid.raw.requests[0].token_id, Hash::from_low_u64_be(1));
+        let next = &content.priority[1].stx;
+        assert_eq!(next.raw.nonce, RawTransaction::nonce_of(1));
+    }
+
+    #[tokio::test]
+    async fn test_account_diagnostics() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let db = Arc::new(MemoryDB::new(true));
+        let mempool = MemPoolImpl::new(runtime, U64::one(), Arc::clone(&db), state_root);
+        let mempool = Arc::new(mempool);
+        let chain = CovalentChain::new(dir.path().join("chain"));
+        let genesis = Block {
+            header: Header {
+                chain_id:         U64::one(),
+                number:           U64::zero(),
+                prev_hash:        Hash::zero(),
+                timestamp:        U128::zero(),
+                transaction_root: Hash::zero(),
+                prev_state_root:  Hash::zero(),
+                cycles_limit:     U64::zero(),
+                proposer:         H160::zero(),
+                post_state_root:  Hash::zero(),
+                logs_bloom:       Default::default(),
+                protocol_version: 1,
+                signature:        None,
+            },
+            txs:    Vec::new(),
+            commit: BlockCommit::default(),
+        };
+        chain.save_block(genesis).await.unwrap();
+        let rpc = RpcImpl::new(
+            db,
+            Arc::new(chain),
+            Arc::clone(&mempool),
+            Arc::new(ConfigReloader::new(
+                dir.path().join("runtime.toml"),
+                RuntimeConfig::default(),
+            )),
+            Arc::new(NodeIdentity::load_or_generate(&dir.path().join("node.key")).unwrap()),
+        );
+
+        let wallet = DevWallet::derive(1).unwrap();
+        let signed = |nonce: u64, cycles_price: u64| {
+            let mut raw = mint(&wallet, cycles_price).raw;
+            raw.nonce = RawTransaction::nonce_of(nonce);
+            UnsignedTransaction::new(raw).sign(&wallet.key).unwrap()
+        };
+        let idle = rpc.get_account_diagnostics(wallet.address).await.unwrap();
+        assert_eq!(
+            (idle.state_nonce, idle.next_nonce),
+            (U64::zero(), U64::zero())
+        );
+        assert!(idle.queued.is_empty());
+
+        // Nonce 1 is never sent, nonce 2 waits for it
+        let (first, third) = (signed(0, 5), signed(2, 7));
+        mempool.insert(third.clone()).await.unwrap();
+        mempool.insert(first.clone()).await.unwrap();
+        let stuck = rpc.get_account_diagnostics(wallet.address).await.unwrap();
+        assert_eq!(
+            (stuck.state_nonce, stuck.next_nonce),
+            (U64::zero(), U64::one())
+        );
+        assert_eq!(
+            { stuck.queued.iter() }
+                .map(|tx| (tx.tx_hash, tx.blocked))
+                .collect::<Vec<_>>(),
+            vec![(first.tx_hash, false), (third.tx_hash, true)]
+        );
+        // Outbids the queued transactions
+        assert_eq!(stuck.suggested_cycles_price, 8u64.into());
+        let other = DevWallet::derive(2).unwrap().address;
+        let other = rpc.get_account_diagnostics(other).await.unwrap();
+        assert!(other.queued.is_empty());
+    }
 }
@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use rlp::Encodable;
+use share::archive::{ArchiveKind, ArchiveReader, ArchiveWriter};
+use share::limits::BLOCK_LIMIT;
+
+use crate::chain::Chain;
+use crate::merkle::Merkle;
+use crate::types::{decode_rlp, Block, Hash, U64};
+
+/// Write blocks `from..=to` to a flat archive at `path`, one RLP encoded
+/// block per record. Returns the number of blocks written.
+pub async fn export_blocks<C: Chain>(chain: &C, from: U64, to: U64, path: &Path) -> Result<u64> {
+    if from > to {
+        return Err(anyhow!("empty block range {}..={}", from, to));
+    }
+
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut writer = ArchiveWriter::new(BufWriter::new(file), ArchiveKind::Layer2)?;
+
+    let mut number = from;
+    while number <= to {
+        let block = chain
+            .get_block_by_number(&number)
+            .await?
+            .ok_or_else(|| anyhow!("block {} not found", number))?;
+        writer.append(&block.rlp_bytes())?;
+        number += U64::one();
+    }
+
+    writer.finish()?;
+    Ok((to - from).as_u64() + 1)
+}
+
+/// Save the blocks of an archive at `path` on top of `chain`. Blocks must
+/// extend the local tip one by one; blocks the chain already has are
+/// skipped as long as their hash matches. Returns the number of blocks
+/// saved.
+///
+/// Only the header linkage and transaction roots are checked, blocks aren't
+/// re-executed, so the state trie has to be seeded separately.
+pub async fn import_blocks<C: Chain>(chain: &C, path: &Path) -> Result<u64> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let reader = ArchiveReader::open(BufReader::new(file), ArchiveKind::Layer2)?;
+
+    let (mut next_number, mut prev_hash) = match chain.get_latest_block().await? {
+        Some(header) => (header.number + U64::one(), header.hash()),
+        None => (U64::one(), Hash::default()),
+    };
+
+    let mut saved = 0;
+    for record in reader {
+        let block: Block = decode_rlp(&record?, &BLOCK_LIMIT)?;
+        let number = block.header.number;
+
+        if number < next_number {
+            match chain.get_header_by_number(&number).await? {
+                Some(local) if local == block.header => continue,
+                _ => return Err(anyhow!("block {} conflicts with the local chain", number)),
+            }
+        }
+        if number != next_number {
+            return Err(anyhow!("expected block {}, got {}", next_number, number));
+        }
+        block
+            .commit_signers()
+            .with_context(|| format!("block {}", number))?;
+        if block.header.prev_hash != prev_hash {
+            return Err(anyhow!(
+                "block {} doesn't extend the previous block",
+                number
+            ));
+        }
+
+        let transaction_root = Merkle::from_hashes(block.txs.iter().map(|tx| tx.tx_hash).collect())
+            .get_root_hash()
+            .unwrap_or_default();
+        if block.header.transaction_root != transaction_root {
+            return Err(anyhow!("block {} transaction root mismatch", number));
+        }
+
+        prev_hash = block.header_hash();
+        next_number = number + U64::one();
+        chain.save_block(block).await?;
+        saved += 1;
+    }
+
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::CovalentChain;
+    use crate::types::{BlockCommit, Bloom, Header, SignaturePair, H160, U128};
+
+    fn block(number: u64, prev_hash: Hash) -> Block {
+        let header = Header {
+            chain_id:         U64::one(),
+            number:           number.into(),
+            prev_hash,
+            timestamp:        U128::from(number),
+            transaction_root: Hash::zero(),
+            prev_state_root:  Hash::zero(),
+            cycles_limit:     U64::one(),
+            proposer:         H160::repeat_byte(1),
+            post_state_root:  Hash::zero(),
+            logs_bloom:       Bloom::zero(),
+            protocol_version: 1,
+            // Left out of the block hash
+            signature:        Some(SignaturePair {
+                pub_key:   vec![1].into(),
+                signature: vec![2].into(),
+            }),
+        };
+
+        Block {
+            header,
+            txs: Vec::new(),
+            commit: BlockCommit::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_onto_signed_tip() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = block(1, Hash::zero());
+        let second = block(2, first.header_hash());
+        let source = CovalentChain::new(dir.path().join("source"));
+        source.save_block(first.clone()).await.unwrap();
+        source.save_block(second).await.unwrap();
+        let path = dir.path().join("blocks.archive");
+        export_blocks(&source, U64::one(), 2u64.into(), &path)
+            .await
+            .unwrap();
+
+        let chain = CovalentChain::new(dir.path().join("chain"));
+        chain.save_block(first).await.unwrap();
+        assert_eq!(import_blocks(&chain, &path).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use derive_more::Display;
+use ophelia::{HashValue, PrivateKey, PublicKey, Signature, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use rlp_derive::{RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use share::error_code::RpcErrorCode;
+
+use crate::consensus::ValidatorSet;
+use crate::multisig::{address_of, verify_signature};
+use crate::offline::rpc_call;
+use crate::replica::attach_attestations;
+use crate::types::{Hash, Hasher, Header, SignaturePair, H160, U64};
+
+const ATTESTATION_TREE: &str = "attestation";
+// Prefixed so that an attestation can't pass as a signed header
+const ATTESTATION_DOMAIN: &[u8] = b"covalent state attestation";
+
+#[derive(Display, Clone, Debug, PartialEq, Eq)]
+pub enum AttestationError {
+    #[display(fmt = "{:?} is not a validator", _0)]
+    NotValidator(H160),
+    #[display(fmt = "Invalid attestation signature of {:?}", _0)]
+    InvalidSignature(H160),
+    #[display(fmt = "{:?} attested block {} differently before", _0, _1)]
+    Conflict(H160, U64),
+    #[display(fmt = "Block {} is attested by {} of {} validators needed", _0, _1, _2)]
+    NoQuorum(U64, usize, usize),
+    #[display(fmt = "Node keeps no attestations")]
+    Disabled,
+    #[display(fmt = "{}", _0)]
+    Store(String),
+}
+
+impl AttestationError {
+    pub fn code(&self) -> RpcErrorCode {
+        match self {
+            AttestationError::NotValidator(_) => RpcErrorCode::Unauthorized,
+            AttestationError::InvalidSignature(_) => RpcErrorCode::InvalidSignature,
+            AttestationError::Conflict(..) | AttestationError::NoQuorum(..) => {
+                RpcErrorCode::InvalidRange
+            }
+            AttestationError::Disabled => RpcErrorCode::ReadOnly,
+            AttestationError::Store(_) => RpcErrorCode::Internal,
+        }
+    }
+}
+
+impl std::error::Error for AttestationError {}
+
+impl From<sled::Error> for AttestationError {
+    fn from(e: sled::Error) -> Self {
+        AttestationError::Store(e.to_string())
+    }
+}
+
+impl From<rlp::DecoderError> for AttestationError {
+    fn from(e: rlp::DecoderError) -> Self {
+        AttestationError::Store(e.to_string())
+    }
+}
+
+/// Validators of the `ValidatorSet` sign the state after every
+/// `interval_blocks` blocks and send it to `peers`. Read replicas only
+/// serve a snapshot once a quorum of them signed the state it holds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct AttestationPolicy {
+    pub interval_blocks: u64,
+    // 0 means more than two thirds of the validators
+    pub quorum:          usize,
+    // RPC urls of the other validators
+    pub peers:           Vec<String>,
+}
+
+impl Default for AttestationPolicy {
+    fn default() -> Self {
+        AttestationPolicy {
+            interval_blocks: 100,
+            quorum:          0,
+            peers:           Vec::new(),
+        }
+    }
+}
+
+impl AttestationPolicy {
+    pub fn quorum(&self, validators: &ValidatorSet) -> usize {
+        match self.quorum {
+            0 => validators.addresses.len() * 2 / 3 + 1,
+            quorum => quorum,
+        }
+    }
+}
+
+/// State a validator saw after block `number`.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Attestation {
+    pub number:     U64,
+    pub block_hash: Hash,
+    pub state_root: Hash,
+}
+
+impl Attestation {
+    pub fn of(header: &Header) -> Self {
+        Attestation {
+            number:     header.number,
+            block_hash: header.hash(),
+            state_root: header.state_root(),
+        }
+    }
+
+    pub fn sig_hash(&self) -> Hash {
+        Hasher::digest_([ATTESTATION_DOMAIN, &rlp::encode(self)].concat())
+    }
+}
+
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct SignedAttestation {
+    pub attestation: Attestation,
+    pub validator:   H160,
+    pub signature:   SignaturePair,
+}
+
+impl SignedAttestation {
+    pub fn verify(&self) -> bool {
+        let pair = &self.signature;
+        address_of(&pair.pub_key) == self.validator
+            && verify_signature(&self.attestation.sig_hash(), &pair.pub_key, &pair.signature)
+    }
+}
+
+/// Fails unless `quorum` validators of `validators` attested the state
+/// after `header` in `attestations`. Signatures from outside the set,
+/// invalid ones and repeated validators don't count.
+pub fn verify_quorum(
+    validators: &ValidatorSet,
+    quorum: usize,
+    header: &Header,
+    attestations: &[SignedAttestation],
+) -> Result<(), AttestationError> {
+    let attested = Attestation::of(header);
+    let voters = { attestations.iter() }
+        .filter(|signed| signed.attestation == attested)
+        .filter(|signed| validators.addresses.contains(&signed.validator) && signed.verify())
+        .map(|signed| signed.validator)
+        .collect::<HashSet<_>>();
+
+    if voters.len() < quorum {
+        return Err(AttestationError::NoQuorum(
+            header.number,
+            voters.len(),
+            quorum,
+        ));
+    }
+    Ok(())
+}
+
+/// Signs this node's attestations while it's a validator and keeps those of
+/// the others, one per validator and block. Shipped snapshots get the
+/// attestations of their block as they come in.
+pub struct AttestationPool {
+    attestations: sled::Tree,
+    validators:   ValidatorSet,
+    policy:       AttestationPolicy,
+    key:          Option<Secp256k1PrivateKey>,
+    snapshots:    Option<PathBuf>,
+}
+
+impl AttestationPool {
+    pub fn new(
+        db: &sled::Db,
+        validators: ValidatorSet,
+        policy: AttestationPolicy,
+    ) -> Result<Self, AttestationError> {
+        Ok(AttestationPool {
+            attestations: db.open_tree(ATTESTATION_TREE)?,
+            validators,
+            policy,
+            key: None,
+            snapshots: None,
+        })
+    }
+
+    /// Attest with `key`, unless it's not the key of a validator.
+    pub fn with_key(mut self, key: Option<Secp256k1PrivateKey>) -> Self {
+        self.key = key.filter(|key| {
+            let address = address_of(&key.pub_key().to_bytes());
+            self.validators.addresses.contains(&address)
+        });
+        self
+    }
+
+    pub fn with_snapshot_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.snapshots = dir;
+        self
+    }
+
+    /// Sign the state after `header` if it's due, keep it and send it to
+    /// the other validators.
+    pub fn attest(&self, header: &Header) -> Result<Option<SignedAttestation>, AttestationError> {
+        let due = header.number.as_u64().is_multiple_of(self.policy.interval_blocks);
+        let key = match &self.key {
+            Some(key) if due => key,
+            _ => return Ok(None),
+        };
+
+        let attestation = Attestation::of(header);
+        let signature =
+            key.sign_message(&HashValue::from_bytes_unchecked(attestation.sig_hash().0));
+        let pub_key = key.pub_key().to_bytes();
+        let signed = SignedAttestation {
+            validator: address_of(&pub_key),
+            signature: SignaturePair {
+                pub_key,
+                signature: signature.to_bytes(),
+            },
+            attestation,
+        };
+        self.insert(&signed)?;
+        for peer in self.policy.peers.iter().cloned() {
+            tokio::spawn(gossip(peer, signed.clone()));
+        }
+
+        Ok(Some(signed))
+    }
+
+    /// Keep an attestation of another validator. Returns false if it's
+    /// already kept, and fails on one from outside the validator set or
+    /// conflicting with what the validator attested before.
+    pub fn receive(&self, signed: SignedAttestation) -> Result<bool, AttestationError> {
+        let validator = signed.validator;
+        if !self.validators.addresses.contains(&validator) {
+            return Err(AttestationError::NotValidator(validator));
+        }
+        if !signed.verify() {
+            return Err(AttestationError::InvalidSignature(validator));
+        }
+
+        let key = attestation_key(signed.attestation.number, &validator);
+        if let Some(known) = self.attestations.get(key)? {
+            let known: SignedAttestation = rlp::decode(&known)?;
+            if known.attestation != signed.attestation {
+                return Err(AttestationError::Conflict(
+                    validator,
+                    known.attestation.number,
+                ));
+            }
+            return Ok(false);
+        }
+        self.insert(&signed)?;
+
+        Ok(true)
+    }
+
+    /// Every attestation kept for block `number`.
+    pub fn attestations(&self, number: U64) -> Result<Vec<SignedAttestation>, AttestationError> {
+        { self.attestations.scan_prefix(number.as_u64().to_be_bytes()) }
+            .map(|entry| Ok(rlp::decode(&entry?.1)?))
+            .collect()
+    }
+
+    /// Write the attestations of block `number` next to its snapshot once
+    /// it's shipped.
+    pub fn publish(&self, number: U64) -> Result<(), AttestationError> {
+        if let Some(dir) = &self.snapshots {
+            let attestations = self.attestations(number)?;
+            attach_attestations(dir, number.as_u64(), &attestations)
+                .map_err(|e| AttestationError::Store(format!("{:#}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn insert(&self, signed: &SignedAttestation) -> Result<(), AttestationError> {
+        let number = signed.attestation.number;
+        self.attestations.insert(
+            attestation_key(number, &signed.validator),
+            rlp::encode(signed).to_vec(),
+        )?;
+        self.publish(number)
+    }
+}
+
+// Block first, so the attestations of a block are next to each other
+fn attestation_key(number: U64, validator: &H160) -> Vec<u8> {
+    [&number.as_u64().to_be_bytes(), validator.as_bytes()].concat()
+}
+
+// Peers down or behind miss the attestation, they get the next one
+async fn gossip(peer: String, signed: SignedAttestation) {
+    if let Err(e) = rpc_call(&peer, "submit_attestation", json!([signed])).await {
+        println!("[attestation] sending to {} failed: {:#}", peer, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dev::DevWallet;
+    use crate::types::U128;
+
+    fn header(number: u64, state_root: Hash) -> Header {
+        Header {
+            chain_id:         U64::one(),
+            number:           number.into(),
+            prev_hash:        Hash::zero(),
+            timestamp:        U128::zero(),
+            transaction_root: Hash::zero(),
+            prev_state_root:  Hash::zero(),
+            cycles_limit:     U64::zero(),
+            proposer:         H160::zero(),
+            post_state_root:  state_root,
+            logs_bloom:       Default::default(),
+            protocol_version: 1,
+            signature:        None,
+        }
+    }
+
+    #[test]
+    fn test_attestation_quorum() {
+        let dir = tempfile::tempdir().unwrap();
+        let wallets = [0, 1, 2].map(|i| DevWallet::derive(i).unwrap());
+        let validators = ValidatorSet {
+            addresses: wallets.iter().map(|wallet| wallet.address).collect(),
+            ..Default::default()
+        };
+        let policy = AttestationPolicy {
+            interval_blocks: 10,
+            ..Default::default()
+        };
+        assert_eq!(policy.quorum(&validators), 3);
+        let pools = { wallets.iter().enumerate() }
+            .map(|(i, wallet)| {
+                let db = sled::open(dir.path().join(i.to_string())).unwrap();
+                AttestationPool::new(&db, validators.clone(), policy.clone())
+                    .unwrap()
+                    .with_key(Some(wallet.key.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        let root = Hash::repeat_byte(7);
+        assert_eq!(pools[0].attest(&header(9, root)), Ok(None));
+        let mut gossip = Vec::new();
+        for pool in pools.iter().take(2) {
+            gossip.push(pool.attest(&header(10, root)).unwrap().unwrap());
+        }
+        assert_eq!(pools[2].receive(gossip[0].clone()), Ok(true));
+        assert_eq!(pools[2].receive(gossip[0].clone()), Ok(false));
+
+        // Two of three validators aren't a quorum, and a validator counts once
+        let attested = header(10, root);
+        assert_eq!(
+            verify_quorum(&validators, 3, &attested, &gossip),
+            Err(AttestationError::NoQuorum(10.into(), 2, 3))
+        );
+        gossip.push(pools[2].attest(&attested).unwrap().unwrap());
+        assert_eq!(verify_quorum(&validators, 3, &attested, &gossip), Ok(()));
+        let repeated = [&gossip[..2], &gossip[..2]].concat();
+        assert!(verify_quorum(&validators, 3, &attested, &repeated).is_err());
+        // Attestations of another state don't count for this one
+        assert!(verify_quorum(&validators, 1, &header(10, Hash::zero()), &gossip).is_err());
+
+        let conflict = pools[0].attest(&header(10, Hash::zero())).unwrap().unwrap();
+        assert_eq!(
+            pools[2].receive(conflict),
+            Err(AttestationError::Conflict(wallets[0].address, 10.into()))
+        );
+        let mut forged = gossip[1].clone();
+        forged.validator = wallets[0].address;
+        assert_eq!(
+            pools[2].receive(forged),
+            Err(AttestationError::InvalidSignature(wallets[0].address))
+        );
+        assert_eq!(pools[2].attestations(10.into()).unwrap().len(), 2);
+
+        let outsider = DevWallet::derive(3).unwrap();
+        let db = sled::open(dir.path().join("outsider")).unwrap();
+        let pool = AttestationPool::new(&db, validators.clone(), policy)
+            .unwrap()
+            .with_key(Some(outsider.key));
+        assert_eq!(pool.attest(&attested), Ok(None));
+    }
+}
@@ -0,0 +1,258 @@
+use derive_more::Display;
+use ophelia::{PublicKey, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use proof::commitment::Commitment;
+use serde::{Deserialize, Serialize};
+use share::error_code::RpcErrorCode;
+
+use crate::multisig::address_of;
+use crate::offline::UnsignedTransaction;
+use crate::types::{
+    Bytes, Hash, Hasher, RawTransaction, SignedTransaction, TokenAction, TransactionRequest, H160,
+    U256, U64,
+};
+
+const COMMITMENT_TREE: &str = "layer3_commitment";
+// Transaction paying each withdrawal batch, by batch id
+const PAYOUT_TREE: &str = "layer3_payout";
+
+#[derive(Display, Clone, Debug, PartialEq, Eq)]
+pub enum BridgeError {
+    #[display(fmt = "Invalid commitment: {}", _0)]
+    InvalidCommitment(String),
+    // The next commitment has to start at this block
+    #[display(fmt = "Commitment doesn't start at block {}", _0)]
+    Gap(u64),
+    #[display(fmt = "Blocks up to {} are committed differently", _0)]
+    Conflict(u64),
+    #[display(fmt = "Node pays no layer3 withdrawals")]
+    NoPayer,
+    #[display(fmt = "Invalid withdrawal batch: {}", _0)]
+    InvalidBatch(String),
+    #[display(fmt = "Payout not signed: {}", _0)]
+    Sign(String),
+    #[display(fmt = "{}", _0)]
+    Store(String),
+}
+
+impl BridgeError {
+    pub fn code(&self) -> RpcErrorCode {
+        match self {
+            BridgeError::Sign(_) | BridgeError::Store(_) => RpcErrorCode::Internal,
+            BridgeError::Gap(_) | BridgeError::Conflict(_) => RpcErrorCode::InvalidRange,
+            BridgeError::NoPayer => RpcErrorCode::ReadOnly,
+            BridgeError::InvalidCommitment(_) | BridgeError::InvalidBatch(_) => {
+                RpcErrorCode::InvalidTransaction
+            }
+        }
+    }
+}
+
+impl From<sled::Error> for BridgeError {
+    fn from(e: sled::Error) -> Self {
+        BridgeError::Store(e.to_string())
+    }
+}
+
+/// A commitment as `Commitment::to_bytes` encodes it, in hex.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EncodedCommitment(#[serde(with = "crate::serde_hex")] pub Bytes);
+
+/// One transfer of a batch, paying the withdrawals `request_ids` together.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BatchedTransfer {
+    pub recipient:   H160,
+    pub token_id:    U256,
+    pub amount:      U256,
+    pub request_ids: Vec<Hash>,
+}
+
+/// Withdrawals of the channels layer3 closed in `block_number`, the way
+/// its relayer sends them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WithdrawalBatch {
+    pub id:           Hash,
+    pub block_number: u64,
+    pub transfers:    Vec<BatchedTransfer>,
+}
+
+struct Payer {
+    key:      Secp256k1PrivateKey,
+    address:  H160,
+    chain_id: U64,
+}
+
+/// Layer3 state its operator hands over on the operator RPC. Checkpoint
+/// commitments are kept in block order, each starting right after the one
+/// before, so together they cover layer3 from its first block. Withdrawal
+/// batches are paid by minting from the payer key, once per batch.
+pub struct Layer3Bridge {
+    commitments: sled::Tree,
+    payouts:     sled::Tree,
+    payer:       Option<Payer>,
+    // Held from picking a nonce until the payout is queued
+    paying:      tokio::sync::Mutex<()>,
+}
+
+impl Layer3Bridge {
+    pub fn new(db: &sled::Db) -> Result<Self, BridgeError> {
+        Ok(Layer3Bridge {
+            commitments: db.open_tree(COMMITMENT_TREE)?,
+            payouts:     db.open_tree(PAYOUT_TREE)?,
+            payer:       None,
+            paying:      tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn with_payer(mut self, chain_id: U64, key: Secp256k1PrivateKey) -> Self {
+        let address = address_of(&key.pub_key().to_bytes());
+        self.payer = Some(Payer {
+            key,
+            address,
+            chain_id,
+        });
+        self
+    }
+
+    pub fn payer(&self) -> Result<H160, BridgeError> {
+        { self.payer.as_ref() }
+            .map(|payer| payer.address)
+            .ok_or(BridgeError::NoPayer)
+    }
+
+    /// Keep a commitment in the `Commitment::to_bytes` encoding. The same
+    /// commitment can be submitted again and gets the same hash.
+    pub fn submit_commitment(&self, bytes: Bytes) -> Result<Hash, BridgeError> {
+        let commitment = Commitment::from_bytes(&bytes)
+            .map_err(|e| BridgeError::InvalidCommitment(format!("{:?}", e)))?;
+        if let Some(known) = self.commitments.get(commitment.to_block.to_be_bytes())? {
+            if known.as_ref() != bytes.as_ref() {
+                return Err(BridgeError::Conflict(commitment.to_block));
+            }
+            return Ok(Hasher::digest_(bytes));
+        }
+
+        let next = match self.commitments.last()? {
+            Some((to_block, _)) => decode_block_number(&to_block) + 1,
+            None => 1,
+        };
+        if commitment.from_block != next {
+            return Err(BridgeError::Gap(next));
+        }
+
+        self.commitments
+            .insert(commitment.to_block.to_be_bytes(), bytes.as_ref())?;
+        Ok(Hasher::digest_(bytes))
+    }
+
+    /// Held while paying a batch, so no two payouts take the same nonce.
+    pub async fn lock_payer(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.paying.lock().await
+    }
+
+    /// Transaction a batch was paid in, if it was.
+    pub fn paid(&self, batch_id: &Hash) -> Result<Option<Hash>, BridgeError> {
+        let tx_hash = self.payouts.get(batch_id.as_bytes())?;
+        Ok(tx_hash.map(|tx_hash| Hash::from_slice(&tx_hash)))
+    }
+
+    /// One mint per transfer of the batch, signed by the payer at `nonce`.
+    pub fn payout_transaction(
+        &self,
+        batch: &WithdrawalBatch,
+        nonce: u64,
+    ) -> Result<SignedTransaction, BridgeError> {
+        let payer = self.payer.as_ref().ok_or(BridgeError::NoPayer)?;
+        if batch.transfers.is_empty() {
+            return Err(BridgeError::InvalidBatch("no transfers".to_owned()));
+        }
+
+        let requests = { batch.transfers.iter() }
+            .map(|transfer| {
+                let mut token_id = Hash::zero();
+                transfer.token_id.to_big_endian(token_id.as_bytes_mut());
+                TransactionRequest {
+                    address: transfer.recipient,
+                    token_id,
+                    amount: transfer.amount,
+                    action: TokenAction::Mint,
+                    to: None,
+                }
+            })
+            .collect();
+        let raw = RawTransaction {
+            chain_id:     payer.chain_id,
+            cycles_price: 1u64.into(),
+            cycles_limit: 1000u64.into(),
+            nonce:        RawTransaction::nonce_of(nonce),
+            requests,
+            sender:       payer.address,
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        };
+
+        UnsignedTransaction::new(raw)
+            .sign(&payer.key)
+            .map_err(|e| BridgeError::Sign(e.to_string()))
+    }
+
+    pub fn record_payout(&self, batch_id: &Hash, tx_hash: &Hash) -> Result<(), BridgeError> {
+        self.payouts
+            .insert(batch_id.as_bytes(), tx_hash.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn decode_block_number(key: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(key);
+    u64::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use proof::commitment::ProverType;
+
+    use super::*;
+
+    fn commitment(from_block: u64, to_block: u64) -> Bytes {
+        Commitment {
+            from_block,
+            to_block,
+            state_root:   [to_block as u8; 32].into(),
+            receipt_root: Default::default(),
+            da_reference: Default::default(),
+            prover:       ProverType::Optimistic,
+        }
+        .to_bytes()
+        .into()
+    }
+
+    #[test]
+    fn test_chained_commitments() {
+        let dir = tempfile::tempdir().unwrap();
+        let bridge = Layer3Bridge::new(&sled::open(dir.path()).unwrap()).unwrap();
+
+        assert_eq!(
+            bridge.submit_commitment(commitment(2, 4)),
+            Err(BridgeError::Gap(1))
+        );
+        let hash = bridge.submit_commitment(commitment(1, 4)).unwrap();
+        // Submitted again after a lost answer
+        assert_eq!(bridge.submit_commitment(commitment(1, 4)), Ok(hash));
+        assert_eq!(
+            bridge.submit_commitment(commitment(3, 4)),
+            Err(BridgeError::Conflict(4))
+        );
+        assert_eq!(
+            bridge.submit_commitment(commitment(4, 6)),
+            Err(BridgeError::Gap(5))
+        );
+        bridge.submit_commitment(commitment(5, 6)).unwrap();
+        assert!(matches!(
+            bridge.submit_commitment(Bytes::from_static(b"\x02")),
+            Err(BridgeError::InvalidCommitment(_))
+        ));
+    }
+}
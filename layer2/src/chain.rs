@@ -1,17 +1,41 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use rlp::{Decodable, Encodable, Rlp};
+use serde::{Deserialize, Serialize};
 use sled::Db;
 
-use crate::types::{Block, Hash, Header, SignedTransaction, U64};
+use crate::merkle::Merkle;
+use crate::replica::{export_db, Snapshot};
+use crate::types::{
+    Block, BlockUsage, Hash, Header, LogEntry, SignedTransaction, TransactionReceipt, U64,
+};
 
 const LATEST_HEADER_KEY: &[u8] = b"latest_block";
+// Full blocks saved before headers and bodies were split
 const BLOCK_TREE: &[u8] = b"block_tree";
+const HEADER_TREE: &[u8] = b"header_tree";
+const BODY_TREE: &[u8] = b"body_tree";
 const NUMBER_HASH_TREE: &[u8] = b"number_hash_tree";
 const TX_TREE: &[u8] = b"transaction_tree";
+const USAGE_TREE: &[u8] = b"block_usage_tree";
+const RECEIPT_TREE: &[u8] = b"receipt_tree";
+const LOG_TREE: &[u8] = b"log_tree";
+const PRUNED_TIP_KEY: &[u8] = b"pruned_tip";
+const TRIMMED_TIP_KEY: &[u8] = b"receipts_trimmed_tip";
+const KNOWN_TREES: [&[u8]; 8] = [
+    BLOCK_TREE,
+    HEADER_TREE,
+    BODY_TREE,
+    NUMBER_HASH_TREE,
+    TX_TREE,
+    USAGE_TREE,
+    RECEIPT_TREE,
+    LOG_TREE,
+];
 
 #[async_trait]
 pub trait Chain: Sync + Send {
@@ -21,21 +45,103 @@ pub trait Chain: Sync + Send {
 
     async fn get_block_by_number(&self, number: &U64) -> Result<Option<Block>>;
 
-    async fn get_latest_block(&self) -> Result<Header>;
+    async fn get_header_by_hash(&self, hash: &Hash) -> Result<Option<Header>>;
+
+    async fn get_header_by_number(&self, number: &U64) -> Result<Option<Header>>;
+
+    /// Transactions of the blocks whose transaction root is `hash`.
+    async fn get_block_body(&self, hash: &Hash) -> Result<Option<Vec<SignedTransaction>>>;
+
+    async fn get_latest_block(&self) -> Result<Option<Header>>;
 
     async fn get_tx_by_hash(&self, hash: &Hash) -> Result<Option<SignedTransaction>>;
+
+    async fn get_block_usage(&self, number: &U64) -> Result<Option<BlockUsage>>;
+
+    /// Receipts are saved before the block they belong to, all receipts of
+    /// a block at once. Their logs are indexed by block too.
+    async fn save_receipts(&self, receipts: Vec<TransactionReceipt>) -> Result<()>;
+
+    async fn get_receipt(&self, tx_hash: &Hash) -> Result<Option<TransactionReceipt>>;
+
+    /// Logs of block `number` in block order, none for unknown blocks.
+    async fn get_block_logs(&self, number: &U64) -> Result<Vec<LogEntry>>;
+
+    /// Receipts and logs of every block up to this one are trimmed.
+    async fn receipts_trimmed_tip(&self) -> Result<U64>;
 }
 
 pub struct CovalentChain {
     db: Arc<Db>,
 }
 
+/// How much receipt and log history is kept. Receipts and logs of the
+/// oldest blocks are trimmed while either bound is exceeded, the blocks and
+/// transactions stay.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    // By block timestamp, 0 keeps them at any age
+    pub max_age_secs:  u64,
+    // Receipts and logs together, 0 is no bound
+    pub max_bytes:     u64,
+    pub interval_secs: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age_secs:  0,
+            max_bytes:     0,
+            interval_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TrimReport {
+    pub trimmed_tip: U64,
+    // Trimmed by this run
+    pub blocks:      u64,
+    pub bytes:       u64,
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    // Transactions of every block up to this one are pruned
+    pub pruned_tip:      U64,
+    pub transactions:    u64,
+    pub dropped_trees:   Vec<String>,
+    pub reclaimed_bytes: u64,
+}
+
 #[async_trait]
 impl Chain for CovalentChain {
+    /// Headers are stored with the block commit, bodies under their
+    /// transaction root, so blocks with the same transactions share one.
     async fn save_block(&self, block: Block) -> Result<()> {
-        let block_t = self.db.open_tree(BLOCK_TREE)?;
-        block_t.insert(block.header_hash(), block.rlp_bytes().to_vec())?;
-        block_t.insert(LATEST_HEADER_KEY, block.header.rlp_bytes().to_vec())?;
+        let body_hash = body_hash(&block.txs);
+        if body_hash != block.header.transaction_root {
+            return Err(anyhow!(
+                "block {} transaction root doesn't match its transactions",
+                block.header.number
+            ));
+        }
+        self.db
+            .open_tree(BODY_TREE)?
+            .insert(body_hash, rlp::encode_list(&block.txs).to_vec())?;
+
+        let stored = Block {
+            header: block.header.clone(),
+            txs:    Vec::new(),
+            commit: block.commit.clone(),
+        };
+        self.db
+            .open_tree(HEADER_TREE)?
+            .insert(block.header_hash(), stored.rlp_bytes().to_vec())?;
+        self.db
+            .open_tree(BLOCK_TREE)?
+            .insert(LATEST_HEADER_KEY, block.header.rlp_bytes().to_vec())?;
 
         self.db.open_tree(NUMBER_HASH_TREE)?.insert(
             u64_le_bytes(&block.header.number),
@@ -47,36 +153,45 @@ impl Chain for CovalentChain {
             tx_t.insert(tx.tx_hash, tx.rlp_bytes().to_vec())?;
         }
 
+        self.db.open_tree(USAGE_TREE)?.insert(
+            u64_le_bytes(&block.header.number),
+            BlockUsage::of(&block).rlp_bytes().to_vec(),
+        )?;
+
         Ok(())
     }
 
     async fn get_block_by_hash(&self, hash: &Hash) -> Result<Option<Block>> {
-        match self.db.open_tree(BLOCK_TREE)?.get(hash)? {
+        self.block(hash)
+    }
+
+    async fn get_block_by_number(&self, number: &U64) -> Result<Option<Block>> {
+        match self.hash_of(number)? {
+            Some(hash) => self.block(&hash),
             None => Ok(None),
-            Some(raw) => Ok(Some(Block::decode(&Rlp::new(raw.as_ref()))?)),
         }
     }
 
-    async fn get_block_by_number(&self, number: &U64) -> Result<Option<Block>> {
-        if let Some(raw) = self
-            .db
-            .open_tree(NUMBER_HASH_TREE)?
-            .get(u64_le_bytes(number))?
-        {
-            return self.get_block_by_hash(&Hash::from_slice(&raw)).await;
+    async fn get_header_by_hash(&self, hash: &Hash) -> Result<Option<Header>> {
+        Ok(self.stored_block(hash)?.map(|(block, _)| block.header))
+    }
+
+    async fn get_header_by_number(&self, number: &U64) -> Result<Option<Header>> {
+        match self.hash_of(number)? {
+            Some(hash) => self.get_header_by_hash(&hash).await,
+            None => Ok(None),
         }
+    }
 
-        Ok(None)
+    async fn get_block_body(&self, hash: &Hash) -> Result<Option<Vec<SignedTransaction>>> {
+        self.body(hash)
     }
 
-    async fn get_latest_block(&self) -> Result<Header> {
-        let raw = self
-            .db
-            .open_tree(BLOCK_TREE)?
-            .get(LATEST_HEADER_KEY)?
-            .unwrap();
-        let ret = Header::decode(&Rlp::new(raw.as_ref()))?;
-        Ok(ret)
+    async fn get_latest_block(&self) -> Result<Option<Header>> {
+        match self.db.open_tree(BLOCK_TREE)?.get(LATEST_HEADER_KEY)? {
+            None => Ok(None),
+            Some(raw) => Ok(Some(Header::decode(&Rlp::new(raw.as_ref()))?)),
+        }
     }
 
     async fn get_tx_by_hash(&self, hash: &Hash) -> Result<Option<SignedTransaction>> {
@@ -85,6 +200,60 @@ impl Chain for CovalentChain {
             Some(raw) => Ok(Some(SignedTransaction::decode(&Rlp::new(raw.as_ref()))?)),
         }
     }
+
+    /// Blocks saved before usage was recorded get it computed on the fly.
+    async fn get_block_usage(&self, number: &U64) -> Result<Option<BlockUsage>> {
+        if let Some(raw) = self.db.open_tree(USAGE_TREE)?.get(u64_le_bytes(number))? {
+            return Ok(Some(BlockUsage::decode(&Rlp::new(raw.as_ref()))?));
+        }
+
+        let block = self.get_block_by_number(number).await?;
+        Ok(block.as_ref().map(BlockUsage::of))
+    }
+
+    async fn save_receipts(&self, receipts: Vec<TransactionReceipt>) -> Result<()> {
+        let receipt_t = self.db.open_tree(RECEIPT_TREE)?;
+        for receipt in receipts.iter() {
+            receipt_t.insert(receipt.tx_hash, receipt.rlp_bytes().to_vec())?;
+        }
+
+        let logs = LogEntry::of_receipts(&receipts);
+        if let Some(first) = logs.first() {
+            self.db.open_tree(LOG_TREE)?.insert(
+                u64_le_bytes(&first.block_number),
+                rlp::encode_list(&logs).to_vec(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_receipt(&self, tx_hash: &Hash) -> Result<Option<TransactionReceipt>> {
+        match self.db.open_tree(RECEIPT_TREE)?.get(tx_hash)? {
+            None => Ok(None),
+            Some(raw) => Ok(Some(TransactionReceipt::decode(&Rlp::new(raw.as_ref()))?)),
+        }
+    }
+
+    async fn get_block_logs(&self, number: &U64) -> Result<Vec<LogEntry>> {
+        match self.db.open_tree(LOG_TREE)?.get(u64_le_bytes(number))? {
+            None => Ok(Vec::new()),
+            Some(raw) => Ok(Rlp::new(raw.as_ref()).as_list()?),
+        }
+    }
+
+    async fn receipts_trimmed_tip(&self) -> Result<U64> {
+        match self.db.open_tree(BLOCK_TREE)?.get(TRIMMED_TIP_KEY)? {
+            Some(raw) => Ok(U64::from_little_endian(&raw)),
+            None => Ok(U64::zero()),
+        }
+    }
+}
+
+impl Snapshot for CovalentChain {
+    fn snapshot_to(&self, path: &Path) -> Result<()> {
+        export_db(&self.db, path)
+    }
 }
 
 impl CovalentChain {
@@ -93,6 +262,185 @@ impl CovalentChain {
             db: Arc::new(sled::open(path).unwrap()),
         }
     }
+
+    fn hash_of(&self, number: &U64) -> Result<Option<Hash>> {
+        let raw = self
+            .db
+            .open_tree(NUMBER_HASH_TREE)?
+            .get(u64_le_bytes(number))?;
+        Ok(raw.map(|raw| Hash::from_slice(&raw)))
+    }
+
+    // The block without its transactions and whether they still have to
+    // be loaded from the body tree
+    fn stored_block(&self, hash: &Hash) -> Result<Option<(Block, bool)>> {
+        if let Some(raw) = self.db.open_tree(HEADER_TREE)?.get(hash)? {
+            return Ok(Some((Block::decode(&Rlp::new(raw.as_ref()))?, true)));
+        }
+
+        match self.db.open_tree(BLOCK_TREE)?.get(hash)? {
+            Some(raw) => Ok(Some((Block::decode(&Rlp::new(raw.as_ref()))?, false))),
+            None => Ok(None),
+        }
+    }
+
+    fn body(&self, hash: &Hash) -> Result<Option<Vec<SignedTransaction>>> {
+        match self.db.open_tree(BODY_TREE)?.get(hash)? {
+            Some(raw) => Ok(Some(Rlp::new(raw.as_ref()).as_list()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn block(&self, hash: &Hash) -> Result<Option<Block>> {
+        let (mut block, split) = match self.stored_block(hash)? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        if split {
+            block.txs = { self.body(&block.header.transaction_root)? }
+                .ok_or_else(|| anyhow!("body of block {} not found", block.header.number))?;
+        }
+
+        Ok(Some(block))
+    }
+
+    /// Drop the transaction index and receipts of blocks older than the latest
+    /// `keep_blocks`, blocks themselves are kept so they can still be served
+    /// and archived. Trees the chain doesn't know of are left overs of older
+    /// versions and get dropped too.
+    pub fn collect_garbage(&self, keep_blocks: u64) -> Result<GcReport> {
+        let size_before = self.db.size_on_disk()?;
+        let block_t = self.db.open_tree(BLOCK_TREE)?;
+        let mut report = GcReport {
+            pruned_tip: match block_t.get(PRUNED_TIP_KEY)? {
+                Some(raw) => U64::from_little_endian(&raw),
+                None => U64::zero(),
+            },
+            ..Default::default()
+        };
+
+        let latest = match block_t.get(LATEST_HEADER_KEY)? {
+            Some(raw) => Header::decode(&Rlp::new(raw.as_ref()))?.number,
+            None => U64::zero(),
+        };
+        let prune_to = latest.saturating_sub(keep_blocks.into());
+
+        let tx_t = self.db.open_tree(TX_TREE)?;
+        let receipt_t = self.db.open_tree(RECEIPT_TREE)?;
+        while report.pruned_tip < prune_to {
+            let number = report.pruned_tip + U64::one();
+            if let Some(hash) = self.hash_of(&number)? {
+                if let Some(block) = self.block(&hash)? {
+                    for tx in block.txs.iter() {
+                        tx_t.remove(tx.tx_hash)?;
+                        receipt_t.remove(tx.tx_hash)?;
+                    }
+                    report.transactions += block.txs.len() as u64;
+                }
+            }
+
+            block_t.insert(PRUNED_TIP_KEY, u64_le_bytes(&number))?;
+            report.pruned_tip = number;
+        }
+
+        let default_tree = self.db.name();
+        for name in self.db.tree_names() {
+            if name == default_tree || KNOWN_TREES.contains(&name.as_ref()) {
+                continue;
+            }
+            self.db.drop_tree(&name)?;
+            report
+                .dropped_trees
+                .push(String::from_utf8_lossy(&name).into_owned());
+        }
+
+        self.db.flush()?;
+        report.reclaimed_bytes = size_before.saturating_sub(self.db.size_on_disk()?);
+        Ok(report)
+    }
+}
+
+impl CovalentChain {
+    /// Trim receipts and logs of the oldest blocks until they're within
+    /// `policy` at unix time `now_ms`. Only bytes of the stored values
+    /// count towards `max_bytes`.
+    pub async fn trim_receipts(&self, policy: &RetentionPolicy, now_ms: u64) -> Result<TrimReport> {
+        let mut report = TrimReport {
+            trimmed_tip: self.receipts_trimmed_tip().await?,
+            ..Default::default()
+        };
+        let latest = match self.get_latest_block().await? {
+            Some(header) => header.number,
+            None => return Ok(report),
+        };
+
+        let receipt_t = self.db.open_tree(RECEIPT_TREE)?;
+        let log_t = self.db.open_tree(LOG_TREE)?;
+        let mut stored_bytes = 0;
+        if policy.max_bytes != 0 {
+            for tree in [&receipt_t, &log_t] {
+                for entry in tree.iter() {
+                    stored_bytes += entry?.1.len() as u64;
+                }
+            }
+        }
+
+        let max_age_ms = policy.max_age_secs.saturating_mul(1000);
+        while report.trimmed_tip < latest {
+            let number = report.trimmed_tip + U64::one();
+            let block = match self.get_block_by_number(&number).await? {
+                Some(block) => block,
+                None => break,
+            };
+            let expired = max_age_ms != 0
+                && block.header.timestamp.as_u64().saturating_add(max_age_ms) < now_ms;
+            let oversized = policy.max_bytes != 0 && stored_bytes > policy.max_bytes;
+            if !expired && !oversized {
+                break;
+            }
+
+            let mut bytes = 0;
+            for tx in block.txs.iter() {
+                bytes += { receipt_t.remove(tx.tx_hash)? }.map_or(0, |raw| raw.len() as u64);
+            }
+            bytes += { log_t.remove(u64_le_bytes(&number))? }.map_or(0, |raw| raw.len() as u64);
+            self.db
+                .open_tree(BLOCK_TREE)?
+                .insert(TRIMMED_TIP_KEY, u64_le_bytes(&number))?;
+
+            stored_bytes = stored_bytes.saturating_sub(bytes);
+            report.trimmed_tip = number;
+            report.blocks += 1;
+            report.bytes += bytes;
+        }
+
+        Ok(report)
+    }
+
+    pub async fn trim_receipts_every(self: Arc<Self>, policy: RetentionPolicy) {
+        let mut timer = tokio::time::interval(Duration::from_secs(policy.interval_secs.max(1)));
+        loop {
+            timer.tick().await;
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            match self.trim_receipts(&policy, now_ms).await {
+                Ok(report) if report.blocks > 0 => println!(
+                    "[retention] trimmed receipts of blocks up to {}, {} bytes",
+                    report.trimmed_tip, report.bytes
+                ),
+                Ok(_) => (),
+                Err(e) => println!("[retention] trimming receipts failed: {:#}", e),
+            }
+        }
+    }
+}
+
+fn body_hash(txs: &[SignedTransaction]) -> Hash {
+    Merkle::from_hashes(txs.iter().map(|tx| tx.tx_hash).collect())
+        .get_root_hash()
+        .unwrap_or_default()
 }
 
 fn u64_le_bytes(input: &U64) -> Vec<u8> {
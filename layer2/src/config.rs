@@ -1,22 +1,380 @@
-use std::fs::File;
+use std::collections::HashSet;
+use std::fs::{self, File};
 use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use log::LevelFilter;
+use ophelia::{PublicKey, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use share::front::{RpcFront, TlsConfig};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 
-use crate::types::{H160, U64};
+use crate::attestation::AttestationPolicy;
+use crate::chain::RetentionPolicy;
+use crate::consensus::{BlockPolicy, ValidatorSet};
+use crate::cors::cors_layer;
+use crate::faucet::FaucetConfig;
+use crate::genesis::{GenesisToken, TokenRegistry};
+use crate::mempool::TX_CYCLE_LIMIT;
+use crate::multisig::{address_of, MAX_SIGNERS};
+use crate::offline::read_private_key;
+use crate::replica::{SnapshotPolicy, SnapshotQuorum};
+use crate::trie::FlushPolicy;
+use crate::types::{Hash, H160, U64};
+use crate::upgrade::Upgrades;
+
+const ENV_PREFIX: &str = "COVALENT_";
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Config {
-    pub db_path:  PathBuf,
-    pub rpc_uri:  SocketAddr,
-    pub address:  H160,
-    pub chain_id: u64,
+    pub db_path:           PathBuf,
+    pub rpc_uri:           SocketAddr,
+    pub address:           H160,
+    // Hex key of `address` the node signs its blocks with, nodes proposing
+    // blocks don't start without it
+    #[serde(default)]
+    pub proposer_key_path: Option<PathBuf>,
+    pub chain_id:          u64,
+    // Token cycles are paid in, no fees are charged when unset
+    #[serde(default)]
+    pub fee_token:         Option<Hash>,
+    // Genesis token list, any token is accepted when empty
+    #[serde(default)]
+    pub tokens:            Vec<GenesisToken>,
+    #[serde(default)]
+    pub runtime:           RuntimeConfig,
+    #[serde(default)]
+    pub block:             BlockPolicy,
+    // Proposers taking turns, `address` proposes every block when empty
+    #[serde(default)]
+    pub validators:        ValidatorSet,
+    #[serde(default)]
+    pub trie_flush:        FlushPolicy,
+    // Snapshots for read replicas, none are written when unset
+    #[serde(default)]
+    pub snapshots:         Option<SnapshotPolicy>,
+    // Validator attestations of the state, replicas serve snapshots without
+    // a quorum of them when unset
+    #[serde(default)]
+    pub attestation:       Option<AttestationPolicy>,
+    // Loopback address of the operator transaction RPC, off when unset.
+    // Being local it goes without the TLS and client front of `rpc`
+    #[serde(default)]
+    pub admin_rpc_uri:     Option<SocketAddr>,
+    // Senders allowed on the operator RPC besides `address`
+    #[serde(default)]
+    pub operators:         Vec<H160>,
+    // Hex key layer3 withdrawals are minted from on the operator RPC, they
+    // aren't paid when unset
+    #[serde(default)]
+    pub payout_key_path:   Option<PathBuf>,
+    #[serde(default)]
+    pub rpc:               RpcLimits,
+    // Testnet faucet, off when unset
+    #[serde(default)]
+    pub faucet:            Option<FaucetConfig>,
+    // Activation heights of protocol versions
+    #[serde(default)]
+    pub upgrades:          Upgrades,
+    // Receipt and log history, kept in full when unset
+    #[serde(default)]
+    pub retention:         Option<RetentionPolicy>,
+}
+
+/// Limits of the public RPC server, fixed at startup. The defaults are
+/// jsonrpsee's. TLS, CORS and client addresses apply to the faucet server
+/// too.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RpcLimits {
+    pub max_connections:         u32,
+    pub max_request_body_bytes:  u32,
+    pub max_response_body_bytes: u32,
+    // Requests running longer are answered with an error, 0 means no limit
+    pub request_timeout_secs:    u64,
+    // Methods served, every method when empty. Only HTTP bodies can be
+    // checked, so WebSocket connections are refused while it's set
+    pub allowed_methods:         Vec<String>,
+    // Origins browsers may call from, "*" for any, none when empty
+    pub cors_allowed_origins:    Vec<String>,
+    // Peers whose X-Forwarded-For names the client, like a load balancer
+    pub trusted_proxies:         Vec<IpAddr>,
+    // Requests per second of every client address, 0 means unlimited
+    pub client_rate_limit:       u32,
+    // Serve HTTPS and WSS instead of plain HTTP and WebSocket
+    pub tls:                     Option<TlsConfig>,
+}
+
+impl RpcLimits {
+    /// The TLS and client address front of a server with these limits.
+    pub fn front(&self) -> Result<Option<RpcFront>> {
+        RpcFront::new(
+            self.tls.as_ref(),
+            &self.trusted_proxies,
+            self.client_rate_limit,
+        )
+    }
+}
+
+impl Default for RpcLimits {
+    fn default() -> Self {
+        RpcLimits {
+            max_connections:         100,
+            max_request_body_bytes:  10 * 1024 * 1024,
+            max_response_body_bytes: 10 * 1024 * 1024,
+            request_timeout_secs:    0,
+            allowed_methods:         Vec::new(),
+            cors_allowed_origins:    Vec::new(),
+            trusted_proxies:         Vec::new(),
+            client_rate_limit:       0,
+            tls:                     None,
+        }
+    }
+}
+
+/// The part of the config that can be reloaded while the node is running.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub log_level:         String,
+    pub mempool_size:      usize,
+    // Accepted `send_transaction` calls per second, 0 means unlimited
+    pub rpc_rate_limit:    u32,
+    pub skip_empty_blocks: bool,
+    // With `skip_empty_blocks`, an empty block is still produced once the
+    // latest one is this many seconds old, 0 means never
+    pub heartbeat_secs:    u64,
+    // Admission limits against dust transactions
+    pub min_cycles_price:  u64,
+    pub max_requests:      usize,
+    pub max_signatures:    usize,
+    // Seconds a transaction without a timeout waits to be packaged before
+    // it's evicted, 0 means forever
+    pub mempool_ttl_secs:  u64,
+    // RPC requests slower than this are logged, 0 means none
+    pub slow_rpc_ms:       u64,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            log_level:         "info".to_owned(),
+            mempool_size:      100,
+            rpc_rate_limit:    0,
+            skip_empty_blocks: false,
+            heartbeat_secs:    0,
+            min_cycles_price:  0,
+            max_requests:      64,
+            max_signatures:    MAX_SIGNERS,
+            mempool_ttl_secs:  0,
+            slow_rpc_ms:       0,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.log_level()?;
+        if self.mempool_size == 0 {
+            return Err(anyhow!("runtime.mempool_size must not be 0"));
+        }
+        if self.max_requests == 0 || self.max_signatures == 0 {
+            return Err(anyhow!(
+                "runtime.max_requests and runtime.max_signatures must not be 0"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Longest gap consensus leaves between two blocks produced every
+    /// `block_interval_secs`, none if blocks only come with transactions.
+    pub fn max_block_gap_secs(&self, block_interval_secs: u64) -> Option<u64> {
+        match (self.skip_empty_blocks, self.heartbeat_secs) {
+            (false, _) => Some(block_interval_secs),
+            (true, 0) => None,
+            (true, secs) => Some(secs.max(block_interval_secs)),
+        }
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter> {
+        LevelFilter::from_str(&self.log_level).map_err(|_| {
+            anyhow!(
+                "runtime.log_level {} is not one of off, error, warn, info, debug, trace",
+                self.log_level
+            )
+        })
+    }
 }
 
 impl Config {
+    /// Read the config file, apply `COVALENT_<FIELD>` environment overrides
+    /// and validate the result.
+    pub fn load(name: impl AsRef<Path>) -> Result<Self> {
+        let config = Self::read(name)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(name: impl AsRef<Path>) -> Result<Self> {
+        let name = name.as_ref();
+        let file: toml::Value =
+            parse_file(name).with_context(|| format!("read config {}", name.display()))?;
+
+        apply_env_overrides(file, ENV_PREFIX, std::env::vars())?
+            .try_into()
+            .with_context(|| format!("invalid config {}", name.display()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.runtime.validate()?;
+        if self.block.interval_secs == 0 {
+            return Err(anyhow!("block.interval_secs must not be 0"));
+        }
+        if self.block.cycles_limit < TX_CYCLE_LIMIT.as_u64() {
+            return Err(anyhow!(
+                "block.cycles_limit must be at least {}, the limit of one transaction",
+                TX_CYCLE_LIMIT
+            ));
+        }
+        let validators = &self.validators;
+        let mut listed = HashSet::new();
+        let twice = { validators.addresses.iter() }.find(|address| !listed.insert(*address));
+        if let Some(twice) = twice {
+            return Err(anyhow!("validators.addresses lists {:?} twice", twice));
+        }
+        if validators.turn_timeout_secs != 0
+            && validators.turn_timeout_secs <= self.block.interval_secs
+        {
+            return Err(anyhow!(
+                "validators.turn_timeout_secs must be 0 or longer than block.interval_secs"
+            ));
+        }
+        match self.trie_flush {
+            FlushPolicy::EveryBlocks { blocks: 0 } => {
+                return Err(anyhow!("trie_flush.blocks must not be 0"));
+            }
+            FlushPolicy::Interval { secs: 0 } => {
+                return Err(anyhow!("trie_flush.secs must not be 0"));
+            }
+            _ => (),
+        }
+        if let Some(snapshots) = &self.snapshots {
+            if snapshots.every_blocks == 0 || snapshots.keep == 0 {
+                return Err(anyhow!(
+                    "snapshots.every_blocks and snapshots.keep must not be 0"
+                ));
+            }
+        }
+        if let Some(attestation) = &self.attestation {
+            if attestation.interval_blocks == 0 {
+                return Err(anyhow!("attestation.interval_blocks must not be 0"));
+            }
+            if validators.addresses.is_empty() {
+                return Err(anyhow!("attestation needs validators.addresses"));
+            }
+            if attestation.quorum > validators.addresses.len() {
+                return Err(anyhow!(
+                    "attestation.quorum must not exceed the {} validators",
+                    validators.addresses.len()
+                ));
+            }
+            // Only attested blocks are snapshots replicas can serve
+            let every_blocks = self.snapshots.as_ref().map(|s| s.every_blocks);
+            if every_blocks.is_some_and(|every| !every.is_multiple_of(attestation.interval_blocks))
+            {
+                return Err(anyhow!(
+                    "snapshots.every_blocks must be a multiple of attestation.interval_blocks"
+                ));
+            }
+        }
+        if let Some(retention) = &self.retention {
+            if retention.interval_secs == 0 {
+                return Err(anyhow!("retention.interval_secs must not be 0"));
+            }
+        }
+        if self.chain_id == 0 {
+            return Err(anyhow!("chain_id must not be 0"));
+        }
+        if self.address.is_zero() {
+            return Err(anyhow!(
+                "address is missing, set it to the block proposer address"
+            ));
+        }
+        if self.proposer_key_path.is_some() {
+            self.proposer_key()?;
+        }
+        if self.rpc.max_connections == 0
+            || self.rpc.max_request_body_bytes == 0
+            || self.rpc.max_response_body_bytes == 0
+        {
+            return Err(anyhow!(
+                "rpc.max_connections and the rpc body limits must not be 0"
+            ));
+        }
+        cors_layer(&self.rpc.cors_allowed_origins).context("invalid rpc.cors_allowed_origins")?;
+        if let Some(tls) = &self.rpc.tls {
+            tls.load().context("invalid rpc.tls")?;
+        }
+        if self.rpc_uri.port() == 0 {
+            return Err(anyhow!("rpc_uri {} has no port", self.rpc_uri));
+        }
+        TcpListener::bind(self.rpc_uri)
+            .with_context(|| format!("rpc_uri {} is not available", self.rpc_uri))?;
+        if let Some(uri) = self.admin_rpc_uri {
+            if !uri.ip().is_loopback() {
+                return Err(anyhow!("admin_rpc_uri {} is not a loopback address", uri));
+            }
+            TcpListener::bind(uri)
+                .with_context(|| format!("admin_rpc_uri {} is not available", uri))?;
+        }
+        if let Some(path) = &self.payout_key_path {
+            read_private_key(path).context("invalid payout_key_path")?;
+        }
+
+        let registry = self.token_registry()?;
+        if let Some(faucet) = &self.faucet {
+            if faucet.amount.is_zero() {
+                return Err(anyhow!("faucet.amount must not be 0"));
+            }
+            if !registry.is_empty() && registry.get(&faucet.token_id).is_none() {
+                return Err(anyhow!(
+                    "faucet.token_id {:?} is not in tokens",
+                    faucet.token_id
+                ));
+            }
+            if let Some(captcha) = &faucet.captcha {
+                if !captcha.verify_url.starts_with("https://")
+                    && !captcha.verify_url.starts_with("http://")
+                {
+                    return Err(anyhow!(
+                        "faucet.captcha.verify_url {} is not an http url",
+                        captcha.verify_url
+                    ));
+                }
+            }
+            if faucet.uri.port() == 0 {
+                return Err(anyhow!("faucet.uri {} has no port", faucet.uri));
+            }
+            TcpListener::bind(faucet.uri)
+                .with_context(|| format!("faucet.uri {} is not available", faucet.uri))?;
+            read_private_key(&faucet.key_path).context("invalid faucet.key_path")?;
+        }
+        if let Some(fee_token) = self.fee_token {
+            if !registry.is_empty() && registry.get(&fee_token).is_none() {
+                return Err(anyhow!("fee_token {:?} is not in tokens", fee_token));
+            }
+        }
+
+        check_writable(&self.db_path)
+    }
+
     pub fn chain_db_path(&self) -> PathBuf {
         let mut path_state = self.db_path.clone();
         path_state.push("rocksdb");
@@ -31,9 +389,188 @@ impl Config {
         path_state
     }
 
+    pub fn peer_db_path(&self) -> PathBuf {
+        let mut path_state = self.db_path.clone();
+        path_state.push("rocksdb");
+        path_state.push("peer_data");
+        path_state
+    }
+
+    pub fn mempool_db_path(&self) -> PathBuf {
+        let mut path_state = self.db_path.clone();
+        path_state.push("rocksdb");
+        path_state.push("mempool_data");
+        path_state
+    }
+
+    // Local copies of the snapshots a read replica serves
+    pub fn bridge_db_path(&self) -> PathBuf {
+        let mut path_state = self.db_path.clone();
+        path_state.push("rocksdb");
+        path_state.push("bridge_data");
+        path_state
+    }
+
+    pub fn replica_db_path(&self) -> PathBuf {
+        let mut path_state = self.db_path.clone();
+        path_state.push("rocksdb");
+        path_state.push("replica_data");
+        path_state
+    }
+
+    pub fn attestation_db_path(&self) -> PathBuf {
+        let mut path_state = self.db_path.clone();
+        path_state.push("rocksdb");
+        path_state.push("attestation_data");
+        path_state
+    }
+
+    /// Attestations a read replica needs on a snapshot before serving it,
+    /// none without an attestation policy.
+    pub fn snapshot_quorum(&self) -> Option<SnapshotQuorum> {
+        self.attestation.as_ref().map(|policy| SnapshotQuorum {
+            validators: self.validators.clone(),
+            size:       policy.quorum(&self.validators),
+        })
+    }
+
+    /// Whether the node proposes blocks, alone or in turns.
+    pub fn is_validator(&self) -> bool {
+        let validators = &self.validators.addresses;
+        validators.is_empty() || validators.contains(&self.address)
+    }
+
+    /// The key of `address` produced blocks are signed with. Followers
+    /// refuse unsigned blocks, so validators can't do without.
+    pub fn proposer_key(&self) -> Result<Option<Secp256k1PrivateKey>> {
+        let path = match &self.proposer_key_path {
+            Some(path) => path,
+            None if self.is_validator() => {
+                return Err(anyhow!(
+                    "proposer_key_path is missing, {:?} can't sign its blocks",
+                    self.address
+                ));
+            }
+            None => return Ok(None),
+        };
+
+        let key = read_private_key(path).context("invalid proposer_key_path")?;
+        let owner = address_of(&key.pub_key().to_bytes());
+        if owner != self.address {
+            return Err(anyhow!(
+                "proposer_key_path holds the key of {:?}, not of address {:?}",
+                owner,
+                self.address
+            ));
+        }
+        Ok(Some(key))
+    }
+
+    pub fn node_key_path(&self) -> PathBuf {
+        self.db_path.join("node_key")
+    }
+
+    pub fn token_registry(&self) -> Result<TokenRegistry> {
+        TokenRegistry::new(&self.tokens).context("invalid tokens")
+    }
+
     pub fn chain_id(&self) -> U64 {
         self.chain_id.into()
     }
+
+    pub fn operators(&self) -> HashSet<H160> {
+        let mut operators = self.operators.iter().copied().collect::<HashSet<_>>();
+        operators.insert(self.address);
+        operators
+    }
+}
+
+/// Publishes `RuntimeConfig` snapshots to the subsystems holding a
+/// receiver, on SIGHUP or when asked through the admin RPC.
+pub struct ConfigReloader {
+    path:   PathBuf,
+    sender: watch::Sender<RuntimeConfig>,
+}
+
+impl ConfigReloader {
+    pub fn new(path: impl Into<PathBuf>, runtime: RuntimeConfig) -> Self {
+        let (sender, _) = watch::channel(runtime);
+        ConfigReloader {
+            path: path.into(),
+            sender,
+        }
+    }
+
+    pub fn current(&self) -> RuntimeConfig {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<RuntimeConfig> {
+        self.sender.subscribe()
+    }
+
+    /// Re-read the config file and publish its runtime section. Other fields
+    /// only take effect after a restart.
+    pub fn reload(&self) -> Result<RuntimeConfig> {
+        let runtime = Config::read(&self.path)?.runtime;
+        runtime.validate()?;
+
+        log::set_max_level(runtime.log_level()?);
+        self.sender.send_replace(runtime.clone());
+        Ok(runtime)
+    }
+
+    pub async fn reload_on_sighup(self: Arc<Self>) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        while hangup.recv().await.is_some() {
+            match self.reload() {
+                Ok(runtime) => println!("[config] reloaded {:?}", runtime),
+                Err(e) => println!("[config] reload failed, keep running config: {:#}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Override top level fields with `<prefix><FIELD>` environment variables.
+/// Values are parsed as toml when possible and taken as strings otherwise,
+/// so `COVALENT_CHAIN_ID=5` and `COVALENT_DB_PATH=/var/covalent` both work.
+pub fn apply_env_overrides(
+    mut config: toml::Value,
+    prefix: &str,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<toml::Value> {
+    let table = config
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("config must be a toml table"))?;
+
+    for (key, raw) in vars {
+        let field = match key.strip_prefix(prefix) {
+            Some(field) if !field.is_empty() => field.to_lowercase(),
+            _ => continue,
+        };
+
+        let value = toml::from_str::<toml::value::Table>(&format!("v = {}", raw))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or(toml::Value::String(raw));
+        table.insert(field, value);
+    }
+
+    Ok(config)
+}
+
+fn check_writable(path: &Path) -> Result<()> {
+    fs::create_dir_all(path)
+        .with_context(|| format!("db_path {} can't be created", path.display()))?;
+
+    let probe = path.join(".write_probe");
+    fs::write(&probe, b"")
+        .with_context(|| format!("db_path {} is not writable", path.display()))?;
+    let _ = fs::remove_file(probe);
+
+    Ok(())
 }
 
 pub fn parse_file<T: DeserializeOwned>(name: impl AsRef<Path>) -> Result<T> {
@@ -46,3 +583,33 @@ pub fn parse_reader<R: Read, T: DeserializeOwned>(r: &mut R) -> Result<T> {
     r.read_to_end(&mut buf)?;
     Ok(toml::from_slice(&buf)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use ophelia::PrivateKey;
+
+    use super::*;
+    use crate::dev::{DevNet, DevWallet};
+
+    #[test]
+    fn test_proposer_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let devnet = DevNet::new(dir.path().to_path_buf(), "127.0.0.1:8000".parse().unwrap(), 2)
+            .unwrap();
+        let path = devnet.write_config().unwrap();
+        let mut config = Config::load(&path).unwrap();
+        let key = config.proposer_key().unwrap().unwrap();
+        assert_eq!(key.to_bytes(), devnet.wallets[0].key.to_bytes());
+
+        // The key of another address signs nothing
+        let other = DevWallet::derive(1).unwrap();
+        config.address = other.address;
+        config.validators.addresses = vec![other.address];
+        assert!(config.validate().is_err());
+        // A validator doesn't start without a key, a follower does
+        config.proposer_key_path = None;
+        assert!(config.proposer_key().is_err());
+        config.validators.addresses = vec![devnet.wallets[0].address];
+        assert!(config.proposer_key().unwrap().is_none());
+    }
+}
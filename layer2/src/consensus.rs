@@ -1,31 +1,124 @@
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use anyhow::{anyhow, Result};
+use ophelia::{HashValue, PrivateKey, PublicKey, Signature, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
 use tokio::time::interval;
 
+use crate::attestation::AttestationPool;
 use crate::chain::Chain;
-use crate::executor::{Execute, Executor};
-use crate::mempool::MemPool;
+use crate::config::RuntimeConfig;
+use crate::executor::{Execute, Executor, FeeConfig};
+use crate::mempool::{ExpiredTransaction, MemPool};
 use crate::merkle::Merkle;
-use crate::types::{Block, Hash, Header, SignedTransaction, H160, U128, U64};
+use crate::replica::{ship_snapshot, Snapshot, SnapshotPolicy};
+use crate::types::{
+    logs_bloom, Block, BlockCommit, Bloom, Hash, Header, SignaturePair, SignedTransaction,
+    TransactionReceipt, H160, U128, U64,
+};
+use crate::upgrade::Upgrades;
 
-const BLOCK_INTERVAL: u64 = 3; // second
-const CYCLE_LIMIT: U64 = U64([30_000_000]);
+pub const BLOCK_INTERVAL: u64 = 3; // second
+pub const CYCLE_LIMIT: U64 = U64([30_000_000]);
+pub const TURN_TIMEOUT: u64 = 10; // second
+// Followers can't tell a late clock from a proposer stamping a later turn
+const CLOCK_DRIFT_MS: u64 = 2_000;
+
+/// Pace and size of produced blocks, fixed at startup.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct BlockPolicy {
+    pub interval_secs: u64,
+    // Cycles of every transaction of a block together
+    pub cycles_limit:  u64,
+}
+
+impl Default for BlockPolicy {
+    fn default() -> Self {
+        BlockPolicy {
+            interval_secs: BLOCK_INTERVAL,
+            cycles_limit:  CYCLE_LIMIT.as_u64(),
+        }
+    }
+}
+
+/// Validators taking turns proposing blocks by block number. A validator
+/// that hasn't proposed its block `turn_timeout_secs` after the previous
+/// one is passed over for the next, so one node down doesn't halt the
+/// chain. Any proposer goes when there are no validators.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct ValidatorSet {
+    pub addresses:         Vec<H160>,
+    // 0 means waiting for every validator forever
+    pub turn_timeout_secs: u64,
+}
+
+impl Default for ValidatorSet {
+    fn default() -> Self {
+        ValidatorSet {
+            addresses:         Vec::new(),
+            turn_timeout_secs: TURN_TIMEOUT,
+        }
+    }
+}
+
+impl ValidatorSet {
+    /// Whose turn block `number` is `elapsed_ms` after the previous block.
+    pub fn proposer(&self, number: U64, elapsed_ms: u64) -> Option<H160> {
+        let count = self.addresses.len() as u64;
+        if count == 0 {
+            return None;
+        }
+
+        let passed_over = match self.turn_timeout_secs.saturating_mul(1000) {
+            0 => 0,
+            timeout_ms => elapsed_ms / timeout_ms,
+        };
+        let turn = (number.as_u64().saturating_sub(1) % count + passed_over % count) % count;
+        Some(self.addresses[turn as usize])
+    }
+}
+
+/// Told about every block once it's saved, so a networking layer can gossip
+/// it. Runs on the consensus task and holds up the next block.
+pub trait OnNewBlock: Sync + Send {
+    fn on_new_block(&self, block: &Block);
+}
 
 pub struct Consensus<DB, M, C> {
-    trie_db:  Arc<DB>,
-    mempool:  Arc<M>,
-    chain:    Arc<C>,
-    state:    State,
-    chain_id: U64,
-    address:  H160,
+    trie_db:    Arc<DB>,
+    mempool:    Arc<M>,
+    chain:      Arc<C>,
+    state:      State,
+    chain_id:   U64,
+    address:    H160,
+    runtime:    watch::Receiver<RuntimeConfig>,
+    fee:        Option<FeeConfig>,
+    // Post state root of every new block, for the mempool checks
+    notify:     Option<watch::Sender<Hash>>,
+    snapshots:  Option<SnapshotPolicy>,
+    // Every saved block, for RPC subscriptions
+    blocks:     Option<broadcast::Sender<Arc<Block>>>,
+    expired:    Option<broadcast::Sender<ExpiredTransaction>>,
+    hooks:      Vec<Arc<dyn OnNewBlock>>,
+    upgrades:   Upgrades,
+    block:      BlockPolicy,
+    // Signs produced block headers, which are left unsigned without it
+    key:        Option<Secp256k1PrivateKey>,
+    validators: ValidatorSet,
+    // Signs the state of committed blocks while this node is a validator
+    attester:   Option<Arc<AttestationPool>>,
 }
 
 impl<DB, M, C> Consensus<DB, M, C>
 where
-    DB: cita_trie::DB,
+    DB: cita_trie::DB + Snapshot,
     M: MemPool,
-    C: Chain,
+    C: Chain + Snapshot,
 {
     pub fn new(
         trie_db: Arc<DB>,
@@ -33,11 +126,14 @@ where
         chain: Arc<C>,
         chain_id: U64,
         address: H160,
+        runtime: watch::Receiver<RuntimeConfig>,
+        fee_token: Option<Hash>,
     ) -> Self {
         let state = State {
             next_number: U64::one(),
             prev_hash:   Hash::default(),
             state_root:  Hash::default(),
+            timestamp:   time_now(),
         };
 
         Consensus {
@@ -47,43 +143,422 @@ where
             state,
             chain_id,
             address,
+            runtime,
+            // The proposer collects the fees of its blocks
+            fee: fee_token.map(|token| FeeConfig {
+                token,
+                recipient: address,
+            }),
+            notify: None,
+            snapshots: None,
+            blocks: None,
+            expired: None,
+            hooks: Vec::new(),
+            upgrades: Upgrades::default(),
+            block: BlockPolicy::default(),
+            key: None,
+            validators: ValidatorSet::default(),
+            attester: None,
         }
     }
 
+    pub fn publish_state_root(mut self, sender: watch::Sender<Hash>) -> Self {
+        self.notify = Some(sender);
+        self
+    }
+
+    pub fn publish_blocks(mut self, sender: broadcast::Sender<Arc<Block>>) -> Self {
+        self.blocks = Some(sender);
+        self
+    }
+
+    pub fn publish_expired(mut self, sender: broadcast::Sender<ExpiredTransaction>) -> Self {
+        self.expired = Some(sender);
+        self
+    }
+
+    /// Switch the rules blocks are produced and executed with at the
+    /// activation heights of `upgrades`.
+    pub fn with_upgrades(mut self, upgrades: Upgrades) -> Self {
+        self.upgrades = upgrades;
+        self
+    }
+
+    pub fn with_block_policy(mut self, block: BlockPolicy) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// Sign produced blocks with `key`, which followers only import when it
+    /// is the key of the proposer address.
+    pub fn with_proposer_key(mut self, key: Option<Secp256k1PrivateKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Take turns with `validators`, producing blocks only in this node's
+    /// turn and refusing blocks proposed out of turn.
+    pub fn with_validators(mut self, validators: ValidatorSet) -> Self {
+        self.validators = validators;
+        self
+    }
+
+    pub fn with_block_hook(mut self, hook: Arc<dyn OnNewBlock>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn ship_snapshots(mut self, policy: Option<SnapshotPolicy>) -> Self {
+        self.snapshots = policy;
+        self
+    }
+
+    /// Attest the state of committed blocks in `pool`, which keeps the
+    /// attestations read replicas check shipped snapshots against.
+    pub fn with_attestations(mut self, pool: Option<Arc<AttestationPool>>) -> Self {
+        self.attester = pool;
+        self
+    }
+
+    /// Continue from the latest block in the chain store, so a restarted
+    /// node builds on its own chain instead of producing a new block 1.
+    /// Blocks whose state the trie lost, which a crash between syncs can
+    /// cause, are executed again from the newest state it kept.
+    pub async fn resume(mut self) -> Result<Self> {
+        let header = match self.chain.get_latest_block().await? {
+            Some(header) => header,
+            None => return Ok(self),
+        };
+
+        let mut kept = header.number;
+        while !kept.is_zero() {
+            let header = { self.chain.get_header_by_number(&kept).await? }
+                .ok_or_else(|| anyhow!("block {} is missing from the chain store", kept))?;
+            if self.state_stored(header.state_root())? {
+                break;
+            }
+            kept -= U64::one();
+        }
+        for number in kept.as_u64() + 1..=header.number.as_u64() {
+            self.execute_again(number.into()).await?;
+        }
+
+        let root = header.state_root();
+        self.state = State {
+            next_number: header.number + U64::one(),
+            prev_hash:   header.hash(),
+            state_root:  root,
+            timestamp:   header.timestamp,
+        };
+        if let Some(notify) = &self.notify {
+            let _ = notify.send(root);
+        }
+        println!("[consensus] resuming at block {}", self.state.next_number);
+
+        Ok(self)
+    }
+
+    fn state_stored(&self, root: Hash) -> Result<bool> {
+        let stored = root.is_zero() || { self.trie_db.contains(root.as_bytes()) }
+            .map_err(|e| anyhow!("trie db: {:?}", e))?;
+        Ok(stored)
+    }
+
+    // Rebuild the state of a saved block, its receipts are saved already
+    async fn execute_again(&self, number: U64) -> Result<()> {
+        let block = { self.chain.get_block_by_number(&number).await? }
+            .ok_or_else(|| anyhow!("block {} is missing from the chain store", number))?;
+        let header = &block.header;
+        let fee = self.fee.map(|fee| FeeConfig {
+            recipient: header.proposer,
+            ..fee
+        });
+        let mut executor = Executor::new(Arc::clone(&self.trie_db))
+            .with_fee(fee)
+            .with_upgrades(self.upgrades)
+            .at_block(number.as_u64());
+        let resp = executor.exec(header.prev_state_root, &block.txs);
+        if !header.is_legacy() && header.post_state_root != resp.state_root {
+            return Err(anyhow!(
+                "block {} executed again on {:?} ends at {:?}, not {:?}",
+                number,
+                header.prev_state_root,
+                resp.state_root,
+                header.post_state_root
+            ));
+        }
+        self.trie_db
+            .flush()
+            .map_err(|e| anyhow!("trie db: {:?}", e))?;
+        println!("[consensus] executed block {} again, its state was lost", number);
+
+        Ok(())
+    }
+
     pub async fn run(mut self) {
-        let mut timer = interval(Duration::from_secs(BLOCK_INTERVAL));
+        let mut timer = interval(Duration::from_secs(self.block.interval_secs));
 
         loop {
             timer.tick().await;
-            let txs = self.mempool.package(CYCLE_LIMIT).await.unwrap();
-            let block = self.build_block(txs);
-            let mut executor = Executor::new(Arc::clone(&self.trie_db));
-            let resp = executor.exec(block.header.state_root, &block.txs);
+            self.produce_block().await.unwrap();
+        }
+    }
+
+    /// Package the mempool into the next block and save it. Returns none
+    /// when it's another validator's turn, or there's nothing to package,
+    /// empty blocks are skipped and no heartbeat block is due.
+    pub async fn produce_block(&mut self) -> Result<Option<Block>> {
+        for expired in self.mempool.evict_expired(self.state.next_number).await? {
+            if let Some(sender) = &self.expired {
+                let _ = sender.send(expired);
+            }
+        }
+        // After the previous block even when the local clock is behind the
+        // clock of its proposer
+        let timestamp = time_now().max(self.state.timestamp + 1);
+        if self.turn_of(timestamp).is_some_and(|proposer| proposer != self.address) {
+            return Ok(None);
+        }
+        let txs = self.mempool.package(self.block.cycles_limit.into()).await?;
+        if txs.is_empty() && !self.heartbeat_due(timestamp) {
+            return Ok(None);
+        }
+
+        let mut block = self.build_block(txs, timestamp);
+        let mut executor = Executor::new(Arc::clone(&self.trie_db))
+            .with_fee(self.fee)
+            .with_upgrades(self.upgrades)
+            .at_block(block.header.number.as_u64());
+        let resp = executor.exec(block.header.prev_state_root, &block.txs);
+        block.header.post_state_root = resp.state_root;
+
+        let receipts = { resp.inner.into_iter().enumerate() }
+            .map(|(index, resp)| TransactionReceipt::new(block.header.number, index, resp))
+            .collect::<Vec<_>>();
+        block.header.logs_bloom = logs_bloom(receipts.iter().flat_map(|r| r.logs.iter()));
+        self.sign_header(&mut block.header);
+        self.commit(&block, receipts).await?;
+
+        Ok(Some(block))
+    }
+
+    /// Validate a block received from the proposer by checking its
+    /// signature and turn and re-executing it on the local state, and save
+    /// it like a produced one if it matches. Nothing is saved for a block
+    /// that doesn't.
+    pub async fn import_block(&mut self, block: Block) -> Result<()> {
+        let header = &block.header;
+        let number = header.number;
+        if header.chain_id != self.chain_id {
+            return Err(anyhow!("block {} is of chain {}", number, header.chain_id));
+        }
+        header.verify_proposer()?;
+        if number != self.state.next_number {
+            return Err(anyhow!(
+                "expected block {}, got {}",
+                self.state.next_number,
+                number
+            ));
+        }
+        if header.prev_hash != self.state.prev_hash {
+            return Err(anyhow!("block {} doesn't extend the local chain", number));
+        }
+        if !self.state.prev_hash.is_zero() && header.timestamp <= self.state.timestamp {
+            return Err(anyhow!("block {} isn't stamped after the previous block", number));
+        }
+        if let Some(proposer) = self.turn_of(header.timestamp) {
+            let now = time_now();
+            if header.timestamp > now + U128::from(CLOCK_DRIFT_MS) {
+                return Err(anyhow!("block {} is stamped ahead of the local clock", number));
+            }
+            // A block stamped further back could claim a turn that passed since
+            let turn_ms = match self.validators.turn_timeout_secs {
+                0 => TURN_TIMEOUT,
+                secs => secs,
+            }
+            .saturating_mul(1000);
+            if header.timestamp + U128::from(turn_ms + CLOCK_DRIFT_MS) < now {
+                return Err(anyhow!("block {} is stamped behind the local clock", number));
+            }
+            if header.proposer != proposer {
+                return Err(anyhow!(
+                    "block {} is the turn of {:?}, not {:?}",
+                    number,
+                    proposer,
+                    header.proposer
+                ));
+            }
+        }
+        if header.prev_state_root != self.state.state_root {
+            return Err(anyhow!(
+                "block {} executes on state {:?}, the local state is {:?}",
+                number,
+                header.prev_state_root,
+                self.state.state_root
+            ));
+        }
+        let version = self.upgrades.version_at(number.as_u64());
+        if header.protocol_version != version {
+            return Err(anyhow!(
+                "block {} follows protocol version {}, expected {}",
+                number,
+                header.protocol_version,
+                version
+            ));
+        }
+        let transaction_root = Merkle::from_hashes(block.txs.iter().map(|tx| tx.tx_hash).collect())
+            .get_root_hash()
+            .unwrap_or_default();
+        if header.transaction_root != transaction_root {
+            return Err(anyhow!("block {} transaction root mismatch", number));
+        }
 
-            self.chain.save_block(block.clone()).await.unwrap();
-            println!("[consensus] Block {:?}", block.header.number);
+        // Fees go to whoever proposed the block
+        let fee = self.fee.map(|fee| FeeConfig {
+            recipient: header.proposer,
+            ..fee
+        });
+        let mut executor = Executor::new(Arc::clone(&self.trie_db))
+            .with_fee(fee)
+            .with_upgrades(self.upgrades)
+            .at_block(number.as_u64());
+        let resp = executor.exec(header.prev_state_root, &block.txs);
+        if header.post_state_root != resp.state_root {
+            return Err(anyhow!(
+                "block {} state root mismatch, expect {:?}, got {:?}",
+                number,
+                header.post_state_root,
+                resp.state_root
+            ));
+        }
+
+        let receipts = { resp.inner.into_iter().enumerate() }
+            .map(|(index, resp)| TransactionReceipt::new(number, index, resp))
+            .collect::<Vec<_>>();
+        if header.logs_bloom != logs_bloom(receipts.iter().flat_map(|r| r.logs.iter())) {
+            return Err(anyhow!("block {} logs bloom mismatch", number));
+        }
+        self.commit(&block, receipts).await
+    }
+
+    // Save an executed block and move on to the next
+    async fn commit(&mut self, block: &Block, receipts: Vec<TransactionReceipt>) -> Result<()> {
+        // State of the block must be on disk before the block is
+        self.trie_db
+            .flush()
+            .map_err(|e| anyhow!("trie db: {:?}", e))?;
+        self.chain.save_receipts(receipts).await?;
+
+        self.chain.save_block(block.clone()).await?;
+        self.hooks.iter().for_each(|hook| hook.on_new_block(block));
+        let packaged = block.txs.iter().map(|tx| tx.tx_hash).collect();
+        self.mempool.remove(packaged).await?;
+        println!("[consensus] Block {:?}", block.header.number);
+
+        let state_root = block.header.post_state_root;
+        self.state.next_number = block.header.number + U64::one();
+        self.state.prev_hash = block.header_hash();
+        self.state.state_root = state_root;
+        self.state.timestamp = block.header.timestamp;
+        if let Some(notify) = &self.notify {
+            let _ = notify.send(state_root);
+        }
+        // No subscriber is fine
+        if let Some(blocks) = &self.blocks {
+            let _ = blocks.send(Arc::new(block.clone()));
+        }
+        if let Some(pool) = &self.attester {
+            if let Err(e) = pool.attest(&block.header) {
+                println!(
+                    "[consensus] Attesting block {} failed: {}",
+                    block.header.number, e
+                );
+            }
+        }
+        // Between blocks, so the snapshot holds whole blocks only
+        self.ship_snapshot(block.header.number.as_u64());
+
+        Ok(())
+    }
+
+    // Whether an empty block is produced now
+    fn heartbeat_due(&self, now: U128) -> bool {
+        let runtime = self.runtime.borrow();
+        if !runtime.skip_empty_blocks {
+            return true;
+        }
+
+        let heartbeat_ms = U128::from(runtime.heartbeat_secs.saturating_mul(1000));
+        !heartbeat_ms.is_zero() && now >= self.state.timestamp + heartbeat_ms
+    }
+
+    // Validator whose turn the next block stamped `timestamp` is. Turns run
+    // from the previous block, so block 1 always waits for the first
+    fn turn_of(&self, timestamp: U128) -> Option<H160> {
+        let elapsed_ms = if self.state.prev_hash.is_zero() {
+            0
+        } else {
+            timestamp.saturating_sub(self.state.timestamp).low_u64()
+        };
+        self.validators.proposer(self.state.next_number, elapsed_ms)
+    }
 
-            self.state.next_number = block.header.number + U64::one();
-            self.state.prev_hash = block.header_hash();
-            self.state.state_root = resp.state_root;
+    fn ship_snapshot(&self, number: u64) {
+        let policy = match &self.snapshots {
+            Some(policy) if number.is_multiple_of(policy.every_blocks) => policy,
+            _ => return,
+        };
+
+        let shipped = ship_snapshot(policy, number, self.chain.as_ref(), self.trie_db.as_ref());
+        if let Err(e) = shipped {
+            println!("[consensus] Snapshot of block {} failed: {:#}", number, e);
+            return;
+        }
+        // Attestations of the block so far, those coming later are added
+        if let Some(pool) = &self.attester {
+            if let Err(e) = pool.publish(number.into()) {
+                println!(
+                    "[consensus] Attaching attestations of block {} failed: {}",
+                    number, e
+                );
+            }
+        }
+    }
+
+    // Over the hash, so once the header is complete
+    fn sign_header(&self, header: &mut Header) {
+        if let Some(key) = &self.key {
+            let signature = key.sign_message(&HashValue::from_bytes_unchecked(header.hash().0));
+            header.signature = Some(SignaturePair {
+                pub_key:   key.pub_key().to_bytes(),
+                signature: signature.to_bytes(),
+            });
         }
     }
 
-    fn build_block(&self, txs: Vec<SignedTransaction>) -> Block {
+    fn build_block(&self, txs: Vec<SignedTransaction>, timestamp: U128) -> Block {
         let header = Header {
             chain_id:         self.chain_id,
             number:           self.state.next_number,
             prev_hash:        self.state.prev_hash,
-            timestamp:        time_now(),
+            timestamp,
             transaction_root: Merkle::from_hashes(txs.iter().map(|tx| tx.tx_hash).collect())
                 .get_root_hash()
                 .unwrap_or_default(),
-            state_root:       self.state.state_root,
-            cycles_limit:     CYCLE_LIMIT,
+            prev_state_root:  self.state.state_root,
+            cycles_limit:     self.block.cycles_limit.into(),
             proposer:         self.address,
+            post_state_root:  Hash::zero(),
+            logs_bloom:       Bloom::zero(),
+            protocol_version: self.upgrades.version_at(self.state.next_number.as_u64()),
+            signature:        None,
         };
 
-        Block { header, txs }
+        Block {
+            header,
+            txs,
+            commit: BlockCommit::default(),
+        }
     }
 }
 
@@ -91,6 +566,8 @@ pub struct State {
     pub next_number: U64,
     pub prev_hash:   Hash,
     pub state_root:  Hash,
+    // Of the latest block, or when the node started a new chain
+    pub timestamp:   U128,
 }
 
 fn time_now() -> U128 {
@@ -100,3 +577,338 @@ fn time_now() -> U128 {
         .as_millis()
         .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use cita_trie::DB;
+
+    use super::*;
+    use crate::attestation::AttestationPolicy;
+    use crate::chain::CovalentChain;
+    use crate::dev::DevWallet;
+    use crate::mempool::MemPoolImpl;
+    use crate::offline::UnsignedTransaction;
+    use crate::replica::{Replica, SnapshotQuorum};
+    use crate::trie::RocksTrieDB;
+    use crate::types::{RawTransaction, TokenAction, TransactionRequest};
+
+    type Node = Consensus<RocksTrieDB, MemPoolImpl<RocksTrieDB>, CovalentChain>;
+
+    // Proposing as dev wallet `proposer`
+    fn node(
+        dir: &Path,
+        runtime: watch::Receiver<RuntimeConfig>,
+        proposer: usize,
+    ) -> (Node, Arc<MemPoolImpl<RocksTrieDB>>) {
+        let trie_db = Arc::new(RocksTrieDB::new(dir.join("trie")));
+        let (root_tx, state_root) = watch::channel(Hash::zero());
+        let mempool = Arc::new(MemPoolImpl::new(
+            runtime.clone(),
+            U64::one(),
+            Arc::clone(&trie_db),
+            state_root,
+        ));
+        let proposer = DevWallet::derive(proposer).unwrap();
+        let consensus = Consensus::new(
+            trie_db,
+            Arc::clone(&mempool),
+            Arc::new(CovalentChain::new(dir.join("chain"))),
+            U64::one(),
+            proposer.address,
+            runtime,
+            None,
+        )
+        .with_proposer_key(Some(proposer.key))
+        .publish_state_root(root_tx);
+
+        (consensus, mempool)
+    }
+
+    fn mint(wallet: &DevWallet) -> SignedTransaction {
+        let raw = RawTransaction {
+            chain_id:     U64::one(),
+            cycles_price: U64::one(),
+            cycles_limit: 1000u64.into(),
+            nonce:        Hash::zero(),
+            requests:     vec![TransactionRequest {
+                address:  wallet.address,
+                token_id: Hash::from_low_u64_be(1),
+                amount:   1u64.into(),
+                action:   TokenAction::Mint,
+                to:       None,
+            }],
+            sender:       wallet.address,
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        };
+        UnsignedTransaction::new(raw).sign(&wallet.key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_import_proposed_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (mut proposer, mempool) = node(&dir.path().join("proposer"), runtime.clone(), 1);
+        let (mut follower, _) = node(&dir.path().join("follower"), runtime, 1);
+
+        let wallet = DevWallet::derive(0).unwrap();
+        mempool.insert(mint(&wallet)).await.unwrap();
+        let block = proposer.produce_block().await.unwrap().unwrap();
+        assert_eq!(block.txs.len(), 1);
+
+        // A proposer lying about the state is refused, and nothing is saved
+        let mut forged = block.clone();
+        forged.header.post_state_root = Hash::repeat_byte(1);
+        proposer.sign_header(&mut forged.header);
+        let err = follower.import_block(forged).await.unwrap_err();
+        assert!(err.to_string().contains("state root mismatch"));
+        assert_eq!(follower.state.next_number, U64::one());
+
+        // So are blocks claiming the proposer without its key
+        let mut unsigned = block.clone();
+        unsigned.header.signature = None;
+        assert!(follower.import_block(unsigned).await.is_err());
+        let mut impostor = block.clone();
+        let other = DevWallet::derive(2).unwrap().key;
+        let signature = other.sign_message(&HashValue::from_bytes_unchecked(block.header_hash().0));
+        impostor.header.signature = Some(SignaturePair {
+            pub_key:   other.pub_key().to_bytes(),
+            signature: signature.to_bytes(),
+        });
+        let err = follower.import_block(impostor).await.unwrap_err();
+        assert!(err.to_string().contains("is signed by"));
+
+        follower.import_block(block.clone()).await.unwrap();
+        assert_eq!(follower.state.prev_hash, block.header_hash());
+        assert_eq!(follower.state.state_root, block.header.post_state_root);
+        let saved = follower.chain.get_latest_block().await.unwrap().unwrap();
+        assert_eq!(saved.hash(), block.header_hash());
+
+        // Blocks are imported in order
+        assert!(follower.import_block(block).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validators_take_turns() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let validators = ValidatorSet {
+            addresses:         vec![
+                DevWallet::derive(1).unwrap().address,
+                DevWallet::derive(2).unwrap().address,
+            ],
+            turn_timeout_secs: 10,
+        };
+        let (first, _) = node(&dir.path().join("first"), runtime.clone(), 1);
+        let (second, _) = node(&dir.path().join("second"), runtime, 2);
+        let mut first = first.with_validators(validators.clone());
+        let mut second = second.with_validators(validators.clone());
+
+        // Block 1 is the first validator's, block 2 the second's
+        assert!(second.produce_block().await.unwrap().is_none());
+        let block = first.produce_block().await.unwrap().unwrap();
+        second.import_block(block).await.unwrap();
+        assert!(first.produce_block().await.unwrap().is_none());
+        let block = second.produce_block().await.unwrap().unwrap();
+        first.import_block(block).await.unwrap();
+
+        // Block 3 is the first validator's until it's been missing a while
+        let mut out_of_turn = second.build_block(Vec::new(), time_now());
+        second.sign_header(&mut out_of_turn.header);
+        let err = first.import_block(out_of_turn).await.unwrap_err();
+        assert!(err.to_string().contains("is the turn of"));
+        let timestamp = second.state.timestamp;
+        assert_eq!(second.turn_of(timestamp + 9_999), Some(first.address));
+        assert_eq!(second.turn_of(timestamp + 10_000), Some(second.address));
+        // Which followers can't be told by stamping blocks ahead
+        let mut ahead = second.build_block(Vec::new(), timestamp + 10_000);
+        second.sign_header(&mut ahead.header);
+        let err = first.import_block(ahead).await.unwrap_err();
+        assert!(err.to_string().contains("ahead of the local clock"));
+        // Or by stamping them back
+        let mut again = second.build_block(Vec::new(), timestamp);
+        second.sign_header(&mut again.header);
+        let err = first.import_block(again).await.unwrap_err();
+        assert!(err.to_string().contains("isn't stamped after"));
+        first.state.timestamp = timestamp - 60_000;
+        let mut behind = second.build_block(Vec::new(), timestamp - 59_999);
+        second.sign_header(&mut behind.header);
+        let err = first.import_block(behind).await.unwrap_err();
+        assert!(err.to_string().contains("behind the local clock"));
+
+        assert_eq!(validators.proposer(3u64.into(), 25_000), Some(first.address));
+        assert_eq!(ValidatorSet::default().proposer(U64::one(), 0), None);
+    }
+
+    #[tokio::test]
+    async fn test_validators_attest_shipped_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let validators = ValidatorSet {
+            addresses: vec![
+                DevWallet::derive(1).unwrap().address,
+                DevWallet::derive(2).unwrap().address,
+            ],
+            ..Default::default()
+        };
+        let snapshots = SnapshotPolicy {
+            dir:          dir.path().join("snapshots"),
+            every_blocks: 1,
+            keep:         1,
+        };
+        // The first validator ships the snapshots
+        let pool = |wallet: usize, snapshot_dir: Option<PathBuf>| {
+            let db = sled::open(dir.path().join(format!("attestation{}", wallet))).unwrap();
+            let policy = AttestationPolicy {
+                interval_blocks: 1,
+                ..Default::default()
+            };
+            let key = DevWallet::derive(wallet).unwrap().key;
+            let pool = AttestationPool::new(&db, validators.clone(), policy).unwrap();
+            Arc::new(pool.with_key(Some(key)).with_snapshot_dir(snapshot_dir))
+        };
+        let shipping = pool(1, Some(snapshots.dir.clone()));
+        let other = pool(2, None);
+        let (first, _) = node(&dir.path().join("first"), runtime.clone(), 1);
+        let (second, _) = node(&dir.path().join("second"), runtime, 2);
+        let mut first = { first.with_validators(validators.clone()) }
+            .ship_snapshots(Some(snapshots.clone()))
+            .with_attestations(Some(Arc::clone(&shipping)));
+        let mut second = { second.with_validators(validators.clone()) }
+            .with_attestations(Some(Arc::clone(&other)));
+
+        let block = first.produce_block().await.unwrap().unwrap();
+        second.import_block(block).await.unwrap();
+        let quorum = SnapshotQuorum {
+            validators,
+            size: 2,
+        };
+        let replica_dir = dir.path().join("replica");
+        let opened = Replica::open(
+            snapshots.dir.clone(),
+            replica_dir.clone(),
+            Some(quorum.clone()),
+        );
+        assert!(opened.await.is_err());
+
+        // As gossip from the second validator would
+        for signed in other.attestations(U64::one()).unwrap() {
+            assert!(shipping.receive(signed).unwrap());
+        }
+        let replica = { Replica::open(snapshots.dir, replica_dir, Some(quorum)) }
+            .await
+            .unwrap();
+        assert_eq!(replica.block_number(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_skip_empty_blocks_between_heartbeats() {
+        let dir = tempfile::tempdir().unwrap();
+        let (runtime_tx, runtime) = watch::channel(RuntimeConfig {
+            skip_empty_blocks: true,
+            ..Default::default()
+        });
+        let (mut node, mempool) = node(dir.path(), runtime, 1);
+
+        assert!(node.produce_block().await.unwrap().is_none());
+        let wallet = DevWallet::derive(0).unwrap();
+        mempool.insert(mint(&wallet)).await.unwrap();
+        assert!(node.produce_block().await.unwrap().is_some());
+        assert!(node.produce_block().await.unwrap().is_none());
+
+        runtime_tx.send_modify(|runtime| runtime.heartbeat_secs = 10);
+        let timestamp = node.state.timestamp;
+        assert!(!node.heartbeat_due(timestamp + 9_999));
+        assert!(node.heartbeat_due(timestamp + 10_000));
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_stored_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = Arc::new(CovalentChain::new(dir.path().join("chain")));
+        let trie_db = Arc::new(RocksTrieDB::new(dir.path().join("trie")));
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let mempool = Arc::new(MemPoolImpl::new(
+            runtime.clone(),
+            U64::one(),
+            Arc::clone(&trie_db),
+            state_root,
+        ));
+        let consensus = || {
+            Consensus::new(
+                Arc::clone(&trie_db),
+                Arc::clone(&mempool),
+                Arc::clone(&chain),
+                U64::one(),
+                H160::zero(),
+                runtime.clone(),
+                None,
+            )
+        };
+
+        // A fresh node starts a new chain
+        let fresh = consensus().resume().await.unwrap();
+        assert_eq!(fresh.state.next_number, U64::one());
+
+        let mut block = fresh.build_block(Vec::new(), time_now());
+        block.header.post_state_root = Hash::repeat_byte(7);
+        chain.save_block(block.clone()).await.unwrap();
+        // Executing the block again doesn't end at its state
+        assert!(consensus().resume().await.is_err());
+
+        trie_db
+            .insert(
+                block.header.post_state_root.as_bytes().to_vec(),
+                b"root".to_vec(),
+            )
+            .unwrap();
+        trie_db.flush().unwrap();
+        let resumed = consensus().resume().await.unwrap();
+        assert_eq!(resumed.state.next_number, 2u64.into());
+        assert_eq!(resumed.state.prev_hash, block.header_hash());
+        assert_eq!(resumed.state.state_root, block.header.post_state_root);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_losing_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (mut node, mempool) = node(dir.path(), runtime.clone(), 0);
+        let kept = dir.path().join("kept");
+        let mut blocks = Vec::new();
+        for index in 0..3 {
+            mempool
+                .insert(mint(&DevWallet::derive(index).unwrap()))
+                .await
+                .unwrap();
+            blocks.push(node.produce_block().await.unwrap().unwrap());
+            if index == 0 {
+                node.trie_db.snapshot_to(&kept).unwrap();
+            }
+        }
+
+        // The crash lost the state of blocks 2 and 3, not the blocks
+        let trie_db = Arc::new(RocksTrieDB::new(&kept));
+        let tip = &blocks[2].header;
+        assert!(!trie_db.contains(tip.post_state_root.as_bytes()).unwrap());
+        let resumed = Consensus::new(
+            Arc::clone(&trie_db),
+            mempool,
+            Arc::clone(&node.chain),
+            U64::one(),
+            node.address,
+            runtime,
+            None,
+        )
+        .resume()
+        .await
+        .unwrap();
+        assert_eq!(resumed.state.next_number, 4u64.into());
+        assert_eq!(resumed.state.state_root, tip.post_state_root);
+        assert!(trie_db.contains(tip.post_state_root.as_bytes()).unwrap());
+    }
+}
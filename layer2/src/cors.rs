@@ -0,0 +1,25 @@
+use anyhow::Result;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::Method;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// CORS headers for browsers calling from `origins`, "*" allows any. None
+/// when the list is empty, which leaves cross origin calls to the browser's
+/// default of refusing them.
+pub fn cors_layer(origins: &[String]) -> Result<Option<CorsLayer>> {
+    let allow_origin = match origins {
+        [] => return Ok(None),
+        _ if origins.iter().any(|origin| origin == "*") => AllowOrigin::from(Any),
+        _ => AllowOrigin::list(
+            { origins.iter() }
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+
+    let layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([CONTENT_TYPE]);
+    Ok(Some(layer))
+}
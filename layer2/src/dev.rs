@@ -0,0 +1,443 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use layer3::{secp256k1_address, MockDepositSource, Node};
+use ophelia::{PrivateKey, PublicKey, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use secp256k1::{Secp256k1, SecretKey};
+use serde_json::json;
+
+use crate::config::{Config, RpcLimits, RuntimeConfig};
+use crate::consensus::{BlockPolicy, ValidatorSet};
+use crate::genesis::GenesisToken;
+use crate::multisig::address_of;
+use crate::offline::UnsignedTransaction;
+use crate::trie::FlushPolicy;
+use crate::types::{
+    Hash, Hasher, RawTransaction, SignedTransaction, TokenAction, TransactionRequest, H160, U256,
+};
+use crate::upgrade::Upgrades;
+
+pub const DEV_CHAIN_ID: u64 = 1337;
+pub const DEV_TOKEN_DECIMALS: u8 = 8;
+
+/// A test account of the local devnet. Keys are derived from the wallet
+/// index, so every devnet has the same accounts.
+pub struct DevWallet {
+    pub address: H160,
+    pub key:     Secp256k1PrivateKey,
+}
+
+impl DevWallet {
+    pub fn derive(index: usize) -> Result<Self> {
+        let seed = Hasher::digest_(format!("covalent dev wallet {}", index).as_bytes());
+        let key = Secp256k1PrivateKey::try_from(seed.as_bytes())
+            .map_err(|e| anyhow!("dev wallet {}: {}", index, e))?;
+
+        Ok(DevWallet {
+            address: address_of(&key.pub_key().to_bytes()),
+            key,
+        })
+    }
+
+    pub fn key_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.key.to_bytes()))
+    }
+
+    /// The same key the way layer3 takes it, which signs channel
+    /// transactions.
+    pub fn channel_key(&self) -> SecretKey {
+        SecretKey::from_slice(&self.key.to_bytes()).unwrap()
+    }
+
+    /// Address of the key on layer3. Layer3 hashes keys the way Ethereum
+    /// does, so it differs from the layer2 address, and withdrawals are paid
+    /// to it on layer2 too.
+    pub fn layer3_address(&self) -> H160 {
+        let pubkey = secp256k1::PublicKey::from_secret_key(&Secp256k1::new(), &self.channel_key());
+        H160::from_slice(secp256k1_address(&pubkey).as_bytes())
+    }
+}
+
+/// Where the layer3 sequencer of a devnet serves, and the operator RPC of
+/// the layer2 node it pays withdrawals through.
+pub struct Layer3Endpoints {
+    pub admin_rpc_uri: SocketAddr,
+    pub rpc_uri:       SocketAddr,
+    pub snapshot_uri:  SocketAddr,
+}
+
+/// A single node chain on a throwaway database, proposing with the first
+/// wallet and charging no fees. With layer3 endpoints, a layer3 sequencer
+/// runs next to it, operated by the first wallet too.
+pub struct DevNet {
+    pub config:  Config,
+    pub wallets: Vec<DevWallet>,
+    pub token:   GenesisToken,
+    pub layer3:  Option<Layer3Endpoints>,
+}
+
+impl DevNet {
+    pub fn new(data_dir: PathBuf, rpc_uri: SocketAddr, wallets: usize) -> Result<Self> {
+        if wallets == 0 {
+            return Err(anyhow!("a devnet needs at least one wallet"));
+        }
+        let wallets = (0..wallets)
+            .map(DevWallet::derive)
+            .collect::<Result<Vec<_>>>()?;
+        let token = GenesisToken {
+            id:           Hash::from_low_u64_be(1),
+            symbol:       "DEV".to_owned(),
+            decimals:     DEV_TOKEN_DECIMALS,
+            l1_type_hash: None,
+        };
+        let config = Config {
+            proposer_key_path: Some(data_dir.join("proposer_key")),
+            db_path: data_dir,
+            rpc_uri,
+            address: wallets[0].address,
+            chain_id: DEV_CHAIN_ID,
+            fee_token: None,
+            tokens: vec![token.clone()],
+            runtime: RuntimeConfig::default(),
+            block: BlockPolicy::default(),
+            validators: ValidatorSet::default(),
+            trie_flush: FlushPolicy::EveryBlock,
+            snapshots: None,
+            attestation: None,
+            retention: None,
+            admin_rpc_uri: None,
+            operators: Vec::new(),
+            payout_key_path: None,
+            rpc: RpcLimits::default(),
+            faucet: None,
+            upgrades: Upgrades::default(),
+        };
+
+        Ok(DevNet {
+            config,
+            wallets,
+            token,
+            layer3: None,
+        })
+    }
+
+    /// Run a layer3 sequencer at `endpoints`, its withdrawals paid from the
+    /// first wallet on the operator RPC.
+    pub fn with_layer3(mut self, endpoints: Layer3Endpoints) -> Self {
+        self.config.admin_rpc_uri = Some(endpoints.admin_rpc_uri);
+        self.config.payout_key_path = self.config.proposer_key_path.clone();
+        self.layer3 = Some(endpoints);
+        self
+    }
+
+    pub fn layer3_config_path(&self) -> PathBuf {
+        self.config.db_path.join("layer3.toml")
+    }
+
+    /// Write the config into the data dir, where SIGHUP reloads it from.
+    /// The first wallet's key goes next to it as the proposer key, so the
+    /// node signs its blocks as their proposer.
+    pub fn write_config(&self) -> Result<PathBuf> {
+        let path = self.config.db_path.join("covalent.toml");
+        fs::create_dir_all(&self.config.db_path)
+            .with_context(|| format!("create {}", self.config.db_path.display()))?;
+        if let Some(path) = &self.config.proposer_key_path {
+            fs::write(path, self.wallets[0].key_hex())?;
+        }
+        // Through a value, which orders the tables after the plain keys
+        let config = toml::to_string(&toml::Value::try_from(&self.config)?)?;
+        fs::write(&path, config).with_context(|| format!("write {}", path.display()))?;
+        if let Some(endpoints) = &self.layer3 {
+            let layer3_path = self.layer3_config_path();
+            let config = toml::to_string(&toml::Value::try_from(self.layer3_config(endpoints))?)?;
+            fs::write(&layer3_path, config)
+                .with_context(|| format!("write {}", layer3_path.display()))?;
+        }
+
+        Ok(path)
+    }
+
+    // Signs with the proposer key, opens test channels in the dev token and
+    // polls the mock L1 every second
+    fn layer3_config(&self, endpoints: &Layer3Endpoints) -> serde_json::Value {
+        json!({
+            "chain_id": DEV_CHAIN_ID,
+            "db_path": self.config.db_path.join("layer3"),
+            "rpc_uri": endpoints.rpc_uri,
+            "snapshot_uri": endpoints.snapshot_uri,
+            "layer2_rpc_uri": format!("http://{}", endpoints.admin_rpc_uri),
+            "operator_key_path": self.config.proposer_key_path,
+            "challenge_window": 100,
+            "oracle": { "poll_interval_secs": 1 },
+            "faucet": { "enabled": true },
+            "tokens": [{
+                "id": self.token.id,
+                "symbol": self.token.symbol,
+                "decimals": self.token.decimals,
+            }],
+        })
+    }
+
+    /// Open the layer3 sequencer of the config `write_config` wrote and run
+    /// it in the background. It finds deposits on the returned mock L1.
+    pub async fn spawn_layer3(&self) -> Result<Arc<MockDepositSource>> {
+        let config = layer3::Config::load(self.layer3_config_path())?;
+        let l1 = Arc::new(MockDepositSource::new());
+        let node = Node::open(config)
+            .await?
+            .with_deposit_source(Arc::clone(&l1) as _);
+        tokio::spawn(async move {
+            if let Err(err) = node.run().await {
+                eprintln!("[layer3] stopped: {:#}", err);
+            }
+        });
+
+        Ok(l1)
+    }
+
+    /// The operator transaction minting `amount` of the dev token to every
+    /// wallet, the first transaction of the fresh chain.
+    pub fn funding_transaction(&self, amount: U256) -> Result<SignedTransaction> {
+        let requests = { self.wallets.iter() }
+            .map(|wallet| TransactionRequest {
+                address: wallet.address,
+                token_id: self.token.id,
+                amount,
+                action: TokenAction::Mint,
+                to: None,
+            })
+            .collect();
+        let raw = RawTransaction {
+            chain_id: DEV_CHAIN_ID.into(),
+            cycles_price: 1u64.into(),
+            cycles_limit: 1000u64.into(),
+            nonce: RawTransaction::nonce_of(0),
+            requests,
+            sender: self.wallets[0].address,
+            multisig: None,
+            alias: None,
+            timeout: None,
+        };
+
+        UnsignedTransaction::new(raw).sign(&self.wallets[0].key)
+    }
+
+    pub fn print_summary(&self, amount: U256) {
+        println!("covalent devnet, chain id {}", DEV_CHAIN_ID);
+        println!("  data dir   {}", self.config.db_path.display());
+        println!("  rpc        http://{}", self.config.rpc_uri);
+        println!("  websocket  ws://{}", self.config.rpc_uri);
+        if let Some(uri) = self.config.admin_rpc_uri {
+            println!("  admin rpc  http://{}", uri);
+        }
+        println!(
+            "  token      {} {:?}, {} decimals",
+            self.token.symbol, self.token.id, self.token.decimals
+        );
+        println!("wallets funded with {} base units each:", amount);
+        for (index, wallet) in self.wallets.iter().enumerate() {
+            println!(
+                "  ({}) {:?} key {}",
+                index,
+                wallet.address,
+                wallet.key_hex()
+            );
+        }
+        if let Some(endpoints) = &self.layer3 {
+            println!("layer3 sequencer on a mock L1, chain id {}", DEV_CHAIN_ID);
+            println!("  api        http://{}", endpoints.rpc_uri);
+            println!("  snapshots  http://{}", endpoints.snapshot_uri);
+            println!(
+                "  operator   {:?} key {}",
+                self.wallets[0].layer3_address(),
+                self.wallets[0].key_hex()
+            );
+            println!(
+                "  faucet     POST http://{}/faucet/<address>",
+                endpoints.rpc_uri
+            );
+            println!("wallets on layer3, signing with the same keys:");
+            for (index, wallet) in self.wallets.iter().enumerate() {
+                println!("  ({}) {:?}", index, wallet.layer3_address());
+            }
+        }
+    }
+}
+
+/// A fresh directory for a devnet, unique to the process.
+pub fn temp_data_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("covalent-dev-{}", std::process::id()))
+}
+
+pub fn is_fresh(data_dir: &Path) -> bool {
+    !data_dir.join("rocksdb").exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::{Body, Client, Method, Request, StatusCode};
+    use layer3::{CloseChannel, OutPoint, UnsignedTransaction};
+    use primitive_types::{H256 as ChannelHash, U128 as ChannelU128, U256 as ChannelU256};
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::api::{run_operator_server, OperatorRpcImpl};
+    use crate::bridge::Layer3Bridge;
+    use crate::chain::CovalentChain;
+    use crate::config::ConfigReloader;
+    use crate::consensus::Consensus;
+    use crate::mempool::MemPoolImpl;
+    use crate::metrics::RpcMetrics;
+    use crate::peer::{NodeIdentity, PeerManager};
+    use crate::trie::RocksTrieDB;
+    use crate::types::U64;
+
+    // None until the server is up
+    async fn request(method: Method, uri: String, body: Vec<u8>) -> Option<(StatusCode, Vec<u8>)> {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let resp = Client::new().request(req).await.ok()?;
+        let status = resp.status();
+        Some((status, hyper::body::to_bytes(resp).await.unwrap().to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_round_trips_through_both_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoints = Layer3Endpoints {
+            admin_rpc_uri: "127.0.0.1:18150".parse().unwrap(),
+            rpc_uri:       "127.0.0.1:18151".parse().unwrap(),
+            snapshot_uri:  "127.0.0.1:18152".parse().unwrap(),
+        };
+        let rpc_uri = "127.0.0.1:18153".parse().unwrap();
+        let devnet = { DevNet::new(dir.path().to_path_buf(), rpc_uri, 2) }
+            .unwrap()
+            .with_layer3(endpoints);
+        let config = Config::load(devnet.write_config().unwrap()).unwrap();
+
+        // The layer2 node, with the operator RPC paying withdrawals
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (root_tx, state_root) = watch::channel(Hash::zero());
+        let trie_db = Arc::new(RocksTrieDB::new(config.trie_db_path()));
+        let chain_id = U64::from(DEV_CHAIN_ID);
+        let mempool = Arc::new(MemPoolImpl::new(
+            runtime.clone(),
+            chain_id,
+            Arc::clone(&trie_db),
+            state_root,
+        ));
+        let mut consensus = Consensus::new(
+            trie_db,
+            Arc::clone(&mempool),
+            Arc::new(CovalentChain::new(config.chain_db_path())),
+            chain_id,
+            config.address,
+            runtime.clone(),
+            None,
+        )
+        .with_proposer_key(config.proposer_key().unwrap())
+        .publish_state_root(root_tx);
+        let payer = config.proposer_key().unwrap().unwrap();
+        let bridge_db = sled::open(config.bridge_db_path()).unwrap();
+        let bridge = Layer3Bridge::new(&bridge_db)
+            .unwrap()
+            .with_payer(chain_id, payer);
+        let peer_db = sled::open(config.peer_db_path()).unwrap();
+        let metrics = RpcMetrics::new(runtime);
+        let operator_rpc = OperatorRpcImpl::new(
+            Arc::clone(&mempool),
+            config.operators(),
+            Arc::new(ConfigReloader::new(
+                dir.path().join("runtime.toml"),
+                RuntimeConfig::default(),
+            )),
+            Arc::new(NodeIdentity::load_or_generate(&config.node_key_path()).unwrap()),
+            Arc::new(PeerManager::new(&peer_db).unwrap()),
+            metrics.clone(),
+        )
+        .with_bridge(Arc::new(bridge));
+        run_operator_server(operator_rpc, config.admin_rpc_uri.unwrap(), metrics).await;
+
+        let l1 = devnet.spawn_layer3().await.unwrap();
+        let api = format!("http://{}", devnet.layer3.as_ref().unwrap().rpc_uri);
+        let poll = || tokio::time::sleep(Duration::from_millis(200));
+
+        // A deposit on the mock L1 is detected
+        let out_point = OutPoint {
+            tx_hash: ChannelHash::repeat_byte(1),
+            index:   0,
+        };
+        l1.deposit(out_point);
+        let deposit = format!("{}/deposits/{:?}/0", api, out_point.tx_hash);
+        let detected = || async {
+            let status = request(Method::GET, deposit.clone(), vec![]).await;
+            status.map(|(status, _)| status) == Some(StatusCode::OK)
+        };
+        while !detected().await {
+            poll().await;
+        }
+
+        // The faucet opens a channel paying the second wallet, who closes it
+        let (operator, wallet) = (&devnet.wallets[0], &devnet.wallets[1]);
+        let faucet = format!("{}/faucet/{:?}", api, wallet.layer3_address());
+        let (status, body) = request(Method::POST, faucet, vec![]).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let channel_id: ChannelU256 = serde_json::from_slice(&body).unwrap();
+        let pending = format!("{}/accounts/{:?}/pending", api, operator.layer3_address());
+        // Until the channel is created
+        let created = || async {
+            let (_, body) = request(Method::GET, pending.clone(), vec![]).await.unwrap();
+            body == b"{}"
+        };
+        while !created().await {
+            poll().await;
+        }
+
+        let close = layer3::RawTransaction::CloseChannel(CloseChannel {
+            chain_id: DEV_CHAIN_ID,
+            channel_id,
+            version: 1,
+            ..Default::default()
+        });
+        let tx = { UnsignedTransaction::new(close).unwrap() }
+            .sign(&operator.channel_key(), 0)
+            .unwrap()
+            .sign(&wallet.channel_key(), 1)
+            .unwrap()
+            .into_transaction(&wallet.channel_key(), ChannelU128::zero())
+            .unwrap();
+        let submit = format!("{}/transactions", api);
+        let (status, body) = { request(Method::POST, submit, serde_json::to_vec(&tx).unwrap()) }
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+
+        // Its withdrawal is minted to the wallet in a layer2 block
+        let mut paid = None;
+        for _ in 0..100 {
+            if let Some(block) = consensus.produce_block().await.unwrap() {
+                paid = { block.txs.into_iter() }
+                    .flat_map(|tx| tx.raw.requests)
+                    .find(|request| request.address == wallet.layer3_address());
+            }
+            if paid.is_some() {
+                break;
+            }
+            poll().await;
+        }
+        let paid = paid.expect("the withdrawal was never paid on layer2");
+        assert_eq!(paid.action, TokenAction::Mint);
+        assert_eq!(paid.token_id, devnet.token.id);
+        assert_eq!(paid.amount, 1000u64.into());
+    }
+}
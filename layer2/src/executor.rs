@@ -1,4 +1,4 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 
 use cita_trie::{PatriciaTrie, Trie};
@@ -6,10 +6,14 @@ use derive_more::Display;
 use num_enum::IntoPrimitive;
 use rlp::{Decodable, Encodable, Rlp};
 
+use crate::alias::alias_key;
+use crate::multisig::{address_of, multisig_key, verify_signature};
 use crate::types::{
-    Account, BlockExecuteResponse, ExecuteError, ExecuteResponse, Hash, Hasher, Log,
-    SignedTransaction, TokenAction, TokenBalance, H160, U256,
+    Account, AliasRecord, AliasRegistration, BlockExecuteResponse, ExecuteError, ExecuteResponse,
+    Hash, Hasher, Log, MultisigConfig, SignedTransaction, Sponsor, TokenAction, TokenBalance, H160,
+    U256,
 };
+use crate::upgrade::Upgrades;
 
 type TxResult<T> = std::result::Result<T, ExecuteError>;
 
@@ -22,6 +26,21 @@ pub struct Executor<DB> {
     block_exec_cache: HashMap<H160, BTreeMap<Hash, TokenBalance>>,
     tx_exec_cache:    HashMap<H160, BTreeMap<Hash, TokenBalance>>,
     log_cache:        BTreeMap<Hash, Vec<Log>>,
+    multisig_cache:   HashMap<H160, MultisigConfig>,
+    // Next nonce of every sender in the block
+    nonce_cache:      HashMap<H160, u64>,
+    // By `alias_key`
+    alias_cache:      HashMap<Hash, AliasRecord>,
+    fee:              Option<FeeConfig>,
+    block_number:     u64,
+    upgrades:         Upgrades,
+}
+
+/// Token cycles are paid in and the account collecting them.
+#[derive(Clone, Copy, Debug)]
+pub struct FeeConfig {
+    pub token:     Hash,
+    pub recipient: H160,
 }
 
 impl<DB: cita_trie::DB> Execute for Executor<DB> {
@@ -34,10 +53,13 @@ impl<DB: cita_trie::DB> Execute for Executor<DB> {
                 Ok(resp) => (resp, None),
                 Err(e) => (Vec::new(), Some(e)),
             };
+            // Logs of the requests run before a failing one are dropped
+            let logs = self.log_cache.remove(&stx.tx_hash).unwrap_or_default();
 
             resp_list.push(ExecuteResponse {
                 tx_hash: stx.tx_hash,
                 ret:     res,
+                logs:    if err.is_none() { logs } else { Vec::new() },
                 error:   err,
             });
         });
@@ -58,6 +80,54 @@ impl<DB: cita_trie::DB> Executor<DB> {
             log_cache:        BTreeMap::new(),
             block_exec_cache: HashMap::new(),
             tx_exec_cache:    HashMap::new(),
+            multisig_cache:   HashMap::new(),
+            nonce_cache:      HashMap::new(),
+            alias_cache:      HashMap::new(),
+            fee:              None,
+            block_number:     0,
+            upgrades:         Upgrades::default(),
+        }
+    }
+
+    /// Number of the block being executed, aliases expire by it.
+    pub fn at_block(mut self, number: u64) -> Self {
+        self.block_number = number;
+        self
+    }
+
+    /// Refuse transaction kinds whose protocol version isn't active at the
+    /// block.
+    pub fn with_upgrades(mut self, upgrades: Upgrades) -> Self {
+        self.upgrades = upgrades;
+        self
+    }
+
+    /// Charge `cycles_price * cycles_limit` of every transaction to its fee
+    /// payer, the sponsor if there is one.
+    pub fn with_fee(mut self, fee: Option<FeeConfig>) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Execute `stx` on `state_trie` without writing the state it leads
+    /// to. Signatures and the nonce aren't checked, so unsigned transactions
+    /// can be tried.
+    pub fn dry_run(
+        &mut self,
+        stx: &SignedTransaction,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+    ) -> ExecuteResponse {
+        let (ret, error) = match self.apply(stx, state_trie) {
+            Ok(ret) => (ret, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        let logs = self.log_cache.remove(&stx.tx_hash).unwrap_or_default();
+
+        ExecuteResponse {
+            tx_hash: stx.tx_hash,
+            ret,
+            logs: if error.is_none() { logs } else { Vec::new() },
+            error,
         }
     }
 
@@ -66,11 +136,58 @@ impl<DB: cita_trie::DB> Executor<DB> {
         stx: &SignedTransaction,
         state_trie: &PatriciaTrie<DB, Hasher>,
     ) -> TxResult<Vec<u8>> {
+        if !self.upgrades.allows(&stx.raw, self.block_number) {
+            return Err(TransactionError::NotActivated.into());
+        }
+        if stx.raw.expired_at(self.block_number) {
+            return Err(TransactionError::TimedOut.into());
+        }
+        let nonce = self.check_nonce(stx, state_trie)?;
+        self.authorize(stx, state_trie)?;
+        // The nonce is used up even if the transaction fails, it's still in
+        // the block
+        self.nonce_cache.insert(stx.raw.sender, nonce + 1);
+        self.apply(stx, state_trie)
+    }
+
+    /// The nonce of `stx`, if it's the next one of its sender.
+    fn check_nonce(
+        &self,
+        stx: &SignedTransaction,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+    ) -> TxResult<u64> {
+        let expected = self.get_nonce(state_trie, &stx.raw.sender);
+        if stx.raw.nonce_number() != Some(expected) {
+            return Err(TransactionError::InvalidNonce.into());
+        }
+
+        Ok(expected)
+    }
+
+    pub fn get_nonce(&self, state_trie: &PatriciaTrie<DB, Hasher>, addr: &H160) -> u64 {
+        match self.nonce_cache.get(addr) {
+            Some(nonce) => *nonce,
+            None => self.get_account(state_trie, addr).nonce.as_u64(),
+        }
+    }
+
+    fn apply(
+        &mut self,
+        stx: &SignedTransaction,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+    ) -> TxResult<Vec<u8>> {
+        self.charge(state_trie, stx.fee_payer(), stx.fee())?;
+        // Paid even if the transaction fails, it still takes block space
+        self.commit_tx_cache();
+        if let Some(alias) = &stx.raw.alias {
+            self.charge(state_trie, stx.raw.sender, alias.fee())?;
+        }
+
         for req in stx.raw.requests.iter() {
             self.load_to_cache(state_trie, &req.address, &req.token_id);
 
-            let log_map = self.log_cache.entry(stx.tx_hash).or_insert_with(Vec::new);
-            let addr_str = req.address.to_string();
+            let log_map = self.log_cache.entry(stx.tx_hash).or_default();
+            let addr_str = format!("{:?}", req.address);
 
             match req.action {
                 TokenAction::Mint => {
@@ -173,54 +290,224 @@ impl<DB: cita_trie::DB> Executor<DB> {
             }
         }
 
-        for (addr, cache) in self.tx_exec_cache.iter() {
-            self.block_exec_cache.insert(*addr, cache.clone());
+        // Checked at admission too, but blocks of other proposers skip it
+        if let Some(config) = &stx.raw.multisig {
+            if config.validate().is_err() {
+                self.clear_tx_cache();
+                return Err(TransactionError::InvalidMultisig.into());
+            }
+        }
+        if let Some(alias) = &stx.raw.alias {
+            if let Err(e) = self.register_alias(state_trie, stx.raw.sender, alias) {
+                self.clear_tx_cache();
+                return Err(e);
+            }
+        }
+
+        self.commit_tx_cache();
+        if let Some(config) = &stx.raw.multisig {
+            self.multisig_cache.insert(stx.raw.sender, config.clone());
         }
 
         Ok(rlp::encode(&gen_resp(stx.tx_hash)).to_vec())
     }
 
+    /// A multisig sender needs its threshold of signers, any other sender
+    /// its own key. Only the sender's account can be debited, and only the
+    /// sponsor's key can make it pay the cycles.
+    fn authorize(
+        &self,
+        stx: &SignedTransaction,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+    ) -> TxResult<()> {
+        let debits_other = stx.raw.requests.iter().any(|req| {
+            !matches!(req.action, TokenAction::Mint) && req.address != stx.raw.sender
+        });
+        if debits_other {
+            return Err(TransactionError::NotAccountOwner.into());
+        }
+
+        match self.get_multisig(state_trie, &stx.raw.sender) {
+            Some(config) if !config.is_satisfied(&stx.tx_hash, &stx.signatures) => {
+                return Err(TransactionError::MultisigThresholdNotMet.into());
+            }
+            None if address_of(&stx.pub_key) != stx.raw.sender
+                || !verify_signature(&stx.tx_hash, &stx.pub_key, &stx.signature) =>
+            {
+                return Err(TransactionError::NotAccountOwner.into());
+            }
+            _ => (),
+        }
+        if let Some(sponsor) = &stx.sponsor {
+            let sig_hash = Sponsor::sig_hash(&stx.tx_hash);
+            if address_of(&sponsor.pub_key) != sponsor.address
+                || !verify_signature(&sig_hash, &sponsor.pub_key, &sponsor.signature)
+            {
+                return Err(TransactionError::InvalidSponsor.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// An alias can be taken while it's free or expired. Its owner can
+    /// renew it, extending the current expiry, and point it elsewhere.
+    fn register_alias(
+        &mut self,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+        sender: H160,
+        registration: &AliasRegistration,
+    ) -> TxResult<()> {
+        registration
+            .validate()
+            .map_err(|_| TransactionError::InvalidAlias)?;
+        let from = match self.get_alias(state_trie, &registration.name) {
+            Some(record) if record.expires_at > self.block_number => {
+                if record.owner != sender {
+                    return Err(TransactionError::AliasTaken.into());
+                }
+                record.expires_at
+            }
+            _ => self.block_number,
+        };
+
+        self.alias_cache
+            .insert(alias_key(&registration.name), AliasRecord {
+                name:       registration.name.clone(),
+                address:    registration.address,
+                owner:      sender,
+                expires_at: from.saturating_add(registration.blocks),
+            });
+        Ok(())
+    }
+
+    fn charge(
+        &mut self,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+        payer: H160,
+        fee: U256,
+    ) -> TxResult<()> {
+        let fee_config = match self.fee {
+            Some(fee_config) => fee_config,
+            None => return Ok(()),
+        };
+        self.load_to_cache(state_trie, &payer, &fee_config.token);
+        self.load_to_cache(state_trie, &fee_config.recipient, &fee_config.token);
+
+        let rec = self
+            .tx_exec_cache
+            .get_mut(&payer)
+            .unwrap()
+            .get_mut(&fee_config.token)
+            .unwrap();
+        if rec.active < fee {
+            self.clear_tx_cache();
+            return Err(TransactionError::ActiveAmountLessThanFee.into());
+        }
+        rec.active -= fee;
+
+        let recipient_rec = self
+            .tx_exec_cache
+            .get_mut(&fee_config.recipient)
+            .unwrap()
+            .get_mut(&fee_config.token)
+            .unwrap();
+        recipient_rec.active += fee;
+
+        Ok(())
+    }
+
+    pub fn get_multisig(
+        &self,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+        addr: &H160,
+    ) -> Option<MultisigConfig> {
+        if let Some(config) = self.multisig_cache.get(addr) {
+            return Some(config.clone());
+        }
+
+        let raw = state_trie
+            .get(multisig_key(addr).as_bytes())
+            .expect("get multisig")?;
+        MultisigConfig::decode(&Rlp::new(&raw)).ok()
+    }
+
+    /// The alias record, also once expired.
+    pub fn get_alias(
+        &self,
+        state_trie: &PatriciaTrie<DB, Hasher>,
+        name: &str,
+    ) -> Option<AliasRecord> {
+        let key = alias_key(name);
+        if let Some(record) = self.alias_cache.get(&key) {
+            return Some(record.clone());
+        }
+
+        let raw = state_trie.get(key.as_bytes()).expect("get alias")?;
+        AliasRecord::decode(&Rlp::new(&raw)).ok()
+    }
+
     fn load_to_cache(
         &mut self,
         state_trie: &PatriciaTrie<DB, Hasher>,
         address: &H160,
         token_id: &Hash,
     ) {
+        // Even spent to zero, a cached balance is newer than the trie's
+        let cached = { self.tx_exec_cache.get(address) }.is_some_and(|c| c.contains_key(token_id));
+        if cached {
+            return;
+        }
+
         let record = self.get_balance(
             &self.trie(&self.get_account(state_trie, address).balance_root),
             token_id,
         );
-        let rec = self
-            .tx_exec_cache
+        self.tx_exec_cache
             .entry(*address)
-            .or_insert_with(BTreeMap::new)
-            .entry(*token_id)
-            .or_default();
-        if rec.is_uninitialized() {
-            *rec = record;
-        }
+            .or_default()
+            .insert(*token_id, record);
     }
 
     fn commit_cache(&self, state_trie: &mut PatriciaTrie<DB, Hasher>) {
-        for (addr, cache) in self.block_exec_cache.iter() {
+        let addrs = { self.block_exec_cache.keys() }
+            .chain(self.nonce_cache.keys())
+            .collect::<BTreeSet<_>>();
+        for addr in addrs {
             let mut account = self.get_account(state_trie, addr);
-            let mut balance_trie = self.trie(&account.balance_root);
-
-            for (token_id, balance) in cache.iter() {
-                balance_trie
-                    .insert(token_id.0.to_vec(), balance.rlp_bytes().to_vec())
-                    .unwrap();
+            if let Some(cache) = self.block_exec_cache.get(addr) {
+                let mut balance_trie = self.trie(&account.balance_root);
+                for (token_id, balance) in cache.iter() {
+                    balance_trie
+                        .insert(token_id.0.to_vec(), balance.rlp_bytes().to_vec())
+                        .unwrap();
+                }
+                account.balance_root = Hash::from_slice(&balance_trie.root().unwrap());
+            }
+            if let Some(nonce) = self.nonce_cache.get(addr) {
+                account.nonce = (*nonce).into();
             }
 
-            account.balance_root = Hash::from_slice(&balance_trie.root().unwrap());
             state_trie
                 .insert(addr.0.to_vec(), account.rlp_bytes().to_vec())
                 .unwrap();
         }
+
+        for (addr, config) in self.multisig_cache.iter() {
+            state_trie
+                .insert(multisig_key(addr).0.to_vec(), config.rlp_bytes().to_vec())
+                .unwrap();
+        }
+
+        for (key, record) in self.alias_cache.iter() {
+            state_trie
+                .insert(key.0.to_vec(), record.rlp_bytes().to_vec())
+                .unwrap();
+        }
     }
 
     pub fn trie(&self, root: &Hash) -> PatriciaTrie<DB, Hasher> {
-        let hasher = Arc::new(Hasher::default());
+        let hasher = Arc::new(Hasher);
         if root.is_zero() {
             return PatriciaTrie::new(Arc::clone(&self.trie_db), hasher);
         }
@@ -236,10 +523,7 @@ impl<DB: cita_trie::DB> Executor<DB> {
             }
         }
 
-        Account {
-            address:      *addr,
-            balance_root: Hash::default(),
-        }
+        Account::new(*addr)
     }
 
     pub fn get_balance(
@@ -256,8 +540,16 @@ impl<DB: cita_trie::DB> Executor<DB> {
         TokenBalance::default()
     }
 
+    // Keep the balances of the transaction so far
+    fn commit_tx_cache(&mut self) {
+        for (addr, cache) in self.tx_exec_cache.iter() {
+            self.block_exec_cache.insert(*addr, cache.clone());
+        }
+    }
+
+    // Roll the balances back to the last commit
     fn clear_tx_cache(&mut self) {
-        self.tx_exec_cache.clear();
+        self.tx_exec_cache = self.block_exec_cache.clone();
     }
 }
 
@@ -284,9 +576,20 @@ enum FlowDirection {
 #[derive(Display, IntoPrimitive, Clone, Copy, Debug)]
 #[repr(u32)]
 enum TransactionError {
-    ActiveAmountLessThanLock,
+    // 0 is the exit code of success
+    ActiveAmountLessThanLock = 1,
     LockedAmountLessThanUnlock,
     ActiveAmountLessThanDivert,
+    MultisigThresholdNotMet,
+    NotAccountOwner,
+    ActiveAmountLessThanFee,
+    InvalidNonce,
+    InvalidAlias,
+    AliasTaken,
+    TimedOut,
+    NotActivated,
+    InvalidSponsor,
+    InvalidMultisig,
 }
 
 impl From<TransactionError> for ExecuteError {
@@ -297,3 +600,217 @@ impl From<TransactionError> for ExecuteError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cita_trie::MemoryDB;
+    use ophelia::{HashValue, PrivateKey, PublicKey, Signature, ToPublicKey};
+
+    use super::*;
+    use crate::dev::DevWallet;
+    use crate::multisig::MAX_SIGNERS;
+    use crate::offline::{sponsor, UnsignedTransaction};
+    use crate::state::StateView;
+    use crate::types::{RawTransaction, SignaturePair, TransactionRequest, U64};
+
+    fn request(address: H160, action: TokenAction, to: Option<H160>) -> TransactionRequest {
+        TransactionRequest {
+            address,
+            token_id: Hash::from_low_u64_be(1),
+            amount: 10u64.into(),
+            action,
+            to,
+        }
+    }
+
+    fn raw(sender: H160, nonce: u64, requests: Vec<TransactionRequest>) -> RawTransaction {
+        RawTransaction {
+            chain_id:     U64::one(),
+            cycles_price: U64::zero(),
+            cycles_limit: 1000u64.into(),
+            nonce:        RawTransaction::nonce_of(nonce),
+            requests,
+            sender,
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        }
+    }
+
+    // Signed by every wallet of `signers` instead of a single key
+    fn multisig(raw: RawTransaction, signers: &[&DevWallet]) -> SignedTransaction {
+        let mut stx = UnsignedTransaction::new(raw).sign(&signers[0].key).unwrap();
+        stx.pub_key = Default::default();
+        stx.signature = Default::default();
+        stx.signatures = { signers.iter() }
+            .map(|wallet| SignaturePair {
+                pub_key:   wallet.key.pub_key().to_bytes(),
+                signature: wallet
+                    .key
+                    .sign_message(&HashValue::from_bytes_unchecked(stx.tx_hash.0))
+                    .to_bytes(),
+            })
+            .collect();
+        stx
+    }
+
+    fn exit_codes(resp: &BlockExecuteResponse) -> Vec<u32> {
+        { resp.inner.iter() }
+            .map(|resp| resp.error.as_ref().map_or(0, |e| e.error_code))
+            .collect()
+    }
+
+    #[test]
+    fn test_multisig_threshold() {
+        let db = Arc::new(MemoryDB::new(true));
+        let wallets = (0..4)
+            .map(|i| DevWallet::derive(i).unwrap())
+            .collect::<Vec<_>>();
+        let (treasury, attacker) = (&wallets[0], &wallets[3]);
+
+        // The treasury mints to itself and becomes a 2 of 3 account
+        let mut setup = raw(treasury.address, 0, vec![request(
+            treasury.address,
+            TokenAction::Mint,
+            None,
+        )]);
+        setup.multisig = Some(MultisigConfig {
+            threshold: 2,
+            pub_keys:  { wallets[..3].iter() }
+                .map(|wallet| wallet.key.pub_key().to_bytes())
+                .collect(),
+        });
+        let setup = UnsignedTransaction::new(setup).sign(&treasury.key).unwrap();
+        let resp = Executor::new(Arc::clone(&db)).exec(Hash::zero(), &[setup]);
+        assert_eq!(exit_codes(&resp), vec![0]);
+
+        let transfer = |nonce| {
+            raw(treasury.address, nonce, vec![request(
+                treasury.address,
+                TokenAction::Transfer,
+                Some(attacker.address),
+            )])
+        };
+        let below = multisig(transfer(1), &[&wallets[1]]);
+        let met = multisig(transfer(1), &[&wallets[1], &wallets[2]]);
+        // Signed by the attacker, debiting the treasury
+        let stolen = UnsignedTransaction::new(raw(attacker.address, 0, vec![request(
+            treasury.address,
+            TokenAction::Transfer,
+            Some(attacker.address),
+        )]))
+        .sign(&attacker.key)
+        .unwrap();
+        let resp = Executor::new(Arc::clone(&db)).exec(resp.state_root, &[below, met, stolen]);
+        assert_eq!(exit_codes(&resp), vec![
+            TransactionError::MultisigThresholdNotMet.into(),
+            0,
+            TransactionError::NotAccountOwner.into(),
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_multisig_config() {
+        let db = Arc::new(MemoryDB::new(true));
+        let wallets = (0..2)
+            .map(|i| DevWallet::derive(i).unwrap())
+            .collect::<Vec<_>>();
+        let treasury = &wallets[0];
+        let pub_key = |wallet: &DevWallet| wallet.key.pub_key().to_bytes();
+
+        let configs = [
+            (0, vec![pub_key(&wallets[0]), pub_key(&wallets[1])]),
+            (3, vec![pub_key(&wallets[0]), pub_key(&wallets[1])]),
+            (2, vec![pub_key(&wallets[1]), pub_key(&wallets[1])]),
+            (1, vec![pub_key(&wallets[1]); MAX_SIGNERS + 1]),
+        ];
+        // Put in a block as is, as another proposer could
+        let txs = { configs.into_iter().enumerate() }
+            .map(|(nonce, (threshold, pub_keys))| {
+                let mut setup = raw(treasury.address, nonce as u64, vec![request(
+                    treasury.address,
+                    TokenAction::Mint,
+                    None,
+                )]);
+                setup.multisig = Some(MultisigConfig { threshold, pub_keys });
+                UnsignedTransaction::new(setup).sign(&treasury.key).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let resp = Executor::new(Arc::clone(&db)).exec(Hash::zero(), &txs);
+        let invalid: u32 = TransactionError::InvalidMultisig.into();
+        assert_eq!(exit_codes(&resp), vec![invalid; 4]);
+
+        // None took effect, the treasury still signs alone
+        let single = UnsignedTransaction::new(raw(treasury.address, 4, vec![request(
+            treasury.address,
+            TokenAction::Mint,
+            None,
+        )]))
+        .sign(&treasury.key)
+        .unwrap();
+        let resp = Executor::new(Arc::clone(&db)).exec(resp.state_root, &[single]);
+        assert_eq!(exit_codes(&resp), vec![0]);
+        let state = StateView::new(Arc::clone(&db), resp.state_root);
+        let active = state.balance(&treasury.address, &Hash::from_low_u64_be(1)).active;
+        assert_eq!(active, 10u64.into());
+    }
+
+    #[test]
+    fn test_fee_payers() {
+        let db = Arc::new(MemoryDB::new(true));
+        let wallets = (0..3)
+            .map(|i| DevWallet::derive(i).unwrap())
+            .collect::<Vec<_>>();
+        let (user, payer, recipient) = (&wallets[0], &wallets[1], &wallets[2]);
+        let token = Hash::from_low_u64_be(1);
+        let fee = FeeConfig {
+            token,
+            recipient: recipient.address,
+        };
+        let signed = |wallet: &DevWallet, nonce, req| {
+            let mut raw = raw(wallet.address, nonce, vec![req]);
+            raw.cycles_price = U64::one();
+            raw.cycles_limit = 2u64.into();
+            UnsignedTransaction::new(raw).sign(&wallet.key).unwrap()
+        };
+        let funding = UnsignedTransaction::new(raw(payer.address, 0, vec![request(
+            payer.address,
+            TokenAction::Mint,
+            None,
+        )]))
+        .sign(&payer.key)
+        .unwrap();
+
+        let sponsored = sponsor(
+            signed(user, 0, request(user.address, TokenAction::Mint, None)),
+            &payer.key,
+        );
+        // Sponsorship signed by someone else than the payer
+        let mut forged = signed(user, 1, request(user.address, TokenAction::Mint, None));
+        forged.sponsor = sponsor(forged.clone(), &user.key).sponsor.map(|sponsor| Sponsor {
+            address: payer.address,
+            ..sponsor
+        });
+        let failing = signed(payer, 1, TransactionRequest {
+            amount: 100u64.into(),
+            ..request(payer.address, TokenAction::Transfer, Some(user.address))
+        });
+        let resp = Executor::new(Arc::clone(&db))
+            .with_fee(Some(fee))
+            .exec(Hash::zero(), &[funding, sponsored, forged, failing]);
+        assert_eq!(exit_codes(&resp), vec![
+            0,
+            0,
+            TransactionError::InvalidSponsor.into(),
+            TransactionError::ActiveAmountLessThanDivert.into(),
+        ]);
+
+        // The failed transfer still paid, and used its nonce
+        let state = StateView::new(Arc::clone(&db), resp.state_root);
+        let active = |wallet: &DevWallet| state.balance(&wallet.address, &token).active;
+        assert_eq!(active(user), 10u64.into());
+        assert_eq!(active(payer), 6u64.into());
+        assert_eq!(active(recipient), 4u64.into());
+        assert_eq!(state.nonce(&payer.address), 2);
+    }
+}
@@ -0,0 +1,428 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use hyper::{Body, Client, Request};
+use jsonrpsee::core::RpcResult;
+use jsonrpsee::proc_macros::rpc;
+use jsonrpsee::server::ServerBuilder;
+use ophelia::{PublicKey, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use serde::{Deserialize, Serialize};
+use share::error_code::RpcErrorCode;
+use share::front::RpcFront;
+use tower::ServiceBuilder;
+
+use crate::api::rpc_error;
+use crate::config::RpcLimits;
+use crate::cors::cors_layer;
+use crate::mempool::MemPool;
+use crate::multisig::address_of;
+use crate::offline::{read_private_key, UnsignedTransaction};
+use crate::types::{
+    Hash, RawTransaction, SignedTransaction, TokenAction, TransactionRequest, H160, U256, U64,
+};
+
+/// Testnet faucet minting a fixed amount of one token to any address that
+/// asks, once per cooldown. Off unless configured.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FaucetConfig {
+    pub uri:                     SocketAddr,
+    // Hex encoded key the mint transactions are signed with
+    pub key_path:                PathBuf,
+    pub token_id:                Hash,
+    pub amount:                  U256,
+    // Seconds an address waits between two grants
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs:           u64,
+    // Grants per minute over all addresses, 0 means unlimited
+    #[serde(default = "default_max_requests_per_minute")]
+    pub max_requests_per_minute: u32,
+    // Requests are only granted with a solved captcha when set
+    #[serde(default)]
+    pub captcha:                 Option<CaptchaConfig>,
+}
+
+fn default_cooldown_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_max_requests_per_minute() -> u32 {
+    30
+}
+
+/// A `siteverify` endpoint of hCaptcha, reCAPTCHA or Turnstile.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CaptchaConfig {
+    pub verify_url: String,
+    pub secret:     String,
+}
+
+#[async_trait]
+pub trait CaptchaVerifier: Sync + Send {
+    /// Whether `response`, the token the widget handed the user, is a
+    /// solved captcha.
+    async fn verify(&self, response: &str) -> Result<bool>;
+}
+
+/// Posts the response with the site secret as a form and reads `success`
+/// from the answer, the protocol the common captcha services share.
+pub struct SiteVerify {
+    client: Client<hyper::client::HttpConnector>,
+    config: CaptchaConfig,
+}
+
+impl SiteVerify {
+    pub fn new(config: CaptchaConfig) -> Self {
+        SiteVerify {
+            client: Client::new(),
+            config,
+        }
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for SiteVerify {
+    async fn verify(&self, response: &str) -> Result<bool> {
+        let form = format!(
+            "secret={}&response={}",
+            form_encode(&self.config.secret),
+            form_encode(response)
+        );
+        let req = Request::post(&self.config.verify_url)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(form))?;
+
+        let resp = self.client.request(req).await?;
+        let resp: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(resp).await?)?;
+        resp["success"]
+            .as_bool()
+            .ok_or_else(|| anyhow!("{} returned no success: {}", self.config.verify_url, resp))
+    }
+}
+
+fn form_encode(raw: &str) -> String {
+    raw.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FaucetGrant {
+    pub tx_hash:  Hash,
+    pub token_id: Hash,
+    pub amount:   U256,
+}
+
+#[rpc(server)]
+pub trait FaucetRpc {
+    /// Mint the faucet amount to `address`. `captcha_response` is required
+    /// when the faucet is configured with a captcha.
+    #[method(name = "faucet_request")]
+    async fn request(
+        &self,
+        address: H160,
+        captcha_response: Option<String>,
+    ) -> RpcResult<FaucetGrant>;
+}
+
+pub struct Faucet<M> {
+    mempool:  Arc<M>,
+    chain_id: U64,
+    key:      Secp256k1PrivateKey,
+    address:  H160,
+    config:   FaucetConfig,
+    captcha:  Option<Box<dyn CaptchaVerifier>>,
+    granted:  DashMap<H160, Instant>,
+    window:   Mutex<(Instant, u32)>,
+    // Held from picking a nonce until the transaction is queued
+    minting:  tokio::sync::Mutex<()>,
+}
+
+impl<M: MemPool> Faucet<M> {
+    pub fn new(mempool: Arc<M>, chain_id: U64, config: FaucetConfig) -> Result<Self> {
+        let key = read_private_key(&config.key_path)?;
+        let captcha = { config.captcha.clone() }
+            .map(|captcha| Box::new(SiteVerify::new(captcha)) as Box<dyn CaptchaVerifier>);
+
+        Ok(Faucet {
+            mempool,
+            chain_id,
+            address: address_of(&key.pub_key().to_bytes()),
+            key,
+            config,
+            captcha,
+            granted: DashMap::new(),
+            window: Mutex::new((Instant::now(), 0)),
+            minting: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn address(&self) -> H160 {
+        self.address
+    }
+
+    /// Reserves the grant for `address`, or returns the seconds left until
+    /// it can ask again.
+    fn reserve(&self, address: H160) -> Result<(), u64> {
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        match self.granted.entry(address) {
+            Entry::Occupied(mut last) => {
+                let elapsed = last.get().elapsed();
+                if elapsed < cooldown {
+                    return Err((cooldown - elapsed).as_secs().max(1));
+                }
+                last.insert(Instant::now());
+            }
+            Entry::Vacant(last) => {
+                last.insert(Instant::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn acquire(&self) -> bool {
+        let limit = self.config.max_requests_per_minute;
+        if limit == 0 {
+            return true;
+        }
+
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= Duration::from_secs(60) {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= limit {
+            return false;
+        }
+
+        window.1 += 1;
+        true
+    }
+
+    fn mint_transaction(&self, address: H160, nonce: u64) -> Result<SignedTransaction> {
+        let raw = RawTransaction {
+            chain_id:     self.chain_id,
+            cycles_price: 1u64.into(),
+            cycles_limit: 1000u64.into(),
+            nonce:        RawTransaction::nonce_of(nonce),
+            requests:     vec![TransactionRequest {
+                address,
+                token_id: self.config.token_id,
+                amount: self.config.amount,
+                action: TokenAction::Mint,
+                to: None,
+            }],
+            sender:       self.address,
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        };
+
+        UnsignedTransaction::new(raw).sign(&self.key)
+    }
+
+    async fn grant(&self, address: H160, captcha_response: Option<String>) -> RpcResult<Hash> {
+        if !self.acquire() {
+            return Err(rpc_error(
+                RpcErrorCode::RateLimited,
+                "Faucet is busy, retry in a minute",
+            ));
+        }
+        self.check_captcha(captcha_response).await?;
+
+        let _minting = self.minting.lock().await;
+        let nonce = { self.mempool.next_nonce(&self.address).await }
+            .ok_or_else(|| rpc_error(RpcErrorCode::Internal, "Faucet has no nonce"))?;
+        let stx = self
+            .mint_transaction(address, nonce)
+            .map_err(|e| rpc_error(RpcErrorCode::Internal, e))?;
+        let tx_hash = stx.tx_hash;
+        // Through the public pool, its admission limits hold for the faucet too
+        self.mempool
+            .insert(stx)
+            .await
+            .map_err(|e| rpc_error(RpcErrorCode::Internal, e))?;
+        Ok(tx_hash)
+    }
+
+    async fn check_captcha(&self, response: Option<String>) -> RpcResult<()> {
+        let captcha = match &self.captcha {
+            Some(captcha) => captcha,
+            None => return Ok(()),
+        };
+        let response = response
+            .ok_or_else(|| rpc_error(RpcErrorCode::Unauthorized, "Captcha response required"))?;
+
+        match captcha.verify(&response).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(rpc_error(RpcErrorCode::Unauthorized, "Captcha not solved")),
+            Err(err) => {
+                println!("[faucet] captcha verify failed: {}", err);
+                Err(rpc_error(
+                    RpcErrorCode::Internal,
+                    "Captcha can't be verified",
+                ))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<M: MemPool + 'static> FaucetRpcServer for Faucet<M> {
+    async fn request(
+        &self,
+        address: H160,
+        captcha_response: Option<String>,
+    ) -> RpcResult<FaucetGrant> {
+        // Cooldown first, a captcha response can only be verified once
+        if let Err(wait_secs) = self.reserve(address) {
+            return Err(rpc_error(
+                RpcErrorCode::RateLimited,
+                format!("Address already funded, retry in {}s", wait_secs),
+            ));
+        }
+        // Requests that aren't granted don't start the cooldown
+        let tx_hash = match self.grant(address, captcha_response).await {
+            Ok(tx_hash) => tx_hash,
+            Err(err) => {
+                self.granted.remove(&address);
+                return Err(err);
+            }
+        };
+
+        println!("[faucet] granted {} to {:?}", self.config.amount, address);
+        Ok(FaucetGrant {
+            tx_hash,
+            token_id: self.config.token_id,
+            amount: self.config.amount,
+        })
+    }
+}
+
+pub async fn run_faucet_server<RPC: FaucetRpcServer>(
+    rpc_impl: RPC,
+    uri: SocketAddr,
+    limits: &RpcLimits,
+) {
+    let front = limits.front().unwrap();
+    let middleware =
+        ServiceBuilder::new().option_layer(cors_layer(&limits.cors_allowed_origins).unwrap());
+    let server = ServerBuilder::default()
+        .set_middleware(middleware)
+        .build(front.as_ref().map_or(uri, |_| RpcFront::inner_addr()))
+        .await
+        .unwrap();
+    if let Some(front) = front {
+        front.spawn(uri, server.local_addr().unwrap()).await.unwrap();
+    }
+    let handle = server.start(rpc_impl.into_rpc()).unwrap();
+    tokio::spawn(handle.stopped());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use cita_trie::MemoryDB;
+    use jsonrpsee::core::Error;
+    use jsonrpsee::types::error::CallError;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::config::RuntimeConfig;
+    use crate::dev::DevWallet;
+    use crate::mempool::MemPoolImpl;
+
+    struct Solved;
+
+    #[async_trait]
+    impl CaptchaVerifier for Solved {
+        async fn verify(&self, response: &str) -> Result<bool> {
+            Ok(response == "solved")
+        }
+    }
+
+    fn faucet(dir: &Path, max_requests_per_minute: u32) -> Faucet<MemPoolImpl<MemoryDB>> {
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let db = Arc::new(MemoryDB::new(true));
+        let mempool = MemPoolImpl::new(runtime, U64::one(), db, state_root);
+        let key_path = dir.join("faucet.key");
+        std::fs::write(&key_path, DevWallet::derive(0).unwrap().key_hex()).unwrap();
+        let config = FaucetConfig {
+            uri: "127.0.0.1:0".parse().unwrap(),
+            key_path,
+            token_id: Hash::from_low_u64_be(1),
+            amount: 100u64.into(),
+            cooldown_secs: default_cooldown_secs(),
+            max_requests_per_minute,
+            captcha: None,
+        };
+        Faucet::new(Arc::new(mempool), U64::one(), config).unwrap()
+    }
+
+    fn error_code(err: Error) -> Option<RpcErrorCode> {
+        match err {
+            Error::Call(CallError::Custom(err)) => RpcErrorCode::from_code(err.code()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cooldown_and_rate_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let faucet = faucet(dir.path(), 2);
+        let [alice, bob, carol] = [1, 2, 3].map(H160::repeat_byte);
+
+        let grant = faucet.request(alice, None).await.unwrap();
+        // Queued like any public transaction
+        let content = faucet.mempool.content().await.unwrap();
+        assert!(content.priority.is_empty());
+        assert_eq!(content.pending[0].stx.tx_hash, grant.tx_hash);
+
+        let err = faucet.request(alice, None).await.unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::RateLimited));
+        faucet.request(bob, None).await.unwrap();
+        // The minute's grants are used up, which doesn't start carol's cooldown
+        let err = faucet.request(carol, None).await.unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::RateLimited));
+        assert!(!faucet.granted.contains_key(&carol));
+        *faucet.window.lock().unwrap() = (Instant::now() - Duration::from_secs(60), 2);
+        faucet.request(carol, None).await.unwrap();
+
+        // Capped unless configured otherwise
+        let config = format!(
+            "uri = \"127.0.0.1:8002\"\nkey_path = \"faucet.key\"\ntoken_id = \"{:?}\"\n{}",
+            Hash::from_low_u64_be(1),
+            "amount = \"0x1\""
+        );
+        let config: FaucetConfig = toml::from_str(&config).unwrap();
+        assert_eq!(config.max_requests_per_minute, 30);
+    }
+
+    #[tokio::test]
+    async fn test_captcha() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut faucet = faucet(dir.path(), 0);
+        faucet.captcha = Some(Box::new(Solved));
+        let alice = H160::repeat_byte(1);
+
+        let err = faucet.request(alice, None).await.unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::Unauthorized));
+        let err = faucet.request(alice, Some("guessed".to_owned())).await.unwrap_err();
+        assert_eq!(error_code(err), Some(RpcErrorCode::Unauthorized));
+        // Failed attempts don't hold the address back
+        faucet.request(alice, Some("solved".to_owned())).await.unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use ophelia::{PublicKey, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use serde::{Deserialize, Serialize};
+
+use crate::executor::{Execute, Executor, FeeConfig};
+use crate::multisig::address_of;
+use crate::offline::UnsignedTransaction;
+use crate::trie::RocksTrieDB;
+use crate::types::{
+    Hash, Hasher, RawTransaction, SignedTransaction, TokenAction, TransactionReceipt,
+    TransactionRequest, H160, U64,
+};
+
+const CHAIN_ID: u64 = 1;
+const WALLETS: u64 = 4;
+const TOKENS: u64 = 2;
+const ACTIONS: [TokenAction; 5] = [
+    TokenAction::Mint,
+    TokenAction::Lock,
+    TokenAction::Unlock,
+    TokenAction::Divert,
+    TokenAction::Transfer,
+];
+
+/// Outcome of one generated block.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GoldenBlock {
+    pub number:        u64,
+    pub state_root:    Hash,
+    // Of the rlp encoded receipts, logs included
+    pub receipts_hash: Hash,
+    pub exit_codes:    Vec<u32>,
+}
+
+/// What executing the chain generated from `seed` must always lead to.
+/// A change is a change of the consensus rules.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Golden {
+    pub seed:   u64,
+    pub blocks: Vec<GoldenBlock>,
+}
+
+impl Golden {
+    pub fn read(path: &Path) -> Result<Self> {
+        let raw = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Fails on the first block that differs from `expected`.
+    pub fn diff(&self, expected: &Golden) -> Result<()> {
+        let mut pairs = self.blocks.iter().zip(expected.blocks.iter());
+        if let Some((got, want)) = pairs.find(|(got, want)| got != want) {
+            return Err(anyhow!(
+                "block {} diverged, got {:?}, expected {:?}",
+                got.number,
+                got,
+                want
+            ));
+        }
+        if self.seed != expected.seed || self.blocks.len() != expected.blocks.len() {
+            return Err(anyhow!(
+                "generated {} blocks of seed {}, expected {} of seed {}",
+                self.blocks.len(),
+                self.seed,
+                expected.blocks.len(),
+                expected.seed
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Numbers drawn from the hash of the seed and a counter, so fixtures don't
+// change with the algorithm of a rand release
+struct SeededRng {
+    seed:    u64,
+    counter: u64,
+}
+
+impl SeededRng {
+    fn below(&mut self, bound: u64) -> u64 {
+        let hash = Hasher::digest_([self.seed.to_le_bytes(), self.counter.to_le_bytes()].concat());
+        self.counter += 1;
+        hash.to_low_u64_be() % bound
+    }
+}
+
+struct Wallet {
+    address: H160,
+    key:     Secp256k1PrivateKey,
+    nonce:   u64,
+}
+
+fn wallet(seed: u64, index: u64) -> Result<Wallet> {
+    let secret = Hasher::digest_(format!("covalent fixture {} wallet {}", seed, index));
+    let key = Secp256k1PrivateKey::try_from(secret.as_bytes())
+        .map_err(|e| anyhow!("fixture wallet {}: {}", index, e))?;
+
+    Ok(Wallet {
+        address: address_of(&key.pub_key().to_bytes()),
+        key,
+        nonce: 0,
+    })
+}
+
+/// Execute `blocks` blocks of random token requests between wallets
+/// derived from `seed`, on a fresh state in `dir`. Cycles are paid in the
+/// first token, so requests of wallets that can't pay fail too.
+pub fn generate(dir: &Path, seed: u64, blocks: u64) -> Result<Golden> {
+    let trie_db = Arc::new(RocksTrieDB::new(dir.join("trie")));
+    let mut rng = SeededRng { seed, counter: 0 };
+    let mut wallets = (0..WALLETS)
+        .map(|index| wallet(seed, index))
+        .collect::<Result<Vec<_>>>()?;
+    let fee = FeeConfig {
+        token:     Hash::from_low_u64_be(1),
+        recipient: wallets[0].address,
+    };
+
+    let mut golden = Golden {
+        seed,
+        blocks: Vec::new(),
+    };
+    let mut state_root = Hash::default();
+    for number in 1..=blocks {
+        let txs = (0..1 + rng.below(4))
+            .map(|_| random_tx(&mut rng, &mut wallets))
+            .collect::<Result<Vec<_>>>()?;
+
+        let resp = Executor::new(Arc::clone(&trie_db))
+            .with_fee(Some(fee))
+            .at_block(number)
+            .exec(state_root, &txs);
+        let receipts = { resp.inner.into_iter().enumerate() }
+            .map(|(index, resp)| TransactionReceipt::new(number.into(), index, resp))
+            .collect::<Vec<_>>();
+
+        state_root = resp.state_root;
+        golden.blocks.push(GoldenBlock {
+            number,
+            state_root,
+            receipts_hash: Hasher::digest_(rlp::encode_list(&receipts)),
+            exit_codes: receipts.iter().map(|receipt| receipt.exit_code).collect(),
+        });
+    }
+
+    Ok(golden)
+}
+
+fn random_tx(rng: &mut SeededRng, wallets: &mut [Wallet]) -> Result<SignedTransaction> {
+    let to = wallets[rng.below(WALLETS) as usize].address;
+    let sender = &mut wallets[rng.below(WALLETS) as usize];
+    let requests = (0..1 + rng.below(2))
+        .map(|_| TransactionRequest {
+            address:  sender.address,
+            token_id: Hash::from_low_u64_be(1 + rng.below(TOKENS)),
+            amount:   (1 + rng.below(100)).into(),
+            action:   ACTIONS[rng.below(ACTIONS.len() as u64) as usize],
+            to:       Some(to),
+        })
+        .collect();
+    let raw = RawTransaction {
+        chain_id:     CHAIN_ID.into(),
+        // Mostly free, so fee failures don't hide the rest
+        cycles_price: rng.below(4).saturating_sub(2).into(),
+        cycles_limit: U64::from(10),
+        nonce:        RawTransaction::nonce_of(sender.nonce),
+        requests,
+        sender:       sender.address,
+        multisig:     None,
+        alias:        None,
+        timeout:      None,
+    };
+
+    sender.nonce += 1;
+    UnsignedTransaction::new(raw).sign(&sender.key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const GOLDEN_SEED: u64 = 1;
+    const GOLDEN_BLOCKS: u64 = 32;
+
+    // Run with COVALENT_UPDATE_GOLDEN=1 to accept an intended change of the
+    // consensus rules
+    #[test]
+    fn test_golden_state() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/golden.json");
+        let dir = tempfile::tempdir().unwrap();
+        let golden = generate(dir.path(), GOLDEN_SEED, GOLDEN_BLOCKS).unwrap();
+
+        let again = tempfile::tempdir().unwrap();
+        assert_eq!(
+            generate(again.path(), GOLDEN_SEED, GOLDEN_BLOCKS).unwrap(),
+            golden
+        );
+        assert!(golden.blocks.iter().any(|b| b.exit_codes.contains(&0)));
+        assert!(golden
+            .blocks
+            .iter()
+            .any(|b| b.exit_codes.iter().any(|c| *c != 0)));
+
+        if std::env::var_os("COVALENT_UPDATE_GOLDEN").is_some() {
+            golden.write(&path).unwrap();
+        }
+        golden.diff(&Golden::read(&path).unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use share::amount::check_decimals;
+
+use crate::types::Hash;
+
+/// A token registered in the genesis spec.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GenesisToken {
+    pub id:           Hash,
+    pub symbol:       String,
+    pub decimals:     u8,
+    // Type script hash of the CKB sUDT this token is bridged from, unset
+    // for tokens native to layer2
+    #[serde(default)]
+    pub l1_type_hash: Option<Hash>,
+}
+
+/// Registered tokens, indexed both by their layer2 id and by their L1
+/// binding, so deposits and withdrawals resolve to exactly one token.
+#[derive(Clone, Debug, Default)]
+pub struct TokenRegistry {
+    tokens: HashMap<Hash, GenesisToken>,
+    by_l1:  HashMap<Hash, Hash>,
+}
+
+impl TokenRegistry {
+    pub fn new(tokens: &[GenesisToken]) -> Result<Self> {
+        let mut registry = TokenRegistry::default();
+        for token in tokens {
+            check_decimals(token.decimals.into())
+                .with_context(|| format!("token {}", token.symbol))?;
+            if let Some(type_hash) = token.l1_type_hash {
+                if registry.by_l1.insert(type_hash, token.id).is_some() {
+                    return Err(anyhow!("L1 type hash {:?} bound twice", type_hash));
+                }
+            }
+            if registry.tokens.insert(token.id, token.clone()).is_some() {
+                return Err(anyhow!("token {:?} registered twice", token.id));
+            }
+        }
+
+        Ok(registry)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn get(&self, id: &Hash) -> Option<&GenesisToken> {
+        self.tokens.get(id)
+    }
+
+    /// Token a deposit of the sUDT with `type_hash` is credited in.
+    pub fn by_l1_type_hash(&self, type_hash: &Hash) -> Option<&GenesisToken> {
+        self.by_l1.get(type_hash).and_then(|id| self.tokens.get(id))
+    }
+
+    pub fn ids(&self) -> HashSet<Hash> {
+        self.tokens.keys().copied().collect()
+    }
+}
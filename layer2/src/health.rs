@@ -0,0 +1,71 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Chain;
+use crate::types::U64;
+
+// A block older than this many gaps means consensus is stalled
+const MAX_BLOCK_AGE_GAPS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HealthReport {
+    pub height:            U64,
+    pub last_block_age_ms: Option<u64>,
+    // Layer2 is the settlement layer itself, so it has no settlement lag
+    pub settlement_lag:    Option<u64>,
+    pub db_ok:             bool,
+    // Single node, no p2p network yet
+    pub peer_count:        usize,
+}
+
+impl HealthReport {
+    pub async fn collect<C: Chain>(chain: &C) -> Self {
+        let latest = chain.get_latest_block().await;
+        let db_ok = latest.is_ok();
+        let latest = latest.ok().flatten();
+
+        HealthReport {
+            height: latest.as_ref().map(|h| h.number).unwrap_or_default(),
+            last_block_age_ms: latest.map(|h| time_now_ms().saturating_sub(h.timestamp.as_u64())),
+            settlement_lag: None,
+            db_ok,
+            peer_count: 0,
+        }
+    }
+
+    /// Healthy as long as the store is readable.
+    pub fn check_health(&self) -> Result<()> {
+        if !self.db_ok {
+            return Err(anyhow!("database unavailable"));
+        }
+
+        Ok(())
+    }
+
+    /// Ready once a block has been produced and, when consensus leaves at
+    /// most `max_block_gap_secs` between blocks, keeps producing them.
+    pub fn check_ready(&self, max_block_gap_secs: Option<u64>) -> Result<()> {
+        self.check_health()?;
+
+        let age = match self.last_block_age_ms {
+            Some(age) => age,
+            None => return Err(anyhow!("no block produced yet")),
+        };
+        if let Some(gap) = max_block_gap_secs {
+            if age > gap * MAX_BLOCK_AGE_GAPS * 1000 {
+                return Err(anyhow!("last block is {}ms old", age));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn time_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
@@ -1,32 +1,76 @@
 #![allow(dead_code)]
 
+mod alias;
 mod api;
+mod archive;
+mod attestation;
+mod bridge;
 mod chain;
 mod config;
 mod consensus;
+mod cors;
+mod dev;
 mod executor;
+mod faucet;
+#[cfg(test)]
+mod fixture;
+mod genesis;
+mod health;
 mod mempool;
 mod merkle;
+mod metrics;
+mod multisig;
+mod offline;
+mod peer;
 mod primitive;
+mod replay;
+mod replica;
+mod rpc_guard;
+mod serde_hex;
+mod state;
 mod trie;
 mod types;
+mod upgrade;
 
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::{Arg, Command};
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use share::address::{encode_address, Network};
+use tokio::sync::{broadcast, watch};
 
-use crate::api::{run_jsonrpc_server, RpcImpl};
+use crate::api::{run_jsonrpc_server, run_operator_server, OperatorRpcImpl, RpcImpl, SyncSource};
+use crate::archive::{export_blocks, import_blocks};
+use crate::attestation::AttestationPool;
+use crate::bridge::Layer3Bridge;
 use crate::chain::CovalentChain;
-use crate::config::{parse_file, Config};
+use crate::config::{Config, ConfigReloader};
 use crate::consensus::Consensus;
-use crate::mempool::MemPoolImpl;
+use crate::dev::{is_fresh, temp_data_dir, DevNet, Layer3Endpoints};
+use crate::faucet::{run_faucet_server, Faucet};
+use crate::mempool::{MemPool, MemPoolImpl};
+use crate::offline::{
+    broadcast, parse_address, read_json, read_private_key, resolve_alias, sponsor,
+    verify_messages, write_json, SignedMessage, UnsignedTransaction,
+};
+use crate::peer::{NodeIdentity, PeerManager};
+use crate::replay::replay_blocks;
+use crate::replica::{ReadOnlyMemPool, Replica};
 use crate::trie::RocksTrieDB;
-
-const MEMPOOL_SIZE: usize = 100;
+use crate::types::{
+    AliasRegistration, Hash, RawTransaction, SignedTransaction, TransactionRequest, U256, U64,
+};
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
-    env_logger::init();
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
+        .init();
     let matches = Command::new("covalent-layer2")
         .arg(
             Arg::new("config_path")
@@ -34,25 +78,690 @@ async fn main() {
                 .short('c')
                 .default_value("./config/covalent.toml"),
         )
+        .subcommand(tx_command())
+        .subcommand(archive_command())
+        .subcommand(
+            Command::new("replica")
+                .about("Serve the query RPCs from the snapshots a block producer ships")
+                .arg(
+                    Arg::new("snapshots")
+                        .long("snapshots")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("refresh_secs")
+                        .long("refresh-secs")
+                        .default_value("5")
+                        .value_parser(clap::value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about(
+                    "Prune old transaction data and compact the chain db, the node must be stopped",
+                )
+                .arg(
+                    Arg::new("keep_blocks")
+                        .long("keep-blocks")
+                        .default_value("100000")
+                        .value_parser(clap::value_parser!(u64)),
+                ),
+        )
+        .subcommand(replay_command())
+        .subcommand(dev_command())
         .get_matches();
 
-    let config: Config = parse_file(matches.get_one::<String>("config_path").unwrap()).unwrap();
+    if let Some(("tx", matches)) = matches.subcommand() {
+        log::set_max_level(log::LevelFilter::Warn);
+        if let Err(e) = run_tx_command(matches).await {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let dev = match matches.subcommand() {
+        Some(("dev", matches)) => match prepare_devnet(matches) {
+            Ok(dev) => Some(dev),
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let config_path = match &dev {
+        Some((_, path, _)) => path.display().to_string(),
+        None => matches.get_one::<String>("config_path").unwrap().clone(),
+    };
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid config: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(("gc", matches)) = matches.subcommand() {
+        log::set_max_level(log::LevelFilter::Warn);
+        let chain = CovalentChain::new(config.chain_db_path());
+        match chain.collect_garbage(*matches.get_one::<u64>("keep_blocks").unwrap()) {
+            Ok(report) => println!(
+                "pruned {} transactions up to block {}, dropped trees {:?}, reclaimed {} bytes",
+                report.transactions,
+                report.pruned_tip,
+                report.dropped_trees,
+                report.reclaimed_bytes
+            ),
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(("archive", matches)) = matches.subcommand() {
+        log::set_max_level(log::LevelFilter::Warn);
+        if let Err(e) = run_archive_command(&config, matches).await {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("replay", matches)) = matches.subcommand() {
+        log::set_max_level(log::LevelFilter::Warn);
+        if let Err(e) = run_replay_command(&config, matches).await {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    log::set_max_level(config.runtime.log_level().unwrap());
+    let reloader = Arc::new(ConfigReloader::new(&config_path, config.runtime.clone()));
+    tokio::spawn(Arc::clone(&reloader).reload_on_sighup());
+
+    let identity = Arc::new(NodeIdentity::load_or_generate(&config.node_key_path()).unwrap());
+    println!("node id {:?}", identity.peer_id);
+    let peer_db = sled::open(config.peer_db_path()).unwrap();
+    let peers = Arc::new(PeerManager::new(&peer_db).unwrap());
+
+    if let Some(("replica", matches)) = matches.subcommand() {
+        let snapshots = matches.get_one::<PathBuf>("snapshots").unwrap().clone();
+        let quorum = config.snapshot_quorum();
+        let replica = match Replica::open(snapshots, config.replica_db_path(), quorum).await {
+            Ok(replica) => Arc::new(replica),
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+        println!("serving snapshot of block {}", replica.block_number());
+        let rpc = RpcImpl::new(
+            Arc::clone(&replica),
+            Arc::clone(&replica),
+            Arc::new(ReadOnlyMemPool),
+            Arc::clone(&reloader),
+            Arc::clone(&identity),
+        )
+        .with_network(config.chain_id(), config.address, config.fee_token)
+        .with_block_policy(config.block)
+        .with_sync_source(Arc::clone(&replica) as Arc<dyn SyncSource>);
+
+        println!("jsonrpc server start");
+        let metrics = rpc.metrics();
+        if let Some(uri) = config.admin_rpc_uri {
+            // Config reloads and peer bans only, replicas take no transactions
+            let operator_rpc = OperatorRpcImpl::new(
+                Arc::new(ReadOnlyMemPool),
+                config.operators(),
+                reloader,
+                identity,
+                peers,
+                metrics.clone(),
+            );
+            println!("operator jsonrpc server start");
+            run_operator_server(operator_rpc, uri, metrics.clone()).await;
+        }
+        run_jsonrpc_server(rpc, config.rpc_uri, metrics, &config.rpc).await;
+
+        let refresh = *matches.get_one::<u64>("refresh_secs").unwrap();
+        replica.follow(Duration::from_secs(refresh)).await;
+        return;
+    }
 
     let chain = Arc::new(CovalentChain::new(config.chain_db_path()));
-    let trie_db = Arc::new(RocksTrieDB::new(config.trie_db_path()));
-    let mempool = Arc::new(MemPoolImpl::new(MEMPOOL_SIZE, config.chain_id()));
+    if let Some(policy) = config.retention.clone() {
+        tokio::spawn(Arc::clone(&chain).trim_receipts_every(policy));
+    }
+    let trie_db =
+        Arc::new(RocksTrieDB::new(config.trie_db_path()).with_flush_policy(config.trie_flush));
+    let (state_root_tx, state_root_rx) = watch::channel(Hash::default());
+    // Subscribers lagging more than this skip blocks
+    let (blocks_tx, _) = broadcast::channel(64);
+    let (expired_tx, _) = broadcast::channel(1024);
+    let mempool_db = sled::open(config.mempool_db_path()).unwrap();
+    let mempool = Arc::new(
+        MemPoolImpl::new(
+            reloader.subscribe(),
+            config.chain_id(),
+            Arc::clone(&trie_db),
+            state_root_rx,
+        )
+        .with_tokens(config.token_registry().unwrap().ids(), config.fee_token)
+        .with_upgrades(config.upgrades)
+        .persist_to(&mempool_db)
+        .unwrap(),
+    );
+    let proposer_key = match config.proposer_key() {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let attestations = config.attestation.clone().map(|policy| {
+        let attestation_db = sled::open(config.attestation_db_path()).unwrap();
+        let pool = AttestationPool::new(&attestation_db, config.validators.clone(), policy)
+            .unwrap()
+            .with_key(proposer_key.clone())
+            .with_snapshot_dir(config.snapshots.as_ref().map(|policy| policy.dir.clone()));
+        Arc::new(pool)
+    });
+    let validators = &config.validators.addresses;
+    if !validators.is_empty() && !validators.contains(&config.address) {
+        println!(
+            "[consensus] {:?} isn't a validator, no blocks are produced",
+            config.address
+        );
+    }
     let consensus = Consensus::new(
         Arc::clone(&trie_db),
         Arc::clone(&mempool),
         Arc::clone(&chain),
         config.chain_id(),
         config.address,
-    );
-    let rpc = RpcImpl::new(trie_db, chain, mempool);
+        reloader.subscribe(),
+        config.fee_token,
+    )
+    .with_upgrades(config.upgrades)
+    .with_block_policy(config.block)
+    .with_proposer_key(proposer_key)
+    .with_validators(config.validators.clone())
+    .publish_state_root(state_root_tx)
+    .publish_blocks(blocks_tx.clone())
+    .publish_expired(expired_tx.clone())
+    .ship_snapshots(config.snapshots.clone())
+    .with_attestations(attestations.clone())
+    .resume()
+    .await
+    .unwrap();
+    let rpc = RpcImpl::new(
+        trie_db,
+        chain,
+        Arc::clone(&mempool),
+        Arc::clone(&reloader),
+        Arc::clone(&identity),
+    )
+    .with_network(config.chain_id(), config.address, config.fee_token)
+    .with_block_policy(config.block)
+    .with_block_feed(blocks_tx)
+    .with_expired_feed(expired_tx)
+    .with_attestations(attestations);
+    let metrics = rpc.metrics();
+    if let Some(uri) = config.admin_rpc_uri {
+        let bridge_db = sled::open(config.bridge_db_path()).unwrap();
+        let mut bridge = Layer3Bridge::new(&bridge_db).unwrap();
+        if let Some(path) = &config.payout_key_path {
+            bridge = bridge.with_payer(config.chain_id(), read_private_key(path).unwrap());
+            let payer = bridge.payer().unwrap();
+            println!("paying layer3 withdrawals from {:?}", payer);
+        }
+        let operator_rpc = OperatorRpcImpl::new(
+            Arc::clone(&mempool),
+            config.operators(),
+            reloader,
+            identity,
+            peers,
+            metrics.clone(),
+        )
+        .with_bridge(Arc::new(bridge));
+        println!("operator jsonrpc server start");
+        run_operator_server(operator_rpc, uri, metrics.clone()).await;
+    }
+
+    if let Some(faucet_config) = config.faucet.clone() {
+        let uri = faucet_config.uri;
+        let faucet = Faucet::new(Arc::clone(&mempool), config.chain_id(), faucet_config).unwrap();
+        println!("faucet server start, minting from {:?}", faucet.address());
+        run_faucet_server(faucet, uri, &config.rpc).await;
+    }
 
     println!("jsonrpc server start");
-    run_jsonrpc_server(rpc, config.rpc_uri).await;
+    run_jsonrpc_server(rpc, config.rpc_uri, metrics, &config.rpc).await;
+
+    if let Some((devnet, _, amount)) = dev {
+        if let Some(amount) = amount {
+            let funding = devnet.funding_transaction(amount).unwrap();
+            mempool.insert_priority(funding).await.unwrap();
+        }
+        if let Err(e) = devnet.spawn_layer3().await {
+            eprintln!("layer3 sequencer: {:#}", e);
+            std::process::exit(1);
+        }
+        devnet.print_summary(amount.unwrap_or_default());
+    }
+
+    let restored = mempool.restore().await.unwrap();
+    if restored > 0 {
+        println!("restored {} pending transactions", restored);
+    }
 
     println!("covalent layer2 start");
     consensus.run().await;
 }
+
+fn tx_command() -> Command {
+    let path_arg = |name: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .required(true)
+            .value_parser(clap::value_parser!(PathBuf))
+    };
+
+    Command::new("tx")
+        .about("Build, sign and broadcast transactions, signing can happen offline")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("build")
+                .about("Export an unsigned transaction")
+                .arg(Arg::new("chain_id").long("chain-id").required(true))
+                .arg(
+                    Arg::new("sender")
+                        .long("sender")
+                        .required(true)
+                        .help("Checksummed address, or 0x prefixed hex"),
+                )
+                .arg(
+                    Arg::new("network")
+                        .long("network")
+                        .default_value("mainnet")
+                        .value_parser(clap::value_parser!(Network)),
+                )
+                .arg(
+                    Arg::new("cycles_price")
+                        .long("cycles-price")
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("cycles_limit")
+                        .long("cycles-limit")
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::new("nonce")
+                        .long("nonce")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Next nonce of the sender, as returned by get_nonce"),
+                )
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Last block number the transaction can be included in"),
+                )
+                .arg(path_arg("requests").help("Json list of transaction requests"))
+                .arg(
+                    Arg::new("alias")
+                        .long("alias")
+                        .help("Register or renew this alias for the sender"),
+                )
+                .arg(
+                    Arg::new("alias_address")
+                        .long("alias-address")
+                        .help("Where the alias points, the sender when omitted"),
+                )
+                .arg(
+                    Arg::new("alias_blocks")
+                        .long("alias-blocks")
+                        .default_value("1000000")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(path_arg("out")),
+        )
+        .subcommand(
+            Command::new("sign")
+                .about("Sign an exported transaction, never touches the network")
+                .arg(
+                    Arg::new("offline")
+                        .long("offline")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(path_arg("key").help("Hex encoded secp256k1 private key"))
+                .arg(path_arg("in"))
+                .arg(path_arg("out")),
+        )
+        .subcommand(
+            Command::new("sponsor")
+                .about("Pay the cycles of a signed transaction")
+                .arg(path_arg("key").help("Hex encoded secp256k1 private key"))
+                .arg(path_arg("in"))
+                .arg(path_arg("out")),
+        )
+        .subcommand(
+            Command::new("sign-message")
+                .about("Sign a personal message to prove control of an address off chain")
+                .arg(path_arg("key").help("Hex encoded secp256k1 private key"))
+                .arg(Arg::new("message").long("message").required(true))
+                .arg(path_arg("out")),
+        )
+        .subcommand(
+            Command::new("verify-message")
+                .about("Check signed messages, fails unless every signature is valid")
+                .arg(path_arg("in").help("A signed message, or a json list of them")),
+        )
+        .subcommand(
+            Command::new("resolve")
+                .about("Print the address an alias points to")
+                .arg(
+                    Arg::new("rpc")
+                        .long("rpc")
+                        .default_value("http://127.0.0.1:8000"),
+                )
+                .arg(
+                    Arg::new("network")
+                        .long("network")
+                        .default_value("mainnet")
+                        .value_parser(clap::value_parser!(Network)),
+                )
+                .arg(Arg::new("alias").long("alias").required(true)),
+        )
+        .subcommand(
+            Command::new("broadcast")
+                .about("Submit a signed transaction to a node")
+                .arg(
+                    Arg::new("rpc")
+                        .long("rpc")
+                        .default_value("http://127.0.0.1:8000"),
+                )
+                .arg(
+                    Arg::new("idempotency_key")
+                        .long("idempotency-key")
+                        .help("Retries with the same key don't submit the transaction twice"),
+                )
+                .arg(path_arg("in")),
+        )
+}
+
+fn archive_command() -> Command {
+    let path_arg = |name: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .required(true)
+            .value_parser(clap::value_parser!(PathBuf))
+    };
+    let number_arg = |name: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .required(true)
+            .value_parser(clap::value_parser!(u64))
+    };
+
+    Command::new("archive")
+        .about("Export and import block ranges as flat file archives, the node must be stopped")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("export")
+                .about("Write a range of blocks to an archive")
+                .arg(number_arg("from"))
+                .arg(number_arg("to"))
+                .arg(path_arg("out")),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Append the blocks of an archive to the local chain")
+                .arg(path_arg("in")),
+        )
+}
+
+async fn run_archive_command(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let chain = CovalentChain::new(config.chain_db_path());
+    let path = |m: &ArgMatches, name: &str| m.get_one::<PathBuf>(name).unwrap().clone();
+    let number = |m: &ArgMatches, name: &str| U64::from(*m.get_one::<u64>(name).unwrap());
+
+    match matches.subcommand() {
+        Some(("export", m)) => {
+            let count =
+                export_blocks(&chain, number(m, "from"), number(m, "to"), &path(m, "out")).await?;
+            println!("exported {} blocks", count);
+        }
+        Some(("import", m)) => {
+            let count = import_blocks(&chain, &path(m, "in")).await?;
+            println!("imported {} blocks", count);
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    Ok(())
+}
+
+fn replay_command() -> Command {
+    let number_arg = |name: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .required(true)
+            .value_parser(clap::value_parser!(u64))
+    };
+
+    Command::new("replay")
+        .about(
+            "Execute stored blocks again and report the first state root or receipt that \
+             differs, the node must be stopped",
+        )
+        .arg(number_arg("from"))
+        .arg(number_arg("to"))
+        .arg(
+            Arg::new("state_root")
+                .long("state-root")
+                .help("State to execute the first block on, the one it was executed on by default"),
+        )
+}
+
+async fn run_replay_command(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let chain = CovalentChain::new(config.chain_db_path());
+    let trie_db = Arc::new(RocksTrieDB::new(config.trie_db_path()));
+    let number = |name: &str| U64::from(*matches.get_one::<u64>(name).unwrap());
+    let state_root = match matches.get_one::<String>("state_root") {
+        Some(root) => Some(Hash::from_str(root.trim_start_matches("0x"))?),
+        None => None,
+    };
+
+    let report = replay_blocks(
+        trie_db,
+        &chain,
+        number("from"),
+        number("to"),
+        state_root,
+        config.fee_token,
+        config.upgrades,
+    )
+    .await?;
+    println!(
+        "replayed {} blocks, compared {} receipts, state root {:?}",
+        report.blocks, report.receipts, report.state_root
+    );
+    match report.divergence {
+        Some(divergence) => Err(anyhow!("diverged: {}", divergence)),
+        None => Ok(()),
+    }
+}
+
+fn dev_command() -> Command {
+    Command::new("dev")
+        .about(
+            "Run a local devnet, a layer2 node and a layer3 sequencer paying its withdrawals \
+             on it, with funded test wallets, on a fresh database unless --data-dir is given",
+        )
+        .arg(
+            Arg::new("rpc")
+                .long("rpc")
+                .default_value("127.0.0.1:8000")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("admin_rpc")
+                .long("admin-rpc")
+                .default_value("127.0.0.1:8001")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("layer3_rpc")
+                .long("layer3-rpc")
+                .default_value("127.0.0.1:8100")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("layer3_snapshot")
+                .long("layer3-snapshot")
+                .default_value("127.0.0.1:8101")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("wallets")
+                .long("wallets")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u64).range(1..=64)),
+        )
+        .arg(
+            Arg::new("fund")
+                .long("fund")
+                .default_value("100000000000000")
+                .help("Base units of the dev token minted to every wallet"),
+        )
+        .arg(
+            Arg::new("data_dir")
+                .long("data-dir")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+}
+
+// The devnet, its config path and the amount to fund its wallets with, none
+// when its database was funded by an earlier run
+fn prepare_devnet(matches: &ArgMatches) -> Result<(DevNet, PathBuf, Option<U256>)> {
+    let data_dir =
+        { matches.get_one::<PathBuf>("data_dir").cloned() }.unwrap_or_else(temp_data_dir);
+    let fund = matches.get_one::<String>("fund").unwrap();
+    let amount = U256::from_dec_str(fund).map_err(|e| anyhow!("invalid fund {}: {:?}", fund, e))?;
+    let uri = |name: &str| *matches.get_one::<SocketAddr>(name).unwrap();
+    let devnet = DevNet::new(
+        data_dir,
+        uri("rpc"),
+        *matches.get_one::<u64>("wallets").unwrap() as usize,
+    )?
+    .with_layer3(Layer3Endpoints {
+        admin_rpc_uri: uri("admin_rpc"),
+        rpc_uri:       uri("layer3_rpc"),
+        snapshot_uri:  uri("layer3_snapshot"),
+    });
+    let fresh = is_fresh(&devnet.config.db_path);
+    let config_path = devnet.write_config()?;
+
+    Ok((devnet, config_path, Some(amount).filter(|_| fresh)))
+}
+
+async fn run_tx_command(matches: &ArgMatches) -> Result<()> {
+    let path = |m: &ArgMatches, name: &str| m.get_one::<PathBuf>(name).unwrap().clone();
+    let arg = |m: &ArgMatches, name: &str| m.get_one::<String>(name).cloned().unwrap_or_default();
+
+    match matches.subcommand() {
+        Some(("build", m)) => {
+            let nonce = RawTransaction::nonce_of(*m.get_one::<u64>("nonce").unwrap());
+            let network = *m.get_one::<Network>("network").unwrap();
+            let sender = parse_address(&arg(m, "sender"), network)?;
+            let alias = match m.get_one::<String>("alias") {
+                Some(name) => Some(AliasRegistration {
+                    name:    name.clone(),
+                    address: match m.get_one::<String>("alias_address") {
+                        Some(address) => parse_address(address, network)?,
+                        None => sender,
+                    },
+                    blocks:  *m.get_one::<u64>("alias_blocks").unwrap(),
+                }),
+                None => None,
+            };
+            let raw = RawTransaction {
+                chain_id: arg(m, "chain_id").parse::<u64>()?.into(),
+                cycles_price: arg(m, "cycles_price").parse::<u64>()?.into(),
+                cycles_limit: arg(m, "cycles_limit").parse::<u64>()?.into(),
+                nonce,
+                requests: read_json::<Vec<TransactionRequest>>(&path(m, "requests"))?,
+                sender,
+                multisig: None,
+                alias,
+                timeout: m.get_one::<u64>("timeout").map(|timeout| (*timeout).into()),
+            };
+
+            let unsigned = UnsignedTransaction::new(raw);
+            write_json(&path(m, "out"), &unsigned)?;
+            println!("sig hash {:?}", unsigned.sig_hash);
+        }
+        Some(("sign", m)) => {
+            if !m.get_flag("offline") {
+                return Err(anyhow!("tx sign only signs with --offline"));
+            }
+
+            let unsigned: UnsignedTransaction = read_json(&path(m, "in"))?;
+            let stx = unsigned.sign(&read_private_key(&path(m, "key"))?)?;
+            write_json(&path(m, "out"), &stx)?;
+            println!("signed {:?}", stx.tx_hash);
+        }
+        Some(("sponsor", m)) => {
+            let stx: SignedTransaction = read_json(&path(m, "in"))?;
+            let stx = sponsor(stx, &read_private_key(&path(m, "key"))?);
+            write_json(&path(m, "out"), &stx)?;
+            println!("sponsored {:?}", stx.tx_hash);
+        }
+        Some(("sign-message", m)) => {
+            let signed = SignedMessage::sign(&read_private_key(&path(m, "key"))?, arg(m, "message"));
+            write_json(&path(m, "out"), &signed)?;
+            println!("signed by {:?}", signed.address);
+        }
+        Some(("verify-message", m)) => {
+            let messages = match read_json::<serde_json::Value>(&path(m, "in"))? {
+                serde_json::Value::Array(list) => { list.into_iter() }
+                    .map(serde_json::from_value)
+                    .collect::<Result<Vec<SignedMessage>, _>>()?,
+                single => vec![serde_json::from_value(single)?],
+            };
+            let invalid = verify_messages(&messages);
+            if !invalid.is_empty() {
+                return Err(anyhow!("invalid signatures at {:?}", invalid));
+            }
+            println!("{} valid", messages.len());
+        }
+        Some(("resolve", m)) => {
+            let name = arg(m, "alias");
+            match resolve_alias(&arg(m, "rpc"), &name).await? {
+                Some(record) => {
+                    let network = *m.get_one::<Network>("network").unwrap();
+                    println!("{}", encode_address(network, &record.address));
+                    println!("expires at block {}", record.expires_at);
+                }
+                None => return Err(anyhow!("alias {} is not registered", name)),
+            }
+        }
+        Some(("broadcast", m)) => {
+            let stx: SignedTransaction = read_json(&path(m, "in"))?;
+            let key = m.get_one::<String>("idempotency_key");
+            let tx_hash = broadcast(&arg(m, "rpc"), &stx, key.map(String::as_str)).await?;
+            println!("sent {:?}", tx_hash);
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    Ok(())
+}
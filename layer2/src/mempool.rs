@@ -1,89 +1,788 @@
-use std::sync::RwLock;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use async_trait::async_trait;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
+use derive_more::Display;
 use ophelia::{HashValue, SignatureVerify};
 use ophelia_secp256k1::{Secp256k1PublicKey, Secp256k1Signature};
-use rlp::Encodable;
+use rlp::{Decodable, Encodable, Rlp};
+use rlp_derive::{RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+use share::error_code::RpcErrorCode;
+use share::limits::TRANSACTION_LIMIT;
+use tokio::sync::watch;
 
-use crate::types::{Hash, Hasher, SignedTransaction, TokenAction, U64};
+use crate::alias::AliasError;
+use crate::config::RuntimeConfig;
+use crate::multisig::{address_of, verify_signature, MultisigError};
+use crate::state::StateView;
+use crate::types::{
+    Hash, Hasher, RawTransaction, SignedTransaction, Sponsor, TokenAction, H160, U256, U64,
+};
+use crate::upgrade::{required_version, Upgrades};
 
-const TX_CYCLE_LIMIT: U64 = U64([100_000]);
+pub const TX_CYCLE_LIMIT: U64 = U64([100_000]);
+const SEEN_CACHE_SIZE: usize = 100_000;
+const MEMPOOL_TREE: &str = "mempool";
+// How far past the account's nonce a queued transaction may be
+const MAX_NONCE_GAP: u64 = 64;
+
+#[derive(Display, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemPoolError {
+    #[display(fmt = "Mempool is full")]
+    Full,
+    #[display(fmt = "Transaction already known")]
+    Duplicate,
+    #[display(fmt = "Invalid chain id")]
+    InvalidChainId,
+    #[display(fmt = "Exceed tx cycle limit")]
+    ExceedCycleLimit,
+    #[display(fmt = "Tx hash diff")]
+    HashMismatch,
+    #[display(fmt = "Invalid transfer request")]
+    InvalidRequest,
+    #[display(fmt = "Invalid signature")]
+    InvalidSignature,
+    #[display(fmt = "Invalid public key")]
+    InvalidPublicKey,
+    #[display(fmt = "Verify signature failed")]
+    VerifySignature,
+    #[display(fmt = "Signed by a key other than the sender's")]
+    NotSenderKey,
+    #[display(fmt = "Invalid multisig config: {}", _0)]
+    InvalidMultisig(MultisigError),
+    #[display(fmt = "Invalid alias: {}", _0)]
+    InvalidAlias(AliasError),
+    #[display(fmt = "Invalid sponsor signature")]
+    InvalidSponsor,
+    #[display(fmt = "Request debits an account other than the sender")]
+    NotAccountOwner,
+    #[display(fmt = "Request amount is zero")]
+    ZeroAmount,
+    #[display(fmt = "Unknown token")]
+    UnknownToken,
+    #[display(fmt = "Insufficient balance")]
+    InsufficientBalance,
+    #[display(fmt = "Cycles price below the minimum of {}", _0)]
+    FeeTooLow(u64),
+    #[display(fmt = "Replacement needs a cycles price above {}", _0)]
+    ReplacementUnderpriced(u64),
+    #[display(fmt = "More than {} requests", _0)]
+    TooManyRequests(usize),
+    #[display(fmt = "More than {} signatures", _0)]
+    TooManySignatures(usize),
+    #[display(fmt = "Encoded transaction larger than {} bytes", _0)]
+    TooLarge(usize),
+    #[display(fmt = "Nonce already used, the account is at {}", _0)]
+    NonceTooLow(u64),
+    #[display(fmt = "Nonce more than {} past the account's", _0)]
+    NonceTooHigh(u64),
+    #[display(fmt = "Timed out, the next block is {}", _0)]
+    TimedOut(u64),
+    #[display(fmt = "Needs protocol version {}, not active yet", _0)]
+    NotActivated(u32),
+    #[display(fmt = "Read replicas don't accept transactions")]
+    ReadOnly,
+}
+
+impl std::error::Error for MemPoolError {}
+
+impl MemPoolError {
+    pub fn code(&self) -> RpcErrorCode {
+        match self {
+            MemPoolError::Full => RpcErrorCode::MempoolFull,
+            MemPoolError::Duplicate => RpcErrorCode::DuplicateTransaction,
+            MemPoolError::InvalidChainId => RpcErrorCode::InvalidChainId,
+            MemPoolError::ExceedCycleLimit => RpcErrorCode::ExceedCycleLimit,
+            MemPoolError::HashMismatch
+            | MemPoolError::InvalidRequest
+            | MemPoolError::InvalidMultisig(_)
+            | MemPoolError::InvalidAlias(_)
+            | MemPoolError::NotAccountOwner
+            | MemPoolError::ZeroAmount
+            | MemPoolError::UnknownToken
+            | MemPoolError::InsufficientBalance
+            | MemPoolError::TooManyRequests(_)
+            | MemPoolError::TooManySignatures(_)
+            | MemPoolError::TooLarge(_)
+            | MemPoolError::TimedOut(_)
+            | MemPoolError::NotActivated(_) => RpcErrorCode::InvalidTransaction,
+            MemPoolError::FeeTooLow(_) | MemPoolError::ReplacementUnderpriced(_) => {
+                RpcErrorCode::FeeTooLow
+            }
+            MemPoolError::NonceTooLow(_) | MemPoolError::NonceTooHigh(_) => {
+                RpcErrorCode::InvalidNonce
+            }
+            MemPoolError::ReadOnly => RpcErrorCode::ReadOnly,
+            MemPoolError::InvalidSignature
+            | MemPoolError::InvalidPublicKey
+            | MemPoolError::VerifySignature
+            | MemPoolError::NotSenderKey
+            | MemPoolError::InvalidSponsor => RpcErrorCode::InvalidSignature,
+        }
+    }
+}
+
+/// Told about every transaction the pool accepts, so a networking layer can
+/// gossip it. Runs on the inserting task before `insert` returns, so it must
+/// be quick and must not call back into the pool.
+pub trait OnNewTransaction: Sync + Send {
+    fn on_new_transaction(&self, stx: &SignedTransaction);
+}
 
 #[async_trait]
 pub trait MemPool: Sync + Send {
+    /// A transaction with the sender and nonce of a queued one replaces it
+    /// if it pays a higher cycles price, and is rejected otherwise.
     async fn insert(&self, stx: SignedTransaction) -> Result<()>;
 
+    /// Queue an operator transaction ahead of the public pool. It skips the
+    /// admission limits and the pool size, but is verified like any other.
+    async fn insert_priority(&self, stx: SignedTransaction) -> Result<()>;
+
     async fn package(&self, cycle_limit: U64) -> Result<Vec<SignedTransaction>>;
 
+    /// The exact ordered transaction list `package` would return, without
+    /// side effects.
+    async fn build_block_template(&self, cycle_limit: U64) -> Result<BlockTemplate>;
+
     async fn remove(&self, hashes: Vec<Hash>) -> Result<()>;
+
+    async fn contains(&self, hash: &Hash) -> bool;
+
+    /// Drop the transactions whose timeout is below `next_number`, the block
+    /// about to be packaged, and the public ones without a timeout that
+    /// waited longer than the configured time to live.
+    async fn evict_expired(&self, next_number: U64) -> Result<Vec<ExpiredTransaction>>;
+
+    /// Every queued transaction, for operators.
+    async fn content(&self) -> Result<MemPoolContent>;
+
+    async fn size(&self) -> MemPoolSize;
+
+    /// Nonce the next transaction of `sender` should carry, past the ones
+    /// it has queued. None if the pool holds no state to start from.
+    async fn next_nonce(&self, sender: &H160) -> Option<u64>;
+
+    /// What a transaction sent now needs to get packaged, the hint evicted
+    /// transactions get too.
+    async fn resubmission_hint(&self) -> ResubmissionHint;
+}
+
+/// Queued transactions, operator ones first in arrival order, the rest by
+/// price. A block still takes each sender's transactions in nonce order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemPoolContent {
+    pub priority: Vec<MemPoolEntry>,
+    pub pending:  Vec<MemPoolEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemPoolEntry {
+    pub stx:      SignedTransaction,
+    // Seconds since the transaction was queued
+    pub age_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MemPoolSize {
+    pub priority: usize,
+    pub pending:  usize,
+    // `runtime.mempool_size`, operator transactions don't count against it
+    pub capacity: usize,
+}
+
+/// A transaction dropped from the pool before it got packaged.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExpiredTransaction {
+    pub tx_hash: Hash,
+    pub sender:  H160,
+    pub hint:    ResubmissionHint,
+}
+
+/// What a resubmission needs to get packaged. The expired hash stays known
+/// to the pool, so the transaction has to be signed again with a new nonce.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ResubmissionHint {
+    // Lowest price packaged while others were left behind, never below the
+    // admission minimum
+    pub min_cycles_price: u64,
+}
+
+/// Transactions a block would be built from, in block order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockTemplate {
+    pub cycles: U64,
+    pub txs:    Vec<SignedTransaction>,
+}
+
+#[derive(Clone, Debug)]
+struct PendingTx {
+    // Arrival order, breaks ties between equally paying transactions
+    seq:     u64,
+    stx:     SignedTransaction,
+    arrived: Instant,
+}
+
+impl PendingTx {
+    fn new(seq: u64, stx: SignedTransaction) -> Self {
+        PendingTx {
+            seq,
+            stx,
+            arrived: Instant::now(),
+        }
+    }
+
+    fn price_key(&self) -> PriceKey {
+        (
+            Reverse(self.stx.raw.cycles_price.as_u64()),
+            self.seq,
+            self.stx.tx_hash,
+        )
+    }
 }
 
-pub struct MemPoolImpl {
-    tx_map:     DashMap<Hash, SignedTransaction>,
-    flush_lock: RwLock<()>,
-    chain_id:   U64,
+// A queued transaction as persisted, keyed by its hash
+#[derive(RlpEncodable, RlpDecodable)]
+struct StoredTx {
+    priority: bool,
+    seq:      u64,
+    stx:      SignedTransaction,
+}
+
+// Highest price first, then arrival
+type PriceKey = (Reverse<u64>, u64, Hash);
+
+pub struct MemPoolImpl<DB> {
+    tx_map:      DashMap<Hash, PendingTx>,
+    // `tx_map` in packaging order
+    by_price:    Mutex<BTreeSet<PriceKey>>,
+    // (sender, nonce) of `tx_map`, a new transaction with the same pair
+    // replaces the queued one if it pays more
+    by_nonce:    DashMap<(H160, Hash), Hash>,
+    // Operator transactions, packaged before `tx_map` in arrival order
+    priority:    DashMap<Hash, PendingTx>,
+    next_seq:    AtomicU64,
+    flush_lock:  RwLock<()>,
+    chain_id:    U64,
+    runtime:     watch::Receiver<RuntimeConfig>,
+    seen:        RecentHashes,
+    trie_db:     Arc<DB>,
+    // Post state root of the latest block, published by consensus
+    state_root:  watch::Receiver<Hash>,
+    // Registered tokens, any token is accepted when empty
+    tokens:      HashSet<Hash>,
+    fee_token:   Option<Hash>,
+    // Lowest price of the last package that left transactions behind, 0
+    // once one took all of them
+    fee_floor:   AtomicU64,
+    // Queued transactions, reloaded by `restore` after a restart
+    store:       Option<sled::Tree>,
+    // Number of the block about to be packaged, as of the last eviction
+    next_number: AtomicU64,
+    tx_hooks:    Vec<Arc<dyn OnNewTransaction>>,
+    upgrades:    Upgrades,
 }
 
 #[async_trait]
-impl MemPool for MemPoolImpl {
+impl<DB: cita_trie::DB + 'static> MemPool for MemPoolImpl<DB> {
     async fn insert(&self, stx: SignedTransaction) -> Result<()> {
+        if self.seen.contains(&stx.tx_hash) {
+            return Err(MemPoolError::Duplicate.into());
+        }
+        self.verify_limits(&stx)?;
+        self.verify_tx(&stx)?;
+        self.verify_nonce(&stx)?;
+        self.verify_requests(&stx)?;
+        let _insert = self.flush_lock.read();
+        // Held until the transaction is queued, so two replacements of the
+        // same nonce can't both win
+        let by_nonce = self.by_nonce.entry((stx.raw.sender, stx.raw.nonce));
+        let replaced = match &by_nonce {
+            Entry::Occupied(queued) => self.tx_map.get(queued.get()).map(|kv| kv.value().clone()),
+            Entry::Vacant(_) => None,
+        };
+        if let Some(queued) = &replaced {
+            let queued_price = queued.stx.raw.cycles_price.as_u64();
+            if stx.raw.cycles_price.as_u64() <= queued_price {
+                return Err(MemPoolError::ReplacementUnderpriced(queued_price).into());
+            }
+        }
+        let pool_size = self.runtime.borrow().mempool_size;
+        if self.tx_map.len() >= pool_size && replaced.is_none() {
+            return Err(MemPoolError::Full.into());
+        }
+        // Only verified txs are remembered, so a forged signature over
+        // someone else's tx can't get the real one dropped
+        self.seen.insert(stx.tx_hash);
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let tx = PendingTx::new(seq, stx);
+        self.persist(&tx, false)?;
+        let mut by_price = self.by_price.lock().unwrap();
+        if let Some(queued) = replaced {
+            self.tx_map.remove(&queued.stx.tx_hash);
+            by_price.remove(&queued.price_key());
+            self.unpersist(&queued.stx.tx_hash);
+            println!(
+                "[mempool] {:?} replaced by {:?}",
+                queued.stx.tx_hash, tx.stx.tx_hash
+            );
+        }
+        by_price.insert(tx.price_key());
+        match by_nonce {
+            Entry::Occupied(mut queued) => {
+                queued.insert(tx.stx.tx_hash);
+            }
+            Entry::Vacant(queued) => {
+                queued.insert(tx.stx.tx_hash);
+            }
+        }
+        self.announce(&tx.stx);
+        self.tx_map.insert(tx.stx.tx_hash, tx);
+        Ok(())
+    }
+
+    async fn insert_priority(&self, stx: SignedTransaction) -> Result<()> {
+        if self.seen.contains(&stx.tx_hash) {
+            return Err(MemPoolError::Duplicate.into());
+        }
         self.verify_tx(&stx)?;
+        self.verify_nonce(&stx)?;
+        self.verify_requests(&stx)?;
         let _insert = self.flush_lock.read();
-        self.tx_map.insert(stx.tx_hash, stx);
+        self.seen.insert(stx.tx_hash);
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let tx = PendingTx::new(seq, stx);
+        self.persist(&tx, true)?;
+        self.announce(&tx.stx);
+        self.priority.insert(tx.stx.tx_hash, tx);
         Ok(())
     }
 
     async fn package(&self, total_limit: U64) -> Result<Vec<SignedTransaction>> {
+        let txs = self.build_block_template(total_limit).await?.txs;
+
+        let packaged = txs.len();
+        let floor = if packaged < self.priority.len() + self.tx_map.len() {
+            { txs.iter() }
+                .filter(|stx| !self.priority.contains_key(&stx.tx_hash))
+                .map(|stx| stx.raw.cycles_price.as_u64())
+                .min()
+                .unwrap_or_default()
+        } else {
+            0
+        };
+        self.fee_floor.store(floor, Ordering::SeqCst);
+
+        Ok(txs)
+    }
+
+    /// Operator transactions come first, in arrival order. The rest are
+    /// ordered by `cycles_price`, highest first, then by arrival. A
+    /// transaction whose nonce isn't its sender's next one waits for the
+    /// ones before it, and follows right after the last of them. They are
+    /// taken until the next one exceeds `total_limit`. The order only
+    /// depends on the pool content, never on map iteration.
+    async fn build_block_template(&self, total_limit: U64) -> Result<BlockTemplate> {
         let _package = self.flush_lock.write();
-        let mut sum_cycle = U64::zero();
+        let priority = self.queued_priority();
+        let by_price = self.by_price.lock().unwrap();
+        // Walks the index, only packaged transactions are cloned
+        let pending = { by_price.iter() }.filter_map(|(_, _, hash)| self.tx_map.get(hash));
 
-        Ok(self
-            .tx_map
-            .iter()
-            .take_while(|kv| {
-                let tx_limit = kv.value().cycle_limit();
-                if total_limit >= (sum_cycle + tx_limit) {
-                    sum_cycle += tx_limit;
-                    true
-                } else {
-                    false
+        let mut gate = NonceGate::new(self.state());
+        let mut cycles = U64::zero();
+        let mut txs = Vec::new();
+        'queued: for tx in priority
+            .into_iter()
+            .chain(pending.map(|kv| kv.value().clone()))
+        {
+            for tx in gate.release(tx) {
+                let tx_limit = tx.stx.cycle_limit();
+                if total_limit < (cycles + tx_limit) {
+                    break 'queued;
                 }
-            })
-            .map(|kv| kv.value().clone())
-            .collect::<Vec<_>>())
+                cycles += tx_limit;
+                txs.push(tx.stx);
+            }
+        }
+
+        Ok(BlockTemplate { cycles, txs })
     }
 
     async fn remove(&self, hashes: Vec<Hash>) -> Result<()> {
         let _flush = self.flush_lock.write();
         hashes.iter().for_each(|hash| {
-            let _ = self.tx_map.remove(hash);
+            self.remove_pending(hash);
+            self.remove_priority(hash);
         });
         Ok(())
     }
+
+    async fn contains(&self, hash: &Hash) -> bool {
+        self.tx_map.contains_key(hash) || self.priority.contains_key(hash)
+    }
+
+    /// Operator transactions only expire by their timeout.
+    async fn evict_expired(&self, next_number: U64) -> Result<Vec<ExpiredTransaction>> {
+        let next_number = next_number.as_u64();
+        self.next_number.store(next_number, Ordering::SeqCst);
+        let ttl = self.runtime.borrow().mempool_ttl_secs;
+        let ttl = Some(Duration::from_secs(ttl)).filter(|ttl| !ttl.is_zero());
+        let timed_out = |tx: &PendingTx| tx.stx.raw.expired_at(next_number);
+        let stale = |tx: &PendingTx| match tx.stx.raw.timeout {
+            Some(_) => timed_out(tx),
+            None => ttl.is_some_and(|ttl| tx.arrived.elapsed() >= ttl),
+        };
+        let hint = self.resubmission_hint().await;
+
+        let _flush = self.flush_lock.write();
+        let mut expired = { self.tx_map.iter() }
+            .filter(|kv| stale(kv.value()))
+            .chain(self.priority.iter().filter(|kv| timed_out(kv.value())))
+            .map(|kv| kv.value().clone())
+            .collect::<Vec<_>>();
+        expired.sort_by_key(|tx| tx.seq);
+
+        Ok({ expired.into_iter() }
+            .filter_map(|tx| {
+                let hash = tx.stx.tx_hash;
+                self.remove_pending(&hash)
+                    .or_else(|| self.remove_priority(&hash))
+            })
+            .map(|tx| ExpiredTransaction {
+                tx_hash: tx.stx.tx_hash,
+                sender:  tx.stx.raw.sender,
+                hint:    hint.clone(),
+            })
+            .collect())
+    }
+
+    async fn content(&self) -> Result<MemPoolContent> {
+        let _flush = self.flush_lock.write();
+        let (priority, pending) = self.queued();
+        let entries = |txs: Vec<PendingTx>| {
+            { txs.into_iter() }
+                .map(|tx| MemPoolEntry {
+                    age_secs: tx.arrived.elapsed().as_secs(),
+                    stx:      tx.stx,
+                })
+                .collect()
+        };
+
+        Ok(MemPoolContent {
+            priority: entries(priority),
+            pending:  entries(pending),
+        })
+    }
+
+    async fn size(&self) -> MemPoolSize {
+        MemPoolSize {
+            priority: self.priority.len(),
+            pending:  self.tx_map.len(),
+            capacity: self.runtime.borrow().mempool_size,
+        }
+    }
+
+    async fn next_nonce(&self, sender: &H160) -> Option<u64> {
+        let priority = { self.priority.iter() }
+            .filter(|kv| kv.value().stx.raw.sender == *sender)
+            .map(|kv| kv.value().stx.raw.nonce)
+            .collect::<HashSet<_>>();
+        let queued = |nonce: u64| {
+            let nonce = RawTransaction::nonce_of(nonce);
+            self.by_nonce.contains_key(&(*sender, nonce)) || priority.contains(&nonce)
+        };
+
+        let mut nonce = self.state().nonce(sender);
+        while queued(nonce) {
+            nonce += 1;
+        }
+        Some(nonce)
+    }
+
+    async fn resubmission_hint(&self) -> ResubmissionHint {
+        let min_cycles_price = self.runtime.borrow().min_cycles_price;
+        ResubmissionHint {
+            min_cycles_price: min_cycles_price.max(self.fee_floor.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+impl<DB: cita_trie::DB + 'static> MemPoolImpl<DB> {
+    /// Queue the transactions persisted before a restart again, in their
+    /// original arrival order. They are verified against the current state
+    /// and the ones that no longer pass are dropped. Expiry counts from the
+    /// restore.
+    pub async fn restore(&self) -> Result<usize> {
+        let store = match &self.store {
+            Some(store) => store.clone(),
+            None => return Ok(0),
+        };
+        let mut stored = Vec::new();
+        for kv in store.iter() {
+            let (key, raw) = kv?;
+            match StoredTx::decode(&Rlp::new(raw.as_ref())) {
+                Ok(tx) => stored.push(tx),
+                Err(e) => {
+                    println!("[mempool] dropped undecodable stored tx: {}", e);
+                    store.remove(key)?;
+                }
+            }
+        }
+        stored.sort_by_key(|tx| tx.seq);
+
+        let mut restored = 0;
+        for tx in stored {
+            let hash = tx.stx.tx_hash;
+            let queued = if tx.priority {
+                self.insert_priority(tx.stx).await
+            } else {
+                self.insert(tx.stx).await
+            };
+            match queued {
+                Ok(()) => restored += 1,
+                Err(e) => {
+                    println!("[mempool] dropped {:?} on restore: {}", hash, e);
+                    store.remove(hash)?;
+                }
+            }
+        }
+        Ok(restored)
+    }
 }
 
-impl MemPoolImpl {
-    pub fn new(pool_size: usize, id: U64) -> Self {
+impl<DB: cita_trie::DB> MemPoolImpl<DB> {
+    pub fn new(
+        runtime: watch::Receiver<RuntimeConfig>,
+        id: U64,
+        trie_db: Arc<DB>,
+        state_root: watch::Receiver<Hash>,
+    ) -> Self {
+        let pool_size = runtime.borrow().mempool_size;
         MemPoolImpl {
-            tx_map:     DashMap::with_capacity(pool_size),
+            tx_map: DashMap::with_capacity(pool_size),
+            by_price: Mutex::new(BTreeSet::new()),
+            by_nonce: DashMap::new(),
+            priority: DashMap::new(),
+            next_seq: AtomicU64::new(0),
             flush_lock: RwLock::new(()),
-            chain_id:   id,
+            chain_id: id,
+            runtime,
+            seen: RecentHashes::new(SEEN_CACHE_SIZE),
+            trie_db,
+            state_root,
+            tokens: HashSet::new(),
+            fee_token: None,
+            fee_floor: AtomicU64::new(0),
+            store: None,
+            next_number: AtomicU64::new(1),
+            tx_hooks: Vec::new(),
+            upgrades: Upgrades::default(),
         }
     }
 
+    /// Refuse transaction kinds whose protocol version isn't active at the
+    /// next block.
+    pub fn with_upgrades(mut self, upgrades: Upgrades) -> Self {
+        self.upgrades = upgrades;
+        self
+    }
+
+    pub fn with_tx_hook(mut self, hook: Arc<dyn OnNewTransaction>) -> Self {
+        self.tx_hooks.push(hook);
+        self
+    }
+
+    /// Keep queued transactions in a tree of `db`, so `restore` can reload
+    /// them after a restart.
+    pub fn persist_to(mut self, db: &sled::Db) -> Result<Self> {
+        self.store = Some(db.open_tree(MEMPOOL_TREE)?);
+        Ok(self)
+    }
+
+    /// Reject requests for tokens outside `tokens`, and count the fee in
+    /// `fee_token` among the debits of the fee payer.
+    pub fn with_tokens(mut self, tokens: HashSet<Hash>, fee_token: Option<Hash>) -> Self {
+        self.tokens = tokens;
+        self.fee_token = fee_token;
+        self
+    }
+
+    // Operator transactions in arrival order and the rest in packaging order
+    fn queued(&self) -> (Vec<PendingTx>, Vec<PendingTx>) {
+        let pending = { self.by_price.lock().unwrap().iter() }
+            .filter_map(|(_, _, hash)| self.tx_map.get(hash).map(|kv| kv.value().clone()))
+            .collect();
+
+        (self.queued_priority(), pending)
+    }
+
+    fn queued_priority(&self) -> Vec<PendingTx> {
+        let mut priority = { self.priority.iter() }
+            .map(|kv| kv.value().clone())
+            .collect::<Vec<_>>();
+        priority.sort_by_key(|tx| tx.seq);
+        priority
+    }
+
+    fn state(&self) -> StateView<DB> {
+        StateView::new(Arc::clone(&self.trie_db), *self.state_root.borrow())
+    }
+
+    fn remove_pending(&self, hash: &Hash) -> Option<PendingTx> {
+        let (_, tx) = self.tx_map.remove(hash)?;
+        self.by_price.lock().unwrap().remove(&tx.price_key());
+        self.unpersist(hash);
+        self.by_nonce
+            .remove_if(&(tx.stx.raw.sender, tx.stx.raw.nonce), |_, queued| {
+                queued == hash
+            });
+        Some(tx)
+    }
+
+    fn announce(&self, stx: &SignedTransaction) {
+        self.tx_hooks
+            .iter()
+            .for_each(|hook| hook.on_new_transaction(stx));
+    }
+
+    fn remove_priority(&self, hash: &Hash) -> Option<PendingTx> {
+        let (_, tx) = self.priority.remove(hash)?;
+        self.unpersist(hash);
+        Some(tx)
+    }
+
+    fn persist(&self, tx: &PendingTx, priority: bool) -> Result<()> {
+        if let Some(store) = &self.store {
+            let stored = StoredTx {
+                priority,
+                seq: tx.seq,
+                stx: tx.stx.clone(),
+            };
+            store.insert(tx.stx.tx_hash, stored.rlp_bytes().to_vec())?;
+        }
+        Ok(())
+    }
+
+    // The transaction has left the pool already, a failed delete only
+    // means it is verified again on the next restore
+    fn unpersist(&self, hash: &Hash) {
+        if let Some(Err(e)) = self.store.as_ref().map(|store| store.remove(hash)) {
+            println!("[mempool] failed to delete {:?}: {}", hash, e);
+        }
+    }
+
+    /// Cheap checks done before any signature is verified, so dust can't
+    /// take block space or verification time.
+    fn verify_limits(&self, stx: &SignedTransaction) -> Result<()> {
+        let runtime = self.runtime.borrow();
+        if stx.raw.cycles_price < runtime.min_cycles_price.into() {
+            return Err(MemPoolError::FeeTooLow(runtime.min_cycles_price).into());
+        }
+        if stx.raw.requests.len() > runtime.max_requests {
+            return Err(MemPoolError::TooManyRequests(runtime.max_requests).into());
+        }
+        if stx.signatures.len() > runtime.max_signatures {
+            return Err(MemPoolError::TooManySignatures(runtime.max_signatures).into());
+        }
+        // Counts first, they bound the cost of encoding
+        if stx.rlp_bytes().len() > TRANSACTION_LIMIT.max_size {
+            return Err(MemPoolError::TooLarge(TRANSACTION_LIMIT.max_size).into());
+        }
+
+        Ok(())
+    }
+
+    /// Used nonces can never be packaged. Later ones wait in the pool for
+    /// the ones before them, but only so far ahead.
+    fn verify_nonce(&self, stx: &SignedTransaction) -> Result<()> {
+        let expected = self.state().nonce(&stx.raw.sender);
+        match stx.raw.nonce_number() {
+            Some(nonce) if nonce < expected => Err(MemPoolError::NonceTooLow(expected).into()),
+            Some(nonce) if nonce - expected <= MAX_NONCE_GAP => Ok(()),
+            _ => Err(MemPoolError::NonceTooHigh(MAX_NONCE_GAP).into()),
+        }
+    }
+
+    /// Stateful checks against the latest state, so transactions bound to
+    /// fail don't take block space. Credits within the transaction aren't
+    /// counted, every debit has to be covered by the balance it starts from.
+    fn verify_requests(&self, stx: &SignedTransaction) -> Result<()> {
+        let mut active_debits = HashMap::<(H160, Hash), U256>::new();
+        let mut locked_debits = HashMap::<(H160, Hash), U256>::new();
+
+        for req in stx.raw.requests.iter() {
+            if req.amount.is_zero() {
+                return Err(MemPoolError::ZeroAmount.into());
+            }
+            if !self.tokens.is_empty() && !self.tokens.contains(&req.token_id) {
+                return Err(MemPoolError::UnknownToken.into());
+            }
+
+            let debits = match req.action {
+                TokenAction::Mint => continue,
+                TokenAction::Unlock => &mut locked_debits,
+                TokenAction::Lock | TokenAction::Divert | TokenAction::Transfer => {
+                    &mut active_debits
+                }
+            };
+            if req.address != stx.raw.sender {
+                return Err(MemPoolError::NotAccountOwner.into());
+            }
+            add_debit(debits, (req.address, req.token_id), req.amount)?;
+        }
+        if let Some(token) = self.fee_token {
+            add_debit(&mut active_debits, (stx.fee_payer(), token), stx.fee())?;
+            if let Some(alias) = &stx.raw.alias {
+                add_debit(&mut active_debits, (stx.raw.sender, token), alias.fee())?;
+            }
+        }
+
+        let state = self.state();
+        let covered = |debits: &HashMap<(H160, Hash), U256>, locked: bool| {
+            debits.iter().all(|((address, token_id), amount)| {
+                let balance = state.balance(address, token_id);
+                *amount
+                    <= if locked {
+                        balance.locked
+                    } else {
+                        balance.active
+                    }
+            })
+        };
+        if !covered(&active_debits, false) || !covered(&locked_debits, true) {
+            return Err(MemPoolError::InsufficientBalance.into());
+        }
+
+        Ok(())
+    }
+
     fn verify_tx(&self, stx: &SignedTransaction) -> Result<()> {
         if stx.chain_id() != self.chain_id {
-            return Err(anyhow!("Invalid chain id"));
+            return Err(MemPoolError::InvalidChainId.into());
+        }
+
+        let next_number = self.next_number.load(Ordering::SeqCst);
+        if !self.upgrades.allows(&stx.raw, next_number) {
+            return Err(MemPoolError::NotActivated(required_version(&stx.raw)).into());
+        }
+        if stx.raw.expired_at(next_number) {
+            return Err(MemPoolError::TimedOut(next_number).into());
         }
 
         if stx.cycle_limit() > TX_CYCLE_LIMIT {
-            return Err(anyhow!("Exceed tx cycle limit"));
+            return Err(MemPoolError::ExceedCycleLimit.into());
         }
 
         if Hasher::digest_(stx.raw.rlp_bytes()) != stx.tx_hash {
-            return Err(anyhow!("Tx hash diff"));
+            return Err(MemPoolError::HashMismatch.into());
         }
 
         if stx
@@ -92,16 +791,457 @@ impl MemPoolImpl {
             .iter()
             .any(|req| req.action == TokenAction::Transfer && req.to.is_none())
         {
-            return Err(anyhow!("Invalid transfer request"));
+            return Err(MemPoolError::InvalidRequest.into());
+        }
+
+        if let Some(config) = &stx.raw.multisig {
+            config.validate().map_err(MemPoolError::InvalidMultisig)?;
+        }
+
+        if let Some(alias) = &stx.raw.alias {
+            alias.validate().map_err(MemPoolError::InvalidAlias)?;
+        }
+
+        if let Some(sponsor) = &stx.sponsor {
+            if address_of(&sponsor.pub_key) != sponsor.address
+                || !verify_signature(
+                    &Sponsor::sig_hash(&stx.tx_hash),
+                    &sponsor.pub_key,
+                    &sponsor.signature,
+                )
+            {
+                return Err(MemPoolError::InvalidSponsor.into());
+            }
+        }
+
+        // The executor checks the set against the account's multisig config,
+        // here every signature of it only has to be valid
+        if !stx.signatures.is_empty() {
+            if stx
+                .signatures
+                .iter()
+                .any(|pair| !verify_signature(&stx.tx_hash, &pair.pub_key, &pair.signature))
+            {
+                return Err(MemPoolError::VerifySignature.into());
+            }
+            return Ok(());
         }
 
+        if address_of(&stx.pub_key) != stx.raw.sender {
+            return Err(MemPoolError::NotSenderKey.into());
+        }
         Secp256k1Signature::try_from(stx.signature.to_vec().as_ref())
-            .map_err(|_| anyhow!("Invalid signature"))?
+            .map_err(|_| MemPoolError::InvalidSignature)?
             .verify(
                 &HashValue::from_bytes_unchecked(stx.tx_hash.0),
                 &Secp256k1PublicKey::try_from(stx.pub_key.to_vec().as_ref())
-                    .map_err(|_| anyhow!("Invalid public key"))?,
+                    .map_err(|_| MemPoolError::InvalidPublicKey)?,
             )
-            .map_err(|_| anyhow!("Verify signature failed"))
+            .map_err(|_| MemPoolError::VerifySignature.into())
+    }
+}
+
+/// Releases each sender's transactions in nonce order, starting from the
+/// sender's nonce in the state. One with a later nonce is held back until
+/// the ones before it are released, one with a used nonce is dropped.
+struct NonceGate<DB: cita_trie::DB> {
+    state:   StateView<DB>,
+    next:    HashMap<H160, u64>,
+    waiting: HashMap<H160, BTreeMap<u64, PendingTx>>,
+}
+
+impl<DB: cita_trie::DB> NonceGate<DB> {
+    fn new(state: StateView<DB>) -> Self {
+        NonceGate {
+            state,
+            next: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    // `tx` if it's next, followed by the held back ones it unblocks
+    fn release(&mut self, tx: PendingTx) -> Vec<PendingTx> {
+        let sender = tx.stx.raw.sender;
+        let state = &self.state;
+        let next = { self.next.entry(sender) }.or_insert_with(|| state.nonce(&sender));
+        match tx.stx.raw.nonce_number() {
+            Some(nonce) if nonce == *next => (),
+            Some(nonce) if nonce > *next => {
+                // An operator transaction and a public one can share a
+                // nonce, the operator one comes first and wins
+                let waiting = self.waiting.entry(sender).or_default();
+                waiting.entry(nonce).or_insert(tx);
+                return Vec::new();
+            }
+            _ => return Vec::new(),
+        }
+
+        let mut released = vec![tx];
+        *next += 1;
+        if let Some(waiting) = self.waiting.get_mut(&sender) {
+            while let Some(tx) = waiting.remove(next) {
+                released.push(tx);
+                *next += 1;
+            }
+        }
+        released
+    }
+}
+
+fn add_debit(
+    debits: &mut HashMap<(H160, Hash), U256>,
+    key: (H160, Hash),
+    amount: U256,
+) -> Result<()> {
+    let debit = debits.entry(key).or_default();
+    *debit = debit
+        .checked_add(amount)
+        .ok_or(MemPoolError::InsufficientBalance)?;
+    Ok(())
+}
+
+/// Hashes of recently accepted transactions. Kept in two generations, the
+/// older one is dropped as a whole once the newer one fills up, so lookups
+/// stay O(1) without tracking per entry age.
+pub struct RecentHashes {
+    generations: Mutex<(HashSet<Hash>, HashSet<Hash>)>,
+    capacity:    usize,
+}
+
+impl RecentHashes {
+    pub fn new(capacity: usize) -> Self {
+        RecentHashes {
+            generations: Mutex::new((HashSet::new(), HashSet::new())),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        let generations = self.generations.lock().unwrap();
+        generations.0.contains(hash) || generations.1.contains(hash)
+    }
+
+    pub fn insert(&self, hash: Hash) {
+        let mut generations = self.generations.lock().unwrap();
+        if generations.0.len() >= self.capacity {
+            let current = std::mem::take(&mut generations.0);
+            generations.1 = current;
+        }
+        generations.0.insert(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cita_trie::MemoryDB;
+
+    use super::*;
+    use crate::dev::DevWallet;
+    use crate::executor::{Execute, Executor};
+    use crate::offline::UnsignedTransaction;
+    use crate::types::{RawTransaction, TransactionRequest};
+
+    fn mint(wallet: &DevWallet, cycles_price: u64, nonce: u64) -> SignedTransaction {
+        let raw = RawTransaction {
+            chain_id:     U64::one(),
+            cycles_price: cycles_price.into(),
+            cycles_limit: 1000u64.into(),
+            nonce:        Hash::from_low_u64_be(nonce),
+            requests:     vec![TransactionRequest {
+                address:  wallet.address,
+                token_id: Hash::from_low_u64_be(1),
+                amount:   1u64.into(),
+                action:   TokenAction::Mint,
+                to:       None,
+            }],
+            sender:       wallet.address,
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        };
+        UnsignedTransaction::new(raw).sign(&wallet.key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_package_by_price() {
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let mempool = MemPoolImpl::new(
+            runtime,
+            U64::one(),
+            Arc::new(MemoryDB::new(true)),
+            state_root,
+        );
+        let txs = [1, 5, 3, 5]
+            .iter()
+            .enumerate()
+            .map(|(i, price)| mint(&DevWallet::derive(i).unwrap(), *price, 0))
+            .collect::<Vec<_>>();
+        for stx in txs.iter() {
+            mempool.insert(stx.clone()).await.unwrap();
+        }
+        let operator = mint(&DevWallet::derive(4).unwrap(), 0, 0);
+        mempool.insert_priority(operator.clone()).await.unwrap();
+
+        // Operator first, then by price and arrival, within the limit
+        let template = mempool.build_block_template(4000u64.into()).await.unwrap();
+        let hashes = |txs: Vec<SignedTransaction>| {
+            txs.into_iter().map(|stx| stx.tx_hash).collect::<Vec<_>>()
+        };
+        assert_eq!(hashes(template.txs), vec![
+            operator.tx_hash,
+            txs[1].tx_hash,
+            txs[3].tx_hash,
+            txs[2].tx_hash
+        ]);
+
+        // Removed transactions leave the index
+        mempool
+            .remove(vec![operator.tx_hash, txs[1].tx_hash])
+            .await
+            .unwrap();
+        let content = mempool.content().await.unwrap();
+        assert!(content.priority.is_empty());
+        assert_eq!(
+            content
+                .pending
+                .iter()
+                .map(|e| e.stx.tx_hash)
+                .collect::<Vec<_>>(),
+            vec![txs[3].tx_hash, txs[2].tx_hash, txs[0].tx_hash]
+        );
+        assert_eq!(mempool.by_price.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_replace_by_fee() {
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let mempool = MemPoolImpl::new(
+            runtime,
+            U64::one(),
+            Arc::new(MemoryDB::new(true)),
+            state_root,
+        );
+        let wallet = DevWallet::derive(0).unwrap();
+        let original = mint(&wallet, 2, 1);
+        mempool.insert(original.clone()).await.unwrap();
+
+        let mut same_price = original.raw.clone();
+        same_price.requests[0].amount = 2u64.into();
+        let same_price = UnsignedTransaction::new(same_price)
+            .sign(&wallet.key)
+            .unwrap();
+        let err = mempool.insert(same_price).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemPoolError>(),
+            Some(&MemPoolError::ReplacementUnderpriced(2))
+        );
+
+        let replacement = mint(&wallet, 3, 1);
+        mempool.insert(replacement.clone()).await.unwrap();
+        assert!(!mempool.contains(&original.tx_hash).await);
+        assert!(mempool.contains(&replacement.tx_hash).await);
+        assert_eq!(mempool.size().await.pending, 1);
+        assert_eq!(mempool.by_price.lock().unwrap().len(), 1);
+
+        // Once packaged and removed the nonce is free in the pool again
+        mempool.remove(vec![replacement.tx_hash]).await.unwrap();
+        assert!(mempool.by_nonce.is_empty());
+    }
+
+    #[derive(Default)]
+    struct Announced(Mutex<Vec<Hash>>);
+
+    impl OnNewTransaction for Announced {
+        fn on_new_transaction(&self, stx: &SignedTransaction) {
+            self.0.lock().unwrap().push(stx.tx_hash);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce_accepted_transactions() {
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let announced = Arc::new(Announced::default());
+        let mempool = MemPoolImpl::new(
+            runtime,
+            U64::one(),
+            Arc::new(MemoryDB::new(true)),
+            state_root,
+        )
+        .with_tx_hook(Arc::clone(&announced) as Arc<dyn OnNewTransaction>);
+
+        let wallet = DevWallet::derive(0).unwrap();
+        let (first, next, replacement) = (
+            mint(&wallet, 1, 0),
+            mint(&wallet, 1, 1),
+            mint(&wallet, 2, 0),
+        );
+        let operator = mint(&DevWallet::derive(1).unwrap(), 0, 0);
+        mempool.insert(first.clone()).await.unwrap();
+        assert!(mempool.insert(first.clone()).await.is_err());
+        mempool.insert(next.clone()).await.unwrap();
+        mempool.insert(replacement.clone()).await.unwrap();
+        mempool.insert_priority(operator.clone()).await.unwrap();
+
+        // Rejected transactions aren't announced, replacements are
+        assert_eq!(*announced.0.lock().unwrap(), vec![
+            first.tx_hash,
+            next.tx_hash,
+            replacement.tx_hash,
+            operator.tx_hash
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_evict_timed_out() {
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (_root_tx, state_root) = watch::channel(Hash::zero());
+        let mempool = MemPoolImpl::new(
+            runtime,
+            U64::one(),
+            Arc::new(MemoryDB::new(true)),
+            state_root,
+        );
+        let timed = |wallet: &DevWallet, nonce: u64, timeout: u64| {
+            let mut raw = mint(wallet, 1, nonce).raw;
+            raw.timeout = Some(timeout.into());
+            UnsignedTransaction::new(raw).sign(&wallet.key).unwrap()
+        };
+        let wallet = DevWallet::derive(0).unwrap();
+        let early = timed(&wallet, 0, 2);
+        let late = timed(&wallet, 1, 5);
+        let untimed = mint(&DevWallet::derive(1).unwrap(), 1, 0);
+        for stx in [&early, &late, &untimed] {
+            mempool.insert(stx.clone()).await.unwrap();
+        }
+        let operator = timed(&DevWallet::derive(2).unwrap(), 0, 2);
+        mempool.insert_priority(operator.clone()).await.unwrap();
+
+        // Block 2 is the last one they fit in
+        assert!(mempool.evict_expired(2u64.into()).await.unwrap().is_empty());
+        let expired = mempool.evict_expired(3u64.into()).await.unwrap();
+        assert_eq!(
+            expired.iter().map(|tx| tx.tx_hash).collect::<Vec<_>>(),
+            vec![early.tx_hash, operator.tx_hash]
+        );
+        let size = mempool.size().await;
+        assert_eq!((size.priority, size.pending), (0, 2));
+
+        let err = mempool.insert(timed(&wallet, 2, 2)).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemPoolError>(),
+            Some(&MemPoolError::TimedOut(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = sled::open(dir.path()).unwrap();
+        let db = Arc::new(MemoryDB::new(true));
+        let open = || {
+            let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+            let (_root_tx, state_root) = watch::channel(Hash::zero());
+            MemPoolImpl::new(runtime, U64::one(), Arc::clone(&db), state_root)
+                .persist_to(&store)
+                .unwrap()
+        };
+        let (alice, bob) = (DevWallet::derive(0).unwrap(), DevWallet::derive(1).unwrap());
+        let txs = [mint(&alice, 2, 0), mint(&bob, 3, 0), mint(&alice, 4, 1)];
+        let operator = mint(&DevWallet::derive(2).unwrap(), 0, 0);
+        {
+            let mempool = open();
+            for stx in txs.iter() {
+                mempool.insert(stx.clone()).await.unwrap();
+            }
+            mempool.insert_priority(operator.clone()).await.unwrap();
+            mempool.insert(mint(&bob, 5, 0)).await.unwrap();
+            mempool.remove(vec![txs[0].tx_hash]).await.unwrap();
+        }
+
+        // The replaced and removed transactions stay gone
+        let mempool = open();
+        assert_eq!(mempool.restore().await.unwrap(), 3);
+        let content = mempool.content().await.unwrap();
+        assert_eq!(content.priority[0].stx.tx_hash, operator.tx_hash);
+        assert_eq!(
+            { content.pending.iter() }
+                .map(|e| e.stx.tx_hash)
+                .collect::<Vec<_>>(),
+            vec![mint(&bob, 5, 0).tx_hash, txs[2].tx_hash]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_package_in_nonce_order() {
+        let (_runtime_tx, runtime) = watch::channel(RuntimeConfig::default());
+        let (root_tx, state_root) = watch::channel(Hash::zero());
+        let db = Arc::new(MemoryDB::new(true));
+        let mempool = MemPoolImpl::new(runtime, U64::one(), Arc::clone(&db), state_root);
+        let (alice, bob) = (DevWallet::derive(0).unwrap(), DevWallet::derive(1).unwrap());
+
+        // Alice's later nonce pays more, it still waits for the earlier one
+        let txs = [
+            mint(&alice, 5, 1),
+            mint(&bob, 4, 0),
+            mint(&alice, 1, 0),
+            mint(&alice, 9, 3),
+        ];
+        for stx in txs.iter() {
+            mempool.insert(stx.clone()).await.unwrap();
+        }
+        assert_eq!(mempool.next_nonce(&alice.address).await, Some(2));
+        let template = mempool
+            .build_block_template(10_000u64.into())
+            .await
+            .unwrap();
+        let hashes = { template.txs.iter() }
+            .map(|stx| stx.tx_hash)
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, vec![txs[1].tx_hash, txs[2].tx_hash, txs[0].tx_hash]);
+
+        let resp = Executor::new(Arc::clone(&db)).exec(Hash::zero(), &template.txs);
+        assert!(resp.inner.iter().all(|resp| resp.error.is_none()));
+        // Out of order the executor refuses it
+        let replayed = Executor::new(Arc::clone(&db)).exec(resp.state_root, &txs[2..3]);
+        assert!(replayed.inner[0].error.is_some());
+
+        root_tx.send(resp.state_root).unwrap();
+        mempool.remove(hashes).await.unwrap();
+        assert_eq!(mempool.state().nonce(&alice.address), 2);
+        let err = mempool.insert(mint(&alice, 2, 1)).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemPoolError>(),
+            Some(&MemPoolError::NonceTooLow(2))
+        );
+        let err = mempool.insert(mint(&bob, 1, 66)).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MemPoolError>(),
+            Some(&MemPoolError::NonceTooHigh(MAX_NONCE_GAP))
+        );
+
+        // Nonce 3 is only held back until 2 shows up
+        assert!(mempool
+            .build_block_template(10_000u64.into())
+            .await
+            .unwrap()
+            .txs
+            .is_empty());
+        let gap = mint(&alice, 1, 2);
+        mempool.insert(gap.clone()).await.unwrap();
+        let template = mempool
+            .build_block_template(10_000u64.into())
+            .await
+            .unwrap();
+        assert_eq!(
+            template
+                .txs
+                .iter()
+                .map(|stx| stx.tx_hash)
+                .collect::<Vec<_>>(),
+            vec![gap.tx_hash, txs[3].tx_hash]
+        );
     }
 }
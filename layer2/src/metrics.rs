@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response};
+use jsonrpsee::server::logger::{HttpRequest, Logger, MethodKind, Params, TransportProtocol};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::watch;
+use tower::{Layer, Service};
+
+use crate::config::RuntimeConfig;
+
+// Upper bounds of the latency buckets in milliseconds, the last bucket
+// takes everything slower
+const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+// Larger request bodies aren't buffered for the slow query log
+const MAX_LOGGED_BODY: u64 = 64 * 1024;
+const MAX_LOGGED_PARAMS: usize = 512;
+const MAX_LOGGED_STRING: usize = 66;
+
+#[derive(Default)]
+struct MethodStats {
+    calls:    u64,
+    errors:   u64,
+    total_ms: u64,
+    buckets:  [u64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+/// Calls, errors and latency of one RPC method.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MethodMetrics {
+    pub method:     String,
+    pub calls:      u64,
+    pub errors:     u64,
+    pub error_rate: f64,
+    pub mean_ms:    u64,
+    pub latency_ms: Vec<LatencyBucket>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LatencyBucket {
+    // None for the bucket of calls slower than every bound
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+/// Per method counters shared by every RPC server of the node, recorded as
+/// their logger. Latency runs from the start of the request, so calls of a
+/// batch include the calls before them.
+#[derive(Clone)]
+pub struct RpcMetrics {
+    methods: Arc<Mutex<BTreeMap<String, MethodStats>>>,
+    runtime: watch::Receiver<RuntimeConfig>,
+}
+
+impl RpcMetrics {
+    pub fn new(runtime: watch::Receiver<RuntimeConfig>) -> Self {
+        RpcMetrics {
+            methods: Default::default(),
+            runtime,
+        }
+    }
+
+    pub fn report(&self) -> Vec<MethodMetrics> {
+        let methods = self.methods.lock().unwrap();
+        { methods.iter() }
+            .map(|(method, stats)| MethodMetrics {
+                method:     method.clone(),
+                calls:      stats.calls,
+                errors:     stats.errors,
+                error_rate: stats.errors as f64 / stats.calls.max(1) as f64,
+                mean_ms:    stats.total_ms / stats.calls.max(1),
+                latency_ms: { stats.buckets.iter().enumerate() }
+                    .map(|(idx, count)| LatencyBucket {
+                        le_ms: LATENCY_BUCKETS_MS.get(idx).copied(),
+                        count: *count,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn record(&self, method: &str, success: bool, elapsed_ms: u64) {
+        let mut methods = self.methods.lock().unwrap();
+        let stats = methods.entry(method.to_owned()).or_default();
+        stats.calls += 1;
+        stats.errors += u64::from(!success);
+        stats.total_ms += elapsed_ms;
+        let bucket = { LATENCY_BUCKETS_MS.iter() }
+            .position(|bound| elapsed_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        stats.buckets[bucket] += 1;
+    }
+}
+
+impl Logger for RpcMetrics {
+    type Instant = Instant;
+
+    fn on_connect(&self, _: SocketAddr, _: &HttpRequest, _: TransportProtocol) {}
+
+    fn on_request(&self, _: TransportProtocol) -> Self::Instant {
+        Instant::now()
+    }
+
+    fn on_call(&self, _: &str, _: Params, _: MethodKind, _: TransportProtocol) {}
+
+    fn on_result(&self, method: &str, success: bool, started_at: Instant, _: TransportProtocol) {
+        self.record(method, success, started_at.elapsed().as_millis() as u64);
+    }
+
+    fn on_response(&self, _: &str, _: Instant, _: TransportProtocol) {}
+
+    fn on_disconnect(&self, _: SocketAddr, _: TransportProtocol) {}
+}
+
+/// Logs HTTP requests slower than `runtime.slow_rpc_ms` with their
+/// sanitized parameters. WebSocket calls only show up in the metrics.
+#[derive(Clone)]
+pub struct SlowQueryLayer {
+    server:  &'static str,
+    runtime: watch::Receiver<RuntimeConfig>,
+}
+
+impl SlowQueryLayer {
+    pub fn new(server: &'static str, metrics: &RpcMetrics) -> Self {
+        SlowQueryLayer {
+            server,
+            runtime: metrics.runtime.clone(),
+        }
+    }
+}
+
+impl<S> Layer<S> for SlowQueryLayer {
+    type Service = SlowQuery<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SlowQuery {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SlowQuery<S> {
+    inner: S,
+    layer: SlowQueryLayer,
+}
+
+impl<S> Service<Request<Body>> for SlowQuery<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let threshold_ms = self.layer.runtime.borrow().slow_rpc_ms;
+        let small = { req.body().size_hint().upper() }
+            .map(|size| size <= MAX_LOGGED_BODY)
+            .unwrap_or(false);
+        if threshold_ms == 0 || req.method() != Method::POST || !small {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+
+        // The inner service was polled ready, the clone takes its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let server = self.layer.server;
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+
+            let started_at = Instant::now();
+            let resp = inner
+                .call(Request::from_parts(parts, Body::from(body.clone())))
+                .await
+                .map_err(Into::into)?;
+            let elapsed_ms = started_at.elapsed().as_millis() as u64;
+            if elapsed_ms >= threshold_ms {
+                let calls = describe_calls(&body);
+                println!("[rpc] slow {} request {}ms: {}", server, elapsed_ms, calls);
+            }
+
+            Ok(resp)
+        })
+    }
+}
+
+// Methods and parameters of a single or batch request
+fn describe_calls(body: &[u8]) -> String {
+    let calls = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(calls)) => calls,
+        Ok(call) => vec![call],
+        Err(_) => return "<invalid json>".to_owned(),
+    };
+
+    { calls.iter() }
+        .map(|call| {
+            let method = call["method"].as_str().unwrap_or("<none>");
+            let mut params = serde_json::to_string(&sanitize(&call["params"])).unwrap_or_default();
+            if params.len() > MAX_LOGGED_PARAMS {
+                params = format!("{}...", truncate(&params, MAX_LOGGED_PARAMS));
+            }
+            format!("{}({})", method, params)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Secrets are redacted and long strings, signatures and payloads mostly,
+/// cut short.
+pub fn sanitize(value: &Value) -> Value {
+    match value {
+        Value::String(s) if s.len() > MAX_LOGGED_STRING => {
+            Value::String(format!("{}...", truncate(s, MAX_LOGGED_STRING)))
+        }
+        Value::Array(values) => Value::Array(values.iter().map(sanitize).collect()),
+        Value::Object(fields) => Value::Object(
+            { fields.iter() }
+                .map(|(key, value)| {
+                    let key_lower = key.to_lowercase();
+                    let secret = ["secret", "private", "password"]
+                        .iter()
+                        .any(|word| key_lower.contains(word));
+                    if secret {
+                        (key.clone(), Value::String("<redacted>".to_owned()))
+                    } else {
+                        (key.clone(), sanitize(value))
+                    }
+                })
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
@@ -0,0 +1,81 @@
+use std::collections::HashSet;
+
+use derive_more::Display;
+use ophelia::{HashValue, SignatureVerify};
+use ophelia_secp256k1::{Secp256k1PublicKey, Secp256k1Signature};
+
+use crate::types::{Hash, Hasher, MultisigConfig, SignaturePair, H160};
+
+pub const MAX_SIGNERS: usize = 16;
+const MULTISIG_KEY_PREFIX: &[u8] = b"multisig";
+
+#[derive(Display, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MultisigError {
+    #[display(fmt = "Threshold must be between 1 and the number of keys")]
+    Threshold,
+    #[display(fmt = "Too many multisig keys")]
+    TooManyKeys,
+    #[display(fmt = "Duplicate multisig key")]
+    DuplicateKey,
+    #[display(fmt = "Invalid multisig key")]
+    InvalidKey,
+}
+
+/// State trie key of an account's multisig config. Accounts are keyed by
+/// their 20 bytes address, so the 32 bytes hash can't collide with one.
+pub fn multisig_key(address: &H160) -> Hash {
+    Hasher::digest_([MULTISIG_KEY_PREFIX, address.as_bytes()].concat())
+}
+
+/// Address controlled by a single key, the last 20 bytes of its hash.
+pub fn address_of(pub_key: &[u8]) -> H160 {
+    H160::from_slice(&Hasher::digest_(pub_key)[12..])
+}
+
+pub fn verify_signature(tx_hash: &Hash, pub_key: &[u8], signature: &[u8]) -> bool {
+    let (signature, pub_key) = match (
+        Secp256k1Signature::try_from(signature),
+        Secp256k1PublicKey::try_from(pub_key),
+    ) {
+        (Ok(signature), Ok(pub_key)) => (signature, pub_key),
+        _ => return false,
+    };
+
+    signature
+        .verify(&HashValue::from_bytes_unchecked(tx_hash.0), &pub_key)
+        .is_ok()
+}
+
+impl MultisigConfig {
+    pub fn validate(&self) -> Result<(), MultisigError> {
+        if self.pub_keys.len() > MAX_SIGNERS {
+            return Err(MultisigError::TooManyKeys);
+        }
+        if self.threshold == 0 || self.threshold as usize > self.pub_keys.len() {
+            return Err(MultisigError::Threshold);
+        }
+
+        let mut keys = HashSet::new();
+        for pub_key in self.pub_keys.iter() {
+            Secp256k1PublicKey::try_from(pub_key.as_ref())
+                .map_err(|_| MultisigError::InvalidKey)?;
+            if !keys.insert(pub_key) {
+                return Err(MultisigError::DuplicateKey);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether at least `threshold` distinct keys of this config signed
+    /// `tx_hash`. Signatures from other keys are ignored.
+    pub fn is_satisfied(&self, tx_hash: &Hash, signatures: &[SignaturePair]) -> bool {
+        let signers = { signatures.iter() }
+            .filter(|pair| self.pub_keys.contains(&pair.pub_key))
+            .filter(|pair| verify_signature(tx_hash, &pair.pub_key, &pair.signature))
+            .map(|pair| &pair.pub_key)
+            .collect::<HashSet<_>>();
+
+        signers.len() >= self.threshold as usize
+    }
+}
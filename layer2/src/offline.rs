@@ -0,0 +1,196 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use hyper::{Body, Client, Request};
+use ophelia::{HashValue, PrivateKey, PublicKey, Signature, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use rlp::Encodable;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use share::address::{self, Network};
+use share::message::personal_message_hash;
+
+use crate::multisig::{address_of, verify_signature};
+use crate::types::{
+    AliasRecord, Bytes, Hash, Hasher, RawTransaction, SignedTransaction, Sponsor, H160,
+};
+
+// Bumped whenever the layout of the exported file changes
+pub const UNSIGNED_TX_FORMAT: u8 = 1;
+
+/// A transaction exported for signing on an offline machine. It carries the
+/// whole raw transaction, so the signer recomputes the sig hash itself
+/// instead of signing whatever hash it was handed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UnsignedTransaction {
+    pub format:   u8,
+    pub raw:      RawTransaction,
+    pub sig_hash: Hash,
+}
+
+impl UnsignedTransaction {
+    pub fn new(raw: RawTransaction) -> Self {
+        UnsignedTransaction {
+            format: UNSIGNED_TX_FORMAT,
+            sig_hash: Hasher::digest_(raw.rlp_bytes()),
+            raw,
+        }
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        if self.format != UNSIGNED_TX_FORMAT {
+            return Err(anyhow!("unsupported unsigned tx format {}", self.format));
+        }
+        if Hasher::digest_(self.raw.rlp_bytes()) != self.sig_hash {
+            return Err(anyhow!("sig_hash doesn't match the raw transaction"));
+        }
+
+        Ok(())
+    }
+
+    pub fn sign(self, key: &Secp256k1PrivateKey) -> Result<SignedTransaction> {
+        self.verify()?;
+
+        let signature = key.sign_message(&HashValue::from_bytes_unchecked(self.sig_hash.0));
+        Ok(SignedTransaction {
+            raw:        self.raw,
+            tx_hash:    self.sig_hash,
+            pub_key:    key.pub_key().to_bytes(),
+            signature:  signature.to_bytes(),
+            signatures: Vec::new(),
+            sponsor:    None,
+        })
+    }
+}
+
+/// Attach a sponsorship, so `key` pays the cycles of `stx`.
+pub fn sponsor(mut stx: SignedTransaction, key: &Secp256k1PrivateKey) -> SignedTransaction {
+    let pub_key = key.pub_key().to_bytes();
+    let signature = key.sign_message(&HashValue::from_bytes_unchecked(
+        Sponsor::sig_hash(&stx.tx_hash).0,
+    ));
+
+    stx.sponsor = Some(Sponsor {
+        address: address_of(&pub_key),
+        pub_key,
+        signature: signature.to_bytes(),
+    });
+    stx
+}
+
+/// A personal message signed over its EIP-191 hash. Layer2 signatures
+/// don't recover the key, so it comes along to check against `address`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedMessage {
+    pub address:   H160,
+    pub message:   String,
+    #[serde(with = "crate::serde_hex")]
+    pub pub_key:   Bytes,
+    #[serde(with = "crate::serde_hex")]
+    pub signature: Bytes,
+}
+
+impl SignedMessage {
+    pub fn sign(key: &Secp256k1PrivateKey, message: String) -> Self {
+        let hash = HashValue::from_bytes_unchecked(personal_message_hash(message.as_bytes()).0);
+        let pub_key = key.pub_key().to_bytes();
+        SignedMessage {
+            address: address_of(&pub_key),
+            signature: key.sign_message(&hash).to_bytes(),
+            message,
+            pub_key,
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        let hash = Hash::from(personal_message_hash(self.message.as_bytes()).0);
+        address_of(&self.pub_key) == self.address
+            && verify_signature(&hash, &self.pub_key, &self.signature)
+    }
+}
+
+/// Indexes of the messages whose signature doesn't verify, empty when
+/// every one does.
+pub fn verify_messages(messages: &[SignedMessage]) -> Vec<usize> {
+    { messages.iter().enumerate() }
+        .filter(|(_, message)| !message.verify())
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Read a hex encoded secp256k1 private key, with or without `0x`.
+pub fn read_private_key(path: &Path) -> Result<Secp256k1PrivateKey> {
+    let raw = fs::read_to_string(path).with_context(|| format!("read key {}", path.display()))?;
+    let key = Hash::from_str(raw.trim().trim_start_matches("0x"))
+        .map_err(|e| anyhow!("key {} is not 32 bytes hex: {}", path.display(), e))?;
+
+    Secp256k1PrivateKey::try_from(key.as_bytes())
+        .map_err(|e| anyhow!("invalid key {}: {}", path.display(), e))
+}
+
+/// Parse a checksummed address of `network`. Raw hex is still accepted for
+/// scripts written before the checksummed format.
+pub fn parse_address(raw: &str, network: Network) -> Result<H160> {
+    if let Some(hex) = raw.strip_prefix("0x") {
+        return H160::from_str(hex).map_err(|e| anyhow!("invalid address {}: {}", raw, e));
+    }
+
+    address::parse_address(raw, network).map_err(|e| anyhow!("invalid address {}: {}", raw, e))
+}
+
+pub fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    let raw = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    serde_json::from_slice(&raw).with_context(|| format!("parse {}", path.display()))
+}
+
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("write {}", path.display()))
+}
+
+/// Submit a transaction signed offline to a node through `send_transaction`.
+/// Retries with the same `idempotency_key` return the hash of the
+/// transaction the node accepted first.
+pub async fn broadcast(
+    rpc_url: &str,
+    stx: &SignedTransaction,
+    idempotency_key: Option<&str>,
+) -> Result<Hash> {
+    let result = rpc_call(rpc_url, "send_transaction", json!([stx, idempotency_key]))
+        .await
+        .map_err(|e| anyhow!("node rejected the transaction: {}", e))?;
+
+    match result.get("tx_hash") {
+        Some(tx_hash) => Ok(serde_json::from_value(tx_hash.clone())?),
+        None => Ok(stx.tx_hash),
+    }
+}
+
+/// Where the alias points, none if it's not registered or expired.
+pub async fn resolve_alias(rpc_url: &str, name: &str) -> Result<Option<AliasRecord>> {
+    let result = rpc_call(rpc_url, "resolve_alias", json!([name])).await?;
+    Ok(serde_json::from_value(result)?)
+}
+
+// The `result` of the call, its `error` as the error
+pub(crate) async fn rpc_call(rpc_url: &str, method: &str, params: Value) -> Result<Value> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+    let req = Request::post(rpc_url)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(&body)?))?;
+
+    let resp = Client::new().request(req).await?;
+    let mut resp: Value = serde_json::from_slice(&hyper::body::to_bytes(resp).await?)?;
+    if let Some(err) = resp.get("error") {
+        return Err(anyhow!("{}", err));
+    }
+
+    Ok(resp["result"].take())
+}
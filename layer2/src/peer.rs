@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use ophelia::{PrivateKey, PublicKey, ToPublicKey};
+use ophelia_secp256k1::Secp256k1PrivateKey;
+use rand::rngs::OsRng;
+use rlp::{Decodable, Encodable, Rlp};
+use rlp_derive::{RlpDecodable, RlpEncodable};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Bytes, Hash, Hasher, U64};
+use crate::upgrade::{Upgrades, PROTOCOL_VERSION};
+
+const BAN_TREE: &[u8] = b"peer_ban_tree";
+// Peers reported down to this score are banned automatically
+const BAN_SCORE: i32 = -100;
+const AUTO_BAN_SECS: u64 = 60 * 60;
+
+/// Persistent key identifying this node to its peers.
+pub struct NodeIdentity {
+    private_key: Secp256k1PrivateKey,
+    pub pub_key: Bytes,
+    pub peer_id: Hash,
+}
+
+impl NodeIdentity {
+    /// Load the node key at `path`, generating and saving a fresh one on
+    /// first start.
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        let private_key = if path.exists() {
+            Secp256k1PrivateKey::try_from(fs::read(path)?.as_ref())
+                .map_err(|e| anyhow!("invalid node key {}: {}", path.display(), e))?
+        } else {
+            let private_key = Secp256k1PrivateKey::generate(&mut OsRng);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, private_key.to_bytes())?;
+            private_key
+        };
+
+        let pub_key = private_key.pub_key().to_bytes();
+        Ok(NodeIdentity {
+            peer_id: Hasher::digest_(&pub_key),
+            pub_key,
+            private_key,
+        })
+    }
+
+    pub fn private_key(&self) -> &Secp256k1PrivateKey {
+        &self.private_key
+    }
+
+    pub fn handshake(&self, chain_id: U64, tip: U64) -> Handshake {
+        Handshake {
+            peer_id: self.peer_id,
+            chain_id,
+            protocol_version: PROTOCOL_VERSION,
+            tip,
+        }
+    }
+}
+
+/// Sent by both sides when two nodes connect.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub peer_id:          Hash,
+    pub chain_id:         U64,
+    // Highest protocol version the node speaks
+    pub protocol_version: u32,
+    pub tip:              U64,
+}
+
+impl Handshake {
+    /// Version to talk to `remote` in, the highest both speak. Fails for a
+    /// peer of another chain, or when either side can't follow the rules of
+    /// the next block after the higher tip.
+    pub fn negotiate(&self, remote: &Handshake, upgrades: &Upgrades) -> Result<u32> {
+        if remote.chain_id != self.chain_id {
+            return Err(anyhow!(
+                "peer {:?} is on chain {}, not {}",
+                remote.peer_id,
+                remote.chain_id,
+                self.chain_id
+            ));
+        }
+
+        let version = self.protocol_version.min(remote.protocol_version);
+        let active = upgrades.version_at(self.tip.max(remote.tip).as_u64() + 1);
+        if version < active {
+            return Err(anyhow!(
+                "protocol version {} is active, peer {:?} speaks {} and this node {}",
+                active,
+                remote.peer_id,
+                remote.protocol_version,
+                self.protocol_version
+            ));
+        }
+        Ok(version)
+    }
+}
+
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct PeerBan {
+    pub peer_id: Hash,
+    pub reason:  String,
+    // Unix seconds, 0 means banned until lifted
+    pub until:   u64,
+}
+
+/// Scores peers on their behaviour and keeps a persisted ban list, so
+/// connections from banned peers can be refused before any work is done.
+pub struct PeerManager {
+    scores: DashMap<Hash, i32>,
+    bans:   sled::Tree,
+}
+
+impl PeerManager {
+    pub fn new(db: &sled::Db) -> Result<Self> {
+        Ok(PeerManager {
+            scores: DashMap::new(),
+            bans:   db.open_tree(BAN_TREE)?,
+        })
+    }
+
+    /// Adjust the score of a peer, banning it once it drops to `BAN_SCORE`.
+    pub fn report(&self, peer_id: Hash, delta: i32, reason: &str) -> Result<i32> {
+        let score = {
+            let mut score = self.scores.entry(peer_id).or_default();
+            *score = score.saturating_add(delta);
+            *score
+        };
+
+        if score <= BAN_SCORE {
+            self.ban(peer_id, reason.to_owned(), Some(AUTO_BAN_SECS))?;
+        }
+        Ok(score)
+    }
+
+    pub fn score(&self, peer_id: &Hash) -> i32 {
+        self.scores.get(peer_id).map(|s| *s).unwrap_or_default()
+    }
+
+    pub fn ban(&self, peer_id: Hash, reason: String, duration_secs: Option<u64>) -> Result<()> {
+        let ban = PeerBan {
+            peer_id,
+            reason,
+            until: duration_secs.map(|d| time_now() + d).unwrap_or_default(),
+        };
+        self.bans.insert(peer_id, ban.rlp_bytes().to_vec())?;
+        Ok(())
+    }
+
+    pub fn unban(&self, peer_id: &Hash) -> Result<()> {
+        self.bans.remove(peer_id)?;
+        self.scores.remove(peer_id);
+        Ok(())
+    }
+
+    pub fn is_banned(&self, peer_id: &Hash) -> Result<bool> {
+        match self.bans.get(peer_id)? {
+            None => Ok(false),
+            Some(raw) => Ok(!expired(&PeerBan::decode(&Rlp::new(raw.as_ref()))?)),
+        }
+    }
+
+    pub fn banned(&self) -> Result<Vec<PeerBan>> {
+        let mut bans = Vec::new();
+        for kv in self.bans.iter() {
+            let ban = PeerBan::decode(&Rlp::new(kv?.1.as_ref()))?;
+            if !expired(&ban) {
+                bans.push(ban);
+            }
+        }
+
+        Ok(bans)
+    }
+}
+
+fn expired(ban: &PeerBan) -> bool {
+    ban.until != 0 && ban.until <= time_now()
+}
+
+fn time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+
+use crate::chain::Chain;
+use crate::executor::{Execute, Executor, FeeConfig};
+use crate::types::{Hash, TransactionReceipt, U64};
+use crate::upgrade::Upgrades;
+
+/// Reads through to the node's trie and keeps every write in memory, so a
+/// replay never touches the state it checks.
+pub struct OverlayDB<DB> {
+    inner:  Arc<DB>,
+    // `None` marks a removal
+    writes: RwLock<HashMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl<DB> OverlayDB<DB> {
+    pub fn new(inner: Arc<DB>) -> Self {
+        OverlayDB {
+            inner,
+            writes: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<DB: cita_trie::DB> cita_trie::DB for OverlayDB<DB> {
+    type Error = DB::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.writes.read().unwrap().get(key) {
+            return Ok(value.clone());
+        }
+        self.inner.get(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if let Some(value) = self.writes.read().unwrap().get(key) {
+            return Ok(value.is_some());
+        }
+        self.inner.contains(key)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.writes.write().unwrap().insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.writes.write().unwrap().insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// First point where the replay disagrees with what the chain stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    StateRoot {
+        number:   U64,
+        stored:   Hash,
+        replayed: Hash,
+    },
+    Receipt {
+        number:   U64,
+        tx_hash:  Hash,
+        stored:   Box<TransactionReceipt>,
+        replayed: Box<TransactionReceipt>,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::StateRoot {
+                number,
+                stored,
+                replayed,
+            } => write!(
+                f,
+                "block {} state root is {:?}, replayed {:?}",
+                number, stored, replayed
+            ),
+            Divergence::Receipt {
+                number,
+                tx_hash,
+                stored,
+                replayed,
+            } => write!(
+                f,
+                "block {} receipt of {:?} is {:?}, replayed {:?}",
+                number, tx_hash, stored, replayed
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    // Blocks executed, including the diverging one
+    pub blocks:     u64,
+    // Receipts compared, pruned ones are skipped
+    pub receipts:   u64,
+    pub state_root: Hash,
+    pub divergence: Option<Divergence>,
+}
+
+/// Execute blocks `from..=to` of `chain` again on top of `state_root`, the
+/// state the first block was executed on by default, and stop at the first
+/// state root or receipt that differs from the stored one.
+///
+/// Legacy headers don't carry their post state root, theirs is checked
+/// against the state the next block was executed on. Fees are charged in
+/// `fee_token` to the proposer of every block, as consensus does.
+pub async fn replay_blocks<DB, C>(
+    trie_db: Arc<DB>,
+    chain: &C,
+    from: U64,
+    to: U64,
+    state_root: Option<Hash>,
+    fee_token: Option<Hash>,
+    upgrades: Upgrades,
+) -> Result<ReplayReport>
+where
+    DB: cita_trie::DB,
+    C: Chain,
+{
+    if from > to {
+        return Err(anyhow!("empty block range {}..={}", from, to));
+    }
+
+    let db = Arc::new(OverlayDB::new(trie_db));
+    let mut report = ReplayReport::default();
+    let mut number = from;
+    while number <= to {
+        let block = chain
+            .get_block_by_number(&number)
+            .await?
+            .ok_or_else(|| anyhow!("block {} not found", number))?;
+        let header = &block.header;
+
+        if number == from {
+            report.state_root = state_root.unwrap_or(header.prev_state_root);
+        } else if report.state_root != header.prev_state_root {
+            report.divergence = Some(Divergence::StateRoot {
+                number:   number - U64::one(),
+                stored:   header.prev_state_root,
+                replayed: report.state_root,
+            });
+            return Ok(report);
+        }
+
+        let fee = fee_token.map(|token| FeeConfig {
+            token,
+            recipient: header.proposer,
+        });
+        let resp = Executor::new(Arc::clone(&db))
+            .with_fee(fee)
+            .with_upgrades(upgrades)
+            .at_block(number.as_u64())
+            .exec(report.state_root, &block.txs);
+        report.blocks += 1;
+        report.state_root = resp.state_root;
+
+        for (index, resp) in resp.inner.into_iter().enumerate() {
+            let replayed = TransactionReceipt::new(number, index, resp);
+            let stored = match chain.get_receipt(&replayed.tx_hash).await? {
+                Some(stored) => stored,
+                None => continue,
+            };
+            report.receipts += 1;
+            if stored != replayed {
+                report.divergence = Some(Divergence::Receipt {
+                    number,
+                    tx_hash: replayed.tx_hash,
+                    stored: Box::new(stored),
+                    replayed: Box::new(replayed),
+                });
+                return Ok(report);
+            }
+        }
+
+        if !header.is_legacy() && header.post_state_root != report.state_root {
+            report.divergence = Some(Divergence::StateRoot {
+                number,
+                stored: header.post_state_root,
+                replayed: report.state_root,
+            });
+            return Ok(report);
+        }
+        number += U64::one();
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,561 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sled::{Db, Error};
+use tokio::time::interval;
+
+use crate::api::SyncSource;
+use crate::attestation::{verify_quorum, SignedAttestation};
+use crate::chain::{Chain, CovalentChain};
+use crate::consensus::ValidatorSet;
+use crate::mempool::{
+    BlockTemplate, ExpiredTransaction, MemPool, MemPoolContent, MemPoolError, MemPoolSize,
+    ResubmissionHint,
+};
+use crate::trie::RocksTrieDB;
+use crate::types::{
+    Block, BlockUsage, Hash, Header, LogEntry, SignedTransaction, TransactionReceipt, H160, U64,
+};
+
+const CHAIN_DIR: &str = "state_data";
+const TRIE_DIR: &str = "trie_data";
+const ATTESTATION_FILE: &str = "attestations.json";
+
+/// Where and how often the producing node writes snapshots of its chain
+/// and trie databases for read replicas to follow.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    pub dir:          PathBuf,
+    pub every_blocks: u64,
+    // Older snapshots are deleted
+    pub keep:         usize,
+}
+
+/// A database that can copy itself into a new database at `path`.
+pub trait Snapshot {
+    fn snapshot_to(&self, path: &Path) -> Result<()>;
+}
+
+pub fn export_db(db: &Db, path: &Path) -> Result<()> {
+    let target = sled::open(path)?;
+    target.import(db.export());
+    target.flush()?;
+    Ok(())
+}
+
+/// Write the snapshot of block `number` under `policy.dir`. It only appears
+/// under its final name once complete, so replicas never load half of one.
+pub fn ship_snapshot<C: Snapshot, DB: Snapshot>(
+    policy: &SnapshotPolicy,
+    number: u64,
+    chain: &C,
+    trie_db: &DB,
+) -> Result<PathBuf> {
+    let tmp = policy.dir.join(format!("{:020}.tmp", number));
+    fs::create_dir_all(&policy.dir)?;
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    chain.snapshot_to(&tmp.join(CHAIN_DIR))?;
+    trie_db.snapshot_to(&tmp.join(TRIE_DIR))?;
+
+    let path = snapshot_path(&policy.dir, number);
+    fs::rename(&tmp, &path)?;
+    for old in snapshots(&policy.dir)?.into_iter().rev().skip(policy.keep) {
+        fs::remove_dir_all(snapshot_path(&policy.dir, old))?;
+    }
+
+    Ok(path)
+}
+
+/// Write the attestations of block `number` next to its snapshot, in place
+/// of those written before. Nothing is written before the snapshot is
+/// shipped or once it's deleted.
+pub fn attach_attestations(
+    dir: &Path,
+    number: u64,
+    attestations: &[SignedAttestation],
+) -> Result<()> {
+    let path = snapshot_path(dir, number);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let tmp = path.join(format!("{}.tmp", ATTESTATION_FILE));
+    fs::write(&tmp, serde_json::to_vec(attestations)?)?;
+    fs::rename(&tmp, path.join(ATTESTATION_FILE))?;
+    Ok(())
+}
+
+fn read_attestations(path: &Path) -> Result<Vec<SignedAttestation>> {
+    match fs::read(path.join(ATTESTATION_FILE)) {
+        Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Validators a replica needs `size` attestations of on a snapshot before
+/// serving it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotQuorum {
+    pub validators: ValidatorSet,
+    pub size:       usize,
+}
+
+fn snapshot_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}", number))
+}
+
+// Block numbers of the complete snapshots in `dir`, oldest first
+fn snapshots(dir: &Path) -> Result<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(number) = name.to_str().and_then(|n| n.parse::<u64>().ok()) {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+
+    Ok(numbers)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+
+    Ok(())
+}
+
+struct Generation {
+    number:  u64,
+    chain:   CovalentChain,
+    trie_db: RocksTrieDB,
+}
+
+/// Read only view of the chain and state, following the snapshots a
+/// producing node ships. Every replica copies a snapshot to its own
+/// `local` dir before opening it, so any number of them can share one
+/// snapshot dir. With a quorum, a snapshot is only served once enough
+/// validators attested the state of its block.
+pub struct Replica {
+    snapshots: PathBuf,
+    local:     PathBuf,
+    quorum:    Option<SnapshotQuorum>,
+    current:   RwLock<Arc<Generation>>,
+}
+
+impl Replica {
+    /// Open the newest snapshot, the newest attested one with a quorum.
+    pub async fn open(
+        snapshots: PathBuf,
+        local: PathBuf,
+        quorum: Option<SnapshotQuorum>,
+    ) -> Result<Self> {
+        let mut refused = None;
+        for number in self::snapshots(&snapshots)?.into_iter().rev() {
+            match Self::load(&snapshots, &local, quorum.as_ref(), number).await {
+                Ok(generation) => {
+                    return Ok(Replica {
+                        snapshots,
+                        local,
+                        quorum,
+                        current: RwLock::new(Arc::new(generation)),
+                    })
+                }
+                Err(e) => {
+                    println!("[replica] skipping snapshot of block {}: {:#}", number, e);
+                    refused = Some(e);
+                }
+            }
+        }
+
+        Err(refused.unwrap_or_else(|| anyhow!("no snapshot in {}", snapshots.display())))
+    }
+
+    async fn load(
+        snapshots: &Path,
+        local: &Path,
+        quorum: Option<&SnapshotQuorum>,
+        number: u64,
+    ) -> Result<Generation> {
+        let path = snapshot_path(local, number);
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        copy_dir(&snapshot_path(snapshots, number), &path)?;
+
+        let generation = Generation {
+            number,
+            chain: CovalentChain::new(path.join(CHAIN_DIR)),
+            trie_db: RocksTrieDB::new(path.join(TRIE_DIR)),
+        };
+        if let Some(quorum) = quorum {
+            let attested = async {
+                let chain = &generation.chain;
+                let header = chain.get_header_by_number(&number.into()).await?;
+                let header = header.ok_or_else(|| anyhow!("no block {} in snapshot", number))?;
+                let attestations = read_attestations(&path)?;
+                verify_quorum(&quorum.validators, quorum.size, &header, &attestations)?;
+                Ok::<_, anyhow::Error>(())
+            };
+            if let Err(e) = attested.await {
+                drop(generation);
+                fs::remove_dir_all(&path)?;
+                return Err(e);
+            }
+        }
+
+        Ok(generation)
+    }
+
+    fn current(&self) -> Arc<Generation> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    pub fn block_number(&self) -> u64 {
+        self.current().number
+    }
+
+    /// Switch to the newest snapshot, returns its block number when there
+    /// was a newer one.
+    pub async fn refresh(&self) -> Result<Option<u64>> {
+        let current = self.block_number();
+        let number = match snapshots(&self.snapshots)?.last() {
+            Some(number) if *number > current => *number,
+            _ => return Ok(None),
+        };
+
+        let quorum = self.quorum.as_ref();
+        let generation = Self::load(&self.snapshots, &self.local, quorum, number).await?;
+        *self.current.write().unwrap() = Arc::new(generation);
+        // Requests still reading the old copy keep its files open
+        for old in snapshots(&self.local)?.into_iter().filter(|n| *n < number) {
+            fs::remove_dir_all(snapshot_path(&self.local, old))?;
+        }
+
+        Ok(Some(number))
+    }
+
+    pub async fn follow(self: Arc<Self>, every: Duration) {
+        let mut timer = interval(every);
+
+        loop {
+            timer.tick().await;
+            match self.refresh().await {
+                Ok(Some(number)) => println!("[replica] serving snapshot of block {}", number),
+                Ok(None) => (),
+                Err(e) => println!("[replica] refresh failed, keep serving: {:#}", e),
+            }
+        }
+    }
+}
+
+/// The newest shipped snapshot, served once the replica refreshes.
+impl SyncSource for Replica {
+    fn highest_block(&self) -> Result<u64> {
+        Ok(snapshots(&self.snapshots)?
+            .last()
+            .copied()
+            .unwrap_or_else(|| self.block_number()))
+    }
+}
+
+#[async_trait]
+impl Chain for Replica {
+    async fn save_block(&self, block: Block) -> Result<()> {
+        Err(anyhow!(
+            "read replica can't save block {}",
+            block.header.number
+        ))
+    }
+
+    async fn get_block_by_hash(&self, hash: &Hash) -> Result<Option<Block>> {
+        self.current().chain.get_block_by_hash(hash).await
+    }
+
+    async fn get_block_by_number(&self, number: &U64) -> Result<Option<Block>> {
+        self.current().chain.get_block_by_number(number).await
+    }
+
+    async fn get_header_by_hash(&self, hash: &Hash) -> Result<Option<Header>> {
+        self.current().chain.get_header_by_hash(hash).await
+    }
+
+    async fn get_header_by_number(&self, number: &U64) -> Result<Option<Header>> {
+        self.current().chain.get_header_by_number(number).await
+    }
+
+    async fn get_block_body(&self, hash: &Hash) -> Result<Option<Vec<SignedTransaction>>> {
+        self.current().chain.get_block_body(hash).await
+    }
+
+    async fn get_latest_block(&self) -> Result<Option<Header>> {
+        self.current().chain.get_latest_block().await
+    }
+
+    async fn get_tx_by_hash(&self, hash: &Hash) -> Result<Option<SignedTransaction>> {
+        self.current().chain.get_tx_by_hash(hash).await
+    }
+
+    async fn get_block_usage(&self, number: &U64) -> Result<Option<BlockUsage>> {
+        self.current().chain.get_block_usage(number).await
+    }
+
+    async fn save_receipts(&self, _receipts: Vec<TransactionReceipt>) -> Result<()> {
+        Err(anyhow!("read replica can't save receipts"))
+    }
+
+    async fn get_receipt(&self, tx_hash: &Hash) -> Result<Option<TransactionReceipt>> {
+        self.current().chain.get_receipt(tx_hash).await
+    }
+
+    async fn get_block_logs(&self, number: &U64) -> Result<Vec<LogEntry>> {
+        self.current().chain.get_block_logs(number).await
+    }
+
+    async fn receipts_trimmed_tip(&self) -> Result<U64> {
+        self.current().chain.receipts_trimmed_tip().await
+    }
+}
+
+impl cita_trie::DB for Replica {
+    type Error = Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.current().trie_db.get(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.current().trie_db.contains(key)
+    }
+
+    fn insert(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<(), Self::Error> {
+        Err(read_only())
+    }
+
+    fn remove(&self, _key: &[u8]) -> Result<(), Self::Error> {
+        Err(read_only())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Err(read_only())
+    }
+}
+
+fn read_only() -> Error {
+    Error::Unsupported("read replica".to_owned())
+}
+
+/// Mempool of a read replica, refuses every transaction.
+pub struct ReadOnlyMemPool;
+
+#[async_trait]
+impl MemPool for ReadOnlyMemPool {
+    async fn insert(&self, _stx: SignedTransaction) -> Result<()> {
+        Err(MemPoolError::ReadOnly.into())
+    }
+
+    async fn insert_priority(&self, _stx: SignedTransaction) -> Result<()> {
+        Err(MemPoolError::ReadOnly.into())
+    }
+
+    async fn package(&self, _cycle_limit: U64) -> Result<Vec<SignedTransaction>> {
+        Ok(Vec::new())
+    }
+
+    async fn build_block_template(&self, _cycle_limit: U64) -> Result<BlockTemplate> {
+        Err(MemPoolError::ReadOnly.into())
+    }
+
+    async fn remove(&self, _hashes: Vec<Hash>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn contains(&self, _hash: &Hash) -> bool {
+        false
+    }
+
+    async fn evict_expired(&self, _next_number: U64) -> Result<Vec<ExpiredTransaction>> {
+        Ok(Vec::new())
+    }
+
+    async fn content(&self) -> Result<MemPoolContent> {
+        Ok(MemPoolContent {
+            priority: Vec::new(),
+            pending:  Vec::new(),
+        })
+    }
+
+    async fn size(&self) -> MemPoolSize {
+        MemPoolSize {
+            priority: 0,
+            pending:  0,
+            capacity: 0,
+        }
+    }
+
+    async fn next_nonce(&self, _sender: &H160) -> Option<u64> {
+        None
+    }
+
+    async fn resubmission_hint(&self) -> ResubmissionHint {
+        ResubmissionHint {
+            min_cycles_price: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cita_trie::DB;
+
+    use super::*;
+    use crate::attestation::{AttestationPolicy, AttestationPool};
+    use crate::dev::DevWallet;
+    use crate::types::{BlockCommit, H160, U128};
+
+    fn block(number: u64) -> Block {
+        Block {
+            header: Header {
+                chain_id:         U64::one(),
+                number:           number.into(),
+                prev_hash:        Hash::zero(),
+                timestamp:        U128::zero(),
+                transaction_root: Hash::zero(),
+                prev_state_root:  Hash::zero(),
+                cycles_limit:     U64::zero(),
+                proposer:         H160::zero(),
+                post_state_root:  Hash::repeat_byte(number as u8),
+                logs_bloom:       Default::default(),
+                protocol_version: 1,
+                signature:        None,
+            },
+            txs:    Vec::new(),
+            commit: BlockCommit::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replica_follows_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = SnapshotPolicy {
+            dir:          dir.path().join("snapshots"),
+            every_blocks: 1,
+            keep:         1,
+        };
+        let chain = CovalentChain::new(dir.path().join("chain"));
+        let trie_db = RocksTrieDB::new(dir.path().join("trie"));
+
+        chain.save_block(block(1)).await.unwrap();
+        trie_db.insert(b"node".to_vec(), b"1".to_vec()).unwrap();
+        trie_db.flush().unwrap();
+        ship_snapshot(&policy, 1, &chain, &trie_db).unwrap();
+
+        let replica = { Replica::open(policy.dir.clone(), dir.path().join("replica"), None) }
+            .await
+            .unwrap();
+        let latest = replica.get_latest_block().await.unwrap().unwrap();
+        assert_eq!(latest.number, U64::one());
+        assert_eq!(replica.get(b"node").unwrap(), Some(b"1".to_vec()));
+        assert!(replica.insert(b"node".to_vec(), b"2".to_vec()).is_err());
+        assert!(replica.save_block(block(2)).await.is_err());
+        assert_eq!(replica.refresh().await.unwrap(), None);
+
+        chain.save_block(block(2)).await.unwrap();
+        trie_db.insert(b"node".to_vec(), b"2".to_vec()).unwrap();
+        trie_db.flush().unwrap();
+        ship_snapshot(&policy, 2, &chain, &trie_db).unwrap();
+        assert_eq!(snapshots(&policy.dir).unwrap(), vec![2]);
+
+        assert_eq!(replica.refresh().await.unwrap(), Some(2));
+        let latest = replica.get_latest_block().await.unwrap().unwrap();
+        assert_eq!(latest.number, U64::from(2));
+        assert_eq!(replica.get(b"node").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_replica_needs_attested_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = SnapshotPolicy {
+            dir:          dir.path().join("snapshots"),
+            every_blocks: 1,
+            keep:         2,
+        };
+        let chain = CovalentChain::new(dir.path().join("chain"));
+        let trie_db = RocksTrieDB::new(dir.path().join("trie"));
+        let wallets = [0, 1].map(|i| DevWallet::derive(i).unwrap());
+        let quorum = SnapshotQuorum {
+            validators: ValidatorSet {
+                addresses: wallets.iter().map(|wallet| wallet.address).collect(),
+                ..Default::default()
+            },
+            size:       2,
+        };
+        // The first validator's node ships the snapshots
+        let pools = { wallets.iter().enumerate() }
+            .map(|(i, wallet)| {
+                let db = sled::open(dir.path().join(format!("attestation{}", i))).unwrap();
+                let attestation = AttestationPolicy {
+                    interval_blocks: 1,
+                    ..Default::default()
+                };
+                AttestationPool::new(&db, quorum.validators.clone(), attestation)
+                    .unwrap()
+                    .with_key(Some(wallet.key.clone()))
+                    .with_snapshot_dir(Some(policy.dir.clone()).filter(|_| i == 0))
+            })
+            .collect::<Vec<_>>();
+
+        chain.save_block(block(1)).await.unwrap();
+        ship_snapshot(&policy, 1, &chain, &trie_db).unwrap();
+        let replica_dir = dir.path().join("replica");
+        let opened = Replica::open(
+            policy.dir.clone(),
+            replica_dir.clone(),
+            Some(quorum.clone()),
+        );
+        assert!(opened.await.is_err());
+        // Nothing of the refused snapshot is left behind
+        assert!(snapshots(&replica_dir).unwrap().is_empty());
+
+        pools[0].attest(&block(1).header).unwrap();
+        let opened = Replica::open(
+            policy.dir.clone(),
+            replica_dir.clone(),
+            Some(quorum.clone()),
+        );
+        assert!(opened.await.is_err());
+        let second = pools[1].attest(&block(1).header).unwrap().unwrap();
+        pools[0].receive(second).unwrap();
+        let replica = { Replica::open(policy.dir.clone(), replica_dir, Some(quorum)) }
+            .await
+            .unwrap();
+        assert_eq!(replica.block_number(), 1);
+
+        // A newer snapshot waits for its attestations, the older one serves
+        chain.save_block(block(2)).await.unwrap();
+        ship_snapshot(&policy, 2, &chain, &trie_db).unwrap();
+        assert!(replica.refresh().await.is_err());
+        assert_eq!(replica.block_number(), 1);
+        let second = pools[1].attest(&block(2).header).unwrap().unwrap();
+        pools[0].receive(second).unwrap();
+        assert!(replica.refresh().await.is_err());
+        pools[0].attest(&block(2).header).unwrap();
+        assert_eq!(replica.refresh().await.unwrap(), Some(2));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_TYPE, UPGRADE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use share::error_code::RpcErrorCode;
+use tower::{Layer, Service};
+
+use crate::config::RpcLimits;
+
+/// Method allowlist and request timeout in front of the public RPC server.
+/// Calls of a method off the list are answered without reaching the
+/// server. Plain GET probes like `/health` aren't checked.
+#[derive(Clone)]
+pub struct RpcGuardLayer {
+    allowed:  Option<Arc<HashSet<String>>>,
+    timeout:  Option<Duration>,
+    max_body: u64,
+}
+
+impl RpcGuardLayer {
+    pub fn new(limits: &RpcLimits) -> Self {
+        let allowed = { limits.allowed_methods.iter() }
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        RpcGuardLayer {
+            allowed:  Some(Arc::new(allowed)).filter(|allowed| !allowed.is_empty()),
+            timeout:  Some(Duration::from_secs(limits.request_timeout_secs))
+                .filter(|timeout| !timeout.is_zero()),
+            max_body: limits.max_request_body_bytes.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for RpcGuardLayer {
+    type Service = RpcGuard<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcGuard {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RpcGuard<S> {
+    inner: S,
+    layer: RpcGuardLayer,
+}
+
+impl<S> Service<Request<Body>> for RpcGuard<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // The inner service was polled ready, the clone takes its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let RpcGuardLayer {
+            allowed,
+            timeout,
+            max_body,
+        } = self.layer.clone();
+
+        Box::pin(async move {
+            let req = match &allowed {
+                Some(_) if req.headers().contains_key(UPGRADE) => {
+                    let resp = Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::from("websocket is disabled by the method allowlist"))?;
+                    return Ok(resp);
+                }
+                // Read whatever the length header claims, chunked bodies
+                // have none
+                Some(allowed) if req.method() == Method::POST => {
+                    let (parts, body) = req.into_parts();
+                    let body = match read_limited(body, max_body).await? {
+                        Some(body) => body,
+                        None => {
+                            let message = format!("request body exceeds {} bytes", max_body);
+                            let resp = Response::builder()
+                                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                                .body(Body::from(message))?;
+                            return Ok(resp);
+                        }
+                    };
+                    if let Some(resp) = refuse_disallowed(allowed, &body) {
+                        return Ok(resp);
+                    }
+                    Request::from_parts(parts, Body::from(body))
+                }
+                _ => req,
+            };
+
+            let call = inner.call(req);
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return call.await.map_err(Into::into),
+            };
+            match tokio::time::timeout(timeout, call).await {
+                Ok(resp) => resp.map_err(Into::into),
+                Err(_) => Ok(error_response(
+                    Value::Null,
+                    RpcErrorCode::RequestTimeout,
+                    format!("request timed out after {}s", timeout.as_secs()),
+                )?),
+            }
+        })
+    }
+}
+
+// None once the body grows past `max` bytes
+async fn read_limited(mut body: Body, max: u64) -> Result<Option<Bytes>, hyper::Error> {
+    let mut read = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        read.extend_from_slice(&chunk?);
+        if read.len() as u64 > max {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(read.freeze()))
+}
+
+// Error response for the first call off the allowlist. A batch is refused
+// as a whole.
+fn refuse_disallowed(allowed: &HashSet<String>, body: &[u8]) -> Option<Response<Body>> {
+    let calls = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(calls)) => calls,
+        Ok(call) => vec![call],
+        // Let the server answer the parse error
+        Err(_) => return None,
+    };
+
+    let refused = calls.iter().find(|call| {
+        let method = call["method"].as_str().unwrap_or_default();
+        !allowed.contains(method)
+    })?;
+    let id = if calls.len() == 1 {
+        refused["id"].clone()
+    } else {
+        Value::Null
+    };
+    let method = refused["method"].as_str().unwrap_or_default();
+    error_response(
+        id,
+        RpcErrorCode::MethodNotAllowed,
+        format!("method {} is not allowed on this node", method),
+    )
+    .ok()
+}
+
+pub(crate) fn error_response(
+    id: Value,
+    code: RpcErrorCode,
+    message: String,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code.code(), "message": message },
+        "id": id,
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_allowlist_checks_chunked_bodies() {
+        let limits = RpcLimits {
+            allowed_methods: vec!["system_health".to_owned()],
+            ..Default::default()
+        };
+        let server = tower::service_fn(|_: Request<Body>| async {
+            Ok::<_, hyper::Error>(Response::new(Body::from("reached")))
+        });
+        let mut guard = RpcGuardLayer::new(&limits).layer(server);
+
+        let call = |method: &str| {
+            let (mut sender, body) = Body::channel();
+            let call = json!({ "jsonrpc": "2.0", "method": method, "id": 1 }).to_string();
+            tokio::spawn(async move { sender.send_data(Bytes::from(call)).await });
+            assert_eq!(body.size_hint().upper(), None);
+            Request::post("/").body(body).unwrap()
+        };
+        let text = |resp: Response<Body>| async {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let resp = guard.call(call("admin_reload_config")).await.unwrap();
+        assert!(text(resp).await.contains("is not allowed"));
+        let resp = guard.call(call("system_health")).await.unwrap();
+        assert_eq!(text(resp).await, "reached");
+
+        let mut limited = RpcGuardLayer::new(&RpcLimits {
+            max_request_body_bytes: 16,
+            ..limits
+        })
+        .layer(guard.inner);
+        let resp = limited.call(call("system_health")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,52 @@
+use bytes::Bytes;
+use serde::{Deserialize, Deserializer, Serializer};
+
+// Byte fields were serialized as arrays of numbers before, files written
+// by older nodes like offline signing requests still parse
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HexOrArray {
+    Hex(String),
+    Array(Vec<u8>),
+}
+
+impl HexOrArray {
+    fn into_bytes<E: serde::de::Error>(self) -> Result<Bytes, E> {
+        match self {
+            HexOrArray::Hex(s) => {
+                let s = s.strip_prefix("0x").unwrap_or(&s);
+                hex::decode(s).map(Bytes::from).map_err(E::custom)
+            }
+            HexOrArray::Array(bytes) => Ok(Bytes::from(bytes)),
+        }
+    }
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+/// 0x prefixed hex for `Bytes` fields, `#[serde(with = "crate::serde_hex")]`.
+/// `Hash`, `H160` and the integer types serialize this way on their own.
+pub fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    HexOrArray::deserialize(deserializer)?.into_bytes()
+}
+
+/// Same for `Vec<Bytes>`.
+pub mod vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(list: &[Bytes], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(|bytes| encode(bytes)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Bytes>, D::Error> {
+        { Vec::<HexOrArray>::deserialize(deserializer)?.into_iter() }
+            .map(HexOrArray::into_bytes)
+            .collect()
+    }
+}
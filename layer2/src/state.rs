@@ -0,0 +1,292 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use cita_trie::{MemoryDB, PatriciaTrie, Trie};
+use rlp::{Decodable, Rlp};
+use serde::{Deserialize, Serialize};
+
+use crate::executor::{Executor, FeeConfig};
+use crate::types::{
+    Account, AliasRecord, ExecuteResponse, Hash, Hasher, MultisigConfig, SignedTransaction,
+    TokenBalance, H160, U64,
+};
+
+/// An account with every token it holds and the nonce its next transaction
+/// has to carry.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AccountState {
+    pub address:      H160,
+    pub balance_root: Hash,
+    pub nonce:        U64,
+    // Tokens ever credited, in token id order
+    pub balances:     Vec<TokenHolding>,
+    pub multisig:     Option<MultisigConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TokenHolding {
+    pub token_id: Hash,
+    pub balance:  TokenBalance,
+}
+
+/// Merkle proofs of an account in the state trie and of one of its
+/// balances in the account's balance trie. A missing account or token is
+/// proven absent, with a zero balance.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BalanceProof {
+    pub state_root:    Hash,
+    pub account:       Account,
+    // Trie nodes from the leaf up to the root
+    #[serde(with = "crate::serde_hex::vec")]
+    pub account_proof: Vec<Bytes>,
+    pub token_id:      Hash,
+    pub balance:       TokenBalance,
+    #[serde(with = "crate::serde_hex::vec")]
+    pub balance_proof: Vec<Bytes>,
+}
+
+impl BalanceProof {
+    /// Check both proofs against `state_root`, which the caller trusts, and
+    /// return the proven balance.
+    pub fn verify(&self, state_root: &Hash) -> Result<TokenBalance> {
+        if self.state_root != *state_root {
+            return Err(anyhow!(
+                "proof is for state root {:?}, not {:?}",
+                self.state_root,
+                state_root
+            ));
+        }
+
+        let address = self.account.address;
+        let account = match verify_leaf(state_root, address.as_bytes(), &self.account_proof)? {
+            Some(raw) => Account::decode(&Rlp::new(&raw))?,
+            None => Account::new(address),
+        };
+        if account != self.account {
+            return Err(anyhow!("account {:?} doesn't match its proof", address));
+        }
+
+        let balance = if account.balance_root.is_zero() {
+            if !self.balance_proof.is_empty() {
+                return Err(anyhow!("account {:?} holds no token", address));
+            }
+            TokenBalance::default()
+        } else {
+            let leaf = verify_leaf(
+                &account.balance_root,
+                self.token_id.as_bytes(),
+                &self.balance_proof,
+            )?;
+            match leaf {
+                Some(raw) => TokenBalance::decode(&Rlp::new(&raw))?,
+                None => TokenBalance::default(),
+            }
+        };
+        if balance != self.balance {
+            return Err(anyhow!(
+                "balance of token {:?} doesn't match its proof",
+                self.token_id
+            ));
+        }
+
+        Ok(balance)
+    }
+}
+
+// The value under `key` the proof shows, none if it shows there's none
+fn verify_leaf(root: &Hash, key: &[u8], proof: &[Bytes]) -> Result<Option<Vec<u8>>> {
+    let trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(Hasher));
+    let proof = proof.iter().map(|node| node.to_vec()).collect();
+    trie.verify_proof(root.as_bytes(), key, proof)
+        .map_err(|e| anyhow!("invalid proof under {:?}: {}", root, e))
+}
+
+/// Read-only view of the account state at one state root.
+pub struct StateView<DB: cita_trie::DB> {
+    executor:     Executor<DB>,
+    state_root:   Hash,
+    state_trie:   PatriciaTrie<DB, Hasher>,
+    // Block whose post state this is
+    block_number: u64,
+}
+
+impl<DB: cita_trie::DB> StateView<DB> {
+    pub fn new(trie_db: Arc<DB>, state_root: Hash) -> Self {
+        let executor = Executor::new(trie_db);
+        let state_trie = executor.trie(&state_root);
+        StateView {
+            executor,
+            state_root,
+            state_trie,
+            block_number: 0,
+        }
+    }
+
+    /// Aliases expiring by block `number` no longer resolve.
+    pub fn at_block(mut self, number: u64) -> Self {
+        self.executor = self.executor.at_block(number);
+        self.block_number = number;
+        self
+    }
+
+    /// Outcome of executing `stx` on this state, which stays as it is.
+    pub fn dry_run(self, stx: &SignedTransaction, fee: Option<FeeConfig>) -> ExecuteResponse {
+        let mut executor = self.executor.with_fee(fee);
+        executor.dry_run(stx, &self.state_trie)
+    }
+
+    pub fn proof(&self, address: &H160, token_id: &Hash) -> Result<BalanceProof> {
+        let account = self.executor.get_account(&self.state_trie, address);
+        let account_proof = self.state_trie.get_proof(address.as_bytes())?;
+        let (balance, balance_proof) = if account.balance_root.is_zero() {
+            (TokenBalance::default(), Vec::new())
+        } else {
+            let balance_trie = self.executor.trie(&account.balance_root);
+            (
+                self.executor.get_balance(&balance_trie, token_id),
+                balance_trie.get_proof(token_id.as_bytes())?,
+            )
+        };
+
+        Ok(BalanceProof {
+            state_root: self.state_root,
+            account,
+            account_proof: account_proof.into_iter().map(Bytes::from).collect(),
+            token_id: *token_id,
+            balance,
+            balance_proof: balance_proof.into_iter().map(Bytes::from).collect(),
+        })
+    }
+
+    pub fn balance(&self, address: &H160, token_id: &Hash) -> TokenBalance {
+        let account = self.executor.get_account(&self.state_trie, address);
+        self.executor
+            .get_balance(&self.executor.trie(&account.balance_root), token_id)
+    }
+
+    /// The alias while it's registered.
+    pub fn resolve_alias(&self, name: &str) -> Option<AliasRecord> {
+        { self.executor.get_alias(&self.state_trie, name) }
+            .filter(|record| record.expires_at > self.block_number)
+    }
+
+    /// Nonce the next transaction of `address` has to carry.
+    pub fn nonce(&self, address: &H160) -> u64 {
+        self.executor.get_nonce(&self.state_trie, address)
+    }
+
+    /// Walks the whole balance trie of the account.
+    pub fn account(&self, address: &H160) -> AccountState {
+        let account = self.executor.get_account(&self.state_trie, address);
+        let balances = if account.balance_root.is_zero() {
+            Vec::new()
+        } else {
+            let balance_trie = self.executor.trie(&account.balance_root);
+            let mut balances = { balance_trie.iter() }
+                .filter(|(token_id, _)| token_id.len() == Hash::len_bytes())
+                .filter_map(|(token_id, raw)| {
+                    Some(TokenHolding {
+                        token_id: Hash::from_slice(&token_id),
+                        balance:  TokenBalance::decode(&Rlp::new(&raw)).ok()?,
+                    })
+                })
+                .collect::<Vec<_>>();
+            balances.sort_by_key(|holding| holding.token_id);
+            balances
+        };
+
+        AccountState {
+            address: *address,
+            balance_root: account.balance_root,
+            nonce: account.nonce,
+            balances,
+            multisig: self.executor.get_multisig(&self.state_trie, address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rlp::Encodable;
+
+    use super::*;
+    use crate::types::{RawTransaction, TokenAction, TransactionRequest};
+
+    #[test]
+    fn test_balance_proof() {
+        let db = Arc::new(MemoryDB::new(true));
+        let (address, token_id) = (H160::repeat_byte(1), Hash::repeat_byte(2));
+        let balance = TokenBalance {
+            locked: 3.into(),
+            active: 4.into(),
+        };
+        let mut balance_trie = PatriciaTrie::new(Arc::clone(&db), Arc::new(Hasher));
+        balance_trie
+            .insert(token_id.as_bytes().to_vec(), balance.rlp_bytes().to_vec())
+            .unwrap();
+        let account = Account {
+            balance_root: Hash::from_slice(&balance_trie.root().unwrap()),
+            ..Account::new(address)
+        };
+        let mut state_trie = PatriciaTrie::new(Arc::clone(&db), Arc::new(Hasher));
+        state_trie
+            .insert(address.as_bytes().to_vec(), account.rlp_bytes().to_vec())
+            .unwrap();
+        let state_root = Hash::from_slice(&state_trie.root().unwrap());
+
+        let state = StateView::new(Arc::clone(&db), state_root);
+        let proof = state.proof(&address, &token_id).unwrap();
+        assert_eq!(proof.verify(&state_root).unwrap(), balance);
+        assert!(proof.verify(&Hash::zero()).is_err());
+
+        // Absent tokens and accounts are proven to hold nothing
+        let proof = state.proof(&address, &Hash::zero()).unwrap();
+        assert_eq!(proof.verify(&state_root).unwrap(), TokenBalance::default());
+        let proof = state.proof(&H160::zero(), &token_id).unwrap();
+        assert_eq!(proof.verify(&state_root).unwrap(), TokenBalance::default());
+
+        let mut forged = state.proof(&address, &token_id).unwrap();
+        forged.balance.active = 5.into();
+        assert!(forged.verify(&state_root).is_err());
+        let mut forged = state.proof(&address, &token_id).unwrap();
+        forged.account.balance_root = Hash::zero();
+        assert!(forged.verify(&state_root).is_err());
+
+        // Dry runs leave the state as it is
+        let transfer = |amount: u64| SignedTransaction {
+            raw:        RawTransaction {
+                chain_id:     1u64.into(),
+                cycles_price: 1u64.into(),
+                cycles_limit: 1000u64.into(),
+                nonce:        Hash::zero(),
+                requests:     vec![TransactionRequest {
+                    address,
+                    token_id,
+                    amount: amount.into(),
+                    action: TokenAction::Transfer,
+                    to: Some(H160::zero()),
+                }],
+                sender:       address,
+                multisig:     None,
+                alias:        None,
+                timeout:      None,
+            },
+            tx_hash:    Hash::repeat_byte(amount as u8),
+            pub_key:    Bytes::new(),
+            signature:  Bytes::new(),
+            signatures: Vec::new(),
+            sponsor:    None,
+        };
+        assert!(StateView::new(Arc::clone(&db), state_root)
+            .dry_run(&transfer(4), None)
+            .error
+            .is_none());
+        assert!(StateView::new(Arc::clone(&db), state_root)
+            .dry_run(&transfer(5), None)
+            .error
+            .is_some());
+        let proof = state.proof(&address, &token_id).unwrap();
+        assert_eq!(proof.verify(&state_root).unwrap(), balance);
+    }
+}
@@ -1,69 +1,208 @@
+use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use sled::{Db, Error};
+use serde::{Deserialize, Serialize};
+use sled::{Batch, Db, Error};
+
+use crate::replica::{export_db, Snapshot};
+
+/// When trie writes committed by `flush` are synced to disk. Every flush
+/// is applied atomically, so a crash loses whole blocks at most, never
+/// part of one.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum FlushPolicy {
+    #[default]
+    EveryBlock,
+    EveryBlocks {
+        blocks: u64,
+    },
+    Interval {
+        secs: u64,
+    },
+}
+
+struct SyncState {
+    unsynced_blocks: u64,
+    last_sync:       Instant,
+}
 
 pub struct RocksTrieDB {
-    db: Arc<Db>,
+    db:      Arc<Db>,
+    // Node writes since the last flush, `None` marks a removal
+    pending: RwLock<HashMap<Vec<u8>, Option<Vec<u8>>>>,
+    policy:  FlushPolicy,
+    sync:    Mutex<SyncState>,
 }
 
 impl RocksTrieDB {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self::from_db(sled::open(path).expect("open"))
+    }
+
+    fn from_db(db: Db) -> Self {
         RocksTrieDB {
-            db: Arc::new(sled::open(path).expect("open")),
+            db:      Arc::new(db),
+            pending: RwLock::new(HashMap::new()),
+            policy:  FlushPolicy::default(),
+            sync:    Mutex::new(SyncState {
+                unsynced_blocks: 0,
+                last_sync:       Instant::now(),
+            }),
+        }
+    }
+
+    pub fn with_flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn sync_due(&self, sync: &SyncState) -> bool {
+        match self.policy {
+            FlushPolicy::EveryBlock => true,
+            FlushPolicy::EveryBlocks { blocks } => sync.unsynced_blocks >= blocks,
+            FlushPolicy::Interval { secs } => sync.last_sync.elapsed() >= Duration::from_secs(secs),
         }
     }
 }
 
+/// Only flushed writes are part of the snapshot.
+impl Snapshot for RocksTrieDB {
+    fn snapshot_to(&self, path: &Path) -> Result<()> {
+        export_db(&self.db, path)
+    }
+}
+
 impl cita_trie::DB for RocksTrieDB {
     type Error = Error;
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.pending.read().unwrap().get(key) {
+            return Ok(value.clone());
+        }
         Ok(self.db.get(key)?.map(|inner| (*inner).to_vec()))
     }
 
     fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if let Some(value) = self.pending.read().unwrap().get(key) {
+            return Ok(value.is_some());
+        }
         self.db.contains_key(key)
     }
 
     fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
-        let _ = self.db.insert(key, value)?;
+        self.pending.write().unwrap().insert(key, Some(value));
         Ok(())
     }
 
     fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
-        self.db
-            .transaction::<_, _, Error>(|tx_db| {
-                for (k, v) in keys.iter().zip(values.iter()) {
-                    tx_db.insert(k.clone(), v.clone())?;
-                }
-                Ok(())
-            })
-            .unwrap();
+        let mut pending = self.pending.write().unwrap();
+        for (k, v) in keys.into_iter().zip(values) {
+            pending.insert(k, Some(v));
+        }
         Ok(())
     }
 
     fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
-        let _ = self.db.remove(key)?;
+        self.pending.write().unwrap().insert(key.to_vec(), None);
         Ok(())
     }
 
     fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
-        self.db
-            .transaction::<_, _, Error>(|tx_db| {
-                for k in keys.iter() {
-                    let _ = tx_db.remove(k.clone())?;
-                }
-                Ok(())
-            })
-            .unwrap();
-
+        let mut pending = self.pending.write().unwrap();
+        for k in keys.iter() {
+            pending.insert(k.clone(), None);
+        }
         Ok(())
     }
 
+    /// Commit the writes of a block in one batch, then sync if the policy
+    /// says so.
     fn flush(&self) -> Result<(), Self::Error> {
-        let _ = self.db.flush()?;
+        let mut sync = self.sync.lock().unwrap();
+        let mut pending = self.pending.write().unwrap();
+        let mut batch = Batch::default();
+        for (k, v) in pending.drain() {
+            match v {
+                Some(v) => batch.insert(k, v),
+                None => batch.remove(k),
+            }
+        }
+        self.db.apply_batch(batch)?;
+        drop(pending);
+
+        sync.unsynced_blocks += 1;
+        if self.sync_due(&sync) {
+            let _ = self.db.flush()?;
+            sync.unsynced_blocks = 0;
+            sync.last_sync = Instant::now();
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cita_trie::{PatriciaTrie, Trie, DB};
+
+    use super::*;
+    use crate::types::Hasher;
+
+    // sled releases the file lock of a dropped db from a background thread
+    fn reopen(path: &Path) -> Arc<RocksTrieDB> {
+        for _ in 0..100 {
+            if let Ok(db) = sled::open(path) {
+                return Arc::new(RocksTrieDB::from_db(db));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} is still locked", path.display());
+    }
+
+    fn write_nodes(db: &Arc<RocksTrieDB>) -> Vec<u8> {
+        let mut trie = PatriciaTrie::new(Arc::clone(db), Arc::new(Hasher));
+        for i in 0u8..32 {
+            trie.insert(vec![i; 20], vec![i; 64]).unwrap();
+        }
+        trie.root().unwrap()
+    }
+
+    #[test]
+    fn test_unflushed_block_is_lost_whole() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let db = Arc::new(RocksTrieDB::new(dir.path()));
+        let root = write_nodes(&db);
+        // Readable before the block is committed
+        assert!(db.contains(&root).unwrap());
+        // Crash before flush
+        drop(db);
+
+        let db = reopen(dir.path());
+        assert!(!db.contains(&root).unwrap());
+        assert!(db.db.is_empty());
+    }
+
+    #[test]
+    fn test_flushed_blocks_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = FlushPolicy::EveryBlocks { blocks: 2 };
+
+        let db = Arc::new(RocksTrieDB::new(dir.path()).with_flush_policy(policy));
+        let root = write_nodes(&db);
+        db.flush().unwrap();
+        assert_eq!(db.sync.lock().unwrap().unsynced_blocks, 1);
+        db.flush().unwrap();
+        assert_eq!(db.sync.lock().unwrap().unsynced_blocks, 0);
+        drop(db);
+
+        let db = reopen(dir.path());
+        let trie = PatriciaTrie::from(Arc::clone(&db), Arc::new(Hasher), &root).unwrap();
+        for i in 0u8..32 {
+            assert_eq!(trie.get(&[i; 20]).unwrap(), Some(vec![i; 64]));
+        }
+    }
+}
@@ -1,11 +1,17 @@
 pub use crate::primitive::{Hash, Hasher};
 pub use bytes::Bytes;
-pub use ethereum_types::{H160, U128, U256, U64};
+pub use ethereum_types::{Bloom, BloomInput, H160, U128, U256, U64};
 
+use std::str::FromStr;
+
+use anyhow::anyhow;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use rlp::{Decodable, DecoderError, Encodable, Rlp};
 use rlp_derive::{RlpDecodable, RlpEncodable};
 use serde::{Deserialize, Serialize};
+use share::limits::{DecodeError, DecodeLimit};
+
+use crate::multisig::{address_of, verify_signature};
 
 #[derive(
     Serialize, Deserialize, IntoPrimitive, TryFromPrimitive, Clone, Copy, Debug, PartialEq, Eq,
@@ -32,10 +38,53 @@ impl Decodable for TokenAction {
     }
 }
 
-#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Account {
     pub address:      H160,
     pub balance_root: Hash,
+    // Nonce the account's next transaction carries
+    #[serde(default)]
+    pub nonce:        U64,
+}
+
+impl Account {
+    pub fn new(address: H160) -> Self {
+        Account {
+            address,
+            balance_root: Hash::zero(),
+            nonce: U64::zero(),
+        }
+    }
+}
+
+// The nonce is left out until the account sends its first transaction, so
+// accounts written before nonces were tracked keep their encoding and the
+// state root its value.
+impl Encodable for Account {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(if self.nonce.is_zero() { 2 } else { 3 })
+            .append(&self.address)
+            .append(&self.balance_root);
+        if !self.nonce.is_zero() {
+            s.append(&self.nonce);
+        }
+    }
+}
+
+impl Decodable for Account {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        let nonce = match rlp.item_count()? {
+            2 => U64::zero(),
+            3 => rlp.val_at(2)?,
+            _ => return Err(DecoderError::RlpIncorrectListLen),
+        };
+
+        Ok(Account {
+            address: rlp.val_at(0)?,
+            balance_root: rlp.val_at(1)?,
+            nonce,
+        })
+    }
 }
 
 #[derive(
@@ -52,14 +101,207 @@ impl TokenBalance {
     }
 }
 
-#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+// Envelope type and version of each transaction kind. Version 2 of each
+// adds the optional timeout after the sender
+pub const TRANSFER_TX_TYPE: u8 = 0;
+pub const TRANSFER_TX_VERSION: u8 = 2;
+pub const MULTISIG_TX_TYPE: u8 = 1;
+pub const MULTISIG_TX_VERSION: u8 = 2;
+pub const ALIAS_TX_TYPE: u8 = 2;
+pub const ALIAS_TX_VERSION: u8 = 2;
+// Layout of every type before the timeout, which transactions without one
+// keep so their hash doesn't change
+pub const UNTIMED_TX_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct RawTransaction {
     pub chain_id:     U64,
     pub cycles_price: U64,
     pub cycles_limit: U64,
+    // The sender's account nonce, big endian
     pub nonce:        Hash,
     pub requests:     Vec<TransactionRequest>,
     pub sender:       H160,
+    // Set for the transaction turning `sender` into a multisig account
+    #[serde(default)]
+    pub multisig:     Option<MultisigConfig>,
+    // Set for the transaction registering or renewing an alias
+    #[serde(default)]
+    pub alias:        Option<AliasRegistration>,
+    // Last block number the transaction can be included in
+    #[serde(default)]
+    pub timeout:      Option<U64>,
+}
+
+// Encoded as `[type, version, body]`. The type byte and version are part of
+// the signed bytes, and decoders reject pairs they don't know instead of
+// misreading a transaction from a newer node.
+impl Encodable for RawTransaction {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        let (tx_type, version) = match (&self.alias, &self.multisig) {
+            (Some(_), _) => (ALIAS_TX_TYPE, ALIAS_TX_VERSION),
+            (None, Some(_)) => (MULTISIG_TX_TYPE, MULTISIG_TX_VERSION),
+            (None, None) => (TRANSFER_TX_TYPE, TRANSFER_TX_VERSION),
+        };
+        let version = match self.timeout {
+            Some(_) => version,
+            None => UNTIMED_TX_VERSION,
+        };
+        s.begin_list(3).append(&tx_type).append(&version);
+
+        let extra = usize::from(version != UNTIMED_TX_VERSION)
+            + self.alias.iter().count()
+            + self.multisig.iter().count();
+        s.begin_list(6 + extra)
+            .append(&self.chain_id)
+            .append(&self.cycles_price)
+            .append(&self.cycles_limit)
+            .append(&self.nonce)
+            .append_list(&self.requests)
+            .append(&self.sender);
+        if version != UNTIMED_TX_VERSION {
+            // An optional field, a list of none or one item
+            s.append_list(&Vec::from_iter(self.timeout));
+        }
+        // An alias registration can also set up multisig, after the alias
+        if let Some(alias) = &self.alias {
+            s.append(alias);
+        }
+        if let Some(config) = &self.multisig {
+            s.append(config);
+        }
+    }
+}
+
+impl Decodable for RawTransaction {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        // Transactions stored before the envelope carry the bare body
+        if rlp.item_count()? == 6 {
+            return Self::decode_body(rlp);
+        }
+
+        let unsupported = DecoderError::Custom("Unsupported transaction type or version");
+        let tx_type: u8 = rlp.val_at(0)?;
+        let version: u8 = rlp.val_at(1)?;
+        let current = match tx_type {
+            TRANSFER_TX_TYPE => TRANSFER_TX_VERSION,
+            MULTISIG_TX_TYPE => MULTISIG_TX_VERSION,
+            ALIAS_TX_TYPE => ALIAS_TX_VERSION,
+            _ => return Err(unsupported),
+        };
+        let body = rlp.at(2)?;
+        // Index of the first item past the timeout
+        let (timeout, at) = match version {
+            UNTIMED_TX_VERSION => (None, 6),
+            version if version == current => {
+                let timeout: Vec<U64> = body.list_at(6)?;
+                if timeout.len() > 1 {
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                (timeout.first().copied(), 7)
+            }
+            _ => return Err(unsupported),
+        };
+        let tx = RawTransaction {
+            timeout,
+            ..Self::decode_body(&body)?
+        };
+
+        match tx_type {
+            MULTISIG_TX_TYPE => Ok(RawTransaction {
+                multisig: Some(body.val_at(at)?),
+                ..tx
+            }),
+            ALIAS_TX_TYPE => {
+                let multisig = match body.item_count()? - at {
+                    1 => None,
+                    2 => Some(body.val_at(at + 1)?),
+                    _ => return Err(DecoderError::RlpIncorrectListLen),
+                };
+                Ok(RawTransaction {
+                    alias: Some(body.val_at(at)?),
+                    multisig,
+                    ..tx
+                })
+            }
+            _ => Ok(tx),
+        }
+    }
+}
+
+impl RawTransaction {
+    /// Nonce of a sender's `n`th transaction, counting from 0.
+    pub fn nonce_of(n: u64) -> Hash {
+        Hash::from_low_u64_be(n)
+    }
+
+    /// The nonce as a number, `None` if it doesn't fit a u64 and can never
+    /// be the account's next one.
+    pub fn nonce_number(&self) -> Option<u64> {
+        let bytes = self.nonce.as_bytes();
+        let (high, low) = bytes.split_at(bytes.len() - 8);
+        if high.iter().any(|b| *b != 0) {
+            return None;
+        }
+        Some(u64::from_be_bytes(low.try_into().unwrap()))
+    }
+
+    fn decode_body(rlp: &Rlp) -> Result<Self, DecoderError> {
+        Ok(RawTransaction {
+            chain_id:     rlp.val_at(0)?,
+            cycles_price: rlp.val_at(1)?,
+            cycles_limit: rlp.val_at(2)?,
+            nonce:        rlp.val_at(3)?,
+            requests:     rlp.list_at(4)?,
+            sender:       rlp.val_at(5)?,
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        })
+    }
+
+    /// Whether the transaction can no longer be included in block `number`.
+    pub fn expired_at(&self, number: u64) -> bool {
+        self.timeout
+            .is_some_and(|timeout| timeout.as_u64() < number)
+    }
+}
+
+/// Points the alias `name` at `address` for `blocks` blocks. Renewing an
+/// alias the sender holds extends it from its current expiry.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct AliasRegistration {
+    pub name:    String,
+    pub address: H160,
+    pub blocks:  u64,
+}
+
+/// A registered alias. It resolves until block `expires_at`, after which
+/// anyone can register it again.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct AliasRecord {
+    pub name:       String,
+    pub address:    H160,
+    // Sender of the registration, the only one who can renew or repoint it
+    pub owner:      H160,
+    pub expires_at: u64,
+}
+
+/// m-of-n signers of a multisig account. Once set, every transaction from
+/// the account needs `threshold` of these keys to sign.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct MultisigConfig {
+    pub threshold: u8,
+    #[serde(with = "crate::serde_hex::vec")]
+    pub pub_keys:  Vec<Bytes>,
+}
+
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct SignaturePair {
+    #[serde(with = "crate::serde_hex")]
+    pub pub_key:   Bytes,
+    #[serde(with = "crate::serde_hex")]
+    pub signature: Bytes,
 }
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
@@ -71,14 +313,75 @@ pub struct TransactionRequest {
     pub to:       Option<H160>,
 }
 
-#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct SignedTransaction {
-    pub raw:       RawTransaction,
-    pub tx_hash:   Hash,
+    pub raw:        RawTransaction,
+    pub tx_hash:    Hash,
+    // Empty for multisig accounts, which sign with `signatures`
+    #[serde(with = "crate::serde_hex")]
+    pub pub_key:    Bytes,
+    #[serde(with = "crate::serde_hex")]
+    pub signature:  Bytes,
+    #[serde(default)]
+    pub signatures: Vec<SignaturePair>,
+    // Pays the cycles instead of the sender
+    #[serde(default)]
+    pub sponsor:    Option<Sponsor>,
+}
+
+impl Encodable for SignedTransaction {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        s.begin_list(6)
+            .append(&self.raw)
+            .append(&self.tx_hash)
+            .append(&self.pub_key)
+            .append(&self.signature)
+            .append_list(&self.signatures)
+            .append(&self.sponsor);
+    }
+}
+
+impl Decodable for SignedTransaction {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        // Transactions stored before multisig have no signature set, and
+        // those stored before sponsoring no sponsor
+        let (signatures, sponsor) = match rlp.item_count()? {
+            4 => (Vec::new(), None),
+            5 => (rlp.list_at(4)?, None),
+            6 => (rlp.list_at(4)?, rlp.val_at(5)?),
+            _ => return Err(DecoderError::RlpIncorrectListLen),
+        };
+
+        Ok(SignedTransaction {
+            raw: rlp.val_at(0)?,
+            tx_hash: rlp.val_at(1)?,
+            pub_key: rlp.val_at(2)?,
+            signature: rlp.val_at(3)?,
+            signatures,
+            sponsor,
+        })
+    }
+}
+
+/// Fee payer of a sponsored transaction, e.g. a relayer or merchant paying
+/// for users without a fee token balance.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct Sponsor {
+    pub address:   H160,
+    #[serde(with = "crate::serde_hex")]
     pub pub_key:   Bytes,
+    // Over `Sponsor::sig_hash` of the transaction
+    #[serde(with = "crate::serde_hex")]
     pub signature: Bytes,
 }
 
+impl Sponsor {
+    /// Prefixed so that a sender signature can't pass as a sponsorship.
+    pub fn sig_hash(tx_hash: &Hash) -> Hash {
+        Hasher::digest_([b"sponsor".as_ref(), tx_hash.as_bytes()].concat())
+    }
+}
+
 impl SignedTransaction {
     pub fn cycle_limit(&self) -> U64 {
         self.raw.cycles_limit
@@ -87,33 +390,304 @@ impl SignedTransaction {
     pub fn chain_id(&self) -> U64 {
         self.raw.chain_id
     }
-}
 
-#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+    pub fn fee_payer(&self) -> H160 {
+        { self.sponsor.as_ref() }
+            .map(|sponsor| sponsor.address)
+            .unwrap_or(self.raw.sender)
+    }
+
+    pub fn fee(&self) -> U256 {
+        U256::from(self.raw.cycles_price.as_u64()) * U256::from(self.raw.cycles_limit.as_u64())
+    }
+}
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Header {
     pub chain_id:         U64,
     pub number:           U64,
     pub prev_hash:        Hash,
     pub timestamp:        U128,
     pub transaction_root: Hash,
-    pub state_root:       Hash,
+    // State the block is executed on
+    pub prev_state_root:  Hash,
     pub cycles_limit:     U64,
     pub proposer:         H160,
+    // State after executing the block, zero for blocks produced before the
+    // header carried it
+    pub post_state_root:  Hash,
+    // Addresses of the block's logs, zero for blocks without any or
+    // produced before the header carried it
+    #[serde(default)]
+    pub logs_bloom:       Bloom,
+    // Protocol version of the rules the block follows, 1 for blocks produced
+    // before the header carried it
+    #[serde(default = "legacy_protocol_version")]
+    pub protocol_version: u32,
+    // Proposer's key and signature over the header hash, none for blocks
+    // produced before headers were signed
+    #[serde(default)]
+    pub signature:        Option<SignaturePair>,
 }
 
-#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+fn legacy_protocol_version() -> u32 {
+    1
+}
+
+impl Header {
+    /// Hash of every field but the signature, which is over it.
+    pub fn hash(&self) -> Hash {
+        let mut s = rlp::RlpStream::new();
+        self.append_fields(&mut s, false);
+        Hasher::digest_(s.out())
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.post_state_root.is_zero()
+    }
+
+    /// State after this block. Legacy headers only know the state they
+    /// were executed on, which is the best available.
+    pub fn state_root(&self) -> Hash {
+        if self.is_legacy() {
+            self.prev_state_root
+        } else {
+            self.post_state_root
+        }
+    }
+
+    /// Fails unless the header is signed by the key of its proposer.
+    pub fn verify_proposer(&self) -> anyhow::Result<()> {
+        let pair = { self.signature.as_ref() }
+            .ok_or_else(|| anyhow!("block {} isn't signed by its proposer", self.number))?;
+        let signer = address_of(&pair.pub_key);
+        if signer != self.proposer {
+            return Err(anyhow!(
+                "block {} of proposer {:?} is signed by {:?}",
+                self.number,
+                self.proposer,
+                signer
+            ));
+        }
+        if !verify_signature(&self.hash(), &pair.pub_key, &pair.signature) {
+            return Err(anyhow!("invalid proposer signature of block {}", self.number));
+        }
+
+        Ok(())
+    }
+
+    fn append_fields(&self, s: &mut rlp::RlpStream, signed: bool) {
+        // Legacy headers keep their layout, so their hash doesn't change
+        let signature = self.signature.as_ref().filter(|_| signed);
+        let len = match (self.is_legacy(), self.logs_bloom.is_zero()) {
+            _ if signature.is_some() => 12,
+            (true, _) => 8,
+            _ if self.protocol_version > 1 => 11,
+            (false, true) => 9,
+            (false, false) => 10,
+        };
+        s.begin_list(len)
+            .append(&self.chain_id)
+            .append(&self.number)
+            .append(&self.prev_hash)
+            .append(&self.timestamp)
+            .append(&self.transaction_root)
+            .append(&self.prev_state_root)
+            .append(&self.cycles_limit)
+            .append(&self.proposer);
+        if len > 8 {
+            s.append(&self.post_state_root);
+        }
+        if len > 9 {
+            s.append(&self.logs_bloom);
+        }
+        if len > 10 {
+            s.append(&self.protocol_version);
+        }
+        if let Some(signature) = signature {
+            s.append(signature);
+        }
+    }
+}
+
+impl Encodable for Header {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        self.append_fields(s, true);
+    }
+}
+
+impl Decodable for Header {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        let len = rlp.item_count()?;
+        let (post_state_root, logs_bloom) = match len {
+            8 => (Hash::zero(), Bloom::zero()),
+            9 => (rlp.val_at(8)?, Bloom::zero()),
+            10..=12 => (rlp.val_at(8)?, rlp.val_at(9)?),
+            _ => return Err(DecoderError::RlpIncorrectListLen),
+        };
+        let protocol_version = match len {
+            11 | 12 => rlp.val_at(10)?,
+            _ => legacy_protocol_version(),
+        };
+        let signature = match len {
+            12 => Some(rlp.val_at(11)?),
+            _ => None,
+        };
+
+        Ok(Header {
+            chain_id: rlp.val_at(0)?,
+            number: rlp.val_at(1)?,
+            prev_hash: rlp.val_at(2)?,
+            timestamp: rlp.val_at(3)?,
+            transaction_root: rlp.val_at(4)?,
+            prev_state_root: rlp.val_at(5)?,
+            cycles_limit: rlp.val_at(6)?,
+            proposer: rlp.val_at(7)?,
+            post_state_root,
+            logs_bloom,
+            protocol_version,
+            signature,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Block {
     pub header: Header,
     pub txs:    Vec<SignedTransaction>,
+    #[serde(default)]
+    pub commit: BlockCommit,
+}
+
+/// Round a block was decided in and the validator signatures over its
+/// header hash. Empty while a single node proposes every block.
+#[derive(
+    Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, Default, PartialEq, Eq,
+)]
+pub struct BlockCommit {
+    pub round:      u64,
+    pub signatures: Vec<SignaturePair>,
+}
+
+impl BlockCommit {
+    pub fn is_empty(&self) -> bool {
+        self.round == 0 && self.signatures.is_empty()
+    }
 }
 
 impl Block {
     pub fn header_hash(&self) -> Hash {
-        Hasher::digest_(self.header.rlp_bytes())
+        self.header.hash()
+    }
+
+    /// Addresses that committed this block. Fails on a signature that
+    /// doesn't verify or a key signing twice.
+    pub fn commit_signers(&self) -> anyhow::Result<Vec<H160>> {
+        let hash = self.header_hash();
+        let mut signers = Vec::with_capacity(self.commit.signatures.len());
+        for pair in self.commit.signatures.iter() {
+            let signer = address_of(&pair.pub_key);
+            if !verify_signature(&hash, &pair.pub_key, &pair.signature) {
+                return Err(anyhow!("invalid commit signature of {:?}", signer));
+            }
+            if signers.contains(&signer) {
+                return Err(anyhow!("{:?} committed twice", signer));
+            }
+            signers.push(signer);
+        }
+
+        Ok(signers)
+    }
+
+    /// Whether both blocks come from the same proposer for the same height
+    /// and round but differ.
+    pub fn equivocates(&self, other: &Block) -> bool {
+        self.header.proposer == other.header.proposer
+            && self.header.number == other.header.number
+            && self.commit.round == other.commit.round
+            && self.header_hash() != other.header_hash()
+    }
+}
+
+/// Resources a block consumed, kept next to the block for capacity planning.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct BlockUsage {
+    pub number:       U64,
+    pub tx_count:     u64,
+    // Cycles are charged by limit, so this is the sum of the tx limits
+    pub cycles_used:  U64,
+    pub cycles_limit: U64,
+    pub total_fee:    U256,
+    // RLP encoded size
+    pub byte_size:    u64,
+}
+
+impl BlockUsage {
+    pub fn of(block: &Block) -> Self {
+        BlockUsage {
+            number:       block.header.number,
+            tx_count:     block.txs.len() as u64,
+            cycles_used:  { block.txs.iter() }
+                .fold(U64::zero(), |sum, tx| sum.saturating_add(tx.cycle_limit())),
+            cycles_limit: block.header.cycles_limit,
+            total_fee:    { block.txs.iter() }
+                .fold(U256::zero(), |sum, tx| sum.saturating_add(tx.fee())),
+            byte_size:    block.rlp_bytes().len() as u64,
+        }
+    }
+}
+
+impl Encodable for Block {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        // Blocks without commit keep the 2 items layout of stored blocks
+        s.begin_list(if self.commit.is_empty() { 2 } else { 3 })
+            .append(&self.header)
+            .append_list(&self.txs);
+        if !self.commit.is_empty() {
+            s.append(&self.commit);
+        }
+    }
+}
+
+impl Decodable for Block {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        let commit = match rlp.item_count()? {
+            2 => BlockCommit::default(),
+            3 => rlp.val_at(2)?,
+            _ => return Err(DecoderError::RlpIncorrectListLen),
+        };
+
+        Ok(Block {
+            header: rlp.val_at(0)?,
+            txs: rlp.list_at(1)?,
+            commit,
+        })
     }
 }
 
+/// Decode RLP from outside the node, e.g. an archive record, within
+/// `limit`. The decoders alone accept any nesting and trailing bytes.
+pub fn decode_rlp<T: Decodable>(raw: &[u8], limit: &DecodeLimit) -> Result<T, DecodeError> {
+    limit.check_size(raw.len())?;
+    let rlp = Rlp::new(raw);
+    let info = rlp.payload_info().map_err(|e| limit.malformed(e))?;
+    if info.total() != raw.len() {
+        return Err(limit.malformed("trailing bytes"));
+    }
+    if !within_depth(&rlp, limit.max_depth) {
+        return Err(limit.too_deep());
+    }
+
+    T::decode(&rlp).map_err(|e| limit.malformed(e))
+}
+
+fn within_depth(rlp: &Rlp, depth: usize) -> bool {
+    if !rlp.is_list() {
+        return true;
+    }
+    depth > 0 && rlp.iter().all(|item| within_depth(&item, depth - 1))
+}
+
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
 pub struct BlockExecuteResponse {
     pub state_root: Hash,
@@ -126,6 +700,8 @@ pub struct ExecuteResponse {
     pub tx_hash: Hash,
     pub ret:     Vec<u8>,
     pub error:   Option<ExecuteError>,
+    // Empty for failed transactions
+    pub logs:    Vec<Log>,
 }
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
@@ -136,9 +712,38 @@ pub struct ExecuteError {
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
 pub struct TransactionReceipt {
-    pub tx_hash:    Hash,
-    pub state_root: Hash,
-    pub logs:       Vec<Log>,
+    pub tx_hash:       Hash,
+    pub block_number:  U64,
+    // Position in the block
+    pub index:         u64,
+    // 0 on success, the `ExecuteError` code otherwise
+    pub exit_code:     u32,
+    pub ret:           Vec<u8>,
+    pub error_message: String,
+    pub logs:          Vec<Log>,
+}
+
+impl TransactionReceipt {
+    pub fn new(block_number: U64, index: usize, resp: ExecuteResponse) -> Self {
+        let (exit_code, error_message) = match resp.error {
+            Some(error) => (error.error_code, error.error_message),
+            None => (0, String::new()),
+        };
+
+        TransactionReceipt {
+            tx_hash: resp.tx_hash,
+            block_number,
+            index: index as u64,
+            exit_code,
+            ret: resp.ret,
+            error_message,
+            logs: resp.logs,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.exit_code == 0
+    }
 }
 
 #[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
@@ -151,4 +756,184 @@ impl Log {
     pub fn new(name: String, data: String) -> Self {
         Log { name, data }
     }
+
+    /// The account the log is about. Logs name it in full hex, older ones
+    /// abbreviated it and have none.
+    pub fn address(&self) -> Option<H160> {
+        let hex = self.name.strip_prefix("0x")?;
+        H160::from_str(hex).ok()
+    }
+}
+
+/// A log and where it was emitted, as `get_logs` returns it.
+#[derive(Serialize, Deserialize, RlpEncodable, RlpDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub block_number: U64,
+    pub tx_hash:      Hash,
+    pub tx_index:     u64,
+    // Position among the logs of the block
+    pub log_index:    u64,
+    pub log:          Log,
+}
+
+impl LogEntry {
+    /// Logs of the receipts of one block, in block order.
+    pub fn of_receipts(receipts: &[TransactionReceipt]) -> Vec<Self> {
+        let mut receipts = receipts.iter().collect::<Vec<_>>();
+        receipts.sort_by_key(|receipt| receipt.index);
+        { receipts.into_iter() }
+            .flat_map(|receipt| receipt.logs.iter().map(move |log| (receipt, log)))
+            .enumerate()
+            .map(|(log_index, (receipt, log))| LogEntry {
+                block_number: receipt.block_number,
+                tx_hash:      receipt.tx_hash,
+                tx_index:     receipt.index,
+                log_index:    log_index as u64,
+                log:          log.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Bloom filter of the addresses the logs are about, for headers.
+pub fn logs_bloom<'a>(logs: impl IntoIterator<Item = &'a Log>) -> Bloom {
+    let mut bloom = Bloom::zero();
+    for address in logs.into_iter().filter_map(Log::address) {
+        bloom.accrue(BloomInput::Raw(address.as_bytes()));
+    }
+    bloom
+}
+
+#[cfg(test)]
+mod tests {
+    use ophelia::{HashValue, PrivateKey, PublicKey, Signature, ToPublicKey};
+    use share::limits::TRANSACTION_LIMIT;
+
+    use super::*;
+    use crate::dev::DevWallet;
+
+    #[test]
+    fn test_signed_header() {
+        let wallet = DevWallet::derive(0).unwrap();
+        let mut header = Header {
+            chain_id:         U64::one(),
+            number:           U64::one(),
+            prev_hash:        Hash::zero(),
+            timestamp:        1000u64.into(),
+            transaction_root: Hash::repeat_byte(1),
+            prev_state_root:  Hash::repeat_byte(2),
+            cycles_limit:     1000u64.into(),
+            proposer:         wallet.address,
+            post_state_root:  Hash::repeat_byte(3),
+            logs_bloom:       Bloom::zero(),
+            protocol_version: 2,
+            signature:        None,
+        };
+        assert!(header.verify_proposer().is_err());
+        let unsigned = header.hash();
+        assert_eq!(rlp::decode::<Header>(&header.rlp_bytes()), Ok(header.clone()));
+
+        let signature = wallet.key.sign_message(&HashValue::from_bytes_unchecked(unsigned.0));
+        header.signature = Some(SignaturePair {
+            pub_key:   wallet.key.pub_key().to_bytes(),
+            signature: signature.to_bytes(),
+        });
+        header.verify_proposer().unwrap();
+        // The signature is over the hash, so it's left out of it
+        assert_eq!(header.hash(), unsigned);
+        assert_eq!(rlp::decode::<Header>(&header.rlp_bytes()), Ok(header.clone()));
+
+        header.proposer = H160::repeat_byte(9);
+        assert!(header.verify_proposer().is_err());
+    }
+
+    #[test]
+    fn test_decode_rlp_limits() {
+        let raw = RawTransaction {
+            chain_id:     U64::one(),
+            cycles_price: U64::one(),
+            cycles_limit: 1000u64.into(),
+            nonce:        Hash::repeat_byte(1),
+            requests:     vec![TransactionRequest {
+                address:  H160::repeat_byte(2),
+                token_id: Hash::repeat_byte(3),
+                amount:   4u64.into(),
+                action:   TokenAction::Transfer,
+                to:       Some(H160::repeat_byte(5)),
+            }],
+            sender:       H160::repeat_byte(2),
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        };
+        let encoded = raw.rlp_bytes().to_vec();
+        assert_eq!(
+            decode_rlp::<RawTransaction>(&encoded, &TRANSACTION_LIMIT),
+            Ok(raw.clone())
+        );
+
+        let envelope = |tx: &RawTransaction| {
+            let encoded = tx.rlp_bytes();
+            let rlp = Rlp::new(&encoded);
+            (rlp.val_at::<u8>(0).unwrap(), rlp.val_at::<u8>(1).unwrap())
+        };
+        assert_eq!(envelope(&raw), (TRANSFER_TX_TYPE, UNTIMED_TX_VERSION));
+
+        // A timeout takes the type's current version, which keeps the type
+        let timed = RawTransaction {
+            timeout: Some(7u64.into()),
+            alias: Some(AliasRegistration {
+                name:    "alice".to_owned(),
+                address: H160::repeat_byte(2),
+                blocks:  10,
+            }),
+            ..raw.clone()
+        };
+        assert_eq!(envelope(&timed), (ALIAS_TX_TYPE, ALIAS_TX_VERSION));
+        assert_eq!(
+            decode_rlp::<RawTransaction>(&timed.rlp_bytes(), &TRANSACTION_LIMIT),
+            Ok(timed.clone())
+        );
+        // Where the timeout field is optional
+        let mut current = rlp::RlpStream::new_list(3);
+        current.append(&TRANSFER_TX_TYPE).append(&TRANSFER_TX_VERSION);
+        current
+            .begin_list(7)
+            .append(&raw.chain_id)
+            .append(&raw.cycles_price)
+            .append(&raw.cycles_limit)
+            .append(&raw.nonce)
+            .append_list(&raw.requests)
+            .append(&raw.sender)
+            .begin_list(0);
+        assert_eq!(
+            decode_rlp::<RawTransaction>(&current.out(), &TRANSACTION_LIMIT),
+            Ok(raw.clone())
+        );
+        assert!(!timed.expired_at(7));
+        assert!(timed.expired_at(8));
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(matches!(
+            decode_rlp::<RawTransaction>(&trailing, &TRANSACTION_LIMIT),
+            Err(DecodeError::Malformed { .. })
+        ));
+
+        let mut nested = rlp::RlpStream::new();
+        for _ in 0..TRANSACTION_LIMIT.max_depth + 1 {
+            nested.begin_list(1);
+        }
+        nested.append_empty_data();
+        assert_eq!(
+            decode_rlp::<RawTransaction>(&nested.out(), &TRANSACTION_LIMIT),
+            Err(TRANSACTION_LIMIT.too_deep())
+        );
+
+        let oversized = vec![0u8; TRANSACTION_LIMIT.max_size + 1];
+        assert!(matches!(
+            decode_rlp::<RawTransaction>(&oversized, &TRANSACTION_LIMIT),
+            Err(DecodeError::TooLarge { .. })
+        ));
+    }
 }
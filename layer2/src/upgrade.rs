@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::RawTransaction;
+
+/// Highest protocol version this node speaks. Version 1 is plain transfers,
+/// version 2 adds multisig, alias and timeout transactions.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Block heights protocol versions activate at, the same on every node of a
+/// chain. Binaries speaking a new version can be rolled out ahead of its
+/// height and keep following the old rules until then.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Upgrades {
+    // Chains started before versioning run version 2 from genesis
+    pub v2_height: u64,
+}
+
+impl Upgrades {
+    /// Protocol version the rules of block `number` follow.
+    pub fn version_at(&self, number: u64) -> u32 {
+        if number >= self.v2_height {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// The next version to activate after block `number`, with its height.
+    pub fn next_activation(&self, number: u64) -> Option<(u32, u64)> {
+        Some((2, self.v2_height)).filter(|(_, height)| *height > number)
+    }
+
+    pub fn allows(&self, raw: &RawTransaction, number: u64) -> bool {
+        required_version(raw) <= self.version_at(number)
+    }
+}
+
+/// Protocol version that introduced the kind of `raw`.
+pub fn required_version(raw: &RawTransaction) -> u32 {
+    if raw.multisig.is_some() || raw.alias.is_some() || raw.timeout.is_some() {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::Handshake;
+    use crate::types::{Hash, H160, U64};
+
+    #[test]
+    fn test_activation_heights() {
+        let upgrades = Upgrades { v2_height: 100 };
+        assert_eq!(upgrades.version_at(99), 1);
+        assert_eq!(upgrades.version_at(100), 2);
+        assert_eq!(upgrades.next_activation(50), Some((2, 100)));
+        assert_eq!(upgrades.next_activation(100), None);
+        assert_eq!(Upgrades::default().version_at(1), PROTOCOL_VERSION);
+
+        let transfer = RawTransaction {
+            chain_id:     U64::one(),
+            cycles_price: U64::one(),
+            cycles_limit: U64::one(),
+            nonce:        Hash::zero(),
+            requests:     Vec::new(),
+            sender:       H160::repeat_byte(1),
+            multisig:     None,
+            alias:        None,
+            timeout:      None,
+        };
+        let timed = RawTransaction {
+            timeout: Some(200u64.into()),
+            ..transfer.clone()
+        };
+        assert!(upgrades.allows(&transfer, 1));
+        assert!(!upgrades.allows(&timed, 99));
+        assert!(upgrades.allows(&timed, 100));
+    }
+
+    #[test]
+    fn test_negotiate_handshake() {
+        let upgrades = Upgrades { v2_height: 100 };
+        let local = Handshake {
+            peer_id:          Hash::repeat_byte(1),
+            chain_id:         U64::one(),
+            protocol_version: PROTOCOL_VERSION,
+            tip:              50u64.into(),
+        };
+        let old = Handshake {
+            peer_id: Hash::repeat_byte(2),
+            protocol_version: 1,
+            ..local.clone()
+        };
+        assert_eq!(local.negotiate(&local, &upgrades).unwrap(), 2);
+        assert_eq!(local.negotiate(&old, &upgrades).unwrap(), 1);
+
+        // Past the activation height version 1 nodes can't follow
+        let ahead = Handshake {
+            tip: 99u64.into(),
+            ..local.clone()
+        };
+        assert!(ahead.negotiate(&old, &upgrades).is_err());
+
+        let other_chain = Handshake {
+            chain_id: 2u64.into(),
+            ..local.clone()
+        };
+        assert!(local.negotiate(&other_chain, &upgrades).is_err());
+    }
+}
@@ -0,0 +1,1108 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use hyper::{
+    header::AUTHORIZATION,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use primitive_types::{H160, H256, U256};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use share::front::RpcFront;
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        index::{ChannelFilter, MAX_CHANNEL_PAGE},
+        mempool::{ChannelMap, MemPool, Submitted},
+        store::{Store, StoreError},
+    },
+    consensus::ChannelConsensus,
+    diagnostics::diagnose_account,
+    dispute::DisputeTracker,
+    faucet::{ChannelFaucet, FaucetError},
+    finality::FinalityTracker,
+    guardian::Guardian,
+    health::{HealthReport, HealthService},
+    notify::{Notifier, Webhook},
+    payment::PaymentTracker,
+    rebalance::Rebalancer,
+    retention::{ReceiptRetention, RetentionError},
+    revenue::RevenueLedger,
+    tracking::{OutPoint, TransferTracker},
+    types::{SignedTransaction, UpdateChannel},
+};
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const MAX_USAGE_BLOCKS: usize = 1024;
+const INVALID_DAYS: &str = "from and to must be days since the unix epoch, from up to to";
+
+/// Page of channels `POST /channels/query` answers.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChannelQuery {
+    #[serde(flatten)]
+    pub filter: ChannelFilter,
+    pub cursor: Option<U256>,
+    // Channels per page, at most and by default MAX_CHANNEL_PAGE
+    pub limit: Option<usize>,
+}
+
+/// HTTP API of the node at `rpc_uri`, json bodies:
+///
+/// - `GET /health` and `GET /ready` probes, the `HealthReport` or 503 with
+///   the reason
+/// - `POST /transactions` a signed transaction, answered with `Submitted`.
+///   An `Idempotency-Key` header makes retries submit it only once
+/// - `GET /transactions/<hash>/receipt` its receipt once packaged, 410 once
+///   trimmed
+/// - `GET /accounts/<address>/pending` transactions of the address waiting
+///   to be packaged, by channel
+/// - `GET /accounts/<address>/diagnostics` why they wait: the versions
+///   each channel is at, which transactions are stale and the fee a
+///   replacement needs
+/// - `POST /channels/query` a page of the channels matching a `ChannelQuery`
+/// - `GET /channels/disputes` the channels in challenge, closest deadline
+///   first, `GET /channels/<id>/dispute` one of them and
+///   `GET /channels/<id>/slashing_evidence` the stale challenges raised on
+///   a channel
+/// - `GET /blocks/<number>/usage` and `GET /blocks/usage?from=<n>&limit=<n>`
+///   resources used by one block or consecutive ones
+/// - `GET /blocks/<number>/finality` how far the block is towards settlement
+/// - `GET /deposits/<tx_hash>/<index>` and `GET /withdrawals/<request_id>`
+///   the stage a deposit or withdrawal reached
+/// - `GET /payments/<payment_id>` the channel updates that paid a payment
+///   and how far they are towards settlement
+/// - `GET /revenue?from=<day>&to=<day>&token=<id>` the fees collected in
+///   the days `from..=to` since the unix epoch, of one token if given, and
+///   `GET /channels/<id>/revenue?from=<day>&to=<day>` those of one channel
+/// - `POST /faucet/<address>` opens a test channel to the address while the
+///   faucet is enabled, answered with its id, 429 within the cooldown or
+///   past the requests of the minute
+///
+/// Routes under `/admin` need `Authorization: Bearer <token>` with one of
+/// the configured `admin_tokens`:
+///
+/// - `GET /admin/webhooks` the registered webhooks, `POST /admin/webhooks`
+///   registers a `Webhook` and answers its id, `DELETE /admin/webhooks/<id>`
+///   removes one
+/// - `GET /admin/rebalance/actions` the latest rebalancing action of every
+///   channel, proposed or executed
+/// - `POST /admin/guardian/states` keeps an `UpdateChannel` both
+///   participants signed, answered with whether it's newer than the one
+///   kept, `GET /admin/guardian/alerts` the latest response of every channel
+#[derive(Clone)]
+pub struct NodeApi {
+    mempool: ChannelMap,
+    chain: ChannelChain,
+    consensus: Arc<ChannelConsensus>,
+    receipts: ReceiptRetention,
+    finality: FinalityTracker,
+    transfers: TransferTracker,
+    health: HealthService,
+    payments: Option<PaymentTracker>,
+    disputes: Option<DisputeTracker>,
+    revenue: Option<RevenueLedger>,
+    notifier: Option<Notifier>,
+    rebalancer: Option<Rebalancer>,
+    guardian: Option<Guardian>,
+    faucet: Option<Arc<ChannelFaucet>>,
+    admin_tokens: Vec<String>,
+}
+
+impl NodeApi {
+    pub fn new(
+        store: Store,
+        mempool: ChannelMap,
+        consensus: Arc<ChannelConsensus>,
+        receipts: ReceiptRetention,
+        finality: FinalityTracker,
+        transfers: TransferTracker,
+        health: HealthService,
+    ) -> Result<Self, StoreError> {
+        let api = NodeApi {
+            mempool,
+            chain: ChannelChain::new(store)?,
+            consensus,
+            receipts,
+            finality,
+            transfers,
+            health,
+            payments: None,
+            disputes: None,
+            revenue: None,
+            notifier: None,
+            rebalancer: None,
+            guardian: None,
+            faucet: None,
+            admin_tokens: Vec::new(),
+        };
+
+        Ok(api)
+    }
+
+    pub fn with_payments(mut self, payments: PaymentTracker) -> Self {
+        self.payments = Some(payments);
+        self
+    }
+
+    pub fn with_disputes(mut self, disputes: DisputeTracker) -> Self {
+        self.disputes = Some(disputes);
+        self
+    }
+
+    pub fn with_revenue(mut self, revenue: RevenueLedger) -> Self {
+        self.revenue = Some(revenue);
+        self
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn with_rebalancer(mut self, rebalancer: Rebalancer) -> Self {
+        self.rebalancer = Some(rebalancer);
+        self
+    }
+
+    pub fn with_guardian(mut self, guardian: Guardian) -> Self {
+        self.guardian = Some(guardian);
+        self
+    }
+
+    pub fn with_faucet(mut self, faucet: Arc<ChannelFaucet>) -> Self {
+        self.faucet = Some(faucet);
+        self
+    }
+
+    pub fn with_admin_tokens(mut self, admin_tokens: Vec<String>) -> Self {
+        self.admin_tokens = admin_tokens;
+        self
+    }
+
+    /// Serve at `addr`, from loopback behind `front` if there's one.
+    pub async fn serve(self, addr: SocketAddr, front: Option<RpcFront>) -> Result<()> {
+        let make_svc = make_service_fn(move |_| {
+            let api = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let api = api.clone();
+                    async move { Ok::<_, Infallible>(api.handle(req).await) }
+                }))
+            }
+        });
+
+        let inner = front.as_ref().map_or(addr, |_| RpcFront::inner_addr());
+        let server = Server::try_bind(&inner)?.serve(make_svc);
+        if let Some(front) = front {
+            front.spawn(addr, server.local_addr()).await?;
+        }
+
+        server.await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let method = req.method().clone();
+        let path = req.uri().path().trim_end_matches('/').to_owned();
+        let segments = path.split('/').skip(1).collect::<Vec<_>>();
+        let query = req.uri().query().unwrap_or_default().to_owned();
+        let param = |name: &str| {
+            { query.split('&') }
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_owned)
+        };
+        let days = || match (param("from")?.parse(), param("to")?.parse()) {
+            (Ok(from), Ok(to)) if from <= to => Some((from, to)),
+            _ => None,
+        };
+        if segments.first() == Some(&"admin") && !bearer_authorized(&req, &self.admin_tokens) {
+            return response(StatusCode::UNAUTHORIZED, Body::empty());
+        }
+        let key = { req.headers().get(IDEMPOTENCY_KEY) }
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_owned);
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+        };
+
+        match (&method, segments.as_slice()) {
+            (&Method::GET, ["health"]) => probe_response(self.health.health().await),
+            (&Method::GET, ["ready"]) => probe_response(self.health.ready().await),
+            (&Method::POST, ["transactions"]) => match serde_json::from_slice(&body) {
+                Ok(tx) => match self.submit(tx, key.as_deref()) {
+                    Ok(submitted) => json_response(Ok(submitted)),
+                    Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                },
+                Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+            },
+            (&Method::GET, ["transactions", hash, "receipt"]) => {
+                let hash = match hash.trim_start_matches("0x").parse::<H256>() {
+                    Ok(hash) => hash,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                match self.receipts.get_receipt(hash).await {
+                    Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+                    Err(e) if e.is::<RetentionError>() => {
+                        response(StatusCode::GONE, e.to_string().into())
+                    }
+                    receipt => json_response(receipt),
+                }
+            }
+            (&Method::GET, ["accounts", address, "pending"]) => {
+                match address.trim_start_matches("0x").parse::<H160>() {
+                    Ok(address) => json_response(Ok(self.mempool.pending_of(address))),
+                    Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                }
+            }
+            (&Method::GET, ["accounts", address, "diagnostics"]) => {
+                match address.trim_start_matches("0x").parse::<H160>() {
+                    Ok(address) => {
+                        json_response(diagnose_account(&self.chain, &self.mempool, address).await)
+                    }
+                    Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                }
+            }
+            (&Method::POST, ["channels", "query"]) => {
+                match serde_json::from_slice::<ChannelQuery>(&body) {
+                    Ok(query) => {
+                        let limit = query.limit.unwrap_or(MAX_CHANNEL_PAGE);
+                        let page =
+                            { self.chain.get_channels(query.filter, query.cursor, limit) }.await;
+                        json_response(page)
+                    }
+                    Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                }
+            }
+            (&Method::GET, ["channels", "disputes"]) => {
+                let disputes = match &self.disputes {
+                    Some(disputes) => disputes,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                let listed = match self.tip_number().await {
+                    Ok(tip) => disputes.disputes(tip).await,
+                    Err(e) => Err(e),
+                };
+                json_response(listed)
+            }
+            (&Method::GET, ["channels", channel_id, route @ ("dispute" | "slashing_evidence")]) => {
+                let disputes = match &self.disputes {
+                    Some(disputes) => disputes,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                let channel_id = match channel_id.trim_start_matches("0x").parse::<U256>() {
+                    Ok(channel_id) => channel_id,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                if *route == "slashing_evidence" {
+                    return json_response(disputes.slashing_evidence(channel_id).await);
+                }
+
+                let dispute = match self.tip_number().await {
+                    Ok(tip) => disputes.get(channel_id, tip).await,
+                    Err(e) => Err(e),
+                };
+                match dispute {
+                    Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+                    dispute => json_response(dispute),
+                }
+            }
+            (&Method::GET, ["channels", channel_id, "revenue"]) => {
+                let revenue = match &self.revenue {
+                    Some(revenue) => revenue,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                let channel_id = match channel_id.trim_start_matches("0x").parse::<U256>() {
+                    Ok(channel_id) => channel_id,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                match days() {
+                    Some((from, to)) => {
+                        json_response(revenue.channel_revenue(channel_id, from, to).await)
+                    }
+                    None => response(StatusCode::BAD_REQUEST, INVALID_DAYS.into()),
+                }
+            }
+            (&Method::GET, ["revenue"]) => {
+                let revenue = match &self.revenue {
+                    Some(revenue) => revenue,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                let token_id = { param("token") }
+                    .map(|token_id| token_id.trim_start_matches("0x").parse::<U256>())
+                    .transpose();
+                match (days(), token_id) {
+                    (Some((from, to)), Ok(token_id)) => {
+                        json_response(revenue.report(from, to, token_id).await)
+                    }
+                    (None, _) => response(StatusCode::BAD_REQUEST, INVALID_DAYS.into()),
+                    (_, Err(e)) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                }
+            }
+            (&Method::GET, ["blocks", "usage"]) => {
+                let from = param("from")
+                    .and_then(|from| from.parse().ok())
+                    .unwrap_or(1);
+                let limit = { param("limit") }
+                    .and_then(|limit| limit.parse().ok())
+                    .unwrap_or(MAX_USAGE_BLOCKS);
+                let usage = {
+                    self.consensus
+                        .block_usage_range(from, limit.min(MAX_USAGE_BLOCKS))
+                }
+                .await;
+                json_response(usage)
+            }
+            (&Method::GET, ["blocks", number, "usage"]) => match number.parse::<u64>() {
+                Ok(number) => match self.consensus.get_block_usage(number).await {
+                    Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+                    usage => json_response(usage),
+                },
+                Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+            },
+            (&Method::GET, ["blocks", number, "finality"]) => match number.parse::<u64>() {
+                Ok(number) => match self.finality.get_block_finality(number).await {
+                    Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+                    finality => json_response(finality),
+                },
+                Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+            },
+            (&Method::GET, ["deposits", tx_hash, index]) => {
+                let out_point = match (tx_hash.trim_start_matches("0x").parse(), index.parse()) {
+                    (Ok(tx_hash), Ok(index)) => OutPoint { tx_hash, index },
+                    _ => return response(StatusCode::BAD_REQUEST, "invalid out point".into()),
+                };
+                match self.transfers.get_deposit_status(out_point).await {
+                    Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+                    status => json_response(status),
+                }
+            }
+            (&Method::GET, ["withdrawals", request_id]) => {
+                let request_id = match request_id.trim_start_matches("0x").parse::<H256>() {
+                    Ok(request_id) => request_id,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                match self.transfers.get_withdrawal_status(request_id).await {
+                    Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+                    status => json_response(status),
+                }
+            }
+            (&Method::GET, ["payments", payment_id]) => {
+                let payments = match &self.payments {
+                    Some(payments) => payments,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                let payment_id = match payment_id.trim_start_matches("0x").parse::<H256>() {
+                    Ok(payment_id) => payment_id,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                match payments.get_payment_status(payment_id).await {
+                    Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+                    status => json_response(status),
+                }
+            }
+            (&Method::POST, ["faucet", address]) => {
+                let faucet = match &self.faucet {
+                    Some(faucet) => faucet,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                let address = match address.trim_start_matches("0x").parse::<H160>() {
+                    Ok(address) => address,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                match faucet.open(address) {
+                    Ok(channel_id) => json_response(Ok(channel_id)),
+                    Err(e @ (FaucetError::Cooldown(_) | FaucetError::Busy)) => {
+                        response(StatusCode::TOO_MANY_REQUESTS, e.to_string().into())
+                    }
+                    Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                }
+            }
+            (_, ["admin", "webhooks", rest @ ..]) => {
+                let notifier = match &self.notifier {
+                    Some(notifier) => notifier,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                match (&method, rest) {
+                    (&Method::GET, []) => json_response(notifier.webhooks().await),
+                    (&Method::POST, []) => match serde_json::from_slice::<Webhook>(&body) {
+                        Ok(webhook) => match notifier.register(webhook).await {
+                            Ok(id) => json_response(Ok(id)),
+                            Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                        },
+                        Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                    },
+                    (&Method::DELETE, [id]) => match id.trim_start_matches("0x").parse::<H256>() {
+                        Ok(id) => json_response(notifier.unregister(id).await),
+                        Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                    },
+                    _ => response(StatusCode::NOT_FOUND, Body::empty()),
+                }
+            }
+            (&Method::GET, ["admin", "rebalance", "actions"]) => match &self.rebalancer {
+                Some(rebalancer) => json_response(rebalancer.actions().await),
+                None => response(StatusCode::NOT_FOUND, Body::empty()),
+            },
+            (_, ["admin", "guardian", route]) => {
+                let guardian = match &self.guardian {
+                    Some(guardian) => guardian,
+                    None => return response(StatusCode::NOT_FOUND, Body::empty()),
+                };
+                match (&method, *route) {
+                    (&Method::GET, "alerts") => json_response(guardian.alerts().await),
+                    (&Method::POST, "states") => {
+                        let update = match serde_json::from_slice::<UpdateChannel>(&body) {
+                            Ok(update) => update,
+                            Err(e) => {
+                                return response(StatusCode::BAD_REQUEST, e.to_string().into())
+                            }
+                        };
+                        match self.chain.get_channel(update.channel_id).await {
+                            Ok(channel) => match guardian.store_state(&channel, update).await {
+                                Ok(stored) => json_response(Ok(stored)),
+                                Err(e) => response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                            },
+                            Err(e) => {
+                                response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string().into())
+                            }
+                        }
+                    }
+                    _ => response(StatusCode::NOT_FOUND, Body::empty()),
+                }
+            }
+            _ => response(StatusCode::NOT_FOUND, Body::empty()),
+        }
+    }
+
+    async fn tip_number(&self) -> Result<u64> {
+        let tip = self.chain.tip_header().await?;
+        Ok(tip.map_or(0, |header| header.number))
+    }
+
+    fn submit(&self, tx: SignedTransaction, key: Option<&str>) -> Result<Submitted> {
+        let tx_hash = tx.hash;
+        match key {
+            Some(key) => self.mempool.push_transaction_with_key(tx, key),
+            None => {
+                self.mempool.push_transaction(tx)?;
+                Ok(Submitted {
+                    tx_hash,
+                    pending: true,
+                })
+            }
+        }
+    }
+}
+
+/// Whether `req` carries `Authorization: Bearer <token>` with one of
+/// `tokens`, never with none.
+pub fn bearer_authorized(req: &Request<Body>, tokens: &[String]) -> bool {
+    let token = { req.headers().get(AUTHORIZATION) }
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        // Compared as hashes, so the comparison time doesn't leak how much
+        // of a token matched
+        Some(token) => {
+            let token = Sha256::digest(token.as_bytes());
+            { tokens.iter() }.any(|allowed| Sha256::digest(allowed.as_bytes()) == token)
+        }
+        None => false,
+    }
+}
+
+fn json_response<T: Serialize>(result: Result<T>) -> Response<Body> {
+    match result.and_then(|value| Ok(serde_json::to_vec(&value)?)) {
+        Ok(body) => response(StatusCode::OK, body.into()),
+        Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string().into()),
+    }
+}
+
+fn probe_response(report: Result<HealthReport>) -> Response<Body> {
+    match report.and_then(|report| Ok(serde_json::to_vec(&report)?)) {
+        Ok(body) => response(StatusCode::OK, body.into()),
+        Err(e) => response(StatusCode::SERVICE_UNAVAILABLE, e.to_string().into()),
+    }
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use primitive_types::U128;
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::{
+            common::{secp256k1_address, sign_recoverable, H256Ext},
+            index::ChannelPage,
+            receipt::StreamedReceipt,
+            smt::SMT,
+            snapshot::{channel_index_key, CHANNEL_INDEX_TREE},
+        },
+        consensus::Consensus,
+        diagnostics::{AccountDiagnostics, PendingStatus},
+        dispute::{DisputeInfo, SlashingEvidence},
+        faucet::FaucetPolicy,
+        finality::{BlockFinality, FinalityStage},
+        fixture::consensus_receipt,
+        guardian::GuardianAlert,
+        health::HealthPolicy,
+        notify::{HttpTransport, WatchTarget},
+        payment::{PaymentStage, PaymentStatus},
+        rebalance::{ActionStatus, RebalanceAction},
+        revenue::{ChannelRevenue, RevenueReport},
+        tracking::{DepositStage, DepositStatus, WithdrawalStatus},
+        types::{
+            Balance, Channel, ChannelState, CreateChannel, RawTransaction, Symbol, Token,
+            UpdateChannel,
+        },
+        usage::BlockUsage,
+    };
+
+    use super::*;
+
+    const CHAIN_ID: u64 = 1;
+    const ADMIN_TOKEN: &str = "0123456789abcdef";
+
+    fn create_channel_tx(id: u64) -> SignedTransaction {
+        let raw = RawTransaction::CreateChannel(CreateChannel {
+            chain_id: CHAIN_ID,
+            id: id.into(),
+            token: Token {
+                symbol: Symbol::new("CKUSD").unwrap(),
+                ..Default::default()
+            },
+            challenge_blocks: 10,
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            balance2: [Balance::default(), Balance::default()],
+            guard: None,
+        });
+
+        SignedTransaction {
+            raw,
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::repeat_byte(1),
+            hash: H256::repeat_byte(id as u8),
+        }
+    }
+
+    fn submit(tx: &SignedTransaction, key: Option<&str>) -> Request<Body> {
+        let mut req = Request::post("/transactions");
+        if let Some(key) = key {
+            req = req.header(IDEMPOTENCY_KEY, key);
+        }
+        req.body(serde_json::to_vec(tx).unwrap().into()).unwrap()
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    fn admin(method: Method, path: &str, body: Body) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+            .body(body)
+            .unwrap()
+    }
+
+    // Api over an empty chain, health and settlement checks at their defaults
+    fn node_api(store: &Store) -> NodeApi {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let consensus = ChannelConsensus::new(mempool.clone(), store.clone(), CHAIN_ID).unwrap();
+        let receipts = consensus.receipt_stream().clone();
+        let retention = ReceiptRetention::new(store, receipts, Default::default()).unwrap();
+        let finality = FinalityTracker::new(store.clone(), 10).unwrap();
+        let transfers = TransferTracker::new(store, finality.clone()).unwrap();
+        let health = HealthService::new(store.clone(), finality.clone(), Default::default());
+        NodeApi::new(
+            store.clone(),
+            mempool,
+            Arc::new(consensus),
+            retention,
+            finality,
+            transfers,
+            health.unwrap(),
+        )
+        .unwrap()
+    }
+
+    async fn read<T: serde::de::DeserializeOwned>(resp: Response<Body>) -> T {
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_submit_and_query() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let consensus = ChannelConsensus::new(mempool.clone(), store.clone(), CHAIN_ID).unwrap();
+        let receipts = consensus.receipt_stream().clone();
+        let retention = ReceiptRetention::new(&store, receipts, Default::default()).unwrap();
+        let consensus = Arc::new(consensus);
+        let finality = FinalityTracker::new(store.clone(), 10).unwrap();
+        let transfers = TransferTracker::new(&store, finality.clone()).unwrap();
+        let policy = HealthPolicy {
+            max_settlement_lag: 1,
+        };
+        let health = HealthService::new(store.clone(), finality.clone(), policy).unwrap();
+        let api = NodeApi::new(
+            store,
+            mempool,
+            Arc::clone(&consensus),
+            retention,
+            finality,
+            transfers.clone(),
+            health,
+        )
+        .unwrap();
+        let [tx1, tx2] = [1, 2].map(create_channel_tx);
+
+        let submitted: Submitted = read(api.handle(submit(&tx1, None)).await).await;
+        assert_eq!(submitted.tx_hash, tx1.hash);
+        // Known transactions are refused, retries with the same key are not
+        let resp = api.handle(submit(&tx1, None)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        for _ in 0..2 {
+            let submitted: Submitted = read(api.handle(submit(&tx2, Some("retry"))).await).await;
+            assert_eq!(submitted.tx_hash, tx2.hash);
+        }
+
+        let resp = api
+            .handle(get(&format!("/accounts/{:?}/pending", tx1.from)))
+            .await;
+        let pending: BTreeMap<U256, Vec<SignedTransaction>> = read(resp).await;
+        assert_eq!(pending[&U256::one()][0].hash, tx1.hash);
+        assert_eq!(pending[&U256::from(2)][0].hash, tx2.hash);
+        let resp = api
+            .handle(get(&format!("/accounts/{:?}/diagnostics", tx1.from)))
+            .await;
+        let diagnostics: AccountDiagnostics = read(resp).await;
+        let statuses = { diagnostics.channels.iter() }
+            .map(|channel| channel.pending[0].status)
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, vec![PendingStatus::Packaged; 2]);
+        let resp = api.handle(get("/accounts/0x01/diagnostics")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = api
+            .handle(get(&format!("/transactions/{:?}/receipt", tx1.hash)))
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let receipt = consensus.produce_block().await.unwrap();
+        consensus.apply_consensus_receipt(&receipt).await.unwrap();
+        let resp = api
+            .handle(get(&format!("/transactions/{:?}/receipt", tx1.hash)))
+            .await;
+        assert_eq!(read::<StreamedReceipt>(resp).await.block_number, 1);
+        let resp = api.handle(get("/transactions/0x01/receipt")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let query = ChannelQuery {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let req = Request::post("/channels/query")
+            .body(serde_json::to_vec(&query).unwrap().into())
+            .unwrap();
+        let page: ChannelPage = read(api.handle(req).await).await;
+        assert_eq!(page.channels[0].id, U256::one());
+        assert_eq!(page.next, Some(2.into()));
+
+        let usage: BlockUsage = read(api.handle(get("/blocks/1/usage")).await).await;
+        assert_eq!(usage.tx_count, 2);
+        let usage: Vec<BlockUsage> = read(api.handle(get("/blocks/usage?from=1")).await).await;
+        assert_eq!(usage.len(), 1);
+        let resp = api.handle(get("/blocks/2/usage")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let finality: BlockFinality = read(api.handle(get("/blocks/1/finality")).await).await;
+        assert_eq!(finality.stage, FinalityStage::Produced);
+        let resp = api.handle(get("/blocks/2/finality")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let out_point = OutPoint {
+            tx_hash: H256::repeat_byte(1),
+            index: 1,
+        };
+        let path = format!("/deposits/{:?}/1", out_point.tx_hash);
+        assert_eq!(api.handle(get(&path)).await.status(), StatusCode::NOT_FOUND);
+        transfers.deposit_detected(out_point).await.unwrap();
+        let status: DepositStatus = read(api.handle(get(&path)).await).await;
+        assert_eq!(status.stage, DepositStage::Detected);
+        let resp = api.handle(get("/deposits/0x01/x")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let request_id = H256::repeat_byte(2);
+        transfers.withdrawal_queued(request_id).await.unwrap();
+        transfers.withdrawal_committed(request_id, 1).await.unwrap();
+        let resp = api
+            .handle(get(&format!("/withdrawals/{:?}", request_id)))
+            .await;
+        assert_eq!(read::<WithdrawalStatus>(resp).await.block_number, Some(1));
+
+        let report: HealthReport = read(api.handle(get("/health")).await).await;
+        assert_eq!(report.height, 1);
+        let report: HealthReport = read(api.handle(get("/ready")).await).await;
+        assert_eq!(report.settlement_lag, 1);
+        // Block 2 leaves settlement further behind than allowed
+        let resp = api.handle(submit(&create_channel_tx(3), None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let receipt = consensus.produce_block().await.unwrap();
+        consensus.apply_consensus_receipt(&receipt).await.unwrap();
+        let resp = api.handle(get("/ready")).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_payment_status() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let api = node_api(&store);
+        let payment_id = H256::repeat_byte(0xaa);
+        let path = format!("/payments/{:?}", payment_id);
+        assert_eq!(api.handle(get(&path)).await.status(), StatusCode::NOT_FOUND);
+
+        let payments = PaymentTracker::new(&store, api.finality.clone()).unwrap();
+        let api = api.with_payments(payments.clone());
+        let tx = SignedTransaction {
+            raw: RawTransaction::UpdateChannel(UpdateChannel {
+                channel_id: 7.into(),
+                version: 2,
+                payment_id: Some(payment_id),
+                ..Default::default()
+            }),
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::zero(),
+            hash: H256::repeat_byte(1),
+        };
+        let channel = Channel {
+            id: 7.into(),
+            version: 2,
+            ..Default::default()
+        };
+        let receipt = consensus_receipt(1, vec![tx], vec![channel]);
+        payments.on_consensus_receipt(&receipt).await.unwrap();
+
+        let status: PaymentStatus = read(api.handle(get(&path)).await).await;
+        assert_eq!(status.stage, PaymentStage::Committed);
+        assert_eq!(status.updates[0].tx_hash, H256::repeat_byte(1));
+        let resp = api
+            .handle(get(&format!("/payments/{:?}", H256::zero())))
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = api.handle(get("/payments/0x01")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_disputes() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let disputes = DisputeTracker::new(&store).unwrap();
+        let api = node_api(&store).with_disputes(disputes.clone());
+        let resp = api.handle(get("/channels/0x7/dispute")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let tx = SignedTransaction {
+            raw: RawTransaction::UpdateChannel(UpdateChannel {
+                channel_id: 7.into(),
+                version: 3,
+                ..Default::default()
+            }),
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::repeat_byte(1),
+            hash: H256::repeat_byte(1),
+        };
+        let channel = Channel {
+            id: 7.into(),
+            challenge_blocks: 10,
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            state: ChannelState::Challenge,
+            version: 3,
+            ..Default::default()
+        };
+        let receipt = consensus_receipt(1, vec![tx], vec![channel]);
+        api.chain
+            .save_block(Arc::clone(&receipt.block))
+            .await
+            .unwrap();
+        disputes.on_consensus_receipt(&receipt).await.unwrap();
+
+        let listed: Vec<DisputeInfo> = read(api.handle(get("/channels/disputes")).await).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].deadline, listed[0].blocks_remaining), (11, 10));
+        let dispute: DisputeInfo = read(api.handle(get("/channels/0x7/dispute")).await).await;
+        assert_eq!(dispute.challenger, Some(H160::repeat_byte(1)));
+        let resp = api.handle(get("/channels/0x7/slashing_evidence")).await;
+        assert!(read::<Vec<SlashingEvidence>>(resp).await.is_empty());
+        let resp = api.handle(get("/channels/0x8/dispute")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = api.handle(get("/channels/x/dispute")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_revenue() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let revenue = RevenueLedger::new(&store).unwrap();
+        let api = node_api(&store).with_revenue(revenue.clone());
+
+        let mut txs = [1, 2].map(create_channel_tx);
+        txs[0].fee = 5.into();
+        txs[1].fee = 3.into();
+        if let RawTransaction::CreateChannel(args) = &mut txs[1].raw {
+            args.token.id = 8.into();
+        }
+        // Produced on the first day of the unix epoch
+        let receipt = consensus_receipt(1, txs.to_vec(), vec![]);
+        revenue.on_consensus_receipt(&receipt).await.unwrap();
+
+        let report: RevenueReport = read(api.handle(get("/revenue?from=0&to=1")).await).await;
+        assert_eq!(report.tokens.len(), 2);
+        let report: RevenueReport =
+            read(api.handle(get("/revenue?from=0&to=0&token=0x8")).await).await;
+        assert_eq!(report.days.len(), 1);
+        assert_eq!(report.tokens[0].fees, 3.into());
+        let resp = api.handle(get("/channels/0x1/revenue?from=0&to=0")).await;
+        let entries: Vec<ChannelRevenue> = read(resp).await;
+        assert_eq!(entries[0].fees, 5.into());
+
+        for path in [
+            "/revenue?from=1&to=0",
+            "/revenue?to=1",
+            "/revenue?from=0&to=1&token=x",
+        ] {
+            let resp = api.handle(get(path)).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhooks() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let api = node_api(&store);
+        // Refused while no token is configured
+        let resp = api
+            .handle(admin(Method::GET, "/admin/webhooks", Body::empty()))
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let (notifier, _worker) = Notifier::new(&store, HttpTransport::new()).unwrap();
+        let api = { api.with_notifier(notifier) }.with_admin_tokens(vec![ADMIN_TOKEN.to_owned()]);
+        let resp = api.handle(get("/admin/webhooks")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let webhook = Webhook {
+            url: "http://127.0.0.1:1/hook".to_owned(),
+            secret: b"secret".to_vec(),
+            targets: vec![WatchTarget::Channel(U256::one())],
+        };
+        let body = serde_json::to_vec(&webhook).unwrap();
+        let resp = api
+            .handle(admin(Method::POST, "/admin/webhooks", body.into()))
+            .await;
+        assert_eq!(read::<H256>(resp).await, webhook.id());
+        let resp = api
+            .handle(admin(Method::GET, "/admin/webhooks", Body::empty()))
+            .await;
+        assert_eq!(read::<Vec<Webhook>>(resp).await, vec![webhook.clone()]);
+
+        let path = format!("/admin/webhooks/{:?}", webhook.id());
+        let resp = api
+            .handle(admin(Method::DELETE, &path, Body::empty()))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = api
+            .handle(admin(Method::GET, "/admin/webhooks", Body::empty()))
+            .await;
+        assert!(read::<Vec<Webhook>>(resp).await.is_empty());
+        let https = Webhook {
+            url: "https://example.com/hook".to_owned(),
+            ..webhook
+        };
+        let body = serde_json::to_vec(&https).unwrap();
+        let resp = api
+            .handle(admin(Method::POST, "/admin/webhooks", body.into()))
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_actions() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let api = node_api(&store).with_admin_tokens(vec![ADMIN_TOKEN.to_owned()]);
+        let list = || admin(Method::GET, "/admin/rebalance/actions", Body::empty());
+        assert_eq!(api.handle(list()).await.status(), StatusCode::NOT_FOUND);
+
+        let key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let rebalancer = Rebalancer::new(&store, CHAIN_ID, key, Default::default()).unwrap();
+        let channel = Channel {
+            id: 7.into(),
+            participant2: [H160::repeat_byte(2), rebalancer.operator()],
+            state: ChannelState::Open,
+            balance2: [
+                Balance {
+                    settled: 100.into(),
+                },
+                Balance {
+                    settled: 900.into(),
+                },
+            ],
+            ..Default::default()
+        };
+        { SMT::new_with_store(store.clone()).unwrap() }
+            .update(channel.id.to_h256(), channel.clone())
+            .unwrap();
+        { store.open_tree(CHANNEL_INDEX_TREE).unwrap() }
+            .insert(channel_index_key(&channel.id), channel.id)
+            .unwrap();
+        rebalancer.scan().await.unwrap();
+
+        let api = api.with_rebalancer(rebalancer);
+        let actions: Vec<RebalanceAction> = read(api.handle(list()).await).await;
+        assert_eq!(actions[0].channel_id, channel.id);
+        assert_eq!(actions[0].status, ActionStatus::Proposed);
+        let resp = api.handle(get("/admin/rebalance/actions")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_guardian_states() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let keys = [[2u8; 32], [1u8; 32]].map(|key| SecretKey::from_slice(&key).unwrap());
+        let [counterparty, operator] =
+            keys.map(|key| secp256k1_address(&PublicKey::from_secret_key(&Secp256k1::new(), &key)));
+        let channel = Channel {
+            id: 7.into(),
+            participant2: [counterparty, operator],
+            state: ChannelState::Open,
+            version: 2,
+            ..Default::default()
+        };
+        { SMT::new_with_store(store.clone()).unwrap() }
+            .update(channel.id.to_h256(), channel.clone())
+            .unwrap();
+        let guardian = Guardian::new(
+            &store,
+            ChannelMap::new(CHAIN_ID),
+            keys[1],
+            Default::default(),
+        )
+        .unwrap();
+        let api = { node_api(&store).with_guardian(guardian) }
+            .with_admin_tokens(vec![ADMIN_TOKEN.to_owned()]);
+
+        let mut update = UpdateChannel {
+            chain_id: CHAIN_ID,
+            channel_id: channel.id,
+            version: 5,
+            ..Default::default()
+        };
+        for (idx, key) in keys.iter().enumerate() {
+            update.signature2[idx] = sign_recoverable(key, update.sig_msg());
+        }
+        let submit = |update: &UpdateChannel| {
+            let body = serde_json::to_vec(update).unwrap();
+            admin(Method::POST, "/admin/guardian/states", body.into())
+        };
+        assert!(read::<bool>(api.handle(submit(&update)).await).await);
+        // Already kept
+        assert!(!read::<bool>(api.handle(submit(&update)).await).await);
+        let mut forged = UpdateChannel {
+            version: 6,
+            ..update.clone()
+        };
+        forged.signature2[0] = sign_recoverable(&keys[1], forged.sig_msg());
+        let resp = api.handle(submit(&forged)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = api
+            .handle(admin(Method::GET, "/admin/guardian/alerts", Body::empty()))
+            .await;
+        assert!(read::<Vec<GuardianAlert>>(resp).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_faucet() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let policy = FaucetPolicy {
+            enabled: true,
+            max_requests_per_minute: 2,
+            ..Default::default()
+        };
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let faucet = ChannelFaucet::new(mempool.clone(), CHAIN_ID, key, Token::default(), policy);
+        let api = node_api(&store).with_faucet(Arc::new(faucet));
+        let open = |address: &str| {
+            Request::post(format!("/faucet/{}", address))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let alice = "0x0101010101010101010101010101010101010101";
+        let channel_id = read::<U256>(api.handle(open(alice)).await).await;
+        match &mempool.package_transactions().unwrap()[0].raw {
+            RawTransaction::CreateChannel(args) => assert_eq!(args.id, channel_id),
+            _ => panic!("not a channel creation"),
+        }
+        let resp = api.handle(open(alice)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        read::<U256>(api.handle(open(&"02".repeat(20))).await).await;
+        // Past the requests of the minute
+        let resp = api.handle(open(&"03".repeat(20))).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = api.handle(open("carol")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = node_api(&store).handle(open(alice)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_serve_behind_front() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let front = RpcFront::new(None, &[], 1).unwrap();
+        tokio::spawn(node_api(&store).serve(addr, front));
+
+        let client = hyper::Client::new();
+        let uri = format!("http://{}/channels/disputes", addr);
+        let status = loop {
+            match client.get(uri.parse().unwrap()).await {
+                Ok(resp) => break resp.status(),
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        // Served from loopback, without disputes tracked
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use primitive_types::H256;
+use share::{
+    archive::{ArchiveKind, ArchiveReader, ArchiveWriter},
+    limits::BLOCK_LIMIT,
+};
+
+use crate::{
+    auxiliaries::{
+        chain::Chain,
+        common::{cbmt_merkle_root, decode_bincode},
+    },
+    scheduler::{Job, Schedule},
+    types::{Block, NumberHash},
+};
+
+/// Write blocks `from..=to` to a flat archive, one bincode encoded block
+/// per record. Returns the number of blocks written.
+pub async fn export_blocks<C: Chain, W: Write>(
+    chain: &C,
+    from: u64,
+    to: u64,
+    out: W,
+) -> Result<u64> {
+    if from > to {
+        return Err(anyhow!("empty block range {}..={}", from, to));
+    }
+
+    let mut writer = ArchiveWriter::new(out, ArchiveKind::Layer3)?;
+    for number in from..=to {
+        let block = chain
+            .get_encoded_block(NumberHash::Number(number))
+            .await?
+            .ok_or_else(|| anyhow!("block {} not found", number))?;
+        writer.append(&block.to_bincode()?)?;
+    }
+
+    writer.finish()?;
+    Ok(to - from + 1)
+}
+
+/// Backs the chain up into a directory, each run writes the blocks produced
+/// since the last one to `blocks-<from>-<to>.archive`. Blocks are kept in
+/// the backups after they're pruned from the chain.
+pub struct BackupJob<C> {
+    chain: C,
+    dir: PathBuf,
+}
+
+impl<C: Chain> BackupJob<C> {
+    pub fn new(chain: C, dir: PathBuf) -> Self {
+        BackupJob { chain, dir }
+    }
+
+    /// Returns the archive written, none if there are no new blocks.
+    pub async fn backup(&self) -> Result<Option<PathBuf>> {
+        fs::create_dir_all(&self.dir).with_context(|| format!("create {}", self.dir.display()))?;
+        let tip = match self.chain.tip_header().await? {
+            Some(tip) => tip.number,
+            None => return Ok(None),
+        };
+        let from = backed_up_tip(&self.dir)? + 1;
+        if from > tip {
+            return Ok(None);
+        }
+
+        // Renamed once complete, so a crash never leaves a partial archive
+        // that looks like a backup
+        let path = self.dir.join(format!("blocks-{}-{}.archive", from, tip));
+        let partial = path.with_extension("partial");
+        let mut out = BufWriter::new(File::create(&partial)?);
+        export_blocks(&self.chain, from, tip, &mut out).await?;
+        out.into_inner()?.sync_all()?;
+        fs::rename(&partial, &path)?;
+
+        Ok(Some(path))
+    }
+}
+
+/// Last block in the backup archives of `dir`.
+fn backed_up_tip(dir: &Path) -> Result<u64> {
+    let mut tip = 0;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let range = { name.to_str() }
+            .and_then(|name| name.strip_prefix("blocks-"))
+            .and_then(|name| name.strip_suffix(".archive"))
+            .and_then(|range| range.split_once('-'));
+        if let Some(to) = range.and_then(|(_, to)| to.parse::<u64>().ok()) {
+            tip = tip.max(to);
+        }
+    }
+
+    Ok(tip)
+}
+
+#[async_trait]
+impl<C: Chain + 'static> Job for BackupJob<C> {
+    fn name(&self) -> &'static str {
+        "backup"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::DailyAt("00:00".to_owned())
+    }
+
+    async fn run(&self) -> Result<()> {
+        if let Some(path) = self.backup().await? {
+            println!("[backup] wrote {}", path.display());
+        }
+        Ok(())
+    }
+}
+
+/// Save the blocks of an archive on top of `chain`. Blocks must extend the
+/// local tip one by one, blocks the chain already has are skipped as long
+/// as their hash matches. Returns the number of blocks saved.
+///
+/// Headers are checked for linkage, hash and transaction root, but blocks
+/// aren't re-executed, so the channel SMT has to be seeded separately.
+// Restores backups, there is no command for it yet
+#[allow(dead_code)]
+pub async fn import_blocks<C: Chain, R: Read>(chain: &C, archive: R) -> Result<u64> {
+    let reader = ArchiveReader::open(archive, ArchiveKind::Layer3)?;
+
+    let (mut next_number, mut parent_hash) = match chain.tip_header().await? {
+        Some(tip) => (tip.number + 1, tip.hash),
+        None => (1, H256::zero()),
+    };
+
+    let mut saved = 0;
+    for record in reader {
+        let block: Block = decode_bincode(&record?, &BLOCK_LIMIT)?;
+        let header = &block.header;
+
+        if header.number < next_number {
+            match chain.get_header(NumberHash::Number(header.number)).await? {
+                Some(local) if local.hash == header.hash => continue,
+                _ => {
+                    return Err(anyhow!(
+                        "block {} conflicts with the local chain",
+                        header.number
+                    ))
+                }
+            }
+        }
+        if header.number != next_number {
+            return Err(anyhow!(
+                "expected block {}, got {}",
+                next_number,
+                header.number
+            ));
+        }
+        if header.parent_hash != parent_hash || header.hash != header.calc_hash() {
+            return Err(anyhow!("block {} has a broken header", header.number));
+        }
+        let transaction_root =
+            cbmt_merkle_root(&block.txs.iter().map(|tx| tx.hash).collect::<Vec<_>>());
+        if header.transaction_root != transaction_root {
+            return Err(anyhow!("block {} transaction root mismatch", header.number));
+        }
+
+        next_number = header.number + 1;
+        parent_hash = header.hash;
+        chain.save_block(Arc::new(block)).await?;
+        saved += 1;
+    }
+
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::{chain::ChannelChain, store::Store},
+        types::BlockHeader,
+    };
+
+    use super::*;
+
+    fn block(number: u64, parent_hash: H256) -> Block {
+        let mut header = BlockHeader {
+            number,
+            parent_hash,
+            transaction_root: cbmt_merkle_root::<H256>(&[]),
+            ..Default::default()
+        };
+        header.hash = header.calc_hash();
+
+        Block {
+            header,
+            txs: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let source_path = tempdir().unwrap();
+        let source = ChannelChain::new(Store::open(source_path).unwrap()).unwrap();
+        let mut parent_hash = H256::zero();
+        for number in 1..=3 {
+            let block = block(number, parent_hash);
+            parent_hash = block.header.hash;
+            source.save_block(Arc::new(block)).await.unwrap();
+        }
+
+        let mut archive = Vec::new();
+        assert_eq!(export_blocks(&source, 1, 3, &mut archive).await.unwrap(), 3);
+
+        let target_path = tempdir().unwrap();
+        let target = ChannelChain::new(Store::open(target_path).unwrap()).unwrap();
+        assert_eq!(import_blocks(&target, archive.as_slice()).await.unwrap(), 3);
+        let tip = target.tip_block().await.unwrap().unwrap();
+        assert_eq!(tip.header.hash, parent_hash);
+
+        // Already imported blocks are skipped
+        assert_eq!(import_blocks(&target, archive.as_slice()).await.unwrap(), 0);
+
+        // A block that doesn't link to the tip is rejected
+        let mut gap = Vec::new();
+        let mut writer = ArchiveWriter::new(&mut gap, ArchiveKind::Layer3).unwrap();
+        writer
+            .append(&bincode::serialize(&block(5, parent_hash)).unwrap())
+            .unwrap();
+        writer.finish().unwrap();
+        assert!(import_blocks(&target, gap.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backup_is_incremental() {
+        let chain_path = tempdir().unwrap();
+        let chain = ChannelChain::new(Store::open(chain_path).unwrap()).unwrap();
+        let backup_dir = tempdir().unwrap();
+        let job = BackupJob::new(chain.clone(), backup_dir.path().to_owned());
+        assert_eq!(job.backup().await.unwrap(), None);
+
+        let mut parent_hash = H256::zero();
+        for number in 1..=3 {
+            let block = block(number, parent_hash);
+            parent_hash = block.header.hash;
+            chain.save_block(Arc::new(block)).await.unwrap();
+            if number == 2 {
+                let path = job.backup().await.unwrap().unwrap();
+                assert!(path.ends_with("blocks-1-2.archive"));
+            }
+        }
+
+        let path = job.backup().await.unwrap().unwrap();
+        assert!(path.ends_with("blocks-3-3.archive"));
+        assert_eq!(job.backup().await.unwrap(), None);
+
+        // The backups restore the whole chain
+        let target_path = tempdir().unwrap();
+        let target = ChannelChain::new(Store::open(target_path).unwrap()).unwrap();
+        for name in ["blocks-1-2.archive", "blocks-3-3.archive"] {
+            let archive = File::open(backup_dir.path().join(name)).unwrap();
+            import_blocks(&target, archive).await.unwrap();
+        }
+        let tip = target.tip_header().await.unwrap().unwrap();
+        assert_eq!(tip.hash, parent_hash);
+    }
+}
@@ -1,12 +1,300 @@
-use anyhow::Result;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use primitive_types::{H256, U256};
 
-use crate::types::{Block, Channel, NumberHash, SignedTransaction};
+use crate::{
+    auxiliaries::{
+        common::{cbmt_merkle_root, H256Ext},
+        index::{ChannelFilter, ChannelIndexes, ChannelPage},
+        smt::SMT,
+        store::{AsyncStore, Store, StoreError},
+    },
+    types::{Block, BlockHeader, Channel, EncodedBlock, NumberHash, SignedTransaction},
+};
+
+// Full blocks saved before headers and bodies were split, and the tip
+const BLOCK_TREE: &str = "block_tree";
+const HEADER_TREE: &str = "header_tree";
+const BODY_TREE: &str = "body_tree";
+const NUMBER_HASH_TREE: &str = "number_hash_tree";
+const TX_TREE: &str = "transaction_tree";
+const TIP_BLOCK_KEY: &str = "tip_block";
+
+#[async_trait]
+pub trait Chain: Sync + Send {
+    // Only tests need the whole tip block so far
+    #[allow(dead_code)]
+    async fn tip_block(&self) -> Result<Option<Arc<Block>>>;
+    async fn save_block(&self, block: Arc<Block>) -> Result<()>;
+    async fn get_block(&self, number_hash: NumberHash) -> Result<Option<Arc<Block>>>;
+    // Like `get_block`, leaving the transactions encoded
+    async fn get_encoded_block(&self, number_hash: NumberHash) -> Result<Option<EncodedBlock>>;
+    async fn tip_header(&self) -> Result<Option<BlockHeader>>;
+    async fn get_header(&self, number_hash: NumberHash) -> Result<Option<BlockHeader>>;
+    // Transactions of the blocks whose transaction root is `hash`
+    // Blocks read their bodies encoded, only tests read one apart so far
+    #[allow(dead_code)]
+    async fn get_body(&self, hash: H256) -> Result<Option<Vec<SignedTransaction>>>;
+    async fn get_channel(&self, channel_id: U256) -> Result<Channel>;
+    // Up to `limit` channels matching `filter` from the `cursor` channel id on
+    async fn get_channels(
+        &self,
+        filter: ChannelFilter,
+        cursor: Option<U256>,
+        limit: usize,
+    ) -> Result<ChannelPage>;
+    async fn get_transaction(&self, tx_hash: H256) -> Result<Option<SignedTransaction>>;
+}
+
+/// Chain data lives in named trees next to the channel SMT, which stays in
+/// the default tree of `store`. Headers and bodies are stored apart, bodies
+/// under their transaction root, so walking headers never decodes
+/// transactions.
+#[derive(Clone)]
+pub struct ChannelChain {
+    store: AsyncStore,
+    blocks: AsyncStore,
+    headers: AsyncStore,
+    bodies: AsyncStore,
+    number_hash: AsyncStore,
+    txs: AsyncStore,
+    indexes: ChannelIndexes,
+}
+
+impl ChannelChain {
+    pub fn new(store: Store) -> Result<Self, StoreError> {
+        let chain = ChannelChain {
+            blocks: AsyncStore::new(store.open_tree(BLOCK_TREE)?),
+            headers: AsyncStore::new(store.open_tree(HEADER_TREE)?),
+            bodies: AsyncStore::new(store.open_tree(BODY_TREE)?),
+            number_hash: AsyncStore::new(store.open_tree(NUMBER_HASH_TREE)?),
+            txs: AsyncStore::new(store.open_tree(TX_TREE)?),
+            indexes: ChannelIndexes::open(&store)?,
+            store: AsyncStore::new(store),
+        };
+
+        Ok(chain)
+    }
+
+    /// Drop the transaction index entries of block `number`, the block
+    /// itself is kept. Returns the hashes of the dropped transactions.
+    pub async fn prune_transactions(&self, number: u64) -> Result<Vec<H256>> {
+        let block = match self.get_block(NumberHash::Number(number)).await? {
+            Some(block) => block,
+            None => return Ok(Vec::new()),
+        };
+
+        let hashes = block.txs.iter().map(|tx| tx.hash).collect::<Vec<_>>();
+        for hash in hashes.iter() {
+            self.txs.remove(hash).await?;
+        }
+
+        Ok(hashes)
+    }
+
+    async fn hash_of(&self, number_hash: NumberHash) -> Result<Option<H256>> {
+        match number_hash {
+            NumberHash::Hash(hash) => Ok(Some(hash)),
+            NumberHash::Number(number) => Ok(self.number_hash.get(&number).await?),
+        }
+    }
+}
+
+#[async_trait]
+impl Chain for ChannelChain {
+    async fn tip_block(&self) -> Result<Option<Arc<Block>>> {
+        match self.blocks.get::<_, H256>(&TIP_BLOCK_KEY).await? {
+            Some(hash) => self.get_block(NumberHash::Hash(hash)).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn save_block(&self, block: Arc<Block>) -> Result<()> {
+        let body_hash = cbmt_merkle_root(&block.txs.iter().map(|tx| tx.hash).collect::<Vec<_>>());
+        if body_hash != block.header.transaction_root {
+            return Err(anyhow!(
+                "block {} transaction root doesn't match its transactions",
+                block.header.number
+            ));
+        }
+
+        let (blocks, headers, bodies, number_hash, txs) = (
+            self.blocks.inner().clone(),
+            self.headers.inner().clone(),
+            self.bodies.inner().clone(),
+            self.number_hash.inner().clone(),
+            self.txs.inner().clone(),
+        );
+
+        self.store
+            .run(move |_| -> Result<(), StoreError> {
+                let hash = block.header.hash;
+
+                for tx in block.txs.iter() {
+                    txs.insert(tx.hash, tx)?;
+                }
+                bodies.insert(body_hash, &block.txs)?;
+                headers.insert(hash, &block.header)?;
+                number_hash.insert(block.header.number, hash)?;
+                blocks.insert(TIP_BLOCK_KEY, hash)?;
+
+                Ok(())
+            })
+            .await??;
+
+        Ok(())
+    }
+
+    async fn get_block(&self, number_hash: NumberHash) -> Result<Option<Arc<Block>>> {
+        match self.get_encoded_block(number_hash).await? {
+            Some(block) => Ok(Some(Arc::new(block.decode()?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_encoded_block(&self, number_hash: NumberHash) -> Result<Option<EncodedBlock>> {
+        let hash = match self.hash_of(number_hash).await? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+
+        let header = match self.headers.get::<_, BlockHeader>(&hash).await? {
+            Some(header) => header,
+            None => {
+                let legacy = self.blocks.get::<_, Block>(&hash).await?;
+                return Ok(legacy.map(EncodedBlock::try_from).transpose()?);
+            }
+        };
+        let body = { self.bodies.get_bytes(&header.transaction_root).await? }
+            .ok_or_else(|| anyhow!("body of block {} not found", header.number))?;
+
+        Ok(Some(EncodedBlock { header, body }))
+    }
+
+    async fn tip_header(&self) -> Result<Option<BlockHeader>> {
+        match self.blocks.get::<_, H256>(&TIP_BLOCK_KEY).await? {
+            Some(hash) => self.get_header(NumberHash::Hash(hash)).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn get_header(&self, number_hash: NumberHash) -> Result<Option<BlockHeader>> {
+        let hash = match self.hash_of(number_hash).await? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+
+        if let Some(header) = self.headers.get(&hash).await? {
+            return Ok(Some(header));
+        }
+        let legacy = self.blocks.get::<_, Block>(&hash).await?;
+        Ok(legacy.map(|block| block.header))
+    }
+
+    async fn get_body(&self, hash: H256) -> Result<Option<Vec<SignedTransaction>>> {
+        Ok(self.bodies.get(&hash).await?)
+    }
+
+    async fn get_channel(&self, channel_id: U256) -> Result<Channel> {
+        let channel = self
+            .store
+            .run(move |store| SMT::new_with_store(store.clone())?.get(&channel_id.to_h256()))
+            .await??;
+
+        Ok(channel)
+    }
+
+    async fn get_channels(
+        &self,
+        filter: ChannelFilter,
+        cursor: Option<U256>,
+        limit: usize,
+    ) -> Result<ChannelPage> {
+        let indexes = self.indexes.clone();
+        self.store
+            .run(move |store| indexes.query(store, &filter, cursor, limit))
+            .await?
+    }
+
+    async fn get_transaction(&self, tx_hash: H256) -> Result<Option<SignedTransaction>> {
+        Ok(self.txs.get(&tx_hash).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::{H160, U128};
+    use tempfile::tempdir;
+
+    use crate::types::{BlockHeader, CloseChannel, RawTransaction};
+
+    use super::*;
+
+    fn block(number: u64, txs: Vec<SignedTransaction>) -> Block {
+        let header = BlockHeader {
+            number,
+            hash: H256::repeat_byte(number as u8),
+            transaction_root: cbmt_merkle_root(&txs.iter().map(|tx| tx.hash).collect::<Vec<_>>()),
+            ..Default::default()
+        };
+        Block { header, txs }
+    }
+
+    #[tokio::test]
+    async fn test_split_header_and_body() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let chain = ChannelChain::new(store).unwrap();
+
+        let tx = SignedTransaction {
+            raw: RawTransaction::CloseChannel(CloseChannel::default()),
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::zero(),
+            hash: H256::repeat_byte(9),
+        };
+        let with_tx = Arc::new(block(1, vec![tx]));
+        chain.save_block(Arc::clone(&with_tx)).await.unwrap();
+        // Blocks saved before the split are still served
+        let legacy = block(2, vec![]);
+        chain
+            .blocks
+            .insert(legacy.header.hash, &legacy)
+            .await
+            .unwrap();
+        chain
+            .number_hash
+            .insert(2u64, legacy.header.hash)
+            .await
+            .unwrap();
+
+        let header = chain.get_header(NumberHash::Number(1)).await.unwrap();
+        assert_eq!(header.unwrap().hash, with_tx.header.hash);
+        let body = chain
+            .get_body(with_tx.header.transaction_root)
+            .await
+            .unwrap();
+        assert_eq!(body.unwrap()[0].hash, H256::repeat_byte(9));
+        let saved = chain.get_block(NumberHash::Number(1)).await.unwrap();
+        assert_eq!(saved.unwrap().txs.len(), 1);
+        // Encoded blocks read the same as whole ones
+        let encoded = chain.get_encoded_block(NumberHash::Number(1)).await;
+        let encoded = encoded.unwrap().unwrap();
+        assert_eq!(encoded.txs().unwrap()[0].hash, H256::repeat_byte(9));
+        let whole = bincode::serialize(&*with_tx).unwrap();
+        assert_eq!(encoded.to_bincode().unwrap(), whole);
+
+        let header = chain.get_header(NumberHash::Number(2)).await.unwrap();
+        assert_eq!(header.unwrap().hash, legacy.header.hash);
+        let encoded = chain.get_encoded_block(NumberHash::Number(2)).await;
+        let encoded = encoded.unwrap().unwrap();
+        let whole = bincode::serialize(&legacy).unwrap();
+        assert_eq!(encoded.to_bincode().unwrap(), whole);
 
-pub trait Chain {
-    fn tip_block(&self) -> Result<NumberHash>;
-    fn save_block(&self, block: Block) -> Result<()>;
-    fn get_block(&self, number_hash: NumberHash) -> Result<Block>;
-    fn get_channel(&self, channel_id: U256) -> Result<Channel>;
-    fn get_transaction(&self, tx_hash: H256) -> Result<SignedTransaction>;
+        let mut forged = block(3, vec![]);
+        forged.header.transaction_root = H256::repeat_byte(1);
+        assert!(chain.save_block(Arc::new(forged)).await.is_err());
+    }
 }
@@ -1,7 +1,31 @@
+use bincode::Options;
 use blake2b_ref::Blake2bBuilder;
-use merkle_cbt::{merkle_tree::Merge, MerkleTree, CBMT};
-use primitive_types::{H256, U256};
-use serde::Serialize;
+use primitive_types::{H160, H256, U256};
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    Message, PublicKey, Secp256k1, SecretKey,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha3::{Digest, Keccak256};
+use share::limits::{DecodeError, DecodeLimit};
+
+use crate::types::Signature;
+
+/// Decode bincode from outside the node within `limit`. The layout is
+/// fixed by the type, so bounding the size is enough: a length prefix can't
+/// make bincode read or allocate more than the input holds. Unlike
+/// `bincode::deserialize`, trailing bytes are rejected.
+pub fn decode_bincode<T: DeserializeOwned>(
+    raw: &[u8],
+    limit: &DecodeLimit,
+) -> Result<T, DecodeError> {
+    limit.check_size(raw.len())?;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(limit.max_size as u64)
+        .deserialize(raw)
+        .map_err(|e| limit.malformed(e))
+}
 
 pub fn blake2b(msg: &[u8]) -> H256 {
     let mut buf = [0u8; 32];
@@ -11,6 +35,41 @@ pub fn blake2b(msg: &[u8]) -> H256 {
     buf.into()
 }
 
+/// Address of a secp256k1 key, the last 20 bytes of the keccak256 hash of
+/// the uncompressed public key.
+pub fn secp256k1_address(pubkey: &PublicKey) -> H160 {
+    let mut hasher = Keccak256::new();
+    hasher.update(&pubkey.serialize_uncompressed()[1..]);
+    H160::from_slice(&hasher.finalize()[12..])
+}
+
+/// 65 bytes recoverable signature over `msg`, the layout participants sign
+/// channel updates with.
+pub fn sign_recoverable(key: &SecretKey, msg: H256) -> Signature {
+    let msg = Message::from_slice(&msg.0).expect("32 bytes message");
+    let (rec_id, sig) = Secp256k1::new()
+        .sign_ecdsa_recoverable(&msg, key)
+        .serialize_compact();
+
+    let mut buf = sig.to_vec();
+    buf.push(rec_id.to_i32() as u8);
+    buf
+}
+
+/// Address that produced a `sign_recoverable` signature, `None` if the
+/// signature is malformed.
+pub fn recover_address(msg: H256, sig: &[u8]) -> Option<H160> {
+    if sig.len() != 65 {
+        return None;
+    }
+    let rec_id = RecoveryId::from_i32(sig[64].into()).ok()?;
+    let sig = RecoverableSignature::from_compact(&sig[..64], rec_id).ok()?;
+    let msg = Message::from_slice(&msg.0).ok()?;
+
+    let pubkey = Secp256k1::new().recover_ecdsa(&msg, &sig).ok()?;
+    Some(secp256k1_address(&pubkey))
+}
+
 pub trait H256Ext<H> {
     fn to_h256(&self) -> H;
 }
@@ -41,29 +100,17 @@ impl H256Ext<sparse_merkle_tree::H256> for H256 {
     }
 }
 
-pub fn cbmt_merkle_root<V: Serialize>(leaves: &Vec<V>) -> H256 {
-    let leaf_hashes = leaves.iter().map(|v| {
-        let encoded = bincode::serialize(v).unwrap();
-        blake2b(&encoded)
-    });
-
-    let tree: MerkleTree<_, MergeH256> = CBMT::build_merkle_tree(&leaf_hashes.collect::<Vec<_>>());
-    tree.root()
+pub fn cbmt_merkle_root<V: Serialize>(leaves: &[V]) -> H256 {
+    let leaf_hashes = leaves.iter().map(cbmt_leaf_hash).collect::<Vec<_>>();
+    cbmt_merkle_root_of_hashes(&leaf_hashes)
 }
 
-struct MergeH256;
-
-impl Merge for MergeH256 {
-    type Item = H256;
-
-    fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
-        let mut buf = [0u8; 32];
-        let mut blake2b = Blake2bBuilder::new(32).personal(b"zk pika! pi~~~").build();
-
-        blake2b.update(left.0.as_slice());
-        blake2b.update(right.0.as_slice());
-        blake2b.finalize(&mut buf);
+/// Leaf hash used by `cbmt_merkle_root`, for callers that keep only hashes.
+pub fn cbmt_leaf_hash<V: Serialize>(leaf: &V) -> H256 {
+    let encoded = bincode::serialize(leaf).unwrap();
+    blake2b(&encoded)
+}
 
-        buf.into()
-    }
+pub fn cbmt_merkle_root_of_hashes(leaf_hashes: &[H256]) -> H256 {
+    proof::cbmt::merkle_root(leaf_hashes)
 }
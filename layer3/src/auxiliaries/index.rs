@@ -0,0 +1,228 @@
+use anyhow::Result;
+use primitive_types::{H160, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::H256Ext,
+        smt::SMT,
+        snapshot::{channel_index_key, CHANNEL_INDEX_TREE},
+        store::{Store, StoreError},
+    },
+    types::{Channel, ChannelState},
+};
+
+const CHANNEL_BY_TOKEN_TREE: &str = "channel_by_token";
+const CHANNEL_BY_STATE_TREE: &str = "channel_by_state";
+const CHANNEL_BY_PARTICIPANT_TREE: &str = "channel_by_participant";
+pub const MAX_CHANNEL_PAGE: usize = 1024;
+
+/// Channels matching every field that is set.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChannelFilter {
+    pub token_id: Option<U256>,
+    pub state: Option<ChannelState>,
+    pub participant: Option<H160>,
+}
+
+impl ChannelFilter {
+    pub fn matches(&self, channel: &Channel) -> bool {
+        self.token_id.is_none_or(|id| channel.token.id == id)
+            && self
+                .state
+                .as_ref()
+                .is_none_or(|state| channel.state == *state)
+            && { self.participant }.is_none_or(|address| channel.participant2.contains(&address))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ChannelPage {
+    pub channels: Vec<Channel>,
+    // Cursor of the following page, none on the last one
+    pub next: Option<U256>,
+}
+
+/// Channel ids by token, state and participant, next to the index of all
+/// channels. Entries are keyed by the indexed value and the channel id, so
+/// a page picks up at a channel id. Updated as blocks are applied.
+#[derive(Clone)]
+pub struct ChannelIndexes {
+    all: Store,
+    by_token: Store,
+    by_state: Store,
+    by_participant: Store,
+}
+
+impl ChannelIndexes {
+    pub fn open(store: &Store) -> Result<Self, StoreError> {
+        Ok(ChannelIndexes {
+            all: store.open_tree(CHANNEL_INDEX_TREE)?,
+            by_token: store.open_tree(CHANNEL_BY_TOKEN_TREE)?,
+            by_state: store.open_tree(CHANNEL_BY_STATE_TREE)?,
+            by_participant: store.open_tree(CHANNEL_BY_PARTICIPANT_TREE)?,
+        })
+    }
+
+    /// Index `channel` in place of `prev`, the state it replaces. Token and
+    /// participants never change once a channel exists.
+    pub fn update(&self, prev: &Channel, channel: &Channel) -> Result<(), StoreError> {
+        let key = channel_index_key(&channel.id);
+        if prev.exists() && prev.state != channel.state {
+            self.by_state.remove((&prev.state, key))?;
+        }
+
+        self.all.insert(key, channel.id)?;
+        self.by_state.insert((&channel.state, key), channel.id)?;
+        self.by_token
+            .insert((channel_index_key(&channel.token.id), key), channel.id)?;
+        for participant in channel.participant2 {
+            self.by_participant
+                .insert((participant.0, key), channel.id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Up to `limit` channels matching `filter` from `cursor` on, by channel
+    /// id. `store` holds the channel SMT. The most selective index set in
+    /// the filter is walked, the other fields are checked on the channels.
+    pub fn query(
+        &self,
+        store: &Store,
+        filter: &ChannelFilter,
+        cursor: Option<U256>,
+        limit: usize,
+    ) -> Result<ChannelPage> {
+        let limit = limit.clamp(1, MAX_CHANNEL_PAGE);
+        let smt = SMT::new_with_store(store.clone())?;
+
+        let mut channels = Vec::new();
+        let mut from = cursor.unwrap_or_default();
+        loop {
+            let from_key = channel_index_key(&from);
+            let ids: Vec<U256> = if let Some(address) = filter.participant {
+                self.by_participant
+                    .values_with_prefix(&address.0, &from_key, limit)?
+            } else if let Some(id) = filter.token_id {
+                self.by_token
+                    .values_with_prefix(&channel_index_key(&id), &from_key, limit)?
+            } else if let Some(state) = &filter.state {
+                self.by_state.values_with_prefix(state, &from_key, limit)?
+            } else {
+                self.all.values_from(&from_key, limit)?
+            };
+
+            let scanned = ids.len();
+            for id in ids {
+                let channel = smt.get(&id.to_h256())?;
+                if filter.matches(&channel) {
+                    channels.push(channel);
+                }
+                from = id;
+            }
+            if channels.len() > limit || scanned < limit {
+                break;
+            }
+            match from.checked_add(U256::one()) {
+                Some(next) => from = next,
+                None => break,
+            }
+        }
+
+        channels.truncate(limit + 1);
+        let next = if channels.len() > limit {
+            channels.pop().map(|channel| channel.id)
+        } else {
+            None
+        };
+
+        Ok(ChannelPage { channels, next })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::types::Token;
+
+    use super::*;
+
+    fn channel(id: u64, participant: u8) -> Channel {
+        Channel {
+            id: id.into(),
+            token: Token {
+                id: (id % 2).into(),
+                ..Default::default()
+            },
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(participant)],
+            state: ChannelState::Open,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_query_channels() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let indexes = ChannelIndexes::open(&store).unwrap();
+        let mut smt = SMT::new_with_store(store.clone()).unwrap();
+        let mut apply = |channel: Channel| {
+            let prev = smt.get(&channel.id.to_h256()).unwrap();
+            indexes.update(&prev, &channel).unwrap();
+            smt.update(channel.id.to_h256(), channel).unwrap();
+        };
+        for id in 1..=5 {
+            apply(channel(id, if id == 3 { 3 } else { 2 }));
+        }
+        apply(Channel {
+            state: ChannelState::Closed,
+            ..channel(2, 2)
+        });
+
+        let query = |filter: ChannelFilter, cursor: Option<u64>, limit| {
+            let page = indexes
+                .query(&store, &filter, cursor.map(U256::from), limit)
+                .unwrap();
+            let ids = { page.channels.iter() }
+                .map(|channel| channel.id.as_u64())
+                .collect::<Vec<_>>();
+            (ids, page.next.map(|next| next.as_u64()))
+        };
+        let by_token = ChannelFilter {
+            token_id: Some(U256::one()),
+            ..Default::default()
+        };
+        assert_eq!(query(by_token.clone(), None, 2), (vec![1, 3], Some(5)));
+        assert_eq!(query(by_token, Some(5), 2), (vec![5], None));
+
+        // Channel 2 left the open state
+        let by_state = |state| ChannelFilter {
+            state: Some(state),
+            ..Default::default()
+        };
+        assert_eq!(
+            query(by_state(ChannelState::Open), None, 10).0,
+            vec![1, 3, 4, 5]
+        );
+        assert_eq!(query(by_state(ChannelState::Closed), None, 10).0, vec![2]);
+
+        let participant = ChannelFilter {
+            participant: Some(H160::repeat_byte(3)),
+            ..Default::default()
+        };
+        assert_eq!(query(participant, None, 10), (vec![3], None));
+        let combined = ChannelFilter {
+            token_id: Some(U256::zero()),
+            participant: Some(H160::repeat_byte(2)),
+            state: Some(ChannelState::Open),
+        };
+        assert_eq!(query(combined, None, 1), (vec![4], None));
+        assert_eq!(
+            query(ChannelFilter::default(), Some(2), 3),
+            (vec![2, 3, 4], Some(5))
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hyper::{Body, Client, Request};
+use primitive_types::H256;
+use proof::commitment::Commitment;
+use serde_json::{json, Value};
+
+use crate::{
+    auxiliaries::relay::Layer2Target, checkpoint::CheckpointTarget, tracking::OutPoint,
+    withdrawal::WithdrawalBatch,
+};
+
+/// JSON-RPC client of the layer2 operator RPC, which keeps the checkpoints
+/// of the operator and pays its withdrawals.
+pub struct Layer2Client {
+    client: Client<hyper::client::HttpConnector>,
+    uri: String,
+}
+
+impl Layer2Client {
+    pub fn new(uri: String) -> Self {
+        Layer2Client {
+            client: Client::new(),
+            uri,
+        }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let req = Request::post(&self.uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))?;
+
+        let resp = self.client.request(req).await?;
+        let mut resp: Value = serde_json::from_slice(&hyper::body::to_bytes(resp).await?)?;
+        match resp.get_mut("result") {
+            Some(result) if !result.is_null() => Ok(result.take()),
+            _ => Err(anyhow!("{} failed {}: {}", self.uri, method, resp)),
+        }
+    }
+}
+
+#[async_trait]
+impl CheckpointTarget for Layer2Client {
+    async fn submit_commitment(&self, commitment: &Commitment) -> Result<H256> {
+        let encoded = format!("0x{}", hex::encode(commitment.to_bytes()));
+        let hash = self
+            .call("admin_submit_commitment", json!([encoded]))
+            .await?;
+        serde_json::from_value(hash.clone())
+            .map_err(|_| anyhow!("{} returned no hash: {}", self.uri, hash))
+    }
+}
+
+#[async_trait]
+impl Layer2Target for Layer2Client {
+    // Layer2 takes no deposits yet, they stay detected
+    async fn relay_deposit(&self, _out_point: OutPoint) -> Result<Option<H256>> {
+        Ok(None)
+    }
+
+    async fn relay_withdrawals(&self, batch: &WithdrawalBatch) -> Result<H256> {
+        let hash = self.call("admin_pay_withdrawals", json!([batch])).await?;
+        serde_json::from_value(hash.clone())
+            .map_err(|_| anyhow!("{} returned no hash: {}", self.uri, hash))
+    }
+}
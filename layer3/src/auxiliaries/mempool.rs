@@ -1,80 +1,909 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
-use primitive_types::H160;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use primitive_types::{H160, H256, U128, U256};
+use serde::{Deserialize, Serialize};
+use share::idempotency::IdempotencyKeys;
 
-use crate::types::{Block, SignedTransaction};
+use crate::{
+    auxiliaries::{common::H256Ext, smt::SMT, store::Store},
+    types::{Block, DustLimits, RawTransaction, SignedTransaction},
+};
+
+const SEEN_CACHE_SIZE: usize = 100_000;
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub trait MemPool {
     fn push_transaction(&self, tx: SignedTransaction) -> Result<()>;
     fn package_transactions(&self) -> Result<Vec<SignedTransaction>>;
+    /// The exact ordered transaction list `package_transactions` would
+    /// return, without side effects.
+    fn build_block_template(&self) -> Result<BlockTemplate>;
     fn reset(&self, block: &Block) -> Result<()>;
 }
 
+/// Result of `push_transaction_with_key`. A repeated key reports the
+/// transaction first pushed with it, which isn't pushed again.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Submitted {
+    pub tx_hash: H256,
+    // False once packaged or dropped
+    pub pending: bool,
+}
+
+/// Transactions a block would be built from, in block order.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockTemplate {
+    pub txs: Vec<SignedTransaction>,
+    // Senders with pending txs that got none packaged
+    pub left_out: Vec<H160>,
+}
+
+/// A transaction dropped from the pool before it got packaged.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExpiredTransaction {
+    pub tx_hash: H256,
+    pub from: H160,
+    pub channel_id: U256,
+    pub version: u64,
+    pub hint: ResubmissionHint,
+}
+
+/// What a resubmission needs to get packaged. The expired hash stays known
+/// to the pool, so the transaction has to be signed again.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ResubmissionHint {
+    // Lowest fee packaged in the last full block, never below the admission
+    // minimum
+    pub min_fee: U128,
+    // Version the channel is at on chain, set by consensus
+    pub channel_version: Option<u64>,
+}
+
+#[derive(Clone)]
+struct PendingTx {
+    // Arrival order, breaks ties between equally paying offers
+    seq: u64,
+    tx: SignedTransaction,
+    arrived: Instant,
+}
+
+/// Pending transactions queued per sender and channel. Each queue lives in
+/// its own dashmap shard entry, so writers on unrelated channels don't
+/// contend on a single lock.
 #[derive(Clone)]
 pub struct ChannelMap {
-    map: Arc<RwLock<HashMap<H160, Vec<SignedTransaction>>>>,
+    map: Arc<DashMap<QueueKey, VecDeque<PendingTx>>>,
+    next_seq: Arc<AtomicU64>,
+    chain_id: u64,
+    policy: PackagePolicy,
+    // Consecutive blocks a sender with pending txs got nothing packaged
+    starved: Arc<DashMap<H160, u32>>,
+    seen: Arc<RecentHashes>,
+    idempotency: Arc<IdempotencyKeys<H256>>,
+    admission: AdmissionPolicy,
+    // Lowest fee of the last package that filled the block, zero after one
+    // that didn't
+    fee_floor: Arc<Mutex<U128>>,
+    dust: Option<Arc<DustCheck>>,
+    capacity: SettlementCapacity,
+}
+
+// Dust limits per token, and the channel state updates are checked against
+struct DustCheck {
+    limits: HashMap<U256, DustLimits>,
+    state: Store,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct PackagePolicy {
+    pub block_limit: usize,
+    // Max transactions packaged per sender in one block
+    pub sender_quota: usize,
+    // Encoded bytes of the transactions of one block, 0 means no limit
+    // besides what the settlement target accepts
+    pub max_block_bytes: usize,
+    // How often the node packages a block while transactions are pending
+    pub interval_ms: u64,
+}
+
+impl Default for PackagePolicy {
+    fn default() -> Self {
+        PackagePolicy {
+            block_limit: 200,
+            sender_quota: 16,
+            max_block_bytes: 0,
+            interval_ms: 1_000,
+        }
+    }
+}
+
+/// Transaction bytes of a block the settlement target accepts in one
+/// commitment, kept up to date by polling the target. Zero until it's
+/// known, which leaves packaging to the package policy.
+#[derive(Debug, Clone, Default)]
+pub struct SettlementCapacity(Arc<AtomicU64>);
+
+impl SettlementCapacity {
+    pub fn set(&self, bytes: u64) {
+        self.0.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits a transaction has to meet to enter the pool, so dust can't
+/// cheaply fill blocks. Signatures are byte vectors of any length, the
+/// size limit keeps them from bloating a transaction.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct AdmissionPolicy {
+    pub min_fee: u64,
+    // Encoded size of the signed transaction
+    pub max_tx_bytes: usize,
+    // Seconds a transaction waits to be packaged before it's evicted, 0
+    // means forever
+    pub ttl_secs: u64,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        AdmissionPolicy {
+            min_fee: 0,
+            max_tx_bytes: 1024,
+            ttl_secs: 0,
+        }
+    }
+}
+
+struct SenderQueues {
+    from: H160,
+    starved: u32,
+    queues: Vec<VecDeque<PendingTx>>,
+    taken: usize,
+    // Its best offer didn't fit in the block bytes left
+    blocked: bool,
+}
+
+impl SenderQueues {
+    // Queue whose front tx pays the most, ties go to the earliest arrival
+    fn best_queue(&self) -> Option<(usize, U128, u64)> {
+        { self.queues.iter().enumerate() }
+            .filter_map(|(idx, queue)| queue.front().map(|p| (idx, p.tx.fee, p.seq)))
+            .min_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct QueueKey {
+    from: H160,
+    channel_id: U256,
+}
+
+impl QueueKey {
+    fn of(tx: &SignedTransaction) -> Self {
+        QueueKey {
+            from: tx.from,
+            channel_id: tx.raw.channel_id(),
+        }
+    }
+}
+
+impl ChannelMap {
+    // The node always packages by the configured policy
+    #[allow(dead_code)]
+    pub fn new(chain_id: u64) -> Self {
+        Self::with_policy(chain_id, PackagePolicy::default())
+    }
+
+    pub fn with_policy(chain_id: u64, policy: PackagePolicy) -> Self {
+        ChannelMap {
+            map: Default::default(),
+            next_seq: Default::default(),
+            chain_id,
+            policy,
+            starved: Default::default(),
+            seen: Arc::new(RecentHashes::new(SEEN_CACHE_SIZE)),
+            idempotency: Arc::new(IdempotencyKeys::new(IDEMPOTENCY_KEY_TTL, SEEN_CACHE_SIZE)),
+            admission: AdmissionPolicy::default(),
+            fee_floor: Default::default(),
+            dust: None,
+            capacity: SettlementCapacity::default(),
+        }
+    }
+
+    pub fn with_admission(mut self, admission: AdmissionPolicy) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Refuse channels and updates below the dust limits of their token.
+    /// Updates are checked against the channel in `state`, not against
+    /// updates still queued before them, the executor has the last word.
+    pub fn with_dust_limits(mut self, limits: HashMap<U256, DustLimits>, state: Store) -> Self {
+        self.dust = Some(Arc::new(DustCheck { limits, state }));
+        self
+    }
+
+    /// Cap the bytes of a block by what the settlement target accepts, on
+    /// top of `max_block_bytes`.
+    // Kept up to date by the settlement follower, see settlement
+    #[allow(dead_code)]
+    pub fn with_settlement_capacity(mut self, capacity: SettlementCapacity) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn policy(&self) -> PackagePolicy {
+        self.policy
+    }
+
+    // Transaction bytes the next block can take, the tighter of the package
+    // policy and the settlement target
+    fn byte_limit(&self) -> Option<usize> {
+        let settlement = self.capacity.get() as usize;
+        [self.policy.max_block_bytes, settlement]
+            .into_iter()
+            .filter(|limit| *limit > 0)
+            .min()
+    }
+
+    /// Pending transactions of `from`, grouped by channel in queue order.
+    pub fn pending_of(&self, from: H160) -> BTreeMap<U256, Vec<SignedTransaction>> {
+        { self.map.iter() }
+            .filter(|queue| queue.key().from == from)
+            .map(|queue| {
+                let txs = queue.iter().map(|p| p.tx.clone()).collect();
+                (queue.key().channel_id, txs)
+            })
+            .collect()
+    }
+
+    pub fn is_pending(&self, hash: &H256) -> bool {
+        { self.map.iter() }.any(|queue| queue.iter().any(|pending| pending.tx.hash == *hash))
+    }
+
+    /// `push_transaction` guarded by a client supplied idempotency key, so a
+    /// retry after a timeout can't submit a second transaction.
+    pub fn push_transaction_with_key(&self, tx: SignedTransaction, key: &str) -> Result<Submitted> {
+        let tx_hash = tx.hash;
+        if let Some(original) = self.idempotency.bind(key, tx_hash)? {
+            return Ok(Submitted {
+                tx_hash: original,
+                pending: self.is_pending(&original),
+            });
+        }
+
+        if let Err(e) = self.push_transaction(tx) {
+            self.idempotency.release(key, tx_hash);
+            return Err(e);
+        }
+        Ok(Submitted {
+            tx_hash,
+            pending: true,
+        })
+    }
+
+    /// Drop the transactions that waited longer than `ttl_secs`. Later
+    /// transactions queued on the same channel stay.
+    pub fn evict_expired(&self) -> Vec<ExpiredTransaction> {
+        self.evict_arrived_before(Instant::now())
+    }
+
+    fn check_dust(&self, raw: &RawTransaction) -> Result<()> {
+        let dust = match &self.dust {
+            Some(dust) => dust,
+            None => return Ok(()),
+        };
+        let limits = |token_id| dust.limits.get(token_id).copied().unwrap_or_default();
+
+        match raw {
+            RawTransaction::CreateChannel(args) => {
+                let limits = limits(&args.token.id);
+                if !limits.admits_deposit(&args.balance2) {
+                    return Err(anyhow!(
+                        "channel deposit below the minimum of {}",
+                        limits.min_deposit
+                    ));
+                }
+            }
+            RawTransaction::UpdateChannel(args) => {
+                let channel =
+                    SMT::new_with_store(dust.state.clone())?.get(&args.channel_id.to_h256())?;
+                let limits = limits(&channel.token.id);
+                if channel.exists() && !limits.admits_update(&channel.balance2, &args.balance2) {
+                    return Err(anyhow!(
+                        "channel update moves less than the minimum of {}",
+                        limits.min_update_delta
+                    ));
+                }
+            }
+            RawTransaction::CloseChannel(_) => (),
+        }
+
+        Ok(())
+    }
+
+    fn evict_arrived_before(&self, now: Instant) -> Vec<ExpiredTransaction> {
+        if self.admission.ttl_secs == 0 {
+            return Vec::new();
+        }
+        let ttl = Duration::from_secs(self.admission.ttl_secs);
+        let hint = ResubmissionHint {
+            min_fee: U128::from(self.admission.min_fee).max(*self.fee_floor.lock().unwrap()),
+            channel_version: None,
+        };
+
+        let mut expired = Vec::new();
+        for mut queue in self.map.iter_mut() {
+            queue.retain(|pending| {
+                let keep = now.saturating_duration_since(pending.arrived) < ttl;
+                if !keep {
+                    expired.push(pending.clone());
+                }
+                keep
+            });
+        }
+        self.map.retain(|_, queue| !queue.is_empty());
+
+        expired.sort_by_key(|pending| pending.seq);
+        { expired.into_iter() }
+            .map(|pending| ExpiredTransaction {
+                tx_hash: pending.tx.hash,
+                from: pending.tx.from,
+                channel_id: pending.tx.raw.channel_id(),
+                version: pending.tx.raw.version(),
+                hint: hint.clone(),
+            })
+            .collect()
+    }
 }
 
 impl MemPool for ChannelMap {
     fn push_transaction(&self, tx: SignedTransaction) -> Result<()> {
-        let mut map = self.map.write().unwrap();
-        map.entry(tx.from)
-            .and_modify(|txs| txs.push(tx.clone()))
-            .or_insert(vec![tx]);
+        if tx.raw.chain_id() != self.chain_id {
+            return Err(anyhow!(
+                "transaction chain id {} mismatch, expect {}",
+                tx.raw.chain_id(),
+                self.chain_id
+            ));
+        }
+        if tx.fee < self.admission.min_fee.into() {
+            return Err(anyhow!(
+                "transaction fee {} below the minimum of {}",
+                tx.fee,
+                self.admission.min_fee
+            ));
+        }
+        let size = bincode::serialized_size(&tx)? as usize;
+        if size > self.admission.max_tx_bytes {
+            return Err(anyhow!(
+                "transaction of {} bytes exceeds the limit of {}",
+                size,
+                self.admission.max_tx_bytes
+            ));
+        }
+        self.check_dust(&tx.raw)?;
+
+        if !self.seen.insert(tx.hash) {
+            return Err(anyhow!("transaction {:?} already known", tx.hash));
+        }
+
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let pending = PendingTx {
+            seq,
+            tx,
+            arrived: Instant::now(),
+        };
+        { self.map.entry(QueueKey::of(&pending.tx)).or_default() }.push_back(pending);
         Ok(())
     }
 
     fn package_transactions(&self) -> Result<Vec<SignedTransaction>> {
-        let mut packaged = Vec::with_capacity(200);
+        let template = self.build_block_template()?;
 
-        let map = self.map.read().unwrap();
-        for txs in map.values() {
-            if packaged.len() >= packaged.capacity() {
-                return Ok(packaged);
-            }
-            if txs.is_empty() {
-                continue;
+        // Starvation only counts for senders still waiting
+        self.starved
+            .retain(|from, _| template.left_out.contains(from));
+        for from in template.left_out {
+            *self.starved.entry(from).or_default() += 1;
+        }
+        *self.fee_floor.lock().unwrap() = if template.txs.len() >= self.policy.block_limit {
+            template
+                .txs
+                .iter()
+                .map(|tx| tx.fee)
+                .min()
+                .unwrap_or_default()
+        } else {
+            U128::zero()
+        };
+
+        Ok(template.txs)
+    }
+
+    /// Weighted round robin over senders. Every round each sender under its
+    /// quota offers the best paying front tx among its channel queues, and
+    /// offers are taken starved senders first, then by fee, then by arrival.
+    /// Queue order is kept, so deep queues drain over consecutive blocks,
+    /// while a sender left out of a full block moves ahead in the next one.
+    ///
+    /// Once an offer doesn't fit in the block bytes left, its sender is
+    /// done for the block, and what it didn't get packaged carries over.
+    ///
+    /// The result only depends on the queued txs, their arrival sequence,
+    /// the starvation counters and the byte limit, never on map iteration
+    /// order.
+    fn build_block_template(&self) -> Result<BlockTemplate> {
+        let quota = self.policy.sender_quota;
+        let limit = self.policy.block_limit;
+        let byte_limit = self.byte_limit().unwrap_or(usize::MAX);
+        let mut bytes = 0;
+
+        let mut by_sender = BTreeMap::<H160, Vec<VecDeque<PendingTx>>>::new();
+        for queue in self.map.iter() {
+            let txs = queue.iter().take(quota).cloned().collect();
+            by_sender.entry(queue.key().from).or_default().push(txs);
+        }
+        let mut senders = { by_sender.into_iter() }
+            .map(|(from, queues)| SenderQueues {
+                from,
+                starved: self.starved.get(&from).map(|n| *n).unwrap_or_default(),
+                queues,
+                taken: 0,
+                blocked: false,
+            })
+            .collect::<Vec<_>>();
+
+        let mut packaged = Vec::with_capacity(limit);
+        while packaged.len() < limit {
+            let mut offers = { senders.iter().enumerate() }
+                .filter(|(_, sender)| sender.taken < quota && !sender.blocked)
+                .filter_map(|(idx, sender)| {
+                    let (queue_idx, fee, seq) = sender.best_queue()?;
+                    Some((sender.starved, fee, seq, idx, queue_idx))
+                })
+                .collect::<Vec<_>>();
+            if offers.is_empty() {
+                break;
             }
+            offers.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(a.2.cmp(&b.2)));
 
-            packaged.push(txs.first().cloned().unwrap());
+            for (_, _, _, idx, queue_idx) in offers {
+                if packaged.len() >= limit {
+                    break;
+                }
+                let sender = &mut senders[idx];
+                let queue = &mut sender.queues[queue_idx];
+                let size = match queue.front() {
+                    Some(pending) => bincode::serialized_size(&pending.tx)? as usize,
+                    None => continue,
+                };
+                if bytes + size > byte_limit {
+                    sender.blocked = true;
+                    continue;
+                }
+                bytes += size;
+                packaged.extend(queue.pop_front().map(|p| p.tx));
+                sender.taken += 1;
+            }
         }
 
-        Ok(packaged)
+        let left_out = { senders.iter() }
+            .filter(|sender| sender.taken == 0)
+            .map(|sender| sender.from)
+            .collect();
+        Ok(BlockTemplate {
+            txs: packaged,
+            left_out,
+        })
     }
 
     fn reset(&self, block: &Block) -> Result<()> {
-        let mut map = self.map.write().unwrap();
-
         for block_tx in &block.txs {
-            let txs = match map.get_mut(&block_tx.from) {
-                Some(txs) => txs,
-                None => continue,
-            };
-            if txs.is_empty() {
-                drop(txs);
-                map.remove(&block_tx.from);
-
-                continue;
+            let key = QueueKey::of(block_tx);
+
+            if let Some(mut txs) = self.map.get_mut(&key) {
+                if let Some(idx) = txs.iter().position(|p| p.tx.hash == block_tx.hash) {
+                    txs.drain(..=idx);
+                }
             }
+            self.map.remove_if(&key, |_, txs| txs.is_empty());
+        }
 
-            let idx = match txs.iter_mut().position(|tx| tx.hash == block_tx.hash) {
-                Some(idx) => idx,
-                None => continue,
-            };
-            if idx == txs.len() - 1 {
-                drop(txs);
-                map.remove(&block_tx.from);
+        Ok(())
+    }
+}
 
-                continue;
-            }
+/// Hashes of recently seen transactions. Kept in two generations, the older
+/// one is dropped as a whole once the newer one fills up.
+struct RecentHashes {
+    generations: Mutex<(HashSet<H256>, HashSet<H256>)>,
+    capacity: usize,
+}
 
-            *txs = txs.split_off(idx + 1);
+impl RecentHashes {
+    fn new(capacity: usize) -> Self {
+        RecentHashes {
+            generations: Mutex::new((HashSet::new(), HashSet::new())),
+            capacity,
         }
+    }
 
-        Ok(())
+    /// Returns false if the hash was already seen.
+    fn insert(&self, hash: H256) -> bool {
+        let mut generations = self.generations.lock().unwrap();
+        if generations.0.contains(&hash) || generations.1.contains(&hash) {
+            return false;
+        }
+
+        if generations.0.len() >= self.capacity {
+            let current = std::mem::take(&mut generations.0);
+            generations.1 = current;
+        }
+        generations.0.insert(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::H256;
+
+    use crate::{
+        auxiliaries::common::blake2b,
+        types::{
+            Balance, BlockHeader, Channel, ChannelState, CloseChannel, CreateChannel, Token,
+            UpdateChannel,
+        },
+    };
+
+    use super::*;
+
+    const CHAIN_ID: u64 = 1;
+
+    fn close_tx(from: u64, channel_id: u64, version: u64) -> SignedTransaction {
+        let raw = RawTransaction::CloseChannel(CloseChannel {
+            chain_id: CHAIN_ID,
+            channel_id: channel_id.into(),
+            version,
+            ..Default::default()
+        });
+
+        SignedTransaction {
+            raw,
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::repeat_byte(from as u8),
+            hash: blake2b(&bincode::serialize(&(from, channel_id, version)).unwrap()),
+        }
+    }
+
+    fn block_with(txs: Vec<SignedTransaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                number: 1,
+                hash: H256::zero(),
+                parent_hash: H256::zero(),
+                timestamp: 0.into(),
+                state_root: H256::zero(),
+                transaction_root: H256::zero(),
+                receipt_root: H256::zero(),
+            },
+            txs,
+        }
+    }
+
+    fn fee_tx(from: u64, channel_id: u64, version: u64, fee: u64) -> SignedTransaction {
+        SignedTransaction {
+            fee: fee.into(),
+            ..close_tx(from, channel_id, version)
+        }
+    }
+
+    #[test]
+    fn test_package_keeps_queue_order() {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        mempool.push_transaction(close_tx(1, 1, 1)).unwrap();
+        mempool.push_transaction(close_tx(1, 1, 2)).unwrap();
+        mempool.push_transaction(close_tx(1, 2, 1)).unwrap();
+        mempool.push_transaction(close_tx(2, 1, 1)).unwrap();
+
+        let hashes = { mempool.package_transactions().unwrap().iter() }
+            .map(|tx| tx.hash)
+            .collect::<Vec<_>>();
+        assert_eq!(hashes.len(), 4);
+
+        let pos = |tx: SignedTransaction| hashes.iter().position(|h| *h == tx.hash).unwrap();
+        assert!(pos(close_tx(1, 1, 1)) < pos(close_tx(1, 1, 2)));
+    }
+
+    #[test]
+    fn test_package_fairness_across_blocks() {
+        let policy = PackagePolicy {
+            block_limit: 4,
+            sender_quota: 2,
+            ..Default::default()
+        };
+        let mempool = ChannelMap::with_policy(CHAIN_ID, policy);
+        for version in 1..=4 {
+            mempool.push_transaction(fee_tx(1, 1, version, 10)).unwrap();
+        }
+        mempool.push_transaction(fee_tx(2, 1, 1, 5)).unwrap();
+        mempool.push_transaction(fee_tx(3, 1, 1, 1)).unwrap();
+
+        // Higher fees go first, but the heavy sender is capped by its quota
+        let packaged = mempool.package_transactions().unwrap();
+        let froms = packaged.iter().map(|tx| tx.from).collect::<Vec<_>>();
+        let expect = [1, 2, 3, 1].map(H160::repeat_byte).to_vec();
+        assert_eq!(froms, expect);
+
+        // Its deep queue drains in the following block
+        mempool.reset(&block_with(packaged)).unwrap();
+        let packaged = mempool.package_transactions().unwrap();
+        let hashes = packaged.iter().map(|tx| tx.hash).collect::<Vec<_>>();
+        assert_eq!(
+            hashes,
+            vec![fee_tx(1, 1, 3, 10).hash, fee_tx(1, 1, 4, 10).hash]
+        );
+
+        // A low fee sender left out of a full block goes first in the next
+        let mempool = ChannelMap::with_policy(CHAIN_ID, policy);
+        mempool.push_transaction(fee_tx(3, 1, 1, 1)).unwrap();
+        for from in 4..=7 {
+            mempool.push_transaction(fee_tx(from, 1, 1, 10)).unwrap();
+        }
+        let first = mempool.package_transactions().unwrap();
+        assert!(first.iter().all(|tx| tx.from != H160::repeat_byte(3)));
+        let second = mempool.package_transactions().unwrap();
+        assert_eq!(second[0].from, H160::repeat_byte(3));
+    }
+
+    #[test]
+    fn test_reset_drains_committed_prefix() {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        mempool.push_transaction(close_tx(1, 1, 1)).unwrap();
+        mempool.push_transaction(close_tx(1, 1, 2)).unwrap();
+        mempool.push_transaction(close_tx(1, 2, 1)).unwrap();
+
+        let block = block_with(vec![close_tx(1, 1, 1), close_tx(1, 2, 1)]);
+        mempool.reset(&block).unwrap();
+
+        let packaged = mempool.package_transactions().unwrap();
+        assert_eq!(packaged.len(), 1);
+        assert_eq!(packaged[0].hash, close_tx(1, 1, 2).hash);
+    }
+
+    #[test]
+    fn test_block_template_is_deterministic() {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        // Equal fees, so arrival decides, across channels and senders
+        for (from, channel_id) in [(2, 9), (1, 5), (2, 3), (1, 1)] {
+            mempool
+                .push_transaction(close_tx(from, channel_id, 1))
+                .unwrap();
+        }
+
+        let template = mempool.build_block_template().unwrap();
+        let hashes = template.txs.iter().map(|tx| tx.hash).collect::<Vec<_>>();
+        let expected = { [(2, 9), (1, 5), (2, 3), (1, 1)].into_iter() }
+            .map(|(from, channel_id)| close_tx(from, channel_id, 1).hash)
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, expected);
+
+        let packaged = mempool.package_transactions().unwrap();
+        assert_eq!(
+            packaged.iter().map(|tx| tx.hash).collect::<Vec<_>>(),
+            hashes
+        );
+    }
+
+    #[test]
+    fn test_drop_duplicate_transaction() {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        mempool.push_transaction(close_tx(1, 1, 1)).unwrap();
+        assert!(mempool.push_transaction(close_tx(1, 1, 1)).is_err());
+
+        let seen = RecentHashes::new(1);
+        assert!(seen.insert(H256::repeat_byte(1)));
+        assert!(seen.insert(H256::repeat_byte(2)));
+        assert!(seen.insert(H256::repeat_byte(3)));
+        assert!(seen.insert(H256::repeat_byte(1)));
+        assert!(!seen.insert(H256::repeat_byte(3)));
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let first = mempool
+            .push_transaction_with_key(close_tx(1, 1, 1), "retry")
+            .unwrap();
+        // A re-signed retry under the same key isn't queued
+        let retried = mempool
+            .push_transaction_with_key(close_tx(1, 1, 2), "retry")
+            .unwrap();
+        assert_eq!(retried, first);
+        assert_eq!(mempool.pending_of(H160::repeat_byte(1))[&1.into()].len(), 1);
+
+        // A rejected transaction doesn't use up the key
+        let other_chain = SignedTransaction {
+            raw: RawTransaction::CloseChannel(CloseChannel::default()),
+            ..close_tx(2, 1, 1)
+        };
+        assert!(mempool
+            .push_transaction_with_key(other_chain, "other")
+            .is_err());
+        assert!(mempool
+            .push_transaction_with_key(close_tx(2, 1, 1), "other")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_evict_expired() {
+        let policy = PackagePolicy {
+            block_limit: 1,
+            sender_quota: 1,
+            ..Default::default()
+        };
+        let admission = AdmissionPolicy {
+            min_fee: 2,
+            ttl_secs: 60,
+            ..Default::default()
+        };
+        let mempool = ChannelMap::with_policy(CHAIN_ID, policy).with_admission(admission);
+        mempool.push_transaction(fee_tx(1, 1, 1, 5)).unwrap();
+        mempool.push_transaction(fee_tx(2, 1, 1, 3)).unwrap();
+        // Congested, the block only took the better paying one
+        let packaged = mempool.package_transactions().unwrap();
+        mempool.reset(&block_with(packaged)).unwrap();
+
+        let now = Instant::now();
+        assert!(mempool.evict_arrived_before(now).is_empty());
+        let expired = mempool.evict_arrived_before(now + Duration::from_secs(60));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].tx_hash, fee_tx(2, 1, 1, 3).hash);
+        assert_eq!(expired[0].hint.min_fee, 5.into());
+        assert!(mempool.package_transactions().unwrap().is_empty());
+
+        // Evicted, but still known
+        assert!(mempool.push_transaction(fee_tx(2, 1, 1, 3)).is_err());
+    }
+
+    #[test]
+    fn test_package_within_settlement_capacity() {
+        let tx_bytes = bincode::serialized_size(&close_tx(1, 1, 1)).unwrap();
+        let policy = PackagePolicy {
+            max_block_bytes: 3 * tx_bytes as usize,
+            ..Default::default()
+        };
+        let capacity = SettlementCapacity::default();
+        let mempool =
+            ChannelMap::with_policy(CHAIN_ID, policy).with_settlement_capacity(capacity.clone());
+        for version in 1..=4 {
+            mempool.push_transaction(close_tx(1, 1, version)).unwrap();
+        }
+        mempool.push_transaction(close_tx(2, 1, 1)).unwrap();
+
+        // The package policy caps the block until the target is known
+        let packaged = mempool.package_transactions().unwrap();
+        assert_eq!(packaged.len(), 3);
+        mempool.reset(&block_with(packaged)).unwrap();
+
+        // The rest carries over into blocks the target can take
+        capacity.set(tx_bytes);
+        let packaged = mempool.package_transactions().unwrap();
+        assert_eq!(packaged.len(), 1);
+        mempool.reset(&block_with(packaged)).unwrap();
+        capacity.set(tx_bytes - 1);
+        assert!(mempool.package_transactions().unwrap().is_empty());
+        capacity.set(0);
+        let packaged = mempool.package_transactions().unwrap();
+        assert_eq!(packaged.len(), 1);
+    }
+
+    #[test]
+    fn test_reject_other_chain_id() {
+        let mempool = ChannelMap::new(CHAIN_ID + 1);
+        assert!(mempool.push_transaction(close_tx(1, 1, 1)).is_err());
+    }
+
+    #[test]
+    fn test_admission_limits() {
+        let admission = AdmissionPolicy {
+            min_fee: 10,
+            max_tx_bytes: 512,
+            ..Default::default()
+        };
+        let mempool = ChannelMap::new(CHAIN_ID).with_admission(admission);
+        assert!(mempool.push_transaction(fee_tx(1, 1, 1, 9)).is_err());
+        mempool.push_transaction(fee_tx(1, 1, 1, 10)).unwrap();
+
+        let mut bloated = fee_tx(1, 1, 2, 10);
+        bloated.sig = vec![0; 512];
+        assert!(mempool.push_transaction(bloated).is_err());
+    }
+
+    #[test]
+    fn test_dust_limits() {
+        let tmp_db_path = tempfile::tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let token = Token {
+            id: 1.into(),
+            ..Default::default()
+        };
+        let balance2 = |a: u64, b: u64| {
+            [a, b].map(|settled| Balance {
+                settled: settled.into(),
+            })
+        };
+        let channel = Channel {
+            id: 7.into(),
+            token: token.clone(),
+            state: ChannelState::Open,
+            balance2: balance2(500, 500),
+            ..Default::default()
+        };
+        let mut smt = SMT::new_with_store(store.clone()).unwrap();
+        smt.update(channel.id.to_h256(), channel).unwrap();
+
+        let limits = DustLimits {
+            min_deposit: 1000.into(),
+            min_update_delta: 10.into(),
+        };
+        let mempool =
+            ChannelMap::new(CHAIN_ID).with_dust_limits(HashMap::from([(token.id, limits)]), store);
+        let tx = |raw: RawTransaction| SignedTransaction {
+            hash: blake2b(&bincode::serialize(&raw).unwrap()),
+            raw,
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::repeat_byte(1),
+        };
+        let create = |id: u64, balances| {
+            tx(RawTransaction::CreateChannel(CreateChannel {
+                chain_id: CHAIN_ID,
+                id: id.into(),
+                token: token.clone(),
+                challenge_blocks: 100,
+                participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+                balance2: balances,
+                guard: None,
+            }))
+        };
+        let update = |balances| {
+            tx(RawTransaction::UpdateChannel(UpdateChannel {
+                chain_id: CHAIN_ID,
+                channel_id: 7.into(),
+                version: 1,
+                balance2: balances,
+                ..Default::default()
+            }))
+        };
+
+        assert!(mempool
+            .push_transaction(create(8, balance2(600, 399)))
+            .is_err());
+        mempool
+            .push_transaction(create(9, balance2(600, 400)))
+            .unwrap();
+        assert!(mempool
+            .push_transaction(update(balance2(500, 500)))
+            .is_err());
+        assert!(mempool
+            .push_transaction(update(balance2(495, 505)))
+            .is_err());
+        mempool
+            .push_transaction(update(balance2(490, 510)))
+            .unwrap();
     }
 }
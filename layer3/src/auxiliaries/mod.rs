@@ -1,7 +1,12 @@
 pub mod chain;
 pub mod common;
+pub mod index;
+pub mod layer2;
 pub mod mempool;
 pub mod oracle;
+pub mod receipt;
 pub mod smt;
+pub mod snapshot;
 pub mod store;
 pub mod relay;
+pub mod wal;
@@ -0,0 +1,345 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hyper::{Body, Client, Request};
+use primitive_types::H256;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    auxiliaries::store::{AsyncStore, Store, StoreError},
+    scheduler::{Job, Schedule},
+    tracking::{OutPoint, TransferTracker},
+};
+
+const ORACLE_META_TREE: &str = "deposit_oracle";
+const SCANNED_KEY: &str = "scanned";
+// Cells asked of the indexer at once
+const CELLS_PAGE: u64 = 256;
+
+/// Lock script of the cells deposits to the operator are made in.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DepositLock {
+    pub code_hash: H256,
+    // type, data or data1
+    pub hash_type: String,
+    // Hex encoded
+    pub args: String,
+}
+
+/// Where the oracle watches for deposits. Off unless both the CKB node and
+/// the deposit lock are set.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct OraclePolicy {
+    // CKB node with its indexer enabled
+    pub ckb_rpc_uri: Option<String>,
+    pub deposit_lock: Option<DepositLock>,
+    pub poll_interval_secs: u64,
+    // L1 blocks scanned per poll
+    pub max_blocks: u64,
+}
+
+impl Default for OraclePolicy {
+    fn default() -> Self {
+        OraclePolicy {
+            ckb_rpc_uri: None,
+            deposit_lock: None,
+            poll_interval_secs: 10,
+            max_blocks: 1_000,
+        }
+    }
+}
+
+#[async_trait]
+pub trait DepositSource: Sync + Send {
+    async fn tip_block_number(&self) -> Result<u64>;
+
+    /// Deposit cells created in the L1 blocks `from..to`.
+    async fn deposits(&self, from: u64, to: u64) -> Result<Vec<OutPoint>>;
+}
+
+#[async_trait]
+impl<S: DepositSource + ?Sized> DepositSource for Arc<S> {
+    async fn tip_block_number(&self) -> Result<u64> {
+        (**self).tip_block_number().await
+    }
+
+    async fn deposits(&self, from: u64, to: u64) -> Result<Vec<OutPoint>> {
+        (**self).deposits(from, to).await
+    }
+}
+
+/// Finds deposits through the indexer of a CKB node. Only live cells are
+/// listed, so a deposit is seen as long as it isn't spent before the
+/// blocks holding it are scanned.
+pub struct CkbDepositSource {
+    client: Client<hyper::client::HttpConnector>,
+    uri: String,
+    lock: DepositLock,
+}
+
+impl CkbDepositSource {
+    pub fn new(uri: String, lock: DepositLock) -> Self {
+        CkbDepositSource {
+            client: Client::new(),
+            uri,
+            lock,
+        }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let req = Request::post(&self.uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))?;
+
+        let resp = self.client.request(req).await?;
+        let mut resp: Value = serde_json::from_slice(&hyper::body::to_bytes(resp).await?)?;
+        match resp.get_mut("result") {
+            Some(result) if !result.is_null() => Ok(result.take()),
+            _ => Err(anyhow!("{} failed {}: {}", self.uri, method, resp)),
+        }
+    }
+}
+
+#[async_trait]
+impl DepositSource for CkbDepositSource {
+    async fn tip_block_number(&self) -> Result<u64> {
+        let tip = self.call("get_tip_block_number", json!([])).await?;
+        tip.as_str()
+            .and_then(parse_hex_number)
+            .ok_or_else(|| anyhow!("{} returned no tip: {}", self.uri, tip))
+    }
+
+    async fn deposits(&self, from: u64, to: u64) -> Result<Vec<OutPoint>> {
+        let search_key = json!({
+            "script": {
+                "code_hash": self.lock.code_hash,
+                "hash_type": self.lock.hash_type,
+                "args": self.lock.args,
+            },
+            "script_type": "lock",
+            "filter": { "block_range": [format!("{:#x}", from), format!("{:#x}", to)] },
+        });
+
+        let mut deposits = Vec::new();
+        let mut cursor = Value::Null;
+        loop {
+            let params = json!([search_key, "asc", format!("{:#x}", CELLS_PAGE), cursor]);
+            let page = self.call("get_cells", params).await?;
+            let cells = page["objects"].as_array().cloned().unwrap_or_default();
+            for cell in cells.iter() {
+                let out_point = &cell["out_point"];
+                let index = out_point["index"].as_str().and_then(parse_hex_number);
+                let tx_hash = serde_json::from_value(out_point["tx_hash"].clone()).ok();
+                match (tx_hash, index) {
+                    (Some(tx_hash), Some(index)) => deposits.push(OutPoint {
+                        tx_hash,
+                        index: index as u32,
+                    }),
+                    _ => return Err(anyhow!("{} returned a bad cell: {}", self.uri, cell)),
+                }
+            }
+            if (cells.len() as u64) < CELLS_PAGE {
+                break;
+            }
+            cursor = page["last_cursor"].clone();
+        }
+
+        Ok(deposits)
+    }
+}
+
+fn parse_hex_number(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex.trim_start_matches("0x"), 16).ok()
+}
+
+/// L1 of a local devnet, which has no CKB node. Every deposit made with
+/// `deposit` is mined in a block of its own.
+pub struct MockDepositSource {
+    // Deposits of each block, none in the genesis block
+    blocks: Mutex<Vec<Vec<OutPoint>>>,
+}
+
+impl MockDepositSource {
+    pub fn new() -> Self {
+        MockDepositSource {
+            blocks: Mutex::new(vec![vec![]]),
+        }
+    }
+
+    /// Mine a block holding a deposit at `out_point`, returns its number.
+    pub fn deposit(&self, out_point: OutPoint) -> u64 {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.push(vec![out_point]);
+        blocks.len() as u64 - 1
+    }
+}
+
+impl Default for MockDepositSource {
+    fn default() -> Self {
+        MockDepositSource::new()
+    }
+}
+
+#[async_trait]
+impl DepositSource for MockDepositSource {
+    async fn tip_block_number(&self) -> Result<u64> {
+        Ok(self.blocks.lock().unwrap().len() as u64 - 1)
+    }
+
+    async fn deposits(&self, from: u64, to: u64) -> Result<Vec<OutPoint>> {
+        let blocks = self.blocks.lock().unwrap();
+        let (from, to) = (from as usize, to as usize);
+        Ok({ blocks.iter().take(to).skip(from) }
+            .flatten()
+            .copied()
+            .collect())
+    }
+}
+
+/// Scans L1 block by block and records every deposit it finds as
+/// detected, picking up after the last block scanned across restarts.
+pub struct DepositOracle<S> {
+    source: S,
+    transfers: TransferTracker,
+    meta: AsyncStore,
+    policy: OraclePolicy,
+}
+
+impl<S: DepositSource> DepositOracle<S> {
+    pub fn new(
+        store: &Store,
+        source: S,
+        transfers: TransferTracker,
+        policy: OraclePolicy,
+    ) -> Result<Self, StoreError> {
+        let oracle = DepositOracle {
+            source,
+            transfers,
+            meta: AsyncStore::new(store.open_tree(ORACLE_META_TREE)?),
+            policy,
+        };
+
+        Ok(oracle)
+    }
+
+    /// Record the deposits of the blocks after the last one scanned, up to
+    /// `max_blocks` of them. Returns the deposits found.
+    pub async fn poll(&self) -> Result<Vec<OutPoint>> {
+        let tip = self.source.tip_block_number().await?;
+        let from = match self.meta.get::<_, u64>(&SCANNED_KEY).await? {
+            Some(scanned) => scanned + 1,
+            None => 0,
+        };
+        if from > tip {
+            return Ok(vec![]);
+        }
+
+        let to = tip.min(from.saturating_add(self.policy.max_blocks.max(1) - 1));
+        let deposits = self.source.deposits(from, to + 1).await?;
+        for out_point in deposits.iter() {
+            self.transfers.deposit_detected(*out_point).await?;
+        }
+        self.meta.insert(SCANNED_KEY, to).await?;
+
+        if !deposits.is_empty() {
+            println!(
+                "[oracle] {} deposits in blocks {} to {}",
+                deposits.len(),
+                from,
+                to
+            );
+        }
+        Ok(deposits)
+    }
+}
+
+#[async_trait]
+impl<S: DepositSource> Job for DepositOracle<S> {
+    fn name(&self) -> &'static str {
+        "deposit_oracle"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(self.policy.poll_interval_secs.max(1))
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.poll().await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use tempfile::tempdir;
+
+    use crate::{finality::FinalityTracker, tracking::DepositStage};
+
+    use super::*;
+
+    // Deposits by the block holding them
+    #[derive(Default)]
+    struct FixedSource(Mutex<BTreeMap<u64, OutPoint>>);
+
+    #[async_trait]
+    impl DepositSource for FixedSource {
+        async fn tip_block_number(&self) -> Result<u64> {
+            let deposits = self.0.lock().unwrap();
+            Ok(deposits.keys().next_back().copied().unwrap_or_default())
+        }
+
+        async fn deposits(&self, from: u64, to: u64) -> Result<Vec<OutPoint>> {
+            let deposits = self.0.lock().unwrap();
+            Ok(deposits
+                .range(from..to)
+                .map(|(_, out_point)| *out_point)
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detect_deposits() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let finality = FinalityTracker::new(store.clone(), 0).unwrap();
+        let transfers = TransferTracker::new(&store, finality).unwrap();
+        let policy = OraclePolicy {
+            max_blocks: 2,
+            ..Default::default()
+        };
+        let source = FixedSource::default();
+        let oracle = DepositOracle::new(&store, source, transfers.clone(), policy).unwrap();
+
+        let out_points = [1, 2, 3].map(|byte| OutPoint {
+            tx_hash: H256::repeat_byte(byte),
+            index: 0,
+        });
+        for (number, out_point) in [1, 2, 5].into_iter().zip(out_points) {
+            oracle.source.0.lock().unwrap().insert(number, out_point);
+        }
+
+        // At most two blocks a poll, from the genesis block on
+        assert_eq!(oracle.poll().await.unwrap(), out_points[..1]);
+        assert_eq!(oracle.poll().await.unwrap(), out_points[1..2]);
+        assert_eq!(oracle.poll().await.unwrap(), out_points[2..]);
+        // Nothing past the tip is scanned
+        assert_eq!(oracle.poll().await.unwrap(), vec![]);
+        oracle.source.0.lock().unwrap().insert(6, out_points[0]);
+        assert_eq!(oracle.poll().await.unwrap(), out_points[..1]);
+
+        for out_point in out_points {
+            let status = transfers.get_deposit_status(out_point).await.unwrap();
+            assert_eq!(status.unwrap().stage, DepositStage::Detected);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use primitive_types::H256;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    auxiliaries::store::{Store, StoreError},
+    types::TransactionReceipt,
+};
+
+const RECEIPT_TREE: &str = "transaction_receipt";
+const STREAM_CAPACITY: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StreamedReceipt {
+    pub block_number: u64,
+    pub index: u32,
+    pub tx_hash: H256,
+    pub receipt: TransactionReceipt,
+    pub payment_id: Option<H256>,
+}
+
+// Layout stored before payment ids
+#[derive(Deserialize)]
+struct StreamedReceiptV1 {
+    block_number: u64,
+    index: u32,
+    tx_hash: H256,
+    receipt: TransactionReceipt,
+}
+
+impl From<StreamedReceiptV1> for StreamedReceipt {
+    fn from(receipt: StreamedReceiptV1) -> Self {
+        StreamedReceipt {
+            block_number: receipt.block_number,
+            index: receipt.index,
+            tx_hash: receipt.tx_hash,
+            receipt: receipt.receipt,
+            payment_id: None,
+        }
+    }
+}
+
+/// Persists transaction receipts and fans them out to subscribers while the
+/// block is still executing, so soft confirmations don't wait for the whole
+/// block. Slow subscribers lag and miss receipts rather than holding up
+/// execution, and can catch up from the store.
+#[derive(Clone)]
+pub struct ReceiptStream {
+    tree: Store,
+    sender: broadcast::Sender<StreamedReceipt>,
+}
+
+impl ReceiptStream {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        let (sender, _) = broadcast::channel(STREAM_CAPACITY);
+        let stream = ReceiptStream {
+            tree: store.open_tree(RECEIPT_TREE)?,
+            sender,
+        };
+
+        Ok(stream)
+    }
+
+    // Soft confirmations aren't pushed to API clients yet
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> broadcast::Receiver<StreamedReceipt> {
+        self.sender.subscribe()
+    }
+
+    pub fn get_receipt(&self, tx_hash: &H256) -> Result<Option<StreamedReceipt>, StoreError> {
+        match self.tree.get(tx_hash) {
+            Err(StoreError::Bincode(_)) => {
+                Ok({ self.tree.get::<_, StreamedReceiptV1>(tx_hash)? }.map(Into::into))
+            }
+            receipt => receipt,
+        }
+    }
+
+    /// Remove the receipt of `tx_hash`, returns the bytes it took.
+    pub fn remove_receipt(&self, tx_hash: &H256) -> Result<u64, StoreError> {
+        let bytes = self.tree.value_len(tx_hash)?.unwrap_or_default();
+        self.tree.remove(tx_hash)?;
+        Ok(bytes)
+    }
+
+    /// Bytes taken by every stored receipt.
+    pub fn stored_bytes(&self) -> Result<u64, StoreError> {
+        self.tree.value_bytes()
+    }
+
+    pub fn publish(&self, receipt: StreamedReceipt) -> Result<(), StoreError> {
+        self.tree.insert(receipt.tx_hash, &receipt)?;
+        // No subscriber is fine
+        let _ = self.sender.send(receipt);
+        Ok(())
+    }
+}
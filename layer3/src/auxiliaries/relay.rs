@@ -0,0 +1,241 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use primitive_types::H256;
+
+use crate::{
+    auxiliaries::store::{AsyncStore, Store, StoreError},
+    consensus::ConsensusReceipt,
+    scheduler::{Job, Schedule},
+    tracking::{OutPoint, TransferTracker},
+    withdrawal::{BatchPolicy, WithdrawalBatch, WithdrawalBatcher},
+};
+
+const RELAY_QUEUE_TREE: &str = "relay_queue";
+const RELAY_INTERVAL_SECS: u64 = 10;
+
+/// Where deposits are credited and withdrawals paid out.
+#[async_trait]
+pub trait Layer2Target: Sync + Send {
+    /// Credit a deposit seen on L1, returns the layer2 transaction. None if
+    /// the target takes no deposits, which leaves them detected.
+    async fn relay_deposit(&self, out_point: OutPoint) -> Result<Option<H256>>;
+
+    /// Pay every transfer of the batch in one layer2 transaction.
+    async fn relay_withdrawals(&self, batch: &WithdrawalBatch) -> Result<H256>;
+}
+
+/// Forwards detected deposits to layer2, and queues the withdrawals of the
+/// channels each block closes until their batch is submitted. The stages
+/// it moves transfers through are recorded in the transfer tracker.
+pub struct Relayer<T> {
+    target: T,
+    transfers: TransferTracker,
+    batcher: WithdrawalBatcher,
+    queue: AsyncStore,
+}
+
+impl<T: Layer2Target> Relayer<T> {
+    pub fn new(
+        store: &Store,
+        target: T,
+        transfers: TransferTracker,
+        policy: BatchPolicy,
+    ) -> Result<Self, StoreError> {
+        let relayer = Relayer {
+            target,
+            transfers,
+            batcher: WithdrawalBatcher::new(store, policy)?,
+            queue: AsyncStore::new(store.open_tree(RELAY_QUEUE_TREE)?),
+        };
+
+        Ok(relayer)
+    }
+
+    /// Queue the withdrawals of the channels the block closed.
+    pub async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        for batch in self.batcher.on_consensus_receipt(receipt).await? {
+            for transfer in batch.transfers.iter() {
+                for request_id in transfer.request_ids.iter() {
+                    self.transfers.withdrawal_queued(*request_id).await?;
+                }
+            }
+            self.queue.insert(batch.id, &batch).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Relay the detected deposits and the queued batches, oldest batch
+    /// first. Returns how many were relayed, stopping at the first one layer2
+    /// refuses so the rest is retried on the next run.
+    pub async fn relay(&self) -> Result<usize> {
+        let mut relayed = 0;
+        for out_point in self.transfers.detected_deposits().await? {
+            let l2_tx_hash = match self.target.relay_deposit(out_point).await? {
+                Some(l2_tx_hash) => l2_tx_hash,
+                None => continue,
+            };
+            self.transfers.deposit_relayed(out_point).await?;
+            println!("[relay] deposit {:?} in {:?}", out_point, l2_tx_hash);
+            relayed += 1;
+        }
+
+        let mut batches: Vec<WithdrawalBatch> = self.queue.run(|store| store.values()).await??;
+        batches.sort_by_key(|batch| batch.block_number);
+        for batch in batches {
+            let l2_tx_hash = self.target.relay_withdrawals(&batch).await?;
+            let number = batch.block_number;
+            for transfer in batch.transfers.iter() {
+                for request_id in transfer.request_ids.iter() {
+                    self.transfers
+                        .withdrawal_committed(*request_id, number)
+                        .await?;
+                }
+            }
+            self.queue.remove(batch.id).await?;
+            println!(
+                "[relay] withdrawal batch {:?} in {:?}",
+                batch.id, l2_tx_hash
+            );
+            relayed += 1;
+        }
+
+        Ok(relayed)
+    }
+}
+
+#[async_trait]
+impl<T: Layer2Target> Job for Relayer<T> {
+    fn name(&self) -> &'static str {
+        "relay"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(RELAY_INTERVAL_SECS)
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.relay().await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use anyhow::anyhow;
+    use primitive_types::{H160, U128};
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::common::H256Ext,
+        finality::FinalityTracker,
+        tracking::{DepositStage, WithdrawalStage},
+        types::{
+            Balance, Block, BlockHeader, Channel, CloseChannel, RawTransaction, SignedTransaction,
+        },
+        withdrawal::withdrawal_request_id,
+    };
+
+    use super::*;
+
+    struct SwitchedTarget(AtomicBool);
+
+    #[async_trait]
+    impl Layer2Target for SwitchedTarget {
+        async fn relay_deposit(&self, out_point: OutPoint) -> Result<Option<H256>> {
+            match self.0.load(Ordering::Relaxed) {
+                true => Ok(Some(out_point.tx_hash)),
+                false => Err(anyhow!("unreachable")),
+            }
+        }
+
+        async fn relay_withdrawals(&self, batch: &WithdrawalBatch) -> Result<H256> {
+            match self.0.load(Ordering::Relaxed) {
+                true => Ok(batch.id),
+                false => Err(anyhow!("unreachable")),
+            }
+        }
+    }
+
+    fn close_receipt(number: u64) -> ConsensusReceipt {
+        let channel = Channel {
+            id: 1.into(),
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            version: 2,
+            balance2: [10u64, 0].map(|settled| Balance {
+                settled: settled.into(),
+            }),
+            ..Default::default()
+        };
+        let tx = SignedTransaction {
+            raw: RawTransaction::CloseChannel(CloseChannel {
+                channel_id: channel.id,
+                version: 2,
+                ..Default::default()
+            }),
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::zero(),
+            hash: H256::zero(),
+        };
+
+        ConsensusReceipt {
+            block: Arc::new(Block {
+                header: BlockHeader {
+                    number,
+                    ..Default::default()
+                },
+                txs: vec![tx],
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: [(channel.id.to_h256(), channel)].into_iter().collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_records_stages() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let finality = FinalityTracker::new(store.clone(), 0).unwrap();
+        let transfers = TransferTracker::new(&store, finality).unwrap();
+        let target = SwitchedTarget(AtomicBool::new(false));
+        let relayer = Relayer::new(&store, target, transfers.clone(), Default::default()).unwrap();
+
+        let out_point = OutPoint {
+            tx_hash: H256::repeat_byte(1),
+            index: 0,
+        };
+        transfers.deposit_detected(out_point).await.unwrap();
+        relayer
+            .on_consensus_receipt(&close_receipt(3))
+            .await
+            .unwrap();
+        let request_id = withdrawal_request_id(1.into(), 2, 0);
+        let status = transfers.get_withdrawal_status(request_id).await.unwrap();
+        assert_eq!(status.unwrap().stage, WithdrawalStage::Queued);
+
+        // Nothing moves while layer2 is unreachable
+        assert!(relayer.relay().await.is_err());
+        let status = transfers.get_deposit_status(out_point).await.unwrap();
+        assert_eq!(status.unwrap().stage, DepositStage::Detected);
+
+        relayer.target.0.store(true, Ordering::Relaxed);
+        assert_eq!(relayer.relay().await.unwrap(), 2);
+        assert_eq!(relayer.relay().await.unwrap(), 0);
+        let status = transfers.get_deposit_status(out_point).await.unwrap();
+        assert_eq!(status.unwrap().stage, DepositStage::Relayed);
+        let status = transfers
+            .get_withdrawal_status(request_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.stage, WithdrawalStage::Committed);
+        assert_eq!(status.block_number, Some(3));
+    }
+}
@@ -15,14 +15,29 @@ use crate::{
         common::{blake2b, H256Ext},
         store::{Store, StoreError},
     },
-    types::Channel,
+    types::{Channel, ChannelV1},
 };
 
 pub type SMT<S> = SparseMerkleTree<Blake2bHasher, Channel, S>;
 
 impl Value for Channel {
+    /// Channels without a rate guard hash as they did before guards, so
+    /// their state roots don't change.
     fn to_h256(&self) -> SMTH256 {
-        let encoded: Vec<u8> = bincode::serialize(self).expect("bincode encode");
+        let encoded: Vec<u8> = match self.guard {
+            Some(_) => bincode::serialize(self),
+            None => bincode::serialize(&(
+                &self.id,
+                &self.token,
+                self.challenge_blocks,
+                &self.participant2,
+                &self.state,
+                self.version,
+                &self.total_balance,
+                &self.balance2,
+            )),
+        }
+        .expect("bincode encode");
         blake2b(&encoded).0.into()
     }
 
@@ -56,14 +71,14 @@ impl MemStore {
 impl StoreReadOps<Channel> for MemStore {
     fn get_branch(&self, branch_key: &BranchKey) -> Result<Option<BranchNode>, SMTError> {
         match self.overlay.branches.get(branch_key) {
-            Some(v) => Ok(Some(v.clone().into())),
+            Some(v) => Ok(Some(v.clone())),
             None => self.store.get_branch(branch_key),
         }
     }
 
     fn get_leaf(&self, leaf_key: &SMTH256) -> Result<Option<Channel>, SMTError> {
         match self.overlay.leaves.get(leaf_key) {
-            Some(v) => Ok(Some(v.clone().into())),
+            Some(v) => Ok(Some(v.clone())),
             None => self.store.get_leaf(leaf_key),
         }
     }
@@ -111,7 +126,8 @@ impl StoreReadOps<Channel> for Store {
     }
 
     fn get_leaf(&self, leaf_key: &SMTH256) -> Result<Option<Channel>, SMTError> {
-        Ok(self.get::<H256, Channel>(&H256Ext::to_h256(leaf_key))?)
+        let key = H256Ext::to_h256(leaf_key);
+        Ok(self.get_migrating::<H256, Channel, ChannelV1>(&key)?)
     }
 }
 
@@ -259,4 +275,38 @@ mod tests {
         smt.update(channel_id.to_h256(), channel.clone()).unwrap();
         assert_eq!(smt.get(&channel_id.to_h256()).unwrap(), channel);
     }
+
+    #[test]
+    fn test_channel_stored_before_guards() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let channel = Channel {
+            id: U256::one(),
+            version: 3,
+            ..Default::default()
+        };
+        let legacy = (
+            &channel.id,
+            &channel.token,
+            channel.challenge_blocks,
+            &channel.participant2,
+            &channel.state,
+            channel.version,
+            &channel.total_balance,
+            &channel.balance2,
+        );
+        let key: H256 = channel.id.to_h256();
+        store.insert(key, legacy).unwrap();
+
+        let leaf = store.get_leaf(&key.0.into()).unwrap().unwrap();
+        assert_eq!(leaf, channel);
+        let encoded = bincode::serialize(&legacy).unwrap();
+        assert_eq!(leaf.to_h256(), blake2b(&encoded).0.into());
+
+        let guarded = Channel {
+            guard: Some(Default::default()),
+            ..channel
+        };
+        assert_ne!(guarded.to_h256(), leaf.to_h256());
+    }
 }
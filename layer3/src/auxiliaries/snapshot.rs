@@ -0,0 +1,686 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use hyper::{
+    client::HttpConnector,
+    service::{make_service_fn, service_fn},
+    Body, Client, Method, Request, Response, Server, StatusCode,
+};
+use primitive_types::{H256, U256};
+use proof::{smt::SmtProof, ProofError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sparse_merkle_tree::traits::Value;
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        common::H256Ext,
+        index::ChannelIndexes,
+        smt::SMT,
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    types::{Block, Channel, NumberHash},
+};
+
+pub const CHANNEL_INDEX_TREE: &str = "channel_index";
+const SNAPSHOT_IMPORT_TREE: &str = "snapshot_import";
+const IMPORT_PROGRESS_KEY: &str = "progress";
+const SNAPSHOT_META_TREE: &str = "snapshot_meta";
+const PINNED_KEY: &str = "pinned";
+const MAX_CHUNK_CHANNELS: usize = 1024;
+const MAX_RECENT_BLOCKS: u64 = 1024;
+
+/// Index key of a channel, big endian so the index iterates by channel id.
+pub fn channel_index_key(channel_id: &U256) -> [u8; 32] {
+    let mut buf = [0u8; 32];
+    channel_id.to_big_endian(&mut buf);
+    buf
+}
+
+/// A slice of the channel state at `state_root`, provable on its own.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotChunk {
+    pub state_root: H256,
+    pub channels: Vec<Channel>,
+    pub proof: Vec<u8>,
+    // Cursor of the following chunk, none on the last one
+    pub next: Option<U256>,
+}
+
+impl SnapshotChunk {
+    // Followers verify chunks, the node only runs as the operator so far
+    #[allow(dead_code)]
+    pub fn verify(&self) -> Result<bool> {
+        if self.channels.is_empty() {
+            return Ok(self.next.is_none());
+        }
+
+        let leaves = { self.channels.iter() }
+            .map(|channel| {
+                (
+                    channel.id.to_h256(),
+                    H256Ext::<H256>::to_h256(&Value::to_h256(channel)),
+                )
+            })
+            .collect::<Vec<(H256, H256)>>();
+
+        match SmtProof(self.proof.clone()).verify(&self.state_root, &leaves) {
+            Ok(()) => Ok(true),
+            Err(ProofError::Leaves) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// How often the channel state is pinned for followers to sync from.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SnapshotPolicy {
+    // Pinned at the blocks whose number is a multiple, 0 pins none
+    pub every_blocks: u64,
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        SnapshotPolicy { every_blocks: 100 }
+    }
+}
+
+/// The channel state as of block `number`, copied aside so its chunks stay
+/// provable against `state_root` while the live state moves on.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PinnedSnapshot {
+    pub number: u64,
+    pub state_root: H256,
+}
+
+impl PinnedSnapshot {
+    fn state_tree(&self) -> String {
+        format!("snapshot_{}", self.number)
+    }
+
+    fn index_tree(&self) -> String {
+        format!("snapshot_{}_index", self.number)
+    }
+}
+
+/// Serves the pinned channel state and recent blocks to syncing followers.
+#[derive(Clone)]
+pub struct SnapshotSource {
+    store: AsyncStore,
+    index: Store,
+    meta: AsyncStore,
+    chain: ChannelChain,
+    policy: SnapshotPolicy,
+}
+
+impl SnapshotSource {
+    pub fn new(store: Store, policy: SnapshotPolicy) -> Result<Self, StoreError> {
+        let source = SnapshotSource {
+            index: store.open_tree(CHANNEL_INDEX_TREE)?,
+            meta: AsyncStore::new(store.open_tree(SNAPSHOT_META_TREE)?),
+            chain: ChannelChain::new(store.clone())?,
+            store: AsyncStore::new(store),
+            policy,
+        };
+
+        Ok(source)
+    }
+
+    /// Pin the state of the blocks the policy picks, once the block is
+    /// applied and before the next one is.
+    pub async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        let header = &receipt.block.header;
+        let every_blocks = self.policy.every_blocks;
+        if every_blocks == 0 || !header.number.is_multiple_of(every_blocks) {
+            return Ok(());
+        }
+
+        self.pin(header.number, header.state_root).await?;
+        Ok(())
+    }
+
+    /// Copy the live state, which must be the state of block `number`, and
+    /// serve chunks from the copy. The previous copy is dropped once this
+    /// one is complete.
+    pub async fn pin(&self, number: u64, state_root: H256) -> Result<PinnedSnapshot> {
+        let pinned = PinnedSnapshot { number, state_root };
+        let index = self.index.clone();
+        let copy = pinned.clone();
+        self.store
+            .run(move |store| -> Result<()> {
+                let live = SMT::new_with_store(store.clone())?;
+                if H256Ext::to_h256(live.root()) != copy.state_root {
+                    return Err(anyhow!(
+                        "live state root {:?} isn't the state root {:?} of block {}",
+                        H256Ext::to_h256(live.root()),
+                        copy.state_root,
+                        copy.number
+                    ));
+                }
+
+                let pinned_index = store.open_tree(copy.index_tree())?;
+                let mut pinned = SMT::new_with_store(store.open_tree(copy.state_tree())?)?;
+                let mut from = channel_index_key(&U256::zero());
+                loop {
+                    let ids: Vec<U256> = index.values_from(&from, MAX_CHUNK_CHANNELS)?;
+                    let leaves = { ids.iter() }
+                        .map(|id| Ok((id.to_h256(), live.get(&id.to_h256())?)))
+                        .collect::<Result<Vec<_>>>()?;
+                    for id in ids.iter() {
+                        pinned_index.insert(channel_index_key(id), id)?;
+                    }
+                    if !leaves.is_empty() {
+                        pinned.update_all(leaves)?;
+                    }
+                    match ids.last() {
+                        Some(last) if ids.len() == MAX_CHUNK_CHANNELS => {
+                            from = channel_index_key(&(last + 1));
+                        }
+                        _ => break,
+                    }
+                }
+                if H256Ext::to_h256(pinned.root()) != copy.state_root {
+                    return Err(anyhow!(
+                        "pinned state of block {} is incomplete",
+                        copy.number
+                    ));
+                }
+
+                Ok(())
+            })
+            .await??;
+
+        let previous = self.pinned().await?;
+        self.meta.insert(PINNED_KEY, &pinned).await?;
+        if let Some(previous) = previous.filter(|previous| previous.number != number) {
+            self.store
+                .run(move |store| -> Result<(), StoreError> {
+                    store.drop_tree(previous.state_tree())?;
+                    store.drop_tree(previous.index_tree())?;
+                    Ok(())
+                })
+                .await??;
+        }
+        println!("[snapshot] pinned the state of block {}", number);
+
+        Ok(pinned)
+    }
+
+    pub async fn pinned(&self) -> Result<Option<PinnedSnapshot>> {
+        Ok(self.meta.get(&PINNED_KEY).await?)
+    }
+
+    /// Up to `limit` channels of the pinned state from `cursor` on.
+    pub async fn channel_chunk(&self, cursor: Option<U256>, limit: usize) -> Result<SnapshotChunk> {
+        let pinned = { self.pinned().await? }.ok_or_else(|| anyhow!("no snapshot pinned yet"))?;
+        let from = channel_index_key(&cursor.unwrap_or_default());
+        let limit = limit.clamp(1, MAX_CHUNK_CHANNELS);
+
+        let chunk = self
+            .store
+            .run(move |store| -> Result<SnapshotChunk> {
+                let index = store.open_tree(pinned.index_tree())?;
+                let mut ids: Vec<U256> = index.values_from(&from, limit + 1)?;
+                let next = if ids.len() > limit { ids.pop() } else { None };
+
+                let smt = SMT::new_with_store(store.open_tree(pinned.state_tree())?)?;
+                let channels = { ids.iter() }
+                    .map(|id| smt.get(&id.to_h256()))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let proof = if ids.is_empty() {
+                    Vec::new()
+                } else {
+                    let keys = ids.iter().map(|id| id.to_h256()).collect::<Vec<_>>();
+                    smt.merkle_proof(keys.clone())?.compile(keys)?.into()
+                };
+
+                Ok(SnapshotChunk {
+                    state_root: pinned.state_root,
+                    channels,
+                    proof,
+                    next,
+                })
+            })
+            .await??;
+
+        Ok(chunk)
+    }
+
+    /// Up to `count` blocks ending at the current tip, oldest first.
+    pub async fn recent_blocks(&self, count: u64) -> Result<Vec<Arc<Block>>> {
+        let tip = match self.chain.tip_header().await? {
+            Some(tip) => tip.number,
+            None => return Ok(Vec::new()),
+        };
+
+        self.blocks_from(tip.saturating_sub(count.saturating_sub(1)), count)
+            .await
+    }
+
+    /// Up to `count` blocks from number `from` on, fewer near the tip.
+    pub async fn blocks_from(&self, from: u64, count: u64) -> Result<Vec<Arc<Block>>> {
+        let tip = match self.chain.tip_header().await? {
+            Some(tip) => tip.number,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut blocks = Vec::new();
+        let from = from.max(1);
+        for number in from..=tip.min(from.saturating_add(count.saturating_sub(1))) {
+            if let Some(block) = self.chain.get_block(NumberHash::Number(number)).await? {
+                blocks.push(block);
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /// Serve followers, json bodies:
+    ///
+    /// - `GET /snapshot` the pinned snapshot
+    /// - `GET /snapshot/chunk?cursor=<channel id>&limit=<n>` a chunk of it
+    /// - `GET /blocks?count=<n>` the recent blocks
+    /// - `GET /blocks?from=<number>&count=<n>` the blocks from `number` on
+    pub async fn serve(self, addr: SocketAddr) -> Result<()> {
+        let make_svc = make_service_fn(move |_| {
+            let source = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let source = source.clone();
+                    async move { Ok::<_, Infallible>(source.handle(req).await) }
+                }))
+            }
+        });
+
+        Server::try_bind(&addr)?.serve(make_svc).await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::GET {
+            return response(StatusCode::NOT_FOUND, Body::empty());
+        }
+        let param = |name: &str| {
+            { req.uri().query().unwrap_or_default().split('&') }
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_owned)
+        };
+
+        let body = match req.uri().path() {
+            "/snapshot" => match self.pinned().await {
+                Ok(None) => return response(StatusCode::NOT_FOUND, Body::empty()),
+                pinned => pinned.and_then(|pinned| Ok(serde_json::to_vec(&pinned)?)),
+            },
+            "/snapshot/chunk" => {
+                let cursor = match param("cursor")
+                    .map(|id| U256::from_dec_str(&id))
+                    .transpose()
+                {
+                    Ok(cursor) => cursor,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                let limit = { param("limit") }
+                    .and_then(|limit| limit.parse().ok())
+                    .unwrap_or(MAX_CHUNK_CHANNELS);
+                { self.channel_chunk(cursor, limit).await }
+                    .and_then(|chunk| Ok(serde_json::to_vec(&chunk)?))
+            }
+            "/blocks" => {
+                let count = param("count")
+                    .and_then(|count| count.parse().ok())
+                    .unwrap_or(1)
+                    .min(MAX_RECENT_BLOCKS);
+                let blocks = match param("from").map(|from| from.parse::<u64>()) {
+                    None => self.recent_blocks(count).await,
+                    Some(Ok(from)) => self.blocks_from(from, count).await,
+                    Some(Err(e)) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                blocks.and_then(|blocks| Ok(serde_json::to_vec(&blocks)?))
+            }
+            _ => return response(StatusCode::NOT_FOUND, Body::empty()),
+        };
+
+        match body {
+            Ok(body) => response(StatusCode::OK, body.into()),
+            Err(e) => response(StatusCode::SERVICE_UNAVAILABLE, e.to_string().into()),
+        }
+    }
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Downloads a snapshot from the `SnapshotSource` serving at `uri`.
+pub struct SnapshotClient {
+    client: Client<HttpConnector>,
+    uri: String,
+}
+
+impl SnapshotClient {
+    pub fn new(uri: String) -> Self {
+        SnapshotClient {
+            client: Client::new(),
+            uri,
+        }
+    }
+
+    pub async fn pinned(&self) -> Result<PinnedSnapshot> {
+        self.get("/snapshot").await
+    }
+
+    pub async fn recent_blocks(&self, count: u64) -> Result<Vec<Block>> {
+        self.get(&format!("/blocks?count={}", count)).await
+    }
+
+    pub async fn blocks_from(&self, from: u64, count: u64) -> Result<Vec<Block>> {
+        self.get(&format!("/blocks?from={}&count={}", from, count))
+            .await
+    }
+
+    /// Fetch the chunks `importer` is missing, so an interrupted sync picks
+    /// up where it stopped.
+    pub async fn sync(&self, importer: &mut SnapshotImporter) -> Result<()> {
+        while let Some(cursor) = importer.cursor() {
+            let path = match cursor {
+                Some(cursor) => format!("/snapshot/chunk?cursor={}", cursor),
+                None => "/snapshot/chunk".to_owned(),
+            };
+            importer.import(self.get(&path).await?).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let uri = format!("{}{}", self.uri.trim_end_matches('/'), path);
+        let resp = self.client.get(uri.parse()?).await?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp).await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "{} answered {}: {}",
+                uri,
+                status,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ImportProgress {
+    state_root: H256,
+    next: Option<U256>,
+    done: bool,
+}
+
+/// Imports snapshot chunks on a follower. Progress is persisted after every
+/// chunk, so an interrupted download resumes from the last cursor as long as
+/// the target state root is unchanged.
+// Follower side, like `SnapshotChunk::verify`
+#[allow(dead_code)]
+pub struct SnapshotImporter {
+    store: AsyncStore,
+    indexes: ChannelIndexes,
+    meta: AsyncStore,
+    progress: ImportProgress,
+}
+
+#[allow(dead_code)]
+impl SnapshotImporter {
+    pub async fn open(store: Store, state_root: H256) -> Result<Self> {
+        let meta = AsyncStore::new(store.open_tree(SNAPSHOT_IMPORT_TREE)?);
+        let progress = match meta.get::<_, ImportProgress>(&IMPORT_PROGRESS_KEY).await? {
+            Some(progress) if progress.state_root == state_root => progress,
+            _ => ImportProgress {
+                state_root,
+                next: None,
+                done: false,
+            },
+        };
+
+        let importer = SnapshotImporter {
+            indexes: ChannelIndexes::open(&store)?,
+            store: AsyncStore::new(store),
+            meta,
+            progress,
+        };
+
+        Ok(importer)
+    }
+
+    /// Cursor to request next, none once the import is complete.
+    pub fn cursor(&self) -> Option<Option<U256>> {
+        (!self.progress.done).then_some(self.progress.next)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.progress.done
+    }
+
+    pub async fn import(&mut self, chunk: SnapshotChunk) -> Result<()> {
+        if self.progress.done {
+            return Err(anyhow!("snapshot import already complete"));
+        }
+        if chunk.state_root != self.progress.state_root {
+            return Err(anyhow!(
+                "snapshot chunk state root mismatch, expect {:?}, got {:?}",
+                self.progress.state_root,
+                chunk.state_root
+            ));
+        }
+        if !chunk.verify()? {
+            return Err(anyhow!("invalid snapshot chunk proof"));
+        }
+        let expect = self.progress.next.unwrap_or_default();
+        if chunk.channels.iter().any(|channel| channel.id < expect) {
+            return Err(anyhow!("snapshot chunk out of order"));
+        }
+
+        let indexes = self.indexes.clone();
+        let next = chunk.next;
+        let state_root = self
+            .store
+            .run(move |store| -> Result<H256> {
+                let leaves = { chunk.channels.iter() }
+                    .map(|channel| (channel.id.to_h256(), channel.clone()))
+                    .collect::<Vec<_>>();
+                let mut smt = SMT::new_with_store(store.clone())?;
+                for channel in chunk.channels.iter() {
+                    indexes.update(&smt.get(&channel.id.to_h256())?, channel)?;
+                }
+
+                Ok(H256Ext::to_h256(smt.update_all(leaves)?))
+            })
+            .await??;
+
+        if next.is_none() {
+            if state_root != self.progress.state_root {
+                return Err(anyhow!(
+                    "imported state root mismatch, expect {:?}, got {:?}",
+                    self.progress.state_root,
+                    state_root
+                ));
+            }
+            self.progress.done = true;
+        }
+        self.progress.next = next;
+        self.meta.insert(IMPORT_PROGRESS_KEY, &self.progress).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, net::TcpListener};
+
+    use primitive_types::H160;
+    use tempfile::tempdir;
+
+    use crate::types::{BlockHeader, Symbol, Token};
+
+    use super::*;
+
+    fn channel(id: u64) -> Channel {
+        Channel {
+            id: id.into(),
+            token: Token {
+                symbol: Symbol::new("CKUSD").unwrap(),
+                ..Default::default()
+            },
+            challenge_blocks: id,
+            ..Default::default()
+        }
+    }
+
+    // Channels `from..=to` on top of the live state
+    fn seed(store: &Store, from: u64, to: u64) -> H256 {
+        let index = store.open_tree(CHANNEL_INDEX_TREE).unwrap();
+        let mut smt = SMT::new_with_store(store.clone()).unwrap();
+        for id in from..=to {
+            let channel = channel(id);
+            index.insert(channel_index_key(&channel.id), channel.id).unwrap();
+            smt.update(channel.id.to_h256(), channel).unwrap();
+        }
+
+        H256Ext::to_h256(smt.root())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_chunks_roundtrip() {
+        let source_db = tempdir().unwrap();
+        let source_store = Store::open(source_db.path()).unwrap();
+        let state_root = seed(&source_store, 1, 5);
+        let source = SnapshotSource::new(source_store.clone(), SnapshotPolicy::default()).unwrap();
+        assert!(source.channel_chunk(None, 2).await.is_err());
+        source.pin(100, state_root).await.unwrap();
+        // Later blocks don't change the chunks of the pinned state
+        seed(&source_store, 6, 7);
+
+        let follower_db = tempdir().unwrap();
+        let follower_store = Store::open(follower_db.path()).unwrap();
+        let mut importer = SnapshotImporter::open(follower_store.clone(), state_root)
+            .await
+            .unwrap();
+
+        let chunk = source.channel_chunk(None, 2).await.unwrap();
+        assert_eq!(chunk.channels.len(), 2);
+        assert!(chunk.verify().unwrap());
+        importer.import(chunk).await.unwrap();
+        drop(importer);
+
+        // Resume from persisted progress
+        let mut importer = SnapshotImporter::open(follower_store.clone(), state_root)
+            .await
+            .unwrap();
+        assert_eq!(importer.cursor(), Some(Some(3u64.into())));
+        while let Some(cursor) = importer.cursor() {
+            let chunk = source.channel_chunk(cursor, 2).await.unwrap();
+            importer.import(chunk).await.unwrap();
+        }
+        assert!(importer.is_complete());
+
+        let smt = SMT::new_with_store(follower_store).unwrap();
+        assert_eq!(H256Ext::to_h256(smt.root()), state_root);
+    }
+
+    #[tokio::test]
+    async fn test_tampered_chunk_rejected() {
+        let source_db = tempdir().unwrap();
+        let source_store = Store::open(source_db.path()).unwrap();
+        let state_root = seed(&source_store, 1, 3);
+        let source = SnapshotSource::new(source_store, SnapshotPolicy::default()).unwrap();
+        source.pin(100, state_root).await.unwrap();
+
+        let mut chunk = source.channel_chunk(None, 3).await.unwrap();
+        chunk.channels[1].challenge_blocks += 1;
+        assert!(!chunk.verify().unwrap());
+
+        let follower_db = tempdir().unwrap();
+        let follower_store = Store::open(follower_db.path()).unwrap();
+        let mut importer = SnapshotImporter::open(follower_store, state_root).await.unwrap();
+        assert!(importer.import(chunk).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_served_snapshot() {
+        let source_db = tempdir().unwrap();
+        let source_store = Store::open(source_db.path()).unwrap();
+        let policy = SnapshotPolicy { every_blocks: 2 };
+        let source = SnapshotSource::new(source_store.clone(), policy).unwrap();
+        let chain = ChannelChain::new(source_store.clone()).unwrap();
+        let mut state_roots = Vec::new();
+        for number in 1..=3 {
+            let state_root = seed(&source_store, number * 10, number * 10 + 4);
+            let block = Arc::new(Block {
+                header: BlockHeader {
+                    number,
+                    hash: H256::from_low_u64_be(number),
+                    state_root,
+                    ..Default::default()
+                },
+                txs: vec![],
+            });
+            chain.save_block(Arc::clone(&block)).await.unwrap();
+            let receipt = ConsensusReceipt {
+                block,
+                proposer: H160::zero(),
+                round: 0,
+                commit_signatures: vec![],
+                updated_channels: BTreeMap::new(),
+            };
+            source.on_consensus_receipt(&receipt).await.unwrap();
+            state_roots.push(state_root);
+        }
+
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(source.serve(addr));
+        let client = SnapshotClient::new(format!("http://{}", addr));
+        let pinned = loop {
+            match client.pinned().await {
+                Ok(pinned) => break pinned,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let expect = PinnedSnapshot {
+            number: 2,
+            state_root: state_roots[1],
+        };
+        assert_eq!(pinned, expect);
+
+        let follower_db = tempdir().unwrap();
+        let follower_store = Store::open(follower_db.path()).unwrap();
+        let mut importer = SnapshotImporter::open(follower_store.clone(), pinned.state_root)
+            .await
+            .unwrap();
+        client.sync(&mut importer).await.unwrap();
+        assert!(importer.is_complete());
+        let smt = SMT::new_with_store(follower_store).unwrap();
+        assert_eq!(H256Ext::to_h256(smt.root()), pinned.state_root);
+
+        let blocks = client.recent_blocks(2).await.unwrap();
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| block.header.number)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        let blocks = client.blocks_from(0, 2).await.unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].header.number, 1);
+        assert!(client.blocks_from(4, 2).await.unwrap().is_empty());
+    }
+}
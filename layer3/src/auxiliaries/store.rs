@@ -2,6 +2,7 @@ use std::path::Path;
 
 use anyhow::Result;
 use bincode::serialize;
+use bytes::Bytes;
 use serde::{de::DeserializeOwned, Serialize};
 
 #[derive(thiserror::Error, Debug)]
@@ -10,35 +11,246 @@ pub enum StoreError {
     Seld(#[from] sled::Error),
     #[error("{0}")]
     Bincode(#[from] bincode::Error),
+    #[error("{0}")]
+    Join(#[from] tokio::task::JoinError),
 }
 
 #[derive(Clone)]
 pub struct Store {
     db: sled::Db,
+    tree: sled::Tree,
 }
 
 impl Store {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
         let db = sled::open(path.as_ref())?;
-        let store = Self { db };
+        let tree = (*db).clone();
+        let store = Self { db, tree };
+
+        Ok(store)
+    }
+
+    /// Open a named tree sharing the same underlying database.
+    pub fn open_tree<N: AsRef<[u8]>>(&self, name: N) -> Result<Self, StoreError> {
+        let tree = self.db.open_tree(name)?;
+        let store = Self {
+            db: self.db.clone(),
+            tree,
+        };
 
         Ok(store)
     }
 
+    /// Remove a named tree and everything in it, false if there was none.
+    pub fn drop_tree<N: AsRef<[u8]>>(&self, name: N) -> Result<bool, StoreError> {
+        Ok(self.db.drop_tree(name)?)
+    }
+
     pub fn get<K: Serialize, V: DeserializeOwned>(&self, key: &K) -> Result<Option<V>, StoreError> {
-        match self.db.get(&serialize(key)?)? {
+        match self.tree.get(serialize(key)?)? {
             None => Ok(None),
             Some(val) => Ok(Some(bincode::deserialize(&val)?)),
         }
     }
 
+    /// Like `get`, values that don't decode as `V` are decoded as `L`, the
+    /// layout they were stored with by older versions.
+    pub fn get_migrating<K: Serialize, V: DeserializeOwned, L: DeserializeOwned + Into<V>>(
+        &self,
+        key: &K,
+    ) -> Result<Option<V>, StoreError> {
+        match self.tree.get(serialize(key)?)? {
+            None => Ok(None),
+            Some(val) => Ok(Some(decode_migrating::<V, L>(&val)?)),
+        }
+    }
+
     pub fn insert<K: Serialize, V: Serialize>(&self, key: K, val: V) -> Result<(), StoreError> {
-        self.db.insert(serialize(&key)?, serialize(&val)?)?;
+        self.tree.insert(serialize(&key)?, serialize(&val)?)?;
         Ok(())
     }
 
+    /// Encoded size of the value under `key`.
+    pub fn value_len<K: Serialize>(&self, key: &K) -> Result<Option<u64>, StoreError> {
+        Ok(self.tree.get(serialize(key)?)?.map(|val| val.len() as u64))
+    }
+
+    /// Encoded size of every value of this tree together.
+    pub fn value_bytes(&self) -> Result<u64, StoreError> {
+        { self.tree.iter().values() }.try_fold(0, |bytes, val| Ok(bytes + val?.len() as u64))
+    }
+
     pub fn remove<K: Serialize>(&self, key: K) -> Result<(), StoreError> {
-        self.db.remove(serialize(&key)?)?;
+        self.tree.remove(serialize(&key)?)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), StoreError> {
+        self.tree.flush()?;
         Ok(())
     }
+
+    /// Flush every tree of the database, so that segments emptied by
+    /// removals can be reclaimed.
+    pub fn flush_db(&self) -> Result<(), StoreError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Bytes used by the whole database on disk.
+    pub fn size_on_disk(&self) -> Result<u64, StoreError> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    /// All values of this tree, in key byte order.
+    pub fn values<V: DeserializeOwned>(&self) -> Result<Vec<V>, StoreError> {
+        { self.tree.iter().values() }
+            .map(|val| Ok(bincode::deserialize(&val?)?))
+            .collect()
+    }
+
+    /// `values` decoding with `get_migrating`.
+    pub fn values_migrating<V: DeserializeOwned, L: DeserializeOwned + Into<V>>(
+        &self,
+    ) -> Result<Vec<V>, StoreError> {
+        { self.tree.iter().values() }
+            .map(|val| decode_migrating::<V, L>(&val?))
+            .collect()
+    }
+
+    /// Up to `limit` values starting from `from` (inclusive), in key byte
+    /// order.
+    pub fn values_from<K: Serialize, V: DeserializeOwned>(
+        &self,
+        from: &K,
+        limit: usize,
+    ) -> Result<Vec<V>, StoreError> {
+        { self.tree.range(serialize(from)?..).values().take(limit) }
+            .map(|val| Ok(bincode::deserialize(&val?)?))
+            .collect()
+    }
+
+    /// Up to `limit` values whose keys start with `prefix`, from the key of
+    /// `prefix` followed by `from` (inclusive), in key byte order.
+    pub fn values_with_prefix<P: Serialize, K: Serialize, V: DeserializeOwned>(
+        &self,
+        prefix: &P,
+        from: &K,
+        limit: usize,
+    ) -> Result<Vec<V>, StoreError> {
+        let prefix = serialize(prefix)?;
+        let start = [prefix.as_slice(), &serialize(from)?].concat();
+        { self.tree.range(start..) }
+            // Errors are kept for `collect` to return
+            .take_while(|entry| match entry {
+                Ok((key, _)) => key.starts_with(&prefix),
+                Err(_) => true,
+            })
+            .take(limit)
+            .map(|entry| Ok(bincode::deserialize(&entry?.1)?))
+            .collect()
+    }
+
+    /// Values with keys from `from` (inclusive) to `to` (exclusive), in key
+    /// byte order.
+    pub fn values_range<K: Serialize, V: DeserializeOwned>(
+        &self,
+        from: &K,
+        to: &K,
+    ) -> Result<Vec<V>, StoreError> {
+        { self.tree.range(serialize(from)?..serialize(to)?).values() }
+            .map(|val| Ok(bincode::deserialize(&val?)?))
+            .collect()
+    }
+}
+
+fn decode_migrating<V: DeserializeOwned, L: DeserializeOwned + Into<V>>(
+    val: &[u8],
+) -> Result<V, StoreError> {
+    match bincode::deserialize(val) {
+        Ok(val) => Ok(val),
+        Err(_) => Ok(bincode::deserialize::<L>(val)?.into()),
+    }
+}
+
+/// Async facade over `Store`. Every call runs on tokio's blocking pool so
+/// that sled I/O never stalls the runtime driving consensus and RPC.
+#[derive(Clone)]
+pub struct AsyncStore {
+    store: Store,
+}
+
+impl AsyncStore {
+    pub fn new(store: Store) -> Self {
+        Self { store }
+    }
+
+    pub fn inner(&self) -> &Store {
+        &self.store
+    }
+
+    // Every tree is opened before the store goes async so far
+    #[allow(dead_code)]
+    pub async fn open_tree<N: AsRef<[u8]>>(&self, name: N) -> Result<Self, StoreError> {
+        let name = name.as_ref().to_vec();
+        let tree = self.run(move |store| store.open_tree(name)).await??;
+        Ok(Self::new(tree))
+    }
+
+    pub async fn get<K: Serialize, V: DeserializeOwned + Send + 'static>(
+        &self,
+        key: &K,
+    ) -> Result<Option<V>, StoreError> {
+        let key = serialize(key)?;
+        let val = self.run(move |store| store.tree.get(key)).await??;
+
+        match val {
+            None => Ok(None),
+            Some(val) => Ok(Some(bincode::deserialize(&val)?)),
+        }
+    }
+
+    /// The value under `key` still encoded.
+    pub async fn get_bytes<K: Serialize>(&self, key: &K) -> Result<Option<Bytes>, StoreError> {
+        let key = serialize(key)?;
+        let val = self.run(move |store| store.tree.get(key)).await??;
+        Ok(val.map(|val| Bytes::copy_from_slice(&val)))
+    }
+
+    pub async fn get_migrating<K, V, L>(&self, key: &K) -> Result<Option<V>, StoreError>
+    where
+        K: Serialize,
+        V: DeserializeOwned + Send + 'static,
+        L: DeserializeOwned + Into<V>,
+    {
+        let key = serialize(key)?;
+        let val = self.run(move |store| store.tree.get(key)).await??;
+
+        match val {
+            None => Ok(None),
+            Some(val) => Ok(Some(decode_migrating::<V, L>(&val)?)),
+        }
+    }
+
+    pub async fn insert<K: Serialize, V: Serialize>(&self, key: K, val: V) -> Result<(), StoreError> {
+        let (key, val) = (serialize(&key)?, serialize(&val)?);
+        self.run(move |store| store.tree.insert(key, val)).await??;
+        Ok(())
+    }
+
+    pub async fn remove<K: Serialize>(&self, key: K) -> Result<(), StoreError> {
+        let key = serialize(&key)?;
+        self.run(move |store| store.tree.remove(key)).await??;
+        Ok(())
+    }
+
+    /// Run a batch of synchronous store operations on the blocking pool.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&Store) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.store.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&store)).await?)
+    }
 }
@@ -0,0 +1,40 @@
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::auxiliaries::store::{AsyncStore, Store, StoreError};
+
+/// Append-only log of entries keyed by block number. An entry is appended
+/// before its block is applied and truncated once the block is durable, so
+/// whatever remains at startup still has to be replayed.
+#[derive(Clone)]
+pub struct WriteAheadLog<V> {
+    log: AsyncStore,
+    phantom: PhantomData<V>,
+}
+
+impl<V: Serialize + DeserializeOwned + Send + 'static> WriteAheadLog<V> {
+    pub fn new(store: &Store, name: &str) -> Result<Self, StoreError> {
+        let log = WriteAheadLog {
+            log: AsyncStore::new(store.open_tree(name)?),
+            phantom: PhantomData,
+        };
+
+        Ok(log)
+    }
+
+    pub async fn append(&self, number: u64, entry: &V) -> Result<(), StoreError> {
+        self.log.insert(number.to_be_bytes(), entry).await?;
+        self.log.run(|store| store.flush()).await??;
+        Ok(())
+    }
+
+    pub async fn truncate(&self, number: u64) -> Result<(), StoreError> {
+        self.log.remove(number.to_be_bytes()).await
+    }
+
+    /// Entries not yet truncated, in block number order.
+    pub async fn pending(&self) -> Result<Vec<V>, StoreError> {
+        self.log.run(|store| store.values()).await?
+    }
+}
@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use layer3::{SnapshotClient, Verifier, VerifierConfig};
+
+// Exits 1 once a block the node published doesn't re-execute to its roots,
+// for a supervisor or cron job to alert on
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let config_path = match (args.next().as_deref(), args.next()) {
+        (None, _) => "./config/verifier.toml".to_owned(),
+        (Some("--config" | "-c"), Some(path)) => path,
+        _ => {
+            eprintln!("usage: covalent-verifier [--config <path>]");
+            std::process::exit(2);
+        }
+    };
+    let config = match VerifierConfig::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let verifier = match Verifier::from_config(&config) {
+        Ok(verifier) => verifier,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("[verifier] following {}", config.snapshot_uri);
+    let client = SnapshotClient::new(config.snapshot_uri.clone());
+    let poll = Duration::from_secs(config.poll_interval_secs);
+    match verifier.watch(&client, poll).await {
+        Ok(mismatch) => eprintln!(
+            "block {} doesn't match its re-execution, {:?}",
+            mismatch.to_block, mismatch.field
+        ),
+        Err(e) => eprintln!("{:#}", e),
+    }
+    std::process::exit(1);
+}
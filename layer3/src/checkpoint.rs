@@ -0,0 +1,475 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use primitive_types::{H160, H256};
+use proof::commitment::{Commitment, ProverType};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::{blake2b, secp256k1_address, sign_recoverable},
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    finality::{FinalityStage, FinalityTracker},
+    scheduler::{Job, Schedule},
+    types::{BlockHeader, Signature},
+};
+
+const CHECKPOINT_TREE: &str = "checkpoint";
+const CHECKPOINT_META_TREE: &str = "checkpoint_meta";
+// Encoded settlement commitment of every checkpoint
+const COMMITMENT_TREE: &str = "checkpoint_commitment";
+const LATEST_KEY: &str = "latest";
+const SETTLED_KEY: &str = "settled";
+
+/// How often the operator checkpoints the state. Disabled unless turned on
+/// in config.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CheckpointPolicy {
+    pub enabled: bool,
+    // A checkpoint is taken at every block whose number is a multiple
+    pub interval_blocks: u64,
+    // Seconds between two submissions of the pending checkpoints to layer2
+    pub submit_interval_secs: u64,
+}
+
+impl Default for CheckpointPolicy {
+    fn default() -> Self {
+        CheckpointPolicy {
+            enabled: false,
+            interval_blocks: 1_000,
+            submit_interval_secs: 30,
+        }
+    }
+}
+
+/// Commitment to the whole channel state after block `number`. Checkpoints
+/// are chained, so a settled one vouches for every checkpoint before it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub number: u64,
+    pub block_hash: H256,
+    pub state_root: H256,
+    // Zero on the first checkpoint
+    pub prev_checkpoint: H256,
+}
+
+impl Checkpoint {
+    pub fn hash(&self) -> H256 {
+        blake2b(&bincode::serialize(self).unwrap())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckpointStage {
+    // Signed by the operator, waiting to be submitted
+    Signed,
+    Submitted,
+    // Accepted by the settlement layer
+    Settled,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CheckpointRecord {
+    pub checkpoint: Checkpoint,
+    // Operator signature over the checkpoint hash
+    pub signature: Signature,
+    pub stage: CheckpointStage,
+    pub l2_tx_hash: Option<H256>,
+    pub ckb_block_number: Option<u64>,
+}
+
+/// Takes operator signed checkpoints of the channel state and tracks their
+/// way into the settlement layer. Fraud and exit proofs never have to reach
+/// behind the latest settled checkpoint, so history up to it can be pruned.
+#[derive(Clone)]
+pub struct CheckpointManager {
+    checkpoints: AsyncStore,
+    meta: AsyncStore,
+    commitments: AsyncStore,
+    operator_key: SecretKey,
+    operator: H160,
+    policy: CheckpointPolicy,
+}
+
+impl CheckpointManager {
+    pub fn new(
+        store: &Store,
+        operator_key: SecretKey,
+        policy: CheckpointPolicy,
+    ) -> Result<Self, StoreError> {
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &operator_key);
+        let manager = CheckpointManager {
+            checkpoints: AsyncStore::new(store.open_tree(CHECKPOINT_TREE)?),
+            meta: AsyncStore::new(store.open_tree(CHECKPOINT_META_TREE)?),
+            commitments: AsyncStore::new(store.open_tree(COMMITMENT_TREE)?),
+            operator_key,
+            operator: secp256k1_address(&pubkey),
+            policy,
+        };
+
+        Ok(manager)
+    }
+
+    pub fn operator(&self) -> H160 {
+        self.operator
+    }
+
+    /// Checkpoint the state of the applied block if it's due.
+    pub async fn on_consensus_receipt(
+        &self,
+        receipt: &ConsensusReceipt,
+    ) -> Result<Option<CheckpointRecord>> {
+        let header = &receipt.block.header;
+        if !self.policy.enabled
+            || !header
+                .number
+                .is_multiple_of(self.policy.interval_blocks.max(1))
+        {
+            return Ok(None);
+        }
+
+        self.take(header).await
+    }
+
+    /// Checkpoint the state after the block of `header`, covering every
+    /// block since the previous checkpoint. Nothing is taken if a checkpoint
+    /// at or after the block exists.
+    pub async fn take(&self, header: &BlockHeader) -> Result<Option<CheckpointRecord>> {
+        let (prev_checkpoint, from_block) = match self.latest().await? {
+            Some(latest) if latest.checkpoint.number >= header.number => return Ok(None),
+            Some(latest) => (latest.checkpoint.hash(), latest.checkpoint.number + 1),
+            None => (H256::zero(), 1),
+        };
+        // Block data is only published in the settlement transaction so far
+        let commitment = Commitment {
+            from_block,
+            to_block: header.number,
+            state_root: header.state_root,
+            receipt_root: header.receipt_root,
+            da_reference: H256::zero(),
+            prover: ProverType::Optimistic,
+        };
+        let checkpoint = Checkpoint {
+            number: header.number,
+            block_hash: header.hash,
+            state_root: header.state_root,
+            prev_checkpoint,
+        };
+        let record = CheckpointRecord {
+            signature: sign_recoverable(&self.operator_key, checkpoint.hash()),
+            checkpoint,
+            stage: CheckpointStage::Signed,
+            l2_tx_hash: None,
+            ckb_block_number: None,
+        };
+
+        self.checkpoints
+            .insert(header.number.to_be_bytes(), &record)
+            .await?;
+        self.commitments
+            .insert(header.number.to_be_bytes(), commitment.to_bytes())
+            .await?;
+        self.meta.insert(LATEST_KEY, header.number).await?;
+        Ok(Some(record))
+    }
+
+    pub async fn get(&self, number: u64) -> Result<Option<CheckpointRecord>> {
+        Ok(self.checkpoints.get(&number.to_be_bytes()).await?)
+    }
+
+    /// Settlement commitment of the checkpoint at `number`, covering the
+    /// blocks since the previous checkpoint. Submitted as `to_bytes`.
+    pub async fn commitment(&self, number: u64) -> Result<Option<Commitment>> {
+        let bytes = {
+            self.commitments
+                .get::<_, Vec<u8>>(&number.to_be_bytes())
+                .await?
+        };
+        bytes
+            .map(|bytes| Commitment::from_bytes(&bytes))
+            .transpose()
+            .map_err(|e| anyhow!("checkpoint {} commitment: {}", number, e))
+    }
+
+    pub async fn latest(&self) -> Result<Option<CheckpointRecord>> {
+        match self.meta.get::<_, u64>(&LATEST_KEY).await? {
+            Some(number) => self.get(number).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Checkpoints waiting to be submitted, oldest first.
+    pub async fn pending(&self) -> Result<Vec<CheckpointRecord>> {
+        let records = self
+            .checkpoints
+            .run(|checkpoints| checkpoints.values::<CheckpointRecord>())
+            .await??;
+
+        Ok({ records.into_iter() }
+            .filter(|record| record.stage == CheckpointStage::Signed)
+            .collect())
+    }
+
+    pub async fn submitted(&self, number: u64, l2_tx_hash: H256) -> Result<()> {
+        self.advance(number, CheckpointStage::Submitted, |record| {
+            record.l2_tx_hash = Some(l2_tx_hash);
+        })
+        .await
+    }
+
+    // Reported by the settlement on CKB, see settlement
+    #[allow(dead_code)]
+    pub async fn settled(&self, number: u64, ckb_block_number: u64) -> Result<()> {
+        self.advance(number, CheckpointStage::Settled, |record| {
+            record.ckb_block_number = Some(ckb_block_number);
+        })
+        .await?;
+
+        if number > self.proof_horizon().await? {
+            self.meta.insert(SETTLED_KEY, number).await?;
+        }
+        Ok(())
+    }
+
+    /// Block of the latest settled checkpoint, 0 without one. Fraud and
+    /// exit proofs only reference blocks after it.
+    pub async fn proof_horizon(&self) -> Result<u64> {
+        Ok(self.meta.get(&SETTLED_KEY).await?.unwrap_or_default())
+    }
+
+    async fn advance<F: FnOnce(&mut CheckpointRecord)>(
+        &self,
+        number: u64,
+        stage: CheckpointStage,
+        update: F,
+    ) -> Result<()> {
+        let mut record = match self.get(number).await? {
+            Some(record) => record,
+            None => return Err(anyhow!("checkpoint {} not found", number)),
+        };
+        if record.stage >= stage {
+            return Err(anyhow!(
+                "checkpoint {} can't move from {:?} to {:?}",
+                number,
+                record.stage,
+                stage
+            ));
+        }
+
+        record.stage = stage;
+        update(&mut record);
+        self.checkpoints
+            .insert(number.to_be_bytes(), &record)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait CheckpointTarget: Sync + Send {
+    /// Hand over a commitment, returns the hash it's kept under. The same
+    /// commitment can be submitted again after a lost answer.
+    async fn submit_commitment(&self, commitment: &Commitment) -> Result<H256>;
+}
+
+/// Submits the pending checkpoints to layer2 oldest first, so each
+/// commitment starts right after the one submitted before it.
+pub struct CheckpointSubmitter<T> {
+    manager: CheckpointManager,
+    target: T,
+    finality: FinalityTracker,
+    policy: CheckpointPolicy,
+}
+
+impl<T: CheckpointTarget> CheckpointSubmitter<T> {
+    pub fn new(
+        manager: CheckpointManager,
+        target: T,
+        finality: FinalityTracker,
+        policy: CheckpointPolicy,
+    ) -> Self {
+        CheckpointSubmitter {
+            manager,
+            target,
+            finality,
+            policy,
+        }
+    }
+
+    /// Submit every pending checkpoint, stopping at the first that fails.
+    /// Returns the numbers of the checkpoints submitted.
+    pub async fn submit(&self) -> Result<Vec<u64>> {
+        let mut submitted = Vec::new();
+        for record in self.manager.pending().await? {
+            let number = record.checkpoint.number;
+            let commitment = match self.manager.commitment(number).await? {
+                Some(commitment) => commitment,
+                None => return Err(anyhow!("checkpoint {} has no commitment", number)),
+            };
+            let l2_tx_hash = self.target.submit_commitment(&commitment).await?;
+
+            // Blocks marked before a failed run are skipped
+            for block in commitment.from_block..=commitment.to_block {
+                let stage = { self.finality.get_block_finality(block).await? }
+                    .map(|finality| finality.stage);
+                if stage == Some(FinalityStage::Produced) {
+                    self.finality.submitted_to_l2(block, l2_tx_hash).await?;
+                }
+            }
+            self.manager.submitted(number, l2_tx_hash).await?;
+            submitted.push(number);
+        }
+
+        if !submitted.is_empty() {
+            println!("[checkpoint] submitted checkpoints {:?}", submitted);
+        }
+        Ok(submitted)
+    }
+}
+
+#[async_trait]
+impl<T: CheckpointTarget> Job for CheckpointSubmitter<T> {
+    fn name(&self) -> &'static str {
+        "checkpoint_submitter"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(self.policy.submit_interval_secs.max(1))
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.submit().await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    };
+
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::common::recover_address,
+        fixture::{consensus_receipt, save_blocks},
+    };
+
+    use super::*;
+
+    fn receipt(number: u64) -> ConsensusReceipt {
+        consensus_receipt(number, vec![], vec![])
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_chain() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let policy = CheckpointPolicy {
+            enabled: true,
+            interval_blocks: 2,
+            ..Default::default()
+        };
+        let key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let manager = CheckpointManager::new(&store, key, policy).unwrap();
+
+        let mut taken = Vec::new();
+        for number in 1..=4 {
+            if let Some(record) = manager
+                .on_consensus_receipt(&receipt(number))
+                .await
+                .unwrap()
+            {
+                taken.push(record);
+            }
+        }
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[0].checkpoint.prev_checkpoint, H256::zero());
+        assert_eq!(
+            taken[1].checkpoint.prev_checkpoint,
+            taken[0].checkpoint.hash()
+        );
+        assert_eq!(
+            recover_address(taken[1].checkpoint.hash(), &taken[1].signature),
+            Some(manager.operator())
+        );
+        assert_eq!(manager.pending().await.unwrap(), taken);
+        let commitment = manager.commitment(4).await.unwrap().unwrap();
+        assert_eq!((commitment.from_block, commitment.to_block), (3, 4));
+        assert_eq!(commitment.state_root, H256::repeat_byte(4));
+        assert!(manager.commitment(3).await.unwrap().is_none());
+
+        manager.submitted(2, H256::repeat_byte(7)).await.unwrap();
+        manager.settled(2, 100).await.unwrap();
+        assert_eq!(manager.proof_horizon().await.unwrap(), 2);
+        assert_eq!(manager.pending().await.unwrap(), vec![taken[1].clone()]);
+        assert!(manager.settled(2, 101).await.is_err());
+    }
+
+    // Takes the commitments it's given unless switched off
+    #[derive(Default)]
+    struct SwitchedTarget {
+        down: AtomicBool,
+        taken: Mutex<Vec<(u64, u64)>>,
+    }
+
+    #[async_trait]
+    impl CheckpointTarget for SwitchedTarget {
+        async fn submit_commitment(&self, commitment: &Commitment) -> Result<H256> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(anyhow!("layer2 is down"));
+            }
+
+            let mut taken = self.taken.lock().unwrap();
+            taken.push((commitment.from_block, commitment.to_block));
+            Ok(H256::from_low_u64_be(commitment.to_block))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_checkpoints() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        save_blocks(&store, 4, |_| 0, None).await;
+        let policy = CheckpointPolicy {
+            enabled: true,
+            interval_blocks: 2,
+            ..Default::default()
+        };
+        let key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let manager = CheckpointManager::new(&store, key, policy.clone()).unwrap();
+        for number in 1..=4 {
+            manager
+                .on_consensus_receipt(&receipt(number))
+                .await
+                .unwrap();
+        }
+        let finality = FinalityTracker::new(store.clone(), 0).unwrap();
+        let target = SwitchedTarget::default();
+        let submitter = CheckpointSubmitter::new(manager, target, finality.clone(), policy);
+
+        submitter.target.down.store(true, Ordering::SeqCst);
+        assert!(submitter.submit().await.is_err());
+        assert_eq!(submitter.manager.pending().await.unwrap().len(), 2);
+
+        submitter.target.down.store(false, Ordering::SeqCst);
+        assert_eq!(submitter.submit().await.unwrap(), vec![2, 4]);
+        assert_eq!(
+            *submitter.target.taken.lock().unwrap(),
+            vec![(1, 2), (3, 4)]
+        );
+        assert!(submitter.manager.pending().await.unwrap().is_empty());
+        let record = submitter.manager.get(4).await.unwrap().unwrap();
+        assert_eq!(record.stage, CheckpointStage::Submitted);
+        assert_eq!(record.l2_tx_hash, Some(H256::from_low_u64_be(4)));
+        let block = finality.get_block_finality(3).await.unwrap().unwrap();
+        assert_eq!(block.stage, FinalityStage::SubmittedToL2);
+        assert_eq!(block.l2_tx_hash, Some(H256::from_low_u64_be(4)));
+        assert!(submitter.submit().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,520 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+};
+
+use secp256k1::SecretKey;
+use serde::{Deserialize, Serialize};
+use share::front::{RpcFront, TlsConfig};
+
+use crate::{
+    auxiliaries::{
+        mempool::{AdmissionPolicy, PackagePolicy},
+        oracle::OraclePolicy,
+        snapshot::SnapshotPolicy,
+    },
+    checkpoint::CheckpointPolicy,
+    cosigner::CosignerPolicy,
+    faucet::FaucetPolicy,
+    genesis::{GenesisToken, TokenRegistry},
+    guardian::GuardianPolicy,
+    health::HealthPolicy,
+    opening::OpenPolicy,
+    prune::PrunePolicy,
+    rebalance::RebalancePolicy,
+    retention::RetentionPolicy,
+    scheduler::SchedulerPolicy,
+    settlement::SettlementPolicy,
+    withdrawal::BatchPolicy,
+};
+
+const ENV_PREFIX: &str = "COVALENT_L3_";
+const VERIFIER_ENV_PREFIX: &str = "COVALENT_VERIFIER_";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("read config {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("parse config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("{field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+fn invalid(field: &'static str, reason: impl ToString) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.to_string(),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Config {
+    // Network the node accepts transactions for
+    pub chain_id: u64,
+    pub db_path: PathBuf,
+    pub rpc_uri: SocketAddr,
+    #[serde(default)]
+    pub api: ApiPolicy,
+    // Bearer tokens of the operator tools allowed on the `/admin` routes of
+    // the API, which refuses them while there are none
+    #[serde(default)]
+    pub admin_tokens: Vec<String>,
+    // Serves snapshot chunks to followers
+    pub snapshot_uri: SocketAddr,
+    #[serde(default)]
+    pub snapshot: SnapshotPolicy,
+    // Operator RPC of layer2, checkpoints and withdrawals are submitted to it
+    pub layer2_rpc_uri: String,
+    // Hex encoded secp256k1 key of the operator
+    pub operator_key_path: PathBuf,
+    // In CKB blocks
+    pub challenge_window: u64,
+    #[serde(default)]
+    pub package: PackagePolicy,
+    #[serde(default)]
+    pub admission: AdmissionPolicy,
+    #[serde(default)]
+    pub rebalance: RebalancePolicy,
+    #[serde(default)]
+    pub prune: PrunePolicy,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    #[serde(default)]
+    pub checkpoint: CheckpointPolicy,
+    #[serde(default)]
+    pub withdrawal_batch: BatchPolicy,
+    #[serde(default)]
+    pub guardian: GuardianPolicy,
+    #[serde(default)]
+    pub open: OpenPolicy,
+    #[serde(default)]
+    pub settlement: SettlementPolicy,
+    // Deposits seen on L1
+    #[serde(default)]
+    pub oracle: OraclePolicy,
+    #[serde(default)]
+    pub scheduler: SchedulerPolicy,
+    #[serde(default)]
+    pub cosigner: CosignerPolicy,
+    #[serde(default)]
+    pub health: HealthPolicy,
+    // Testnet faucet opening small test channels
+    #[serde(default)]
+    pub faucet: FaucetPolicy,
+    // Genesis token list with the L1 sUDT each token is bound to
+    #[serde(default)]
+    pub tokens: Vec<GenesisToken>,
+}
+
+/// How the API at `rpc_uri` takes connections, through the same front as
+/// the public layer2 RPC.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ApiPolicy {
+    // Peers whose X-Forwarded-For names the client, like a load balancer
+    pub trusted_proxies: Vec<IpAddr>,
+    // Requests per second of every client address, 0 means unlimited
+    pub client_rate_limit: u32,
+    // Serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+}
+
+impl ApiPolicy {
+    /// None when the API takes connections itself.
+    pub fn front(&self) -> anyhow::Result<Option<RpcFront>> {
+        RpcFront::new(
+            self.tls.as_ref(),
+            &self.trusted_proxies,
+            self.client_rate_limit,
+        )
+    }
+}
+
+impl Config {
+    /// Read the config file, apply `COVALENT_L3_<FIELD>` environment
+    /// overrides and validate the result.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let raw = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        let file: toml::Value = toml::from_str(&raw)?;
+        let config: Config = apply_env_overrides(file, ENV_PREFIX, std::env::vars())?.try_into()?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.chain_id == 0 {
+            return Err(invalid("chain_id", "must not be 0"));
+        }
+        if self.challenge_window == 0 {
+            return Err(invalid(
+                "challenge_window",
+                "must be at least 1 block, otherwise withdrawals are never challengeable",
+            ));
+        }
+        if self.package.block_limit == 0
+            || self.package.sender_quota == 0
+            || self.package.interval_ms == 0
+        {
+            return Err(invalid(
+                "package",
+                "block_limit, sender_quota and interval_ms must be at least 1",
+            ));
+        }
+        if self.admission.max_tx_bytes == 0 {
+            return Err(invalid("admission", "max_tx_bytes must be at least 1"));
+        }
+        if self.rebalance.target_bps > 10_000 || self.rebalance.threshold_bps == 0 {
+            return Err(invalid(
+                "rebalance",
+                "target_bps must be at most 10000 and threshold_bps at least 1",
+            ));
+        }
+        if self.withdrawal_batch.max_transfers == 0 {
+            return Err(invalid(
+                "withdrawal_batch",
+                "max_transfers must be at least 1",
+            ));
+        }
+        if self.retention.enabled
+            && (self.retention.interval_secs == 0
+                || (self.retention.max_age_secs == 0 && self.retention.max_bytes == 0))
+        {
+            return Err(invalid(
+                "retention",
+                "interval_secs must be at least 1, and max_age_secs or max_bytes set",
+            ));
+        }
+        if self.checkpoint.interval_blocks == 0 {
+            return Err(invalid("checkpoint", "interval_blocks must be at least 1"));
+        }
+        if let Some(uri) = &self.settlement.ckb_rpc_uri {
+            if !uri.starts_with("http://") {
+                return Err(invalid(
+                    "settlement",
+                    format!("ckb_rpc_uri {} is not an http url", uri),
+                ));
+            }
+        }
+        if let Some(uri) = &self.oracle.ckb_rpc_uri {
+            if !uri.starts_with("http://") {
+                return Err(invalid(
+                    "oracle",
+                    format!("ckb_rpc_uri {} is not an http url", uri),
+                ));
+            }
+        }
+        if self.oracle.max_blocks == 0 {
+            return Err(invalid("oracle", "max_blocks must be at least 1"));
+        }
+        if let Some(batch) = &self.settlement.batch {
+            if self.settlement.ckb_rpc_uri.is_none() || self.checkpoint.enabled {
+                return Err(invalid(
+                    "settlement",
+                    "batch needs ckb_rpc_uri for fee rates, and replaces the enabled checkpoint interval",
+                ));
+            }
+            if batch.max_delay_secs == 0 || batch.min_delay_secs > batch.max_delay_secs {
+                return Err(invalid(
+                    "settlement",
+                    "batch max_delay_secs must be at least 1 and at least min_delay_secs",
+                ));
+            }
+        }
+        for (name, schedule) in self.scheduler.jobs.iter() {
+            schedule
+                .validate()
+                .map_err(|e| invalid("scheduler", format!("{}: {}", name, e)))?;
+        }
+        if self.cosigner.enabled {
+            if self.cosigner.api_tokens.is_empty()
+                || self
+                    .cosigner
+                    .api_tokens
+                    .iter()
+                    .any(|token| token.len() < 16)
+            {
+                return Err(invalid(
+                    "cosigner",
+                    "api_tokens must not be empty, and each at least 16 characters",
+                ));
+            }
+            match self.cosigner.uri {
+                Some(uri) if [self.rpc_uri, self.snapshot_uri].contains(&uri) => {
+                    return Err(invalid(
+                        "cosigner",
+                        format!("uri {} collides with rpc_uri or snapshot_uri", uri),
+                    ))
+                }
+                Some(_) => (),
+                None => return Err(invalid("cosigner", "uri must be set")),
+            }
+            self.cosigner_key()?;
+        }
+        if self.admin_tokens.iter().any(|token| token.len() < 16) {
+            return Err(invalid(
+                "admin_tokens",
+                "each must be at least 16 characters",
+            ));
+        }
+        if self.faucet.enabled && (self.faucet.amount == 0 || self.tokens.is_empty()) {
+            return Err(invalid(
+                "faucet",
+                "amount must be at least 1, and tokens list the token test channels hold",
+            ));
+        }
+        if let Some(tls) = &self.api.tls {
+            tls.load()
+                .map_err(|e| invalid("api", format!("tls {:#}", e)))?;
+        }
+        if self.rpc_uri.port() == self.snapshot_uri.port() {
+            return Err(invalid(
+                "snapshot_uri",
+                format!("port {} collides with rpc_uri", self.snapshot_uri.port()),
+            ));
+        }
+        for (field, addr) in [
+            ("rpc_uri", self.rpc_uri),
+            ("snapshot_uri", self.snapshot_uri),
+        ] {
+            if addr.port() == 0 {
+                return Err(invalid(field, format!("{} has no port", addr)));
+            }
+            TcpListener::bind(addr)
+                .map_err(|e| invalid(field, format!("{} is not available, {}", addr, e)))?;
+        }
+        if !self.layer2_rpc_uri.starts_with("http://") {
+            return Err(invalid(
+                "layer2_rpc_uri",
+                format!("{} is not an http url", self.layer2_rpc_uri),
+            ));
+        }
+
+        self.token_registry()?;
+        self.operator_key()?;
+        check_writable(&self.db_path)
+    }
+
+    pub fn token_registry(&self) -> Result<TokenRegistry, ConfigError> {
+        TokenRegistry::new(&self.tokens).map_err(|e| invalid("tokens", e))
+    }
+
+    pub fn operator_key(&self) -> Result<SecretKey, ConfigError> {
+        read_secret_key("operator_key_path", &self.operator_key_path)
+    }
+
+    pub fn cosigner_key(&self) -> Result<SecretKey, ConfigError> {
+        match &self.cosigner.key_path {
+            Some(path) => read_secret_key("cosigner", path),
+            None => Err(invalid("cosigner", "key_path must be set")),
+        }
+    }
+}
+
+/// Config of the `covalent-verifier` binary, which re-executes the blocks a
+/// node publishes at `snapshot_uri` on a store of its own at `db_path`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VerifierConfig {
+    pub chain_id: u64,
+    pub db_path: PathBuf,
+    // The snapshot server of the node, e.g. "http://127.0.0.1:8101"
+    pub snapshot_uri: String,
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    // The genesis token list of the node, its dust limits are enforced
+    #[serde(default)]
+    pub tokens: Vec<GenesisToken>,
+}
+
+fn default_poll_interval_secs() -> u64 {
+    10
+}
+
+impl VerifierConfig {
+    /// Read the config file, apply `COVALENT_VERIFIER_<FIELD>` environment
+    /// overrides and validate the result.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let raw = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        let file: toml::Value = toml::from_str(&raw)?;
+        let config: VerifierConfig =
+            apply_env_overrides(file, VERIFIER_ENV_PREFIX, std::env::vars())?.try_into()?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.chain_id == 0 {
+            return Err(invalid("chain_id", "must not be 0"));
+        }
+        if !self.snapshot_uri.starts_with("http://") {
+            return Err(invalid(
+                "snapshot_uri",
+                format!("{} is not an http url", self.snapshot_uri),
+            ));
+        }
+        if self.poll_interval_secs == 0 {
+            return Err(invalid("poll_interval_secs", "must be at least 1"));
+        }
+
+        self.token_registry()?;
+        check_writable(&self.db_path)
+    }
+
+    pub fn token_registry(&self) -> Result<TokenRegistry, ConfigError> {
+        TokenRegistry::new(&self.tokens).map_err(|e| invalid("tokens", e))
+    }
+}
+
+fn read_secret_key(field: &'static str, path: &Path) -> Result<SecretKey, ConfigError> {
+    let raw = fs::read_to_string(path)
+        .map_err(|e| invalid(field, format!("{} can't be read, {}", path.display(), e)))?;
+
+    let bytes = hex::decode(raw.trim().trim_start_matches("0x"))
+        .map_err(|e| invalid(field, format!("key is not hex, {}", e)))?;
+    SecretKey::from_slice(&bytes).map_err(|e| invalid(field, e))
+}
+
+/// Override top level fields with `<prefix><FIELD>` environment variables.
+/// Values are parsed as toml when possible and taken as strings otherwise.
+pub fn apply_env_overrides(
+    mut config: toml::Value,
+    prefix: &str,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<toml::Value, ConfigError> {
+    let table = config
+        .as_table_mut()
+        .ok_or_else(|| invalid("config", "must be a toml table"))?;
+
+    for (key, raw) in vars {
+        let field = match key.strip_prefix(prefix) {
+            Some(field) if !field.is_empty() => field.to_lowercase(),
+            _ => continue,
+        };
+
+        let value = toml::from_str::<toml::value::Table>(&format!("v = {}", raw))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or(toml::Value::String(raw));
+        table.insert(field, value);
+    }
+
+    Ok(config)
+}
+
+fn check_writable(path: &Path) -> Result<(), ConfigError> {
+    fs::create_dir_all(path).map_err(|e| {
+        invalid(
+            "db_path",
+            format!("{} can't be created, {}", path.display(), e),
+        )
+    })?;
+
+    let probe = path.join(".write_probe");
+    fs::write(&probe, b"").map_err(|e| {
+        invalid(
+            "db_path",
+            format!("{} is not writable, {}", path.display(), e),
+        )
+    })?;
+    let _ = fs::remove_file(probe);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn write_config(dir: &Path, rpc_port: u16, snapshot_port: u16) -> PathBuf {
+        let key_path = dir.join("operator.key");
+        fs::write(&key_path, hex::encode([1u8; 32])).unwrap();
+
+        let config = format!(
+            r#"
+            chain_id = 1
+            db_path = "{}"
+            rpc_uri = "127.0.0.1:{}"
+            snapshot_uri = "127.0.0.1:{}"
+            layer2_rpc_uri = "http://127.0.0.1:8000"
+            operator_key_path = "{}"
+            challenge_window = 100
+            "#,
+            dir.join("data").display(),
+            rpc_port,
+            snapshot_port,
+            key_path.display(),
+        );
+        let path = dir.join("layer3.toml");
+        fs::write(&path, config).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_validate_and_env_overrides() {
+        let tmp_dir = tempdir().unwrap();
+        let path = write_config(tmp_dir.path(), 18120, 18121);
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.challenge_window, 100);
+
+        let file: toml::Value = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let vars = vec![
+            ("COVALENT_L3_CHALLENGE_WINDOW".to_owned(), "0".to_owned()),
+            ("COVALENT_L3_DB_PATH".to_owned(), "/tmp/l3".to_owned()),
+            ("OTHER".to_owned(), "1".to_owned()),
+        ];
+        let config: Config = apply_env_overrides(file, ENV_PREFIX, vars.into_iter())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(config.db_path, PathBuf::from("/tmp/l3"));
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Invalid {
+                field: "challenge_window",
+                ..
+            })
+        ));
+
+        let path = write_config(tmp_dir.path(), 18122, 18122);
+        assert!(matches!(
+            Config::load(path),
+            Err(ConfigError::Invalid {
+                field: "snapshot_uri",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_load_verifier_config() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("verifier.toml");
+        let config = format!(
+            r#"
+            chain_id = 1
+            db_path = "{}"
+            snapshot_uri = "http://127.0.0.1:8101"
+            "#,
+            tmp_dir.path().join("data").display(),
+        );
+        fs::write(&path, &config).unwrap();
+        let loaded = VerifierConfig::load(&path).unwrap();
+        assert_eq!(loaded.poll_interval_secs, 10);
+
+        fs::write(&path, config.replace("http://", "")).unwrap();
+        assert!(matches!(
+            VerifierConfig::load(&path),
+            Err(ConfigError::Invalid {
+                field: "snapshot_uri",
+                ..
+            })
+        ));
+    }
+}
@@ -1,41 +1,468 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Result;
-use primitive_types::H256;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use primitive_types::{H160, H256, U128, U256};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     auxiliaries::{
-        mempool::{ChannelMap, MemPool},
-        store::Store,
+        chain::{Chain, ChannelChain},
+        common::{cbmt_merkle_root, recover_address, secp256k1_address, sign_recoverable, H256Ext},
+        index::ChannelIndexes,
+        mempool::{ChannelMap, ExpiredTransaction, MemPool},
+        receipt::{ReceiptStream, StreamedReceipt},
+        smt::SMT,
+        store::{AsyncStore, Store},
+        wal::WriteAheadLog,
     },
-    executor::{ChannelExecutor, ExecutionReceipt, Executor},
-    types::{Block, Channel, TransactionReceipt},
+    executor::{ChannelExecutor, Executor},
+    notify::Notifier,
+    scheduler::{Job, Schedule},
+    types::{Block, BlockHeader, Channel, DustLimits, Signature},
+    usage::BlockUsage,
 };
 
+const RECEIPT_LOG_TREE: &str = "consensus_receipt_log";
+const BLOCK_USAGE_TREE: &str = "block_usage";
+
+/// A produced block together with the outcome of executing it. The block is
+/// shared so that saving it to the chain and resetting the mempool don't
+/// copy the transaction list.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ConsensusReceipt {
-    pub block: Block,
+    pub block: Arc<Block>,
+    // Who produced the block, and the round and quorum that committed it. A
+    // single operator proposes every block in round 0 and is its own quorum
+    pub proposer: H160,
+    pub round: u64,
+    pub commit_signatures: Vec<CommitSignature>,
 
-    // Cache
-    pub transaction_receipts: Vec<TransactionReceipt>,
+    // Cache, transaction receipts are streamed to `ReceiptStream` instead
     pub updated_channels: BTreeMap<H256, Channel>,
 }
 
-pub trait Consensus {
-    fn produce_block(&self) -> Result<ConsensusReceipt>;
+/// Signature of a validator over the block hash.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CommitSignature {
+    pub validator: H160,
+    pub signature: Signature,
+}
+
+impl ConsensusReceipt {
+    /// Validators that committed the block, fails on a signature that
+    /// doesn't recover to its validator or a validator signing twice.
+    pub fn verify_commit(&self) -> Result<Vec<H160>> {
+        let hash = self.block.header.hash;
+        let mut signers = Vec::with_capacity(self.commit_signatures.len());
+        for commit in self.commit_signatures.iter() {
+            if recover_address(hash, &commit.signature) != Some(commit.validator) {
+                return Err(anyhow!(
+                    "invalid commit signature of {:?} on block {}",
+                    commit.validator,
+                    self.block.header.number
+                ));
+            }
+            if signers.contains(&commit.validator) {
+                return Err(anyhow!("{:?} committed twice", commit.validator));
+            }
+            signers.push(commit.validator);
+        }
+
+        Ok(signers)
+    }
+
+    /// Whether the proposer of both receipts signed two different blocks
+    /// for the same height and round.
+    // Slashing evidence of a validator set, the node runs a single operator
+    #[allow(dead_code)]
+    pub fn equivocates(&self, other: &ConsensusReceipt) -> bool {
+        self.proposer == other.proposer
+            && self.round == other.round
+            && self.block.header.number == other.block.header.number
+            && self.block.header.hash != other.block.header.hash
+    }
+}
+
+#[async_trait]
+pub trait Consensus: Sync + Send {
+    async fn produce_block(&self) -> Result<ConsensusReceipt>;
+    async fn apply_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()>;
 }
 
 pub struct ChannelConsensus {
     mempool: ChannelMap,
-    store: Store,
+    store: AsyncStore,
+    chain: ChannelChain,
+    indexes: ChannelIndexes,
+    receipt_log: WriteAheadLog<ConsensusReceipt>,
+    receipts: ReceiptStream,
+    usage: AsyncStore,
+    chain_id: u64,
+    // Signs produced blocks, which are left unsigned without it
+    operator_key: Option<SecretKey>,
+    dust: HashMap<U256, DustLimits>,
+    // Tells the watchers of evicted transactions
+    notifier: Option<Notifier>,
+}
+
+impl ChannelConsensus {
+    pub fn new(mempool: ChannelMap, store: Store, chain_id: u64) -> Result<Self> {
+        let chain = ChannelChain::new(store.clone())?;
+        let indexes = ChannelIndexes::open(&store)?;
+        let receipt_log = WriteAheadLog::new(&store, RECEIPT_LOG_TREE)?;
+        let receipts = ReceiptStream::new(&store)?;
+        let usage = AsyncStore::new(store.open_tree(BLOCK_USAGE_TREE)?);
+
+        Ok(Self {
+            mempool,
+            store: AsyncStore::new(store),
+            chain,
+            indexes,
+            receipt_log,
+            receipts,
+            usage,
+            chain_id,
+            operator_key: None,
+            dust: HashMap::new(),
+            notifier: None,
+        })
+    }
+
+    pub fn with_operator_key(mut self, operator_key: SecretKey) -> Self {
+        self.operator_key = Some(operator_key);
+        self
+    }
+
+    /// Per token dust limits of the genesis spec, enforced by the executor.
+    pub fn with_dust_limits(mut self, dust: HashMap<U256, DustLimits>) -> Self {
+        self.dust = dust;
+        self
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub fn receipt_stream(&self) -> &ReceiptStream {
+        &self.receipts
+    }
+
+    pub async fn get_block_usage(&self, number: u64) -> Result<Option<BlockUsage>> {
+        Ok(self.usage.get(&number.to_be_bytes()).await?)
+    }
+
+    /// Usage of up to `limit` blocks starting at `from`.
+    pub async fn block_usage_range(&self, from: u64, limit: usize) -> Result<Vec<BlockUsage>> {
+        Ok(self
+            .usage
+            .run(move |usage| usage.values_from(&from.to_be_bytes(), limit))
+            .await??)
+    }
+
+    /// Evict the expired transactions of the mempool, with the version
+    /// their channels are at.
+    pub async fn evict_expired(&self) -> Result<Vec<ExpiredTransaction>> {
+        let mut expired = self.mempool.evict_expired();
+        for tx in expired.iter_mut() {
+            let channel = self.chain.get_channel(tx.channel_id).await?;
+            tx.hint.channel_version = Some(channel.version).filter(|_| channel.id == tx.channel_id);
+        }
+
+        Ok(expired)
+    }
+
+    /// Re-apply receipts that were produced but not applied before the last
+    /// shutdown. Must run before producing new blocks.
+    pub async fn replay_receipt_log(&self) -> Result<usize> {
+        let pending = self.receipt_log.pending().await?;
+        for receipt in pending.iter() {
+            self.apply_consensus_receipt(receipt).await?;
+        }
+
+        Ok(pending.len())
+    }
+}
+
+/// Transactions left unpackaged past their expiry are dropped even when no
+/// block is produced.
+#[async_trait]
+impl Job for ChannelConsensus {
+    fn name(&self) -> &'static str {
+        "mempool_expiry"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(60)
+    }
+
+    async fn run(&self) -> Result<()> {
+        let expired = self.evict_expired().await?;
+        if !expired.is_empty() {
+            println!("[mempool] evicted {} expired transactions", expired.len());
+        }
+        if let Some(notifier) = &self.notifier {
+            for tx in expired.iter() {
+                notifier.on_transaction_expired(tx).await?;
+            }
+        }
+        Ok(())
+    }
 }
 
+#[async_trait]
 impl Consensus for ChannelConsensus {
-    fn produce_block(&self) -> Result<ConsensusReceipt> {
-        let executor = ChannelExecutor::new(self.store.clone());
+    async fn produce_block(&self) -> Result<ConsensusReceipt> {
+        let (number, parent_hash) = match self.chain.tip_header().await? {
+            Some(tip) => (tip.number + 1, tip.hash),
+            None => (1, H256::zero()),
+        };
 
         let txs = self.mempool.package_transactions()?;
-        let exec_receipt = executor.exec(&txs.iter().map(|s| s.raw).collect())?;
+        let chain_id = self.chain_id;
+        let receipts = self.receipts.clone();
+        let dust = self.dust.clone();
+        let (txs, exec_summary) = self
+            .store
+            .run(move |store| {
+                let executor = ChannelExecutor::new(store.clone(), chain_id)
+                    .with_block_number(number)
+                    .with_dust_limits(dust);
+                let exec_summary = executor.exec_streaming(&txs, &mut |idx, receipt| {
+                    let streamed = StreamedReceipt {
+                        block_number: number,
+                        index: idx as u32,
+                        tx_hash: txs[idx].hash,
+                        receipt,
+                        payment_id: txs[idx].raw.payment_id(),
+                    };
+                    Ok(receipts.publish(streamed)?)
+                });
+                (txs, exec_summary)
+            })
+            .await?;
+        let exec_summary = exec_summary?;
+
+        let mut header = BlockHeader {
+            number,
+            hash: H256::zero(),
+            parent_hash,
+            timestamp: time_now(),
+            state_root: exec_summary.state_root,
+            transaction_root: cbmt_merkle_root(&txs.iter().map(|tx| tx.hash).collect::<Vec<_>>()),
+            receipt_root: exec_summary.receipt_root,
+        };
+        header.hash = header.calc_hash();
+
+        let (proposer, commit_signatures) = match &self.operator_key {
+            Some(key) => {
+                let proposer =
+                    secp256k1_address(&PublicKey::from_secret_key(&Secp256k1::new(), key));
+                let commit = CommitSignature {
+                    validator: proposer,
+                    signature: sign_recoverable(key, header.hash),
+                };
+                (proposer, vec![commit])
+            }
+            None => (H160::zero(), vec![]),
+        };
+        let receipt = ConsensusReceipt {
+            block: Arc::new(Block { header, txs }),
+            proposer,
+            round: 0,
+            commit_signatures,
+            updated_channels: exec_summary.updated_channels,
+        };
+        self.receipt_log.append(number, &receipt).await?;
+
+        Ok(receipt)
+    }
+
+    async fn apply_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        receipt.verify_commit()?;
+
+        let leaves = { receipt.updated_channels.iter() }
+            .map(|(key, channel)| (key.to_h256(), channel.clone()))
+            .collect::<Vec<_>>();
+
+        let indexes = self.indexes.clone();
+
+        let state_root = self
+            .store
+            .run(move |store| -> Result<H256> {
+                let mut smt = SMT::new_with_store(store.clone())?;
+                for (key, channel) in leaves.iter() {
+                    indexes.update(&smt.get(key)?, channel)?;
+                }
+
+                Ok(smt.update_all(leaves)?.to_h256())
+            })
+            .await??;
+        if state_root != receipt.block.header.state_root {
+            return Err(anyhow!(
+                "state root mismatch, expect {:?}, got {:?}",
+                receipt.block.header.state_root,
+                state_root
+            ));
+        }
+
+        self.chain.save_block(Arc::clone(&receipt.block)).await?;
+        let usage = BlockUsage::of(&receipt.block, self.mempool.policy().block_limit);
+        self.usage
+            .insert(receipt.block.header.number.to_be_bytes(), usage)
+            .await?;
+        self.mempool.reset(&receipt.block)?;
+        self.receipt_log.truncate(receipt.block.header.number).await?;
+
+        Ok(())
+    }
+}
+
+fn time_now() -> U128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::{H160, U256};
+    use tempfile::tempdir;
+
+    use crate::types::{
+        Balance, CreateChannel, ExecutionExitCode, NumberHash, RawTransaction, SignedTransaction,
+    };
+
+    use super::*;
+
+    const CHAIN_ID: u64 = 1;
+
+    fn create_channel_tx(id: u64) -> SignedTransaction {
+        let raw = RawTransaction::CreateChannel(CreateChannel {
+            chain_id: CHAIN_ID,
+            id: id.into(),
+            token: Default::default(),
+            challenge_blocks: 10,
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            balance2: [Balance::default(), Balance::default()],
+            guard: None,
+        });
+
+        SignedTransaction {
+            raw,
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::repeat_byte(1),
+            hash: H256::repeat_byte(id as u8),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_produce_and_apply_block() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let consensus = ChannelConsensus::new(mempool.clone(), store.clone(), CHAIN_ID).unwrap();
+
+        mempool.push_transaction(create_channel_tx(1)).unwrap();
+        let mut stream = consensus.receipt_stream().subscribe();
+        let receipt = consensus.produce_block().await.unwrap();
+
+        // Receipts are out before the block is applied
+        let streamed = stream.try_recv().unwrap();
+        assert_eq!(streamed.block_number, 1);
+        assert_eq!(streamed.tx_hash, create_channel_tx(1).hash);
+        assert_eq!(streamed.receipt.exit_code, ExecutionExitCode::Success);
+        let stored = { consensus.receipt_stream() }
+            .get_receipt(&streamed.tx_hash)
+            .unwrap();
+        assert_eq!(stored.unwrap().index, 0);
+        consensus.apply_consensus_receipt(&receipt).await.unwrap();
+
+        let chain = ChannelChain::new(store).unwrap();
+        let tip = chain.tip_block().await.unwrap().unwrap();
+        assert_eq!(tip.header.number, 1);
+        assert_eq!(tip.header.hash, tip.header.calc_hash());
+        assert!(chain.get_channel(U256::one()).await.unwrap().exists());
+        assert!(chain.get_block(NumberHash::Number(1)).await.unwrap().is_some());
+        assert!(mempool.package_transactions().unwrap().is_empty());
+
+        let receipt = consensus.produce_block().await.unwrap();
+        consensus.apply_consensus_receipt(&receipt).await.unwrap();
+        assert_eq!(receipt.block.header.number, 2);
+        assert_eq!(receipt.block.header.parent_hash, tip.header.hash);
+        assert_eq!(receipt.block.header.state_root, tip.header.state_root);
+
+        let usage = consensus.block_usage_range(1, 10).await.unwrap();
+        assert_eq!(
+            usage.iter().map(|u| u.tx_count).collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+        assert_eq!(usage[0].weight_limit, 200);
+        assert!(usage[0].byte_size > usage[1].byte_size);
+    }
+
+    #[tokio::test]
+    async fn test_replay_unapplied_receipt() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let consensus = ChannelConsensus::new(mempool.clone(), store.clone(), CHAIN_ID).unwrap();
+        mempool.push_transaction(create_channel_tx(1)).unwrap();
+        let produced = consensus.produce_block().await.unwrap();
+        drop(consensus);
+
+        let consensus =
+            ChannelConsensus::new(ChannelMap::new(CHAIN_ID), store.clone(), CHAIN_ID).unwrap();
+        assert_eq!(consensus.replay_receipt_log().await.unwrap(), 1);
+        assert_eq!(consensus.replay_receipt_log().await.unwrap(), 0);
+
+        let chain = ChannelChain::new(store).unwrap();
+        let tip = chain.tip_block().await.unwrap().unwrap();
+        assert_eq!(tip.header.hash, produced.block.header.hash);
+        assert!(chain.get_channel(U256::one()).await.unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn test_commit_signatures() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let consensus = ChannelConsensus::new(ChannelMap::new(CHAIN_ID), store, CHAIN_ID)
+            .unwrap()
+            .with_operator_key(key);
+
+        let mut receipt = consensus.produce_block().await.unwrap();
+        let proposer = secp256k1_address(&PublicKey::from_secret_key(&Secp256k1::new(), &key));
+        assert_eq!(receipt.proposer, proposer);
+        assert_eq!(receipt.verify_commit().unwrap(), vec![proposer]);
+
+        // A conflicting block at the same height and round
+        let mut header = receipt.block.header.clone();
+        header.timestamp += U128::one();
+        header.hash = header.calc_hash();
+        let conflicting = ConsensusReceipt {
+            block: Arc::new(Block {
+                header,
+                txs: vec![],
+            }),
+            proposer,
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: BTreeMap::new(),
+        };
+        assert!(receipt.equivocates(&conflicting));
 
-        todo!()
+        receipt.commit_signatures[0].validator = H160::repeat_byte(9);
+        assert!(consensus.apply_consensus_receipt(&receipt).await.is_err());
     }
 }
@@ -0,0 +1,488 @@
+use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use anyhow::Result;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use primitive_types::{H160, H256, U128, U256};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    api::bearer_authorized,
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        common::{recover_address, secp256k1_address},
+        snapshot::channel_index_key,
+        store::{AsyncStore, Store, StoreError},
+    },
+    offline::{OfflineError, UnsignedTransaction},
+    types::{ChannelState, RawTransaction},
+};
+
+const PROPOSAL_TREE: &str = "cosigner_proposal";
+// (channel id, version) to the sig_msg signed at that version
+const SIGNED_TREE: &str = "cosigner_signed";
+
+/// Custodial second signer holding one participant's key for a hosted
+/// wallet. Disabled unless turned on in config.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct CosignerPolicy {
+    pub enabled: bool,
+    pub uri: Option<SocketAddr>,
+    // Hex encoded secp256k1 key of the custodied participant
+    pub key_path: Option<PathBuf>,
+    // Bearer tokens of the clients allowed to call the API
+    pub api_tokens: Vec<String>,
+    // Empty allows every counterparty and token
+    pub allowed_counterparties: Vec<H160>,
+    pub allowed_tokens: Vec<U256>,
+    // Most the custodied participant pays in one update in base units, 0
+    // means no limit
+    pub max_outflow: u64,
+    // Updates paying less are signed without waiting for a client decision
+    pub auto_approve_below: u64,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum CosignerError {
+    #[error(transparent)]
+    Offline(#[from] OfflineError),
+    #[error("only channel updates are co-signed")]
+    NotAnUpdate,
+    #[error("channel {0} isn't open")]
+    NotOpen(U256),
+    #[error("custodied key isn't a participant of channel {0}")]
+    NotParticipant(U256),
+    #[error("version {0} isn't newer than the channel's {1}")]
+    StaleVersion(u64, u64),
+    #[error("update isn't signed by the counterparty {0:?}")]
+    CounterpartySignature(H160),
+    #[error("balances don't add up to the channel total")]
+    Balance,
+    #[error("unknown proposal {0:?}")]
+    UnknownProposal(H256),
+    #[error("proposal {0:?} is already decided")]
+    Decided(H256),
+    #[error("another update of channel {0} is already signed at version {1}")]
+    Conflict(U256, u64),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum ProposalStatus {
+    Pending,
+    Approved,
+    Denied(String),
+}
+
+/// An update the counterparty signed and asks the custodied participant
+/// to sign, identified by its `sig_msg`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Proposal {
+    pub id: H256,
+    pub channel_id: U256,
+    pub version: u64,
+    pub counterparty: H160,
+    // What the custodied participant pays with the update
+    pub outflow: U128,
+    pub status: ProposalStatus,
+    // Carries both signatures once approved
+    pub unsigned: UnsignedTransaction,
+}
+
+/// Signs channel updates as the custodied participant. The counterparty
+/// signs an `UnsignedTransaction` export first and proposes it; updates the
+/// policy rejects are denied, small ones are signed right away and the
+/// rest wait for an API client to approve or deny them. At most one update
+/// is ever signed per channel version, so the counterparty can't pick
+/// between two states later.
+#[derive(Clone)]
+pub struct Cosigner {
+    proposals: AsyncStore,
+    signed: AsyncStore,
+    chain: ChannelChain,
+    key: SecretKey,
+    address: H160,
+    policy: CosignerPolicy,
+    // Serializes signing, for the one update per version check
+    signing: Arc<Mutex<()>>,
+}
+
+impl Cosigner {
+    pub fn new(store: &Store, key: SecretKey, policy: CosignerPolicy) -> Result<Self, StoreError> {
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &key);
+        let cosigner = Cosigner {
+            proposals: AsyncStore::new(store.open_tree(PROPOSAL_TREE)?),
+            signed: AsyncStore::new(store.open_tree(SIGNED_TREE)?),
+            chain: ChannelChain::new(store.clone())?,
+            key,
+            address: secp256k1_address(&pubkey),
+            policy,
+            signing: Arc::new(Mutex::new(())),
+        };
+
+        Ok(cosigner)
+    }
+
+    pub fn address(&self) -> H160 {
+        self.address
+    }
+
+    /// Check a counter-signed update and decide it if the policy can. The
+    /// same update proposed again returns the recorded proposal.
+    pub async fn propose(&self, unsigned: UnsignedTransaction) -> Result<Proposal> {
+        unsigned.verify().map_err(CosignerError::from)?;
+        let id = unsigned.sig_msg;
+        if let Some(known) = self.proposal(id).await? {
+            return Ok(known);
+        }
+
+        let update = match &unsigned.raw {
+            RawTransaction::UpdateChannel(update) => update,
+            _ => return Err(CosignerError::NotAnUpdate.into()),
+        };
+        let channel = self.chain.get_channel(update.channel_id).await?;
+        if channel.state != ChannelState::Open {
+            return Err(CosignerError::NotOpen(channel.id).into());
+        }
+        let slot = { channel.participant2.iter() }
+            .position(|participant| *participant == self.address)
+            .ok_or(CosignerError::NotParticipant(channel.id))?;
+        if update.version <= channel.version {
+            return Err(CosignerError::StaleVersion(update.version, channel.version).into());
+        }
+        let counterparty = channel.participant2[1 - slot];
+        if recover_address(id, &update.signature2[1 - slot]) != Some(counterparty) {
+            return Err(CosignerError::CounterpartySignature(counterparty).into());
+        }
+        let total = { update.balance2.iter() }.fold(U256::zero(), |accu, b| accu + b.settled);
+        if total != channel.total_balance {
+            return Err(CosignerError::Balance.into());
+        }
+
+        let outflow =
+            { channel.balance2[slot].settled }.saturating_sub(update.balance2[slot].settled);
+        let mut proposal = Proposal {
+            id,
+            channel_id: channel.id,
+            version: update.version,
+            counterparty,
+            outflow,
+            status: ProposalStatus::Pending,
+            unsigned,
+        };
+        if let Err(reason) = self.check_policy(&proposal, channel.token.id) {
+            proposal.status = ProposalStatus::Denied(reason);
+        } else if outflow < self.policy.auto_approve_below.into() {
+            return self.sign(proposal).await;
+        }
+
+        self.proposals.insert(id, &proposal).await?;
+        Ok(proposal)
+    }
+
+    fn check_policy(&self, proposal: &Proposal, token_id: U256) -> Result<(), String> {
+        let policy = &self.policy;
+        if !policy.allowed_counterparties.is_empty()
+            && !policy
+                .allowed_counterparties
+                .contains(&proposal.counterparty)
+        {
+            return Err(format!(
+                "counterparty {:?} isn't allowed",
+                proposal.counterparty
+            ));
+        }
+        if !policy.allowed_tokens.is_empty() && !policy.allowed_tokens.contains(&token_id) {
+            return Err(format!("token {} isn't allowed", token_id));
+        }
+        if policy.max_outflow != 0 && proposal.outflow > policy.max_outflow.into() {
+            return Err(format!(
+                "outflow {} is above the limit of {}",
+                proposal.outflow, policy.max_outflow
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub async fn proposal(&self, id: H256) -> Result<Option<Proposal>> {
+        Ok(self.proposals.get(&id).await?)
+    }
+
+    /// Proposals waiting for a client decision.
+    pub async fn pending(&self) -> Result<Vec<Proposal>> {
+        let proposals: Vec<Proposal> = self.proposals.run(|store| store.values()).await??;
+        Ok({ proposals.into_iter() }
+            .filter(|proposal| proposal.status == ProposalStatus::Pending)
+            .collect())
+    }
+
+    /// Sign a pending proposal. Returns it with both signatures, ready to
+    /// submit.
+    pub async fn approve(&self, id: H256) -> Result<Proposal> {
+        let proposal = self.pending_proposal(id).await?;
+        self.sign(proposal).await
+    }
+
+    pub async fn deny(&self, id: H256, reason: String) -> Result<Proposal> {
+        let mut proposal = self.pending_proposal(id).await?;
+        proposal.status = ProposalStatus::Denied(reason);
+        self.proposals.insert(id, &proposal).await?;
+        Ok(proposal)
+    }
+
+    async fn pending_proposal(&self, id: H256) -> Result<Proposal> {
+        let proposal = { self.proposal(id).await? }.ok_or(CosignerError::UnknownProposal(id))?;
+        if proposal.status != ProposalStatus::Pending {
+            return Err(CosignerError::Decided(id).into());
+        }
+        Ok(proposal)
+    }
+
+    async fn sign(&self, mut proposal: Proposal) -> Result<Proposal> {
+        let _signing = self.signing.lock().await;
+
+        let channel = self.chain.get_channel(proposal.channel_id).await?;
+        if proposal.version <= channel.version {
+            return Err(CosignerError::StaleVersion(proposal.version, channel.version).into());
+        }
+        let key = (channel_index_key(&proposal.channel_id), proposal.version);
+        match self.signed.get::<_, H256>(&key).await? {
+            Some(signed) if signed != proposal.id => {
+                return Err(CosignerError::Conflict(proposal.channel_id, proposal.version).into())
+            }
+            _ => (),
+        }
+
+        let slot = { channel.participant2.iter() }
+            .position(|participant| *participant == self.address)
+            .ok_or(CosignerError::NotParticipant(channel.id))?;
+        proposal.unsigned = proposal
+            .unsigned
+            .sign(&self.key, slot)
+            .map_err(CosignerError::from)?;
+        proposal.status = ProposalStatus::Approved;
+
+        self.signed.insert(key, proposal.id).await?;
+        self.proposals.insert(proposal.id, &proposal).await?;
+        Ok(proposal)
+    }
+
+    /// Serve the client API, every request needs `Authorization: Bearer
+    /// <token>` with one of the configured tokens:
+    ///
+    /// - `POST /proposals` with an `UnsignedTransaction`, the counterparty
+    ///   signature filled in
+    /// - `GET /proposals` lists the pending proposals
+    /// - `GET /proposals/<id>`
+    /// - `POST /proposals/<id>/approve`
+    /// - `POST /proposals/<id>/deny` with the reason as body
+    pub async fn serve(self, addr: SocketAddr) -> Result<()> {
+        let make_svc = make_service_fn(move |_| {
+            let cosigner = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let cosigner = cosigner.clone();
+                    async move { Ok::<_, Infallible>(cosigner.handle(req).await) }
+                }))
+            }
+        });
+
+        Server::try_bind(&addr)?.serve(make_svc).await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if !self.authorized(&req) {
+            return response(StatusCode::UNAUTHORIZED, Body::empty());
+        }
+
+        let method = req.method().clone();
+        let path = req.uri().path().trim_end_matches('/').to_owned();
+        let segments = path.split('/').skip(1).collect::<Vec<_>>();
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+        };
+
+        let result = match (&method, segments.as_slice()) {
+            (&Method::POST, ["proposals"]) => match serde_json::from_slice(&body) {
+                Ok(unsigned) => self.propose(unsigned).await.map(Some),
+                Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+            },
+            (&Method::GET, ["proposals"]) => {
+                return json_response(self.pending().await);
+            }
+            (method, ["proposals", id, action @ ..]) => {
+                let id = match id.trim_start_matches("0x").parse::<H256>() {
+                    Ok(id) => id,
+                    Err(e) => return response(StatusCode::BAD_REQUEST, e.to_string().into()),
+                };
+                match (method, action) {
+                    (&Method::GET, []) => self.proposal(id).await,
+                    (&Method::POST, ["approve"]) => self.approve(id).await.map(Some),
+                    (&Method::POST, ["deny"]) => {
+                        let reason = String::from_utf8_lossy(&body).into_owned();
+                        self.deny(id, reason).await.map(Some)
+                    }
+                    _ => return response(StatusCode::NOT_FOUND, Body::empty()),
+                }
+            }
+            _ => return response(StatusCode::NOT_FOUND, Body::empty()),
+        };
+
+        match result {
+            Ok(None) => response(StatusCode::NOT_FOUND, Body::empty()),
+            Ok(Some(proposal)) => json_response(Ok(proposal)),
+            Err(e) => match e.downcast_ref::<CosignerError>() {
+                Some(CosignerError::UnknownProposal(_)) => {
+                    response(StatusCode::NOT_FOUND, e.to_string().into())
+                }
+                Some(_) => response(StatusCode::UNPROCESSABLE_ENTITY, e.to_string().into()),
+                None => response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string().into()),
+            },
+        }
+    }
+
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        bearer_authorized(req, &self.policy.api_tokens)
+    }
+}
+
+fn json_response<T: Serialize>(result: Result<T>) -> Response<Body> {
+    match result.and_then(|value| Ok(serde_json::to_vec(&value)?)) {
+        Ok(body) => response(StatusCode::OK, body.into()),
+        Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string().into()),
+    }
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::{
+            common::{sign_recoverable, H256Ext},
+            smt::SMT,
+        },
+        types::{Balance, Channel, UpdateChannel},
+    };
+
+    use super::*;
+
+    fn update(version: u64, balances: [u64; 2], counterparty: &SecretKey) -> UnsignedTransaction {
+        let mut update = UpdateChannel {
+            chain_id: 1,
+            channel_id: 7.into(),
+            version,
+            balance2: balances.map(|settled| Balance {
+                settled: settled.into(),
+            }),
+            ..Default::default()
+        };
+        update.signature2[0] = sign_recoverable(counterparty, update.sig_msg());
+        UnsignedTransaction::new(RawTransaction::UpdateChannel(update)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cosign_by_policy() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(&tmp_db_path).unwrap();
+        let counterparty = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let custodied = SecretKey::from_slice(&[2u8; 32]).unwrap();
+        let address_of = |key: &SecretKey| {
+            secp256k1_address(&PublicKey::from_secret_key(&Secp256k1::new(), key))
+        };
+
+        let policy = CosignerPolicy {
+            enabled: true,
+            max_outflow: 50,
+            auto_approve_below: 10,
+            ..Default::default()
+        };
+        let cosigner = Cosigner::new(&store, custodied, policy).unwrap();
+
+        let mut channel = Channel {
+            id: 7.into(),
+            participant2: [address_of(&counterparty), cosigner.address()],
+            state: ChannelState::Open,
+            version: 1,
+            total_balance: 200.into(),
+            balance2: [100u64, 100].map(|settled| Balance {
+                settled: settled.into(),
+            }),
+            ..Default::default()
+        };
+        let mut smt = SMT::new_with_store(store.clone()).unwrap();
+        smt.update(channel.id.to_h256(), channel.clone()).unwrap();
+
+        // Receiving and small payments are signed right away
+        let signed = cosigner
+            .propose(update(2, [95, 105], &counterparty))
+            .await
+            .unwrap();
+        assert_eq!(signed.status, ProposalStatus::Approved);
+        match &signed.unsigned.raw {
+            RawTransaction::UpdateChannel(args) => assert_eq!(args.signature2[1].len(), 65),
+            _ => unreachable!(),
+        }
+
+        // Larger ones wait, above the limit they're denied
+        let pending = cosigner
+            .propose(update(3, [130, 70], &counterparty))
+            .await
+            .unwrap();
+        assert_eq!(pending.status, ProposalStatus::Pending);
+        let denied = cosigner
+            .propose(update(3, [160, 40], &counterparty))
+            .await
+            .unwrap();
+        assert!(matches!(denied.status, ProposalStatus::Denied(_)));
+        let ids = |proposals: Vec<Proposal>| proposals.iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(ids(cosigner.pending().await.unwrap()), vec![pending.id]);
+
+        // Only one update is signed per version
+        let other = cosigner
+            .propose(update(3, [120, 80], &counterparty))
+            .await
+            .unwrap();
+        cosigner.approve(pending.id).await.unwrap();
+        let err = cosigner.approve(other.id).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CosignerError>(),
+            Some(&CosignerError::Conflict(7.into(), 3))
+        );
+        let err = cosigner
+            .deny(pending.id, "late".to_owned())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CosignerError>(),
+            Some(&CosignerError::Decided(pending.id))
+        );
+
+        // Updates not signed by the counterparty, or older than the channel
+        let forged = update(4, [100, 100], &custodied);
+        assert!(cosigner.propose(forged).await.is_err());
+        channel.version = 5;
+        smt.update(channel.id.to_h256(), channel).unwrap();
+        let err = cosigner
+            .propose(update(4, [110, 90], &counterparty))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CosignerError>(),
+            Some(&CosignerError::StaleVersion(4, 5))
+        );
+    }
+}
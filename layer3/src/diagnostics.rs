@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use primitive_types::{H160, H256, U128, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        chain::Chain,
+        mempool::{ChannelMap, MemPool},
+    },
+    types::RawTransaction,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PendingStatus {
+    // In the template of the next block
+    Packaged,
+    // Valid, waiting for block space
+    Queued,
+    // Its version is already used on chain or by an earlier pending tx, it
+    // will fail and has to be replaced
+    Stale,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PendingTxInfo {
+    pub hash: H256,
+    pub version: u64,
+    pub fee: U128,
+    pub status: PendingStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ChannelDiagnostics {
+    pub channel_id: U256,
+    // Version committed on chain
+    pub state_version: u64,
+    // Lowest version a new transaction on this channel can use
+    pub next_version: u64,
+    // In queue order
+    pub pending: Vec<PendingTxInfo>,
+}
+
+/// Answers "why is my transaction stuck" for one sender.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AccountDiagnostics {
+    pub address: H160,
+    pub channels: Vec<ChannelDiagnostics>,
+    // Fee a replacement needs to get into the next block, zero while blocks
+    // aren't full
+    pub suggested_fee: U128,
+}
+
+pub async fn diagnose_account<C: Chain>(
+    chain: &C,
+    mempool: &ChannelMap,
+    address: H160,
+) -> Result<AccountDiagnostics> {
+    let template = mempool.build_block_template()?;
+    let packaged = template
+        .txs
+        .iter()
+        .map(|tx| tx.hash)
+        .collect::<HashSet<_>>();
+    let suggested_fee = if template.txs.len() >= mempool.policy().block_limit {
+        { template.txs.iter() }
+            .map(|tx| tx.fee.saturating_add(U128::one()))
+            .min()
+            .unwrap_or_default()
+    } else {
+        U128::zero()
+    };
+
+    let mut channels = Vec::new();
+    for (channel_id, txs) in mempool.pending_of(address) {
+        let channel = chain.get_channel(channel_id).await?;
+        let mut exists = channel.exists();
+        let mut latest = channel.version;
+
+        let pending = { txs.into_iter() }
+            .map(|tx| {
+                let version = tx.raw.version();
+                let stale = match tx.raw {
+                    RawTransaction::CreateChannel(_) => exists,
+                    _ => version <= latest,
+                };
+                let status = if stale {
+                    PendingStatus::Stale
+                } else if packaged.contains(&tx.hash) {
+                    PendingStatus::Packaged
+                } else {
+                    PendingStatus::Queued
+                };
+                if !stale {
+                    exists = true;
+                    latest = version;
+                }
+
+                PendingTxInfo {
+                    hash: tx.hash,
+                    version,
+                    fee: tx.fee,
+                    status,
+                }
+            })
+            .collect();
+
+        channels.push(ChannelDiagnostics {
+            channel_id,
+            state_version: channel.version,
+            next_version: latest + 1,
+            pending,
+        });
+    }
+
+    Ok(AccountDiagnostics {
+        address,
+        channels,
+        suggested_fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::{
+            chain::ChannelChain, common::H256Ext, mempool::PackagePolicy, smt::SMT, store::Store,
+        },
+        types::{Channel, ChannelState, SignedTransaction, UpdateChannel},
+    };
+
+    use super::*;
+
+    fn update_tx(channel_id: u64, version: u64, fee: u64) -> SignedTransaction {
+        SignedTransaction {
+            raw: RawTransaction::UpdateChannel(UpdateChannel {
+                chain_id: 1,
+                channel_id: channel_id.into(),
+                version,
+                ..Default::default()
+            }),
+            sig: vec![],
+            fee: fee.into(),
+            from: H160::repeat_byte(1),
+            hash: H256::from_low_u64_be(channel_id << 32 | version),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_stuck_transactions() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let channel = Channel {
+            id: 7.into(),
+            state: ChannelState::Open,
+            version: 3,
+            ..Default::default()
+        };
+        SMT::new_with_store(store.clone())
+            .unwrap()
+            .update(channel.id.to_h256(), channel.clone())
+            .unwrap();
+        let chain = ChannelChain::new(store).unwrap();
+
+        let policy = PackagePolicy {
+            block_limit: 2,
+            sender_quota: 2,
+            ..Default::default()
+        };
+        let mempool = ChannelMap::with_policy(1, policy);
+        // Version 3 is already on chain, version 5 fits after version 4
+        for (version, fee) in [(3, 10), (4, 10), (5, 1)] {
+            mempool
+                .push_transaction(update_tx(7, version, fee))
+                .unwrap();
+        }
+
+        let report = diagnose_account(&chain, &mempool, H160::repeat_byte(1))
+            .await
+            .unwrap();
+        let channel = &report.channels[0];
+        assert_eq!((channel.state_version, channel.next_version), (3, 6));
+        let statuses = channel.pending.iter().map(|p| p.status).collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![
+                PendingStatus::Stale,
+                PendingStatus::Packaged,
+                PendingStatus::Queued
+            ]
+        );
+        assert_eq!(report.suggested_fee, 11.into());
+    }
+}
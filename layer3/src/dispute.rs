@@ -0,0 +1,408 @@
+use anyhow::Result;
+use primitive_types::{H160, H256, U256};
+use proof::{cbmt::CbmtProof, ProofError};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::cbmt_leaf_hash,
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    types::{Channel, ChannelState, ChannelV1, SignedTransaction},
+};
+
+const DISPUTE_TREE: &str = "dispute";
+// Never pruned
+const SLASHING_EVIDENCE_TREE: &str = "slashing_evidence";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DisputeRecord {
+    channel: Channel,
+    // Sender of the transaction that moved the channel into challenge
+    challenger: Option<H160>,
+    challenged_version: u64,
+    started_at: u64,
+    // None if the block held no transaction on the channel
+    challenge: Option<StateEvidence>,
+}
+
+// Layout stored before channels had rate guards
+#[derive(Deserialize)]
+struct DisputeRecordV1 {
+    channel: ChannelV1,
+    challenger: Option<H160>,
+    challenged_version: u64,
+    started_at: u64,
+    challenge: Option<StateEvidence>,
+}
+
+impl From<DisputeRecordV1> for DisputeRecord {
+    fn from(record: DisputeRecordV1) -> Self {
+        DisputeRecord {
+            channel: record.channel.into(),
+            challenger: record.challenger,
+            challenged_version: record.challenged_version,
+            started_at: record.started_at,
+            challenge: record.challenge,
+        }
+    }
+}
+
+/// A signed channel state and its proof of inclusion in a block.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateEvidence {
+    pub tx: SignedTransaction,
+    pub block_number: u64,
+    pub block_hash: H256,
+    pub transaction_root: H256,
+    // `CbmtProof` bytes of the transaction hash under `transaction_root`
+    pub tx_proof: Vec<u8>,
+}
+
+impl StateEvidence {
+    /// Evidence of the last transaction on `channel_id` in the block.
+    fn find(receipt: &ConsensusReceipt, channel_id: U256) -> Option<Self> {
+        let txs = &receipt.block.txs;
+        let index = txs
+            .iter()
+            .rposition(|tx| tx.raw.channel_id() == channel_id)?;
+        let leaf_hashes = { txs.iter() }
+            .map(|tx| cbmt_leaf_hash(&tx.hash))
+            .collect::<Vec<_>>();
+        let tx_proof = CbmtProof::build(&leaf_hashes, &[index as u32])?;
+
+        let header = &receipt.block.header;
+        Some(StateEvidence {
+            tx: txs[index].clone(),
+            block_number: header.number,
+            block_hash: header.hash,
+            transaction_root: header.transaction_root,
+            tx_proof: tx_proof.to_bytes(),
+        })
+    }
+
+    pub fn verify(&self) -> Result<(), ProofError> {
+        CbmtProof::from_bytes(&self.tx_proof)?
+            .verify(&self.transaction_root, &[cbmt_leaf_hash(&self.tx.hash)])
+    }
+}
+
+/// Proof that a challenge was raised with a stale state: the state it was
+/// raised with and the newer one both participants signed, which overrode
+/// it within the challenge period.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SlashingEvidence {
+    pub channel_id: U256,
+    pub challenger: H160,
+    pub challenge: StateEvidence,
+    pub response: StateEvidence,
+    pub resolution_block: u64,
+    // Channel state after the response
+    pub state_root: H256,
+}
+
+impl SlashingEvidence {
+    // Checked by whoever takes the evidence to settlement, the node only
+    // archives it
+    #[allow(dead_code)]
+    pub fn verify(&self) -> Result<(), ProofError> {
+        self.challenge.verify()?;
+        self.response.verify()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum RequiredResponse {
+    // Answer with a state of at least `min_version` signed by both
+    // participants
+    CounterUpdate { participant: H160, min_version: u64 },
+    // The challenge period is over, the channel can be closed at `version`
+    Close { version: u64 },
+}
+
+/// One channel in challenge, as shown to operators and watchtowers.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DisputeInfo {
+    pub channel_id: U256,
+    pub participant2: [H160; 2],
+    pub challenger: Option<H160>,
+    // Version the challenge was raised with
+    pub challenged_version: u64,
+    // Latest version on chain, higher once a participant responded
+    pub current_version: u64,
+    pub started_at: u64,
+    pub deadline: u64,
+    // Zero once the deadline passed
+    pub blocks_remaining: u64,
+    pub required_responses: Vec<RequiredResponse>,
+}
+
+impl DisputeRecord {
+    fn info(&self, tip: u64) -> DisputeInfo {
+        let channel = &self.channel;
+        let deadline = self.started_at.saturating_add(channel.challenge_blocks);
+        let blocks_remaining = deadline.saturating_sub(tip);
+
+        let required_responses = if blocks_remaining == 0 {
+            vec![RequiredResponse::Close {
+                version: channel.version,
+            }]
+        } else {
+            { channel.participant2.iter() }
+                .filter(|participant| Some(**participant) != self.challenger)
+                .map(|participant| RequiredResponse::CounterUpdate {
+                    participant: *participant,
+                    min_version: channel.version + 1,
+                })
+                .collect()
+        };
+
+        DisputeInfo {
+            channel_id: channel.id,
+            participant2: channel.participant2,
+            challenger: self.challenger,
+            challenged_version: self.challenged_version,
+            current_version: channel.version,
+            started_at: self.started_at,
+            deadline,
+            blocks_remaining,
+            required_responses,
+        }
+    }
+}
+
+/// Follows channels through their challenge period, so the most urgent
+/// disputes can be listed without scanning the whole state. Challenges
+/// overridden by a newer state are archived as slashing evidence, which
+/// outlives pruning.
+#[derive(Clone)]
+pub struct DisputeTracker {
+    disputes: AsyncStore,
+    evidence: AsyncStore,
+}
+
+impl DisputeTracker {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        let tracker = DisputeTracker {
+            disputes: AsyncStore::new(store.open_tree(DISPUTE_TREE)?),
+            evidence: AsyncStore::new(store.open_tree(SLASHING_EVIDENCE_TREE)?),
+        };
+
+        Ok(tracker)
+    }
+
+    pub async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        let block_number = receipt.block.header.number;
+        for channel in receipt.updated_channels.values() {
+            let record = self.record(channel.id).await?;
+            if let Some(record) = &record {
+                // Only the first newer state answers the challenge
+                if record.channel.version == record.challenged_version
+                    && channel.version > record.challenged_version
+                {
+                    self.archive_evidence(receipt, record, channel).await?;
+                }
+            }
+
+            if channel.state != ChannelState::Challenge {
+                self.disputes.remove(channel.id).await?;
+                continue;
+            }
+
+            let record = match record {
+                Some(record) => DisputeRecord {
+                    channel: channel.clone(),
+                    ..record
+                },
+                None => {
+                    let challenge = StateEvidence::find(receipt, channel.id);
+                    DisputeRecord {
+                        channel: channel.clone(),
+                        challenger: challenge.as_ref().map(|evidence| evidence.tx.from),
+                        challenged_version: channel.version,
+                        started_at: block_number,
+                        challenge,
+                    }
+                }
+            };
+            self.disputes.insert(channel.id, &record).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn archive_evidence(
+        &self,
+        receipt: &ConsensusReceipt,
+        record: &DisputeRecord,
+        channel: &Channel,
+    ) -> Result<()> {
+        let (challenge, challenger) = match (&record.challenge, record.challenger) {
+            (Some(challenge), Some(challenger)) => (challenge.clone(), challenger),
+            _ => return Ok(()),
+        };
+        let response = match StateEvidence::find(receipt, channel.id) {
+            Some(response) => response,
+            None => return Ok(()),
+        };
+
+        let mut archived = self.slashing_evidence(channel.id).await?;
+        archived.push(SlashingEvidence {
+            channel_id: channel.id,
+            challenger,
+            challenge,
+            response,
+            resolution_block: receipt.block.header.number,
+            state_root: receipt.block.header.state_root,
+        });
+        self.evidence.insert(channel.id, &archived).await?;
+
+        Ok(())
+    }
+
+    /// Slashing evidence archived for a channel, oldest first.
+    pub async fn slashing_evidence(&self, channel_id: U256) -> Result<Vec<SlashingEvidence>> {
+        Ok(self.evidence.get(&channel_id).await?.unwrap_or_default())
+    }
+
+    pub async fn get(&self, channel_id: U256, tip: u64) -> Result<Option<DisputeInfo>> {
+        let record = self.record(channel_id).await?;
+        Ok(record.map(|record| record.info(tip)))
+    }
+
+    async fn record(&self, channel_id: U256) -> Result<Option<DisputeRecord>> {
+        Ok(self
+            .disputes
+            .get_migrating::<_, DisputeRecord, DisputeRecordV1>(&channel_id)
+            .await?)
+    }
+
+    /// Channels in challenge at block `tip`, closest deadline first.
+    pub async fn disputes(&self, tip: u64) -> Result<Vec<DisputeInfo>> {
+        let records = self
+            .disputes
+            .run(|disputes| disputes.values_migrating::<DisputeRecord, DisputeRecordV1>())
+            .await??;
+
+        let mut disputes = { records.iter() }
+            .map(|record| record.info(tip))
+            .collect::<Vec<_>>();
+        disputes.sort_by_key(|dispute| (dispute.deadline, dispute.channel_id));
+        Ok(disputes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::{H256, U128};
+    use tempfile::tempdir;
+
+    use crate::{
+        fixture::consensus_receipt,
+        types::{RawTransaction, SignedTransaction, UpdateChannel},
+    };
+
+    use super::*;
+
+    fn channel(id: u64, state: ChannelState, version: u64, challenge_blocks: u64) -> Channel {
+        Channel {
+            id: id.into(),
+            challenge_blocks,
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            state,
+            version,
+            ..Default::default()
+        }
+    }
+
+    fn receipt(number: u64, from: H160, channels: Vec<Channel>) -> ConsensusReceipt {
+        let txs: Vec<_> = { channels.iter() }
+            .map(|channel| SignedTransaction {
+                raw: RawTransaction::UpdateChannel(UpdateChannel {
+                    channel_id: channel.id,
+                    version: channel.version,
+                    ..Default::default()
+                }),
+                sig: vec![],
+                fee: U128::zero(),
+                from,
+                hash: H256::from_low_u64_be(number),
+            })
+            .collect();
+
+        consensus_receipt(number, txs, channels)
+    }
+
+    #[tokio::test]
+    async fn test_disputes_by_deadline() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let tracker = DisputeTracker::new(&store).unwrap();
+        let (alice, bob) = (H160::repeat_byte(1), H160::repeat_byte(2));
+
+        let receipts = [
+            receipt(10, alice, vec![channel(1, ChannelState::Challenge, 4, 100)]),
+            receipt(20, bob, vec![channel(2, ChannelState::Challenge, 7, 50)]),
+            // Bob answers the challenge on channel 1 with a newer state
+            receipt(30, bob, vec![channel(1, ChannelState::Challenge, 5, 100)]),
+            receipt(31, alice, vec![channel(3, ChannelState::Open, 1, 10)]),
+        ];
+        for receipt in receipts.iter() {
+            tracker.on_consensus_receipt(receipt).await.unwrap();
+        }
+
+        let disputes = tracker.disputes(40).await.unwrap();
+        assert_eq!(disputes.len(), 2);
+        let (first, second) = (&disputes[0], &disputes[1]);
+        assert_eq!((first.channel_id, first.blocks_remaining), (2.into(), 30));
+        assert_eq!(
+            first.required_responses,
+            vec![RequiredResponse::CounterUpdate {
+                participant: alice,
+                min_version: 8
+            }]
+        );
+        assert_eq!(second.challenger, Some(alice));
+        assert_eq!((second.challenged_version, second.current_version), (4, 5));
+        assert_eq!(second.deadline, 110);
+
+        let expired = tracker.get(2.into(), 70).await.unwrap().unwrap();
+        assert_eq!(
+            expired.required_responses,
+            vec![RequiredResponse::Close { version: 7 }]
+        );
+
+        // Alice challenged channel 1 with version 4, bob's version 5 overrode it
+        let evidence = tracker.slashing_evidence(1.into()).await.unwrap();
+        assert_eq!(evidence.len(), 1);
+        let evidence = &evidence[0];
+        assert_eq!(
+            (evidence.challenger, evidence.resolution_block),
+            (alice, 30)
+        );
+        assert_eq!(
+            (
+                evidence.challenge.tx.raw.version(),
+                evidence.response.tx.raw.version()
+            ),
+            (4, 5)
+        );
+        assert_eq!(evidence.verify(), Ok(()));
+        let mut forged = evidence.clone();
+        forged.response.tx.hash = H256::repeat_byte(9);
+        assert_eq!(forged.verify(), Err(ProofError::Leaves));
+        assert!(tracker
+            .slashing_evidence(2.into())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let closed = channel(2, ChannelState::Closed, 7, 50);
+        tracker
+            .on_consensus_receipt(&receipt(71, alice, vec![closed]))
+            .await
+            .unwrap();
+        assert_eq!(tracker.disputes(71).await.unwrap().len(), 1);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use anyhow::Result;
 use primitive_types::{H160, H256, U256};
@@ -11,13 +11,14 @@ use sha3::{Digest, Keccak256};
 
 use crate::{
     auxiliaries::{
-        common::{cbmt_merkle_root, H256Ext},
+        common::{cbmt_leaf_hash, cbmt_merkle_root_of_hashes, H256Ext},
         smt::{MemStore, SMT},
-        store::Store,
+        store::{Store, StoreError},
     },
     types::{
-        Channel, ChannelState, CloseChannel, CreateChannel, ExecutionExitCode, RawTransaction,
-        Signature, TransactionReceipt, UpdateChannel,
+        Channel, ChannelGuard, ChannelState, CloseChannel, CreateChannel, DustLimits,
+        ExecutionExitCode, RawTransaction, Signature, SignedTransaction, TransactionReceipt,
+        UpdateChannel,
     },
 };
 
@@ -25,6 +26,8 @@ use crate::{
 pub enum ExecutionError {
     #[error("{0}")]
     SMT(sparse_merkle_tree::error::Error),
+    #[error("receipt sink {0}")]
+    Sink(#[from] StoreError),
 }
 
 impl From<sparse_merkle_tree::error::Error> for ExecutionError {
@@ -33,6 +36,8 @@ impl From<sparse_merkle_tree::error::Error> for ExecutionError {
     }
 }
 
+// Result of `Executor::exec`, which collects receipts for tests and fixtures
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecutionReceipt {
     pub state_root: H256,
@@ -41,54 +46,128 @@ pub struct ExecutionReceipt {
     pub updated_channels: BTreeMap<H256, Channel>,
 }
 
+/// Outcome of a block execution whose receipts were handed to a sink.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExecutionSummary {
+    pub state_root: H256,
+    pub receipt_root: H256,
+    pub updated_channels: BTreeMap<H256, Channel>,
+}
+
 pub trait Executor {
-    fn exec(&self, transactions: &Vec<RawTransaction>) -> Result<ExecutionReceipt, ExecutionError>;
+    /// Execute `transactions`, passing each receipt with its tx index to
+    /// `on_receipt` as soon as it is produced. Only receipt hashes are kept
+    /// for the receipt root.
+    fn exec_streaming(
+        &self,
+        transactions: &[SignedTransaction],
+        on_receipt: &mut dyn FnMut(usize, TransactionReceipt) -> Result<(), ExecutionError>,
+    ) -> Result<ExecutionSummary, ExecutionError>;
+
+    // Consensus streams receipts instead
+    #[allow(dead_code)]
+    fn exec(&self, transactions: &[SignedTransaction]) -> Result<ExecutionReceipt, ExecutionError> {
+        let mut receipts = Vec::with_capacity(transactions.len());
+        let summary = self.exec_streaming(transactions, &mut |_, receipt| {
+            receipts.push(receipt);
+            Ok(())
+        })?;
+
+        Ok(ExecutionReceipt {
+            state_root: summary.state_root,
+            receipt_root: summary.receipt_root,
+            transaction_receipts: receipts,
+            updated_channels: summary.updated_channels,
+        })
+    }
 }
 
 pub struct ChannelExecutor {
     store: Store,
+    chain_id: u64,
+    // Of the block being executed, for rate guard windows
+    block_number: u64,
+    // Per token, tokens without an entry have no limits
+    dust: HashMap<U256, DustLimits>,
 }
 
 impl ChannelExecutor {
-    pub fn new(store: Store) -> Self {
-        Self { store }
+    pub fn new(store: Store, chain_id: u64) -> Self {
+        Self {
+            store,
+            chain_id,
+            block_number: 0,
+            dust: HashMap::new(),
+        }
+    }
+
+    pub fn with_block_number(mut self, block_number: u64) -> Self {
+        self.block_number = block_number;
+        self
+    }
+
+    pub fn with_dust_limits(mut self, dust: HashMap<U256, DustLimits>) -> Self {
+        self.dust = dust;
+        self
+    }
+
+    fn dust_limits(&self, token_id: &U256) -> DustLimits {
+        self.dust.get(token_id).copied().unwrap_or_default()
     }
 }
 
 impl Executor for ChannelExecutor {
-    fn exec(&self, transactions: &Vec<RawTransaction>) -> Result<ExecutionReceipt, ExecutionError> {
+    fn exec_streaming(
+        &self,
+        transactions: &[SignedTransaction],
+        on_receipt: &mut dyn FnMut(usize, TransactionReceipt) -> Result<(), ExecutionError>,
+    ) -> Result<ExecutionSummary, ExecutionError> {
         let snap = MemStore::new(self.store.clone());
         let mut smt = SMT::new_with_store(snap)?;
 
-        let mut receipts = Vec::with_capacity(transactions.len());
-        for tx in transactions {
-            let receipt = match tx {
-                RawTransaction::CreateChannel(args) => create_channel(&mut smt, args)?,
-                RawTransaction::UpdateChannel(args) => update_channel(&mut smt, args)?,
+        let mut receipt_hashes = Vec::with_capacity(transactions.len());
+        for (idx, tx) in transactions.iter().enumerate() {
+            let receipt = match &tx.raw {
+                raw if raw.chain_id() != self.chain_id => {
+                    TransactionReceipt::err_res(ExecutionExitCode::ErrorChainIdMismatch)
+                }
+                RawTransaction::CreateChannel(args) => {
+                    let dust = self.dust_limits(&args.token.id);
+                    create_channel(&mut smt, args, self.block_number, &dust)?
+                }
+                RawTransaction::UpdateChannel(args) => {
+                    update_channel(&mut smt, args, self.block_number, &self.dust)?
+                }
                 RawTransaction::CloseChannel(args) => close_channel(&mut smt, args)?,
             };
-            receipts.push(receipt);
+            receipt_hashes.push(cbmt_leaf_hash(&receipt));
+            on_receipt(idx, receipt)?;
         }
 
-        let exec_receipt = ExecutionReceipt {
+        let summary = ExecutionSummary {
             state_root: smt.root().to_h256(),
-            receipt_root: cbmt_merkle_root(&receipts),
-            transaction_receipts: receipts,
+            receipt_root: cbmt_merkle_root_of_hashes(&receipt_hashes),
             updated_channels: smt.take_store().take_leaves(),
         };
 
-        Ok(exec_receipt)
+        Ok(summary)
     }
 }
 
 fn create_channel(
     smt: &mut SMT<MemStore>,
     args: &CreateChannel,
+    block_number: u64,
+    dust: &DustLimits,
 ) -> Result<TransactionReceipt, ExecutionError> {
     if smt.get(&args.id.to_h256())?.exists() {
         let receipt = TransactionReceipt::err_res(ExecutionExitCode::ErrorChannelExists);
         return Ok(receipt);
     }
+    if !dust.admits_deposit(&args.balance2) {
+        let receipt = TransactionReceipt::err_res(ExecutionExitCode::ErrorDust);
+        return Ok(receipt);
+    }
 
     let total_balance =
         { args.balance2.iter() }.fold(U256::zero(), |accu, balance| accu + balance.settled);
@@ -103,6 +182,7 @@ fn create_channel(
         version: 0u64,
         total_balance,
         balance2: args.balance2.clone(),
+        guard: { args.guard.clone() }.map(|policy| ChannelGuard::new(policy, block_number)),
     };
 
     let root = smt.update(args.id.to_h256(), channel)?;
@@ -114,6 +194,8 @@ fn create_channel(
 fn update_channel(
     smt: &mut SMT<MemStore>,
     args: &UpdateChannel,
+    block_number: u64,
+    dust: &HashMap<U256, DustLimits>,
 ) -> Result<TransactionReceipt, ExecutionError> {
     let channel = smt.get(&args.channel_id.to_h256())?;
     if !channel.exists() {
@@ -133,9 +215,34 @@ fn update_channel(
         return Ok(receipt);
     }
 
+    if let Some(memo) = &args.memo {
+        if !memo.matches(&channel.balance2, &args.balance2) {
+            let receipt = TransactionReceipt::err_res(ExecutionExitCode::ErrorSubAccountMemo);
+            return Ok(receipt);
+        }
+    }
+
+    let limits = dust.get(&channel.token.id).copied().unwrap_or_default();
+    if !limits.admits_update(&channel.balance2, &args.balance2) {
+        let receipt = TransactionReceipt::err_res(ExecutionExitCode::ErrorDust);
+        return Ok(receipt);
+    }
+
+    let guard = match &channel.guard {
+        Some(guard) => match guard.admit(block_number, &channel.balance2, &args.balance2) {
+            Some(guard) => Some(guard),
+            None => {
+                let receipt = TransactionReceipt::err_res(ExecutionExitCode::ErrorRateGuard);
+                return Ok(receipt);
+            }
+        },
+        None => None,
+    };
+
     let updated = Channel {
         version: args.version,
         balance2: args.balance2.clone(),
+        guard,
         ..channel
     };
 
@@ -190,8 +297,8 @@ enum SignatureError {
 
 fn extract_rec_id(rec_id: u8) -> Result<RecoveryId, SignatureError> {
     let param = match rec_id {
-        r if r == 27 => 0,
-        r if r == 28 => 1,
+        27 => 0,
+        28 => 1,
         r => r,
     };
     Ok(RecoveryId::from_i32(param.into())?)
@@ -220,7 +327,7 @@ fn verify_signature2(
         hasher.update(&pk.serialize_uncompressed()[1..]);
         let rec_addr = &hasher.finalize()[12..];
 
-        if !participant2.into_iter().any(|addr| addr.0 == rec_addr) {
+        if !participant2.iter().any(|addr| addr.0 == rec_addr) {
             return Err(SignatureError::ParticipantAddressNotFound);
         }
     }
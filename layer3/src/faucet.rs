@@ -0,0 +1,240 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use primitive_types::{H160, U128, U256};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::{blake2b, secp256k1_address, sign_recoverable},
+        mempool::{ChannelMap, MemPool},
+    },
+    types::{Balance, CreateChannel, RawTransaction, SignedTransaction, Token, TransactionEnvelope},
+};
+
+/// Small test channels the testnet faucet opens between the operator and
+/// any address that asks, once per cooldown. Off unless enabled.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct FaucetPolicy {
+    pub enabled: bool,
+    // Balance of the requester's side, in base units
+    pub amount: u64,
+    pub challenge_blocks: u64,
+    // Seconds an address waits between two channels
+    pub cooldown_secs: u64,
+    // Channels per minute over all addresses, 0 means unlimited
+    pub max_requests_per_minute: u32,
+}
+
+impl Default for FaucetPolicy {
+    fn default() -> Self {
+        FaucetPolicy {
+            enabled: false,
+            amount: 1_000,
+            challenge_blocks: 100,
+            cooldown_secs: 24 * 60 * 60,
+            max_requests_per_minute: 30,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum FaucetError {
+    #[error("faucet is disabled")]
+    Disabled,
+    #[error("address already has a test channel, retry in {0}s")]
+    Cooldown(u64),
+    #[error("faucet is busy, retry in a minute")]
+    Busy,
+    #[error("test channel refused: {0}")]
+    Refused(String),
+}
+
+/// Opens test channels by submitting `CreateChannel` signed by the
+/// operator, the requester only has to receive on it.
+pub struct ChannelFaucet {
+    mempool: ChannelMap,
+    operator_key: SecretKey,
+    operator: H160,
+    chain_id: u64,
+    token: Token,
+    policy: FaucetPolicy,
+    granted: DashMap<H160, Instant>,
+    window: Mutex<(Instant, u32)>,
+}
+
+impl ChannelFaucet {
+    pub fn new(
+        mempool: ChannelMap,
+        chain_id: u64,
+        operator_key: SecretKey,
+        token: Token,
+        policy: FaucetPolicy,
+    ) -> Self {
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &operator_key);
+        ChannelFaucet {
+            mempool,
+            operator: secp256k1_address(&pubkey),
+            operator_key,
+            chain_id,
+            token,
+            policy,
+            granted: DashMap::new(),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    pub fn operator(&self) -> H160 {
+        self.operator
+    }
+
+    /// Submit a test channel between the operator and `address`, returns
+    /// its id. Refused requests don't start the cooldown.
+    pub fn open(&self, address: H160) -> Result<U256, FaucetError> {
+        if !self.policy.enabled {
+            return Err(FaucetError::Disabled);
+        }
+        self.reserve(address)?;
+
+        let opened = self.acquire().and_then(|()| {
+            let tx = self.create_channel_tx(address);
+            let channel_id = match &tx.raw {
+                RawTransaction::CreateChannel(args) => args.id,
+                _ => unreachable!(),
+            };
+            { self.mempool.push_transaction(tx) }
+                .map_err(|e| FaucetError::Refused(e.to_string()))?;
+            Ok(channel_id)
+        });
+        match opened {
+            Ok(channel_id) => {
+                println!("[faucet] test channel {} for {:?}", channel_id, address);
+                Ok(channel_id)
+            }
+            Err(err) => {
+                self.granted.remove(&address);
+                Err(err)
+            }
+        }
+    }
+
+    fn reserve(&self, address: H160) -> Result<(), FaucetError> {
+        let cooldown = Duration::from_secs(self.policy.cooldown_secs);
+        match self.granted.entry(address) {
+            Entry::Occupied(mut last) => {
+                let elapsed = last.get().elapsed();
+                if elapsed < cooldown {
+                    return Err(FaucetError::Cooldown((cooldown - elapsed).as_secs().max(1)));
+                }
+                last.insert(Instant::now());
+            }
+            Entry::Vacant(last) => {
+                last.insert(Instant::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn acquire(&self) -> Result<(), FaucetError> {
+        let limit = self.policy.max_requests_per_minute;
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= Duration::from_secs(60) {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= limit {
+            return Err(FaucetError::Busy);
+        }
+
+        window.1 += 1;
+        Ok(())
+    }
+
+    fn create_channel_tx(&self, address: H160) -> SignedTransaction {
+        let participant2 = [self.operator, address];
+        let seed = bincode::serialize(&(self.chain_id, participant2, time_now_ms())).unwrap();
+        let raw = RawTransaction::CreateChannel(CreateChannel {
+            chain_id: self.chain_id,
+            id: U256::from_little_endian(blake2b(&seed).as_bytes()),
+            token: self.token.clone(),
+            challenge_blocks: self.policy.challenge_blocks,
+            participant2,
+            balance2: [U128::zero(), self.policy.amount.into()].map(|settled| Balance { settled }),
+            guard: None,
+        });
+        let hash = blake2b(&bincode::serialize(&TransactionEnvelope::from(raw.clone())).unwrap());
+
+        SignedTransaction {
+            sig: sign_recoverable(&self.operator_key, hash),
+            fee: 0u64.into(),
+            from: self.operator,
+            hash,
+            raw,
+        }
+    }
+}
+
+fn time_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAIN_ID: u64 = 1;
+
+    fn faucet(mempool: ChannelMap, max_requests_per_minute: u32) -> ChannelFaucet {
+        let policy = FaucetPolicy {
+            enabled: true,
+            max_requests_per_minute,
+            ..Default::default()
+        };
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        ChannelFaucet::new(mempool, CHAIN_ID, key, Token::default(), policy)
+    }
+
+    #[test]
+    fn test_open_test_channels() {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let faucet = faucet(mempool.clone(), 2);
+        let [alice, bob, carol] = [1, 2, 3].map(H160::repeat_byte);
+
+        let channel_id = faucet.open(alice).unwrap();
+        let txs = mempool.package_transactions().unwrap();
+        match &txs[0].raw {
+            RawTransaction::CreateChannel(args) => {
+                assert_eq!(args.id, channel_id);
+                assert_eq!(args.participant2, [faucet.operator, alice]);
+                assert_eq!(args.balance2[1].settled, 1_000.into());
+            }
+            _ => panic!("not a channel creation"),
+        }
+
+        assert!(matches!(faucet.open(alice), Err(FaucetError::Cooldown(_))));
+        faucet.open(bob).unwrap();
+        // The minute's channels are used up, which doesn't start carol's cooldown
+        assert_eq!(faucet.open(carol), Err(FaucetError::Busy));
+        assert!(!faucet.granted.contains_key(&carol));
+        *faucet.window.lock().unwrap() = (Instant::now() - Duration::from_secs(60), 2);
+        faucet.open(carol).unwrap();
+
+        let disabled = ChannelFaucet {
+            policy: FaucetPolicy::default(),
+            ..faucet
+        };
+        assert_eq!(disabled.open(alice), Err(FaucetError::Disabled));
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use primitive_types::H256;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        store::{AsyncStore, Store, StoreError},
+    },
+    types::NumberHash,
+};
+
+const BLOCK_FINALITY_TREE: &str = "block_finality";
+const FINALIZED_TIP_KEY: &str = "finalized_tip";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FinalityStage {
+    Produced,
+    SubmittedToL2,
+    L2Confirmed,
+    CommittedToCkb,
+    Finalized,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BlockFinality {
+    pub number: u64,
+    pub stage: FinalityStage,
+    pub l2_tx_hash: Option<H256>,
+    pub l2_block_number: Option<u64>,
+    pub ckb_tx_hash: Option<H256>,
+    pub ckb_block_number: Option<u64>,
+}
+
+impl BlockFinality {
+    fn produced(number: u64) -> Self {
+        BlockFinality {
+            number,
+            stage: FinalityStage::Produced,
+            l2_tx_hash: None,
+            l2_block_number: None,
+            ckb_tx_hash: None,
+            ckb_block_number: None,
+        }
+    }
+
+    /// Deposits in this block can no longer be reverted.
+    pub fn is_irreversible(&self) -> bool {
+        self.stage == FinalityStage::Finalized
+    }
+}
+
+/// Tracks how far each layer3 block has travelled towards settlement:
+/// produced, submitted to layer2, confirmed on layer2, committed to CKB and
+/// finally out of its challenge window.
+#[derive(Clone)]
+pub struct FinalityTracker {
+    chain: ChannelChain,
+    finality: AsyncStore,
+    // Read once blocks are committed to CKB, see checkpoint
+    #[allow(dead_code)]
+    challenge_window: u64,
+}
+
+impl FinalityTracker {
+    pub fn new(store: Store, challenge_window: u64) -> Result<Self, StoreError> {
+        let tracker = FinalityTracker {
+            finality: AsyncStore::new(store.open_tree(BLOCK_FINALITY_TREE)?),
+            chain: ChannelChain::new(store)?,
+            challenge_window,
+        };
+
+        Ok(tracker)
+    }
+
+    pub async fn get_block_finality(&self, number: u64) -> Result<Option<BlockFinality>> {
+        if let Some(finality) = self.finality.get(&number.to_be_bytes()).await? {
+            return Ok(Some(finality));
+        }
+
+        let produced = { self.chain.get_header(NumberHash::Number(number)).await? }
+            .map(|_| BlockFinality::produced(number));
+        Ok(produced)
+    }
+
+    pub async fn submitted_to_l2(&self, number: u64, l2_tx_hash: H256) -> Result<()> {
+        self.advance(number, FinalityStage::SubmittedToL2, |finality| {
+            finality.l2_tx_hash = Some(l2_tx_hash);
+        })
+        .await
+    }
+
+    // Later stages are reported by the settlement path, see settlement
+    #[allow(dead_code)]
+    pub async fn l2_confirmed(&self, number: u64, l2_block_number: u64) -> Result<()> {
+        self.advance(number, FinalityStage::L2Confirmed, |finality| {
+            finality.l2_block_number = Some(l2_block_number);
+        })
+        .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn committed_to_ckb(
+        &self,
+        number: u64,
+        ckb_tx_hash: H256,
+        ckb_block_number: u64,
+    ) -> Result<()> {
+        self.advance(number, FinalityStage::CommittedToCkb, |finality| {
+            finality.ckb_tx_hash = Some(ckb_tx_hash);
+            finality.ckb_block_number = Some(ckb_block_number);
+        })
+        .await
+    }
+
+    /// Finalize committed blocks whose challenge window has elapsed at
+    /// `ckb_tip`. Blocks settle in order, so this stops at the first block
+    /// that is not yet final. Returns the highest finalized block number.
+    #[allow(dead_code)]
+    pub async fn ckb_tip_updated(&self, ckb_tip: u64) -> Result<u64> {
+        let mut finalized_tip = self.finalized_tip().await?;
+
+        loop {
+            let number = finalized_tip + 1;
+            let mut finality = match self
+                .finality
+                .get::<_, BlockFinality>(&number.to_be_bytes())
+                .await?
+            {
+                Some(finality) if finality.stage == FinalityStage::CommittedToCkb => finality,
+                _ => break,
+            };
+
+            let committed_at = finality.ckb_block_number.unwrap_or(u64::MAX);
+            if committed_at.saturating_add(self.challenge_window) > ckb_tip {
+                break;
+            }
+
+            finality.stage = FinalityStage::Finalized;
+            self.finality
+                .insert(number.to_be_bytes(), &finality)
+                .await?;
+            self.finality.insert(FINALIZED_TIP_KEY, number).await?;
+            finalized_tip = number;
+        }
+
+        Ok(finalized_tip)
+    }
+
+    pub async fn finalized_tip(&self) -> Result<u64> {
+        Ok(self
+            .finality
+            .get(&FINALIZED_TIP_KEY)
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn advance<F: FnOnce(&mut BlockFinality)>(
+        &self,
+        number: u64,
+        stage: FinalityStage,
+        update: F,
+    ) -> Result<()> {
+        let mut finality = match self.get_block_finality(number).await? {
+            Some(finality) => finality,
+            None => return Err(anyhow!("block {} not found", number)),
+        };
+        if finality.stage >= stage {
+            return Err(anyhow!(
+                "block {} finality can't move from {:?} to {:?}",
+                number,
+                finality.stage,
+                stage
+            ));
+        }
+
+        finality.stage = stage;
+        update(&mut finality);
+        self.finality
+            .insert(number.to_be_bytes(), &finality)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::fixture::save_blocks;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_finality_progression() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        save_blocks(&store, 2, |_| 0, None).await;
+
+        let tracker = FinalityTracker::new(store, 10).unwrap();
+        assert!(tracker.get_block_finality(3).await.unwrap().is_none());
+        assert_eq!(
+            tracker.get_block_finality(1).await.unwrap().unwrap().stage,
+            FinalityStage::Produced
+        );
+
+        for number in 1..=2 {
+            tracker
+                .submitted_to_l2(number, H256::repeat_byte(1))
+                .await
+                .unwrap();
+            tracker.l2_confirmed(number, 100).await.unwrap();
+        }
+        tracker
+            .committed_to_ckb(1, H256::repeat_byte(2), 50)
+            .await
+            .unwrap();
+        tracker
+            .committed_to_ckb(2, H256::repeat_byte(3), 55)
+            .await
+            .unwrap();
+        assert!(tracker.submitted_to_l2(1, H256::zero()).await.is_err());
+
+        assert_eq!(tracker.ckb_tip_updated(59).await.unwrap(), 0);
+        assert_eq!(tracker.ckb_tip_updated(60).await.unwrap(), 1);
+        assert!(tracker
+            .get_block_finality(1)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_irreversible());
+        assert!(!tracker
+            .get_block_finality(2)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_irreversible());
+        assert_eq!(tracker.ckb_tip_updated(65).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,380 @@
+use std::{collections::BTreeMap, fs, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use primitive_types::{H160, H256, U128};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        common::{blake2b, cbmt_merkle_root, secp256k1_address, sign_recoverable, H256Ext},
+        receipt::{ReceiptStream, StreamedReceipt},
+        smt::SMT,
+        store::Store,
+    },
+    consensus::ConsensusReceipt,
+    executor::{ChannelExecutor, Executor},
+    types::{
+        Balance, Block, BlockHeader, Channel, CloseChannel, CreateChannel, RawTransaction,
+        SignedTransaction, TransactionEnvelope, TransactionReceipt, UpdateChannel,
+    },
+};
+
+const CHAIN_ID: u64 = 1;
+const WALLETS: u64 = 4;
+const CHANNELS: u64 = 4;
+
+/// Outcome of one generated block. Block hashes cover timestamps, so only
+/// what the executor decides is kept.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GoldenBlock {
+    pub number: u64,
+    pub state_root: H256,
+    pub receipt_root: H256,
+    pub exit_codes: Vec<u8>,
+}
+
+/// What executing the chain generated from `seed` must always lead to. A
+/// change is a change of the consensus rules.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Golden {
+    pub seed: u64,
+    pub blocks: Vec<GoldenBlock>,
+}
+
+impl Golden {
+    pub fn read(path: &Path) -> Result<Self> {
+        let raw = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Fails on the first block that differs from `expected`.
+    pub fn diff(&self, expected: &Golden) -> Result<()> {
+        let mut pairs = self.blocks.iter().zip(expected.blocks.iter());
+        if let Some((got, want)) = pairs.find(|(got, want)| got != want) {
+            return Err(anyhow!(
+                "block {} diverged, got {:?}, expected {:?}",
+                got.number,
+                got,
+                want
+            ));
+        }
+        if self.seed != expected.seed || self.blocks.len() != expected.blocks.len() {
+            return Err(anyhow!(
+                "generated {} blocks of seed {}, expected {} of seed {}",
+                self.blocks.len(),
+                self.seed,
+                expected.blocks.len(),
+                expected.seed
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Numbers drawn from the hash of the seed and a counter, so fixtures don't
+// change with the algorithm of a rand release
+struct SeededRng {
+    seed: u64,
+    counter: u64,
+}
+
+impl SeededRng {
+    fn below(&mut self, bound: u64) -> u64 {
+        let hash = blake2b(&[self.seed.to_le_bytes(), self.counter.to_le_bytes()].concat());
+        self.counter += 1;
+        hash.to_low_u64_be() % bound
+    }
+}
+
+// What the generator believes a channel is at, transactions may fail
+// regardless
+struct Tracked {
+    participants: [usize; 2],
+    version: u64,
+}
+
+struct Generator {
+    rng: SeededRng,
+    keys: Vec<SecretKey>,
+    addresses: Vec<H160>,
+    channels: BTreeMap<u64, Tracked>,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Result<Self> {
+        let keys = (0..WALLETS)
+            .map(|index| {
+                let secret =
+                    blake2b(format!("covalent fixture {} wallet {}", seed, index).as_bytes());
+                SecretKey::from_slice(secret.as_bytes())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let secp = Secp256k1::new();
+        let addresses = { keys.iter() }
+            .map(|key| secp256k1_address(&PublicKey::from_secret_key(&secp, key)))
+            .collect();
+
+        Ok(Generator {
+            rng: SeededRng { seed, counter: 0 },
+            keys,
+            addresses,
+            channels: BTreeMap::new(),
+        })
+    }
+
+    fn wallet(&mut self) -> usize {
+        self.rng.below(WALLETS) as usize
+    }
+
+    fn balances(&mut self) -> [Balance; 2] {
+        [0; 2].map(|_| Balance {
+            settled: (1 + self.rng.below(100)).into(),
+        })
+    }
+
+    // The channel's participants, one of them swapped for a random wallet
+    // now and then
+    fn signers(&mut self, participants: [usize; 2]) -> [usize; 2] {
+        let mut signers = participants;
+        if self.rng.below(6) == 0 {
+            signers[self.rng.below(2) as usize] = self.wallet();
+        }
+        signers
+    }
+
+    // The next version mostly, a stale one sometimes
+    fn version(&mut self, id: u64) -> u64 {
+        let stale = self.rng.below(5) == 0;
+        let tracked = match self.channels.get_mut(&id) {
+            Some(tracked) => tracked,
+            None => return 1,
+        };
+        if !stale {
+            tracked.version += 1;
+        }
+        tracked.version
+    }
+
+    fn tx(&mut self) -> SignedTransaction {
+        let id = 1 + self.rng.below(CHANNELS);
+        let chain_id = match self.rng.below(16) {
+            0 => CHAIN_ID + 1,
+            _ => CHAIN_ID,
+        };
+        let participants = match self.channels.get(&id) {
+            Some(tracked) => tracked.participants,
+            None => [self.wallet(), self.wallet()],
+        };
+
+        let raw = match self.rng.below(4) {
+            0 => {
+                self.channels.entry(id).or_insert(Tracked {
+                    participants,
+                    version: 0,
+                });
+                RawTransaction::CreateChannel(CreateChannel {
+                    chain_id,
+                    id: id.into(),
+                    token: Default::default(),
+                    challenge_blocks: 2,
+                    participant2: participants.map(|i| self.addresses[i]),
+                    balance2: self.balances(),
+                    guard: None,
+                })
+            }
+            1 | 2 => {
+                let mut update = UpdateChannel {
+                    chain_id,
+                    channel_id: id.into(),
+                    version: self.version(id),
+                    balance2: self.balances(),
+                    ..Default::default()
+                };
+                let msg = update.sig_msg();
+                update.signature2 =
+                    { self.signers(participants) }.map(|i| sign_recoverable(&self.keys[i], msg));
+                RawTransaction::UpdateChannel(update)
+            }
+            _ => {
+                let mut close = CloseChannel {
+                    chain_id,
+                    channel_id: id.into(),
+                    version: self.version(id),
+                    ..Default::default()
+                };
+                let msg = close.sig_msg();
+                close.signature2 =
+                    { self.signers(participants) }.map(|i| sign_recoverable(&self.keys[i], msg));
+                RawTransaction::CloseChannel(close)
+            }
+        };
+
+        let sender = participants[0];
+        let hash = blake2b(&bincode::serialize(&TransactionEnvelope::from(raw.clone())).unwrap());
+        SignedTransaction {
+            sig: sign_recoverable(&self.keys[sender], hash),
+            fee: U128::zero(),
+            from: self.addresses[sender],
+            hash,
+            raw,
+        }
+    }
+}
+
+/// Execute `blocks` blocks of random channel transactions between wallets
+/// derived from `seed` on `store`, committing each block's channels the
+/// way consensus does.
+pub fn generate(store: &Store, seed: u64, blocks: u64) -> Result<Golden> {
+    let mut generator = Generator::new(seed)?;
+    let mut golden = Golden {
+        seed,
+        blocks: Vec::new(),
+    };
+
+    for number in 1..=blocks {
+        let txs = (0..1 + generator.rng.below(4))
+            .map(|_| generator.tx())
+            .collect::<Vec<_>>();
+        let receipt = ChannelExecutor::new(store.clone(), CHAIN_ID)
+            .with_block_number(number)
+            .exec(&txs)?;
+
+        let leaves = { receipt.updated_channels.into_iter() }
+            .map(|(key, channel)| (key.to_h256(), channel))
+            .collect::<Vec<_>>();
+        let state_root = SMT::new_with_store(store.clone())?
+            .update_all(leaves)?
+            .to_h256();
+        if state_root != receipt.state_root {
+            return Err(anyhow!(
+                "block {} committed state root {:?}, executed {:?}",
+                number,
+                state_root,
+                receipt.state_root
+            ));
+        }
+
+        golden.blocks.push(GoldenBlock {
+            number,
+            state_root,
+            receipt_root: receipt.receipt_root,
+            exit_codes: { receipt.transaction_receipts.iter() }
+                .map(|receipt| receipt.exit_code as u8)
+                .collect(),
+        });
+    }
+
+    Ok(golden)
+}
+
+// Block `number` holding `txs`, its hash is the number
+fn block(number: u64, txs: Vec<SignedTransaction>) -> Block {
+    let tx_hashes = txs.iter().map(|tx| tx.hash).collect::<Vec<_>>();
+    Block {
+        header: BlockHeader {
+            number,
+            hash: H256::from_low_u64_be(number),
+            state_root: H256::repeat_byte(number as u8),
+            transaction_root: cbmt_merkle_root(&tx_hashes),
+            ..Default::default()
+        },
+        txs,
+    }
+}
+
+/// Receipt of block `number` holding `txs` and updating `channels`, the
+/// way consensus hands it out.
+pub fn consensus_receipt(
+    number: u64,
+    txs: Vec<SignedTransaction>,
+    channels: Vec<Channel>,
+) -> ConsensusReceipt {
+    ConsensusReceipt {
+        block: Arc::new(block(number, txs)),
+        proposer: H160::zero(),
+        round: 0,
+        commit_signatures: vec![],
+        updated_channels: { channels.into_iter() }
+            .map(|channel| (channel.id.to_h256(), channel))
+            .collect(),
+    }
+}
+
+/// Save blocks `1..=count` to the chain of `store`, block `n` produced at
+/// `timestamp_ms(n)` with one transaction hashing to `n`. The receipts of
+/// the transactions are published to `receipts` when given.
+pub async fn save_blocks(
+    store: &Store,
+    count: u64,
+    timestamp_ms: impl Fn(u64) -> u64,
+    receipts: Option<&ReceiptStream>,
+) {
+    let chain = ChannelChain::new(store.clone()).unwrap();
+    for number in 1..=count {
+        let tx = SignedTransaction {
+            raw: RawTransaction::CloseChannel(CloseChannel::default()),
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::zero(),
+            hash: H256::from_low_u64_be(number),
+        };
+        if let Some(receipts) = receipts {
+            let receipt = StreamedReceipt {
+                block_number: number,
+                index: 0,
+                tx_hash: tx.hash,
+                receipt: TransactionReceipt::success(H256::zero()),
+                payment_id: None,
+            };
+            receipts.publish(receipt).unwrap();
+        }
+
+        let mut block = block(number, vec![tx]);
+        block.header.timestamp = timestamp_ms(number).into();
+        chain.save_block(Arc::new(block)).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const GOLDEN_SEED: u64 = 1;
+    const GOLDEN_BLOCKS: u64 = 32;
+
+    // Run with COVALENT_UPDATE_GOLDEN=1 to accept an intended change of the
+    // consensus rules
+    #[test]
+    fn test_golden_state() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/golden.json");
+        let generate_fresh = || {
+            let dir = tempdir().unwrap();
+            let store = Store::open(dir.path()).unwrap();
+            generate(&store, GOLDEN_SEED, GOLDEN_BLOCKS).unwrap()
+        };
+        let golden = generate_fresh();
+        assert_eq!(generate_fresh(), golden);
+        assert!(golden.blocks.iter().any(|b| b.exit_codes.contains(&0)));
+        assert!(golden
+            .blocks
+            .iter()
+            .any(|b| b.exit_codes.iter().any(|c| *c != 0)));
+
+        if std::env::var_os("COVALENT_UPDATE_GOLDEN").is_some() {
+            golden.write(&path).unwrap();
+        }
+        golden.diff(&Golden::read(&path).unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use primitive_types::{H256, U256};
+use serde::{Deserialize, Serialize};
+use share::amount::{self, AmountError};
+
+use crate::types::{DustLimits, Symbol, Token};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum GenesisError {
+    #[error("token {0} registered twice")]
+    DuplicateToken(U256),
+    #[error("L1 type hash {0:?} bound to both token {1} and {2}")]
+    DuplicateBinding(H256, U256, U256),
+    #[error("symbol {0} used by both token {1} and {2}")]
+    DuplicateSymbol(Symbol, U256, U256),
+    #[error("token {0}: {1}")]
+    Decimals(U256, AmountError),
+}
+
+/// A token registered in the genesis spec.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GenesisToken {
+    pub id: U256,
+    pub symbol: Symbol,
+    pub decimals: u8,
+    // Type script hash of the CKB sUDT this token is bridged from, unset for
+    // tokens that only exist on layer3
+    #[serde(default)]
+    pub l1_type_hash: Option<H256>,
+    // Smallest total deposit a channel opens with, in base units
+    #[serde(default)]
+    pub min_deposit: u64,
+    // Smallest balance move of a channel update, in base units
+    #[serde(default)]
+    pub min_update_delta: u64,
+}
+
+impl GenesisToken {
+    pub fn token(&self) -> Result<Token, GenesisError> {
+        amount::check_decimals(self.decimals.into())
+            .map_err(|e| GenesisError::Decimals(self.id, e))?;
+
+        Ok(Token {
+            id: self.id,
+            symbol: self.symbol,
+            decimal: self.decimals.into(),
+        })
+    }
+
+    pub fn dust_limits(&self) -> DustLimits {
+        DustLimits {
+            min_deposit: self.min_deposit.into(),
+            min_update_delta: self.min_update_delta.into(),
+        }
+    }
+}
+
+/// Genesis tokens indexed by id and by L1 binding, deposits and withdrawals
+/// map an sUDT to its layer3 token through `token_for_l1`.
+#[derive(Debug, Clone, Default)]
+pub struct TokenRegistry {
+    tokens: HashMap<U256, Token>,
+    by_l1: HashMap<H256, U256>,
+    by_symbol: HashMap<Symbol, U256>,
+    dust: HashMap<U256, DustLimits>,
+}
+
+impl TokenRegistry {
+    pub fn new(genesis: &[GenesisToken]) -> Result<Self, GenesisError> {
+        let mut registry = TokenRegistry::default();
+        for token in genesis {
+            if let Some(type_hash) = token.l1_type_hash {
+                if let Some(bound) = registry.by_l1.insert(type_hash, token.id) {
+                    return Err(GenesisError::DuplicateBinding(type_hash, bound, token.id));
+                }
+            }
+            if registry.tokens.insert(token.id, token.token()?).is_some() {
+                return Err(GenesisError::DuplicateToken(token.id));
+            }
+            if let Some(used) = registry.by_symbol.insert(token.symbol, token.id) {
+                return Err(GenesisError::DuplicateSymbol(token.symbol, used, token.id));
+            }
+            if token.dust_limits() != DustLimits::default() {
+                registry.dust.insert(token.id, token.dust_limits());
+            }
+        }
+
+        Ok(registry)
+    }
+
+    // Nothing resolves tokens on the node yet, channels carry the whole token
+    #[allow(dead_code)]
+    pub fn get(&self, id: &U256) -> Option<&Token> {
+        self.tokens.get(id)
+    }
+
+    #[allow(dead_code)]
+    pub fn by_symbol(&self, symbol: &Symbol) -> Option<&Token> {
+        self.by_symbol
+            .get(symbol)
+            .and_then(|id| self.tokens.get(id))
+    }
+
+    #[allow(dead_code)]
+    pub fn token_for_l1(&self, type_hash: &H256) -> Option<&Token> {
+        self.by_l1.get(type_hash).and_then(|id| self.tokens.get(id))
+    }
+
+    #[allow(dead_code)]
+    pub fn l1_binding(&self, id: &U256) -> Option<H256> {
+        { self.by_l1.iter() }
+            .find(|(_, token_id)| *token_id == id)
+            .map(|(type_hash, _)| *type_hash)
+    }
+
+    #[allow(dead_code)]
+    pub fn tokens(&self) -> impl Iterator<Item = &Token> {
+        self.tokens.values()
+    }
+
+    /// Dust limits of the tokens that have any, for the executor and the
+    /// mempool.
+    pub fn dust_limits(&self) -> HashMap<U256, DustLimits> {
+        self.dust.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genesis_token(id: u64, l1_type_hash: Option<H256>) -> GenesisToken {
+        GenesisToken {
+            id: id.into(),
+            symbol: Symbol::new(&format!("CKUSD{}", id)).unwrap(),
+            decimals: 8,
+            l1_type_hash,
+            min_deposit: 0,
+            min_update_delta: 0,
+        }
+    }
+
+    #[test]
+    fn test_token_registry() {
+        let sudt = H256::repeat_byte(0xaa);
+        let registry =
+            TokenRegistry::new(&[genesis_token(1, Some(sudt)), genesis_token(2, None)]).unwrap();
+
+        let token = registry.token_for_l1(&sudt).unwrap();
+        assert_eq!(token.id, 1.into());
+        assert_eq!(token.symbol.as_str(), "CKUSD1");
+        let symbol = Symbol::new("CKUSD2").unwrap();
+        assert_eq!(registry.by_symbol(&symbol).unwrap().id, 2.into());
+        assert_eq!(token.decimals().unwrap(), 8);
+        assert_eq!(registry.l1_binding(&1.into()), Some(sudt));
+        assert_eq!(registry.l1_binding(&2.into()), None);
+        assert!(registry.token_for_l1(&H256::zero()).is_none());
+
+        assert_eq!(
+            TokenRegistry::new(&[genesis_token(1, Some(sudt)), genesis_token(2, Some(sudt))])
+                .unwrap_err(),
+            GenesisError::DuplicateBinding(sudt, 1.into(), 2.into())
+        );
+        assert_eq!(
+            TokenRegistry::new(&[genesis_token(1, None), genesis_token(1, None)]).unwrap_err(),
+            GenesisError::DuplicateToken(1.into())
+        );
+        let mut same_symbol = genesis_token(3, None);
+        same_symbol.symbol = Symbol::new("CKUSD1").unwrap();
+        assert_eq!(
+            TokenRegistry::new(&[genesis_token(1, None), same_symbol]).unwrap_err(),
+            GenesisError::DuplicateSymbol(Symbol::new("CKUSD1").unwrap(), 1.into(), 3.into())
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{anyhow, Result};
+use primitive_types::{H160, H256, U256};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::{blake2b, recover_address, secp256k1_address, sign_recoverable},
+        mempool::{ChannelMap, MemPool},
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    types::{
+        Channel, ChannelState, RawTransaction, SignedTransaction, TransactionEnvelope,
+        UpdateChannel,
+    },
+};
+
+// Channel id to the latest update of the channel both participants signed
+const GUARDED_STATE_TREE: &str = "guardian_state";
+const ALERT_TREE: &str = "guardian_alert";
+
+/// Automatic responses to stale closes and challenges on the operator's
+/// channels. On by default, it only acts on channels the operator is in.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct GuardianPolicy {
+    pub enabled: bool,
+    // Offered with every response, in base units
+    pub response_fee: u64,
+}
+
+impl Default for GuardianPolicy {
+    fn default() -> Self {
+        GuardianPolicy {
+            enabled: true,
+            response_fee: 0,
+        }
+    }
+}
+
+/// Raised every time the guardian answers a stale state.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GuardianAlert {
+    pub channel_id: U256,
+    pub block_number: u64,
+    // Version the counterparty moved the channel to
+    pub challenged_version: u64,
+    // Version of the stored state submitted in response
+    pub response_version: u64,
+    pub tx_hash: H256,
+}
+
+/// Keeps the latest counter-signed state of the operator's channels and,
+/// when an applied block closes or challenges one of them with an older
+/// version, submits the stored state to the mempool to override it.
+#[derive(Clone)]
+pub struct Guardian {
+    states: AsyncStore,
+    alerts: AsyncStore,
+    mempool: ChannelMap,
+    operator_key: SecretKey,
+    operator: H160,
+    policy: GuardianPolicy,
+}
+
+impl Guardian {
+    pub fn new(
+        store: &Store,
+        mempool: ChannelMap,
+        operator_key: SecretKey,
+        policy: GuardianPolicy,
+    ) -> Result<Self, StoreError> {
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &operator_key);
+        let guardian = Guardian {
+            states: AsyncStore::new(store.open_tree(GUARDED_STATE_TREE)?),
+            alerts: AsyncStore::new(store.open_tree(ALERT_TREE)?),
+            mempool,
+            operator_key,
+            operator: secp256k1_address(&pubkey),
+            policy,
+        };
+
+        Ok(guardian)
+    }
+
+    pub fn operator(&self) -> H160 {
+        self.operator
+    }
+
+    /// Keep `update` as the state to respond with on `channel`. Returns false
+    /// if an equal or newer state is already kept.
+    pub async fn store_state(&self, channel: &Channel, update: UpdateChannel) -> Result<bool> {
+        if update.channel_id != channel.id {
+            return Err(anyhow!(
+                "update is for channel {}, not {}",
+                update.channel_id,
+                channel.id
+            ));
+        }
+        if !channel.participant2.contains(&self.operator) {
+            return Err(anyhow!(
+                "operator isn't a participant of channel {}",
+                channel.id
+            ));
+        }
+        let msg = update.sig_msg();
+        for (participant, sig) in channel.participant2.iter().zip(update.signature2.iter()) {
+            if recover_address(msg, sig) != Some(*participant) {
+                return Err(anyhow!(
+                    "update of channel {} isn't signed by {:?}",
+                    channel.id,
+                    participant
+                ));
+            }
+        }
+
+        if let Some(stored) = self.state(channel.id).await? {
+            if stored.version >= update.version {
+                return Ok(false);
+            }
+        }
+        self.states.insert(channel.id, update).await?;
+        Ok(true)
+    }
+
+    pub async fn state(&self, channel_id: U256) -> Result<Option<UpdateChannel>> {
+        Ok(self.states.get(&channel_id).await?)
+    }
+
+    /// Answer every stale close or challenge the block applied to the
+    /// operator's channels. Returns the alerts raised.
+    pub async fn on_consensus_receipt(
+        &self,
+        receipt: &ConsensusReceipt,
+    ) -> Result<Vec<GuardianAlert>> {
+        if !self.policy.enabled {
+            return Ok(Vec::new());
+        }
+
+        let block_number = receipt.block.header.number;
+        let mut alerts = Vec::new();
+        for channel in receipt.updated_channels.values() {
+            if !channel.participant2.contains(&self.operator) {
+                continue;
+            }
+            let stored = match self.state(channel.id).await? {
+                Some(stored) if stored.version > channel.version => stored,
+                _ => continue,
+            };
+            let closed = receipt.block.txs.iter().any(|tx| {
+                matches!(tx.raw, RawTransaction::CloseChannel(_))
+                    && tx.raw.channel_id() == channel.id
+            });
+            if !closed && channel.state != ChannelState::Challenge {
+                continue;
+            }
+            // Already answered, the response is pending
+            let last = self.alerts.get::<_, GuardianAlert>(&channel.id).await?;
+            if last.map(|a| a.challenged_version == channel.version) == Some(true) {
+                continue;
+            }
+
+            let tx = self.response(stored)?;
+            let alert = GuardianAlert {
+                channel_id: channel.id,
+                block_number,
+                challenged_version: channel.version,
+                response_version: tx.raw.version(),
+                tx_hash: tx.hash,
+            };
+            self.mempool.push_transaction(tx)?;
+            println!(
+                "[guardian] channel {} moved to stale version {} in block {}, responded with version {} in {:?}",
+                alert.channel_id,
+                alert.challenged_version,
+                block_number,
+                alert.response_version,
+                alert.tx_hash
+            );
+
+            self.alerts.insert(channel.id, &alert).await?;
+            alerts.push(alert);
+        }
+
+        Ok(alerts)
+    }
+
+    /// Latest alert of every channel, for the admin RPC.
+    pub async fn alerts(&self) -> Result<Vec<GuardianAlert>> {
+        Ok(self.alerts.run(|store| store.values()).await??)
+    }
+
+    fn response(&self, update: UpdateChannel) -> Result<SignedTransaction> {
+        let raw = RawTransaction::UpdateChannel(update);
+        let hash = blake2b(&bincode::serialize(&TransactionEnvelope::from(
+            raw.clone(),
+        ))?);
+
+        Ok(SignedTransaction {
+            sig: sign_recoverable(&self.operator_key, hash),
+            fee: self.policy.response_fee.into(),
+            from: self.operator,
+            hash,
+            raw,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use primitive_types::U128;
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::common::H256Ext,
+        types::{Balance, Block, CloseChannel},
+    };
+
+    use super::*;
+
+    const CHAIN_ID: u64 = 1;
+
+    fn signed_update(keys: &[SecretKey; 2], version: u64, balances: [u64; 2]) -> UpdateChannel {
+        let mut update = UpdateChannel {
+            chain_id: CHAIN_ID,
+            channel_id: 7.into(),
+            version,
+            balance2: balances.map(|settled| Balance {
+                settled: settled.into(),
+            }),
+            ..Default::default()
+        };
+        for (idx, key) in keys.iter().enumerate() {
+            update.signature2[idx] = sign_recoverable(key, update.sig_msg());
+        }
+        update
+    }
+
+    fn close_receipt(number: u64, channel: &Channel) -> ConsensusReceipt {
+        let close = SignedTransaction {
+            raw: RawTransaction::CloseChannel(CloseChannel {
+                chain_id: CHAIN_ID,
+                channel_id: channel.id,
+                version: channel.version,
+                ..Default::default()
+            }),
+            sig: vec![],
+            fee: U128::zero(),
+            from: channel.participant2[0],
+            hash: H256::from_low_u64_be(number),
+        };
+
+        ConsensusReceipt {
+            block: Arc::new(Block {
+                header: Default::default(),
+                txs: vec![close],
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: BTreeMap::from([(channel.id.to_h256(), channel.clone())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_respond_to_stale_close() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let keys = [
+            SecretKey::from_slice(&[2u8; 32]).unwrap(),
+            SecretKey::from_slice(&[1u8; 32]).unwrap(),
+        ];
+        let guardian =
+            Guardian::new(&store, mempool.clone(), keys[1], GuardianPolicy::default()).unwrap();
+
+        let counterparty =
+            secp256k1_address(&PublicKey::from_secret_key(&Secp256k1::new(), &keys[0]));
+        let channel = Channel {
+            id: 7.into(),
+            participant2: [counterparty, guardian.operator()],
+            state: ChannelState::Open,
+            version: 2,
+            ..Default::default()
+        };
+
+        let latest = signed_update(&keys, 5, [40, 60]);
+        assert!(guardian
+            .store_state(&channel, latest.clone())
+            .await
+            .unwrap());
+        assert!(!guardian
+            .store_state(&channel, signed_update(&keys, 4, [50, 50]))
+            .await
+            .unwrap());
+        let mut forged = signed_update(&keys, 6, [100, 0]);
+        forged.signature2[1] = forged.signature2[0].clone();
+        assert!(guardian.store_state(&channel, forged).await.is_err());
+
+        // Closed at version 3, older than the stored one
+        let stale = Channel {
+            version: 3,
+            ..channel.clone()
+        };
+        let alerts = guardian
+            .on_consensus_receipt(&close_receipt(1, &stale))
+            .await
+            .unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].challenged_version, 3);
+        assert_eq!(alerts[0].response_version, 5);
+        assert!(mempool.is_pending(&alerts[0].tx_hash));
+
+        let txs = mempool.package_transactions().unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].from, guardian.operator());
+        match &txs[0].raw {
+            RawTransaction::UpdateChannel(update) => assert_eq!(update.balance2, latest.balance2),
+            _ => panic!("response isn't a channel update"),
+        }
+
+        // Answered once per stale version
+        let again = guardian
+            .on_consensus_receipt(&close_receipt(2, &stale))
+            .await
+            .unwrap();
+        assert!(again.is_empty());
+
+        // Nothing to answer once the channel caught up
+        let current = Channel {
+            version: 5,
+            ..channel
+        };
+        let none = guardian
+            .on_consensus_receipt(&close_receipt(3, &current))
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+        assert_eq!(guardian.alerts().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        store::{Store, StoreError},
+    },
+    finality::FinalityTracker,
+};
+
+/// When the node stops taking traffic.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct HealthPolicy {
+    // Not ready while more blocks are waiting for settlement, 0 is no bound
+    pub max_settlement_lag: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub height: u64,
+    pub last_block_age_ms: Option<u64>,
+    // Blocks produced but not finalized on CKB yet
+    pub settlement_lag: u64,
+    pub db_ok: bool,
+    // Single operator, no p2p network yet
+    pub peer_count: usize,
+}
+
+impl HealthReport {
+    /// Healthy as long as the store is readable.
+    pub fn check_health(&self) -> Result<()> {
+        if !self.db_ok {
+            return Err(anyhow!("database unavailable"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Backs the `/health` and `/ready` probes of the node API.
+#[derive(Clone)]
+pub struct HealthService {
+    chain: ChannelChain,
+    finality: FinalityTracker,
+    policy: HealthPolicy,
+}
+
+impl HealthService {
+    pub fn new(
+        store: Store,
+        finality: FinalityTracker,
+        policy: HealthPolicy,
+    ) -> Result<Self, StoreError> {
+        let service = HealthService {
+            chain: ChannelChain::new(store)?,
+            finality,
+            policy,
+        };
+
+        Ok(service)
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        let tip = self.chain.tip_header().await;
+        let finalized_tip = self.finality.finalized_tip().await;
+        let db_ok = tip.is_ok() && finalized_tip.is_ok();
+
+        let tip = tip.ok().flatten();
+        let height = tip.as_ref().map(|h| h.number).unwrap_or_default();
+        HealthReport {
+            height,
+            last_block_age_ms: tip.map(|h| time_now_ms().saturating_sub(h.timestamp.as_u64())),
+            settlement_lag: height.saturating_sub(finalized_tip.unwrap_or_default()),
+            db_ok,
+            peer_count: 0,
+        }
+    }
+
+    pub async fn health(&self) -> Result<HealthReport> {
+        let report = self.report().await;
+        report.check_health()?;
+        Ok(report)
+    }
+
+    /// Ready while settlement keeps up. Blocks are only produced while
+    /// transactions are pending, so a node that produced none is ready to
+    /// take the first.
+    pub async fn ready(&self) -> Result<HealthReport> {
+        let report = self.report().await;
+        report.check_health()?;
+
+        let max_lag = self.policy.max_settlement_lag;
+        if max_lag > 0 && report.settlement_lag > max_lag {
+            return Err(anyhow!(
+                "settlement lags {} blocks behind",
+                report.settlement_lag
+            ));
+        }
+
+        Ok(report)
+    }
+}
+
+fn time_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::H256;
+    use tempfile::tempdir;
+
+    use crate::fixture::save_blocks;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ready_follows_settlement_lag() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let finality = FinalityTracker::new(store.clone(), 0).unwrap();
+        let policy = HealthPolicy {
+            max_settlement_lag: 1,
+        };
+        let service = HealthService::new(store.clone(), finality.clone(), policy).unwrap();
+        let unbounded =
+            HealthService::new(store.clone(), finality.clone(), Default::default()).unwrap();
+
+        // Ready before the first block, which waits for a transaction
+        assert!(service.health().await.is_ok());
+        assert_eq!(service.ready().await.unwrap().last_block_age_ms, None);
+
+        save_blocks(&store, 2, |_| time_now_ms(), None).await;
+        assert!(service.ready().await.is_err());
+        assert!(unbounded.ready().await.is_ok());
+
+        finality.submitted_to_l2(1, H256::zero()).await.unwrap();
+        finality.l2_confirmed(1, 1).await.unwrap();
+        finality.committed_to_ckb(1, H256::zero(), 1).await.unwrap();
+        finality.ckb_tip_updated(1).await.unwrap();
+
+        let report = service.ready().await.unwrap();
+        assert_eq!(report.height, 2);
+        assert_eq!(report.settlement_lag, 1);
+    }
+}
@@ -0,0 +1,65 @@
+//! Layer3 channel node, and what the `covalent-verifier` binary shares
+//! with it to re-execute published blocks with the node's own executor.
+//! The layer2 devnet runs the node in process on a mock L1, and signs
+//! channel transactions for its test wallets the way offline wallets do.
+
+#![allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
+
+mod api;
+mod archive;
+mod auxiliaries;
+mod checkpoint;
+mod config;
+mod consensus;
+mod cosigner;
+mod diagnostics;
+mod dispute;
+mod executor;
+mod faucet;
+#[cfg(test)]
+mod fixture;
+mod finality;
+mod genesis;
+mod guardian;
+mod health;
+mod node;
+mod notify;
+mod offline;
+// Open handshakes need a counterparty transport, there is none yet
+#[allow(dead_code)]
+mod opening;
+mod payment;
+// Prunes up to the settled tip, see finality
+#[allow(dead_code)]
+mod prune;
+mod rebalance;
+mod retention;
+mod revenue;
+#[cfg(test)]
+mod scenario;
+mod scheduler;
+// Settles on CKB, which the node has no client of yet
+#[allow(dead_code)]
+mod settlement;
+mod tracking;
+mod types;
+mod usage;
+mod verifier;
+// Used by wallets, not by the node
+#[allow(dead_code)]
+mod wallet;
+mod withdrawal;
+
+pub use crate::{
+    auxiliaries::{
+        common::secp256k1_address,
+        oracle::{DepositSource, MockDepositSource},
+        snapshot::SnapshotClient,
+    },
+    config::{Config, VerifierConfig},
+    node::Node,
+    offline::{OfflineError, UnsignedTransaction},
+    tracking::OutPoint,
+    types::{CloseChannel, RawTransaction, SignedTransaction},
+    verifier::{Mismatch, Verifier},
+};
@@ -1,8 +1,33 @@
-mod auxiliaries;
-mod consensus;
-mod executor;
-mod types;
+use layer3::{Config, Node};
 
-fn main() {
-    println!("Hello, world!");
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let config_path = match (args.next().as_deref(), args.next()) {
+        (None, _) => "./config/layer3.toml".to_owned(),
+        (Some("--config" | "-c"), Some(path)) => path,
+        _ => {
+            eprintln!("usage: layer3 [--config <path>]");
+            std::process::exit(2);
+        }
+    };
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let node = match Node::open(config).await {
+        Ok(node) => node,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = node.run().await {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
 }
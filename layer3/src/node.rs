@@ -0,0 +1,370 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use anyhow::Result;
+
+use crate::{
+    api::NodeApi,
+    archive::BackupJob,
+    auxiliaries::{
+        chain::ChannelChain,
+        layer2::Layer2Client,
+        mempool::{ChannelMap, MemPool},
+        oracle::{CkbDepositSource, DepositOracle, DepositSource},
+        relay::Relayer,
+        snapshot::SnapshotSource,
+        store::Store,
+    },
+    checkpoint::{CheckpointManager, CheckpointSubmitter},
+    config::Config,
+    consensus::{ChannelConsensus, Consensus, ConsensusReceipt},
+    cosigner::Cosigner,
+    dispute::DisputeTracker,
+    faucet::ChannelFaucet,
+    finality::FinalityTracker,
+    guardian::Guardian,
+    health::HealthService,
+    notify::{DeliveryWorker, HttpTransport, Notifier, WithdrawalNotifier},
+    payment::PaymentTracker,
+    rebalance::Rebalancer,
+    retention::ReceiptRetention,
+    revenue::RevenueLedger,
+    scheduler::Scheduler,
+    tracking::TransferTracker,
+};
+
+/// Single operator node: takes transactions at `rpc_uri`, packages them
+/// into a block every `interval_ms` while any are pending, and serves the
+/// pinned state to followers at `snapshot_uri`.
+pub struct Node {
+    config: Config,
+    store: Store,
+    mempool: ChannelMap,
+    consensus: Arc<ChannelConsensus>,
+    retention: ReceiptRetention,
+    finality: FinalityTracker,
+    transfers: TransferTracker,
+    snapshot: SnapshotSource,
+    // Set while checkpoints are enabled
+    checkpoints: Option<CheckpointManager>,
+    relayer: Arc<Relayer<Layer2Client>>,
+    payments: PaymentTracker,
+    disputes: DisputeTracker,
+    revenue: RevenueLedger,
+    notifier: Notifier,
+    delivery: Arc<DeliveryWorker>,
+    // Set while rebalancing is enabled
+    rebalancer: Option<Rebalancer>,
+    // Set while the guardian is enabled
+    guardian: Option<Guardian>,
+    // Set while the faucet is enabled
+    faucet: Option<Arc<ChannelFaucet>>,
+    // Watched in place of the CKB node of the oracle policy when set
+    deposits: Option<Arc<dyn DepositSource>>,
+}
+
+impl Node {
+    /// Open the store and apply the blocks produced but not applied before
+    /// the last shutdown.
+    pub async fn open(config: Config) -> Result<Self> {
+        let store = Store::open(&config.db_path)?;
+        let dust = config.token_registry()?.dust_limits();
+        let mempool = ChannelMap::with_policy(config.chain_id, config.package)
+            .with_admission(config.admission)
+            .with_dust_limits(dust.clone(), store.clone());
+        let (notifier, delivery) = Notifier::new(&store, HttpTransport::new())?;
+        let consensus = ChannelConsensus::new(mempool.clone(), store.clone(), config.chain_id)?
+            .with_operator_key(config.operator_key()?)
+            .with_dust_limits(dust)
+            .with_notifier(notifier.clone());
+        let replayed = consensus.replay_receipt_log().await?;
+        if replayed > 0 {
+            println!("[consensus] applied {} blocks left over", replayed);
+        }
+
+        let receipts = consensus.receipt_stream().clone();
+        let finality = FinalityTracker::new(store.clone(), config.challenge_window)?;
+        let checkpoints = if config.checkpoint.enabled {
+            let (key, policy) = (config.operator_key()?, config.checkpoint.clone());
+            Some(CheckpointManager::new(&store, key, policy)?)
+        } else {
+            None
+        };
+        let rebalancer = if config.rebalance.enabled {
+            let (key, policy) = (config.operator_key()?, config.rebalance.clone());
+            Some(Rebalancer::new(&store, config.chain_id, key, policy)?)
+        } else {
+            None
+        };
+        let guardian = if config.guardian.enabled {
+            let (key, policy) = (config.operator_key()?, config.guardian.clone());
+            Some(Guardian::new(&store, mempool.clone(), key, policy)?)
+        } else {
+            None
+        };
+        // Test channels hold the first genesis token
+        let faucet = match (config.faucet.enabled, config.tokens.first()) {
+            (true, Some(token)) => Some(Arc::new(ChannelFaucet::new(
+                mempool.clone(),
+                config.chain_id,
+                config.operator_key()?,
+                token.token()?,
+                config.faucet.clone(),
+            ))),
+            _ => None,
+        };
+        let transfers = TransferTracker::new(&store, finality.clone())?;
+        let relayer = Relayer::new(
+            &store,
+            Layer2Client::new(config.layer2_rpc_uri.clone()),
+            transfers.clone(),
+            config.withdrawal_batch.clone(),
+        )?;
+        Ok(Node {
+            retention: ReceiptRetention::new(&store, receipts, config.retention.clone())?,
+            transfers,
+            payments: PaymentTracker::new(&store, finality.clone())?,
+            disputes: DisputeTracker::new(&store)?,
+            revenue: RevenueLedger::new(&store)?,
+            notifier,
+            delivery: Arc::new(delivery),
+            rebalancer,
+            guardian,
+            faucet,
+            deposits: None,
+            finality,
+            snapshot: SnapshotSource::new(store.clone(), config.snapshot.clone())?,
+            checkpoints,
+            relayer: Arc::new(relayer),
+            config,
+            store,
+            mempool,
+            consensus: Arc::new(consensus),
+        })
+    }
+
+    /// Find deposits on `source`, as devnets do on their mock L1.
+    pub fn with_deposit_source(mut self, source: Arc<dyn DepositSource>) -> Self {
+        self.deposits = Some(source);
+        self
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let api = NodeApi::new(
+            self.store.clone(),
+            self.mempool.clone(),
+            Arc::clone(&self.consensus),
+            self.retention.clone(),
+            self.finality.clone(),
+            self.transfers.clone(),
+            HealthService::new(
+                self.store.clone(),
+                self.finality.clone(),
+                self.config.health.clone(),
+            )?,
+        )?
+        .with_payments(self.payments.clone())
+        .with_disputes(self.disputes.clone())
+        .with_revenue(self.revenue.clone())
+        .with_notifier(self.notifier.clone())
+        .with_admin_tokens(self.config.admin_tokens.clone());
+        let api = match &self.rebalancer {
+            Some(rebalancer) => api.with_rebalancer(rebalancer.clone()),
+            None => api,
+        };
+        let api = match &self.guardian {
+            Some(guardian) => api.with_guardian(guardian.clone()),
+            None => api,
+        };
+        let api = match &self.faucet {
+            Some(faucet) => api.with_faucet(Arc::clone(faucet)),
+            None => api,
+        };
+        let front = self.config.api.front()?;
+        spawn_server("api", api.serve(self.config.rpc_uri, front));
+        spawn_server(
+            "snapshot",
+            self.snapshot.clone().serve(self.config.snapshot_uri),
+        );
+        if let (true, Some(uri)) = (self.config.cosigner.enabled, self.config.cosigner.uri) {
+            let key = self.config.cosigner_key()?;
+            let cosigner = Cosigner::new(&self.store, key, self.config.cosigner.clone())?;
+            println!("[cosigner] signing for {:?} at {}", cosigner.address(), uri);
+            spawn_server("cosigner", cosigner.serve(uri));
+        }
+        if let Some(manager) = &self.checkpoints {
+            println!("[checkpoint] signing as {:?}", manager.operator());
+        }
+        if let Some(rebalancer) = &self.rebalancer {
+            println!("[rebalance] proposing as {:?}", rebalancer.operator());
+        }
+        if let Some(guardian) = &self.guardian {
+            println!("[guardian] guarding as {:?}", guardian.operator());
+        }
+        if let Some(faucet) = &self.faucet {
+            println!(
+                "[faucet] opening test channels from {:?}",
+                faucet.operator()
+            );
+        }
+        let scheduler = self.scheduler()?;
+        for (name, schedule) in scheduler.schedules() {
+            println!("[scheduler] {} runs {:?}", name, schedule);
+        }
+        tokio::spawn(scheduler.run());
+
+        println!("covalent layer3 start, api at {}", self.config.rpc_uri);
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.package.interval_ms));
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.produce_block().await {
+                eprintln!("[consensus] producing a block failed: {:#}", err);
+            }
+        }
+    }
+
+    fn scheduler(&self) -> Result<Scheduler> {
+        let mut scheduler = Scheduler::new(self.config.scheduler.clone())
+            .register_shared(Arc::clone(&self.consensus) as _)
+            .register_shared(Arc::clone(&self.relayer) as _)
+            .register_shared(Arc::clone(&self.delivery) as _)
+            .register(self.retention.clone())
+            .register(WithdrawalNotifier::new(
+                &self.store,
+                self.notifier.clone(),
+                self.finality.clone(),
+                self.transfers.clone(),
+            )?);
+        if let Some(dir) = self.config.scheduler.backup_dir.clone() {
+            let chain = ChannelChain::new(self.store.clone())?;
+            scheduler = scheduler.register(BackupJob::new(chain, dir));
+        }
+        let oracle = &self.config.oracle;
+        let source = match (&self.deposits, &oracle.ckb_rpc_uri, &oracle.deposit_lock) {
+            (Some(source), _, _) => Some(Arc::clone(source)),
+            (None, Some(uri), Some(lock)) => {
+                Some(Arc::new(CkbDepositSource::new(uri.clone(), lock.clone())) as _)
+            }
+            _ => None,
+        };
+        if let Some(source) = source {
+            let transfers = self.transfers.clone();
+            let oracle = DepositOracle::new(&self.store, source, transfers, oracle.clone())?;
+            scheduler = scheduler.register(oracle);
+        }
+        if let Some(rebalancer) = self.rebalancer.clone() {
+            scheduler = scheduler.register(rebalancer);
+        }
+        if let Some(manager) = self.checkpoints.clone() {
+            let target = Layer2Client::new(self.config.layer2_rpc_uri.clone());
+            let finality = self.finality.clone();
+            let policy = self.config.checkpoint.clone();
+            let submitter = CheckpointSubmitter::new(manager, target, finality, policy);
+            scheduler = scheduler.register(submitter);
+        }
+
+        Ok(scheduler)
+    }
+
+    /// Package the pending transactions into a block and apply it, None
+    /// while nothing is pending.
+    pub async fn produce_block(&self) -> Result<Option<ConsensusReceipt>> {
+        if self.mempool.build_block_template()?.txs.is_empty() {
+            return Ok(None);
+        }
+
+        let receipt = self.consensus.produce_block().await?;
+        self.consensus.apply_consensus_receipt(&receipt).await?;
+        self.on_consensus_receipt(&receipt).await?;
+        Ok(Some(receipt))
+    }
+
+    async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        if let Some(manager) = &self.checkpoints {
+            manager.on_consensus_receipt(receipt).await?;
+        }
+        self.relayer.on_consensus_receipt(receipt).await?;
+        self.payments.on_consensus_receipt(receipt).await?;
+        self.disputes.on_consensus_receipt(receipt).await?;
+        self.revenue.on_consensus_receipt(receipt).await?;
+        self.notifier.on_consensus_receipt(receipt).await?;
+        if let Some(rebalancer) = &self.rebalancer {
+            rebalancer.on_consensus_receipt(receipt).await?;
+        }
+        if let Some(guardian) = &self.guardian {
+            for alert in guardian.on_consensus_receipt(receipt).await? {
+                self.notifier.on_guardian_alert(&alert).await?;
+            }
+        }
+        self.snapshot.on_consensus_receipt(receipt).await
+    }
+}
+
+fn spawn_server(name: &'static str, server: impl Future<Output = Result<()>> + Send + 'static) {
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            eprintln!("[{}] server stopped: {:#}", name, err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::{H160, H256, U128};
+    use tempfile::tempdir;
+
+    use crate::types::{Balance, CreateChannel, RawTransaction, SignedTransaction};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_produce_blocks_while_pending() {
+        let tmp_dir = tempdir().unwrap();
+        let key_path = tmp_dir.path().join("operator.key");
+        std::fs::write(&key_path, hex::encode([1u8; 32])).unwrap();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            chain_id = 1
+            db_path = "{}"
+            rpc_uri = "127.0.0.1:18130"
+            snapshot_uri = "127.0.0.1:18131"
+            layer2_rpc_uri = "http://127.0.0.1:8000"
+            operator_key_path = "{}"
+            challenge_window = 100
+
+            [snapshot]
+            every_blocks = 1
+            "#,
+            tmp_dir.path().join("data").display(),
+            key_path.display(),
+        ))
+        .unwrap();
+        let node = Node::open(config).await.unwrap();
+        assert!(node.produce_block().await.unwrap().is_none());
+
+        let raw = RawTransaction::CreateChannel(CreateChannel {
+            chain_id: 1,
+            id: 1.into(),
+            token: Default::default(),
+            challenge_blocks: 10,
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            balance2: [Balance::default(), Balance::default()],
+            guard: None,
+        });
+        let tx = SignedTransaction {
+            raw,
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::repeat_byte(1),
+            hash: H256::repeat_byte(1),
+        };
+        node.mempool.push_transaction(tx).unwrap();
+        let receipt = node.produce_block().await.unwrap().unwrap();
+        assert_eq!(receipt.block.header.number, 1);
+        assert!(!receipt.commit_signatures.is_empty());
+
+        let pinned = node.snapshot.pinned().await.unwrap().unwrap();
+        assert_eq!(pinned.state_root, receipt.block.header.state_root);
+        assert!(node.produce_block().await.unwrap().is_none());
+    }
+}
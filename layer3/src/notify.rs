@@ -0,0 +1,810 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use hyper::{Body, Client, Request};
+use primitive_types::{H160, H256, U128, U256};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Mutex,
+};
+
+use crate::{
+    auxiliaries::{
+        common::blake2b,
+        mempool::{ExpiredTransaction, ResubmissionHint},
+        receipt::ReceiptStream,
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    finality::FinalityTracker,
+    guardian::GuardianAlert,
+    scheduler::{Job, Schedule},
+    tracking::TransferTracker,
+    types::{Channel, ChannelState, ChannelV1, ExecutionExitCode},
+    verifier::{Mismatch, MismatchField},
+};
+
+const WEBHOOK_TREE: &str = "webhook";
+const WATCHED_CHANNEL_TREE: &str = "webhook_watched_channel";
+const NOTIFIED_TIP_TREE: &str = "webhook_notified_tip";
+const NOTIFIED_TIP_KEY: &str = "finalized_tip";
+pub const SIGNATURE_HEADER: &str = "X-Covalent-Signature";
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum WatchTarget {
+    Address(H160),
+    Channel(U256),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: Vec<u8>,
+    pub targets: Vec<WatchTarget>,
+}
+
+impl Webhook {
+    pub fn id(&self) -> H256 {
+        blake2b(self.url.as_bytes())
+    }
+
+    fn watches(&self, channel: &Channel) -> bool {
+        self.targets.iter().any(|target| match target {
+            WatchTarget::Address(address) => channel.participant2.contains(address),
+            WatchTarget::Channel(channel_id) => channel.id == *channel_id,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+    BalanceChanged {
+        block_number: u64,
+        channel_id: U256,
+        address: H160,
+        balance: U128,
+    },
+    ChannelUpdated {
+        block_number: u64,
+        channel_id: U256,
+        state: ChannelState,
+        version: u64,
+    },
+    ChannelChallenged {
+        block_number: u64,
+        channel_id: U256,
+        version: u64,
+    },
+    WithdrawalFinalized {
+        request_id: H256,
+    },
+    SubAccountDeposit {
+        block_number: u64,
+        channel_id: U256,
+        tx_hash: H256,
+        recipient: H160,
+        sub_account: u64,
+        amount: U128,
+        payment_id: Option<H256>,
+    },
+    // The node answered a stale close or challenge with a newer state
+    ChallengeAnswered {
+        block_number: u64,
+        channel_id: U256,
+        challenged_version: u64,
+        response_version: u64,
+        tx_hash: H256,
+    },
+    // Evicted from the mempool before it got packaged
+    TransactionExpired {
+        tx_hash: H256,
+        from: H160,
+        channel_id: U256,
+        version: u64,
+        hint: ResubmissionHint,
+    },
+    // Re-execution doesn't reproduce a published block or commitment
+    RootMismatch {
+        from_block: u64,
+        to_block: u64,
+        field: MismatchField,
+        published: H256,
+        computed: H256,
+    },
+}
+
+/// Hex encoded HMAC-SHA256 of `body` under `secret`, sent in
+/// `SIGNATURE_HEADER` with every notification.
+pub fn sign_payload(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Receiver side check of a notification signature.
+// Run by the services receiving the webhooks, the node only signs
+#[allow(dead_code)]
+pub fn verify_payload(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[async_trait]
+pub trait WebhookTransport: Sync + Send {
+    async fn post(&self, url: &str, body: Vec<u8>, signature: String) -> Result<()>;
+}
+
+pub struct HttpTransport {
+    client: Client<hyper::client::HttpConnector>,
+}
+
+impl HttpTransport {
+    pub fn new() -> Self {
+        HttpTransport {
+            client: Client::new(),
+        }
+    }
+}
+
+impl Default for HttpTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl WebhookTransport for HttpTransport {
+    async fn post(&self, url: &str, body: Vec<u8>, signature: String) -> Result<()> {
+        let req = Request::post(url)
+            .header("content-type", "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .body(Body::from(body))?;
+
+        let resp = self.client.request(req).await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("webhook {} responded {}", url, resp.status()));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Delivery {
+    url: String,
+    secret: Vec<u8>,
+    body: Vec<u8>,
+    // Failed attempts so far, the next one is due at `due`
+    attempts: u32,
+    due: Instant,
+}
+
+/// Keeps the operator's watch list and turns applied blocks into webhook
+/// notifications. Deliveries are handed to a `DeliveryWorker`.
+#[derive(Clone)]
+pub struct Notifier {
+    webhooks: AsyncStore,
+    // Last notified state of every watched channel, to detect balance changes
+    watched_channels: AsyncStore,
+    // Read only, to tell which memo carrying updates succeeded
+    receipts: ReceiptStream,
+    queue: UnboundedSender<Delivery>,
+}
+
+impl Notifier {
+    pub fn new<T: WebhookTransport + 'static>(
+        store: &Store,
+        transport: T,
+    ) -> Result<(Self, DeliveryWorker), StoreError> {
+        let (queue, pending) = unbounded_channel();
+        let notifier = Notifier {
+            webhooks: AsyncStore::new(store.open_tree(WEBHOOK_TREE)?),
+            watched_channels: AsyncStore::new(store.open_tree(WATCHED_CHANNEL_TREE)?),
+            receipts: ReceiptStream::new(store)?,
+            queue,
+        };
+        let worker = DeliveryWorker {
+            transport: Arc::new(transport),
+            pending: Mutex::new(pending),
+            queued: Mutex::new(Vec::new()),
+            base_delay: RETRY_BASE_DELAY,
+        };
+
+        Ok((notifier, worker))
+    }
+
+    pub async fn register(&self, webhook: Webhook) -> Result<H256> {
+        if !webhook.url.starts_with("http://") {
+            return Err(anyhow!("unsupported webhook url {}", webhook.url));
+        }
+
+        let id = webhook.id();
+        self.webhooks.insert(id, webhook).await?;
+        Ok(id)
+    }
+
+    pub async fn unregister(&self, id: H256) -> Result<()> {
+        Ok(self.webhooks.remove(id).await?)
+    }
+
+    pub async fn webhooks(&self) -> Result<Vec<Webhook>> {
+        Ok(self.webhooks.run(|store| store.values()).await??)
+    }
+
+    pub async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        let webhooks = self.webhooks().await?;
+        if webhooks.is_empty() {
+            return Ok(());
+        }
+
+        let block_number = receipt.block.header.number;
+        for channel in receipt.updated_channels.values() {
+            let watchers = { webhooks.iter() }
+                .filter(|webhook| webhook.watches(channel))
+                .collect::<Vec<_>>();
+            if watchers.is_empty() {
+                continue;
+            }
+
+            let prev = self
+                .watched_channels
+                .get_migrating::<_, Channel, ChannelV1>(&channel.id)
+                .await?;
+            let mut notifications = channel_notifications(block_number, prev.as_ref(), channel);
+            notifications.extend(self.deposit_notifications(receipt, channel)?);
+            self.watched_channels.insert(channel.id, channel).await?;
+
+            for webhook in watchers {
+                for notification in notifications.iter() {
+                    if let Notification::BalanceChanged { address, .. }
+                    | Notification::SubAccountDeposit {
+                        recipient: address, ..
+                    } = notification
+                    {
+                        let by_channel =
+                            webhook.targets.contains(&WatchTarget::Channel(channel.id));
+                        if !by_channel && !webhook.targets.contains(&WatchTarget::Address(*address))
+                        {
+                            continue;
+                        }
+                    }
+                    self.enqueue(webhook, notification)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn on_withdrawal_finalized(&self, request_id: H256) -> Result<()> {
+        let notification = Notification::WithdrawalFinalized { request_id };
+        for webhook in self.webhooks().await? {
+            self.enqueue(&webhook, &notification)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn on_guardian_alert(&self, alert: &GuardianAlert) -> Result<()> {
+        let notification = Notification::ChallengeAnswered {
+            block_number: alert.block_number,
+            channel_id: alert.channel_id,
+            challenged_version: alert.challenged_version,
+            response_version: alert.response_version,
+            tx_hash: alert.tx_hash,
+        };
+        for webhook in self.webhooks().await? {
+            self.enqueue(&webhook, &notification)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn on_root_mismatch(&self, mismatch: &Mismatch) -> Result<()> {
+        let notification = Notification::RootMismatch {
+            from_block: mismatch.from_block,
+            to_block: mismatch.to_block,
+            field: mismatch.field,
+            published: mismatch.published,
+            computed: mismatch.computed,
+        };
+        for webhook in self.webhooks().await? {
+            self.enqueue(&webhook, &notification)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sent to the webhooks watching the sender or the channel.
+    pub async fn on_transaction_expired(&self, expired: &ExpiredTransaction) -> Result<()> {
+        let notification = Notification::TransactionExpired {
+            tx_hash: expired.tx_hash,
+            from: expired.from,
+            channel_id: expired.channel_id,
+            version: expired.version,
+            hint: expired.hint.clone(),
+        };
+        for webhook in self.webhooks().await? {
+            let watched = webhook.targets.iter().any(|target| match target {
+                WatchTarget::Address(address) => *address == expired.from,
+                WatchTarget::Channel(channel_id) => *channel_id == expired.channel_id,
+            });
+            if watched {
+                self.enqueue(&webhook, &notification)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deposits to sub-accounts of the channel's participants made by the
+    /// successful updates of the block.
+    fn deposit_notifications(
+        &self,
+        receipt: &ConsensusReceipt,
+        channel: &Channel,
+    ) -> Result<Vec<Notification>> {
+        let mut notifications = Vec::new();
+        for tx in receipt.block.txs.iter() {
+            let memo = match tx.raw.memo() {
+                Some(memo) if tx.raw.channel_id() == channel.id => memo,
+                _ => continue,
+            };
+            let succeeded = { self.receipts.get_receipt(&tx.hash)? }
+                .map(|r| r.receipt.exit_code == ExecutionExitCode::Success)
+                .unwrap_or(false);
+            if !succeeded {
+                continue;
+            }
+
+            notifications.push(Notification::SubAccountDeposit {
+                block_number: receipt.block.header.number,
+                channel_id: channel.id,
+                tx_hash: tx.hash,
+                recipient: channel.participant2[memo.recipient as usize],
+                sub_account: memo.sub_account,
+                amount: memo.amount,
+                payment_id: tx.raw.payment_id(),
+            });
+        }
+
+        Ok(notifications)
+    }
+
+    fn enqueue(&self, webhook: &Webhook, notification: &Notification) -> Result<()> {
+        let delivery = Delivery {
+            url: webhook.url.clone(),
+            secret: webhook.secret.clone(),
+            body: serde_json::to_vec(notification)?,
+            attempts: 0,
+            due: Instant::now(),
+        };
+
+        { self.queue.send(delivery) }.map_err(|_| anyhow!("webhook delivery worker stopped"))
+    }
+}
+
+fn channel_notifications(
+    block_number: u64,
+    prev: Option<&Channel>,
+    channel: &Channel,
+) -> Vec<Notification> {
+    let mut notifications = vec![Notification::ChannelUpdated {
+        block_number,
+        channel_id: channel.id,
+        state: channel.state.clone(),
+        version: channel.version,
+    }];
+
+    if channel.state == ChannelState::Challenge
+        && prev
+            .map(|p| p.state != ChannelState::Challenge)
+            .unwrap_or(true)
+    {
+        notifications.push(Notification::ChannelChallenged {
+            block_number,
+            channel_id: channel.id,
+            version: channel.version,
+        });
+    }
+
+    for (idx, address) in channel.participant2.iter().enumerate() {
+        let balance = channel.balance2[idx].settled;
+        if prev
+            .map(|p| p.balance2[idx].settled != balance)
+            .unwrap_or(true)
+        {
+            notifications.push(Notification::BalanceChanged {
+                block_number,
+                channel_id: channel.id,
+                address: *address,
+                balance,
+            });
+        }
+    }
+
+    notifications
+}
+
+/// Delivers queued notifications on every run, together with the failed
+/// ones whose backoff elapsed. Each is tried up to `MAX_DELIVERY_ATTEMPTS`
+/// times, the wait doubling after every failure.
+pub struct DeliveryWorker {
+    transport: Arc<dyn WebhookTransport>,
+    pending: Mutex<UnboundedReceiver<Delivery>>,
+    // Deliveries waiting for a retry
+    queued: Mutex<Vec<Delivery>>,
+    base_delay: Duration,
+}
+
+#[async_trait]
+impl Job for DeliveryWorker {
+    fn name(&self) -> &'static str {
+        "webhook_delivery"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(1)
+    }
+
+    async fn run(&self) -> Result<()> {
+        let mut queued = self.queued.lock().await;
+        let mut pending = self.pending.lock().await;
+        while let Ok(delivery) = pending.try_recv() {
+            queued.push(delivery);
+        }
+
+        let now = Instant::now();
+        let (due, waiting) = { queued.drain(..) }.partition::<Vec<_>, _>(|d| d.due <= now);
+        *queued = waiting;
+        let tasks = { due.into_iter() }
+            .map(|delivery| {
+                let transport = Arc::clone(&self.transport);
+                tokio::spawn(deliver(transport, delivery, self.base_delay))
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            if let Some(failed) = task.await? {
+                queued.push(failed);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Attempt a delivery once, returns it back while it has attempts left.
+async fn deliver(
+    transport: Arc<dyn WebhookTransport>,
+    mut delivery: Delivery,
+    base_delay: Duration,
+) -> Option<Delivery> {
+    let signature = sign_payload(&delivery.secret, &delivery.body);
+    let body = delivery.body.clone();
+    let err = match transport.post(&delivery.url, body, signature).await {
+        Ok(()) => return None,
+        Err(err) => err,
+    };
+
+    eprintln!(
+        "[notify] deliver to {} attempt {} failed: {}",
+        delivery.url, delivery.attempts, err
+    );
+    delivery.attempts += 1;
+    if delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+        return None;
+    }
+    delivery.due = Instant::now() + base_delay * 2u32.pow(delivery.attempts - 1);
+    Some(delivery)
+}
+
+/// Notifies the withdrawals of the blocks finalized since its last run,
+/// once they can be claimed on L1.
+pub struct WithdrawalNotifier {
+    notifier: Notifier,
+    finality: FinalityTracker,
+    transfers: TransferTracker,
+    // Finalized tip as of the last run
+    notified: AsyncStore,
+}
+
+impl WithdrawalNotifier {
+    pub fn new(
+        store: &Store,
+        notifier: Notifier,
+        finality: FinalityTracker,
+        transfers: TransferTracker,
+    ) -> Result<Self, StoreError> {
+        Ok(WithdrawalNotifier {
+            notifier,
+            finality,
+            transfers,
+            notified: AsyncStore::new(store.open_tree(NOTIFIED_TIP_TREE)?),
+        })
+    }
+}
+
+#[async_trait]
+impl Job for WithdrawalNotifier {
+    fn name(&self) -> &'static str {
+        "withdrawal_notifier"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(30)
+    }
+
+    async fn run(&self) -> Result<()> {
+        let tip = self.finality.finalized_tip().await?;
+        // Withdrawals finalized before the first run aren't notified
+        let notified = { self.notified.get(&NOTIFIED_TIP_KEY).await? }.unwrap_or(tip);
+        if tip > notified {
+            let finalized = { self.transfers.committed_withdrawals(notified + 1..=tip) }.await?;
+            for request_id in finalized {
+                self.notifier.on_withdrawal_finalized(request_id).await?;
+            }
+        }
+
+        self.notified.insert(NOTIFIED_TIP_KEY, tip).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::receipt::StreamedReceipt,
+        fixture::save_blocks,
+        types::{
+            Balance, Block, BlockHeader, RawTransaction, SignedTransaction, SubAccountMemo,
+            TransactionReceipt, UpdateChannel,
+        },
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockTransport {
+        fail_first: Mutex<bool>,
+        received: Mutex<Vec<(Vec<u8>, String)>>,
+    }
+
+    #[async_trait]
+    impl WebhookTransport for Arc<MockTransport> {
+        async fn post(&self, _url: &str, body: Vec<u8>, signature: String) -> Result<()> {
+            let mut fail_first = self.fail_first.lock().unwrap();
+            if *fail_first {
+                *fail_first = false;
+                return Err(anyhow!("unavailable"));
+            }
+
+            self.received.lock().unwrap().push((body, signature));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify_payload() {
+        let signature = sign_payload(b"secret", b"{}");
+        assert!(verify_payload(b"secret", b"{}", &signature));
+        assert!(!verify_payload(b"other", b"{}", &signature));
+        assert!(!verify_payload(b"secret", b"{ }", &signature));
+    }
+
+    #[tokio::test]
+    async fn test_notify_watched_channel_with_retry() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let transport = Arc::new(MockTransport {
+            fail_first: Mutex::new(true),
+            ..Default::default()
+        });
+        let (notifier, mut worker) = Notifier::new(&store, Arc::clone(&transport)).unwrap();
+        worker.base_delay = Duration::ZERO;
+
+        let alice = H160::repeat_byte(1);
+        let webhook = Webhook {
+            url: "http://127.0.0.1:1/hook".to_owned(),
+            secret: b"secret".to_vec(),
+            targets: vec![WatchTarget::Address(alice)],
+        };
+        notifier.register(webhook).await.unwrap();
+
+        let channel = Channel {
+            id: U256::one(),
+            participant2: [alice, H160::repeat_byte(2)],
+            state: ChannelState::Challenge,
+            balance2: [Balance { settled: 5.into() }, Balance::default()],
+            ..Default::default()
+        };
+        let receipt = ConsensusReceipt {
+            block: Arc::new(Block {
+                header: BlockHeader {
+                    number: 1,
+                    ..Default::default()
+                },
+                txs: vec![],
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: [(H256::zero(), channel)].into_iter().collect(),
+        };
+        notifier.on_consensus_receipt(&receipt).await.unwrap();
+
+        // Updated, challenged, and alice's balance, but not the counterparty's.
+        // The failed delivery is retried on the next run
+        worker.run().await.unwrap();
+        assert_eq!(transport.received.lock().unwrap().len(), 2);
+        worker.run().await.unwrap();
+
+        let received = transport.received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        for (body, signature) in received.iter() {
+            assert!(verify_payload(b"secret", body, signature));
+        }
+        let updated = { received.iter() }
+            .map(|(body, _)| serde_json::from_slice::<Notification>(body).unwrap())
+            .filter(|notification| matches!(notification, Notification::ChannelUpdated { .. }));
+        assert_eq!(updated.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_notify_sub_account_deposit() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let (notifier, mut worker) =
+            Notifier::new(&store, Arc::new(MockTransport::default())).unwrap();
+
+        let exchange = H160::repeat_byte(2);
+        let webhook = Webhook {
+            url: "http://127.0.0.1:1/hook".to_owned(),
+            secret: b"secret".to_vec(),
+            targets: vec![WatchTarget::Address(exchange)],
+        };
+        notifier.register(webhook).await.unwrap();
+
+        let memo = SubAccountMemo {
+            recipient: 1,
+            sub_account: 42,
+            amount: 30.into(),
+        };
+        let deposit = |version: u64, hash: H256| SignedTransaction {
+            raw: RawTransaction::UpdateChannel(UpdateChannel {
+                channel_id: U256::one(),
+                version,
+                memo: Some(memo),
+                ..Default::default()
+            }),
+            sig: vec![],
+            fee: 0.into(),
+            from: H160::zero(),
+            hash,
+        };
+        let (ok, failed) = (H256::repeat_byte(1), H256::repeat_byte(2));
+        let receipts = ReceiptStream::new(&store).unwrap();
+        for (index, (tx_hash, exit_code)) in [
+            (ok, ExecutionExitCode::Success),
+            (failed, ExecutionExitCode::ErrorSubAccountMemo),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let receipt = TransactionReceipt {
+                exit_code,
+                state_root: H256::zero(),
+            };
+            receipts
+                .publish(StreamedReceipt {
+                    block_number: 1,
+                    index: index as u32,
+                    tx_hash,
+                    receipt,
+                    payment_id: None,
+                })
+                .unwrap();
+        }
+
+        let channel = Channel {
+            id: U256::one(),
+            participant2: [H160::repeat_byte(1), exchange],
+            version: 2,
+            balance2: [
+                Balance { settled: 70.into() },
+                Balance { settled: 30.into() },
+            ],
+            ..Default::default()
+        };
+        let receipt = ConsensusReceipt {
+            block: Arc::new(Block {
+                header: BlockHeader {
+                    number: 1,
+                    ..Default::default()
+                },
+                txs: vec![deposit(1, ok), deposit(2, failed)],
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: [(H256::zero(), channel)].into_iter().collect(),
+        };
+        notifier.on_consensus_receipt(&receipt).await.unwrap();
+
+        let mut deposits = Vec::new();
+        while let Ok(delivery) = worker.pending.get_mut().try_recv() {
+            let notification: Notification = serde_json::from_slice(&delivery.body).unwrap();
+            if let Notification::SubAccountDeposit { .. } = notification {
+                deposits.push(notification);
+            }
+        }
+        assert_eq!(
+            deposits,
+            vec![Notification::SubAccountDeposit {
+                block_number: 1,
+                channel_id: U256::one(),
+                tx_hash: ok,
+                recipient: exchange,
+                sub_account: 42,
+                amount: 30.into(),
+                payment_id: None,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notify_finalized_withdrawals() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        save_blocks(&store, 1, |_| 0, None).await;
+        let (notifier, mut worker) =
+            Notifier::new(&store, Arc::new(MockTransport::default())).unwrap();
+        let webhook = Webhook {
+            url: "http://127.0.0.1:1/hook".to_owned(),
+            secret: b"secret".to_vec(),
+            targets: vec![],
+        };
+        notifier.register(webhook).await.unwrap();
+
+        let finality = FinalityTracker::new(store.clone(), 10).unwrap();
+        let transfers = TransferTracker::new(&store, finality.clone()).unwrap();
+        let request_id = H256::repeat_byte(1);
+        transfers.withdrawal_queued(request_id).await.unwrap();
+        transfers.withdrawal_committed(request_id, 1).await.unwrap();
+        let job = WithdrawalNotifier::new(&store, notifier, finality.clone(), transfers).unwrap();
+        job.run().await.unwrap();
+        assert!(worker.pending.get_mut().try_recv().is_err());
+
+        finality.committed_to_ckb(1, H256::zero(), 1).await.unwrap();
+        finality.ckb_tip_updated(11).await.unwrap();
+        for _ in 0..2 {
+            job.run().await.unwrap();
+        }
+        let delivery = worker.pending.get_mut().try_recv().unwrap();
+        let notification: Notification = serde_json::from_slice(&delivery.body).unwrap();
+        assert_eq!(
+            notification,
+            Notification::WithdrawalFinalized { request_id }
+        );
+        assert!(worker.pending.get_mut().try_recv().is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use primitive_types::{H256, U128};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::common::{blake2b, secp256k1_address, sign_recoverable},
+    types::{RawTransaction, SignedTransaction, TransactionEnvelope},
+};
+
+// Bumped whenever the layout of the exported file changes
+pub const UNSIGNED_TX_FORMAT: u8 = 1;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum OfflineError {
+    #[error("unsupported unsigned tx format {0}")]
+    Format(u8),
+    #[error("transaction carries no participant signatures")]
+    Unsignable,
+    #[error("sig_msg doesn't match the raw transaction")]
+    SigMsgMismatch,
+    #[error("participant slot {0} out of range")]
+    Slot(usize),
+}
+
+/// A channel transaction exported for a participant to sign on an offline
+/// machine. The signer recomputes `sig_msg` from `raw` and refuses to sign
+/// when they differ, so a tampered export can't get a foreign message
+/// signed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UnsignedTransaction {
+    pub format: u8,
+    pub raw: RawTransaction,
+    pub sig_msg: H256,
+}
+
+impl UnsignedTransaction {
+    pub fn new(raw: RawTransaction) -> Result<Self, OfflineError> {
+        let sig_msg = raw.sig_msg().ok_or(OfflineError::Unsignable)?;
+        Ok(UnsignedTransaction {
+            format: UNSIGNED_TX_FORMAT,
+            raw,
+            sig_msg,
+        })
+    }
+
+    pub fn verify(&self) -> Result<(), OfflineError> {
+        if self.format != UNSIGNED_TX_FORMAT {
+            return Err(OfflineError::Format(self.format));
+        }
+        if self.raw.sig_msg() != Some(self.sig_msg) {
+            return Err(OfflineError::SigMsgMismatch);
+        }
+
+        Ok(())
+    }
+
+    /// Fill the signature of participant `slot`. The other participant signs
+    /// the same export, signatures already present are kept.
+    pub fn sign(mut self, key: &SecretKey, slot: usize) -> Result<Self, OfflineError> {
+        self.verify()?;
+
+        let signature2 = self.raw.signature2_mut().ok_or(OfflineError::Unsignable)?;
+        *signature2.get_mut(slot).ok_or(OfflineError::Slot(slot))? =
+            sign_recoverable(key, self.sig_msg);
+        Ok(self)
+    }
+
+    /// The transaction submitting the signed export, sent by the holder of
+    /// `key` and paying `fee`.
+    pub fn into_transaction(
+        self,
+        key: &SecretKey,
+        fee: U128,
+    ) -> Result<SignedTransaction, OfflineError> {
+        self.verify()?;
+
+        let envelope = TransactionEnvelope::from(self.raw.clone());
+        let hash = blake2b(&bincode::serialize(&envelope).unwrap());
+        Ok(SignedTransaction {
+            sig: sign_recoverable(key, hash),
+            fee,
+            from: secp256k1_address(&PublicKey::from_secret_key(&Secp256k1::new(), key)),
+            hash,
+            raw: self.raw,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::UpdateChannel;
+
+    use super::*;
+
+    #[test]
+    fn test_sign_offline() {
+        let raw = RawTransaction::UpdateChannel(UpdateChannel {
+            chain_id: 1,
+            channel_id: 7.into(),
+            version: 2,
+            ..Default::default()
+        });
+        let unsigned = UnsignedTransaction::new(raw).unwrap();
+        let exported = serde_json::to_string(&unsigned).unwrap();
+
+        let imported: UnsignedTransaction = serde_json::from_str(&exported).unwrap();
+        let key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let signed = imported.clone().sign(&key, 1).unwrap();
+        match &signed.raw {
+            RawTransaction::UpdateChannel(args) => {
+                assert!(args.signature2[0].is_empty());
+                assert_eq!(args.signature2[1].len(), 65);
+            }
+            _ => unreachable!(),
+        }
+
+        let mut tampered = imported;
+        tampered.sig_msg = H256::repeat_byte(1);
+        assert_eq!(
+            tampered.sign(&key, 0).unwrap_err(),
+            OfflineError::SigMsgMismatch
+        );
+    }
+}
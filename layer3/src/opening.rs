@@ -0,0 +1,697 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use primitive_types::{H160, H256, U128, U256};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::{blake2b, recover_address, secp256k1_address, sign_recoverable, H256Ext},
+        mempool::{ChannelMap, MemPool},
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    scheduler::{Job, Schedule},
+    tracking::{DepositStage, OutPoint, TransferTracker},
+    types::{
+        Balance, CreateChannel, RateGuard, RawTransaction, Signature, SignedTransaction, Token,
+        TransactionEnvelope,
+    },
+};
+
+const NEGOTIATION_TREE: &str = "open_negotiation";
+
+/// Proposals the node accepts from a counterparty without asking anyone.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct OpenPolicy {
+    pub enabled: bool,
+    pub min_challenge_blocks: u64,
+    // Most either side may deposit, in base units
+    pub max_deposit: u64,
+    // A negotiation whose deposits haven't both been relayed by then expires
+    pub funding_timeout_secs: u64,
+    // Offered with the `CreateChannel` transaction, in base units
+    pub create_fee: u64,
+    pub poll_interval_secs: u64,
+}
+
+impl Default for OpenPolicy {
+    fn default() -> Self {
+        OpenPolicy {
+            enabled: false,
+            min_challenge_blocks: 100,
+            max_deposit: u64::MAX,
+            funding_timeout_secs: 24 * 60 * 60,
+            create_fee: 0,
+            poll_interval_secs: 10,
+        }
+    }
+}
+
+/// Parameters of a channel one participant proposes to the other.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OpenProposal {
+    pub chain_id: u64,
+    pub channel_id: U256,
+    pub token: Token,
+    pub challenge_blocks: u64,
+    pub participant2: [H160; 2],
+    // Each side deposits its opening balance through the L1 deposit path
+    pub deposit2: [U128; 2],
+    pub guard: Option<RateGuard>,
+}
+
+impl OpenProposal {
+    pub fn id(&self) -> H256 {
+        blake2b(&bincode::serialize(self).unwrap())
+    }
+
+    pub fn create_channel(&self) -> CreateChannel {
+        CreateChannel {
+            chain_id: self.chain_id,
+            id: self.channel_id,
+            token: self.token.clone(),
+            challenge_blocks: self.challenge_blocks,
+            participant2: self.participant2,
+            balance2: self.deposit2.map(|settled| Balance { settled }),
+            guard: self.guard.clone(),
+        }
+    }
+}
+
+/// Counterparty protocol messages of the open handshake.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum OpenMessage {
+    Propose(OpenProposal),
+    Accept {
+        proposal_id: H256,
+    },
+    Reject {
+        proposal_id: H256,
+        reason: String,
+    },
+    // The sender's deposit, to be relayed before the channel is created
+    Funded {
+        proposal_id: H256,
+        out_point: OutPoint,
+    },
+}
+
+impl OpenMessage {
+    pub fn proposal_id(&self) -> H256 {
+        match self {
+            OpenMessage::Propose(proposal) => proposal.id(),
+            OpenMessage::Accept { proposal_id }
+            | OpenMessage::Reject { proposal_id, .. }
+            | OpenMessage::Funded { proposal_id, .. } => *proposal_id,
+        }
+    }
+
+    pub fn sig_msg(&self) -> H256 {
+        blake2b(&bincode::serialize(self).unwrap())
+    }
+}
+
+/// A message signed by the participant sending it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SignedOpenMessage {
+    pub message: OpenMessage,
+    pub signature: Signature,
+}
+
+impl SignedOpenMessage {
+    pub fn sender(&self) -> Option<H160> {
+        recover_address(self.message.sig_msg(), &self.signature)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OpenStage {
+    // Waiting for the counterparty to accept
+    Proposed,
+    // Both sides agreed, waiting for their deposits to be relayed
+    Accepted,
+    // `CreateChannel` is in the mempool
+    Submitted,
+    Opened,
+    Rejected,
+    Expired,
+}
+
+/// One side's view of an open handshake.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Negotiation {
+    pub proposal: OpenProposal,
+    // Index of the node's operator in `participant2`
+    pub me: usize,
+    pub proposed_by_me: bool,
+    pub deposit2: [Option<OutPoint>; 2],
+    pub stage: OpenStage,
+    pub reason: Option<String>,
+    pub tx_hash: Option<H256>,
+    pub started_at_ms: u64,
+}
+
+/// Runs the operator's side of the open handshake: proposals are accepted
+/// within the policy, deposits are followed through the transfer tracker,
+/// and the proposer submits `CreateChannel` once both have been relayed.
+#[derive(Clone)]
+pub struct ChannelOpener {
+    negotiations: AsyncStore,
+    mempool: ChannelMap,
+    transfers: TransferTracker,
+    operator_key: SecretKey,
+    operator: H160,
+    chain_id: u64,
+    policy: OpenPolicy,
+}
+
+impl ChannelOpener {
+    pub fn new(
+        store: &Store,
+        mempool: ChannelMap,
+        transfers: TransferTracker,
+        chain_id: u64,
+        operator_key: SecretKey,
+        policy: OpenPolicy,
+    ) -> Result<Self, StoreError> {
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &operator_key);
+        let opener = ChannelOpener {
+            negotiations: AsyncStore::new(store.open_tree(NEGOTIATION_TREE)?),
+            mempool,
+            transfers,
+            operator_key,
+            operator: secp256k1_address(&pubkey),
+            chain_id,
+            policy,
+        };
+
+        Ok(opener)
+    }
+
+    pub fn operator(&self) -> H160 {
+        self.operator
+    }
+
+    /// Start a handshake, returns the message for the counterparty.
+    pub async fn propose(&self, proposal: OpenProposal) -> Result<SignedOpenMessage> {
+        let me = self.position(&proposal)?;
+        self.check(&proposal)?;
+        if self.negotiation(proposal.id()).await?.is_some() {
+            return Err(anyhow!("proposal {:?} already exists", proposal.id()));
+        }
+
+        let negotiation = Negotiation {
+            me,
+            proposed_by_me: true,
+            deposit2: [None; 2],
+            stage: OpenStage::Proposed,
+            reason: None,
+            tx_hash: None,
+            started_at_ms: time_now_ms(),
+            proposal: proposal.clone(),
+        };
+        self.negotiations.insert(proposal.id(), negotiation).await?;
+
+        Ok(self.sign(OpenMessage::Propose(proposal)))
+    }
+
+    /// Record the operator's own deposit, returns the message telling the
+    /// counterparty about it.
+    pub async fn fund(&self, proposal_id: H256, out_point: OutPoint) -> Result<SignedOpenMessage> {
+        let mut negotiation = self.accepted(proposal_id).await?;
+        negotiation.deposit2[negotiation.me] = Some(out_point);
+        self.negotiations.insert(proposal_id, negotiation).await?;
+
+        Ok(self.sign(OpenMessage::Funded {
+            proposal_id,
+            out_point,
+        }))
+    }
+
+    /// Handle a message from the counterparty, returns the reply to send
+    /// back if any.
+    pub async fn handle(&self, msg: SignedOpenMessage) -> Result<Option<SignedOpenMessage>> {
+        if !self.policy.enabled {
+            return Err(anyhow!("channel opening is disabled"));
+        }
+        let sender = msg
+            .sender()
+            .ok_or_else(|| anyhow!("invalid open message signature"))?;
+
+        let proposal_id = msg.message.proposal_id();
+        let proposal = match &msg.message {
+            OpenMessage::Propose(proposal) => proposal,
+            _ => {
+                let negotiation = self.negotiation(proposal_id).await?;
+                let negotiation =
+                    negotiation.ok_or_else(|| anyhow!("proposal {:?} not found", proposal_id))?;
+                return self.handle_reply(negotiation, sender, msg.message).await;
+            }
+        };
+
+        let me = self.position(proposal)?;
+        if proposal.participant2[1 - me] != sender {
+            return Err(anyhow!("proposal isn't sent by the counterparty"));
+        }
+        if let Some(negotiation) = self.negotiation(proposal_id).await? {
+            // The counterparty didn't see our answer, send it again
+            return match negotiation.stage {
+                OpenStage::Rejected => Ok(Some(self.reject(proposal_id, negotiation.reason))),
+                _ => Ok(Some(self.sign(OpenMessage::Accept { proposal_id }))),
+            };
+        }
+
+        let mut negotiation = Negotiation {
+            me,
+            proposed_by_me: false,
+            deposit2: [None; 2],
+            stage: OpenStage::Accepted,
+            reason: None,
+            tx_hash: None,
+            started_at_ms: time_now_ms(),
+            proposal: proposal.clone(),
+        };
+        let reply = match self.check(proposal) {
+            Ok(()) => self.sign(OpenMessage::Accept { proposal_id }),
+            Err(err) => {
+                negotiation.stage = OpenStage::Rejected;
+                negotiation.reason = Some(err.to_string());
+                self.reject(proposal_id, negotiation.reason.clone())
+            }
+        };
+        self.negotiations.insert(proposal_id, negotiation).await?;
+
+        Ok(Some(reply))
+    }
+
+    /// Submit `CreateChannel` for the operator's proposals whose deposits
+    /// were both relayed, and expire the ones funded too slowly. Returns
+    /// the proposals submitted.
+    pub async fn poll_funding(&self) -> Result<Vec<H256>> {
+        let now_ms = time_now_ms();
+        let timeout_ms = self.policy.funding_timeout_secs.saturating_mul(1000);
+        let mut submitted = Vec::new();
+        for mut negotiation in self.negotiations().await? {
+            let proposal_id = negotiation.proposal.id();
+            let expired = now_ms.saturating_sub(negotiation.started_at_ms) >= timeout_ms;
+            match negotiation.stage {
+                OpenStage::Proposed | OpenStage::Accepted if expired => {
+                    negotiation.stage = OpenStage::Expired;
+                    self.negotiations.insert(proposal_id, negotiation).await?;
+                    continue;
+                }
+                OpenStage::Accepted if negotiation.proposed_by_me => (),
+                _ => continue,
+            }
+
+            let mut relayed = true;
+            for out_point in negotiation.deposit2.iter() {
+                let status = match out_point {
+                    Some(out_point) => self.transfers.get_deposit_status(*out_point).await?,
+                    None => None,
+                };
+                relayed &= status.map(|s| s.stage >= DepositStage::Relayed) == Some(true);
+            }
+            if !relayed {
+                continue;
+            }
+
+            let tx = self.create_channel_tx(&negotiation.proposal)?;
+            negotiation.stage = OpenStage::Submitted;
+            negotiation.tx_hash = Some(tx.hash);
+            self.mempool.push_transaction(tx)?;
+            println!(
+                "[opening] deposits of channel {} relayed, submitted {:?}",
+                negotiation.proposal.channel_id,
+                negotiation.tx_hash.unwrap_or_default()
+            );
+
+            self.negotiations.insert(proposal_id, negotiation).await?;
+            submitted.push(proposal_id);
+        }
+
+        Ok(submitted)
+    }
+
+    /// Mark channels the block created as opened and their deposits as
+    /// credited.
+    pub async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        let block_number = receipt.block.header.number;
+        for mut negotiation in self.negotiations().await? {
+            if !matches!(
+                negotiation.stage,
+                OpenStage::Accepted | OpenStage::Submitted
+            ) {
+                continue;
+            }
+            let proposal = &negotiation.proposal;
+            let channel = match receipt.updated_channels.get(&proposal.channel_id.to_h256()) {
+                Some(channel) => channel,
+                None => continue,
+            };
+            // Someone else took the channel id
+            if channel.version != 0 || channel.participant2 != proposal.participant2 {
+                continue;
+            }
+
+            for out_point in negotiation.deposit2.iter().flatten() {
+                let status = self.transfers.get_deposit_status(*out_point).await?;
+                if status.map(|s| s.stage < DepositStage::Credited) == Some(true) {
+                    self.transfers
+                        .deposit_credited(*out_point, block_number)
+                        .await?;
+                }
+            }
+            negotiation.stage = OpenStage::Opened;
+            self.negotiations
+                .insert(proposal.id(), &negotiation)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn negotiation(&self, proposal_id: H256) -> Result<Option<Negotiation>> {
+        Ok(self.negotiations.get(&proposal_id).await?)
+    }
+
+    /// Every negotiation, for the admin RPC.
+    pub async fn negotiations(&self) -> Result<Vec<Negotiation>> {
+        Ok(self.negotiations.run(|store| store.values()).await??)
+    }
+
+    async fn handle_reply(
+        &self,
+        mut negotiation: Negotiation,
+        sender: H160,
+        message: OpenMessage,
+    ) -> Result<Option<SignedOpenMessage>> {
+        let counterparty = 1 - negotiation.me;
+        if negotiation.proposal.participant2[counterparty] != sender {
+            return Err(anyhow!("message isn't sent by the counterparty"));
+        }
+
+        let proposal_id = negotiation.proposal.id();
+        match (negotiation.stage, message) {
+            (OpenStage::Proposed, OpenMessage::Accept { .. }) => {
+                negotiation.stage = OpenStage::Accepted;
+            }
+            (OpenStage::Proposed | OpenStage::Accepted, OpenMessage::Reject { reason, .. }) => {
+                negotiation.stage = OpenStage::Rejected;
+                negotiation.reason = Some(reason);
+            }
+            (OpenStage::Accepted, OpenMessage::Funded { out_point, .. }) => {
+                negotiation.deposit2[counterparty] = Some(out_point);
+            }
+            (stage, message) => {
+                return Err(anyhow!(
+                    "unexpected {:?} for proposal {:?} in stage {:?}",
+                    message,
+                    proposal_id,
+                    stage
+                ));
+            }
+        }
+        self.negotiations.insert(proposal_id, negotiation).await?;
+
+        Ok(None)
+    }
+
+    async fn accepted(&self, proposal_id: H256) -> Result<Negotiation> {
+        match self.negotiation(proposal_id).await? {
+            Some(negotiation) if negotiation.stage == OpenStage::Accepted => Ok(negotiation),
+            Some(negotiation) => Err(anyhow!(
+                "proposal {:?} is {:?}, not accepted",
+                proposal_id,
+                negotiation.stage
+            )),
+            None => Err(anyhow!("proposal {:?} not found", proposal_id)),
+        }
+    }
+
+    fn position(&self, proposal: &OpenProposal) -> Result<usize> {
+        { proposal.participant2.iter() }
+            .position(|p| *p == self.operator)
+            .ok_or_else(|| anyhow!("operator isn't a participant of the proposal"))
+    }
+
+    fn check(&self, proposal: &OpenProposal) -> Result<()> {
+        if proposal.chain_id != self.chain_id {
+            return Err(anyhow!(
+                "chain id {} mismatch, expect {}",
+                proposal.chain_id,
+                self.chain_id
+            ));
+        }
+        if proposal.participant2[0] == proposal.participant2[1] {
+            return Err(anyhow!("participants must differ"));
+        }
+        if proposal.challenge_blocks < self.policy.min_challenge_blocks {
+            return Err(anyhow!(
+                "challenge blocks {} below the minimum of {}",
+                proposal.challenge_blocks,
+                self.policy.min_challenge_blocks
+            ));
+        }
+        let max = U128::from(self.policy.max_deposit);
+        if proposal.deposit2.iter().any(|deposit| *deposit > max) {
+            return Err(anyhow!("deposit above the maximum of {}", max));
+        }
+        if proposal.deposit2.iter().all(|deposit| deposit.is_zero()) {
+            return Err(anyhow!("channel has no deposit"));
+        }
+
+        Ok(())
+    }
+
+    fn sign(&self, message: OpenMessage) -> SignedOpenMessage {
+        SignedOpenMessage {
+            signature: sign_recoverable(&self.operator_key, message.sig_msg()),
+            message,
+        }
+    }
+
+    fn reject(&self, proposal_id: H256, reason: Option<String>) -> SignedOpenMessage {
+        self.sign(OpenMessage::Reject {
+            proposal_id,
+            reason: reason.unwrap_or_default(),
+        })
+    }
+
+    fn create_channel_tx(&self, proposal: &OpenProposal) -> Result<SignedTransaction> {
+        let raw = RawTransaction::CreateChannel(proposal.create_channel());
+        let hash = blake2b(&bincode::serialize(&TransactionEnvelope::from(
+            raw.clone(),
+        ))?);
+
+        Ok(SignedTransaction {
+            sig: sign_recoverable(&self.operator_key, hash),
+            fee: self.policy.create_fee.into(),
+            from: self.operator,
+            hash,
+            raw,
+        })
+    }
+}
+
+#[async_trait]
+impl Job for ChannelOpener {
+    fn name(&self) -> &'static str {
+        "open_funding"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        match self.policy.enabled {
+            true => Schedule::Every(self.policy.poll_interval_secs.max(1)),
+            false => Schedule::Never,
+        }
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.poll_funding().await.map(|_| ())
+    }
+}
+
+fn time_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use tempfile::tempdir;
+
+    use crate::{
+        finality::FinalityTracker,
+        types::{Block, BlockHeader, Channel, ChannelState},
+    };
+
+    use super::*;
+
+    const CHAIN_ID: u64 = 1;
+
+    fn opener(key: u8, mempool: ChannelMap) -> (ChannelOpener, TransferTracker) {
+        let store = Store::open(tempdir().unwrap().into_path()).unwrap();
+        let transfers =
+            TransferTracker::new(&store, FinalityTracker::new(store.clone(), 10).unwrap()).unwrap();
+        let policy = OpenPolicy {
+            enabled: true,
+            ..Default::default()
+        };
+        let key = SecretKey::from_slice(&[key; 32]).unwrap();
+        let opener =
+            ChannelOpener::new(&store, mempool, transfers.clone(), CHAIN_ID, key, policy).unwrap();
+        (opener, transfers)
+    }
+
+    #[tokio::test]
+    async fn test_open_handshake() {
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let (alice, alice_transfers) = opener(1, mempool.clone());
+        let (bob, _) = opener(2, ChannelMap::new(CHAIN_ID));
+
+        let proposal = OpenProposal {
+            chain_id: CHAIN_ID,
+            channel_id: 9.into(),
+            token: Token::default(),
+            challenge_blocks: 100,
+            participant2: [alice.operator(), bob.operator()],
+            deposit2: [600.into(), 400.into()],
+            guard: None,
+        };
+        let proposal_id = proposal.id();
+
+        // Too short a challenge period is rejected
+        let short = alice
+            .propose(OpenProposal {
+                challenge_blocks: 1,
+                ..proposal.clone()
+            })
+            .await;
+        assert!(short.is_err());
+
+        let propose = alice.propose(proposal.clone()).await.unwrap();
+        let accept = bob.handle(propose).await.unwrap().unwrap();
+        assert_eq!(accept.message, OpenMessage::Accept { proposal_id });
+        assert!(alice.handle(accept).await.unwrap().is_none());
+
+        let deposits = [
+            OutPoint {
+                tx_hash: H256::repeat_byte(1),
+                index: 0,
+            },
+            OutPoint {
+                tx_hash: H256::repeat_byte(2),
+                index: 0,
+            },
+        ];
+        alice.fund(proposal_id, deposits[0]).await.unwrap();
+        let funded = bob.fund(proposal_id, deposits[1]).await.unwrap();
+        alice.handle(funded).await.unwrap();
+
+        // Nothing is submitted before both deposits are relayed
+        for out_point in deposits {
+            alice_transfers.deposit_detected(out_point).await.unwrap();
+        }
+        alice_transfers.deposit_relayed(deposits[0]).await.unwrap();
+        assert!(alice.poll_funding().await.unwrap().is_empty());
+        alice_transfers.deposit_relayed(deposits[1]).await.unwrap();
+        assert_eq!(alice.poll_funding().await.unwrap(), vec![proposal_id]);
+
+        let txs = mempool.package_transactions().unwrap();
+        assert_eq!(txs.len(), 1);
+        match &txs[0].raw {
+            RawTransaction::CreateChannel(args) => {
+                assert_eq!(args.participant2, proposal.participant2);
+                assert_eq!(args.balance2[1].settled, 400.into());
+            }
+            _ => panic!("not a channel creation"),
+        }
+
+        let channel = Channel {
+            id: proposal.channel_id,
+            participant2: proposal.participant2,
+            state: ChannelState::Open,
+            ..Default::default()
+        };
+        let receipt = ConsensusReceipt {
+            block: Arc::new(Block {
+                header: BlockHeader {
+                    number: 3,
+                    ..Default::default()
+                },
+                txs,
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: BTreeMap::from([(channel.id.to_h256(), channel)]),
+        };
+        alice.on_consensus_receipt(&receipt).await.unwrap();
+
+        let negotiation = alice.negotiation(proposal_id).await.unwrap().unwrap();
+        assert_eq!(negotiation.stage, OpenStage::Opened);
+        let status = alice_transfers.get_deposit_status(deposits[1]).await;
+        assert_eq!(status.unwrap().unwrap().stage, DepositStage::Credited);
+    }
+
+    #[tokio::test]
+    async fn test_reject_out_of_policy() {
+        let (alice, _) = opener(1, ChannelMap::new(CHAIN_ID));
+        let (bob, _) = opener(2, ChannelMap::new(CHAIN_ID));
+
+        let proposal = OpenProposal {
+            chain_id: CHAIN_ID,
+            channel_id: 9.into(),
+            token: Token::default(),
+            challenge_blocks: 100,
+            participant2: [alice.operator(), bob.operator()],
+            deposit2: [600.into(), 400.into()],
+            guard: None,
+        };
+        let propose = alice.propose(proposal.clone()).await.unwrap();
+
+        // Bob's node only takes longer challenge periods
+        let bob = ChannelOpener {
+            policy: OpenPolicy {
+                enabled: true,
+                min_challenge_blocks: 500,
+                ..Default::default()
+            },
+            ..bob
+        };
+        let reject = bob.handle(propose.clone()).await.unwrap().unwrap();
+        assert!(matches!(reject.message, OpenMessage::Reject { .. }));
+        alice.handle(reject).await.unwrap();
+
+        let negotiation = alice.negotiation(proposal.id()).await.unwrap().unwrap();
+        assert_eq!(negotiation.stage, OpenStage::Rejected);
+        assert!(alice
+            .fund(
+                proposal.id(),
+                OutPoint {
+                    tx_hash: H256::zero(),
+                    index: 0,
+                }
+            )
+            .await
+            .is_err());
+
+        // A forged sender is refused
+        let forged = SignedOpenMessage {
+            signature: propose.signature.clone(),
+            message: OpenMessage::Accept {
+                proposal_id: proposal.id(),
+            },
+        };
+        assert!(alice.handle(forged).await.is_err());
+    }
+}
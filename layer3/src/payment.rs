@@ -0,0 +1,269 @@
+use anyhow::Result;
+use primitive_types::{H256, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::H256Ext,
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    finality::FinalityTracker,
+    types::RawTransaction,
+    withdrawal::closed_channel_withdrawals,
+};
+
+const PAYMENT_TREE: &str = "payment_status";
+// Channel id to the payments made on it that aren't settled yet
+const CHANNEL_PAYMENT_TREE: &str = "channel_payment";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PaymentStage {
+    // Paid by updates in blocks that aren't final yet
+    Committed,
+    // Every paying update is in a final block
+    Final,
+    // The channels that carried it were closed and paid out
+    Settled,
+}
+
+/// A channel update carrying the payment.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PaymentUpdate {
+    pub channel_id: U256,
+    pub version: u64,
+    pub tx_hash: H256,
+    pub block_number: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PaymentSettlement {
+    pub channel_id: U256,
+    pub block_number: u64,
+    // Withdrawals paying out the closed channel
+    pub withdrawal_ids: Vec<H256>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PaymentStatus {
+    pub payment_id: H256,
+    pub stage: PaymentStage,
+    pub updates: Vec<PaymentUpdate>,
+    pub settlements: Vec<PaymentSettlement>,
+}
+
+/// Lets a merchant follow a payment id from the channel updates that paid
+/// it to the settlement of those channels.
+#[derive(Clone)]
+pub struct PaymentTracker {
+    payments: AsyncStore,
+    channel_payments: AsyncStore,
+    finality: FinalityTracker,
+}
+
+impl PaymentTracker {
+    pub fn new(store: &Store, finality: FinalityTracker) -> Result<Self, StoreError> {
+        let tracker = PaymentTracker {
+            payments: AsyncStore::new(store.open_tree(PAYMENT_TREE)?),
+            channel_payments: AsyncStore::new(store.open_tree(CHANNEL_PAYMENT_TREE)?),
+            finality,
+        };
+
+        Ok(tracker)
+    }
+
+    pub async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        let block_number = receipt.block.header.number;
+        for tx in receipt.block.txs.iter() {
+            let (args, payment_id) = match &tx.raw {
+                RawTransaction::UpdateChannel(args) => match args.payment_id {
+                    Some(payment_id) => (args, payment_id),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            // Failed updates leave the channel at an older version
+            match receipt.updated_channels.get(&args.channel_id.to_h256()) {
+                Some(channel) if channel.version == args.version => (),
+                _ => continue,
+            }
+
+            let mut status = { self.payments.get(&payment_id).await? }.unwrap_or(PaymentStatus {
+                payment_id,
+                stage: PaymentStage::Committed,
+                updates: Vec::new(),
+                settlements: Vec::new(),
+            });
+            status.updates.push(PaymentUpdate {
+                channel_id: args.channel_id,
+                version: args.version,
+                tx_hash: tx.hash,
+                block_number,
+            });
+            self.payments.insert(payment_id, &status).await?;
+
+            let mut open = self.payments_on(args.channel_id).await?;
+            if !open.contains(&payment_id) {
+                open.push(payment_id);
+                self.channel_payments.insert(args.channel_id, open).await?;
+            }
+        }
+
+        let withdrawals = closed_channel_withdrawals(receipt);
+        for channel_id in receipt.updated_channels.values().map(|c| c.id) {
+            let ids = { withdrawals.iter() }
+                .filter(|w| w.channel_id == channel_id)
+                .map(|w| w.request_id)
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                continue;
+            }
+
+            for payment_id in self.payments_on(channel_id).await? {
+                let mut status = match self.payments.get::<_, PaymentStatus>(&payment_id).await? {
+                    Some(status) => status,
+                    None => continue,
+                };
+                status.settlements.push(PaymentSettlement {
+                    channel_id,
+                    block_number,
+                    withdrawal_ids: ids.clone(),
+                });
+                self.payments.insert(payment_id, &status).await?;
+            }
+            self.channel_payments.remove(channel_id).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_payment_status(&self, payment_id: H256) -> Result<Option<PaymentStatus>> {
+        let mut status = match self.payments.get::<_, PaymentStatus>(&payment_id).await? {
+            Some(status) => status,
+            None => return Ok(None),
+        };
+
+        let settled = { status.updates.iter() }
+            .all(|update| { status.settlements.iter() }.any(|s| s.channel_id == update.channel_id));
+        status.stage = if settled {
+            PaymentStage::Settled
+        } else if self.all_final(&status.updates).await? {
+            PaymentStage::Final
+        } else {
+            PaymentStage::Committed
+        };
+
+        Ok(Some(status))
+    }
+
+    async fn payments_on(&self, channel_id: U256) -> Result<Vec<H256>> {
+        Ok(self
+            .channel_payments
+            .get(&channel_id)
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn all_final(&self, updates: &[PaymentUpdate]) -> Result<bool> {
+        for update in updates {
+            let finality = self
+                .finality
+                .get_block_finality(update.block_number)
+                .await?;
+            if !finality.map(|f| f.is_irreversible()).unwrap_or(false) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use primitive_types::{H160, U128};
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::chain::{Chain, ChannelChain},
+        fixture::consensus_receipt,
+        types::{Balance, Channel, ChannelState, CloseChannel, SignedTransaction, UpdateChannel},
+    };
+
+    use super::*;
+
+    fn receipt(number: u64, raw: RawTransaction, balances: [u64; 2]) -> ConsensusReceipt {
+        let tx = SignedTransaction {
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::zero(),
+            hash: H256::from_low_u64_be(number),
+            raw,
+        };
+        let channel = Channel {
+            id: tx.raw.channel_id(),
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            state: ChannelState::Open,
+            version: tx.raw.version(),
+            balance2: balances.map(|settled| Balance {
+                settled: settled.into(),
+            }),
+            ..Default::default()
+        };
+
+        consensus_receipt(number, vec![tx], vec![channel])
+    }
+
+    #[tokio::test]
+    async fn test_payment_stages() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let chain = ChannelChain::new(store.clone()).unwrap();
+        let finality = FinalityTracker::new(store.clone(), 0).unwrap();
+        let tracker = PaymentTracker::new(&store, finality.clone()).unwrap();
+
+        let payment_id = H256::repeat_byte(0xaa);
+        let update = RawTransaction::UpdateChannel(UpdateChannel {
+            channel_id: 7.into(),
+            version: 2,
+            payment_id: Some(payment_id),
+            ..Default::default()
+        });
+        let close = RawTransaction::CloseChannel(CloseChannel {
+            channel_id: 7.into(),
+            version: 2,
+            ..Default::default()
+        });
+        let receipts = [receipt(1, update, [90, 10]), receipt(2, close, [90, 10])];
+
+        for (number, receipt) in receipts.iter().enumerate() {
+            chain.save_block(Arc::clone(&receipt.block)).await.unwrap();
+            tracker.on_consensus_receipt(receipt).await.unwrap();
+            if number == 0 {
+                let status = tracker.get_payment_status(payment_id).await.unwrap();
+                let status = status.unwrap();
+                assert_eq!(status.stage, PaymentStage::Committed);
+                assert_eq!(status.updates[0].tx_hash, H256::from_low_u64_be(1));
+
+                finality.submitted_to_l2(1, H256::zero()).await.unwrap();
+                finality.l2_confirmed(1, 1).await.unwrap();
+                finality.committed_to_ckb(1, H256::zero(), 1).await.unwrap();
+                finality.ckb_tip_updated(1).await.unwrap();
+                let status = tracker.get_payment_status(payment_id).await.unwrap();
+                assert_eq!(status.unwrap().stage, PaymentStage::Final);
+            }
+        }
+
+        let status = tracker.get_payment_status(payment_id).await.unwrap();
+        let status = status.unwrap();
+        assert_eq!(status.stage, PaymentStage::Settled);
+        assert_eq!(status.settlements[0].block_number, 2);
+        assert_eq!(status.settlements[0].withdrawal_ids.len(), 2);
+        assert!(tracker
+            .get_payment_status(H256::zero())
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
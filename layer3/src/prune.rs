@@ -0,0 +1,246 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        receipt::ReceiptStream,
+        store::{AsyncStore, Store, StoreError},
+    },
+    checkpoint::CheckpointManager,
+    finality::FinalityTracker,
+    scheduler::{Job, Schedule},
+    tracking::TransferTracker,
+};
+
+const PRUNE_TREE: &str = "prune";
+const PRUNED_TIP_KEY: &str = "pruned_tip";
+
+/// How much transaction data the node keeps. Disabled unless turned on in
+/// config.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PrunePolicy {
+    pub enabled: bool,
+    // Transactions and receipts of the latest blocks are always kept
+    pub keep_blocks: u64,
+    pub interval_secs: u64,
+}
+
+impl Default for PrunePolicy {
+    fn default() -> Self {
+        PrunePolicy {
+            enabled: false,
+            keep_blocks: 100_000,
+            interval_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    // Every block up to this one has been pruned
+    pub pruned_tip: u64,
+    // Pruned by this run
+    pub blocks: u64,
+    pub transactions: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// Drops the transaction index and receipts of old blocks, block headers
+/// and bodies stay. Only finalized blocks are pruned, and never a block
+/// holding a withdrawal that hasn't been claimed yet.
+#[derive(Clone)]
+pub struct Pruner {
+    store: AsyncStore,
+    chain: ChannelChain,
+    receipts: ReceiptStream,
+    finality: FinalityTracker,
+    transfers: TransferTracker,
+    meta: AsyncStore,
+    policy: PrunePolicy,
+    // Pruning stops at the latest settled checkpoint when set
+    checkpoints: Option<CheckpointManager>,
+}
+
+impl Pruner {
+    pub fn new(
+        store: &Store,
+        receipts: ReceiptStream,
+        finality: FinalityTracker,
+        transfers: TransferTracker,
+        policy: PrunePolicy,
+    ) -> Result<Self, StoreError> {
+        let pruner = Pruner {
+            store: AsyncStore::new(store.clone()),
+            chain: ChannelChain::new(store.clone())?,
+            receipts,
+            finality,
+            transfers,
+            meta: AsyncStore::new(store.open_tree(PRUNE_TREE)?),
+            policy,
+            checkpoints: None,
+        };
+
+        Ok(pruner)
+    }
+
+    pub fn with_checkpoints(mut self, checkpoints: CheckpointManager) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    pub async fn pruned_tip(&self) -> Result<u64> {
+        Ok(self.meta.get(&PRUNED_TIP_KEY).await?.unwrap_or_default())
+    }
+
+    /// Highest block that may be pruned now.
+    pub async fn prunable_tip(&self) -> Result<u64> {
+        let tip = { self.chain.tip_header().await? }
+            .map(|header| header.number)
+            .unwrap_or_default();
+
+        let mut prunable = { tip.saturating_sub(self.policy.keep_blocks) }
+            .min(self.finality.finalized_tip().await?);
+        if let Some(number) = self.transfers.oldest_unclaimed_withdrawal().await? {
+            prunable = prunable.min(number.saturating_sub(1));
+        }
+        if let Some(checkpoints) = &self.checkpoints {
+            prunable = prunable.min(checkpoints.proof_horizon().await?);
+        }
+
+        Ok(prunable)
+    }
+
+    /// Prune every block past the last pruned one up to `prunable_tip`,
+    /// then flush so sled can release the emptied segments.
+    pub async fn prune(&self) -> Result<PruneReport> {
+        let size_before = self.store.run(|store| store.size_on_disk()).await??;
+        let mut report = PruneReport {
+            pruned_tip: self.pruned_tip().await?,
+            ..Default::default()
+        };
+
+        for number in report.pruned_tip + 1..=self.prunable_tip().await? {
+            let hashes = self.chain.prune_transactions(number).await?;
+            report.transactions += hashes.len() as u64;
+            let receipts = self.receipts.clone();
+            self.store
+                .run(move |_| -> Result<(), StoreError> {
+                    for hash in hashes.iter() {
+                        receipts.remove_receipt(hash)?;
+                    }
+                    Ok(())
+                })
+                .await??;
+            self.meta.insert(PRUNED_TIP_KEY, number).await?;
+
+            report.pruned_tip = number;
+            report.blocks += 1;
+        }
+
+        let size_after = self
+            .store
+            .run(|store| -> Result<u64, StoreError> {
+                store.flush_db()?;
+                store.size_on_disk()
+            })
+            .await??;
+        report.reclaimed_bytes = size_before.saturating_sub(size_after);
+
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl Job for Pruner {
+    fn name(&self) -> &'static str {
+        "prune"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        match self.policy.enabled {
+            true => Schedule::Every(self.policy.interval_secs.max(1)),
+            false => Schedule::Never,
+        }
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.prune().await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::H256;
+    use tempfile::tempdir;
+
+    use crate::fixture::save_blocks;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prune_keeps_unclaimed_withdrawals() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(&tmp_db_path).unwrap();
+        let chain = ChannelChain::new(store.clone()).unwrap();
+        let receipts = ReceiptStream::new(&store).unwrap();
+
+        save_blocks(&store, 4, |_| 0, Some(&receipts)).await;
+
+        let finality = FinalityTracker::new(store.clone(), 1).unwrap();
+        for number in 1..=4 {
+            finality
+                .committed_to_ckb(number, H256::zero(), number)
+                .await
+                .unwrap();
+        }
+        finality.ckb_tip_updated(100).await.unwrap();
+
+        let transfers = TransferTracker::new(&store, finality.clone()).unwrap();
+        let request_id = H256::repeat_byte(9);
+        transfers.withdrawal_queued(request_id).await.unwrap();
+        transfers.withdrawal_committed(request_id, 3).await.unwrap();
+
+        let policy = PrunePolicy {
+            keep_blocks: 1,
+            ..Default::default()
+        };
+        let pruner = Pruner::new(
+            &store,
+            receipts.clone(),
+            finality,
+            transfers.clone(),
+            policy,
+        )
+        .unwrap();
+
+        // Block 3 holds an unclaimed withdrawal
+        let report = pruner.prune().await.unwrap();
+        assert_eq!((report.pruned_tip, report.blocks), (2, 2));
+        assert!(chain
+            .get_transaction(H256::from_low_u64_be(2))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(receipts
+            .get_receipt(&H256::from_low_u64_be(2))
+            .unwrap()
+            .is_none());
+        assert!(chain
+            .get_transaction(H256::from_low_u64_be(3))
+            .await
+            .unwrap()
+            .is_some());
+
+        // Block 4 is within `keep_blocks`
+        transfers.withdrawal_claimed(request_id).await.unwrap();
+        let report = pruner.prune().await.unwrap();
+        assert_eq!((report.pruned_tip, report.blocks), (3, 1));
+        assert!(receipts
+            .get_receipt(&H256::from_low_u64_be(4))
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,352 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use primitive_types::{H160, U128, U256};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::{secp256k1_address, sign_recoverable, H256Ext},
+        smt::SMT,
+        snapshot::CHANNEL_INDEX_TREE,
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    scheduler::{Job, Schedule},
+    types::{Balance, Channel, ChannelState, UpdateChannel},
+};
+
+const ACTION_TREE: &str = "rebalance_action";
+const BPS: u64 = 10_000;
+
+/// Limits of the rebalancing service. Disabled unless turned on in config.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RebalancePolicy {
+    pub enabled: bool,
+    // Operator share of a channel the service steers towards, in basis points
+    pub target_bps: u32,
+    // Distance from the target, in basis points, before a channel counts as
+    // skewed
+    pub threshold_bps: u32,
+    // Upper bound on the amount moved by a single proposal, in base units
+    pub max_amount: u64,
+    // A channel isn't proposed again while its last proposal is younger
+    pub cooldown_secs: u64,
+    pub scan_interval_secs: u64,
+}
+
+impl Default for RebalancePolicy {
+    fn default() -> Self {
+        RebalancePolicy {
+            enabled: false,
+            target_bps: 5_000,
+            threshold_bps: 2_000,
+            max_amount: u64::MAX,
+            cooldown_secs: 60 * 60,
+            scan_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ActionStatus {
+    // Signed by the operator, waiting for the counterparty
+    Proposed,
+    // Committed in a block with the proposed balances
+    Applied,
+    // The channel moved past the proposed version with other balances
+    Superseded,
+}
+
+/// A rebalancing update proposed for one channel.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RebalanceAction {
+    pub channel_id: U256,
+    // Operator balance before and after the update
+    pub from: U128,
+    pub to: U128,
+    // Carries the operator signature, the counterparty adds the other one
+    pub update: UpdateChannel,
+    pub proposed_at_ms: u64,
+    pub status: ActionStatus,
+}
+
+/// Watches the operator's channels and proposes updates moving skewed
+/// balances back towards the configured target share. Proposals are kept
+/// per channel for the admin RPC to list.
+#[derive(Clone)]
+pub struct Rebalancer {
+    store: AsyncStore,
+    index: Store,
+    actions: AsyncStore,
+    operator_key: SecretKey,
+    operator: H160,
+    chain_id: u64,
+    policy: RebalancePolicy,
+}
+
+impl Rebalancer {
+    pub fn new(
+        store: &Store,
+        chain_id: u64,
+        operator_key: SecretKey,
+        policy: RebalancePolicy,
+    ) -> Result<Self, StoreError> {
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &operator_key);
+        let rebalancer = Rebalancer {
+            store: AsyncStore::new(store.clone()),
+            index: store.open_tree(CHANNEL_INDEX_TREE)?,
+            actions: AsyncStore::new(store.open_tree(ACTION_TREE)?),
+            operator_key,
+            operator: secp256k1_address(&pubkey),
+            chain_id,
+            policy,
+        };
+
+        Ok(rebalancer)
+    }
+
+    pub fn operator(&self) -> H160 {
+        self.operator
+    }
+
+    /// Operator signed update for `channel`, or `None` if the channel isn't
+    /// the operator's or isn't skewed past the threshold.
+    pub fn propose(&self, channel: &Channel) -> Option<RebalanceAction> {
+        if channel.state != ChannelState::Open {
+            return None;
+        }
+        let me = channel
+            .participant2
+            .iter()
+            .position(|p| *p == self.operator)?;
+
+        let balances = channel.balance2.clone().map(|b| b.settled.as_u128());
+        let total = balances[0].checked_add(balances[1])?;
+        if total == 0 {
+            return None;
+        }
+
+        // In U256 so that scaling by basis points can't overflow
+        let share_bps = (U256::from(balances[me]) * BPS / total).as_u128();
+        let target_bps = u64::from(self.policy.target_bps).min(BPS);
+        if share_bps.abs_diff(target_bps.into()) < u128::from(self.policy.threshold_bps) {
+            return None;
+        }
+
+        let target = (U256::from(total) * target_bps / BPS).as_u128();
+        let max = u128::from(self.policy.max_amount);
+        let to = if target > balances[me] {
+            balances[me] + (target - balances[me]).min(max)
+        } else {
+            balances[me] - (balances[me] - target).min(max)
+        };
+        if to == balances[me] {
+            return None;
+        }
+
+        let mut balance2 = channel.balance2.clone();
+        balance2[me] = Balance { settled: to.into() };
+        balance2[1 - me] = Balance {
+            settled: (total - to).into(),
+        };
+
+        let mut update = UpdateChannel {
+            chain_id: self.chain_id,
+            channel_id: channel.id,
+            version: channel.version + 1,
+            balance2,
+            ..Default::default()
+        };
+        update.signature2[me] = sign_recoverable(&self.operator_key, update.sig_msg());
+
+        Some(RebalanceAction {
+            channel_id: channel.id,
+            from: balances[me].into(),
+            to: to.into(),
+            update,
+            proposed_at_ms: time_now_ms(),
+            status: ActionStatus::Proposed,
+        })
+    }
+
+    /// Check every channel and record a proposal for the skewed ones, unless
+    /// a proposal for the same channel version is still cooling down.
+    pub async fn scan(&self) -> Result<Vec<RebalanceAction>> {
+        let index = self.index.clone();
+        let channels = self
+            .store
+            .run(move |store| -> Result<Vec<Channel>> {
+                let ids: Vec<U256> = index.values()?;
+                let smt = SMT::new_with_store(store.clone())?;
+                let channels = { ids.iter() }
+                    .map(|id| smt.get(&id.to_h256()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(channels)
+            })
+            .await??;
+
+        let cooldown_ms = self.policy.cooldown_secs.saturating_mul(1000);
+        let mut proposed = Vec::new();
+        for channel in channels {
+            let action = match self.propose(&channel) {
+                Some(action) => action,
+                None => continue,
+            };
+
+            let last = self.actions.get::<_, RebalanceAction>(&channel.id).await?;
+            if let Some(last) = last {
+                let pending = last.status == ActionStatus::Proposed
+                    && last.update.version == action.update.version;
+                let age = action.proposed_at_ms.saturating_sub(last.proposed_at_ms);
+                if pending && age < cooldown_ms {
+                    continue;
+                }
+            }
+
+            self.actions.insert(channel.id, &action).await?;
+            proposed.push(action);
+        }
+
+        Ok(proposed)
+    }
+
+    /// Settle proposals whose channel reached the proposed version.
+    pub async fn on_consensus_receipt(&self, receipt: &ConsensusReceipt) -> Result<()> {
+        for channel in receipt.updated_channels.values() {
+            let mut action = match self.actions.get::<_, RebalanceAction>(&channel.id).await? {
+                Some(action) if action.status == ActionStatus::Proposed => action,
+                _ => continue,
+            };
+            if channel.version < action.update.version {
+                continue;
+            }
+
+            action.status = if channel.version == action.update.version
+                && channel.balance2 == action.update.balance2
+            {
+                ActionStatus::Applied
+            } else {
+                ActionStatus::Superseded
+            };
+            self.actions.insert(channel.id, action).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Latest action of every channel, for the admin RPC.
+    pub async fn actions(&self) -> Result<Vec<RebalanceAction>> {
+        Ok(self.actions.run(|store| store.values()).await??)
+    }
+}
+
+#[async_trait]
+impl Job for Rebalancer {
+    fn name(&self) -> &'static str {
+        "rebalance"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        match self.policy.enabled {
+            true => Schedule::Every(self.policy.scan_interval_secs.max(1)),
+            false => Schedule::Never,
+        }
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.scan().await.map(|_| ())
+    }
+}
+
+fn time_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use primitive_types::H256;
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::snapshot::channel_index_key,
+        types::{Block, Token},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_propose_and_settle() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let policy = RebalancePolicy {
+            max_amount: 300,
+            ..Default::default()
+        };
+        let key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let rebalancer = Rebalancer::new(&store, 1, key, policy).unwrap();
+
+        let channel = Channel {
+            id: 7.into(),
+            token: Token::default(),
+            participant2: [H160::repeat_byte(2), rebalancer.operator()],
+            state: ChannelState::Open,
+            version: 3,
+            total_balance: 1000.into(),
+            balance2: [
+                Balance {
+                    settled: 100.into(),
+                },
+                Balance {
+                    settled: 900.into(),
+                },
+            ],
+            ..Default::default()
+        };
+        let mut smt = SMT::new_with_store(store.clone()).unwrap();
+        smt.update(channel.id.to_h256(), channel.clone()).unwrap();
+        { store.open_tree(CHANNEL_INDEX_TREE).unwrap() }
+            .insert(channel_index_key(&channel.id), channel.id)
+            .unwrap();
+
+        let proposed = rebalancer.scan().await.unwrap();
+        assert_eq!(proposed.len(), 1);
+        let update = &proposed[0].update;
+        assert_eq!(update.version, 4);
+        assert_eq!(update.balance2[1].settled, 600.into());
+        assert_eq!(update.balance2[0].settled, 400.into());
+        assert!(update.signature2[0].is_empty());
+        assert_eq!(update.signature2[1].len(), 65);
+
+        // Still cooling down
+        assert!(rebalancer.scan().await.unwrap().is_empty());
+
+        let applied = Channel {
+            version: update.version,
+            balance2: update.balance2.clone(),
+            ..channel
+        };
+        let receipt = ConsensusReceipt {
+            block: Arc::new(Block {
+                header: Default::default(),
+                txs: vec![],
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: BTreeMap::from([(H256::zero(), applied)]),
+        };
+        rebalancer.on_consensus_receipt(&receipt).await.unwrap();
+
+        let actions = rebalancer.actions().await.unwrap();
+        assert_eq!(actions[0].status, ActionStatus::Applied);
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use primitive_types::H256;
+use serde::{Deserialize, Serialize};
+use share::error_code::RpcErrorCode;
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        receipt::{ReceiptStream, StreamedReceipt},
+        store::{AsyncStore, Store, StoreError},
+    },
+    scheduler::{Job, Schedule},
+    types::NumberHash,
+};
+
+const RETENTION_TREE: &str = "retention";
+const TRIMMED_TIP_KEY: &str = "trimmed_tip";
+
+/// How much receipt history the node keeps. Receipts of the oldest blocks
+/// are trimmed while either bound is exceeded, 0 turns a bound off.
+/// Disabled unless turned on in config.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    pub enabled: bool,
+    // By block timestamp
+    pub max_age_secs: u64,
+    // Encoded receipts together
+    pub max_bytes: u64,
+    pub interval_secs: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            enabled: false,
+            max_age_secs: 0,
+            max_bytes: 0,
+            interval_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    // Receipts of every block up to this one have been trimmed
+    pub trimmed_tip: u64,
+    // Trimmed by this run
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum RetentionError {
+    #[error("receipt of {0:?} is trimmed, receipts are kept after block {1}")]
+    Trimmed(H256, u64),
+}
+
+impl RetentionError {
+    // For JSON-RPC, the node API answers 410 instead
+    #[allow(dead_code)]
+    pub fn code(&self) -> RpcErrorCode {
+        RpcErrorCode::TrimmedHistory
+    }
+}
+
+/// Trims transaction receipts of old blocks to keep their history within
+/// a byte or age budget. Blocks and transactions stay, unlike pruning.
+#[derive(Clone)]
+pub struct ReceiptRetention {
+    store: AsyncStore,
+    chain: ChannelChain,
+    receipts: ReceiptStream,
+    meta: AsyncStore,
+    policy: RetentionPolicy,
+}
+
+impl ReceiptRetention {
+    pub fn new(
+        store: &Store,
+        receipts: ReceiptStream,
+        policy: RetentionPolicy,
+    ) -> Result<Self, StoreError> {
+        let retention = ReceiptRetention {
+            store: AsyncStore::new(store.clone()),
+            chain: ChannelChain::new(store.clone())?,
+            receipts,
+            meta: AsyncStore::new(store.open_tree(RETENTION_TREE)?),
+            policy,
+        };
+
+        Ok(retention)
+    }
+
+    pub async fn trimmed_tip(&self) -> Result<u64> {
+        Ok(self.meta.get(&TRIMMED_TIP_KEY).await?.unwrap_or_default())
+    }
+
+    /// The receipt of `tx_hash`, or `RetentionError::Trimmed` if the
+    /// transaction is known but its receipt was trimmed.
+    pub async fn get_receipt(&self, tx_hash: H256) -> Result<Option<StreamedReceipt>> {
+        let receipts = self.receipts.clone();
+        let receipt = { self.store.run(move |_| receipts.get_receipt(&tx_hash)).await?? };
+        if receipt.is_some() {
+            return Ok(receipt);
+        }
+
+        let trimmed_tip = self.trimmed_tip().await?;
+        if trimmed_tip > 0 && self.chain.get_transaction(tx_hash).await?.is_some() {
+            return Err(RetentionError::Trimmed(tx_hash, trimmed_tip).into());
+        }
+        Ok(None)
+    }
+
+    /// Trim receipts of the oldest blocks until they are within the policy
+    /// at unix time `now_ms`.
+    pub async fn trim(&self, now_ms: u64) -> Result<RetentionReport> {
+        let mut report = RetentionReport {
+            trimmed_tip: self.trimmed_tip().await?,
+            ..Default::default()
+        };
+        let tip = { self.chain.tip_header().await? }
+            .map(|header| header.number)
+            .unwrap_or_default();
+
+        let receipts = self.receipts.clone();
+        let mut stored_bytes = match self.policy.max_bytes {
+            0 => 0,
+            _ => self.store.run(move |_| receipts.stored_bytes()).await??,
+        };
+        let max_age_ms = self.policy.max_age_secs.saturating_mul(1000);
+        while report.trimmed_tip < tip {
+            let number = report.trimmed_tip + 1;
+            let block = match self.chain.get_block(NumberHash::Number(number)).await? {
+                Some(block) => block,
+                None => break,
+            };
+            let expired = max_age_ms != 0
+                && block.header.timestamp.low_u64().saturating_add(max_age_ms) < now_ms;
+            let oversized = self.policy.max_bytes != 0 && stored_bytes > self.policy.max_bytes;
+            if !expired && !oversized {
+                break;
+            }
+
+            let receipts = self.receipts.clone();
+            let bytes = self
+                .store
+                .run(move |_| -> Result<u64, StoreError> {
+                    { block.txs.iter() }
+                        .try_fold(0, |bytes, tx| Ok(bytes + receipts.remove_receipt(&tx.hash)?))
+                })
+                .await??;
+            self.meta.insert(TRIMMED_TIP_KEY, number).await?;
+
+            stored_bytes = stored_bytes.saturating_sub(bytes);
+            report.trimmed_tip = number;
+            report.blocks += 1;
+            report.bytes += bytes;
+        }
+
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl Job for ReceiptRetention {
+    fn name(&self) -> &'static str {
+        "retention"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        match self.policy.enabled {
+            true => Schedule::Every(self.policy.interval_secs.max(1)),
+            false => Schedule::Never,
+        }
+    }
+
+    async fn run(&self) -> Result<()> {
+        let now_ms = crate::scheduler::unix_now().saturating_mul(1000);
+        self.trim(now_ms).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::fixture::save_blocks;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trim_oldest_receipts_by_age_and_bytes() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(&tmp_db_path).unwrap();
+        let chain = ChannelChain::new(store.clone()).unwrap();
+        let receipts = ReceiptStream::new(&store).unwrap();
+
+        save_blocks(&store, 4, |number| number * 1000, Some(&receipts)).await;
+        let receipt_bytes = receipts.stored_bytes().unwrap() / 4;
+
+        // Blocks 1 and 2 are older than 2 seconds at 4.5s
+        let policy = RetentionPolicy {
+            enabled: true,
+            max_age_secs: 2,
+            ..Default::default()
+        };
+        let retention = ReceiptRetention::new(&store, receipts.clone(), policy).unwrap();
+        let report = retention.trim(4500).await.unwrap();
+        assert_eq!((report.trimmed_tip, report.blocks), (2, 2));
+        assert_eq!(report.bytes, receipt_bytes * 2);
+
+        let trimmed = H256::from_low_u64_be(2);
+        let err = retention.get_receipt(trimmed).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RetentionError>(),
+            Some(&RetentionError::Trimmed(trimmed, 2))
+        );
+        assert!(chain.get_transaction(trimmed).await.unwrap().is_some());
+        assert!(retention
+            .get_receipt(H256::from_low_u64_be(3))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(retention
+            .get_receipt(H256::repeat_byte(9))
+            .await
+            .unwrap()
+            .is_none());
+
+        // One receipt fits the byte budget
+        let policy = RetentionPolicy {
+            enabled: true,
+            max_bytes: receipt_bytes,
+            ..Default::default()
+        };
+        let retention = ReceiptRetention::new(&store, receipts.clone(), policy).unwrap();
+        let report = retention.trim(4500).await.unwrap();
+        assert_eq!((report.trimmed_tip, report.blocks), (3, 1));
+        assert!(receipts
+            .get_receipt(&H256::from_low_u64_be(4))
+            .unwrap()
+            .is_some());
+    }
+}
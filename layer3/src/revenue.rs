@@ -0,0 +1,312 @@
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use anyhow::Result;
+use primitive_types::{U128, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        snapshot::channel_index_key,
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    types::RawTransaction,
+};
+
+const REVENUE_TREE: &str = "operator_revenue";
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Fees the transactions of one channel paid in one block, in the channel's
+/// token.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ChannelRevenue {
+    // Days since the unix epoch, of the block timestamp
+    pub day: u64,
+    pub block_number: u64,
+    pub channel_id: U256,
+    pub token_id: U256,
+    pub fees: U128,
+    pub tx_count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DailyRevenue {
+    pub day: u64,
+    pub token_id: U256,
+    pub fees: U128,
+    pub tx_count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TokenRevenue {
+    pub token_id: U256,
+    pub fees: U128,
+    pub tx_count: u64,
+}
+
+/// Operator revenue of the days `from_day..=to_day`, per day and token and
+/// in total per token.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RevenueReport {
+    pub from_day: u64,
+    pub to_day: u64,
+    pub days: Vec<DailyRevenue>,
+    pub tokens: Vec<TokenRevenue>,
+}
+
+/// Records the fees of every applied block per channel. Entries are keyed
+/// by day, block and channel, so applying a receipt again after a crash
+/// doesn't count its fees twice.
+#[derive(Clone)]
+pub struct RevenueLedger {
+    revenue: AsyncStore,
+    chain: ChannelChain,
+}
+
+impl RevenueLedger {
+    pub fn new(store: &Store) -> Result<Self, StoreError> {
+        Ok(RevenueLedger {
+            revenue: AsyncStore::new(store.open_tree(REVENUE_TREE)?),
+            chain: ChannelChain::new(store.clone())?,
+        })
+    }
+
+    /// Record the fees of the applied block, transactions paying none are
+    /// left out.
+    pub async fn on_consensus_receipt(
+        &self,
+        receipt: &ConsensusReceipt,
+    ) -> Result<Vec<ChannelRevenue>> {
+        let header = &receipt.block.header;
+        let day = day_of(header.timestamp);
+        let mut by_channel = BTreeMap::<[u8; 32], ChannelRevenue>::new();
+        for tx in receipt.block.txs.iter().filter(|tx| !tx.fee.is_zero()) {
+            let channel_id = tx.raw.channel_id();
+            let entry = match by_channel.entry(channel_index_key(&channel_id)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(ChannelRevenue {
+                    day,
+                    block_number: header.number,
+                    channel_id,
+                    token_id: self.token_of(receipt, &tx.raw).await?,
+                    fees: U128::zero(),
+                    tx_count: 0,
+                }),
+            };
+            entry.fees = entry.fees.saturating_add(tx.fee);
+            entry.tx_count += 1;
+        }
+
+        let entries = by_channel.into_iter().collect::<Vec<_>>();
+        let stored = entries.clone();
+        self.revenue
+            .run(move |revenue| -> Result<(), StoreError> {
+                for (key, entry) in stored {
+                    let number = entry.block_number.to_be_bytes();
+                    revenue.insert((entry.day.to_be_bytes(), number, key), entry)?;
+                }
+                Ok(())
+            })
+            .await??;
+
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// The channel's token, which a failed `CreateChannel` only names in
+    /// the transaction.
+    async fn token_of(&self, receipt: &ConsensusReceipt, raw: &RawTransaction) -> Result<U256> {
+        if let RawTransaction::CreateChannel(args) = raw {
+            return Ok(args.token.id);
+        }
+
+        let channel_id = raw.channel_id();
+        let updated =
+            { receipt.updated_channels.values() }.find(|channel| channel.id == channel_id);
+        match updated {
+            Some(channel) => Ok(channel.token.id),
+            None => Ok(self.chain.get_channel(channel_id).await?.token.id),
+        }
+    }
+
+    /// Entries of the days `from_day..=to_day`, by day, block and channel.
+    pub async fn entries(&self, from_day: u64, to_day: u64) -> Result<Vec<ChannelRevenue>> {
+        let from = (from_day.to_be_bytes(), [0u8; 8], [0u8; 32]);
+        let to = (to_day.saturating_add(1).to_be_bytes(), [0u8; 8], [0u8; 32]);
+        Ok(self
+            .revenue
+            .run(move |revenue| revenue.values_range(&from, &to))
+            .await??)
+    }
+
+    /// Entries of one channel in the days `from_day..=to_day`.
+    pub async fn channel_revenue(
+        &self,
+        channel_id: U256,
+        from_day: u64,
+        to_day: u64,
+    ) -> Result<Vec<ChannelRevenue>> {
+        let mut entries = self.entries(from_day, to_day).await?;
+        entries.retain(|entry| entry.channel_id == channel_id);
+        Ok(entries)
+    }
+
+    /// Totals of the days `from_day..=to_day`, of one token if given.
+    pub async fn report(
+        &self,
+        from_day: u64,
+        to_day: u64,
+        token_id: Option<U256>,
+    ) -> Result<RevenueReport> {
+        let mut days = BTreeMap::<(u64, [u8; 32]), DailyRevenue>::new();
+        let mut tokens = BTreeMap::<[u8; 32], TokenRevenue>::new();
+        let mut entries = self.entries(from_day, to_day).await?;
+        entries.retain(|entry| token_id.is_none_or(|token_id| entry.token_id == token_id));
+        for entry in entries {
+            let token_key = channel_index_key(&entry.token_id);
+            let daily = days
+                .entry((entry.day, token_key))
+                .or_insert_with(|| DailyRevenue {
+                    day: entry.day,
+                    token_id: entry.token_id,
+                    fees: U128::zero(),
+                    tx_count: 0,
+                });
+            daily.fees = daily.fees.saturating_add(entry.fees);
+            daily.tx_count += entry.tx_count;
+
+            let total = tokens.entry(token_key).or_insert_with(|| TokenRevenue {
+                token_id: entry.token_id,
+                fees: U128::zero(),
+                tx_count: 0,
+            });
+            total.fees = total.fees.saturating_add(entry.fees);
+            total.tx_count += entry.tx_count;
+        }
+
+        Ok(RevenueReport {
+            from_day,
+            to_day,
+            days: days.into_values().collect(),
+            tokens: tokens.into_values().collect(),
+        })
+    }
+}
+
+/// Days since the unix epoch of a millisecond timestamp.
+pub fn day_of(timestamp_ms: U128) -> u64 {
+    (timestamp_ms / U128::from(MS_PER_DAY)).low_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use primitive_types::{H160, H256};
+    use tempfile::tempdir;
+
+    use crate::types::{
+        Balance, Block, BlockHeader, CloseChannel, CreateChannel, SignedTransaction, Token,
+    };
+
+    use super::*;
+
+    fn tx(raw: RawTransaction, fee: u64) -> SignedTransaction {
+        SignedTransaction {
+            raw,
+            sig: Default::default(),
+            fee: fee.into(),
+            from: H160::zero(),
+            hash: H256::random(),
+        }
+    }
+
+    fn create(channel_id: u64, token_id: u64) -> RawTransaction {
+        RawTransaction::CreateChannel(CreateChannel {
+            chain_id: 1,
+            id: channel_id.into(),
+            token: Token {
+                id: token_id.into(),
+                ..Default::default()
+            },
+            challenge_blocks: 10,
+            participant2: [H160::zero(); 2],
+            balance2: [Balance::default(), Balance::default()],
+            guard: None,
+        })
+    }
+
+    fn receipt(number: u64, day: u64, txs: Vec<SignedTransaction>) -> ConsensusReceipt {
+        ConsensusReceipt {
+            block: Arc::new(Block {
+                header: BlockHeader {
+                    number,
+                    timestamp: U128::from(day * MS_PER_DAY + 1000),
+                    ..Default::default()
+                },
+                txs,
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revenue_report() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(&tmp_db_path).unwrap();
+        let ledger = RevenueLedger::new(&store).unwrap();
+
+        let close = RawTransaction::CloseChannel(CloseChannel {
+            chain_id: 1,
+            channel_id: 9.into(),
+            ..Default::default()
+        });
+        let block1 = receipt(
+            1,
+            100,
+            vec![
+                tx(create(1, 7), 5),
+                tx(create(2, 8), 3),
+                tx(create(3, 8), 0),
+            ],
+        );
+        let entries = ledger.on_consensus_receipt(&block1).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        // Applied again after a crash
+        ledger.on_consensus_receipt(&block1).await.unwrap();
+
+        // The token of a channel not updated by the block is looked up,
+        // unknown channels have the default token
+        let block2 = receipt(2, 101, vec![tx(create(1, 7), 2), tx(close, 4)]);
+        ledger.on_consensus_receipt(&block2).await.unwrap();
+
+        let report = ledger.report(100, 101, None).await.unwrap();
+        let daily = |day: u64, token: u64| {
+            report
+                .days
+                .iter()
+                .find(|d| d.day == day && d.token_id == token.into())
+                .map(|d| (d.fees.low_u64(), d.tx_count))
+        };
+        assert_eq!(daily(100, 7), Some((5, 1)));
+        assert_eq!(daily(100, 8), Some((3, 1)));
+        assert_eq!(daily(101, 7), Some((2, 1)));
+        assert_eq!(daily(101, 0), Some((4, 1)));
+        assert_eq!(report.tokens.len(), 3);
+        let token7 = report.tokens.iter().find(|t| t.token_id == 7.into());
+        assert_eq!(token7.unwrap().fees, 7.into());
+
+        assert_eq!(ledger.report(101, 101, None).await.unwrap().days.len(), 2);
+        let token8 = ledger.report(100, 101, Some(8.into())).await.unwrap();
+        assert_eq!((token8.days.len(), token8.tokens.len()), (1, 1));
+        let channel1 = ledger.channel_revenue(1.into(), 0, 200).await.unwrap();
+        assert_eq!(
+            channel1.iter().map(|e| e.block_number).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(channel1[1].fees, 2.into());
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::{anyhow, Result};
+use primitive_types::{H160, U128};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use tempfile::{tempdir, TempDir};
+
+use crate::{
+    auxiliaries::{
+        chain::{Chain, ChannelChain},
+        common::{blake2b, secp256k1_address, sign_recoverable},
+        mempool::{ChannelMap, MemPool},
+        store::Store,
+    },
+    consensus::{ChannelConsensus, Consensus, ConsensusReceipt},
+    guardian::{Guardian, GuardianPolicy},
+    types::{
+        Balance, Block, Channel, ChannelState, CloseChannel, CreateChannel, RawTransaction,
+        SignedTransaction, TransactionEnvelope, UpdateChannel,
+    },
+};
+
+const CHAIN_ID: u64 = 1;
+const COUNTERPARTY: usize = 0;
+const OPERATOR: usize = 1;
+
+/// One move of a scripted run. The counterparty may misbehave, the operator
+/// is honest and leaves its defence to the guardian.
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    // Both sign the next version with these balances
+    Pay([u64; 2]),
+    // The operator signs the next version, the counterparty keeps its own
+    // signature to itself
+    WithholdSignature([u64; 2]),
+    // The counterparty publishes the update of `version`, signed by both
+    Publish(u64),
+    // The counterparty closes at `version` with a close the operator signed
+    // back then
+    StaleClose(u64),
+    // Blocks reach the guardian this many blocks late from now on
+    DelayRelay(u64),
+    // The guardian's transactions don't reach the block producer for this
+    // many blocks
+    Censor(u64),
+    Blocks(u64),
+}
+
+/// Runs the channel protocol in process, mempool, consensus and the
+/// operator's guardian on one store, and checks that the operator gets its
+/// funds back whatever the script throws at it.
+pub struct Scenario {
+    _dir: TempDir,
+    mempool: ChannelMap,
+    consensus: ChannelConsensus,
+    chain: ChannelChain,
+    guardian: Guardian,
+    // Guardian transactions waiting to reach the block producer
+    outbox: ChannelMap,
+    keys: [SecretKey; 2],
+    channel: Channel,
+    // Every update the operator signed, by version
+    signed: BTreeMap<u64, UpdateChannel>,
+    // Latest version the operator holds both signatures on
+    held: u64,
+    tip: u64,
+    relay_delay: u64,
+    censored_until: u64,
+    // Applied blocks the guardian hasn't seen yet, with the block they
+    // reach it at
+    relayed: VecDeque<(u64, ConsensusReceipt)>,
+    closing: Option<u64>,
+    closed_at: Option<u64>,
+}
+
+impl Scenario {
+    /// A channel opened with `deposit` in block 1.
+    pub async fn open(deposit: [u64; 2], challenge_blocks: u64) -> Result<Self> {
+        let dir = tempdir()?;
+        let store = Store::open(dir.path())?;
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let outbox = ChannelMap::new(CHAIN_ID);
+        let keys = [
+            SecretKey::from_slice(&[1u8; 32])?,
+            SecretKey::from_slice(&[2u8; 32])?,
+        ];
+        let guardian = Guardian::new(
+            &store,
+            outbox.clone(),
+            keys[OPERATOR],
+            GuardianPolicy::default(),
+        )?;
+
+        let participant2 = keys.map(|key| address(&key));
+        let create = CreateChannel {
+            chain_id: CHAIN_ID,
+            id: 7.into(),
+            token: Default::default(),
+            challenge_blocks,
+            participant2,
+            balance2: balances(deposit),
+            guard: None,
+        };
+        let channel = Channel {
+            id: create.id,
+            challenge_blocks,
+            participant2,
+            state: ChannelState::Open,
+            balance2: create.balance2.clone(),
+            ..Default::default()
+        };
+        let opening = UpdateChannel {
+            chain_id: CHAIN_ID,
+            channel_id: channel.id,
+            balance2: create.balance2.clone(),
+            ..Default::default()
+        };
+
+        let mut scenario = Scenario {
+            _dir: dir,
+            consensus: ChannelConsensus::new(mempool.clone(), store.clone(), CHAIN_ID)?,
+            chain: ChannelChain::new(store)?,
+            mempool,
+            guardian,
+            outbox,
+            keys,
+            channel,
+            signed: BTreeMap::from([(0, opening)]),
+            held: 0,
+            tip: 0,
+            relay_delay: 0,
+            censored_until: 0,
+            relayed: VecDeque::new(),
+            closing: None,
+            closed_at: None,
+        };
+        let tx = scenario.sign(RawTransaction::CreateChannel(create), COUNTERPARTY);
+        scenario.mempool.push_transaction(tx)?;
+        scenario.block().await?;
+
+        Ok(scenario)
+    }
+
+    pub async fn run(&mut self, steps: &[Step]) -> Result<()> {
+        for step in steps {
+            match *step {
+                Step::Pay(amounts) => {
+                    let update = self.next_update(amounts);
+                    self.held = update.version;
+                    self.guardian.store_state(&self.channel, update).await?;
+                }
+                Step::WithholdSignature(amounts) => {
+                    self.next_update(amounts);
+                }
+                Step::Publish(version) => {
+                    let update = self.signed.get(&version).cloned();
+                    let update =
+                        update.ok_or_else(|| anyhow!("version {} never signed", version))?;
+                    let tx = self.sign(RawTransaction::UpdateChannel(update), COUNTERPARTY);
+                    self.mempool.push_transaction(tx)?;
+                }
+                Step::StaleClose(version) => {
+                    let mut close = CloseChannel {
+                        chain_id: CHAIN_ID,
+                        channel_id: self.channel.id,
+                        version,
+                        ..Default::default()
+                    };
+                    let msg = close.sig_msg();
+                    close.signature2 = self.keys.map(|key| sign_recoverable(&key, msg));
+                    let tx = self.sign(RawTransaction::CloseChannel(close), COUNTERPARTY);
+                    self.mempool.push_transaction(tx)?;
+                    self.closing = Some(version);
+                }
+                Step::DelayRelay(blocks) => self.relay_delay = blocks,
+                Step::Censor(blocks) => self.censored_until = self.tip + blocks,
+                Step::Blocks(blocks) => {
+                    for _ in 0..blocks {
+                        self.block().await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run until whatever was submitted is packaged and, after a close,
+    /// until its challenge period is over. Then check the channel holds a
+    /// state the operator signed, at least as new as the latest one it holds
+    /// both signatures on.
+    pub async fn assert_recovered(&mut self) -> Result<()> {
+        self.block().await?;
+        if self.closing.is_some() {
+            let closed_at = self
+                .closed_at
+                .ok_or_else(|| anyhow!("the close was refused"))?;
+            while self.tip < closed_at + self.channel.challenge_blocks {
+                self.block().await?;
+            }
+        }
+
+        let channel = self.chain.get_channel(self.channel.id).await?;
+        let signed = self.signed.get(&channel.version);
+        if channel.version < self.held || signed.map(|u| &u.balance2) != Some(&channel.balance2) {
+            return Err(anyhow!(
+                "channel ended at version {} with {:?}, the operator holds version {} with {:?}",
+                channel.version,
+                channel.balance2,
+                self.held,
+                self.signed[&self.held].balance2
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Signed by the operator, and by the counterparty too
+    fn next_update(&mut self, amounts: [u64; 2]) -> UpdateChannel {
+        let version = self.signed.keys().last().copied().unwrap_or_default() + 1;
+        let mut update = UpdateChannel {
+            chain_id: CHAIN_ID,
+            channel_id: self.channel.id,
+            version,
+            balance2: balances(amounts),
+            ..Default::default()
+        };
+        update.signature2 = self
+            .keys
+            .map(|key| sign_recoverable(&key, update.sig_msg()));
+        self.signed.insert(version, update.clone());
+        update
+    }
+
+    fn sign(&self, raw: RawTransaction, participant: usize) -> SignedTransaction {
+        let key = &self.keys[participant];
+        let hash = blake2b(&bincode::serialize(&TransactionEnvelope::from(raw.clone())).unwrap());
+
+        SignedTransaction {
+            sig: sign_recoverable(key, hash),
+            fee: U128::zero(),
+            from: self.channel.participant2[participant],
+            hash,
+            raw,
+        }
+    }
+
+    async fn block(&mut self) -> Result<()> {
+        if self.tip >= self.censored_until {
+            let txs = self.outbox.package_transactions()?;
+            self.outbox.reset(&Block {
+                header: Default::default(),
+                txs: txs.clone(),
+            })?;
+            for tx in txs {
+                self.mempool.push_transaction(tx)?;
+            }
+        }
+
+        let receipt = self.consensus.produce_block().await?;
+        self.consensus.apply_consensus_receipt(&receipt).await?;
+        self.tip = receipt.block.header.number;
+        if self.closed_at.is_none() && self.closing.is_some() {
+            let channel = self.chain.get_channel(self.channel.id).await?;
+            if Some(channel.version) == self.closing {
+                self.closed_at = Some(self.tip);
+            }
+        }
+
+        self.relayed
+            .push_back((self.tip + self.relay_delay, receipt));
+        while let Some((at, _)) = self.relayed.front() {
+            if *at > self.tip {
+                break;
+            }
+            let (_, receipt) = self.relayed.pop_front().unwrap();
+            self.guardian.on_consensus_receipt(&receipt).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn balances(amounts: [u64; 2]) -> [Balance; 2] {
+    amounts.map(|settled| Balance {
+        settled: settled.into(),
+    })
+}
+
+fn address(key: &SecretKey) -> H160 {
+    secp256k1_address(&PublicKey::from_secret_key(&Secp256k1::new(), key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stale_close_is_overridden() {
+        let mut scenario = Scenario::open([50, 50], 5).await.unwrap();
+        scenario
+            .run(&[
+                Step::Pay([40, 60]),
+                Step::Pay([20, 80]),
+                // Keeps the deposit on chain, undoing both payments
+                Step::StaleClose(1),
+            ])
+            .await
+            .unwrap();
+        scenario.assert_recovered().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_withheld_signature_and_stale_update() {
+        let mut scenario = Scenario::open([50, 50], 5).await.unwrap();
+        scenario
+            .run(&[
+                Step::Pay([40, 60]),
+                Step::Pay([30, 70]),
+                Step::Pay([25, 75]),
+                Step::WithholdSignature([35, 65]),
+                Step::Publish(1),
+                Step::Blocks(1),
+                Step::StaleClose(2),
+            ])
+            .await
+            .unwrap();
+        // The operator responds with version 3, the one it holds
+        scenario.assert_recovered().await.unwrap();
+
+        // Publishing the withheld update only hands over what the operator
+        // agreed to
+        let mut scenario = Scenario::open([50, 50], 5).await.unwrap();
+        scenario
+            .run(&[
+                Step::Pay([40, 60]),
+                Step::WithholdSignature([45, 55]),
+                Step::Publish(2),
+            ])
+            .await
+            .unwrap();
+        scenario.assert_recovered().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delayed_relay_and_censored_response() {
+        let mut scenario = Scenario::open([50, 50], 6).await.unwrap();
+        scenario
+            .run(&[
+                Step::Pay([30, 70]),
+                Step::Pay([10, 90]),
+                Step::DelayRelay(2),
+                Step::Censor(3),
+                Step::StaleClose(1),
+            ])
+            .await
+            .unwrap();
+        scenario.assert_recovered().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_response_censored_past_challenge_period() {
+        let mut scenario = Scenario::open([50, 50], 3).await.unwrap();
+        scenario
+            .run(&[
+                Step::Pay([40, 60]),
+                Step::Pay([20, 80]),
+                Step::Censor(10),
+                Step::StaleClose(1),
+            ])
+            .await
+            .unwrap();
+        let err = scenario.assert_recovered().await.unwrap_err();
+        assert!(err.to_string().starts_with("channel ended at version 1"));
+    }
+}
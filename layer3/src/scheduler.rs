@@ -0,0 +1,240 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When a job runs, written `{ every = 3600 }`, `{ daily_at = "03:30" }`
+/// (UTC) or `"never"` in config.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    // Seconds between the start of two runs, the first run is right away
+    Every(u64),
+    DailyAt(String),
+    Never,
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Schedule::Every(0) => Err("every must be at least 1 second".to_owned()),
+            Schedule::DailyAt(at) => parse_time_of_day(at).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Unix time of the next run, given the time of the last one. `None`
+    /// if the job doesn't run again.
+    pub fn next_run(&self, now: u64, last: Option<u64>) -> Option<u64> {
+        match self {
+            Schedule::Every(secs) => Some(last.map_or(now, |last| last + secs.max(&1)).max(now)),
+            Schedule::DailyAt(at) => {
+                let at = now - now % SECS_PER_DAY + parse_time_of_day(at).ok()?;
+                // Runs missed before the process started are skipped
+                if at < now || Some(at) <= last {
+                    Some(at + SECS_PER_DAY)
+                } else {
+                    Some(at)
+                }
+            }
+            Schedule::Never => None,
+        }
+    }
+}
+
+/// Seconds past midnight of a `HH:MM` time.
+fn parse_time_of_day(raw: &str) -> Result<u64, String> {
+    let invalid = || format!("daily_at {} is not a HH:MM time", raw);
+    let (hours, minutes) = raw.split_once(':').ok_or_else(invalid)?;
+    let hours: u64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+
+    Ok(hours * 60 * 60 + minutes * 60)
+}
+
+/// Schedules of the maintenance jobs. Jobs not listed keep their own,
+/// usually taken from the policy of the subsystem they maintain.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SchedulerPolicy {
+    pub jobs: BTreeMap<String, Schedule>,
+    // Where the backup job writes block archives, it doesn't run when unset
+    pub backup_dir: Option<PathBuf>,
+}
+
+/// Periodic maintenance work of a subsystem.
+#[async_trait]
+pub trait Job: Sync + Send {
+    fn name(&self) -> &'static str;
+
+    /// Used when the scheduler policy has no schedule for the job.
+    fn default_schedule(&self) -> Schedule;
+
+    async fn run(&self) -> Result<()>;
+}
+
+/// Runs every registered job on its schedule, each in its own task so a
+/// slow job only delays itself. A job never overlaps with itself, and a
+/// failed run is logged and retried at the next scheduled time.
+pub struct Scheduler {
+    policy: SchedulerPolicy,
+    jobs: Vec<Arc<dyn Job>>,
+}
+
+impl Scheduler {
+    pub fn new(policy: SchedulerPolicy) -> Self {
+        Scheduler {
+            policy,
+            jobs: Vec::new(),
+        }
+    }
+
+    pub fn register(mut self, job: impl Job + 'static) -> Self {
+        self.jobs.push(Arc::new(job));
+        self
+    }
+
+    /// Register a job the node keeps using outside of the scheduler.
+    pub fn register_shared(mut self, job: Arc<dyn Job>) -> Self {
+        self.jobs.push(job);
+        self
+    }
+
+    /// Every registered job with the schedule it runs on.
+    pub fn schedules(&self) -> Vec<(&'static str, Schedule)> {
+        { self.jobs.iter() }
+            .map(|job| (job.name(), self.schedule_of(job.as_ref())))
+            .collect()
+    }
+
+    fn schedule_of(&self, job: &dyn Job) -> Schedule {
+        { self.policy.jobs.get(job.name()).cloned() }.unwrap_or_else(|| job.default_schedule())
+    }
+
+    pub async fn run(self) {
+        for name in self.policy.jobs.keys() {
+            if !self.jobs.iter().any(|job| job.name() == name) {
+                eprintln!("[scheduler] no job named {}, its schedule is ignored", name);
+            }
+        }
+
+        let tasks = { self.jobs.iter() }
+            .map(|job| tokio::spawn(run_job(Arc::clone(job), self.schedule_of(job.as_ref()))))
+            .collect::<Vec<_>>();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+async fn run_job(job: Arc<dyn Job>, schedule: Schedule) {
+    let mut last = None;
+    loop {
+        let now = unix_now();
+        let next = match schedule.next_run(now, last) {
+            Some(next) => next,
+            None => return,
+        };
+        tokio::time::sleep(Duration::from_secs(next - now)).await;
+
+        last = Some(next);
+        if let Err(err) = job.run().await {
+            eprintln!("[scheduler] {} failed: {}", job.name(), err);
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    struct CountingJob(Arc<AtomicU64>);
+
+    #[async_trait]
+    impl Job for CountingJob {
+        fn name(&self) -> &'static str {
+            "count"
+        }
+
+        fn default_schedule(&self) -> Schedule {
+            Schedule::Never
+        }
+
+        async fn run(&self) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_next_run() {
+        let day = 100 * SECS_PER_DAY;
+        let every = Schedule::Every(60);
+        assert_eq!(every.next_run(day, None), Some(day));
+        assert_eq!(every.next_run(day + 10, Some(day)), Some(day + 60));
+        // A run longer than the interval is followed right away
+        assert_eq!(every.next_run(day + 90, Some(day)), Some(day + 90));
+
+        let daily = Schedule::DailyAt("03:30".to_owned());
+        let at = day + 3 * 60 * 60 + 30 * 60;
+        assert_eq!(daily.next_run(day, None), Some(at));
+        assert_eq!(daily.next_run(at, None), Some(at));
+        assert_eq!(daily.next_run(at, Some(at)), Some(at + SECS_PER_DAY));
+        assert_eq!(daily.next_run(at + 1, None), Some(at + SECS_PER_DAY));
+        assert_eq!(Schedule::Never.next_run(day, None), None);
+
+        assert!(Schedule::Every(0).validate().is_err());
+        assert!(Schedule::DailyAt("24:00".to_owned()).validate().is_err());
+        assert!(Schedule::DailyAt("3".to_owned()).validate().is_err());
+
+        let policy: SchedulerPolicy = toml::from_str(
+            r#"
+            [jobs]
+            prune = { every = 3600 }
+            backup = { daily_at = "03:30" }
+            rebalance = "never"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(policy.jobs["prune"], Schedule::Every(3600));
+        assert_eq!(policy.jobs["backup"], Schedule::DailyAt("03:30".to_owned()));
+        assert_eq!(policy.jobs["rebalance"], Schedule::Never);
+    }
+
+    #[tokio::test]
+    async fn test_policy_overrides_default_schedule() {
+        let runs = Arc::new(AtomicU64::new(0));
+        let scheduler =
+            Scheduler::new(SchedulerPolicy::default()).register(CountingJob(Arc::clone(&runs)));
+        assert_eq!(scheduler.schedules(), vec![("count", Schedule::Never)]);
+        scheduler.run().await;
+        assert_eq!(runs.load(Ordering::Relaxed), 0);
+
+        let mut policy = SchedulerPolicy::default();
+        policy.jobs.insert("count".to_owned(), Schedule::Every(1));
+        let scheduler = Scheduler::new(policy).register(CountingJob(Arc::clone(&runs)));
+        tokio::spawn(scheduler.run());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hyper::{Body, Client, Request};
+use primitive_types::{H256, U256};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::{
+    auxiliaries::mempool::SettlementCapacity,
+    checkpoint::{CheckpointManager, CheckpointRecord},
+    consensus::ConsensusReceipt,
+    scheduler::{unix_now, Job, Schedule},
+    types::BlockHeader,
+};
+
+/// CKB nodes refuse transactions larger than this into their pool.
+pub const CKB_MAX_TX_BYTES: u64 = 512_000;
+
+/// Where blocks are committed and how their size is kept within it.
+/// Packaging is only capped by the package policy unless a target is set.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SettlementPolicy {
+    // CKB node whose consensus limits blocks are packaged within
+    pub ckb_rpc_uri: Option<String>,
+    // Bytes of the commitment transaction besides the block transactions,
+    // its header, cell deps and signatures
+    pub overhead_bytes: u64,
+    pub poll_interval_secs: u64,
+    // Applied blocks are checkpointed in batches when set, in place of
+    // the checkpoint interval
+    pub batch: Option<BatchPolicy>,
+}
+
+impl Default for SettlementPolicy {
+    fn default() -> Self {
+        SettlementPolicy {
+            ckb_rpc_uri: None,
+            overhead_bytes: 4096,
+            poll_interval_secs: 600,
+            batch: None,
+        }
+    }
+}
+
+/// When the blocks applied since the last checkpoint are worth settling.
+/// A batch is settled as soon as it holds too much value or waited too
+/// long, and early while CKB fees are low.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct BatchPolicy {
+    // Total balance of the channels the batch updated, summed over tokens,
+    // 0 is no bound
+    pub max_value_at_risk: u64,
+    // Since the first block of the batch
+    pub max_delay_secs: u64,
+    // Low fees don't settle a batch younger than this
+    pub min_delay_secs: u64,
+    // Shannons per KB, the median CKB fee rate at or below it is low
+    pub low_fee_rate: u64,
+    pub poll_interval_secs: u64,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        BatchPolicy {
+            max_value_at_risk: 0,
+            max_delay_secs: 60 * 60,
+            min_delay_secs: 60,
+            low_fee_rate: 1_000,
+            poll_interval_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettleReason {
+    ValueAtRisk,
+    Deadline,
+    LowFees,
+}
+
+impl BatchPolicy {
+    /// Why `batch` should be settled at unix time `now`, none if it can
+    /// wait. An unknown fee rate never counts as low.
+    pub fn decide(
+        &self,
+        batch: &PendingBatch,
+        now: u64,
+        fee_rate: Option<u64>,
+    ) -> Option<SettleReason> {
+        let age = now.saturating_sub(batch.since());
+        if self.max_value_at_risk != 0 && batch.value_at_risk() >= self.max_value_at_risk.into() {
+            Some(SettleReason::ValueAtRisk)
+        } else if age >= self.max_delay_secs {
+            Some(SettleReason::Deadline)
+        } else if age >= self.min_delay_secs
+            && fee_rate.is_some_and(|rate| rate <= self.low_fee_rate)
+        {
+            Some(SettleReason::LowFees)
+        } else {
+            None
+        }
+    }
+}
+
+#[async_trait]
+pub trait SettlementTarget: Sync + Send {
+    /// Bytes one commitment transaction can have right now.
+    async fn max_commitment_bytes(&self) -> Result<u64>;
+
+    /// Fee rate a commitment transaction pays right now, in shannons per
+    /// KB.
+    async fn fee_rate(&self) -> Result<u64>;
+}
+
+/// Commits to CKB, limited by both its block size and its tx pool.
+pub struct CkbTarget {
+    client: Client<hyper::client::HttpConnector>,
+    uri: String,
+}
+
+impl CkbTarget {
+    pub fn new(uri: String) -> Self {
+        CkbTarget {
+            client: Client::new(),
+            uri,
+        }
+    }
+
+    // The hex number at `field` of what `method` returns
+    async fn call_for_number(&self, method: &str, field: &str) -> Result<u64> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": [],
+        });
+        let req = Request::post(&self.uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))?;
+
+        let resp = self.client.request(req).await?;
+        let resp: Value = serde_json::from_slice(&hyper::body::to_bytes(resp).await?)?;
+        resp["result"][field]
+            .as_str()
+            .and_then(|hex| u64::from_str_radix(hex.trim_start_matches("0x"), 16).ok())
+            .ok_or_else(|| anyhow!("{} returned no {}: {}", self.uri, field, resp))
+    }
+}
+
+#[async_trait]
+impl SettlementTarget for CkbTarget {
+    async fn max_commitment_bytes(&self) -> Result<u64> {
+        let max_block_bytes = self
+            .call_for_number("get_consensus", "max_block_bytes")
+            .await?;
+
+        Ok(max_block_bytes.min(CKB_MAX_TX_BYTES))
+    }
+
+    /// Median of the recent blocks, the node returns none without traffic.
+    async fn fee_rate(&self) -> Result<u64> {
+        self.call_for_number("get_fee_rate_statistics", "median")
+            .await
+    }
+}
+
+/// Polls the settlement target and caps the bytes of the blocks the
+/// mempool packages by what a commitment has left for them.
+pub struct SettlementFollower<T> {
+    target: T,
+    capacity: SettlementCapacity,
+    policy: SettlementPolicy,
+}
+
+impl<T: SettlementTarget> SettlementFollower<T> {
+    pub fn new(target: T, capacity: SettlementCapacity, policy: SettlementPolicy) -> Self {
+        SettlementFollower {
+            target,
+            capacity,
+            policy,
+        }
+    }
+
+    /// Returns the block bytes now allowed.
+    pub async fn refresh(&self) -> Result<u64> {
+        let max_bytes = self.target.max_commitment_bytes().await?;
+        // Zero would lift the limit, one byte packages nothing
+        let block_bytes = max_bytes.saturating_sub(self.policy.overhead_bytes).max(1);
+        self.capacity.set(block_bytes);
+
+        Ok(block_bytes)
+    }
+}
+
+/// The last known limit stays in force while the target can't be reached.
+#[async_trait]
+impl<T: SettlementTarget> Job for SettlementFollower<T> {
+    fn name(&self) -> &'static str {
+        "settlement_limits"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(self.policy.poll_interval_secs.max(1))
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.refresh().await.map(|_| ())
+    }
+}
+
+/// Blocks applied since the last checkpoint.
+#[derive(Debug, Clone)]
+pub struct PendingBatch {
+    pub first: BlockHeader,
+    pub last: BlockHeader,
+    // Total balance of every channel the batch updated, after its latest
+    // update
+    channels: BTreeMap<H256, U256>,
+}
+
+impl PendingBatch {
+    fn new(header: &BlockHeader) -> Self {
+        PendingBatch {
+            first: header.clone(),
+            last: header.clone(),
+            channels: BTreeMap::new(),
+        }
+    }
+
+    /// Unix time of the first block.
+    pub fn since(&self) -> u64 {
+        (self.first.timestamp / 1000).low_u64()
+    }
+
+    /// Funds a fraud would put at stake until the batch is settled.
+    pub fn value_at_risk(&self) -> U256 {
+        { self.channels.values() }.fold(U256::zero(), |sum, value| sum.saturating_add(*value))
+    }
+}
+
+/// Checkpoints applied blocks in batches, as the batch policy decides, in
+/// place of one every `interval_blocks`. The pending batch is only kept in
+/// memory, after a restart it starts over at the next applied block, which
+/// still checkpoints every block since the last checkpoint.
+pub struct SettlementBatcher<T> {
+    target: T,
+    checkpoints: CheckpointManager,
+    policy: BatchPolicy,
+    pending: Mutex<Option<PendingBatch>>,
+}
+
+impl<T: SettlementTarget> SettlementBatcher<T> {
+    pub fn new(target: T, checkpoints: CheckpointManager, policy: BatchPolicy) -> Self {
+        SettlementBatcher {
+            target,
+            checkpoints,
+            policy,
+            pending: Mutex::new(None),
+        }
+    }
+
+    /// Add the applied block to the pending batch and settle it right away
+    /// if it's due without asking for fees.
+    pub async fn on_consensus_receipt(
+        &self,
+        receipt: &ConsensusReceipt,
+    ) -> Result<Option<CheckpointRecord>> {
+        let header = &receipt.block.header;
+        let mut pending = self.pending.lock().await;
+        let batch = pending.get_or_insert_with(|| PendingBatch::new(header));
+        batch.last = header.clone();
+        for (key, channel) in receipt.updated_channels.iter() {
+            batch.channels.insert(*key, channel.total_balance);
+        }
+
+        self.settle_if_due(&mut pending, None).await
+    }
+
+    async fn settle_if_due(
+        &self,
+        pending: &mut Option<PendingBatch>,
+        fee_rate: Option<u64>,
+    ) -> Result<Option<CheckpointRecord>> {
+        let batch = match pending {
+            Some(batch) => batch,
+            None => return Ok(None),
+        };
+        let reason = match self.policy.decide(batch, unix_now(), fee_rate) {
+            Some(reason) => reason,
+            None => return Ok(None),
+        };
+
+        let record = self.checkpoints.take(&batch.last).await?;
+        println!(
+            "[settlement] batch of blocks {} to {} settled, {:?}",
+            batch.first.number, batch.last.number, reason
+        );
+        *pending = None;
+        Ok(record)
+    }
+
+    pub async fn pending(&self) -> Option<PendingBatch> {
+        self.pending.lock().await.clone()
+    }
+}
+
+/// Settles batches that waited long enough or meet low fees. Fees that
+/// can't be read leave a batch to its deadline.
+#[async_trait]
+impl<T: SettlementTarget> Job for SettlementBatcher<T> {
+    fn name(&self) -> &'static str {
+        "settlement_batches"
+    }
+
+    fn default_schedule(&self) -> Schedule {
+        Schedule::Every(self.policy.poll_interval_secs.max(1))
+    }
+
+    async fn run(&self) -> Result<()> {
+        if self.pending.lock().await.is_none() {
+            return Ok(());
+        }
+        let fee_rate = match self.target.fee_rate().await {
+            Ok(fee_rate) => Some(fee_rate),
+            Err(err) => {
+                eprintln!("[settlement] no fee rate: {}", err);
+                None
+            }
+        };
+
+        let mut pending = self.pending.lock().await;
+        self.settle_if_due(&mut pending, fee_rate).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use primitive_types::{H160, U128};
+    use secp256k1::SecretKey;
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::store::Store,
+        checkpoint::CheckpointPolicy,
+        types::{Block, Channel},
+    };
+
+    use super::*;
+
+    struct FixedTarget(AtomicU64);
+
+    #[async_trait]
+    impl SettlementTarget for FixedTarget {
+        async fn max_commitment_bytes(&self) -> Result<u64> {
+            match self.0.load(Ordering::Relaxed) {
+                0 => Err(anyhow!("unreachable")),
+                bytes => Ok(bytes),
+            }
+        }
+
+        async fn fee_rate(&self) -> Result<u64> {
+            self.max_commitment_bytes().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follow_settlement_limits() {
+        let capacity = SettlementCapacity::default();
+        let policy = SettlementPolicy {
+            overhead_bytes: 100,
+            ..Default::default()
+        };
+        let follower =
+            SettlementFollower::new(FixedTarget(AtomicU64::new(1000)), capacity.clone(), policy);
+
+        assert_eq!(follower.refresh().await.unwrap(), 900);
+        assert_eq!(capacity.get(), 900);
+
+        // Unreachable keeps the last limit, a target too small for the
+        // overhead packages nothing
+        follower.target.0.store(0, Ordering::Relaxed);
+        assert!(follower.refresh().await.is_err());
+        assert_eq!(capacity.get(), 900);
+        follower.target.0.store(50, Ordering::Relaxed);
+        assert_eq!(follower.refresh().await.unwrap(), 1);
+    }
+
+    fn receipt(number: u64, timestamp: u64, channels: &[(u64, u64)]) -> ConsensusReceipt {
+        ConsensusReceipt {
+            block: Arc::new(Block {
+                header: BlockHeader {
+                    number,
+                    hash: H256::from_low_u64_be(number),
+                    timestamp: U128::from(timestamp * 1000),
+                    ..Default::default()
+                },
+                txs: vec![],
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: { channels.iter() }
+                .map(|(id, balance)| {
+                    let channel = Channel {
+                        id: (*id).into(),
+                        total_balance: (*balance).into(),
+                        ..Default::default()
+                    };
+                    (H256::from_low_u64_be(*id), channel)
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_settle_batches() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let checkpoints = CheckpointManager::new(&store, key, CheckpointPolicy::default()).unwrap();
+        let policy = BatchPolicy {
+            max_value_at_risk: 100,
+            ..Default::default()
+        };
+        let batcher = SettlementBatcher::new(
+            FixedTarget(AtomicU64::new(0)),
+            checkpoints.clone(),
+            policy.clone(),
+        );
+
+        // A channel counts once, at its latest balance
+        let now = unix_now();
+        let settled = batcher
+            .on_consensus_receipt(&receipt(1, now, &[(1, 40)]))
+            .await
+            .unwrap();
+        assert!(settled.is_none());
+        let settled = batcher
+            .on_consensus_receipt(&receipt(2, now, &[(1, 60)]))
+            .await
+            .unwrap();
+        assert!(settled.is_none());
+        assert_eq!(batcher.pending().await.unwrap().value_at_risk(), 60.into());
+        let settled = batcher
+            .on_consensus_receipt(&receipt(3, now, &[(2, 50)]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settled.checkpoint.number, 3);
+        assert_eq!(
+            checkpoints.commitment(3).await.unwrap().unwrap().from_block,
+            1
+        );
+        assert!(batcher.pending().await.is_none());
+
+        // Fees that can't be read don't settle early, low ones do
+        let old = now - policy.min_delay_secs;
+        batcher
+            .on_consensus_receipt(&receipt(4, old, &[]))
+            .await
+            .unwrap();
+        batcher.run().await.unwrap();
+        assert!(batcher.pending().await.is_some());
+        batcher
+            .target
+            .0
+            .store(policy.low_fee_rate + 1, Ordering::Relaxed);
+        batcher.run().await.unwrap();
+        assert!(batcher.pending().await.is_some());
+        batcher
+            .target
+            .0
+            .store(policy.low_fee_rate, Ordering::Relaxed);
+        batcher.run().await.unwrap();
+        assert!(batcher.pending().await.is_none());
+        assert_eq!(
+            checkpoints
+                .latest()
+                .await
+                .unwrap()
+                .unwrap()
+                .checkpoint
+                .number,
+            4
+        );
+
+        let batch = PendingBatch::new(&receipt(5, now, &[]).block.header);
+        assert_eq!(policy.decide(&batch, now + 10, Some(0)), None);
+        assert_eq!(
+            policy.decide(&batch, now + policy.max_delay_secs, None),
+            Some(SettleReason::Deadline)
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, Result};
+use primitive_types::H256;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::store::{AsyncStore, Store, StoreError},
+    finality::FinalityTracker,
+};
+
+const DEPOSIT_TREE: &str = "deposit_status";
+const WITHDRAWAL_TREE: &str = "withdrawal_status";
+
+/// A CKB cell carrying a deposit.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub tx_hash: H256,
+    pub index: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DepositStage {
+    // Seen on L1 by the oracle
+    Detected,
+    // Forwarded to layer2 by the relayer
+    Relayed,
+    // Reflected in layer3 channel state
+    Credited,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WithdrawalStage {
+    // Waiting in the relayer queue
+    Queued,
+    // Included in a layer3 block that is not final yet
+    Committed,
+    // The including block is final, funds can be claimed on L1
+    Claimable,
+    Claimed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DepositStatus {
+    pub out_point: OutPoint,
+    pub stage: DepositStage,
+    pub block_number: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct WithdrawalStatus {
+    pub request_id: H256,
+    pub stage: WithdrawalStage,
+    pub block_number: Option<u64>,
+}
+
+/// End-user view of deposits and withdrawals. The oracle and relayer record
+/// the stages they own, while `Claimable` is derived from block finality.
+#[derive(Clone)]
+pub struct TransferTracker {
+    deposits: AsyncStore,
+    withdrawals: AsyncStore,
+    finality: FinalityTracker,
+}
+
+impl TransferTracker {
+    pub fn new(store: &Store, finality: FinalityTracker) -> Result<Self, StoreError> {
+        let tracker = TransferTracker {
+            deposits: AsyncStore::new(store.open_tree(DEPOSIT_TREE)?),
+            withdrawals: AsyncStore::new(store.open_tree(WITHDRAWAL_TREE)?),
+            finality,
+        };
+
+        Ok(tracker)
+    }
+
+    pub async fn deposit_detected(&self, out_point: OutPoint) -> Result<()> {
+        if self
+            .deposits
+            .get::<_, DepositStatus>(&out_point)
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let status = DepositStatus {
+            out_point,
+            stage: DepositStage::Detected,
+            block_number: None,
+        };
+        self.deposits.insert(out_point, status).await?;
+        Ok(())
+    }
+
+    pub async fn deposit_relayed(&self, out_point: OutPoint) -> Result<()> {
+        self.advance_deposit(out_point, DepositStage::Relayed, None)
+            .await
+    }
+
+    pub async fn deposit_credited(&self, out_point: OutPoint, block_number: u64) -> Result<()> {
+        self.advance_deposit(out_point, DepositStage::Credited, Some(block_number))
+            .await
+    }
+
+    pub async fn withdrawal_queued(&self, request_id: H256) -> Result<()> {
+        if self
+            .withdrawals
+            .get::<_, WithdrawalStatus>(&request_id)
+            .await?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let status = WithdrawalStatus {
+            request_id,
+            stage: WithdrawalStage::Queued,
+            block_number: None,
+        };
+        self.withdrawals.insert(request_id, status).await?;
+        Ok(())
+    }
+
+    pub async fn withdrawal_committed(&self, request_id: H256, block_number: u64) -> Result<()> {
+        self.advance_withdrawal(request_id, WithdrawalStage::Committed, Some(block_number))
+            .await
+    }
+
+    // Claims are made on L1, the oracle only watches for deposits so far
+    #[allow(dead_code)]
+    pub async fn withdrawal_claimed(&self, request_id: H256) -> Result<()> {
+        match self.get_withdrawal_status(request_id).await? {
+            Some(status) if status.stage == WithdrawalStage::Claimable => {
+                self.advance_withdrawal(request_id, WithdrawalStage::Claimed, None)
+                    .await
+            }
+            Some(status) => Err(anyhow!(
+                "withdrawal {:?} is {:?}, not claimable",
+                request_id,
+                status.stage
+            )),
+            None => Err(anyhow!("withdrawal {:?} not found", request_id)),
+        }
+    }
+
+    pub async fn get_deposit_status(&self, out_point: OutPoint) -> Result<Option<DepositStatus>> {
+        Ok(self.deposits.get(&out_point).await?)
+    }
+
+    /// Deposits seen on L1 that the relayer hasn't forwarded yet.
+    pub async fn detected_deposits(&self) -> Result<Vec<OutPoint>> {
+        let deposits: Vec<DepositStatus> = self.deposits.run(|store| store.values()).await??;
+
+        let detected = { deposits.into_iter() }
+            .filter(|status| status.stage == DepositStage::Detected)
+            .map(|status| status.out_point)
+            .collect();
+        Ok(detected)
+    }
+
+    pub async fn get_withdrawal_status(
+        &self,
+        request_id: H256,
+    ) -> Result<Option<WithdrawalStatus>> {
+        let mut status = match self
+            .withdrawals
+            .get::<_, WithdrawalStatus>(&request_id)
+            .await?
+        {
+            Some(status) => status,
+            None => return Ok(None),
+        };
+
+        if let (WithdrawalStage::Committed, Some(number)) = (status.stage, status.block_number) {
+            let finality = self.finality.get_block_finality(number).await?;
+            if finality.map(|f| f.is_irreversible()).unwrap_or(false) {
+                status.stage = WithdrawalStage::Claimable;
+            }
+        }
+
+        Ok(Some(status))
+    }
+
+    /// Withdrawals committed in the blocks `numbers`.
+    pub async fn committed_withdrawals(&self, numbers: RangeInclusive<u64>) -> Result<Vec<H256>> {
+        let withdrawals: Vec<WithdrawalStatus> =
+            self.withdrawals.run(|store| store.values()).await??;
+
+        let committed = { withdrawals.into_iter() }
+            .filter(|status| { status.block_number }.is_some_and(|n| numbers.contains(&n)))
+            .map(|status| status.request_id)
+            .collect();
+        Ok(committed)
+    }
+
+    /// Lowest block holding a withdrawal that hasn't been claimed yet. Its
+    /// data is needed to build the claim proof and must not be pruned.
+    pub async fn oldest_unclaimed_withdrawal(&self) -> Result<Option<u64>> {
+        let withdrawals: Vec<WithdrawalStatus> =
+            self.withdrawals.run(|store| store.values()).await??;
+
+        let oldest = { withdrawals.iter() }
+            .filter(|status| status.stage != WithdrawalStage::Claimed)
+            .filter_map(|status| status.block_number)
+            .min();
+        Ok(oldest)
+    }
+
+    async fn advance_deposit(
+        &self,
+        out_point: OutPoint,
+        stage: DepositStage,
+        block_number: Option<u64>,
+    ) -> Result<()> {
+        let mut status = match self.get_deposit_status(out_point).await? {
+            Some(status) => status,
+            None => return Err(anyhow!("deposit {:?} not found", out_point)),
+        };
+        if status.stage >= stage {
+            return Err(anyhow!(
+                "deposit {:?} can't move from {:?} to {:?}",
+                out_point,
+                status.stage,
+                stage
+            ));
+        }
+
+        status.stage = stage;
+        status.block_number = block_number.or(status.block_number);
+        self.deposits.insert(out_point, status).await?;
+        Ok(())
+    }
+
+    async fn advance_withdrawal(
+        &self,
+        request_id: H256,
+        stage: WithdrawalStage,
+        block_number: Option<u64>,
+    ) -> Result<()> {
+        let mut status = match self.get_withdrawal_status(request_id).await? {
+            Some(status) => status,
+            None => return Err(anyhow!("withdrawal {:?} not found", request_id)),
+        };
+        if status.stage >= stage {
+            return Err(anyhow!(
+                "withdrawal {:?} can't move from {:?} to {:?}",
+                request_id,
+                status.stage,
+                stage
+            ));
+        }
+
+        status.stage = stage;
+        status.block_number = block_number.or(status.block_number);
+        self.withdrawals.insert(request_id, status).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::fixture::save_blocks;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_withdrawal_becomes_claimable_when_final() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        save_blocks(&store, 1, |_| 0, None).await;
+
+        let finality = FinalityTracker::new(store.clone(), 0).unwrap();
+        let tracker = TransferTracker::new(&store, finality.clone()).unwrap();
+
+        let request_id = H256::repeat_byte(1);
+        tracker.withdrawal_queued(request_id).await.unwrap();
+        tracker.withdrawal_committed(request_id, 1).await.unwrap();
+        assert!(tracker.withdrawal_claimed(request_id).await.is_err());
+
+        finality.submitted_to_l2(1, H256::zero()).await.unwrap();
+        finality.l2_confirmed(1, 1).await.unwrap();
+        finality.committed_to_ckb(1, H256::zero(), 1).await.unwrap();
+        finality.ckb_tip_updated(1).await.unwrap();
+
+        let status = tracker.get_withdrawal_status(request_id).await.unwrap();
+        assert_eq!(status.unwrap().stage, WithdrawalStage::Claimable);
+        tracker.withdrawal_claimed(request_id).await.unwrap();
+
+        let status = tracker.get_withdrawal_status(request_id).await.unwrap();
+        assert_eq!(status.unwrap().stage, WithdrawalStage::Claimed);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_stages() {
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let finality = FinalityTracker::new(store.clone(), 0).unwrap();
+        let tracker = TransferTracker::new(&store, finality).unwrap();
+
+        let out_point = OutPoint {
+            tx_hash: H256::repeat_byte(1),
+            index: 0,
+        };
+        assert!(tracker.deposit_relayed(out_point).await.is_err());
+
+        tracker.deposit_detected(out_point).await.unwrap();
+        tracker.deposit_relayed(out_point).await.unwrap();
+        tracker.deposit_credited(out_point, 3).await.unwrap();
+
+        let status = tracker
+            .get_deposit_status(out_point)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.stage, DepositStage::Credited);
+        assert_eq!(status.block_number, Some(3));
+    }
+}
@@ -1,7 +1,14 @@
+use std::{fmt, str::FromStr};
+
+use bytes::Bytes;
 use primitive_types::{H160, H256, U128, U256};
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use share::{
+    amount::{self, AmountError},
+    limits::{DecodeError, TRANSACTION_LIMIT},
+};
 
-use crate::auxiliaries::common::blake2b;
+use crate::auxiliaries::common::{blake2b, decode_bincode};
 
 pub type Signature = Vec<u8>;
 pub type Byte32 = [u8; 32];
@@ -9,10 +16,141 @@ pub type Byte32 = [u8; 32];
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
 pub struct Token {
     pub id: U256,
-    pub symbol: Byte32,
+    pub symbol: Symbol,
     pub decimal: U256,
 }
 
+impl Token {
+    // Amounts are in base units on the node, wallets format them
+    #[allow(dead_code)]
+    pub fn decimals(&self) -> Result<u8, AmountError> {
+        if self.decimal > U256::from(u64::MAX) {
+            return Err(AmountError::Decimals(u64::MAX));
+        }
+        amount::check_decimals(self.decimal.as_u64())
+    }
+
+    /// Parse a decimal string like `"1.5"` into base units of this token.
+    #[allow(dead_code)]
+    pub fn parse_amount(&self, amount: &str) -> Result<U128, AmountError> {
+        amount::parse_amount_u128(amount, self.decimals()?).map(U128::from)
+    }
+
+    #[allow(dead_code)]
+    pub fn format_amount(&self, value: U128) -> Result<String, AmountError> {
+        Ok(amount::format_amount(
+            value.as_u128().into(),
+            self.decimals()?,
+        ))
+    }
+}
+
+pub const MAX_SYMBOL_LEN: usize = 32;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum SymbolError {
+    #[error("symbol is empty")]
+    Empty,
+    #[error("symbol {0:?} is longer than {MAX_SYMBOL_LEN} bytes")]
+    TooLong(String),
+    #[error("symbol {0:?} has control characters")]
+    ControlCharacter(String),
+    #[error("symbol is not utf-8")]
+    NotUtf8,
+}
+
+/// A token symbol, UTF-8 of at most 32 bytes. Stored zero padded in 32
+/// bytes, and written as a string in JSON and TOML.
+#[derive(Default, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct Symbol(Byte32);
+
+impl Symbol {
+    pub fn new(symbol: &str) -> Result<Self, SymbolError> {
+        if symbol.is_empty() {
+            return Err(SymbolError::Empty);
+        }
+        if symbol.len() > MAX_SYMBOL_LEN {
+            return Err(SymbolError::TooLong(symbol.to_owned()));
+        }
+        // NUL included, it's the padding
+        if symbol.chars().any(char::is_control) {
+            return Err(SymbolError::ControlCharacter(symbol.to_owned()));
+        }
+
+        let mut bytes = Byte32::default();
+        bytes[..symbol.len()].copy_from_slice(symbol.as_bytes());
+        Ok(Symbol(bytes))
+    }
+
+    /// The stored form. All zeros is the empty symbol of a default token.
+    pub fn from_bytes(bytes: Byte32) -> Result<Self, SymbolError> {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(MAX_SYMBOL_LEN);
+        let symbol = std::str::from_utf8(&bytes[..len]).map_err(|_| SymbolError::NotUtf8)?;
+        if bytes[len..].iter().any(|b| *b != 0) {
+            return Err(SymbolError::ControlCharacter(symbol.to_owned()));
+        }
+
+        Ok(Symbol(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self
+            .0
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(MAX_SYMBOL_LEN);
+        // Checked on construction
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+
+    // The zero padded form, which only the store sees so far
+    #[allow(dead_code)]
+    pub fn as_bytes(&self) -> &Byte32 {
+        &self.0
+    }
+}
+
+impl FromStr for Symbol {
+    type Err = SymbolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Symbol::new(s)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Symbol({:?})", self.as_str())
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(self.as_str())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let symbol = String::deserialize(deserializer)?;
+            Symbol::new(&symbol).map_err(D::Error::custom)
+        } else {
+            Symbol::from_bytes(Byte32::deserialize(deserializer)?).map_err(D::Error::custom)
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
 pub struct Balance {
     pub settled: U128,
@@ -40,6 +178,7 @@ pub struct Channel {
     pub total_balance: U256,
     pub balance2: [Balance; 2],
     // pub transaction_root: H256,
+    pub guard: Option<ChannelGuard>,
 }
 
 impl Channel {
@@ -48,40 +187,268 @@ impl Channel {
     }
 }
 
+// Layout stored before rate guards
+#[derive(Deserialize)]
+pub struct ChannelV1 {
+    id: U256,
+    token: Token,
+    challenge_blocks: u64,
+    participant2: [H160; 2],
+    state: ChannelState,
+    version: u64,
+    total_balance: U256,
+    balance2: [Balance; 2],
+}
+
+impl From<ChannelV1> for Channel {
+    fn from(channel: ChannelV1) -> Self {
+        Channel {
+            id: channel.id,
+            token: channel.token,
+            challenge_blocks: channel.challenge_blocks,
+            participant2: channel.participant2,
+            state: channel.state,
+            version: channel.version,
+            total_balance: channel.total_balance,
+            balance2: channel.balance2,
+            guard: None,
+        }
+    }
+}
+
+/// Limits on how fast the balances of a channel can move, fixed when the
+/// channel is created. A compromised participant key can then only drain
+/// the channel slowly. Zero means no limit.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
+pub struct RateGuard {
+    // Most a balance may move by in one update
+    pub max_delta_per_update: U128,
+    // Most a balance may move by within `window_blocks` blocks
+    pub max_delta_per_window: U128,
+    pub window_blocks: u64,
+}
+
+/// A channel's rate guard and the movement counted in its current window.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
+pub struct ChannelGuard {
+    pub policy: RateGuard,
+    pub window_start: u64,
+    pub window_moved: U128,
+}
+
+impl ChannelGuard {
+    pub fn new(policy: RateGuard, block_number: u64) -> Self {
+        ChannelGuard {
+            policy,
+            window_start: block_number,
+            window_moved: U128::zero(),
+        }
+    }
+
+    /// Guard after moving balances from `prev` to `next` in block
+    /// `block_number`, `None` if that exceeds a limit.
+    pub fn admit(
+        &self,
+        block_number: u64,
+        prev: &[Balance; 2],
+        next: &[Balance; 2],
+    ) -> Option<ChannelGuard> {
+        let delta = { prev.iter().zip(next.iter()) }
+            .map(|(p, n)| p.settled.abs_diff(n.settled))
+            .max()
+            .unwrap_or_default();
+        let policy = &self.policy;
+        if !policy.max_delta_per_update.is_zero() && delta > policy.max_delta_per_update {
+            return None;
+        }
+        if policy.max_delta_per_window.is_zero() || policy.window_blocks == 0 {
+            return Some(self.clone());
+        }
+
+        let mut next = self.clone();
+        if block_number >= self.window_start.saturating_add(policy.window_blocks) {
+            next.window_start = block_number;
+            next.window_moved = U128::zero();
+        }
+        next.window_moved = next.window_moved.checked_add(delta)?;
+        if next.window_moved > policy.max_delta_per_window {
+            return None;
+        }
+        Some(next)
+    }
+}
+
+/// Smallest deposit a channel of a token opens with and smallest balance
+/// move an update of it makes, so micro channels and no-op updates can't
+/// bloat the state. Zero means no limit.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub struct DustLimits {
+    pub min_deposit: U128,
+    pub min_update_delta: U128,
+}
+
+impl DustLimits {
+    pub fn admits_deposit(&self, balance2: &[Balance; 2]) -> bool {
+        let total = { balance2.iter() }.fold(U256::zero(), |accu, b| accu + b.settled);
+        total >= self.min_deposit.as_u128().into()
+    }
+
+    pub fn admits_update(&self, prev: &[Balance; 2], next: &[Balance; 2]) -> bool {
+        let delta = { prev.iter().zip(next.iter()) }
+            .map(|(p, n)| p.settled.abs_diff(n.settled))
+            .max()
+            .unwrap_or_default();
+        delta >= self.min_update_delta
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CreateChannel {
+    pub chain_id: u64,
     pub id: U256,
     pub token: Token,
     pub challenge_blocks: u64,
     pub participant2: [H160; 2],
     pub balance2: [Balance; 2],
+    pub guard: Option<RateGuard>,
+}
+
+// Layout of transaction versions 1 to 3, before rate guards
+#[derive(Deserialize)]
+struct CreateChannelV1 {
+    chain_id: u64,
+    id: U256,
+    token: Token,
+    challenge_blocks: u64,
+    participant2: [H160; 2],
+    balance2: [Balance; 2],
+}
+
+impl From<CreateChannelV1> for CreateChannel {
+    fn from(args: CreateChannelV1) -> Self {
+        CreateChannel {
+            chain_id: args.chain_id,
+            id: args.id,
+            token: args.token,
+            challenge_blocks: args.challenge_blocks,
+            participant2: args.participant2,
+            balance2: args.balance2,
+            guard: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct UpdateChannel {
+    pub chain_id: u64,
     pub channel_id: U256,
     pub version: u64,
     pub balance2: [Balance; 2],
     // pub transaction_root: H256,
     pub signature2: [Signature; 2],
+    // Invoice hash or client generated id of the payment this update makes
+    pub payment_id: Option<H256>,
+    pub memo: Option<SubAccountMemo>,
+}
+
+/// Tags an update as a deposit of `amount` to `sub_account` of participant
+/// `recipient`, so an exchange can credit its users through one channel.
+/// The executor rejects updates whose balances don't match the memo.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct SubAccountMemo {
+    pub recipient: u8,
+    pub sub_account: u64,
+    pub amount: U128,
+}
+
+impl SubAccountMemo {
+    /// Whether moving from `prev` to `next` credits exactly `amount` to the
+    /// recipient.
+    pub fn matches(&self, prev: &[Balance; 2], next: &[Balance; 2]) -> bool {
+        let recipient = self.recipient as usize;
+        if recipient >= prev.len() {
+            return false;
+        }
+
+        prev[recipient].settled.checked_add(self.amount) == Some(next[recipient].settled)
+    }
+}
+
+// Layout of transaction version 2, before sub-account memos
+#[derive(Deserialize)]
+struct UpdateChannelV2 {
+    chain_id: u64,
+    channel_id: U256,
+    version: u64,
+    balance2: [Balance; 2],
+    signature2: [Signature; 2],
+    payment_id: Option<H256>,
+}
+
+impl From<UpdateChannelV2> for UpdateChannel {
+    fn from(args: UpdateChannelV2) -> Self {
+        UpdateChannel {
+            chain_id: args.chain_id,
+            channel_id: args.channel_id,
+            version: args.version,
+            balance2: args.balance2,
+            signature2: args.signature2,
+            payment_id: args.payment_id,
+            memo: None,
+        }
+    }
+}
+
+// Layout of transaction version 1, before payment ids
+#[derive(Deserialize)]
+struct UpdateChannelV1 {
+    chain_id: u64,
+    channel_id: U256,
+    version: u64,
+    balance2: [Balance; 2],
+    signature2: [Signature; 2],
+}
+
+impl From<UpdateChannelV1> for UpdateChannel {
+    fn from(args: UpdateChannelV1) -> Self {
+        UpdateChannel {
+            chain_id: args.chain_id,
+            channel_id: args.channel_id,
+            version: args.version,
+            balance2: args.balance2,
+            signature2: args.signature2,
+            payment_id: None,
+            memo: None,
+        }
+    }
 }
 
 impl UpdateChannel {
+    /// The payment id and memo are signed along when there are any, updates
+    /// without keep the message of version 1.
     pub fn sig_msg(&self) -> H256 {
-        let args = UpdateChannel {
-            channel_id: self.channel_id,
-            version: self.version,
-            balance2: self.balance2.clone(),
-            ..Default::default()
-        };
+        let args = (
+            self.chain_id,
+            self.channel_id,
+            self.version,
+            &self.balance2,
+            <[Signature; 2]>::default(),
+        );
 
-        let encoded = bincode::serialize(&args).unwrap();
+        let mut encoded = bincode::serialize(&args).unwrap();
+        if let Some(payment_id) = self.payment_id {
+            encoded.extend_from_slice(payment_id.as_bytes());
+        }
+        if let Some(memo) = &self.memo {
+            encoded.extend(bincode::serialize(memo).unwrap());
+        }
         blake2b(&encoded)
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct CloseChannel {
+    pub chain_id: u64,
     pub channel_id: U256,
     pub version: u64,
     pub signature2: [Signature; 2],
@@ -90,6 +457,7 @@ pub struct CloseChannel {
 impl CloseChannel {
     pub fn sig_msg(&self) -> H256 {
         let args = CloseChannel {
+            chain_id: self.chain_id,
             channel_id: self.channel_id,
             version: self.version,
             ..Default::default()
@@ -100,6 +468,8 @@ impl CloseChannel {
     }
 }
 
+// Not a transaction of its own, transfers are channel updates so far
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Transfer {
     pub channel_id: U256,
@@ -107,24 +477,153 @@ pub struct Transfer {
     pub amount: U128,
 }
 
+pub const TRANSACTION_VERSION: u8 = 4;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(into = "TransactionEnvelope", try_from = "TransactionEnvelope")]
 pub enum RawTransaction {
     CreateChannel(CreateChannel),
     UpdateChannel(UpdateChannel),
     CloseChannel(CloseChannel),
 }
 
+impl RawTransaction {
+    pub fn chain_id(&self) -> u64 {
+        match self {
+            RawTransaction::CreateChannel(args) => args.chain_id,
+            RawTransaction::UpdateChannel(args) => args.chain_id,
+            RawTransaction::CloseChannel(args) => args.chain_id,
+        }
+    }
+
+    pub fn channel_id(&self) -> U256 {
+        match self {
+            RawTransaction::CreateChannel(args) => args.id,
+            RawTransaction::UpdateChannel(args) => args.channel_id,
+            RawTransaction::CloseChannel(args) => args.channel_id,
+        }
+    }
+
+    /// Channel version the transaction moves to, 0 for a new channel.
+    pub fn version(&self) -> u64 {
+        match self {
+            RawTransaction::CreateChannel(_) => 0,
+            RawTransaction::UpdateChannel(args) => args.version,
+            RawTransaction::CloseChannel(args) => args.version,
+        }
+    }
+
+    pub fn payment_id(&self) -> Option<H256> {
+        match self {
+            RawTransaction::UpdateChannel(args) => args.payment_id,
+            _ => None,
+        }
+    }
+
+    pub fn memo(&self) -> Option<&SubAccountMemo> {
+        match self {
+            RawTransaction::UpdateChannel(args) => args.memo.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Message both participants sign, `None` for transactions without
+    /// participant signatures.
+    pub fn sig_msg(&self) -> Option<H256> {
+        match self {
+            RawTransaction::CreateChannel(_) => None,
+            RawTransaction::UpdateChannel(args) => Some(args.sig_msg()),
+            RawTransaction::CloseChannel(args) => Some(args.sig_msg()),
+        }
+    }
+
+    pub fn signature2_mut(&mut self) -> Option<&mut [Signature; 2]> {
+        match self {
+            RawTransaction::CreateChannel(_) => None,
+            RawTransaction::UpdateChannel(args) => Some(&mut args.signature2),
+            RawTransaction::CloseChannel(args) => Some(&mut args.signature2),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EnvelopeError {
+    #[error("unsupported transaction type {0} version {1}")]
+    Unsupported(u8, u8),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// Wire and storage form of `RawTransaction`. The type byte picks the
+/// transaction kind and the version its layout, so a node rejects a
+/// transaction it doesn't understand instead of misreading it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransactionEnvelope {
+    pub tx_type: u8,
+    pub version: u8,
+    pub payload: Vec<u8>,
+}
+
+impl From<RawTransaction> for TransactionEnvelope {
+    fn from(raw: RawTransaction) -> Self {
+        let (tx_type, payload) = match &raw {
+            RawTransaction::CreateChannel(args) => (0, bincode::serialize(args)),
+            RawTransaction::UpdateChannel(args) => (1, bincode::serialize(args)),
+            RawTransaction::CloseChannel(args) => (2, bincode::serialize(args)),
+        };
+
+        TransactionEnvelope {
+            tx_type,
+            version: TRANSACTION_VERSION,
+            payload: payload.unwrap(),
+        }
+    }
+}
+
+impl TryFrom<TransactionEnvelope> for RawTransaction {
+    type Error = EnvelopeError;
+
+    fn try_from(envelope: TransactionEnvelope) -> Result<Self, Self::Error> {
+        let payload = &envelope.payload;
+        let raw = match (envelope.tx_type, envelope.version) {
+            (0, 1..=3) => RawTransaction::CreateChannel(
+                decode_bincode::<CreateChannelV1>(payload, &TRANSACTION_LIMIT)?.into(),
+            ),
+            (0, TRANSACTION_VERSION) => {
+                RawTransaction::CreateChannel(decode_bincode(payload, &TRANSACTION_LIMIT)?)
+            }
+            (1, 1) => RawTransaction::UpdateChannel(
+                decode_bincode::<UpdateChannelV1>(payload, &TRANSACTION_LIMIT)?.into(),
+            ),
+            (1, 2) => RawTransaction::UpdateChannel(
+                decode_bincode::<UpdateChannelV2>(payload, &TRANSACTION_LIMIT)?.into(),
+            ),
+            (1, 3..=TRANSACTION_VERSION) => {
+                RawTransaction::UpdateChannel(decode_bincode(payload, &TRANSACTION_LIMIT)?)
+            }
+            (2, 1..=TRANSACTION_VERSION) => {
+                RawTransaction::CloseChannel(decode_bincode(payload, &TRANSACTION_LIMIT)?)
+            }
+            (tx_type, version) => return Err(EnvelopeError::Unsupported(tx_type, version)),
+        };
+
+        Ok(raw)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SignedTransaction {
     pub raw: RawTransaction,
     pub sig: Signature,
+    // Offered by the sender for priority in packaging
+    pub fee: U128,
 
     // Cache only
     pub from: H160,
     pub hash: H256,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum ExecutionExitCode {
     Success = 0,
@@ -132,9 +631,16 @@ pub enum ExecutionExitCode {
     ErrorChannelNotFound = 2,
     ErrorRollbackChannelVersion = 3,
     ErrorUpdateChannelSignature = 4,
+    ErrorChainIdMismatch = 5,
+    // Balances don't credit what the sub-account memo says
+    ErrorSubAccountMemo = 6,
+    // Balances move faster than the channel's rate guard allows
+    ErrorRateGuard = 7,
+    // Deposit or balance move below the token's dust limits
+    ErrorDust = 8,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TransactionReceipt {
     pub exit_code: ExecutionExitCode,
     pub state_root: H256,
@@ -156,7 +662,7 @@ impl TransactionReceipt {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct BlockHeader {
     pub number: u64,
     pub hash: H256,
@@ -167,13 +673,271 @@ pub struct BlockHeader {
     pub receipt_root: H256,
 }
 
+impl BlockHeader {
+    pub fn calc_hash(&self) -> H256 {
+        let args = BlockHeader {
+            hash: H256::zero(),
+            ..self.clone()
+        };
+
+        let encoded = bincode::serialize(&args).unwrap();
+        blake2b(&encoded)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Block {
     pub header: BlockHeader,
     pub txs: Vec<SignedTransaction>,
 }
 
+/// A block read from the store with its transactions still bincode encoded,
+/// they're decoded only when asked for.
+#[derive(Debug, Clone)]
+pub struct EncodedBlock {
+    pub header: BlockHeader,
+    // Encoded `Vec<SignedTransaction>`
+    pub body: Bytes,
+}
+
+impl EncodedBlock {
+    pub fn txs(&self) -> Result<Vec<SignedTransaction>, bincode::Error> {
+        bincode::deserialize(&self.body)
+    }
+
+    pub fn decode(self) -> Result<Block, bincode::Error> {
+        let txs = self.txs()?;
+        Ok(Block {
+            header: self.header,
+            txs,
+        })
+    }
+
+    /// The block as `bincode::serialize` encodes a `Block`, without decoding
+    /// its transactions.
+    pub fn to_bincode(&self) -> Result<Vec<u8>, bincode::Error> {
+        let mut encoded = bincode::serialize(&self.header)?;
+        encoded.extend_from_slice(&self.body);
+        Ok(encoded)
+    }
+}
+
+impl TryFrom<Block> for EncodedBlock {
+    type Error = bincode::Error;
+
+    fn try_from(block: Block) -> Result<Self, Self::Error> {
+        Ok(EncodedBlock {
+            body: bincode::serialize(&block.txs)?.into(),
+            header: block.header,
+        })
+    }
+}
+
 pub enum NumberHash {
     Number(u64),
     Hash(H256),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transaction_envelope() {
+        let raw = RawTransaction::CloseChannel(CloseChannel {
+            chain_id: 1,
+            channel_id: 7.into(),
+            version: 3,
+            ..Default::default()
+        });
+        let encoded = bincode::serialize(&raw).unwrap();
+        let decoded: RawTransaction = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded.channel_id(), 7.into());
+
+        let mut envelope = TransactionEnvelope::from(raw);
+        envelope.version = TRANSACTION_VERSION + 1;
+        assert!(matches!(
+            RawTransaction::try_from(envelope.clone()),
+            Err(EnvelopeError::Unsupported(2, _))
+        ));
+
+        let encoded = bincode::serialize(&envelope).unwrap();
+        assert!(bincode::deserialize::<RawTransaction>(&encoded).is_err());
+
+        // Payloads with trailing bytes or above the size limit
+        envelope.version = TRANSACTION_VERSION;
+        envelope.payload.push(0);
+        assert!(matches!(
+            RawTransaction::try_from(envelope.clone()),
+            Err(EnvelopeError::Decode(DecodeError::Malformed { .. }))
+        ));
+        envelope.payload.resize(TRANSACTION_LIMIT.max_size + 1, 0);
+        assert!(matches!(
+            RawTransaction::try_from(envelope),
+            Err(EnvelopeError::Decode(DecodeError::TooLarge { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_version_1_update() {
+        let update = UpdateChannel {
+            chain_id: 1,
+            channel_id: 7.into(),
+            version: 3,
+            ..Default::default()
+        };
+        let v1 = (
+            update.chain_id,
+            update.channel_id,
+            update.version,
+            &update.balance2,
+            &update.signature2,
+        );
+        let envelope = TransactionEnvelope {
+            tx_type: 1,
+            version: 1,
+            payload: bincode::serialize(&v1).unwrap(),
+        };
+        match RawTransaction::try_from(envelope).unwrap() {
+            RawTransaction::UpdateChannel(decoded) => {
+                assert_eq!(decoded.payment_id, None);
+                assert_eq!(decoded.sig_msg(), update.sig_msg());
+            }
+            _ => panic!("not an update"),
+        }
+
+        let paying = UpdateChannel {
+            payment_id: Some(H256::repeat_byte(1)),
+            ..update.clone()
+        };
+        assert_ne!(paying.sig_msg(), update.sig_msg());
+
+        let v2 = (v1.0, v1.1, v1.2, v1.3, v1.4, paying.payment_id);
+        let envelope = TransactionEnvelope {
+            tx_type: 1,
+            version: 2,
+            payload: bincode::serialize(&v2).unwrap(),
+        };
+        let decoded = RawTransaction::try_from(envelope).unwrap();
+        assert_eq!(decoded.payment_id(), paying.payment_id);
+        assert_eq!(decoded.memo(), None);
+        assert_eq!(decoded.sig_msg(), Some(paying.sig_msg()));
+    }
+
+    #[test]
+    fn test_sub_account_memo() {
+        let balances = |a: u64, b: u64| {
+            [a, b].map(|settled| Balance {
+                settled: settled.into(),
+            })
+        };
+        let memo = SubAccountMemo {
+            recipient: 1,
+            sub_account: 42,
+            amount: 30.into(),
+        };
+        assert!(memo.matches(&balances(100, 0), &balances(70, 30)));
+        assert!(!memo.matches(&balances(100, 0), &balances(80, 20)));
+        assert!(!SubAccountMemo {
+            recipient: 2,
+            ..memo
+        }
+        .matches(&balances(0, 0), &balances(0, 0)));
+
+        let update = UpdateChannel {
+            memo: Some(memo),
+            ..Default::default()
+        };
+        let other = UpdateChannel {
+            memo: Some(SubAccountMemo {
+                sub_account: 43,
+                ..memo
+            }),
+            ..Default::default()
+        };
+        assert_ne!(update.sig_msg(), other.sig_msg());
+    }
+
+    #[test]
+    fn test_rate_guard() {
+        let balances = |a: u64, b: u64| {
+            [a, b].map(|settled| Balance {
+                settled: settled.into(),
+            })
+        };
+        let policy = RateGuard {
+            max_delta_per_update: 50.into(),
+            max_delta_per_window: 80.into(),
+            window_blocks: 10,
+        };
+        let guard = ChannelGuard::new(policy, 1);
+
+        assert!(guard
+            .admit(2, &balances(100, 0), &balances(40, 60))
+            .is_none());
+        let guard = guard
+            .admit(2, &balances(100, 0), &balances(50, 50))
+            .unwrap();
+        assert_eq!(guard.window_moved, 50.into());
+        // 50 + 40 exceeds the window limit
+        assert!(guard
+            .admit(5, &balances(50, 50), &balances(10, 90))
+            .is_none());
+        let guard = guard
+            .admit(5, &balances(50, 50), &balances(70, 30))
+            .unwrap();
+        assert_eq!(guard.window_moved, 70.into());
+
+        // The next window starts empty
+        let guard = guard
+            .admit(11, &balances(70, 30), &balances(20, 80))
+            .unwrap();
+        assert_eq!((guard.window_start, guard.window_moved), (11, 50.into()));
+        assert!(ChannelGuard::new(RateGuard::default(), 0)
+            .admit(0, &balances(100, 0), &balances(0, 100))
+            .is_some());
+    }
+
+    #[test]
+    fn test_token_amount() {
+        let token = Token {
+            decimal: 8.into(),
+            ..Default::default()
+        };
+        let amount = token.parse_amount("12.345").unwrap();
+        assert_eq!(amount, 1_234_500_000u64.into());
+        assert_eq!(token.format_amount(amount).unwrap(), "12.345");
+        assert!(token.parse_amount("0.000000001").is_err());
+
+        let token = Token {
+            decimal: 100.into(),
+            ..Default::default()
+        };
+        assert_eq!(token.decimals(), Err(AmountError::Decimals(100)));
+    }
+
+    #[test]
+    fn test_symbol() {
+        let symbol = Symbol::new("CKUSD").unwrap();
+        assert_eq!(symbol.as_str(), "CKUSD");
+        assert_eq!(Symbol::new(""), Err(SymbolError::Empty));
+        assert!(matches!(
+            Symbol::new(&"X".repeat(33)),
+            Err(SymbolError::TooLong(_))
+        ));
+        assert!(matches!(
+            Symbol::new("CK\0USD"),
+            Err(SymbolError::ControlCharacter(_))
+        ));
+        assert_eq!(Symbol::new("币").unwrap().to_string(), "币");
+
+        // 32 bytes stored, a string in JSON
+        let encoded = bincode::serialize(&symbol).unwrap();
+        assert_eq!(encoded.len(), MAX_SYMBOL_LEN);
+        assert_eq!(bincode::deserialize::<Symbol>(&encoded).unwrap(), symbol);
+        assert_eq!(serde_json::to_string(&symbol).unwrap(), "\"CKUSD\"");
+        assert_eq!(serde_json::from_str::<Symbol>("\"CKUSD\"").unwrap(), symbol);
+        assert!(serde_json::from_str::<Symbol>("\"\"").is_err());
+        assert!(bincode::deserialize::<Symbol>(&[0xff; 32]).is_err());
+    }
+}
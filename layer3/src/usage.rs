@@ -0,0 +1,32 @@
+use primitive_types::U128;
+use serde::{Deserialize, Serialize};
+
+use crate::types::Block;
+
+/// Resources a block consumed, recorded when it's applied for capacity
+/// planning and fee tuning. Every transaction weighs 1, the package policy
+/// caps a block by transaction count and by the bytes of its transactions.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct BlockUsage {
+    pub number: u64,
+    pub tx_count: u64,
+    pub weight_used: u64,
+    pub weight_limit: u64,
+    pub total_fee: U128,
+    // bincode encoded size
+    pub byte_size: u64,
+}
+
+impl BlockUsage {
+    pub fn of(block: &Block, weight_limit: usize) -> Self {
+        BlockUsage {
+            number: block.header.number,
+            tx_count: block.txs.len() as u64,
+            weight_used: block.txs.len() as u64,
+            weight_limit: weight_limit as u64,
+            total_fee: { block.txs.iter() }
+                .fold(U128::zero(), |sum, tx| sum.saturating_add(tx.fee)),
+            byte_size: bincode::serialized_size(block).unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,565 @@
+use std::{collections::HashMap, io::Read, time::Duration};
+
+use anyhow::{anyhow, Result};
+use primitive_types::{H256, U256};
+use proof::commitment::Commitment;
+use serde::{Deserialize, Serialize};
+use share::{
+    archive::{ArchiveKind, ArchiveReader},
+    limits::BLOCK_LIMIT,
+};
+use tokio::time::interval;
+
+use crate::{
+    auxiliaries::{
+        chain::Chain,
+        common::{cbmt_merkle_root, decode_bincode, H256Ext},
+        smt::SMT,
+        snapshot::SnapshotClient,
+        store::{AsyncStore, Store, StoreError},
+    },
+    config::VerifierConfig,
+    executor::{ChannelExecutor, Executor},
+    notify::Notifier,
+    types::{Block, DustLimits, NumberHash},
+};
+
+const VERIFIER_META_TREE: &str = "verifier_meta";
+// Roots of the verified blocks no checked commitment covers yet
+const VERIFIED_BLOCK_TREE: &str = "verifier_block";
+const PROGRESS_KEY: &str = "progress";
+// Blocks fetched from a snapshot server at a time
+const FETCH_BLOCKS: u64 = 256;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchField {
+    ParentHash,
+    BlockHash,
+    TransactionRoot,
+    StateRoot,
+    ReceiptRoot,
+}
+
+/// A published value that re-execution doesn't reproduce.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    // A single block, or the range of a settlement commitment
+    pub from_block: u64,
+    pub to_block: u64,
+    pub field: MismatchField,
+    pub published: H256,
+    pub computed: H256,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct VerifierProgress {
+    pub next_number: u64,
+    pub parent_hash: H256,
+    // Set by the first block whose header or roots are wrong, no block
+    // after it can be verified
+    pub diverged: Option<Mismatch>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct VerifiedBlock {
+    hash: H256,
+    state_root: H256,
+    receipt_root: H256,
+}
+
+/// Honest verifier of the optimistic settlement. Re-executes layer3 blocks
+/// from published data on a channel SMT of its own and checks them, and
+/// the settlement commitments over them, against the recomputed roots.
+///
+/// `store` must not be shared with a node, the SMT lives in its default
+/// tree like the node's does.
+pub struct Verifier {
+    state: AsyncStore,
+    meta: AsyncStore,
+    blocks: AsyncStore,
+    chain_id: u64,
+    dust: HashMap<U256, DustLimits>,
+    notifier: Option<Notifier>,
+}
+
+impl Verifier {
+    pub fn open(store: &Store, chain_id: u64) -> Result<Self, StoreError> {
+        Ok(Verifier {
+            state: AsyncStore::new(store.clone()),
+            meta: AsyncStore::new(store.open_tree(VERIFIER_META_TREE)?),
+            blocks: AsyncStore::new(store.open_tree(VERIFIED_BLOCK_TREE)?),
+            chain_id,
+            dust: HashMap::new(),
+            notifier: None,
+        })
+    }
+
+    /// Open the verifier of `config` on the store at its `db_path`.
+    pub fn from_config(config: &VerifierConfig) -> Result<Self> {
+        let store = Store::open(&config.db_path)?;
+        let dust = config.token_registry()?.dust_limits();
+        Ok(Verifier::open(&store, config.chain_id)?.with_dust_limits(dust))
+    }
+
+    /// Per token dust limits of the genesis spec, the executor enforces
+    /// them.
+    pub fn with_dust_limits(mut self, dust: HashMap<U256, DustLimits>) -> Self {
+        self.dust = dust;
+        self
+    }
+
+    /// Mismatches go to every registered webhook as well as the log.
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    pub async fn progress(&self) -> Result<VerifierProgress> {
+        let progress = self.meta.get(&PROGRESS_KEY).await?;
+        Ok(progress.unwrap_or(VerifierProgress {
+            next_number: 1,
+            ..Default::default()
+        }))
+    }
+
+    /// Verify the next block. Blocks behind it are only checked against
+    /// the hash verified for their number, if it's still kept. Fails on a
+    /// gap and after the verifier diverged.
+    pub async fn verify_block(&self, block: &Block) -> Result<Option<Mismatch>> {
+        let mut progress = self.progress().await?;
+        if let Some(diverged) = &progress.diverged {
+            return Err(anyhow!(
+                "diverged at block {}, {:?} mismatch",
+                diverged.to_block,
+                diverged.field
+            ));
+        }
+
+        let header = &block.header;
+        if header.number < progress.next_number {
+            let verified = self
+                .blocks
+                .get::<_, VerifiedBlock>(&header.number.to_be_bytes())
+                .await?;
+            let mismatch = verified.and_then(|verified| {
+                check(
+                    header.number,
+                    header.number,
+                    MismatchField::BlockHash,
+                    header.hash,
+                    verified.hash,
+                )
+            });
+            if let Some(mismatch) = &mismatch {
+                self.alert(mismatch).await?;
+            }
+            return Ok(mismatch);
+        }
+        if header.number != progress.next_number {
+            return Err(anyhow!(
+                "expected block {}, got {}",
+                progress.next_number,
+                header.number
+            ));
+        }
+
+        let block_check = |field, published, computed| {
+            check(header.number, header.number, field, published, computed)
+        };
+        let transaction_root =
+            cbmt_merkle_root(&block.txs.iter().map(|tx| tx.hash).collect::<Vec<_>>());
+        let mut mismatch = block_check(
+            MismatchField::ParentHash,
+            header.parent_hash,
+            progress.parent_hash,
+        )
+        .or_else(|| block_check(MismatchField::BlockHash, header.hash, header.calc_hash()))
+        .or_else(|| {
+            block_check(
+                MismatchField::TransactionRoot,
+                header.transaction_root,
+                transaction_root,
+            )
+        });
+
+        let mut summary = None;
+        if mismatch.is_none() {
+            let txs = block.txs.clone();
+            let (number, chain_id, dust) = (header.number, self.chain_id, self.dust.clone());
+            let exec_summary = self
+                .state
+                .run(move |store| {
+                    ChannelExecutor::new(store.clone(), chain_id)
+                        .with_block_number(number)
+                        .with_dust_limits(dust)
+                        .exec_streaming(&txs, &mut |_, _| Ok(()))
+                })
+                .await??;
+            mismatch = block_check(
+                MismatchField::StateRoot,
+                header.state_root,
+                exec_summary.state_root,
+            )
+            .or_else(|| {
+                block_check(
+                    MismatchField::ReceiptRoot,
+                    header.receipt_root,
+                    exec_summary.receipt_root,
+                )
+            });
+            summary = Some(exec_summary);
+        }
+
+        if let Some(mismatch) = mismatch {
+            progress.diverged = Some(mismatch.clone());
+            self.meta.insert(PROGRESS_KEY, &progress).await?;
+            self.alert(&mismatch).await?;
+            return Ok(Some(mismatch));
+        }
+
+        let summary = summary.expect("executed without a mismatch");
+        let leaves = { summary.updated_channels.into_iter() }
+            .map(|(key, channel)| (key.to_h256(), channel))
+            .collect();
+        self.state
+            .run(move |store| -> Result<()> {
+                SMT::new_with_store(store.clone())?.update_all(leaves)?;
+                Ok(())
+            })
+            .await??;
+
+        let verified = VerifiedBlock {
+            hash: header.hash,
+            state_root: summary.state_root,
+            receipt_root: summary.receipt_root,
+        };
+        self.blocks
+            .insert(header.number.to_be_bytes(), verified)
+            .await?;
+        progress.next_number = header.number + 1;
+        progress.parent_hash = header.hash;
+        self.meta.insert(PROGRESS_KEY, &progress).await?;
+
+        Ok(None)
+    }
+
+    /// Verify the blocks of a published archive, as written by
+    /// `export_blocks`. Stops at the first block the verifier diverges on.
+    pub async fn verify_archive<R: Read>(&self, archive: R) -> Result<Vec<Mismatch>> {
+        let reader = ArchiveReader::open(archive, ArchiveKind::Layer3)?;
+
+        let mut mismatches = Vec::new();
+        for record in reader {
+            let block: Block = decode_bincode(&record?, &BLOCK_LIMIT)?;
+            if let Some(mismatch) = self.verify_block(&block).await? {
+                mismatches.push(mismatch);
+                if self.progress().await?.diverged.is_some() {
+                    break;
+                }
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Verify the blocks of `chain` up to its tip, the same way.
+    pub async fn follow_chain<C: Chain>(&self, chain: &C) -> Result<Vec<Mismatch>> {
+        let tip = match chain.tip_header().await? {
+            Some(tip) => tip.number,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut mismatches = Vec::new();
+        for number in self.progress().await?.next_number..=tip {
+            let block = chain
+                .get_block(NumberHash::Number(number))
+                .await?
+                .ok_or_else(|| anyhow!("block {} not found", number))?;
+            if let Some(mismatch) = self.verify_block(&block).await? {
+                mismatches.push(mismatch);
+                break;
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Verify the blocks the `SnapshotSource` behind `client` serves up to
+    /// its tip, the same way.
+    pub async fn follow_source(&self, client: &SnapshotClient) -> Result<Vec<Mismatch>> {
+        loop {
+            let next_number = self.progress().await?.next_number;
+            let blocks = client.blocks_from(next_number, FETCH_BLOCKS).await?;
+            if blocks.is_empty() {
+                return Ok(Vec::new());
+            }
+            for block in blocks {
+                if let Some(mismatch) = self.verify_block(&block).await? {
+                    return Ok(vec![mismatch]);
+                }
+            }
+        }
+    }
+
+    /// Follow `client` every `poll` until a block mismatches. A source that
+    /// can't be reached is tried again on the next poll.
+    pub async fn watch(&self, client: &SnapshotClient, poll: Duration) -> Result<Mismatch> {
+        let mut timer = interval(poll);
+        loop {
+            timer.tick().await;
+            match self.follow_source(client).await {
+                Ok(mut mismatches) if !mismatches.is_empty() => return Ok(mismatches.remove(0)),
+                Ok(_) => (),
+                Err(e) if self.progress().await?.diverged.is_some() => return Err(e),
+                Err(e) => println!("[verifier] following the snapshot server failed: {:#}", e),
+            }
+        }
+    }
+
+    /// Check a settlement commitment against the verified roots of its last
+    /// block. Its blocks must be verified first, and their roots are
+    /// dropped once it's checked.
+    pub async fn verify_commitment(&self, commitment: &Commitment) -> Result<Option<Mismatch>> {
+        let (from, to) = (commitment.from_block, commitment.to_block);
+        if to >= self.progress().await?.next_number {
+            return Err(anyhow!("blocks up to {} aren't verified yet", to));
+        }
+        let verified = self
+            .blocks
+            .get::<_, VerifiedBlock>(&to.to_be_bytes())
+            .await?
+            .ok_or_else(|| anyhow!("roots of block {} are no longer kept", to))?;
+
+        let mismatch = check(
+            from,
+            to,
+            MismatchField::StateRoot,
+            commitment.state_root,
+            verified.state_root,
+        )
+        .or_else(|| {
+            check(
+                from,
+                to,
+                MismatchField::ReceiptRoot,
+                commitment.receipt_root,
+                verified.receipt_root,
+            )
+        });
+        if let Some(mismatch) = &mismatch {
+            self.alert(mismatch).await?;
+        }
+
+        self.blocks
+            .run(move |blocks| -> Result<(), StoreError> {
+                for number in from..=to {
+                    blocks.remove(number.to_be_bytes())?;
+                }
+                Ok(())
+            })
+            .await??;
+
+        Ok(mismatch)
+    }
+
+    async fn alert(&self, mismatch: &Mismatch) -> Result<()> {
+        println!(
+            "[verifier] blocks {}..={} {:?} mismatch, published {:?}, computed {:?}",
+            mismatch.from_block,
+            mismatch.to_block,
+            mismatch.field,
+            mismatch.published,
+            mismatch.computed
+        );
+        if let Some(notifier) = &self.notifier {
+            notifier.on_root_mismatch(mismatch).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn check(
+    from_block: u64,
+    to_block: u64,
+    field: MismatchField,
+    published: H256,
+    computed: H256,
+) -> Option<Mismatch> {
+    (published != computed).then_some(Mismatch {
+        from_block,
+        to_block,
+        field,
+        published,
+        computed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Arc};
+
+    use primitive_types::{H160, U128};
+    use proof::commitment::ProverType;
+    use share::archive::ArchiveWriter;
+    use tempfile::tempdir;
+
+    use crate::{
+        archive::export_blocks,
+        auxiliaries::{
+            chain::ChannelChain,
+            mempool::{ChannelMap, MemPool},
+            snapshot::{SnapshotPolicy, SnapshotSource},
+        },
+        consensus::{ChannelConsensus, Consensus},
+        types::{Balance, CreateChannel, RawTransaction, SignedTransaction},
+    };
+
+    use super::*;
+
+    const CHAIN_ID: u64 = 1;
+
+    fn create_channel_tx(id: u64) -> SignedTransaction {
+        let raw = RawTransaction::CreateChannel(CreateChannel {
+            chain_id: CHAIN_ID,
+            id: id.into(),
+            token: Default::default(),
+            challenge_blocks: 10,
+            participant2: [H160::repeat_byte(1), H160::repeat_byte(2)],
+            balance2: [Balance::default(), Balance::default()],
+            guard: None,
+        });
+
+        SignedTransaction {
+            raw,
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::repeat_byte(1),
+            hash: H256::repeat_byte(id as u8),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_published_blocks() {
+        let node_path = tempdir().unwrap();
+        let store = Store::open(node_path.path()).unwrap();
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let consensus = ChannelConsensus::new(mempool.clone(), store.clone(), CHAIN_ID).unwrap();
+        for id in 1..=3 {
+            mempool.push_transaction(create_channel_tx(id)).unwrap();
+            let receipt = consensus.produce_block().await.unwrap();
+            consensus.apply_consensus_receipt(&receipt).await.unwrap();
+        }
+        let chain = ChannelChain::new(store).unwrap();
+        let mut archive = Vec::new();
+        export_blocks(&chain, 1, 2, &mut archive).await.unwrap();
+
+        let verifier_path = tempdir().unwrap();
+        let verifier = Verifier::open(&Store::open(verifier_path).unwrap(), CHAIN_ID).unwrap();
+        assert!(verifier
+            .verify_archive(archive.as_slice())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(verifier.follow_chain(&chain).await.unwrap().is_empty());
+        assert_eq!(verifier.progress().await.unwrap().next_number, 4);
+
+        let tip = chain.tip_header().await.unwrap().unwrap();
+        let mut commitment = Commitment {
+            from_block: 1,
+            to_block: 3,
+            state_root: tip.state_root,
+            receipt_root: tip.receipt_root,
+            da_reference: H256::zero(),
+            prover: ProverType::Optimistic,
+        };
+        assert_eq!(verifier.verify_commitment(&commitment).await.unwrap(), None);
+        // Covered roots are dropped
+        assert!(verifier.verify_commitment(&commitment).await.is_err());
+
+        // A commitment past the verified blocks can't be checked
+        commitment.from_block = 4;
+        commitment.to_block = 4;
+        assert!(verifier.verify_commitment(&commitment).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_detect_forged_state_root() {
+        let node_path = tempdir().unwrap();
+        let store = Store::open(node_path.path()).unwrap();
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let consensus = ChannelConsensus::new(mempool.clone(), store.clone(), CHAIN_ID).unwrap();
+        mempool.push_transaction(create_channel_tx(1)).unwrap();
+        let receipt = consensus.produce_block().await.unwrap();
+
+        // The operator publishes a block whose state drops the new channel
+        let mut block = Block {
+            header: receipt.block.header.clone(),
+            txs: receipt.block.txs.clone(),
+        };
+        block.header.state_root = H256::zero();
+        block.header.hash = block.header.calc_hash();
+        let mut archive = Vec::new();
+        let mut writer = ArchiveWriter::new(&mut archive, ArchiveKind::Layer3).unwrap();
+        writer.append(&bincode::serialize(&block).unwrap()).unwrap();
+        writer.finish().unwrap();
+
+        let verifier_path = tempdir().unwrap();
+        let verifier = Verifier::open(&Store::open(verifier_path).unwrap(), CHAIN_ID).unwrap();
+        let mismatches = verifier.verify_archive(archive.as_slice()).await.unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].field, MismatchField::StateRoot);
+        assert_eq!(mismatches[0].computed, receipt.block.header.state_root);
+
+        let progress = verifier.progress().await.unwrap();
+        assert_eq!(progress.diverged, Some(mismatches[0].clone()));
+        assert!(verifier.verify_block(&receipt.block).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watch_snapshot_server() {
+        let node_path = tempdir().unwrap();
+        let store = Store::open(node_path.path()).unwrap();
+        let mempool = ChannelMap::new(CHAIN_ID);
+        let consensus = ChannelConsensus::new(mempool.clone(), store, CHAIN_ID).unwrap();
+        let mut blocks = Vec::new();
+        for id in 1..=2 {
+            mempool.push_transaction(create_channel_tx(id)).unwrap();
+            let receipt = consensus.produce_block().await.unwrap();
+            consensus.apply_consensus_receipt(&receipt).await.unwrap();
+            blocks.push(Block {
+                header: receipt.block.header.clone(),
+                txs: receipt.block.txs.clone(),
+            });
+        }
+
+        // The operator serves block 1 as produced and block 2 with a forged
+        // state root
+        let published_path = tempdir().unwrap();
+        let published = Store::open(published_path.path()).unwrap();
+        let chain = ChannelChain::new(published.clone()).unwrap();
+        blocks[1].header.state_root = H256::zero();
+        blocks[1].header.hash = blocks[1].header.calc_hash();
+        for block in blocks {
+            chain.save_block(Arc::new(block)).await.unwrap();
+        }
+        let source = SnapshotSource::new(published, SnapshotPolicy::default()).unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = SnapshotClient::new(format!("http://{}", addr));
+
+        let verifier_path = tempdir().unwrap();
+        let verifier = Verifier::open(&Store::open(verifier_path).unwrap(), CHAIN_ID).unwrap();
+        // Not serving yet is retried
+        let watching = verifier.watch(&client, Duration::from_millis(10));
+        tokio::spawn(source.serve(addr));
+        let mismatch = watching.await.unwrap();
+        assert_eq!(mismatch.from_block, 2);
+        assert_eq!(mismatch.field, MismatchField::StateRoot);
+        assert_eq!(verifier.progress().await.unwrap().next_number, 2);
+        assert!(verifier.follow_source(&client).await.is_err());
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use primitive_types::{H160, U256};
+use rand::RngCore;
+use secp256k1::SecretKey;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use share::message::personal_message_hash;
+
+use crate::{
+    auxiliaries::{
+        chain::Chain,
+        common::{recover_address, sign_recoverable},
+        store::{Store, StoreError},
+    },
+    types::{Channel, NumberHash, RawTransaction, Signature, UpdateChannel},
+};
+
+// Channel id to the sealed latest state, ids are kept in the clear
+const STATE_TREE: &str = "wallet_state";
+const META_TREE: &str = "wallet_meta";
+const KDF_KEY: &str = "kdf";
+const KDF_ROUNDS: u32 = 100_000;
+const BACKUP_VERSION: u8 = 1;
+// Sealed under the derived key to tell a wrong passphrase apart
+const CHECK_PLAINTEXT: &[u8] = b"covalent wallet";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum WalletError {
+    #[error("wrong passphrase")]
+    Passphrase,
+    #[error("state of channel {0} was tampered with")]
+    Tampered(U256),
+    #[error("unsupported backup version {0}")]
+    BackupVersion(u8),
+    #[error("{0}")]
+    Store(#[from] StoreError),
+    #[error("{0}")]
+    Bincode(#[from] bincode::Error),
+}
+
+/// Latest state of a channel both participants signed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateRecord {
+    pub participant2: [H160; 2],
+    pub update: UpdateChannel,
+}
+
+impl StateRecord {
+    fn verify(&self) -> Result<()> {
+        let msg = self.update.sig_msg();
+        for (participant, sig) in self.participant2.iter().zip(self.update.signature2.iter()) {
+            if recover_address(msg, sig) != Some(*participant) {
+                return Err(anyhow!(
+                    "state of channel {} isn't signed by {:?}",
+                    self.update.channel_id,
+                    participant
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Sealed {
+    nonce: [u8; 16],
+    ciphertext: Vec<u8>,
+    tag: [u8; 32],
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct KdfParams {
+    salt: [u8; 16],
+    rounds: u32,
+    check: Sealed,
+}
+
+/// Export of every stored state, still sealed under the passphrase of the
+/// store it came from.
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    version: u8,
+    kdf: KdfParams,
+    states: Vec<(U256, Sealed)>,
+}
+
+// Encryption and authentication keys derived from the passphrase
+#[derive(Clone)]
+struct Keys {
+    enc: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl Keys {
+    fn derive(passphrase: &str, salt: &[u8], rounds: u32) -> Self {
+        let master = pbkdf2_sha256(passphrase.as_bytes(), salt, rounds);
+        Keys {
+            enc: hmac_sha256(&master, &[b"enc"]),
+            mac: hmac_sha256(&master, &[b"mac"]),
+        }
+    }
+
+    // HMAC-SHA256 in counter mode as the keystream, then HMAC over nonce and
+    // ciphertext
+    fn seal(&self, plaintext: &[u8]) -> Sealed {
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self.apply_keystream(&nonce, plaintext);
+        let tag = hmac_sha256(&self.mac, &[&nonce, &ciphertext]);
+
+        Sealed {
+            nonce,
+            ciphertext,
+            tag,
+        }
+    }
+
+    fn open(&self, sealed: &Sealed) -> Option<Vec<u8>> {
+        let mut mac = HmacSha256::new_from_slice(&self.mac).expect("hmac accepts any key length");
+        mac.update(&sealed.nonce);
+        mac.update(&sealed.ciphertext);
+        mac.verify_slice(&sealed.tag).ok()?;
+
+        Some(self.apply_keystream(&sealed.nonce, &sealed.ciphertext))
+    }
+
+    fn apply_keystream(&self, nonce: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for (counter, chunk) in data.chunks(32).enumerate() {
+            let block = hmac_sha256(&self.enc, &[nonce, &(counter as u64).to_be_bytes()]);
+            out.extend(chunk.iter().zip(block.iter()).map(|(b, k)| b ^ k));
+        }
+        out
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+// PBKDF2 with HMAC-SHA256, a single output block
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut u = hmac_sha256(password, &[salt, &1u32.to_be_bytes()]);
+    let mut key = u;
+    for _ in 1..rounds {
+        u = hmac_sha256(password, &[&u]);
+        key.iter_mut().zip(u.iter()).for_each(|(k, u)| *k ^= u);
+    }
+    key
+}
+
+/// Participant side store of the latest counter-signed state of every
+/// channel, encrypted under a passphrase. It's what a participant needs to
+/// answer a stale close, so it can be exported for backup and rebuilt from
+/// the node's history after losing the device.
+pub struct StateStore {
+    states: Store,
+    meta: Store,
+    keys: Keys,
+}
+
+impl StateStore {
+    /// Open the store, setting `passphrase` on first use.
+    pub fn open(store: &Store, passphrase: &str) -> Result<Self, WalletError> {
+        let meta = store.open_tree(META_TREE)?;
+        let keys = match meta.get::<_, KdfParams>(&KDF_KEY)? {
+            Some(kdf) => unlock(&kdf, passphrase)?,
+            None => {
+                let mut salt = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut salt);
+                let keys = Keys::derive(passphrase, &salt, KDF_ROUNDS);
+                let kdf = KdfParams {
+                    salt,
+                    rounds: KDF_ROUNDS,
+                    check: keys.seal(CHECK_PLAINTEXT),
+                };
+                meta.insert(KDF_KEY, kdf)?;
+                keys
+            }
+        };
+
+        Ok(StateStore {
+            states: store.open_tree(STATE_TREE)?,
+            meta,
+            keys,
+        })
+    }
+
+    /// Keep `record` unless an equal or newer state of the channel is kept.
+    /// Returns whether it was kept.
+    pub fn save(&self, record: StateRecord) -> Result<bool> {
+        record.verify()?;
+
+        let channel_id = record.update.channel_id;
+        if let Some(stored) = self.get(channel_id)? {
+            if stored.update.version >= record.update.version {
+                return Ok(false);
+            }
+        }
+        let sealed = self.keys.seal(&bincode::serialize(&record)?);
+        self.states.insert(channel_id, sealed)?;
+        Ok(true)
+    }
+
+    pub fn get(&self, channel_id: U256) -> Result<Option<StateRecord>, WalletError> {
+        match self.states.get::<_, Sealed>(&channel_id)? {
+            Some(sealed) => Ok(Some(open_record(&self.keys, channel_id, &sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn states(&self) -> Result<Vec<StateRecord>, WalletError> {
+        let mut records = Vec::new();
+        for sealed in self.states.values::<Sealed>()? {
+            // Ids are only needed for the error, the record holds its own
+            records.push(open_record(&self.keys, U256::zero(), &sealed)?);
+        }
+        Ok(records)
+    }
+
+    /// Every stored state as an encrypted backup, restored with `import`
+    /// and the same passphrase.
+    pub fn export(&self) -> Result<Vec<u8>, WalletError> {
+        let kdf = { self.meta.get::<_, KdfParams>(&KDF_KEY)? }.expect("set when opened");
+        let mut states = Vec::new();
+        for record in self.states()? {
+            let channel_id = record.update.channel_id;
+            states.push((channel_id, self.keys.seal(&bincode::serialize(&record)?)));
+        }
+
+        let backup = Backup {
+            version: BACKUP_VERSION,
+            kdf,
+            states,
+        };
+        Ok(bincode::serialize(&backup)?)
+    }
+
+    /// Merge a backup made by `export`, keeping the newer state of every
+    /// channel. Returns how many states were taken from the backup.
+    pub fn import(&self, backup: &[u8], passphrase: &str) -> Result<usize> {
+        let backup: Backup = bincode::deserialize(backup)?;
+        if backup.version != BACKUP_VERSION {
+            return Err(WalletError::BackupVersion(backup.version).into());
+        }
+
+        let keys = unlock(&backup.kdf, passphrase)?;
+        let mut imported = 0;
+        for (channel_id, sealed) in backup.states.iter() {
+            if self.save(open_record(&keys, *channel_id, sealed)?)? {
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Store the states found by `recover_positions`. Returns how many
+    /// were newer than the stored ones.
+    pub fn restore(&self, positions: Vec<RecoveredPosition>) -> Result<usize> {
+        let mut restored = 0;
+        for position in positions {
+            let update = match position.latest {
+                Some(update) => update,
+                None => continue,
+            };
+            let record = StateRecord {
+                participant2: position.channel.participant2,
+                update,
+            };
+            if self.save(record)? {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+}
+
+fn unlock(kdf: &KdfParams, passphrase: &str) -> Result<Keys, WalletError> {
+    let keys = Keys::derive(passphrase, &kdf.salt, kdf.rounds);
+    match keys.open(&kdf.check) {
+        Some(check) if check == CHECK_PLAINTEXT => Ok(keys),
+        _ => Err(WalletError::Passphrase),
+    }
+}
+
+fn open_record(keys: &Keys, channel_id: U256, sealed: &Sealed) -> Result<StateRecord, WalletError> {
+    let plaintext = keys.open(sealed).ok_or(WalletError::Tampered(channel_id))?;
+    Ok(bincode::deserialize(&plaintext)?)
+}
+
+/// A participant's channel as the node has it now, with the newest state
+/// both participants signed that the history holds.
+/// A personal message with a detached signature of its EIP-191 hash, for
+/// proving control of an address off chain.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SignedMessage {
+    pub address: H160,
+    pub message: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl SignedMessage {
+    pub fn sign(key: &SecretKey, address: H160, message: &[u8]) -> Self {
+        SignedMessage {
+            address,
+            message: message.to_vec(),
+            signature: sign_message(key, message),
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        verify_message(self.address, &self.message, &self.signature)
+    }
+}
+
+/// Recoverable signature over the EIP-191 hash of `message`, what wallets
+/// produce for `personal_sign`.
+pub fn sign_message(key: &SecretKey, message: &[u8]) -> Signature {
+    sign_recoverable(key, personal_message_hash(message))
+}
+
+/// Whether `signature` is `address`'s signature of `message`.
+pub fn verify_message(address: H160, message: &[u8], signature: &[u8]) -> bool {
+    recover_address(personal_message_hash(message), signature) == Some(address)
+}
+
+/// Indexes of the messages whose signature doesn't verify, empty when
+/// every one does.
+pub fn verify_messages(messages: &[SignedMessage]) -> Vec<usize> {
+    { messages.iter().enumerate() }
+        .filter(|(_, message)| !message.verify())
+        .map(|(index, _)| index)
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveredPosition {
+    pub channel: Channel,
+    // None if the channel was never updated, or only in pruned blocks
+    pub latest: Option<UpdateChannel>,
+}
+
+/// Rebuild `address`'s channel positions from the node's block history.
+/// Updates are counter-signed by both participants, so the newest one in a
+/// block is as good to challenge with as a locally kept copy.
+pub async fn recover_positions<C: Chain>(
+    chain: &C,
+    address: H160,
+) -> Result<Vec<RecoveredPosition>> {
+    let tip = match chain.tip_header().await? {
+        Some(tip) => tip.number,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut participants = BTreeMap::<U256, [H160; 2]>::new();
+    let mut latest = BTreeMap::<U256, UpdateChannel>::new();
+    for number in 0..=tip {
+        let block = match chain.get_block(NumberHash::Number(number)).await? {
+            Some(block) => block,
+            None => continue,
+        };
+        for tx in block.txs.iter() {
+            match &tx.raw {
+                RawTransaction::CreateChannel(args) if args.participant2.contains(&address) => {
+                    participants.insert(args.id, args.participant2);
+                }
+                RawTransaction::UpdateChannel(args) => {
+                    let participant2 = match participants.get(&args.channel_id) {
+                        Some(participant2) => *participant2,
+                        None => continue,
+                    };
+                    if latest
+                        .get(&args.channel_id)
+                        .map(|l| l.version >= args.version)
+                        .unwrap_or(false)
+                    {
+                        continue;
+                    }
+                    // Failed updates are in blocks too
+                    let record = StateRecord {
+                        participant2,
+                        update: args.clone(),
+                    };
+                    if record.verify().is_ok() {
+                        latest.insert(args.channel_id, record.update);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let mut positions = Vec::new();
+    for channel_id in participants.into_keys() {
+        positions.push(RecoveredPosition {
+            channel: chain.get_channel(channel_id).await?,
+            latest: latest.remove(&channel_id),
+        });
+    }
+    Ok(positions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use primitive_types::{H256, U128};
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use tempfile::tempdir;
+
+    use crate::{
+        auxiliaries::{
+            chain::ChannelChain,
+            common::{cbmt_merkle_root, secp256k1_address, sign_recoverable, H256Ext},
+            smt::SMT,
+        },
+        types::{
+            Balance, Block, BlockHeader, ChannelState, CreateChannel, SignedTransaction, Token,
+        },
+    };
+
+    use super::*;
+
+    fn keys() -> ([SecretKey; 2], [H160; 2]) {
+        let keys = [
+            SecretKey::from_slice(&[1u8; 32]).unwrap(),
+            SecretKey::from_slice(&[2u8; 32]).unwrap(),
+        ];
+        let secp = Secp256k1::new();
+        let addresses = keys.map(|key| secp256k1_address(&PublicKey::from_secret_key(&secp, &key)));
+        (keys, addresses)
+    }
+
+    fn update(keys: &[SecretKey; 2], version: u64) -> UpdateChannel {
+        let mut update = UpdateChannel {
+            chain_id: 1,
+            channel_id: 7.into(),
+            version,
+            balance2: [10, 90].map(|settled| Balance {
+                settled: settled.into(),
+            }),
+            ..Default::default()
+        };
+        for (idx, key) in keys.iter().enumerate() {
+            update.signature2[idx] = sign_recoverable(key, update.sig_msg());
+        }
+        update
+    }
+
+    fn tx(number: u64, raw: RawTransaction) -> SignedTransaction {
+        SignedTransaction {
+            raw,
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::zero(),
+            hash: H256::from_low_u64_be(number),
+        }
+    }
+
+    #[test]
+    fn test_encrypted_store_export_import() {
+        let (keys, participant2) = keys();
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path.path()).unwrap();
+        let states = StateStore::open(&store, "correct horse").unwrap();
+
+        let record = |version| StateRecord {
+            participant2,
+            update: update(&keys, version),
+        };
+        assert!(states.save(record(2)).unwrap());
+        assert!(!states.save(record(1)).unwrap());
+        let mut forged = record(3);
+        forged.update.signature2[1] = forged.update.signature2[0].clone();
+        assert!(states.save(forged).is_err());
+        assert_eq!(states.get(7.into()).unwrap().unwrap().update.version, 2);
+
+        // Nothing readable on disk
+        let raw = store.open_tree(STATE_TREE).unwrap();
+        let sealed = raw.get::<_, Sealed>(&U256::from(7)).unwrap().unwrap();
+        assert!(bincode::deserialize::<StateRecord>(&sealed.ciphertext).is_err());
+        assert!(matches!(
+            StateStore::open(&store, "wrong"),
+            Err(WalletError::Passphrase)
+        ));
+
+        let backup = states.export().unwrap();
+        let other_db_path = tempdir().unwrap();
+        let other = StateStore::open(&Store::open(other_db_path).unwrap(), "other").unwrap();
+        assert!(other.import(&backup, "wrong").is_err());
+        assert_eq!(other.import(&backup, "correct horse").unwrap(), 1);
+        assert_eq!(other.states().unwrap()[0].update.version, 2);
+    }
+
+    #[test]
+    fn test_sign_and_verify_messages() {
+        let (keys, addresses) = keys();
+        let signed = SignedMessage::sign(&keys[0], addresses[0], b"login 42");
+        assert!(signed.verify());
+        assert!(!verify_message(addresses[1], b"login 42", &signed.signature));
+        assert!(!verify_message(addresses[0], b"login 43", &signed.signature));
+        assert!(!verify_message(addresses[0], b"login 42", &[0u8; 65]));
+
+        let forged = SignedMessage {
+            address: addresses[1],
+            ..signed.clone()
+        };
+        let other = SignedMessage::sign(&keys[1], addresses[1], b"");
+        assert_eq!(verify_messages(&[signed, forged, other]), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_recover_positions() {
+        let (keys, participant2) = keys();
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path.path()).unwrap();
+        let chain = ChannelChain::new(store.clone()).unwrap();
+
+        let create = CreateChannel {
+            chain_id: 1,
+            id: 7.into(),
+            token: Token::default(),
+            challenge_blocks: 10,
+            participant2,
+            balance2: Default::default(),
+            guard: None,
+        };
+        let channel = Channel {
+            id: create.id,
+            participant2,
+            state: ChannelState::Open,
+            version: 2,
+            ..Default::default()
+        };
+        let mut smt = SMT::new_with_store(store).unwrap();
+        smt.update(channel.id.to_h256(), channel).unwrap();
+        let create = RawTransaction::CreateChannel(create);
+        let mut unsigned = update(&keys, 3);
+        unsigned.signature2[0].clear();
+        let txs = [
+            vec![tx(1, create)],
+            vec![
+                tx(2, RawTransaction::UpdateChannel(update(&keys, 2))),
+                tx(3, RawTransaction::UpdateChannel(unsigned)),
+            ],
+        ];
+        for (number, txs) in txs.into_iter().enumerate() {
+            let block = Block {
+                header: BlockHeader {
+                    number: number as u64,
+                    hash: H256::repeat_byte(number as u8 + 1),
+                    transaction_root: cbmt_merkle_root(
+                        &txs.iter().map(|tx| tx.hash).collect::<Vec<_>>(),
+                    ),
+                    ..Default::default()
+                },
+                txs,
+            };
+            chain.save_block(Arc::new(block)).await.unwrap();
+        }
+
+        let positions = recover_positions(&chain, participant2[0]).await.unwrap();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].channel.version, 2);
+        assert_eq!(positions[0].latest.as_ref().unwrap().version, 2);
+        assert!(recover_positions(&chain, H160::repeat_byte(9))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let states_db_path = tempdir().unwrap();
+        let states = StateStore::open(&Store::open(states_db_path).unwrap(), "pass").unwrap();
+        assert_eq!(states.restore(positions).unwrap(), 1);
+        assert_eq!(states.get(7.into()).unwrap().unwrap().update.version, 2);
+    }
+}
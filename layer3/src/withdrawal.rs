@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use primitive_types::{H160, H256, U256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auxiliaries::{
+        common::{blake2b, H256Ext},
+        store::{AsyncStore, Store, StoreError},
+    },
+    consensus::ConsensusReceipt,
+    types::RawTransaction,
+};
+
+const BATCH_TREE: &str = "withdrawal_batch";
+const BATCH_INDEX_TREE: &str = "withdrawal_batch_index";
+
+/// How the relayer groups withdrawals into layer2 transfers.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct BatchPolicy {
+    // Off submits one transfer per withdrawal
+    pub netting: bool,
+    // Transfers per layer2 transaction
+    pub max_transfers: usize,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        BatchPolicy {
+            netting: true,
+            max_transfers: 64,
+        }
+    }
+}
+
+/// Funds a closed channel pays out to one participant.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Withdrawal {
+    pub request_id: H256,
+    pub channel_id: U256,
+    pub recipient: H160,
+    pub token_id: U256,
+    pub amount: U256,
+}
+
+/// One layer2 transfer paying the sum of `request_ids` to `recipient`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BatchedTransfer {
+    pub recipient: H160,
+    pub token_id: U256,
+    pub amount: U256,
+    pub request_ids: Vec<H256>,
+}
+
+/// Transfers submitted together in one layer2 transaction.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct WithdrawalBatch {
+    pub id: H256,
+    pub block_number: u64,
+    pub transfers: Vec<BatchedTransfer>,
+}
+
+/// Id of the withdrawal paying participant `index` of a channel closed at
+/// `version`.
+pub fn withdrawal_request_id(channel_id: U256, version: u64, index: usize) -> H256 {
+    let encoded = bincode::serialize(&(channel_id, version, index as u8)).unwrap();
+    blake2b(&encoded)
+}
+
+/// Withdrawals of the channels closed in a block, in transaction order.
+pub fn closed_channel_withdrawals(receipt: &ConsensusReceipt) -> Vec<Withdrawal> {
+    let mut withdrawals = Vec::new();
+    for tx in receipt.block.txs.iter() {
+        let args = match &tx.raw {
+            RawTransaction::CloseChannel(args) => args,
+            _ => continue,
+        };
+        // Failed closes leave the channel at an older version
+        let channel = match receipt.updated_channels.get(&args.channel_id.to_h256()) {
+            Some(channel) if channel.version == args.version => channel,
+            _ => continue,
+        };
+
+        for (index, (recipient, balance)) in { channel.participant2.iter() }
+            .zip(channel.balance2.iter())
+            .enumerate()
+        {
+            if balance.settled.is_zero() {
+                continue;
+            }
+            withdrawals.push(Withdrawal {
+                request_id: withdrawal_request_id(channel.id, channel.version, index),
+                channel_id: channel.id,
+                recipient: *recipient,
+                token_id: channel.token.id,
+                amount: balance.settled.as_u128().into(),
+            });
+        }
+    }
+
+    withdrawals
+}
+
+/// Groups withdrawals into batches of at most `max_transfers` transfers.
+/// With netting, withdrawals to the same recipient and token become one
+/// transfer.
+pub fn batch_withdrawals(
+    block_number: u64,
+    withdrawals: &[Withdrawal],
+    policy: &BatchPolicy,
+) -> Vec<WithdrawalBatch> {
+    let mut transfers: Vec<BatchedTransfer> = Vec::new();
+    let mut netted: BTreeMap<(H160, U256), usize> = BTreeMap::new();
+    for withdrawal in withdrawals {
+        let key = (withdrawal.recipient, withdrawal.token_id);
+        if policy.netting {
+            if let Some(transfer) = netted.get(&key).map(|i| &mut transfers[*i]) {
+                if let Some(amount) = transfer.amount.checked_add(withdrawal.amount) {
+                    transfer.amount = amount;
+                    transfer.request_ids.push(withdrawal.request_id);
+                    continue;
+                }
+            }
+            netted.insert(key, transfers.len());
+        }
+
+        transfers.push(BatchedTransfer {
+            recipient: withdrawal.recipient,
+            token_id: withdrawal.token_id,
+            amount: withdrawal.amount,
+            request_ids: vec![withdrawal.request_id],
+        });
+    }
+
+    { transfers.chunks(policy.max_transfers.max(1)) }
+        .map(|transfers| {
+            let encoded = bincode::serialize(&(block_number, transfers)).unwrap();
+            WithdrawalBatch {
+                id: blake2b(&encoded),
+                block_number,
+                transfers: transfers.to_vec(),
+            }
+        })
+        .collect()
+}
+
+/// Batches the withdrawals of every applied block and keeps the mapping
+/// from each withdrawal to the batch paying it for audits.
+#[derive(Clone)]
+pub struct WithdrawalBatcher {
+    batches: AsyncStore,
+    index: AsyncStore,
+    policy: BatchPolicy,
+}
+
+impl WithdrawalBatcher {
+    pub fn new(store: &Store, policy: BatchPolicy) -> Result<Self, StoreError> {
+        let batcher = WithdrawalBatcher {
+            batches: AsyncStore::new(store.open_tree(BATCH_TREE)?),
+            index: AsyncStore::new(store.open_tree(BATCH_INDEX_TREE)?),
+            policy,
+        };
+
+        Ok(batcher)
+    }
+
+    /// Batches for the relayer to submit, one layer2 transaction each.
+    pub async fn on_consensus_receipt(
+        &self,
+        receipt: &ConsensusReceipt,
+    ) -> Result<Vec<WithdrawalBatch>> {
+        let withdrawals = closed_channel_withdrawals(receipt);
+        let batches = batch_withdrawals(receipt.block.header.number, &withdrawals, &self.policy);
+
+        for batch in batches.iter() {
+            self.batches.insert(batch.id, batch).await?;
+            for transfer in batch.transfers.iter() {
+                for request_id in transfer.request_ids.iter() {
+                    self.index.insert(request_id, batch.id).await?;
+                }
+            }
+        }
+
+        Ok(batches)
+    }
+
+    // Audits read batches from the store, the API serves withdrawal stages only
+    #[allow(dead_code)]
+    pub async fn get_batch(&self, id: H256) -> Result<Option<WithdrawalBatch>> {
+        Ok(self.batches.get(&id).await?)
+    }
+
+    /// Batch paying the withdrawal `request_id`.
+    #[allow(dead_code)]
+    pub async fn batch_of(&self, request_id: H256) -> Result<Option<WithdrawalBatch>> {
+        match self.index.get::<_, H256>(&request_id).await? {
+            Some(id) => self.get_batch(id).await,
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use primitive_types::U128;
+    use tempfile::tempdir;
+
+    use crate::types::{
+        Balance, Block, BlockHeader, Channel, ChannelState, CloseChannel, SignedTransaction, Token,
+    };
+
+    use super::*;
+
+    fn closed_channel(id: u64, participant2: [H160; 2], balances: [u64; 2]) -> Channel {
+        Channel {
+            id: id.into(),
+            token: Token {
+                id: 1.into(),
+                ..Default::default()
+            },
+            participant2,
+            state: ChannelState::Open,
+            version: 2,
+            balance2: balances.map(|settled| Balance {
+                settled: settled.into(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn close_tx(channel_id: u64) -> SignedTransaction {
+        SignedTransaction {
+            raw: RawTransaction::CloseChannel(CloseChannel {
+                channel_id: channel_id.into(),
+                version: 2,
+                ..Default::default()
+            }),
+            sig: vec![],
+            fee: U128::zero(),
+            from: H160::zero(),
+            hash: H256::from_low_u64_be(channel_id),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_net_withdrawals() {
+        let (operator, alice, bob) = (
+            H160::repeat_byte(1),
+            H160::repeat_byte(2),
+            H160::repeat_byte(3),
+        );
+        let channels = [
+            closed_channel(1, [operator, alice], [10, 5]),
+            closed_channel(2, [operator, bob], [20, 0]),
+            closed_channel(3, [operator, alice], [30, 7]),
+        ];
+        let receipt = ConsensusReceipt {
+            block: Arc::new(Block {
+                header: BlockHeader {
+                    number: 9,
+                    ..Default::default()
+                },
+                txs: (1..=3).map(close_tx).collect(),
+            }),
+            proposer: H160::zero(),
+            round: 0,
+            commit_signatures: vec![],
+            updated_channels: { channels.into_iter() }
+                .map(|channel| (channel.id.to_h256(), channel))
+                .collect(),
+        };
+
+        let tmp_db_path = tempdir().unwrap();
+        let store = Store::open(tmp_db_path).unwrap();
+        let policy = BatchPolicy {
+            max_transfers: 1,
+            ..Default::default()
+        };
+        let batcher = WithdrawalBatcher::new(&store, policy).unwrap();
+
+        // Five withdrawals net into a transfer to the operator and one to alice
+        let batches = batcher.on_consensus_receipt(&receipt).await.unwrap();
+        assert_eq!(batches.len(), 2);
+        let to_operator = &batches[0].transfers[0];
+        assert_eq!(
+            (to_operator.recipient, to_operator.amount),
+            (operator, 60.into())
+        );
+        assert_eq!(to_operator.request_ids.len(), 3);
+        let to_alice = &batches[1].transfers[0];
+        assert_eq!((to_alice.recipient, to_alice.amount), (alice, 12.into()));
+
+        let request_id = withdrawal_request_id(3.into(), 2, 1);
+        assert_eq!(
+            batcher.batch_of(request_id).await.unwrap().unwrap().id,
+            batches[1].id
+        );
+
+        let unnetted = BatchPolicy {
+            netting: false,
+            max_transfers: 64,
+        };
+        let withdrawals = closed_channel_withdrawals(&receipt);
+        let batches = batch_withdrawals(9, &withdrawals, &unnetted);
+        assert_eq!(batches[0].transfers.len(), 5);
+    }
+}
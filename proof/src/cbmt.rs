@@ -0,0 +1,130 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::vec::Vec;
+
+use blake2b_ref::Blake2bBuilder;
+use merkle_cbt::{merkle_tree::Merge, MerkleProof, CBMT};
+use primitive_types::H256;
+
+use crate::{check_len, read_u32, write_u32, ProofError};
+
+/// Merge of two CBMT nodes, blake2b with the project personalization.
+pub struct MergeH256;
+
+impl Merge for MergeH256 {
+    type Item = H256;
+
+    fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
+        let mut buf = [0u8; 32];
+        let mut blake2b = Blake2bBuilder::new(32).personal(b"zk pika! pi~~~").build();
+
+        blake2b.update(left.0.as_slice());
+        blake2b.update(right.0.as_slice());
+        blake2b.finalize(&mut buf);
+
+        buf.into()
+    }
+}
+
+pub fn merkle_root(leaf_hashes: &[H256]) -> H256 {
+    CBMT::<H256, MergeH256>::build_merkle_root(leaf_hashes)
+}
+
+/// Proof that some leaves, given by their indices, are part of a CBMT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CbmtProof {
+    pub indices: Vec<u32>,
+    pub lemmas: Vec<H256>,
+}
+
+impl CbmtProof {
+    /// `None` if an index is out of range.
+    pub fn build(leaf_hashes: &[H256], indices: &[u32]) -> Option<Self> {
+        let proof = CBMT::<H256, MergeH256>::build_merkle_proof(leaf_hashes, indices)?;
+        Some(CbmtProof {
+            indices: proof.indices().to_vec(),
+            lemmas: proof.lemmas().to_vec(),
+        })
+    }
+
+    /// `leaf_hashes` follow the order of `indices`.
+    pub fn verify(&self, root: &H256, leaf_hashes: &[H256]) -> Result<(), ProofError> {
+        if leaf_hashes.len() != self.indices.len() {
+            return Err(ProofError::Leaves);
+        }
+
+        let proof = MerkleProof::<H256, MergeH256>::new(self.indices.clone(), self.lemmas.clone());
+        if proof.verify(root, leaf_hashes) {
+            Ok(())
+        } else {
+            Err(ProofError::Leaves)
+        }
+    }
+
+    /// Index count, indices, lemma count and lemmas, counts and indices as
+    /// u32 little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.indices.len() * 4 + self.lemmas.len() * 32);
+        write_u32(&mut out, self.indices.len() as u32);
+        for index in self.indices.iter() {
+            write_u32(&mut out, *index);
+        }
+        write_u32(&mut out, self.lemmas.len() as u32);
+        for lemma in self.lemmas.iter() {
+            out.extend_from_slice(lemma.as_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, ProofError> {
+        check_len(bytes)?;
+        let count = read_u32(&mut bytes)? as usize;
+        if bytes.len() < count.saturating_mul(4) {
+            return Err(ProofError::Length);
+        }
+        let indices = (0..count)
+            .map(|_| read_u32(&mut bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let count = read_u32(&mut bytes)? as usize;
+        if bytes.len() != count.saturating_mul(32) {
+            return Err(ProofError::Length);
+        }
+        let lemmas = bytes.chunks(32).map(H256::from_slice).collect();
+
+        Ok(CbmtProof { indices, lemmas })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_PROOF_LEN;
+
+    #[test]
+    fn test_cbmt_proof() {
+        let leaves = (1..=5).map(H256::from_low_u64_be).collect::<Vec<_>>();
+        let root = merkle_root(&leaves);
+
+        let proof = CbmtProof::build(&leaves, &[1, 3]).unwrap();
+        let decoded = CbmtProof::from_bytes(&proof.to_bytes()).unwrap();
+        assert_eq!(decoded, proof);
+        decoded.verify(&root, &[leaves[1], leaves[3]]).unwrap();
+
+        assert_eq!(
+            decoded.verify(&root, &[leaves[1], leaves[2]]),
+            Err(ProofError::Leaves)
+        );
+        let bytes = proof.to_bytes();
+        assert_eq!(
+            CbmtProof::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ProofError::Length)
+        );
+        assert_eq!(
+            CbmtProof::from_bytes(&[0; MAX_PROOF_LEN + 1]),
+            Err(ProofError::TooLarge(MAX_PROOF_LEN + 1))
+        );
+        assert!(CbmtProof::build(&leaves, &[5]).is_none());
+    }
+}
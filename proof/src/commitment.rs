@@ -0,0 +1,139 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::vec::Vec;
+
+use primitive_types::H256;
+
+use crate::{read_bytes, ProofError};
+
+/// Layout written by `Commitment::to_bytes`. Parsers keep accepting every
+/// version they ever did, so withdrawals against old commitments stay
+/// provable after the format moves on.
+pub const COMMITMENT_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProverType {
+    // Operator signed, open to fraud proofs during the challenge window
+    Optimistic = 0,
+    // Backed by a validity proof
+    Validity = 1,
+}
+
+impl TryFrom<u8> for ProverType {
+    type Error = ProofError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ProverType::Optimistic),
+            1 => Ok(ProverType::Validity),
+            _ => Err(ProofError::Field),
+        }
+    }
+}
+
+/// What a settlement transaction commits to for the layer3 blocks
+/// `from_block..=to_block`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commitment {
+    pub from_block: u64,
+    pub to_block: u64,
+    pub state_root: H256,
+    pub receipt_root: H256,
+    // Where the block data of the range is published, zero when it's only
+    // in the settlement transaction itself
+    pub da_reference: H256,
+    pub prover: ProverType,
+}
+
+impl Commitment {
+    /// Version byte, then for version 1 the block range as u64 little
+    /// endian, the three hashes and the prover type byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 16 + 3 * 32 + 1);
+        out.push(COMMITMENT_VERSION);
+        out.extend_from_slice(&self.from_block.to_le_bytes());
+        out.extend_from_slice(&self.to_block.to_le_bytes());
+        out.extend_from_slice(self.state_root.as_bytes());
+        out.extend_from_slice(self.receipt_root.as_bytes());
+        out.extend_from_slice(self.da_reference.as_bytes());
+        out.push(self.prover as u8);
+        out
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, ProofError> {
+        let version = read_bytes(&mut bytes, 1)?[0];
+        let commitment = match version {
+            1 => Self::read_v1(&mut bytes)?,
+            _ => return Err(ProofError::Version(version)),
+        };
+        if !bytes.is_empty() {
+            return Err(ProofError::Length);
+        }
+        if commitment.from_block > commitment.to_block {
+            return Err(ProofError::Field);
+        }
+
+        Ok(commitment)
+    }
+
+    fn read_v1(bytes: &mut &[u8]) -> Result<Self, ProofError> {
+        let read_u64 = |bytes: &mut &[u8]| -> Result<u64, ProofError> {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(read_bytes(bytes, 8)?);
+            Ok(u64::from_le_bytes(buf))
+        };
+        let from_block = read_u64(bytes)?;
+        let to_block = read_u64(bytes)?;
+
+        Ok(Commitment {
+            from_block,
+            to_block,
+            state_root: H256::from_slice(read_bytes(bytes, 32)?),
+            receipt_root: H256::from_slice(read_bytes(bytes, 32)?),
+            da_reference: H256::from_slice(read_bytes(bytes, 32)?),
+            prover: read_bytes(bytes, 1)?[0].try_into()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commitment_encoding() {
+        let commitment = Commitment {
+            from_block: 3,
+            to_block: 4,
+            state_root: H256::repeat_byte(1),
+            receipt_root: H256::repeat_byte(2),
+            da_reference: H256::zero(),
+            prover: ProverType::Optimistic,
+        };
+        let bytes = commitment.to_bytes();
+        assert_eq!(bytes[0], COMMITMENT_VERSION);
+        assert_eq!(Commitment::from_bytes(&bytes), Ok(commitment.clone()));
+
+        assert_eq!(
+            Commitment::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ProofError::Length)
+        );
+        let mut future = bytes.clone();
+        future[0] = COMMITMENT_VERSION + 1;
+        assert_eq!(
+            Commitment::from_bytes(&future),
+            Err(ProofError::Version(COMMITMENT_VERSION + 1))
+        );
+        let mut reversed = Commitment {
+            from_block: 5,
+            ..commitment
+        }
+        .to_bytes();
+        assert_eq!(Commitment::from_bytes(&reversed), Err(ProofError::Field));
+        reversed[1] = 4;
+        *reversed.last_mut().unwrap() = 9;
+        assert_eq!(Commitment::from_bytes(&reversed), Err(ProofError::Field));
+    }
+}
@@ -0,0 +1,82 @@
+//! Merkle proof verification shared by the nodes, contracts, light client
+//! and SDKs. Builds without std when the default `std` feature is off.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+pub mod cbmt;
+pub mod commitment;
+pub mod smt;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::vec::Vec;
+
+use core::fmt;
+
+// Encoded proofs above this size are rejected before they're parsed, no
+// honest proof over a few million leaves comes close
+pub const MAX_PROOF_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProofError {
+    // Encoded proof ends early or has trailing bytes
+    Length,
+    // Leaves don't match the proof
+    Leaves,
+    // Commitment layout this build doesn't know
+    Version(u8),
+    // Commitment field out of its range
+    Field,
+    // Encoded proof longer than `MAX_PROOF_LEN`
+    TooLarge(usize),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::Length => f.write_str("malformed proof encoding"),
+            ProofError::Leaves => f.write_str("leaves don't match the proof"),
+            ProofError::Version(v) => write!(f, "unsupported commitment version {}", v),
+            ProofError::Field => f.write_str("invalid commitment field"),
+            ProofError::TooLarge(len) => {
+                write!(f, "proof of {} bytes is larger than {}", len, MAX_PROOF_LEN)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProofError {}
+
+pub(crate) fn check_len(bytes: &[u8]) -> Result<(), ProofError> {
+    if bytes.len() > MAX_PROOF_LEN {
+        return Err(ProofError::TooLarge(bytes.len()));
+    }
+    Ok(())
+}
+
+pub(crate) fn read_u32(bytes: &mut &[u8]) -> Result<u32, ProofError> {
+    if bytes.len() < 4 {
+        return Err(ProofError::Length);
+    }
+    let (head, tail) = bytes.split_at(4);
+    *bytes = tail;
+    Ok(u32::from_le_bytes([head[0], head[1], head[2], head[3]]))
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn read_bytes<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], ProofError> {
+    if bytes.len() < len {
+        return Err(ProofError::Length);
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head)
+}
@@ -0,0 +1,65 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::vec::Vec;
+
+use primitive_types::H256;
+use sparse_merkle_tree::{blake2b::Blake2bHasher, CompiledMerkleProof};
+
+use crate::{check_len, ProofError};
+
+/// Compiled SMT proof, the encoding of `sparse-merkle-tree` which is stable
+/// across its versions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtProof(pub Vec<u8>);
+
+impl SmtProof {
+    /// `leaves` are `(key, value hash)` pairs, a zero value hash proves the
+    /// key is absent.
+    pub fn verify(&self, root: &H256, leaves: &[(H256, H256)]) -> Result<(), ProofError> {
+        check_len(&self.0)?;
+        let leaves = { leaves.iter() }
+            .map(|(key, value)| (key.0.into(), value.0.into()))
+            .collect();
+        let proof = CompiledMerkleProof(self.0.clone());
+
+        match proof.verify::<Blake2bHasher>(&root.0.into(), leaves) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ProofError::Leaves),
+            Err(_) => Err(ProofError::Length),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree};
+
+    use super::*;
+
+    type Tree = SparseMerkleTree<
+        Blake2bHasher,
+        sparse_merkle_tree::H256,
+        DefaultStore<sparse_merkle_tree::H256>,
+    >;
+
+    #[test]
+    fn test_smt_proof() {
+        let mut tree = Tree::default();
+        let key = H256::repeat_byte(1);
+        let value = H256::repeat_byte(2);
+        tree.update(key.0.into(), value.0.into()).unwrap();
+        let root = H256::from_slice(tree.root().as_slice());
+
+        let proof: Vec<u8> = { tree.merkle_proof(vec![key.0.into()]).unwrap() }
+            .compile(vec![key.0.into()])
+            .unwrap()
+            .into();
+        let proof = SmtProof(proof);
+        proof.verify(&root, &[(key, value)]).unwrap();
+        assert_eq!(
+            proof.verify(&root, &[(key, H256::repeat_byte(3))]),
+            Err(ProofError::Leaves)
+        );
+    }
+}
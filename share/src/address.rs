@@ -0,0 +1,288 @@
+use std::{fmt, str::FromStr};
+
+use primitive_types::H160;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const SEPARATOR: char = '1';
+const CHECKSUM_LEN: usize = 6;
+// bech32m, see BIP-350
+const CHECKSUM_CONST: u32 = 0x2bc8_30a3;
+const GENERATOR: [u32; 5] = [
+    0x3b6a_57b2,
+    0x2650_8e6d,
+    0x1ea1_19fa,
+    0x3d42_33dd,
+    0x2a14_62b3,
+];
+
+/// Network an address belongs to, encoded as its human readable prefix so
+/// that an address can't be pasted into the wrong network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Devnet,
+}
+
+impl Network {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "cov",
+            Network::Testnet => "tcov",
+            Network::Devnet => "dcov",
+        }
+    }
+
+    pub fn from_prefix(prefix: &str) -> Option<Self> {
+        [Network::Mainnet, Network::Testnet, Network::Devnet]
+            .into_iter()
+            .find(|network| network.prefix() == prefix)
+    }
+}
+
+impl FromStr for Network {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "devnet" => Ok(Network::Devnet),
+            _ => Err(AddressError::Network(s.to_owned())),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    #[error("unknown network {0:?}")]
+    Network(String),
+    #[error("address mixes upper and lower case")]
+    MixedCase,
+    #[error("address has no separator")]
+    Separator,
+    #[error("invalid address character {0:?}")]
+    Char(char),
+    #[error("address checksum mismatch")]
+    Checksum,
+    #[error("address doesn't hold 20 bytes")]
+    Length,
+    #[error("address is for {found:?}, expected {expected:?}")]
+    WrongNetwork { expected: Network, found: Network },
+}
+
+/// Checksummed form of a 20 bytes address, e.g. `cov1...`.
+pub fn encode_address(network: Network, address: &H160) -> String {
+    let prefix = network.prefix();
+    let mut data = to_base32(address.as_bytes());
+    let checksum = create_checksum(prefix, &data);
+    data.extend_from_slice(&checksum);
+
+    let mut encoded = String::with_capacity(prefix.len() + 1 + data.len());
+    encoded.push_str(prefix);
+    encoded.push(SEPARATOR);
+    encoded.extend(data.iter().map(|d| CHARSET[*d as usize] as char));
+    encoded
+}
+
+/// Decode an address of any known network. Uppercase addresses are
+/// accepted, mixed case ones aren't.
+pub fn decode_address(encoded: &str) -> Result<(Network, H160), AddressError> {
+    let has_lower = encoded.chars().any(|c| c.is_ascii_lowercase());
+    let has_upper = encoded.chars().any(|c| c.is_ascii_uppercase());
+    if has_lower && has_upper {
+        return Err(AddressError::MixedCase);
+    }
+    let encoded = encoded.to_ascii_lowercase();
+
+    let (prefix, payload) = encoded
+        .rsplit_once(SEPARATOR)
+        .ok_or(AddressError::Separator)?;
+    let network =
+        Network::from_prefix(prefix).ok_or_else(|| AddressError::Network(prefix.to_owned()))?;
+    if payload.len() < CHECKSUM_LEN {
+        return Err(AddressError::Length);
+    }
+
+    let data = { payload.chars() }
+        .map(|c| {
+            { CHARSET.iter() }
+                .position(|b| *b as char == c)
+                .map(|d| d as u8)
+                .ok_or(AddressError::Char(c))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if polymod(&[expand_prefix(prefix), data.clone()].concat()) != CHECKSUM_CONST {
+        return Err(AddressError::Checksum);
+    }
+
+    let bytes = from_base32(&data[..data.len() - CHECKSUM_LEN]).ok_or(AddressError::Length)?;
+    if bytes.len() != H160::len_bytes() {
+        return Err(AddressError::Length);
+    }
+
+    Ok((network, H160::from_slice(&bytes)))
+}
+
+/// Decode an address and check that it belongs to `network`.
+pub fn parse_address(encoded: &str, network: Network) -> Result<H160, AddressError> {
+    match decode_address(encoded)? {
+        (found, address) if found == network => Ok(address),
+        (found, _) => Err(AddressError::WrongNetwork {
+            expected: network,
+            found,
+        }),
+    }
+}
+
+/// An address together with the network it's displayed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkAddress {
+    pub network: Network,
+    pub address: H160,
+}
+
+impl fmt::Display for NetworkAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_address(self.network, &self.address))
+    }
+}
+
+impl FromStr for NetworkAddress {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, address) = decode_address(s)?;
+        Ok(NetworkAddress { network, address })
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk = 1u32;
+    for value in values {
+        let top = chk >> 25;
+        chk = (chk & 0x01ff_ffff) << 5 ^ u32::from(*value);
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn expand_prefix(prefix: &str) -> Vec<u8> {
+    let mut expanded = prefix.bytes().map(|b| b >> 5).collect::<Vec<_>>();
+    expanded.push(0);
+    expanded.extend(prefix.bytes().map(|b| b & 0x1f));
+    expanded
+}
+
+fn create_checksum(prefix: &str, data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let values = [expand_prefix(prefix), data.to_vec(), vec![0; CHECKSUM_LEN]].concat();
+    let modulus = polymod(&values) ^ CHECKSUM_CONST;
+
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    for (i, c) in checksum.iter_mut().enumerate() {
+        *c = ((modulus >> (5 * (5 - i))) & 0x1f) as u8;
+    }
+    checksum
+}
+
+fn to_base32(bytes: &[u8]) -> Vec<u8> {
+    let (mut acc, mut bits) = (0u32, 0u32);
+    let mut out = Vec::with_capacity(bytes.len() * 8 / 5 + 1);
+    for byte in bytes {
+        acc = acc << 8 | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(((acc >> bits) & 0x1f) as u8);
+        }
+    }
+    if bits > 0 {
+        out.push(((acc << (5 - bits)) & 0x1f) as u8);
+    }
+    out
+}
+
+// `None` if the padding isn't made of at most 4 zero bits
+fn from_base32(data: &[u8]) -> Option<Vec<u8>> {
+    let (mut acc, mut bits) = (0u32, 0u32);
+    let mut out = Vec::with_capacity(data.len() * 5 / 8);
+    for d in data {
+        acc = (acc << 5 | u32::from(*d)) & 0xfff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || (acc << (8 - bits)) & 0xff != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let address = H160::repeat_byte(0xab);
+        let encoded = encode_address(Network::Testnet, &address);
+        assert!(encoded.starts_with("tcov1"));
+
+        assert_eq!(
+            decode_address(&encoded).unwrap(),
+            (Network::Testnet, address)
+        );
+        assert_eq!(decode_address(&encoded.to_uppercase()).unwrap().1, address);
+        assert_eq!(
+            parse_address(&encoded, Network::Mainnet),
+            Err(AddressError::WrongNetwork {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            })
+        );
+
+        let shown = NetworkAddress {
+            network: Network::Mainnet,
+            address,
+        };
+        assert_eq!(shown.to_string().parse::<NetworkAddress>().unwrap(), shown);
+    }
+
+    #[test]
+    fn test_bech32m_vector() {
+        // "a1lqfn3a" from BIP-350
+        let data = { "lqfn3a".chars() }
+            .map(|c| CHARSET.iter().position(|b| *b as char == c).unwrap() as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            polymod(&[expand_prefix("a"), data].concat()),
+            CHECKSUM_CONST
+        );
+    }
+
+    #[test]
+    fn test_rejects_typos() {
+        let encoded = encode_address(Network::Mainnet, &H160::repeat_byte(7));
+
+        let mut typo = encoded.clone().into_bytes();
+        let last = typo.len() - 1;
+        typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+        assert_eq!(
+            decode_address(std::str::from_utf8(&typo).unwrap()),
+            Err(AddressError::Checksum)
+        );
+
+        let mixed = encoded.replacen('c', "C", 1);
+        assert_eq!(decode_address(&mixed), Err(AddressError::MixedCase));
+        assert_eq!(
+            decode_address(&encoded.replace('1', "b")),
+            Err(AddressError::Separator)
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use primitive_types::U256;
+
+// 10^77 is the largest power of ten that fits in a U256
+pub const MAX_DECIMALS: u8 = 77;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AmountError {
+    #[error("amount {0:?} is not a plain decimal number")]
+    Malformed(String),
+    #[error("amount {amount:?} has more than {decimals} fractional digits")]
+    Precision { amount: String, decimals: u8 },
+    #[error("amount {0:?} overflows")]
+    Overflow(String),
+    #[error("token decimals {0} exceed {}", MAX_DECIMALS)]
+    Decimals(u64),
+}
+
+/// Parse a human readable amount like `"1.5"` into base units of a token
+/// with `decimals` fractional digits.
+///
+/// Amounts are never rounded: fractional digits beyond `decimals` are
+/// rejected unless they are zeros. Signs, exponents, separators and bare
+/// dots (`".5"`, `"1."`) are rejected too.
+pub fn parse_amount(amount: &str, decimals: u8) -> Result<U256, AmountError> {
+    check_decimals(decimals.into())?;
+    let malformed = || AmountError::Malformed(amount.to_owned());
+
+    let (int, frac) = match amount.split_once('.') {
+        Some((int, frac)) => (int, Some(frac)),
+        None => (amount, None),
+    };
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !all_digits(int) || !frac.is_none_or(all_digits) {
+        return Err(malformed());
+    }
+
+    let frac = frac.unwrap_or_default().trim_end_matches('0');
+    if frac.len() > decimals as usize {
+        return Err(AmountError::Precision {
+            amount: amount.to_owned(),
+            decimals,
+        });
+    }
+
+    let overflow = || AmountError::Overflow(amount.to_owned());
+    let digits = format!("{}{:0<width$}", int, frac, width = decimals as usize);
+    U256::from_dec_str(&digits).map_err(|_| overflow())
+}
+
+/// Like `parse_amount`, for tokens whose balances are kept in a u128.
+pub fn parse_amount_u128(amount: &str, decimals: u8) -> Result<u128, AmountError> {
+    let value = parse_amount(amount, decimals)?;
+    if value.bits() > 128 {
+        return Err(AmountError::Overflow(amount.to_owned()));
+    }
+
+    Ok(value.as_u128())
+}
+
+/// Render base units as a decimal string, without trailing fractional
+/// zeros. `format_amount(1_500_000.into(), 6)` is `"1.5"`.
+pub fn format_amount(value: U256, decimals: u8) -> String {
+    let digits = value.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+
+    let digits = format!("{:0>width$}", digits, width = decimals + 1);
+    let (int, frac) = digits.split_at(digits.len() - decimals);
+    let frac = frac.trim_end_matches('0');
+    if frac.is_empty() {
+        int.to_owned()
+    } else {
+        format!("{}.{}", int, frac)
+    }
+}
+
+/// Narrow token decimals as stored on chain to the range amounts support.
+pub fn check_decimals(decimals: u64) -> Result<u8, AmountError> {
+    if decimals > MAX_DECIMALS as u64 {
+        return Err(AmountError::Decimals(decimals));
+    }
+
+    Ok(decimals as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        assert_eq!(parse_amount("1.5", 6), Ok(1_500_000.into()));
+        assert_eq!(parse_amount("0.000001", 6), Ok(1.into()));
+        assert_eq!(parse_amount("42", 0), Ok(42.into()));
+        assert_eq!(parse_amount("1.50", 1), Ok(15.into()));
+        assert_eq!(format_amount(1_500_000.into(), 6), "1.5");
+        assert_eq!(format_amount(1.into(), 6), "0.000001");
+        assert_eq!(format_amount(2_000_000.into(), 6), "2");
+
+        for bad in ["", ".5", "1.", "-1", "+1", "1e6", "1_000", " 1", "1.2.3"] {
+            assert!(matches!(
+                parse_amount(bad, 6),
+                Err(AmountError::Malformed(_))
+            ));
+        }
+        assert!(matches!(
+            parse_amount("0.0000001", 6),
+            Err(AmountError::Precision { .. })
+        ));
+        assert!(matches!(
+            parse_amount_u128("340282366920938463463374607431768211456", 0),
+            Err(AmountError::Overflow(_))
+        ));
+        assert!(matches!(
+            parse_amount("1", 78),
+            Err(AmountError::Decimals(78))
+        ));
+    }
+}
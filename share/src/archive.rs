@@ -0,0 +1,226 @@
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"COVARCH\0";
+// Bumped whenever the layout of the archive changes
+pub const ARCHIVE_VERSION: u8 = 1;
+// Records above this size are rejected before allocating for them
+pub const MAX_RECORD_LEN: u32 = 256 * 1024 * 1024;
+
+const CHECKSUM_LEN: usize = 32;
+// Length prefix marking the trailer instead of a record
+const TRAILER_MARK: u32 = u32::MAX;
+
+/// Which chain the records of an archive belong to, so that an archive
+/// exported by one layer can't be imported into the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ArchiveKind {
+    Layer2 = 2,
+    Layer3 = 3,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ArchiveError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a covalent archive")]
+    BadMagic,
+    #[error("unsupported archive version {0}")]
+    Version(u8),
+    #[error("archive holds {found} blocks, expected {expected:?}")]
+    Kind { expected: ArchiveKind, found: u8 },
+    #[error("record {0} is larger than {}", MAX_RECORD_LEN)]
+    RecordTooLarge(u64),
+    #[error("checksum mismatch in record {0}")]
+    Checksum(u64),
+    #[error("archive ends after {0} records without a trailer")]
+    Truncated(u64),
+    #[error("trailer counts {trailer} records, read {read}")]
+    Count { trailer: u64, read: u64 },
+}
+
+/// Writes a flat archive:
+///
+/// ```text
+/// magic (8) | version (1) | kind (1)
+/// { len: u32 BE | payload (len) | sha256(payload) (32) }*
+/// 0xffffffff | record count: u64 BE
+/// ```
+///
+/// The trailer is only written by `finish`, an archive cut short by a crash
+/// or a full disk is reported as truncated when read back.
+pub struct ArchiveWriter<W: Write> {
+    inner: W,
+    records: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut inner: W, kind: ArchiveKind) -> Result<Self, ArchiveError> {
+        inner.write_all(ARCHIVE_MAGIC)?;
+        inner.write_all(&[ARCHIVE_VERSION, kind as u8])?;
+
+        Ok(ArchiveWriter { inner, records: 0 })
+    }
+
+    pub fn append(&mut self, payload: &[u8]) -> Result<(), ArchiveError> {
+        let len = u32::try_from(payload.len())
+            .ok()
+            .filter(|len| *len <= MAX_RECORD_LEN)
+            .ok_or(ArchiveError::RecordTooLarge(self.records))?;
+
+        self.inner.write_all(&len.to_be_bytes())?;
+        self.inner.write_all(payload)?;
+        self.inner.write_all(&Sha256::digest(payload))?;
+        self.records += 1;
+
+        Ok(())
+    }
+
+    /// Write the trailer and return the underlying writer, flushed.
+    pub fn finish(mut self) -> Result<W, ArchiveError> {
+        self.inner.write_all(&TRAILER_MARK.to_be_bytes())?;
+        self.inner.write_all(&self.records.to_be_bytes())?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+/// Reads back an archive written by `ArchiveWriter`, checking every record
+/// checksum and the trailer record count.
+pub struct ArchiveReader<R: Read> {
+    inner: R,
+    records: u64,
+    done: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn open(mut inner: R, kind: ArchiveKind) -> Result<Self, ArchiveError> {
+        let mut header = [0u8; 10];
+        inner.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ArchiveError::BadMagic,
+            _ => e.into(),
+        })?;
+
+        if &header[..8] != ARCHIVE_MAGIC {
+            return Err(ArchiveError::BadMagic);
+        }
+        if header[8] != ARCHIVE_VERSION {
+            return Err(ArchiveError::Version(header[8]));
+        }
+        if header[9] != kind as u8 {
+            return Err(ArchiveError::Kind {
+                expected: kind,
+                found: header[9],
+            });
+        }
+
+        Ok(ArchiveReader {
+            inner,
+            records: 0,
+            done: false,
+        })
+    }
+
+    /// Next record payload, `None` once the trailer has been read.
+    pub fn next_record(&mut self) -> Result<Option<Vec<u8>>, ArchiveError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut len = [0u8; 4];
+        self.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+
+        if len == TRAILER_MARK {
+            let mut count = [0u8; 8];
+            self.read_exact(&mut count)?;
+            let count = u64::from_be_bytes(count);
+            if count != self.records {
+                return Err(ArchiveError::Count {
+                    trailer: count,
+                    read: self.records,
+                });
+            }
+
+            self.done = true;
+            return Ok(None);
+        }
+        if len > MAX_RECORD_LEN {
+            return Err(ArchiveError::RecordTooLarge(self.records));
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        self.read_exact(&mut payload)?;
+        let mut checksum = [0u8; CHECKSUM_LEN];
+        self.read_exact(&mut checksum)?;
+        if Sha256::digest(&payload).as_slice() != checksum {
+            return Err(ArchiveError::Checksum(self.records));
+        }
+
+        self.records += 1;
+        Ok(Some(payload))
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ArchiveError> {
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ArchiveError::Truncated(self.records),
+            _ => e.into(),
+        })
+    }
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+    type Item = Result<Vec<u8>, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_record().transpose();
+        if matches!(next, Some(Err(_))) {
+            self.done = true;
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(records: &[&[u8]]) -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(Vec::new(), ArchiveKind::Layer2).unwrap();
+        for record in records {
+            writer.append(record).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let raw = archive(&[b"one", b"", b"three"]);
+        let reader = ArchiveReader::open(raw.as_slice(), ArchiveKind::Layer2).unwrap();
+        let records = reader.collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(records, vec![b"one".to_vec(), vec![], b"three".to_vec()]);
+    }
+
+    #[test]
+    fn test_rejects_corruption() {
+        let raw = archive(&[b"one", b"two"]);
+
+        let mut flipped = raw.clone();
+        flipped[10 + 4 + 3 + 32 + 4] ^= 1;
+        let reader = ArchiveReader::open(flipped.as_slice(), ArchiveKind::Layer2).unwrap();
+        let err = reader.collect::<Result<Vec<_>, _>>().unwrap_err();
+        assert!(matches!(err, ArchiveError::Checksum(1)));
+
+        let cut = &raw[..raw.len() - 12];
+        let reader = ArchiveReader::open(cut, ArchiveKind::Layer2).unwrap();
+        let err = reader.collect::<Result<Vec<_>, _>>().unwrap_err();
+        assert!(matches!(err, ArchiveError::Truncated(2)));
+
+        let err = ArchiveReader::open(raw.as_slice(), ArchiveKind::Layer3).err();
+        assert!(matches!(err, Some(ArchiveError::Kind { found: 2, .. })));
+    }
+}
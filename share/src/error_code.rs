@@ -0,0 +1,79 @@
+/// Error codes returned by the layer2 and layer3 RPC servers. Codes live in
+/// the JSON-RPC implementation defined range and never change meaning once
+/// released, new errors get new codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum RpcErrorCode {
+    Internal = -32000,
+    MempoolFull = -32001,
+    InvalidNonce = -32002,
+    UnknownBlock = -32003,
+    PrunedState = -32004,
+    Unauthorized = -32005,
+    RateLimited = -32006,
+    InvalidTransaction = -32007,
+    InvalidSignature = -32008,
+    InvalidChainId = -32009,
+    DuplicateTransaction = -32010,
+    ExceedCycleLimit = -32011,
+    NotReady = -32012,
+    InvalidIdempotencyKey = -32013,
+    FeeTooLow = -32014,
+    ReadOnly = -32015,
+    BatchTooLarge = -32016,
+    MethodNotAllowed = -32017,
+    RequestTimeout = -32018,
+    InvalidRange = -32019,
+    TrimmedHistory = -32020,
+}
+
+impl RpcErrorCode {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        use RpcErrorCode::*;
+
+        [
+            Internal,
+            MempoolFull,
+            InvalidNonce,
+            UnknownBlock,
+            PrunedState,
+            Unauthorized,
+            RateLimited,
+            InvalidTransaction,
+            InvalidSignature,
+            InvalidChainId,
+            DuplicateTransaction,
+            ExceedCycleLimit,
+            NotReady,
+            InvalidIdempotencyKey,
+            FeeTooLow,
+            ReadOnly,
+            BatchTooLarge,
+            MethodNotAllowed,
+            RequestTimeout,
+            InvalidRange,
+            TrimmedHistory,
+        ]
+        .into_iter()
+        .find(|c| c.code() == code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_roundtrip() {
+        assert_eq!(RpcErrorCode::MempoolFull.code(), -32001);
+        assert_eq!(
+            RpcErrorCode::from_code(-32006),
+            Some(RpcErrorCode::RateLimited)
+        );
+        assert_eq!(RpcErrorCode::from_code(-32600), None);
+    }
+}
@@ -0,0 +1,306 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use dashmap::DashMap;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE, UPGRADE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::error_code::RpcErrorCode;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// Windows that ran out are dropped once this many clients are tracked
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// PEM files of the certificate chain and the private key an RPC server
+/// terminates TLS with.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn load(&self) -> Result<Arc<ServerConfig>> {
+        let certs = rustls_pemfile::certs(&mut open(&self.cert_path)?)
+            .with_context(|| format!("read {}", self.cert_path.display()))?;
+        if certs.is_empty() {
+            return Err(anyhow!("no certificate in {}", self.cert_path.display()));
+        }
+
+        let mut reader = open(&self.key_path)?;
+        let key = loop {
+            match rustls_pemfile::read_one(&mut reader)
+                .with_context(|| format!("read {}", self.key_path.display()))?
+            {
+                Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => break key,
+                Some(_) => continue,
+                None => return Err(anyhow!("no private key in {}", self.key_path.display())),
+            }
+        };
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                certs.into_iter().map(Certificate).collect(),
+                PrivateKey(key),
+            )?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Sits in front of an RPC server bound to loopback, for what the server
+/// doesn't do itself: terminating TLS and telling clients apart by address.
+/// Requests are forwarded with the client chain in X-Forwarded-For,
+/// WebSocket connections once upgraded. Refused requests are answered with
+/// a JSON-RPC error.
+pub struct RpcFront {
+    tls: Option<TlsAcceptor>,
+    trusted: Vec<IpAddr>,
+    limiter: ClientRateLimiter,
+    client: Client<HttpConnector>,
+}
+
+impl RpcFront {
+    /// None when the server can take connections itself, which it can
+    /// without TLS and a per client rate limit.
+    pub fn new(
+        tls: Option<&TlsConfig>,
+        trusted_proxies: &[IpAddr],
+        client_rate_limit: u32,
+    ) -> Result<Option<Self>> {
+        if tls.is_none() && client_rate_limit == 0 {
+            return Ok(None);
+        }
+
+        let tls = match tls {
+            Some(tls) => Some(TlsAcceptor::from(tls.load()?)),
+            None => None,
+        };
+        Ok(Some(RpcFront {
+            tls,
+            trusted: trusted_proxies.to_vec(),
+            limiter: ClientRateLimiter::new(client_rate_limit),
+            client: Client::new(),
+        }))
+    }
+
+    /// Where the server behind the front binds.
+    pub fn inner_addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    /// Take connections on `uri` for the server listening on `inner`.
+    pub async fn spawn(self, uri: SocketAddr, inner: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(uri).await?;
+        let front = Arc::new(self);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        tokio::spawn(Arc::clone(&front).serve(stream, peer.ip(), inner));
+                    }
+                    Err(e) => println!("[rpc] accepting on {} failed: {}", uri, e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    // Failed handshakes and dropped connections are the client's business
+    async fn serve(self: Arc<Self>, stream: TcpStream, peer: IpAddr, inner: SocketAddr) {
+        let front = Arc::clone(&self);
+        let service = service_fn(move |req| Arc::clone(&front).forward(req, peer, inner));
+        let _ = match &self.tls {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    Http::new()
+                        .serve_connection(stream, service)
+                        .with_upgrades()
+                        .await
+                }
+                Err(_) => return,
+            },
+            None => {
+                Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await
+            }
+        };
+    }
+
+    async fn forward(
+        self: Arc<Self>,
+        mut req: Request<Body>,
+        peer: IpAddr,
+        inner: SocketAddr,
+    ) -> Result<Response<Body>, Infallible> {
+        let chain = client_chain(req.headers(), peer, &self.trusted);
+        if !self.limiter.acquire(chain[0]) {
+            let message = format!("rate limit of {} requests per second", self.limiter.limit);
+            return Ok(refused(StatusCode::TOO_MANY_REQUESTS, message));
+        }
+
+        let path = { req.uri().path_and_query() }
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        *req.uri_mut() = match format!("http://{}{}", inner, path).parse() {
+            Ok(uri) => uri,
+            Err(e) => return Ok(refused(StatusCode::BAD_REQUEST, e)),
+        };
+        let listed = chain.iter().map(ToString::to_string).collect::<Vec<_>>();
+        if let Ok(listed) = HeaderValue::from_str(&listed.join(", ")) {
+            req.headers_mut().insert(X_FORWARDED_FOR, listed);
+        }
+
+        let upgrade = { req.headers().contains_key(UPGRADE) }.then(|| hyper::upgrade::on(&mut req));
+        let mut resp = match self.client.request(req).await {
+            Ok(resp) => resp,
+            Err(e) => return Ok(refused(StatusCode::BAD_GATEWAY, e)),
+        };
+        let switched = resp.status() == StatusCode::SWITCHING_PROTOCOLS;
+        if let Some(upgrade) = upgrade.filter(|_| switched) {
+            let upgraded = hyper::upgrade::on(&mut resp);
+            tokio::spawn(async move {
+                if let (Ok(mut client), Ok(mut server)) = tokio::join!(upgrade, upgraded) {
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                }
+            });
+        }
+
+        Ok(resp)
+    }
+}
+
+// Addresses the request passed, client first and the direct peer last.
+// X-Forwarded-For is only believed as far back as trusted proxies added to
+// it, so clients can't pick their own address.
+fn client_chain(headers: &HeaderMap, peer: IpAddr, trusted: &[IpAddr]) -> Vec<IpAddr> {
+    let listed = { headers.get_all(X_FORWARDED_FOR).iter() }
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut chain = vec![peer];
+    for entry in listed.rsplit(',') {
+        if !chain.last().is_some_and(|hop| trusted.contains(hop)) {
+            break;
+        }
+        match entry.trim().parse() {
+            Ok(hop) => chain.push(hop),
+            Err(_) => break,
+        }
+    }
+    chain.reverse();
+    chain
+}
+
+fn refused(status: StatusCode, message: impl ToString) -> Response<Body> {
+    let code = match status {
+        StatusCode::TOO_MANY_REQUESTS => RpcErrorCode::RateLimited,
+        _ => RpcErrorCode::Internal,
+    };
+    let body = json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code.code(), "message": message.to_string() },
+        "id": null,
+    });
+
+    let mut resp = Response::new(Body::from(body.to_string()));
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
+
+/// Fixed one second windows per client address, 0 means unlimited.
+struct ClientRateLimiter {
+    limit: u32,
+    windows: DashMap<IpAddr, (Instant, u32)>,
+}
+
+impl ClientRateLimiter {
+    fn new(limit: u32) -> Self {
+        ClientRateLimiter {
+            limit,
+            windows: DashMap::new(),
+        }
+    }
+
+    fn acquire(&self, client: IpAddr) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        if self.windows.len() > MAX_TRACKED_CLIENTS {
+            self.windows
+                .retain(|_, window| window.0.elapsed() < RATE_WINDOW);
+        }
+
+        let mut window = self.windows.entry(client).or_insert((Instant::now(), 0));
+        if window.0.elapsed() >= RATE_WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= self.limit {
+            return false;
+        }
+
+        window.1 += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_chain_trusts_only_proxies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let proxy = ip("10.0.0.1");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2, 10.0.0.2"),
+        );
+
+        // A client can't pass itself off as another
+        let client = ip("3.3.3.3");
+        assert_eq!(client_chain(&headers, client, &[proxy]), vec![client]);
+        // Through two trusted proxies, the hop before them is the client
+        assert_eq!(
+            client_chain(&headers, proxy, &[proxy, ip("10.0.0.2")]),
+            vec![ip("2.2.2.2"), ip("10.0.0.2"), proxy]
+        );
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("bogus"));
+        assert_eq!(client_chain(&headers, proxy, &[proxy]), vec![proxy]);
+
+        let limiter = ClientRateLimiter::new(2);
+        assert!(limiter.acquire(proxy) && limiter.acquire(proxy));
+        assert!(!limiter.acquire(proxy));
+        assert!(limiter.acquire(client));
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const MAX_KEY_LEN: usize = 64;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("idempotency key must be 1 to {} bytes", MAX_KEY_LEN)]
+pub struct InvalidKey;
+
+/// Binds client supplied idempotency keys to the transaction first sent
+/// with them, so a retried `send_transaction` gets the original hash back
+/// instead of submitting again. Keys expire after `ttl`, and the oldest key
+/// is forgotten once `capacity` keys are held.
+pub struct IdempotencyKeys<H> {
+    inner: Mutex<Bindings<H>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+struct Bindings<H> {
+    by_key: HashMap<String, (H, Instant)>,
+    // Insertion order, for expiry
+    order: VecDeque<(String, Instant)>,
+}
+
+impl<H: Copy + PartialEq> IdempotencyKeys<H> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        IdempotencyKeys {
+            inner: Mutex::new(Bindings {
+                by_key: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            ttl,
+            capacity,
+        }
+    }
+
+    pub fn check_key(key: &str) -> Result<(), InvalidKey> {
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(InvalidKey);
+        }
+        Ok(())
+    }
+
+    /// Bind `key` to `hash` unless it's bound already, in which case the
+    /// original hash is returned and nothing changes.
+    pub fn bind(&self, key: &str, hash: H) -> Result<Option<H>, InvalidKey> {
+        Self::check_key(key)?;
+
+        let mut bindings = self.inner.lock().unwrap();
+        let now = Instant::now();
+        bindings.expire(now, self.ttl, self.capacity.saturating_sub(1));
+        if let Some((original, _)) = bindings.by_key.get(key) {
+            return Ok(Some(*original));
+        }
+
+        bindings.by_key.insert(key.to_owned(), (hash, now));
+        bindings.order.push_back((key.to_owned(), now));
+        Ok(None)
+    }
+
+    /// Undo `bind` after the transaction was rejected, so the client can
+    /// retry with a fixed transaction under the same key.
+    pub fn release(&self, key: &str, hash: H) {
+        let mut bindings = self.inner.lock().unwrap();
+        if matches!(bindings.by_key.get(key), Some((bound, _)) if *bound == hash) {
+            bindings.by_key.remove(key);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<H> {
+        let bindings = self.inner.lock().unwrap();
+        { bindings.by_key.get(key) }
+            .filter(|(_, at)| at.elapsed() < self.ttl)
+            .map(|(hash, _)| *hash)
+    }
+}
+
+impl<H> Bindings<H> {
+    fn expire(&mut self, now: Instant, ttl: Duration, keep: usize) {
+        while let Some((key, at)) = self.order.front() {
+            if now.duration_since(*at) < ttl && self.order.len() <= keep {
+                break;
+            }
+            // A released and re-bound key has a newer entry further back
+            if matches!(self.by_key.get(key), Some((_, bound_at)) if bound_at == at) {
+                self.by_key.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_and_release() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60), 2);
+        assert_eq!(keys.bind("retry-1", 1u32), Ok(None));
+        assert_eq!(keys.bind("retry-1", 2), Ok(Some(1)));
+        assert_eq!(keys.get("retry-1"), Some(1));
+
+        keys.release("retry-1", 2);
+        assert_eq!(keys.get("retry-1"), Some(1));
+        keys.release("retry-1", 1);
+        assert_eq!(keys.bind("retry-1", 3), Ok(None));
+
+        // The oldest key goes once capacity is reached
+        keys.bind("retry-2", 4).unwrap();
+        keys.bind("retry-3", 5).unwrap();
+        assert_eq!(keys.get("retry-1"), None);
+        assert_eq!(keys.get("retry-3"), Some(5));
+
+        assert_eq!(keys.bind("", 6), Err(InvalidKey));
+        assert_eq!(keys.bind(&"k".repeat(MAX_KEY_LEN + 1), 6), Err(InvalidKey));
+    }
+
+    #[test]
+    fn test_keys_expire() {
+        let keys = IdempotencyKeys::new(Duration::ZERO, 16);
+        keys.bind("retry", 1u32).unwrap();
+        assert_eq!(keys.get("retry"), None);
+        assert_eq!(keys.bind("retry", 2), Ok(None));
+    }
+}
@@ -1,3 +1,12 @@
+pub mod address;
+pub mod amount;
+pub mod archive;
+pub mod error_code;
+pub mod front;
+pub mod idempotency;
+pub mod limits;
+pub mod message;
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -0,0 +1,63 @@
+/// Bounds on one kind of untrusted input. The size is checked before
+/// decoding starts, so a length prefix inside the input can never claim
+/// more than the input itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimit {
+    pub kind: &'static str,
+    pub max_size: usize,
+    // Nesting of lists, only checked for self-describing encodings
+    pub max_depth: usize,
+}
+
+pub const TRANSACTION_LIMIT: DecodeLimit = DecodeLimit {
+    kind: "transaction",
+    max_size: 128 * 1024,
+    max_depth: 8,
+};
+
+pub const BLOCK_LIMIT: DecodeLimit = DecodeLimit {
+    kind: "block",
+    max_size: 16 * 1024 * 1024,
+    max_depth: 12,
+};
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("{kind} of {size} bytes is larger than {limit}")]
+    TooLarge {
+        kind: &'static str,
+        size: usize,
+        limit: usize,
+    },
+    #[error("{kind} nests deeper than {limit} levels")]
+    TooDeep { kind: &'static str, limit: usize },
+    #[error("malformed {kind}: {reason}")]
+    Malformed { kind: &'static str, reason: String },
+}
+
+impl DecodeLimit {
+    pub fn check_size(&self, size: usize) -> Result<(), DecodeError> {
+        if size > self.max_size {
+            return Err(DecodeError::TooLarge {
+                kind: self.kind,
+                size,
+                limit: self.max_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn too_deep(&self) -> DecodeError {
+        DecodeError::TooDeep {
+            kind: self.kind,
+            limit: self.max_depth,
+        }
+    }
+
+    pub fn malformed(&self, reason: impl ToString) -> DecodeError {
+        DecodeError::Malformed {
+            kind: self.kind,
+            reason: reason.to_string(),
+        }
+    }
+}
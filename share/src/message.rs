@@ -0,0 +1,31 @@
+use primitive_types::H256;
+use sha3::{Digest, Keccak256};
+
+const PERSONAL_MESSAGE_PREFIX: &[u8] = b"\x19Ethereum Signed Message:\n";
+
+/// EIP-191 hash of a personal message, the keccak256 of the message behind
+/// a prefix with its length. Signing this instead of the raw message keeps
+/// a signed message from ever being a valid transaction.
+pub fn personal_message_hash(message: &[u8]) -> H256 {
+    let mut hasher = Keccak256::new();
+    hasher.update(PERSONAL_MESSAGE_PREFIX);
+    hasher.update(message.len().to_string().as_bytes());
+    hasher.update(message);
+    H256::from_slice(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_personal_message_hash() {
+        assert_eq!(
+            personal_message_hash(b"Hello World"),
+            H256::from_str("a1de988600a42c4b4ab089b619297c17d53cffae5d5120d82d8a92d0bb3b78f2")
+                .unwrap()
+        );
+    }
+}